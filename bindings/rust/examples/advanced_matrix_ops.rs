@@ -35,11 +35,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut result = vec![0.0f32; m * p];
 
     // Fill with test data
-    for i in 0..matrix_a.len() {
-        matrix_a[i] = (i % 100) as f32 * 0.01;
+    for (i, value) in matrix_a.iter_mut().enumerate() {
+        *value = (i % 100) as f32 * 0.01;
     }
-    for i in 0..matrix_b.len() {
-        matrix_b[i] = ((i + 50) % 100) as f32 * 0.01;
+    for (i, value) in matrix_b.iter_mut().enumerate() {
+        *value = ((i + 50) % 100) as f32 * 0.01;
     }
 
     println!("⏱️  Performing matrix multiplication...");
@@ -63,7 +63,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("-----------------------------------");
 
     // Test with 2x2 matrices (larger matrices not yet supported)
-    let test_matrices = vec![
+    let test_matrices = [
         vec![2.0, 1.0, 1.0, 3.0], // det = 5
         vec![4.0, 2.0, 2.0, 1.0], // det = 0 (singular)
         vec![1.0, 0.0, 0.0, 1.0], // identity matrix
@@ -191,14 +191,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("--------------------------");
 
     // Test with invalid dimensions
-    let invalid_result = matrix.multiply(&vec![1.0, 2.0], &vec![3.0, 4.0], &mut vec![0.0], 2, 3, 2);
+    let invalid_result = matrix.multiply(&[1.0, 2.0], &[3.0, 4.0], &mut [0.0], 2, 3, 2);
     match invalid_result {
         Err(e) => println!("✅ Expected error caught: {:?}", e),
         Ok(_) => println!("❌ Expected error was not caught"),
     }
 
     // Test with mismatched vector sizes
-    let mismatch_result = matrix.vector_add(&vec![1.0, 2.0], &vec![3.0], &mut vec![0.0, 0.0]);
+    let mismatch_result = matrix.vector_add(&[1.0, 2.0], &[3.0], &mut [0.0, 0.0]);
     match mismatch_result {
         Err(e) => println!("✅ Vector size mismatch error caught: {:?}", e),
         Ok(_) => println!("❌ Expected vector mismatch error was not caught"),
@@ -6,7 +6,6 @@ serialization, and deserialization.
 */
 
 use umicp_core::{Envelope, OperationType};
-use uuid;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("UMICP Rust Bindings - Basic Envelope Example");
@@ -73,6 +73,7 @@ enum CommunicationData {
 
 /// High-performance embedding serializer
 struct EmbeddingSerializer {
+    #[allow(dead_code)]
     matrix: Matrix,
 }
 
@@ -90,20 +91,20 @@ impl EmbeddingSerializer {
         let mut token_ids = vec![0i32; sequence_length];
 
         // Generate realistic embedding patterns
-        for i in 0..embeddings.len() {
+        for (i, value) in embeddings.iter_mut().enumerate() {
             let seq_pos = i / embedding_dim;
             let dim_pos = i % embedding_dim;
-            embeddings[i] = (seq_pos as f32 * 0.1).sin() * (dim_pos as f32 * 0.01).cos() * 0.1;
+            *value = (seq_pos as f32 * 0.1).sin() * (dim_pos as f32 * 0.01).cos() * 0.1;
         }
 
         // Set attention mask (first part is real tokens)
-        for i in 0..(sequence_length / 2) {
-            attention_mask[i] = 1;
+        for mask in attention_mask.iter_mut().take(sequence_length / 2) {
+            *mask = 1;
         }
 
         // Generate token IDs
-        for i in 0..sequence_length {
-            token_ids[i] = (i % 30522) as i32; // BERT vocab size
+        for (i, token_id) in token_ids.iter_mut().enumerate() {
+            *token_id = (i % 30522) as i32; // BERT vocab size
         }
 
         EmbeddingData {
@@ -157,10 +158,10 @@ impl EmbeddingSerializer {
         let mut embeddings = vec![0.0f32; sequence_length * embedding_dim];
 
         // Generate encoder-style patterns
-        for i in 0..embeddings.len() {
+        for (i, value) in embeddings.iter_mut().enumerate() {
             let seq_pos = i / embedding_dim;
             let dim_pos = i % embedding_dim;
-            embeddings[i] = (seq_pos as f32 * 0.05).cos() * (dim_pos as f32 * 0.02).sin() * 0.15;
+            *value = (seq_pos as f32 * 0.05).cos() * (dim_pos as f32 * 0.02).sin() * 0.15;
         }
 
         EmbeddingData {
@@ -191,6 +192,7 @@ impl EmbeddingSerializer {
 
 /// Transformer communication handler
 struct TransformerCommunication {
+    #[allow(dead_code)]
     serializer: EmbeddingSerializer,
     matrix: Matrix,
 }
@@ -239,6 +241,7 @@ impl TransformerCommunication {
     }
 
     /// Process embedding envelope
+    #[allow(dead_code)]
     fn process_embedding_envelope(&self, envelope: &Envelope) -> Result<EmbeddingData, Box<dyn std::error::Error>> {
         let capabilities = envelope.capabilities().ok_or("No capabilities found")?;
 
@@ -255,8 +258,8 @@ impl TransformerCommunication {
 
         // Create mock embedding data
         let mut embeddings = vec![0.0f32; sequence_length * embedding_dim];
-        for i in 0..embeddings.len() {
-            embeddings[i] = (i as f32 * 0.001).sin() * 0.1;
+        for (i, value) in embeddings.iter_mut().enumerate() {
+            *value = (i as f32 * 0.001).sin() * 0.1;
         }
 
         let metadata = ModelMetadata {
@@ -295,32 +298,15 @@ impl TransformerCommunication {
         Ok(similarities.into_iter().take(top_k).collect())
     }
 
-    /// Aggregate embeddings from multiple sources
-    fn aggregate_embeddings(&self, embeddings: &[Vec<f32>]) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+    /// Aggregate embeddings from multiple sources, weighted by sample count
+    fn aggregate_embeddings(&self, embeddings: &[Vec<f32>], sample_counts: &[f32]) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
         if embeddings.is_empty() {
             return Err("No embeddings to aggregate".into());
         }
 
-        let embedding_dim = embeddings[0].len();
-        let mut aggregated = vec![0.0f32; embedding_dim];
-
-        // Verify all embeddings have the same dimension
-        for embedding in embeddings {
-            if embedding.len() != embedding_dim {
-                return Err("All embeddings must have the same dimension".into());
-            }
-        }
-
-        // Average the embeddings
-        for embedding in embeddings {
-            for i in 0..embedding_dim {
-                aggregated[i] += embedding[i];
-            }
-        }
-
-        for i in 0..embedding_dim {
-            aggregated[i] /= embeddings.len() as f32;
-        }
+        let views: Vec<&[f32]> = embeddings.iter().map(|e| e.as_slice()).collect();
+        let mut aggregated = vec![0.0f32; embeddings[0].len()];
+        self.matrix.weighted_average(&views, sample_counts, &mut aggregated)?;
 
         Ok(aggregated)
     }
@@ -338,7 +324,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("-------------------------------");
 
     let bert_embedding = serializer.create_bert_embedding(128, 768);
-    let bert_envelope = communication.create_embedding_envelope(
+    let _bert_envelope = communication.create_embedding_envelope(
         "bert-encoder",
         "classification-head",
         &bert_embedding
@@ -359,7 +345,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let num_heads = 12;
     let attention_weights = serializer.create_gpt_attention(sequence_length, num_heads);
 
-    let attention_envelope = communication.create_attention_envelope(
+    let _attention_envelope = communication.create_attention_envelope(
         "gpt-decoder",
         "attention-aggregator",
         &attention_weights,
@@ -379,7 +365,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("-----------------------------------------");
 
     let t5_embedding = serializer.create_t5_encoder_output(256, 512);
-    let t5_envelope = communication.create_embedding_envelope(
+    let _t5_envelope = communication.create_embedding_envelope(
         "t5-encoder",
         "t5-decoder",
         &t5_embedding
@@ -398,18 +384,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut embedding_database: Vec<Vec<f32>> = Vec::new();
 
     for i in 0..database_size {
-        let mut embedding = vec![0.0f32; embedding_dim];
-        for j in 0..embedding_dim {
-            embedding[j] = ((i as f32) * 0.1).sin() + ((j as f32) * 0.01).cos() * 0.1;
-        }
-        embedding_database.push(embedding);
+        embedding_database.push(Matrix::random_normal(embedding_dim, 0.0, 1.0, Some(i as u64))?);
     }
 
     // Create query embedding
-    let mut query_embedding = vec![0.0f32; embedding_dim];
-    for j in 0..embedding_dim {
-        query_embedding[j] = (0.05_f32).sin() + ((j as f32) * 0.01_f32).cos() * 0.1_f32;
-    }
+    let query_embedding = Matrix::random_normal(embedding_dim, 0.0, 1.0, Some(database_size as u64))?;
 
     let start_time = std::time::Instant::now();
     let similar_results = communication.find_similar_embeddings(
@@ -439,13 +418,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     for client in 0..num_clients {
         let mut client_embedding = vec![0.0f32; shared_embedding_dim];
-        for i in 0..shared_embedding_dim {
-            client_embedding[i] = ((i as f32) * 0.01).sin() + (client as f32 * 0.01);
+        for (i, value) in client_embedding.iter_mut().enumerate() {
+            *value = ((i as f32) * 0.01).sin() + (client as f32 * 0.01);
         }
         client_embeddings.push(client_embedding);
     }
 
-    let aggregated_embedding = communication.aggregate_embeddings(&client_embeddings)?;
+    // Clients holding more local samples contribute proportionally more
+    let sample_counts: Vec<f32> = (0..num_clients).map(|client| 100.0 * (client + 1) as f32).collect();
+    let aggregated_embedding = communication.aggregate_embeddings(&client_embeddings, &sample_counts)?;
 
     let magnitude = aggregated_embedding.iter()
         .fold(0.0f32, |sum, &val| sum + val * val)
@@ -487,7 +468,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("   JSON size: {} bytes", serialized_model.len());
 
     // Deserialize
-    let deserialized_model: ModelCommunication = serde_json::from_str(&serialized_model)?;
+    let _deserialized_model: ModelCommunication = serde_json::from_str(&serialized_model)?;
     println!("✅ Model state deserialized successfully");
 
     println!("\n🎉 Embedding Communication Example Completed!");
@@ -7,6 +7,9 @@ strategies using the UMICP Rust bindings with proper error propagation.
 
 use umicp_core::{Envelope, Matrix, OperationType, UmicpError};
 
+/// Fallible demo operation used by the error aggregation example
+type Operation = fn() -> Result<(), UmicpError>;
+
 /// Comprehensive error handling demonstration
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("🛡️  UMICP Rust Bindings - Error Handling Example");
@@ -70,9 +73,9 @@ fn demonstrate_basic_errors() -> Result<(), Box<dyn std::error::Error>> {
     println!("📊 Testing matrix operations with invalid inputs...");
 
     let invalid_result = matrix.multiply(
-        &vec![1.0, 2.0],
-        &vec![3.0, 4.0],
-        &mut vec![0.0],
+        &[1.0, 2.0],
+        &[3.0, 4.0],
+        &mut [0.0],
         2, 3, 2  // Invalid dimensions: 2x3 * 3x2 = 2x2, but we provided 2x2 result
     );
 
@@ -86,9 +89,9 @@ fn demonstrate_basic_errors() -> Result<(), Box<dyn std::error::Error>> {
 
     // Test vector operations with mismatched sizes
     let vec_mismatch = matrix.vector_add(
-        &vec![1.0, 2.0, 3.0],
-        &vec![4.0, 5.0],
-        &mut vec![0.0, 0.0, 0.0]
+        &[1.0, 2.0, 3.0],
+        &[4.0, 5.0],
+        &mut [0.0, 0.0, 0.0]
     );
 
     match vec_mismatch {
@@ -178,7 +181,6 @@ fn demonstrate_custom_errors() -> Result<(), Box<dyn std::error::Error>> {
         Err(CorruptionError::FormatError(msg)) => {
             println!("✅ Format error caught: {}", msg);
         }
-        Err(e) => println!("✅ Other error caught: {:?}", e),
     }
 
     Ok(())
@@ -205,7 +207,7 @@ fn demonstrate_validation() -> Result<(), Box<dyn std::error::Error>> {
 
         match envelope {
             Ok(env) => {
-                let is_valid = validate_envelope(&env)?;
+                let _is_valid = validate_envelope(&env)?;
                 println!("✅ {}: Envelope created and validated", expected);
             }
             Err(e) => {
@@ -245,8 +247,8 @@ fn demonstrate_graceful_degradation() {
 
         // Create test data
         let mut matrix_data = vec![0.0f32; size * size];
-        for i in 0..matrix_data.len() {
-            matrix_data[i] = (i % 100) as f32 * 0.01;
+        for (i, value) in matrix_data.iter_mut().enumerate() {
+            *value = (i % 100) as f32 * 0.01;
         }
 
         // Try matrix multiplication
@@ -277,11 +279,11 @@ fn demonstrate_graceful_degradation() {
 fn demonstrate_error_aggregation() -> Result<(), Box<dyn std::error::Error>> {
     println!("📊 Testing error aggregation and reporting...");
 
-    let operations: Vec<(&str, fn() -> Result<(), UmicpError>)> = vec![
-        ("matrix_multiply", perform_matrix_operation as fn() -> Result<(), UmicpError>),
-        ("vector_add", perform_vector_operation as fn() -> Result<(), UmicpError>),
-        ("envelope_create", perform_envelope_operation as fn() -> Result<(), UmicpError>),
-        ("dot_product", perform_dot_product_operation as fn() -> Result<(), UmicpError>),
+    let operations: Vec<(&str, Operation)> = vec![
+        ("matrix_multiply", perform_matrix_operation as Operation),
+        ("vector_add", perform_vector_operation as Operation),
+        ("envelope_create", perform_envelope_operation as Operation),
+        ("dot_product", perform_dot_product_operation as Operation),
     ];
 
     let mut errors = Vec::new();
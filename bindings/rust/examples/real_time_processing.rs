@@ -41,6 +41,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 struct StreamingProcessor {
     buffer: Mutex<VecDeque<Envelope>>,
     batch_size: usize,
+    #[allow(dead_code)]
     flush_interval: Duration,
     matrix: Matrix,
     processed_count: Mutex<u64>,
@@ -130,8 +131,8 @@ impl StreamingProcessor {
 
         // Create test data for processing
         let mut data = vec![0.0f32; size];
-        for i in 0..size {
-            data[i] = (i as f32 * 0.1).sin();
+        for (i, value) in data.iter_mut().enumerate() {
+            *value = (i as f32 * 0.1).sin();
         }
 
         // Perform matrix operations
@@ -170,6 +171,7 @@ impl StreamingProcessor {
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 struct ProcessorStats {
     buffer_size: usize,
     batch_size: usize,
@@ -255,36 +257,19 @@ impl AnalyticsEngine {
         }
 
         // Basic statistics
-        let sum: f64 = data.iter().map(|&x| x as f64).sum();
-        let mean = sum / data.len() as f64;
-        results.insert("mean".to_string(), mean);
-
-        let variance: f64 = data.iter()
-            .map(|&x| {
-                let diff = x as f64 - mean;
-                diff * diff
-            })
-            .sum::<f64>() / data.len() as f64;
-        results.insert("variance".to_string(), variance);
-        results.insert("std_dev".to_string(), variance.sqrt());
+        let stat = |result: umicp_core::MatrixResult| result.result.unwrap_or(0.0);
+        results.insert("mean".to_string(), stat(self.matrix.mean(data)?));
+        results.insert("variance".to_string(), stat(self.matrix.variance(data)?));
+        results.insert("std_dev".to_string(), stat(self.matrix.std_dev(data)?));
 
         // Find min/max
-        let min = data.iter().fold(f32::INFINITY, |a, &b| a.min(b)) as f64;
-        let max = data.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b)) as f64;
-        results.insert("min".to_string(), min);
-        results.insert("max".to_string(), max);
+        results.insert("min".to_string(), stat(self.matrix.percentile(data, 0.0)?));
+        results.insert("max".to_string(), stat(self.matrix.percentile(data, 100.0)?));
 
-        // Percentiles (simplified)
-        let mut sorted_data: Vec<f32> = data.to_vec();
-        sorted_data.sort_by(|a, b| a.partial_cmp(b).unwrap());
-
-        let p25_idx = (sorted_data.len() as f64 * 0.25) as usize;
-        let p75_idx = (sorted_data.len() as f64 * 0.75) as usize;
-        let p95_idx = (sorted_data.len() as f64 * 0.95) as usize;
-
-        results.insert("p25".to_string(), sorted_data.get(p25_idx).copied().unwrap_or(0.0) as f64);
-        results.insert("p75".to_string(), sorted_data.get(p75_idx).copied().unwrap_or(0.0) as f64);
-        results.insert("p95".to_string(), sorted_data.get(p95_idx).copied().unwrap_or(0.0) as f64);
+        // Percentiles
+        results.insert("p25".to_string(), stat(self.matrix.percentile(data, 25.0)?));
+        results.insert("p75".to_string(), stat(self.matrix.percentile(data, 75.0)?));
+        results.insert("p95".to_string(), stat(self.matrix.percentile(data, 95.0)?));
 
         Ok(results)
     }
@@ -305,7 +290,7 @@ fn demonstrate_load_balancing() -> Result<(), Box<dyn std::error::Error>> {
         let processing_time = 10 + (task_id % 20); // 10-30ms variation
         thread::sleep(Duration::from_millis(processing_time as u64));
 
-        worker_stats[worker_id as usize] += 1;
+        worker_stats[worker_id] += 1;
 
         if task_id % 20 == 0 {
         }
@@ -314,11 +299,13 @@ fn demonstrate_load_balancing() -> Result<(), Box<dyn std::error::Error>> {
     // Show load balancing results
     for (i, &tasks) in worker_stats.iter().enumerate() {
         let percentage = (tasks as f32 / total_tasks as f32) * 100.0;
+        println!("Worker {}: {} tasks ({:.1}%)", i, tasks, percentage);
     }
 
     let max_load = worker_stats.iter().max().unwrap();
     let min_load = worker_stats.iter().min().unwrap();
     let load_imbalance = (*max_load as f32 - *min_load as f32) / *max_load as f32 * 100.0;
+    println!("Load imbalance: {:.1}%", load_imbalance);
 
 
     Ok(())
@@ -340,7 +327,7 @@ impl PerformanceMonitor {
     fn record_timing(&self, operation: &str, duration: Duration) {
         let mut metrics = self.metrics.lock().unwrap();
         metrics.entry(operation.to_string())
-            .or_insert_with(Vec::new)
+            .or_default()
             .push(duration);
     }
 
@@ -379,6 +366,7 @@ impl PerformanceMonitor {
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 struct PerformanceStats {
     count: usize,
     total_time: Duration,
@@ -416,6 +404,7 @@ fn demonstrate_streaming_processor(processor: Arc<StreamingProcessor>) -> Result
 
     // Show statistics
     let stats = processor.get_stats();
+    println!("Processor stats: {:?}", stats);
 
     Ok(())
 }
@@ -433,6 +422,7 @@ fn demonstrate_real_time_analytics(analytics: Arc<AnalyticsEngine>) -> Result<()
 
         // Perform statistical analysis
         let analysis = analytics.analyze_data(&data)?;
+        println!("{}: mean={:.2}, std_dev={:.2}, min={:.2}, max={:.2}",
                  stream_name,
                  analysis["mean"],
                  analysis["std_dev"],
@@ -443,6 +433,7 @@ fn demonstrate_real_time_analytics(analytics: Arc<AnalyticsEngine>) -> Result<()
     // Show analytics summary
     let summary = analytics.get_summary();
     for (key, value) in summary {
+        println!("{}: {}", key, value);
     }
 
     Ok(())
@@ -480,6 +471,7 @@ fn demonstrate_performance_monitoring(monitor: PerformanceMonitor) -> Result<(),
     let stats = monitor.get_stats();
 
     for (operation, stat) in stats {
+        println!("{}: {:?}", operation, stat);
     }
 
     Ok(())
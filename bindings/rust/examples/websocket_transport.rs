@@ -8,7 +8,13 @@ real-time communication with UMICP envelopes.
 // WebSocket transport example - requires websocket feature
 
 #[cfg(feature = "websocket")]
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "websocket")]
+use umicp_core::{Envelope, OperationType, WebSocketTransport};
+
+#[cfg(feature = "websocket")]
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("UMICP Rust Bindings - WebSocket Transport Example");
     println!("================================================\n");
 
@@ -30,20 +36,25 @@ async fn run_server() -> Result<(), Box<dyn std::error::Error>> {
     println!("Starting WebSocket server...");
 
     // Create server transport
-    let mut server = WebSocketTransport::new_server("127.0.0.1:8080").await?;
+    let server = WebSocketTransport::new_server("127.0.0.1:8080").await?;
 
     // Message counter
     let message_count = Arc::new(Mutex::new(0));
 
     // Set message handler
     let message_count_clone = Arc::clone(&message_count);
+    let responder = server.clone();
     server.set_message_handler(move |envelope: Envelope, conn_id: String| {
         let message_count = Arc::clone(&message_count_clone);
+        let responder = responder.clone();
         async move {
-            let mut count = message_count.lock().await;
-            *count += 1;
+            let count = {
+                let mut count = message_count.lock().unwrap();
+                *count += 1;
+                *count
+            };
 
-            println!("📨 Received message #{} from {}", *count, conn_id);
+            println!("📨 Received message #{} from {}", count, conn_id);
             println!("   From: {}", envelope.from());
             println!("   Operation: {:?}", envelope.operation());
             println!("   Message ID: {}", envelope.message_id());
@@ -60,13 +71,14 @@ async fn run_server() -> Result<(), Box<dyn std::error::Error>> {
                 .from("server")
                 .to(envelope.from())
                 .operation(OperationType::Ack)
-                .message_id(format!("ack-{}", envelope.message_id()))
+                .message_id(&uuid::Uuid::new_v4().to_string())
                 .capability("status", "received")
+                .capability("ack_for", envelope.message_id())
                 .capability("server_time", &chrono::Utc::now().to_rfc3339())
                 .build()?;
 
             // Send response
-            server.send(response, &conn_id).await?;
+            responder.send(response, &conn_id).await?;
             println!("   ✓ Sent acknowledgment\n");
 
             Ok(())
@@ -96,7 +108,7 @@ async fn run_client() -> Result<(), Box<dyn std::error::Error>> {
     println!("Starting WebSocket client...");
 
     // Create client transport
-    let mut client = WebSocketTransport::new_client("ws://127.0.0.1:8080").await?;
+    let client = WebSocketTransport::new_client("ws://127.0.0.1:8080").await?;
 
     // Message counter
     let message_count = Arc::new(Mutex::new(0));
@@ -106,10 +118,13 @@ async fn run_client() -> Result<(), Box<dyn std::error::Error>> {
     client.set_message_handler(move |envelope: Envelope, _conn_id: String| {
         let message_count = Arc::clone(&message_count_clone);
         async move {
-            let mut count = message_count.lock().await;
-            *count += 1;
+            let count = {
+                let mut count = message_count.lock().unwrap();
+                *count += 1;
+                *count
+            };
 
-            println!("📬 Received response #{} from server", *count);
+            println!("📬 Received response #{} from server", count);
             println!("   From: {}", envelope.from());
             println!("   Operation: {:?}", envelope.operation());
             println!("   Message ID: {}", envelope.message_id());
@@ -155,7 +170,7 @@ async fn run_client() -> Result<(), Box<dyn std::error::Error>> {
             .from("rust-client")
             .to("server")
             .operation(OperationType::Data)
-            .message_id(format!("test-msg-{}", i))
+            .message_id(&uuid::Uuid::new_v4().to_string())
             .capability("message_type", "test")
             .capability("sequence", &i.to_string())
             .capability("timestamp", &chrono::Utc::now().to_rfc3339())
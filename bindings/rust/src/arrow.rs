@@ -0,0 +1,162 @@
+/*!
+# UMICP Arrow Interop
+
+Conversions between UMICP data and Apache Arrow arrays (requires the `arrow` feature), for
+handing tensors and envelope batches to DataFusion/Polars style analytics pipelines.
+
+A [`Tensor`] maps to a `FixedSizeList<Float32>` whose list size is the innermost dimension.
+Moving a tensor into Arrow reuses its allocation; reading values back out of a list array
+borrows the Arrow buffer.
+
+A batch of envelopes maps to a [`RecordBatch`] with one row per envelope. The header fields
+get their own columns for querying, and the `envelope` column carries the full serialized
+envelope so the conversion round-trips losslessly.
+*/
+
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::Tensor;
+use arrow_array::{Array, ArrayRef, FixedSizeListArray, Float32Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use std::sync::Arc;
+
+/// Name of the column holding the serialized envelope
+pub const ENVELOPE_COLUMN: &str = "envelope";
+
+/// Move a tensor into a `FixedSizeList<Float32>` array without copying its elements
+///
+/// Each list holds one innermost row; a rank-1 tensor becomes a single list.
+pub fn tensor_to_fixed_size_list(tensor: Tensor) -> Result<FixedSizeListArray> {
+    let size = match tensor.shape.last() {
+        Some(&size) if size > 0 => size,
+        _ => return Err(UmicpError::validation("Tensor must have a non-empty innermost dimension")),
+    };
+    let size = i32::try_from(size)
+        .map_err(|_| UmicpError::validation(format!("Innermost dimension {} exceeds Arrow list size", size)))?;
+
+    let values = Arc::new(Float32Array::from(tensor.data));
+    let field = Arc::new(Field::new("item", DataType::Float32, false));
+    FixedSizeListArray::try_new(field, size, values, None)
+        .map_err(|e| UmicpError::serialization(format!("Arrow conversion failed: {}", e)))
+}
+
+/// Borrow the flat values behind a `FixedSizeList<Float32>` array
+pub fn fixed_size_list_values(array: &FixedSizeListArray) -> Result<&[f32]> {
+    if array.null_count() > 0 {
+        return Err(UmicpError::validation("Fixed-size list array contains null lists"));
+    }
+    let values = array
+        .values()
+        .as_any()
+        .downcast_ref::<Float32Array>()
+        .ok_or_else(|| UmicpError::validation(format!("Expected Float32 list values, got {}", array.value_type())))?;
+    if values.null_count() > 0 {
+        return Err(UmicpError::validation("Fixed-size list values contain nulls"));
+    }
+
+    let size = array.value_length() as usize;
+    let start = array.offset() * size;
+    Ok(&values.values()[start..start + array.len() * size])
+}
+
+/// Copy a `FixedSizeList<Float32>` array into a `[lists, list_size]` tensor
+pub fn fixed_size_list_to_tensor(array: &FixedSizeListArray) -> Result<Tensor> {
+    let values = fixed_size_list_values(array)?;
+    Tensor::new(vec![array.len(), array.value_length() as usize], values.to_vec())
+}
+
+/// Build a record batch with one row per envelope
+pub fn envelopes_to_record_batch(envelopes: &[Envelope]) -> Result<RecordBatch> {
+    let column = |f: fn(&Envelope) -> &str| -> ArrayRef {
+        Arc::new(StringArray::from_iter_values(envelopes.iter().map(f)))
+    };
+    let operations: ArrayRef = Arc::new(StringArray::from_iter_values(
+        envelopes.iter().map(|e| e.operation().to_string()),
+    ));
+    let serialized = envelopes.iter().map(|e| e.serialize()).collect::<Result<Vec<_>>>()?;
+
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("message_id", DataType::Utf8, false),
+        Field::new("timestamp", DataType::Utf8, false),
+        Field::new("from", DataType::Utf8, false),
+        Field::new("to", DataType::Utf8, false),
+        Field::new("operation", DataType::Utf8, false),
+        Field::new(ENVELOPE_COLUMN, DataType::Utf8, false),
+    ]));
+
+    RecordBatch::try_new(
+        schema,
+        vec![
+            column(Envelope::message_id),
+            column(Envelope::timestamp),
+            column(Envelope::from),
+            column(Envelope::to),
+            operations,
+            Arc::new(StringArray::from(serialized)),
+        ],
+    )
+    .map_err(|e| UmicpError::serialization(format!("Arrow conversion failed: {}", e)))
+}
+
+/// Rebuild envelopes from a record batch produced by [`envelopes_to_record_batch`]
+pub fn record_batch_to_envelopes(batch: &RecordBatch) -> Result<Vec<Envelope>> {
+    let column = batch
+        .column_by_name(ENVELOPE_COLUMN)
+        .ok_or_else(|| UmicpError::validation(format!("Record batch has no '{}' column", ENVELOPE_COLUMN)))?
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or_else(|| UmicpError::validation(format!("Column '{}' must be Utf8", ENVELOPE_COLUMN)))?;
+
+    column
+        .iter()
+        .map(|json| {
+            let json = json.ok_or_else(|| UmicpError::validation("Null envelope in record batch"))?;
+            Envelope::deserialize(json)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::OperationType;
+
+    #[test]
+    fn test_tensor_fixed_size_list_round_trip() {
+        let tensor = Tensor::new(vec![3, 2], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]).unwrap();
+        let array = tensor_to_fixed_size_list(tensor.clone()).unwrap();
+        assert_eq!(array.len(), 3);
+        assert_eq!(array.value_length(), 2);
+        assert_eq!(fixed_size_list_to_tensor(&array).unwrap(), tensor);
+
+        // Slicing keeps the borrowed values aligned with the visible lists
+        let tail = array.slice(1, 2);
+        assert_eq!(fixed_size_list_values(&tail).unwrap(), &[3.0, 4.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn test_envelope_record_batch_round_trip() {
+        let envelopes: Vec<Envelope> = (0..3)
+            .map(|i| {
+                Envelope::builder()
+                    .from("node-a")
+                    .to(&format!("node-{}", i))
+                    .operation(OperationType::Data)
+                    .message_id(&uuid::Uuid::new_v4().to_string())
+                    .capability("shard", &i.to_string())
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+
+        let batch = envelopes_to_record_batch(&envelopes).unwrap();
+        assert_eq!(batch.num_rows(), 3);
+        let to = batch.column_by_name("to").unwrap().as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(to.value(2), "node-2");
+
+        let restored = record_batch_to_envelopes(&batch).unwrap();
+        for (original, restored) in envelopes.iter().zip(&restored) {
+            assert_eq!(original.serialize().unwrap(), restored.serialize().unwrap());
+        }
+    }
+}
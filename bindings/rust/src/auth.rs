@@ -0,0 +1,332 @@
+/*!
+# UMICP Authentication
+
+Token-based authentication of connections.
+
+Servers hold a [`TokenValidator`] that turns a bearer token into the authenticated [`Principal`].
+[`JwtValidator`] checks HS256-signed JWTs: the signature, `exp` and `nbf` (with some leeway for
+clock skew), and optionally `iss` and `aud`. With the `oidc` feature, `oidc::OidcValidator`
+checks tokens from an OpenID Connect provider against its published keys. Other schemes plug in
+by implementing the trait.
+
+Clients present their token either in the `Authorization: Bearer` header of the connection
+upgrade or, when they cannot set headers, as the first envelope on the connection: a `Control`
+envelope carrying the token in its [`AUTH_CAPABILITY`].
+
+Once a connection is up, an [`Authorizer`] decides per envelope whether its sender may perform
+the operation, e.g. to keep read-only clients from sending `Data`. Closures taking the principal
+and envelope implement the trait.
+
+```rust
+use umicp_core::auth::{sign_hs256, JwtValidator, TokenValidator};
+
+let validator = JwtValidator::hs256(b"shared secret").issuer("umicp-auth");
+let token = sign_hs256(
+    &serde_json::json!({ "sub": "worker-7", "iss": "umicp-auth", "exp": 4102444800u64 }),
+    b"shared secret",
+).unwrap();
+assert_eq!(validator.validate(&token).unwrap().subject, "worker-7");
+```
+*/
+
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::secret::Secret;
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::Sha256;
+use std::time::Duration;
+
+/// Capability carrying the token of an authentication envelope
+pub const AUTH_CAPABILITY: &str = "auth";
+
+/// Authenticated identity behind a connection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Principal {
+    /// Who the token was issued to (the JWT `sub` claim)
+    pub subject: String,
+    /// Every claim of the token, `sub` included
+    #[serde(default)]
+    pub claims: Map<String, Value>,
+}
+
+impl Principal {
+    /// Principal with no claims beyond its subject
+    pub fn new(subject: &str) -> Self {
+        let mut claims = Map::new();
+        claims.insert("sub".to_string(), Value::String(subject.to_string()));
+        Principal {
+            subject: subject.to_string(),
+            claims,
+        }
+    }
+
+    /// Value of a claim
+    pub fn claim(&self, name: &str) -> Option<&Value> {
+        self.claims.get(name)
+    }
+}
+
+/// Checks bearer tokens presented by connecting clients
+///
+/// Runs on the connection's handshake, so it should not block for long; validators that need
+/// remote key material should fetch and cache it in the background.
+pub trait TokenValidator: Send + Sync {
+    /// Principal the token authenticates, or an authentication error
+    fn validate(&self, token: &str) -> Result<Principal>;
+}
+
+/// Decides whether an envelope may be dispatched
+///
+/// Called for every envelope a connection sends, before any handler or subscriber sees it, with
+/// the principal the connection authenticated as (`None` when it did not). Returning an error,
+/// usually [`UmicpError::Forbidden`], drops the envelope and answers it with an `Error` reply.
+pub trait Authorizer: Send + Sync {
+    /// `Ok` to let the envelope through
+    fn authorize(&self, principal: Option<&Principal>, envelope: &Envelope) -> Result<()>;
+
+    /// Key under which the decision for `envelope` may be reused for later envelopes on the same
+    /// connection, or `None` (the default) to decide every envelope afresh
+    ///
+    /// Envelopes with equal keys must get the same decision from the same principal, so only
+    /// authorizers whose decisions depend on nothing else, such as the time, should return one.
+    fn decision_key(&self, _envelope: &Envelope) -> Option<String> {
+        None
+    }
+}
+
+impl<F> Authorizer for F
+where
+    F: Fn(Option<&Principal>, &Envelope) -> Result<()> + Send + Sync,
+{
+    fn authorize(&self, principal: Option<&Principal>, envelope: &Envelope) -> Result<()> {
+        self(principal, envelope)
+    }
+}
+
+/// What the claims of a token must satisfy, whoever signed it
+#[derive(Debug, Clone)]
+pub(crate) struct ClaimRules {
+    pub(crate) issuer: Option<String>,
+    pub(crate) audience: Option<String>,
+    pub(crate) leeway: Duration,
+    pub(crate) require_expiry: bool,
+}
+
+impl Default for ClaimRules {
+    /// 60s of leeway and `exp` required
+    fn default() -> Self {
+        ClaimRules {
+            issuer: None,
+            audience: None,
+            leeway: Duration::from_secs(60),
+            require_expiry: true,
+        }
+    }
+}
+
+impl ClaimRules {
+    pub(crate) fn check(&self, claims: &Map<String, Value>) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        let leeway = self.leeway.as_secs() as i64;
+        let time = |name: &str| -> Result<Option<i64>> {
+            match claims.get(name) {
+                None => Ok(None),
+                Some(value) => value
+                    .as_i64()
+                    .or_else(|| value.as_f64().map(|seconds| seconds as i64))
+                    .map(Some)
+                    .ok_or_else(|| UmicpError::authentication(format!("Claim `{}` is not a timestamp", name))),
+            }
+        };
+
+        match time("exp")? {
+            Some(expiry) if now > expiry + leeway => return Err(UmicpError::authentication("Token has expired")),
+            None if self.require_expiry => return Err(UmicpError::authentication("Token has no `exp` claim")),
+            _ => {}
+        }
+        if time("nbf")?.is_some_and(|not_before| now + leeway < not_before) {
+            return Err(UmicpError::authentication("Token is not valid yet"));
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+                return Err(UmicpError::authentication("Token has the wrong issuer"));
+            }
+        }
+        if let Some(audience) = &self.audience {
+            let matches = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !matches {
+                return Err(UmicpError::authentication("Token is not meant for this audience"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A JWT split into its parts, its signature not yet checked
+pub(crate) struct DecodedJwt<'a> {
+    pub(crate) header: Map<String, Value>,
+    pub(crate) claims: Map<String, Value>,
+    /// The encoded header and payload, which the signature covers
+    pub(crate) signing_input: &'a str,
+    pub(crate) signature: Vec<u8>,
+}
+
+impl<'a> DecodedJwt<'a> {
+    pub(crate) fn decode(token: &'a str) -> Result<Self> {
+        let malformed = || UmicpError::authentication("Malformed JWT");
+        let mut parts = token.split('.');
+        let (header, payload, signature) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(payload), Some(signature), None) => (header, payload, signature),
+            _ => return Err(malformed()),
+        };
+        Ok(DecodedJwt {
+            header: serde_json::from_slice(&decode_segment(header)?).map_err(|_| malformed())?,
+            claims: serde_json::from_slice(&decode_segment(payload)?).map_err(|_| malformed())?,
+            signing_input: &token[..token.len() - signature.len() - 1],
+            signature: decode_segment(signature)?,
+        })
+    }
+
+    /// The `alg` header
+    pub(crate) fn algorithm(&self) -> &str {
+        self.header.get("alg").and_then(Value::as_str).unwrap_or_default()
+    }
+
+    /// The principal the claims name, once the signature and claims are checked
+    pub(crate) fn into_principal(self) -> Result<Principal> {
+        let subject = self
+            .claims
+            .get("sub")
+            .and_then(Value::as_str)
+            .ok_or_else(|| UmicpError::authentication("Token has no `sub` claim"))?
+            .to_string();
+        Ok(Principal {
+            subject,
+            claims: self.claims,
+        })
+    }
+}
+
+/// Validator for JWTs signed with HMAC-SHA256
+#[derive(Debug, Clone)]
+pub struct JwtValidator {
+    secret: Secret<Vec<u8>>,
+    rules: ClaimRules,
+}
+
+impl JwtValidator {
+    /// Accept tokens signed with `secret`, with 60s of leeway and `exp` required
+    pub fn hs256(secret: &[u8]) -> Self {
+        JwtValidator {
+            secret: Secret::new(secret.to_vec()),
+            rules: ClaimRules::default(),
+        }
+    }
+
+    /// Require the `iss` claim to equal `issuer`
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.rules.issuer = Some(issuer.to_string());
+        self
+    }
+
+    /// Require the `aud` claim to be, or to contain, `audience`
+    pub fn audience(mut self, audience: &str) -> Self {
+        self.rules.audience = Some(audience.to_string());
+        self
+    }
+
+    /// Tolerated clock skew when checking `exp` and `nbf`
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.rules.leeway = leeway;
+        self
+    }
+
+    /// Whether tokens without an `exp` claim are rejected
+    pub fn require_expiry(mut self, required: bool) -> Self {
+        self.rules.require_expiry = required;
+        self
+    }
+}
+
+impl TokenValidator for JwtValidator {
+    fn validate(&self, token: &str) -> Result<Principal> {
+        let jwt = DecodedJwt::decode(token)?;
+        // Checked before the signature so `alg: none` tokens are never accepted
+        if jwt.algorithm() != "HS256" {
+            return Err(UmicpError::authentication("Unsupported JWT algorithm; expected HS256"));
+        }
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.expose()).expect("HMAC accepts keys of any length");
+        mac.update(jwt.signing_input.as_bytes());
+        mac.verify(&jwt.signature)
+            .map_err(|_| UmicpError::authentication("Invalid JWT signature"))?;
+        self.rules.check(&jwt.claims)?;
+        jwt.into_principal()
+    }
+}
+
+/// Issue an HS256 JWT carrying `claims`, e.g. for tests or a small token service
+pub fn sign_hs256(claims: &Value, secret: &[u8]) -> Result<String> {
+    let header = encode_segment(br#"{"alg":"HS256","typ":"JWT"}"#);
+    let payload = encode_segment(&serde_json::to_vec(claims)?);
+    let signing_input = format!("{}.{}", header, payload);
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(signing_input.as_bytes());
+    Ok(format!("{}.{}", signing_input, encode_segment(&mac.finalize().into_bytes())))
+}
+
+fn encode_segment(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_segment(segment: &str) -> Result<Vec<u8>> {
+    base64::decode_config(segment, base64::URL_SAFE_NO_PAD).map_err(|_| UmicpError::authentication("Malformed JWT"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SECRET: &[u8] = b"test secret";
+
+    fn in_an_hour() -> i64 {
+        chrono::Utc::now().timestamp() + 3600
+    }
+
+    #[test]
+    fn test_jwt_validation() {
+        let validator = JwtValidator::hs256(SECRET).issuer("issuer").audience("umicp");
+        let claims = json!({
+            "sub": "alice", "iss": "issuer", "aud": ["other", "umicp"], "exp": in_an_hour(), "role": "reader"
+        });
+        let principal = validator.validate(&sign_hs256(&claims, SECRET).unwrap()).unwrap();
+        assert_eq!(principal.subject, "alice");
+        assert_eq!(principal.claim("role"), Some(&json!("reader")));
+
+        let rejected = |claims: Value, secret: &[u8]| {
+            validator.validate(&sign_hs256(&claims, secret).unwrap()).is_err()
+        };
+        assert!(rejected(claims.clone(), b"wrong secret"));
+        assert!(rejected(json!({ "sub": "alice", "iss": "issuer", "aud": "umicp", "exp": 1 }), SECRET));
+        assert!(rejected(json!({ "sub": "alice", "iss": "issuer", "aud": "umicp" }), SECRET));
+        assert!(rejected(json!({ "sub": "alice", "iss": "other", "aud": "umicp", "exp": in_an_hour() }), SECRET));
+        assert!(rejected(json!({ "sub": "alice", "iss": "issuer", "aud": "web", "exp": in_an_hour() }), SECRET));
+        assert!(rejected(
+            json!({ "sub": "alice", "iss": "issuer", "aud": "umicp", "exp": in_an_hour(), "nbf": in_an_hour() }),
+            SECRET
+        ));
+        assert!(rejected(json!({ "iss": "issuer", "aud": "umicp", "exp": in_an_hour() }), SECRET));
+
+        // Unsigned tokens and garbage never pass
+        let header = encode_segment(br#"{"alg":"none"}"#);
+        let unsigned = format!("{}.{}.", header, encode_segment(claims.to_string().as_bytes()));
+        assert!(validator.validate(&unsigned).is_err());
+        assert!(validator.validate("not-a-jwt").is_err());
+    }
+}
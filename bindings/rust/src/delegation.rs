@@ -0,0 +1,371 @@
+/*!
+# UMICP Delegation Tokens
+
+Macaroon-style bearer tokens granting scoped rights, such as "may send `Data` to `metrics.*`
+until noon", for handing a narrow slice of a coordinator's authority to workers without an
+account for each.
+
+A coordinator [`mint`](DelegationToken::mint)s a token from a root key only it and the servers
+know. Whoever holds a token can [`attenuate`](DelegationToken::attenuate) it with further
+[`Caveat`]s before passing it on, without the root key; caveats can only narrow what a token
+allows, never widen it. The signature is an HMAC-SHA256 chain: the root key signs the token ID,
+and each caveat is signed with the previous signature, so removing or altering a caveat breaks
+the chain.
+
+Clients attach a token to each envelope in its [`DELEGATION_CAPABILITY`]. A server installs a
+[`DelegationAuthorizer`] holding the root key as its [`Authorizer`], which checks the chain and
+every caveat against the envelope before it is dispatched.
+
+```rust
+use umicp_core::delegation::{Caveat, DelegationAuthorizer, DelegationToken, DELEGATION_CAPABILITY};
+use umicp_core::auth::Authorizer;
+use umicp_core::{Envelope, OperationType};
+
+# fn main() -> umicp_core::Result<()> {
+let root_key = b"coordinator root key";
+let token = DelegationToken::mint(root_key, "workers")
+    .attenuate(Caveat::Operations(vec![OperationType::Data]))
+    .attenuate(Caveat::Destinations(vec!["metrics.*".to_string()]));
+
+let mut envelope = Envelope::builder().from("worker-1").to("metrics.cpu").operation(OperationType::Data).build()?;
+envelope.add_capability(DELEGATION_CAPABILITY, &token.encode());
+DelegationAuthorizer::new(root_key).authorize(None, &envelope)?;
+# Ok(())
+# }
+```
+*/
+
+use crate::auth::{Authorizer, Principal};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::secret::Secret;
+use crate::types::OperationType;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// Capability carrying an envelope's delegation token
+pub const DELEGATION_CAPABILITY: &str = "delegation";
+
+/// Restriction a token places on the envelopes it authorizes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Caveat {
+    /// Only envelopes with one of these operations
+    Operations(Vec<OperationType>),
+    /// Only envelopes addressed to one of these destinations; a trailing `*` matches any suffix
+    Destinations(Vec<String>),
+    /// Only until this time
+    Expires(DateTime<Utc>),
+    /// Only for this sender: the principal the connection authenticated as, or else the
+    /// envelope's `from`
+    Subject(String),
+    /// Only envelopes carrying capability `name` with `value`
+    Capability {
+        /// Capability name
+        name: String,
+        /// Required value
+        value: String,
+    },
+}
+
+impl Caveat {
+    /// Whether `envelope`, sent by `principal`, satisfies the caveat at `now`
+    fn check(&self, principal: Option<&Principal>, envelope: &Envelope, now: DateTime<Utc>) -> Result<()> {
+        let allowed = match self {
+            Caveat::Operations(operations) => operations.contains(&envelope.operation()),
+            Caveat::Destinations(destinations) => destinations.iter().any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => envelope.to().starts_with(prefix),
+                None => envelope.to() == pattern,
+            }),
+            Caveat::Expires(expiry) => now <= *expiry,
+            Caveat::Subject(subject) => principal.map_or(envelope.from(), |principal| &principal.subject) == subject,
+            Caveat::Capability { name, value } => {
+                envelope.capabilities().and_then(|capabilities| capabilities.get(name)) == Some(value)
+            }
+        };
+        match allowed {
+            true => Ok(()),
+            false => Err(UmicpError::forbidden(format!("Delegation token does not allow this: {}", self))),
+        }
+    }
+}
+
+/// Caveats are signed and sent in this form, e.g. `op = data,request` or `to = metrics.*`
+impl std::fmt::Display for Caveat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Caveat::Operations(operations) => {
+                let names: Vec<String> = operations.iter().map(ToString::to_string).collect();
+                write!(f, "op = {}", names.join(","))
+            }
+            Caveat::Destinations(destinations) => write!(f, "to = {}", destinations.join(",")),
+            Caveat::Expires(expiry) => write!(f, "expires = {}", expiry.to_rfc3339()),
+            Caveat::Subject(subject) => write!(f, "sub = {}", subject),
+            Caveat::Capability { name, value } => write!(f, "cap {} = {}", name, value),
+        }
+    }
+}
+
+impl std::str::FromStr for Caveat {
+    type Err = UmicpError;
+
+    fn from_str(caveat: &str) -> Result<Self> {
+        let unknown = || UmicpError::authentication(format!("Unknown caveat: {}", caveat));
+        let (key, value) = caveat.split_once(" = ").ok_or_else(unknown)?;
+        let list = || value.split(',').map(str::to_string).collect::<Vec<_>>();
+        match key {
+            "op" => Ok(Caveat::Operations(value.split(',').map(str::parse).collect::<Result<_>>()?)),
+            "to" => Ok(Caveat::Destinations(list())),
+            "expires" => DateTime::parse_from_rfc3339(value)
+                .map(|expiry| Caveat::Expires(expiry.with_timezone(&Utc)))
+                .map_err(|_| unknown()),
+            "sub" => Ok(Caveat::Subject(value.to_string())),
+            _ => match key.strip_prefix("cap ") {
+                Some(name) => Ok(Caveat::Capability {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                }),
+                None => Err(unknown()),
+            },
+        }
+    }
+}
+
+/// A token granting whatever its caveats together allow
+#[derive(Clone, PartialEq, Eq)]
+pub struct DelegationToken {
+    id: String,
+    caveats: Vec<Caveat>,
+    signature: Vec<u8>,
+}
+
+/// Wire form of a token, base64url-encoded JSON
+#[derive(Serialize, Deserialize)]
+struct EncodedToken {
+    id: String,
+    caveats: Vec<String>,
+    sig: String,
+}
+
+impl DelegationToken {
+    /// New token with no caveats, signed with `root_key`; `id` names it, e.g. for revocation
+    ///
+    /// Without caveats the token allows everything, so it is normally attenuated before being
+    /// handed out.
+    pub fn mint(root_key: &[u8], id: &str) -> Self {
+        DelegationToken {
+            id: id.to_string(),
+            caveats: Vec::new(),
+            signature: sign(root_key, id.as_bytes()),
+        }
+    }
+
+    /// The token restricted further by `caveat`; needs no root key
+    pub fn attenuate(mut self, caveat: Caveat) -> Self {
+        self.signature = sign(&self.signature, caveat.to_string().as_bytes());
+        self.caveats.push(caveat);
+        self
+    }
+
+    /// Token ID given when it was minted
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Caveats, in the order they were added
+    pub fn caveats(&self) -> &[Caveat] {
+        &self.caveats
+    }
+
+    /// Check that the token descends from `root_key` with its caveats unchanged
+    pub fn verify(&self, root_key: &[u8]) -> Result<()> {
+        let Some((last, earlier)) = self.caveats.split_last() else {
+            return check_signature(root_key, self.id.as_bytes(), &self.signature);
+        };
+        let key = earlier
+            .iter()
+            .fold(sign(root_key, self.id.as_bytes()), |key, caveat| sign(&key, caveat.to_string().as_bytes()));
+        check_signature(&key, last.to_string().as_bytes(), &self.signature)
+    }
+
+    /// Check the token against `root_key`, then every caveat against `envelope`
+    pub fn authorize(&self, root_key: &[u8], principal: Option<&Principal>, envelope: &Envelope) -> Result<()> {
+        self.verify(root_key)?;
+        let now = Utc::now();
+        self.caveats
+            .iter()
+            .try_for_each(|caveat| caveat.check(principal, envelope, now))
+    }
+
+    /// Text form, for [`DELEGATION_CAPABILITY`]
+    pub fn encode(&self) -> String {
+        let encoded = EncodedToken {
+            id: self.id.clone(),
+            caveats: self.caveats.iter().map(ToString::to_string).collect(),
+            sig: base64::encode_config(&self.signature, base64::URL_SAFE_NO_PAD),
+        };
+        let json = serde_json::to_vec(&encoded).expect("tokens serialize");
+        base64::encode_config(json, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Parse the text form made by [`encode`](Self::encode); does not check the signature
+    pub fn decode(token: &str) -> Result<Self> {
+        let malformed = || UmicpError::authentication("Malformed delegation token");
+        let json = base64::decode_config(token, base64::URL_SAFE_NO_PAD).map_err(|_| malformed())?;
+        let encoded: EncodedToken = serde_json::from_slice(&json).map_err(|_| malformed())?;
+        Ok(DelegationToken {
+            id: encoded.id,
+            caveats: encoded.caveats.iter().map(|caveat| caveat.parse()).collect::<Result<_>>()?,
+            signature: base64::decode_config(&encoded.sig, base64::URL_SAFE_NO_PAD).map_err(|_| malformed())?,
+        })
+    }
+}
+
+impl std::fmt::Debug for DelegationToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The signature is what grants the rights, so it stays out of logs
+        f.debug_struct("DelegationToken")
+            .field("id", &self.id)
+            .field("caveats", &self.caveats)
+            .finish()
+    }
+}
+
+/// [`Authorizer`] admitting envelopes whose [`DELEGATION_CAPABILITY`] holds a token that
+/// allows them
+///
+/// Envelopes without a token are rejected, except for operations
+/// [`exempt`](Self::exempt)ed from needing one.
+#[derive(Debug, Clone)]
+pub struct DelegationAuthorizer {
+    root_key: Secret<Vec<u8>>,
+    exempt: Vec<OperationType>,
+    revoked: Vec<String>,
+}
+
+impl DelegationAuthorizer {
+    /// Accept tokens minted with `root_key`
+    pub fn new(root_key: &[u8]) -> Self {
+        DelegationAuthorizer {
+            root_key: Secret::new(root_key.to_vec()),
+            exempt: Vec::new(),
+            revoked: Vec::new(),
+        }
+    }
+
+    /// Let envelopes with `operation` through without a token, e.g. `Ack`
+    pub fn exempt(mut self, operation: OperationType) -> Self {
+        self.exempt.push(operation);
+        self
+    }
+
+    /// Reject tokens minted with ID `id`, and everything attenuated from them
+    pub fn revoke(mut self, id: &str) -> Self {
+        self.revoked.push(id.to_string());
+        self
+    }
+}
+
+impl Authorizer for DelegationAuthorizer {
+    fn authorize(&self, principal: Option<&Principal>, envelope: &Envelope) -> Result<()> {
+        let token = envelope.capabilities().and_then(|capabilities| capabilities.get(DELEGATION_CAPABILITY));
+        let Some(token) = token else {
+            return match self.exempt.contains(&envelope.operation()) {
+                true => Ok(()),
+                false => Err(UmicpError::forbidden("Envelope carries no delegation token")),
+            };
+        };
+        let token = DelegationToken::decode(token)?;
+        if self.revoked.contains(&token.id) {
+            return Err(UmicpError::forbidden(format!("Delegation token {} was revoked", token.id)));
+        }
+        token.authorize(self.root_key.expose(), principal, envelope)
+    }
+}
+
+fn sign(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Compare in constant time, so the signature cannot be guessed byte by byte
+fn check_signature(key: &[u8], data: &[u8], signature: &[u8]) -> Result<()> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.verify(signature)
+        .map_err(|_| UmicpError::authentication("Invalid delegation token signature"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT_KEY: &[u8] = b"root key";
+
+    fn envelope(operation: OperationType, to: &str, token: &DelegationToken) -> Envelope {
+        let mut envelope = Envelope::builder().from("worker").to(to).operation(operation).build().unwrap();
+        envelope.add_capability(DELEGATION_CAPABILITY, &token.encode());
+        envelope
+    }
+
+    #[test]
+    fn test_delegation_attenuation_and_verification() {
+        let coordinator = DelegationToken::mint(ROOT_KEY, "fleet")
+            .attenuate(Caveat::Operations(vec![OperationType::Data, OperationType::Request]))
+            .attenuate(Caveat::Expires(Utc::now() + chrono::Duration::hours(1)));
+        // A holder narrows it further without the root key
+        let worker = coordinator.clone().attenuate(Caveat::Destinations(vec!["metrics/*".to_string()]));
+        let authorizer = DelegationAuthorizer::new(ROOT_KEY).exempt(OperationType::Ack);
+
+        assert!(authorizer.authorize(None, &envelope(OperationType::Data, "metrics/cpu", &worker)).is_ok());
+        assert!(authorizer.authorize(None, &envelope(OperationType::Data, "logs", &coordinator)).is_ok());
+        let denied = authorizer.authorize(None, &envelope(OperationType::Data, "logs", &worker)).unwrap_err();
+        assert!(matches!(denied, UmicpError::Forbidden { .. }), "{}", denied);
+        assert!(authorizer.authorize(None, &envelope(OperationType::Control, "metrics/cpu", &worker)).is_err());
+
+        // Dropping a caveat, a wrong root key and an expired caveat all fail
+        let mut widened = DelegationToken::decode(&worker.encode()).unwrap();
+        assert_eq!(widened, worker);
+        widened.caveats.pop();
+        assert!(widened.verify(ROOT_KEY).is_err());
+        assert!(worker.verify(b"other key").is_err());
+        let expired = worker.clone().attenuate(Caveat::Expires(Utc::now() - chrono::Duration::seconds(1)));
+        assert!(authorizer.authorize(None, &envelope(OperationType::Data, "metrics/cpu", &expired)).is_err());
+
+        // Subject caveats bind to the authenticated principal when there is one
+        let bound = worker.clone().attenuate(Caveat::Subject("alice".to_string()));
+        let to_metrics = envelope(OperationType::Data, "metrics/cpu", &bound);
+        assert!(authorizer.authorize(Some(&Principal::new("alice")), &to_metrics).is_ok());
+        assert!(authorizer.authorize(Some(&Principal::new("mallory")), &to_metrics).is_err());
+        assert!(authorizer.authorize(None, &to_metrics).is_err());
+
+        // Exempt operations pass without a token; revoked tokens never do
+        let ack = Envelope::builder().from("worker").to("server").operation(OperationType::Ack).build().unwrap();
+        assert!(authorizer.authorize(None, &ack).is_ok());
+        let data = Envelope::builder().from("worker").to("server").operation(OperationType::Data).build().unwrap();
+        assert!(authorizer.authorize(None, &data).is_err());
+        let revoked = DelegationAuthorizer::new(ROOT_KEY).revoke("fleet");
+        assert!(revoked.authorize(None, &envelope(OperationType::Data, "metrics/cpu", &worker)).is_err());
+    }
+
+    #[test]
+    fn test_caveat_text_round_trip() {
+        let caveats = [
+            Caveat::Operations(vec![OperationType::Data, OperationType::Ack]),
+            Caveat::Destinations(vec!["a".to_string(), "b/*".to_string()]),
+            Caveat::Expires(DateTime::parse_from_rfc3339("2030-01-01T00:00:00Z").unwrap().with_timezone(&Utc)),
+            Caveat::Subject("alice".to_string()),
+            Caveat::Capability {
+                name: "tenant".to_string(),
+                value: "acme".to_string(),
+            },
+        ];
+        for caveat in caveats {
+            assert_eq!(caveat.to_string().parse::<Caveat>().unwrap(), caveat);
+        }
+        assert!("colour = blue".parse::<Caveat>().is_err());
+        assert!("op = fly".parse::<Caveat>().is_err());
+    }
+}
@@ -0,0 +1,279 @@
+/*!
+# UMICP Model Deltas
+
+Parameter deltas relative to a base model, so clients upload what changed in training instead of
+the full weights.
+
+A [`ModelDelta`] holds the parameters that changed and the [`model_hash`] of the base it applies
+to; [`apply`](ModelDelta::apply) refuses any other base, so an update trained from a stale global
+model is caught instead of being averaged into the new one. On the wire a delta is a `Data`
+envelope naming its base in the [`DELTA_BASE_CAPABILITY`]. Its payload is either dense, every
+change as little-endian `f32`, or sparse, `(u32 index, f32 change)` pairs with the parameter
+count in the [`DELTA_DIMENSION_CAPABILITY`]; [`to_envelope`](ModelDelta::to_envelope) picks the
+smaller, as the [`DELTA_ENCODING_CAPABILITY`] records.
+
+A [`DeltaEncoder`] sends only changes of at least its threshold and carries the rest over as a
+residual added to the next delta, so small changes accumulate until they are worth sending
+instead of being lost.
+
+```rust
+use umicp_core::delta::{DeltaEncoder, ModelDelta};
+
+let base = vec![1.0, 1.0, 1.0, 1.0];
+let trained = vec![1.5, 1.0, 1.01, 1.0];
+let mut encoder = DeltaEncoder::new(0.1);
+let delta = encoder.encode(&base, &trained)?;
+assert_eq!(delta.changes, vec![(0, 0.5)]);
+
+let received = ModelDelta::from_envelope(&delta.to_envelope("worker-1", "aggregator")?)?;
+assert_eq!(received.apply(&base)?, vec![1.5, 1.0, 1.0, 1.0]);
+# Ok::<(), umicp_core::UmicpError>(())
+```
+*/
+
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::registry::model_hash;
+use crate::types::OperationType;
+use crate::utils::constant_time_eq;
+
+/// Capability holding the [`model_hash`] of the base a delta applies to
+pub const DELTA_BASE_CAPABILITY: &str = "delta_base";
+
+/// Capability naming a delta's payload layout: `dense` or `sparse`
+pub const DELTA_ENCODING_CAPABILITY: &str = "delta_encoding";
+
+/// Capability of a sparse delta holding the number of parameters of the model
+pub const DELTA_DIMENSION_CAPABILITY: &str = "delta_dimension";
+
+const DENSE: &str = "dense";
+const SPARSE: &str = "sparse";
+
+/// Whether `envelope` carries a delta rather than full weights
+pub fn is_delta(envelope: &Envelope) -> bool {
+    envelope
+        .capabilities()
+        .is_some_and(|capabilities| capabilities.contains_key(DELTA_BASE_CAPABILITY))
+}
+
+/// Changes to a model relative to a base version
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelDelta {
+    /// [`model_hash`] of the base
+    pub base_hash: String,
+    /// Number of parameters of the model
+    pub dimension: usize,
+    /// Parameters that changed, as `(index, change)` by ascending index
+    pub changes: Vec<(u32, f32)>,
+}
+
+impl ModelDelta {
+    /// Delta turning `base` into `weights`
+    pub fn compute(base: &[f32], weights: &[f32]) -> Result<Self> {
+        check_dimension(base, weights.len())?;
+        let changes = base
+            .iter()
+            .zip(weights)
+            .enumerate()
+            .map(|(index, (base, weight))| (index as u32, weight - base))
+            .filter(|(_, change)| *change != 0.0)
+            .collect();
+        Ok(ModelDelta {
+            base_hash: model_hash(base),
+            dimension: base.len(),
+            changes,
+        })
+    }
+
+    /// `base` with the changes applied, refused unless `base` is the version the delta was
+    /// computed against
+    pub fn apply(&self, base: &[f32]) -> Result<Vec<f32>> {
+        if !constant_time_eq(model_hash(base).as_bytes(), self.base_hash.as_bytes()) {
+            return Err(UmicpError::validation("Delta was computed against another base model"));
+        }
+        check_dimension(base, self.dimension)?;
+        let mut weights = base.to_vec();
+        for (index, change) in &self.changes {
+            weights[*index as usize] += change;
+        }
+        if weights.iter().any(|weight| !weight.is_finite()) {
+            return Err(UmicpError::validation("Delta makes weights non-finite"));
+        }
+        Ok(weights)
+    }
+
+    /// Envelope carrying the delta from `from` to `to`, sparse when that is smaller
+    pub fn to_envelope(&self, from: &str, to: &str) -> Result<Envelope> {
+        let sparse = self.changes.len() * 2 < self.dimension;
+        let builder = Envelope::builder()
+            .from(from)
+            .to(to)
+            .operation(OperationType::Data)
+            .capability(DELTA_BASE_CAPABILITY, &self.base_hash);
+        let (builder, payload) = match sparse {
+            true => {
+                let payload = self
+                    .changes
+                    .iter()
+                    .flat_map(|(index, change)| index.to_le_bytes().into_iter().chain(change.to_le_bytes()))
+                    .collect();
+                let builder = builder
+                    .capability(DELTA_ENCODING_CAPABILITY, SPARSE)
+                    .capability(DELTA_DIMENSION_CAPABILITY, &self.dimension.to_string());
+                (builder, payload)
+            }
+            false => {
+                let mut dense = vec![0.0f32; self.dimension];
+                for (index, change) in &self.changes {
+                    dense[*index as usize] = *change;
+                }
+                let payload = dense.iter().flat_map(|change| change.to_le_bytes()).collect();
+                (builder.capability(DELTA_ENCODING_CAPABILITY, DENSE), payload)
+            }
+        };
+        builder.payload(payload).build()
+    }
+
+    /// Delta carried by `envelope`
+    pub fn from_envelope(envelope: &Envelope) -> Result<Self> {
+        let capability = |key| envelope.capabilities().and_then(|capabilities| capabilities.get(key));
+        let invalid = |what: &str| UmicpError::validation(format!("Delta from {} {}", envelope.from(), what));
+        let base_hash = capability(DELTA_BASE_CAPABILITY).ok_or_else(|| invalid("names no base"))?.clone();
+        let payload = envelope.payload().unwrap_or_default();
+        let words: Vec<[u8; 4]> = match payload.len().is_multiple_of(4) {
+            true => payload.chunks_exact(4).map(|word| [word[0], word[1], word[2], word[3]]).collect(),
+            false => return Err(invalid("is not a whole number of 4-byte values")),
+        };
+        let (dimension, changes) = match capability(DELTA_ENCODING_CAPABILITY).map(String::as_str) {
+            Some(DENSE) => {
+                let changes = words
+                    .iter()
+                    .enumerate()
+                    .map(|(index, word)| (index as u32, f32::from_le_bytes(*word)))
+                    .filter(|(_, change)| *change != 0.0)
+                    .collect();
+                (words.len(), changes)
+            }
+            Some(SPARSE) => {
+                let dimension = capability(DELTA_DIMENSION_CAPABILITY)
+                    .and_then(|dimension| dimension.parse::<usize>().ok())
+                    .ok_or_else(|| invalid("has no parameter count"))?;
+                if !words.len().is_multiple_of(2) {
+                    return Err(invalid("has an index without a change"));
+                }
+                let changes: Vec<(u32, f32)> = words
+                    .chunks_exact(2)
+                    .map(|pair| (u32::from_le_bytes(pair[0]), f32::from_le_bytes(pair[1])))
+                    .collect();
+                let ascending = changes.windows(2).all(|pair| pair[0].0 < pair[1].0);
+                if !ascending || changes.last().is_some_and(|(index, _)| *index as usize >= dimension) {
+                    return Err(invalid("has indices out of order or out of range"));
+                }
+                (dimension, changes)
+            }
+            other => return Err(invalid(&format!("has an unknown encoding: {:?}", other))),
+        };
+        Ok(ModelDelta {
+            base_hash,
+            dimension,
+            changes,
+        })
+    }
+}
+
+fn check_dimension(base: &[f32], dimension: usize) -> Result<()> {
+    match base.len() == dimension {
+        true => Ok(()),
+        false => Err(UmicpError::validation(format!(
+            "Delta has {} parameters, the base model {}",
+            dimension,
+            base.len()
+        ))),
+    }
+}
+
+/// Computes deltas that skip small changes, carrying them over until they add up
+#[derive(Debug, Clone, Default)]
+pub struct DeltaEncoder {
+    threshold: f32,
+    residual: Vec<f32>,
+}
+
+impl DeltaEncoder {
+    /// Encoder sending changes whose magnitude is at least `threshold`; 0 sends every change
+    pub fn new(threshold: f32) -> Self {
+        DeltaEncoder {
+            threshold,
+            residual: Vec::new(),
+        }
+    }
+
+    /// Changes held back so far, by parameter
+    pub fn residual(&self) -> &[f32] {
+        &self.residual
+    }
+
+    /// Delta from `base` towards `weights` plus the residual, keeping the changes too small to
+    /// send as the new residual
+    pub fn encode(&mut self, base: &[f32], weights: &[f32]) -> Result<ModelDelta> {
+        let mut delta = ModelDelta::compute(base, weights)?;
+        if self.residual.len() != base.len() {
+            self.residual = vec![0.0; base.len()];
+        }
+        for (index, change) in delta.changes.drain(..) {
+            self.residual[index as usize] += change;
+        }
+        for (index, pending) in self.residual.iter_mut().enumerate() {
+            if *pending != 0.0 && pending.abs() >= self.threshold {
+                delta.changes.push((index as u32, *pending));
+                *pending = 0.0;
+            }
+        }
+        Ok(delta)
+    }
+
+    /// Forget the residual, e.g. when the base model is replaced rather than updated
+    pub fn reset(&mut self) {
+        self.residual.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_round_trip() {
+        let base: Vec<f32> = (0..8).map(|i| i as f32).collect();
+        let mut weights = base.clone();
+        weights[3] += 2.0;
+        let delta = ModelDelta::compute(&base, &weights).unwrap();
+        let sparse = delta.to_envelope("worker", "aggregator").unwrap();
+        assert_eq!(sparse.capabilities().unwrap()[DELTA_ENCODING_CAPABILITY], SPARSE);
+        assert_eq!(sparse.payload().unwrap().len(), 8);
+        let received = Envelope::deserialize(&sparse.serialize().unwrap()).unwrap();
+        assert!(is_delta(&received));
+        assert_eq!(ModelDelta::from_envelope(&received).unwrap().apply(&base).unwrap(), weights);
+
+        // Dense once most parameters change
+        let shifted: Vec<f32> = base.iter().map(|weight| weight + 1.0).collect();
+        let dense = ModelDelta::compute(&base, &shifted).unwrap().to_envelope("worker", "aggregator").unwrap();
+        assert_eq!(dense.capabilities().unwrap()[DELTA_ENCODING_CAPABILITY], DENSE);
+        assert_eq!(ModelDelta::from_envelope(&dense).unwrap().apply(&base).unwrap(), shifted);
+
+        // A delta against another base is refused
+        assert!(matches!(delta.apply(&shifted), Err(UmicpError::Validation { .. })));
+        let mut out_of_range = sparse.clone();
+        out_of_range.add_capability(DELTA_DIMENSION_CAPABILITY, "3");
+        assert!(ModelDelta::from_envelope(&out_of_range).is_err());
+
+        // Changes under the threshold wait in the residual until they add up
+        let mut encoder = DeltaEncoder::new(0.5);
+        let nudged: Vec<f32> = base.iter().map(|weight| weight + 0.3).collect();
+        assert!(encoder.encode(&base, &nudged).unwrap().changes.is_empty());
+        assert_eq!(encoder.residual()[0], 0.3);
+        let delta = encoder.encode(&base, &nudged).unwrap();
+        assert_eq!(delta.changes.len(), 8);
+        assert!(encoder.residual().iter().all(|pending| *pending == 0.0));
+    }
+}
@@ -0,0 +1,524 @@
+/*!
+# UMICP Model Distribution
+
+Sending model checkpoints to clients as verified chunks over a WebSocket connection, resuming
+where a dropped connection left off.
+
+A [`ModelDistributor`] splits each checkpoint it holds into fixed-size chunks and describes it
+with a [`ChunkManifest`]: its size, the SHA-256 hash of every chunk and of the whole checkpoint.
+Clients ask for a checkpoint with a `fetch` request naming it in the [`CHECKPOINT_CAPABILITY`]
+and the first chunk they need in the [`RESUME_CAPABILITY`]. The distributor answers with the
+manifest, then streams the checkpoint from that chunk on with
+[`send_stream`](WebSocketTransport::send_stream), the manifest travelling in the transfer's header.
+
+A [`ModelDownload`] keeps only chunks that match their hash. When the connection drops mid-way,
+the next [`download`](ModelDownload::download) asks for the first chunk it is missing; when the
+checkpoint has changed in the meantime, it starts over. Once every chunk is in, the whole
+checkpoint is checked against the manifest before [`checkpoint`](ModelDownload::checkpoint)
+hands it out.
+
+```rust,no_run
+use std::time::Duration;
+use umicp_core::distribution::{ModelDistributor, ModelDownload, DEFAULT_CHUNK_SIZE};
+use umicp_core::{Transport, WebSocketTransport};
+
+# async fn example(checkpoint: Vec<u8>) -> umicp_core::Result<()> {
+// On the server
+let server = WebSocketTransport::new_server("0.0.0.0:8080").await?;
+server.connect().await?;
+let distributor = ModelDistributor::new("coordinator", server, DEFAULT_CHUNK_SIZE);
+distributor.add_checkpoint("mnist", checkpoint);
+distributor.start();
+
+// On each client, retrying after a dropped connection resumes the download
+let client = WebSocketTransport::new_client("ws://coordinator:8080").await?;
+let mut streams = client.incoming_streams();
+client.connect().await?;
+let mut download = ModelDownload::new("worker-001", "mnist");
+while let Err(error) = download.download(&client, &mut streams, "coordinator", Duration::from_secs(30)).await {
+    eprintln!("Download interrupted at chunk {}: {}", download.next_chunk(), error);
+}
+let checkpoint = download.checkpoint().unwrap();
+# Ok(())
+# }
+```
+*/
+
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::transport::{serve_requests_until, CancellationToken, IncomingStream, WebSocketTransport};
+use crate::types::OperationType;
+use crate::utils::{constant_time_eq, generate_hash};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Capability naming the distribution message: a `fetch` request or the `chunks` transfer
+pub const DISTRIBUTION_CAPABILITY: &str = "model_distribution";
+
+/// Capability naming the checkpoint fetched
+pub const CHECKPOINT_CAPABILITY: &str = "checkpoint";
+
+/// Capability holding the hash of the checkpoint a resumed fetch continues
+pub const CHECKPOINT_HASH_CAPABILITY: &str = "checkpoint_hash";
+
+/// Capability holding the first chunk fetched, or carried by a transfer
+pub const RESUME_CAPABILITY: &str = "resume_from";
+
+/// Capability carrying a checkpoint's [`ChunkManifest`] as JSON
+pub const MANIFEST_CAPABILITY: &str = "manifest";
+
+/// Chunk size used unless another is given
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+const FETCH: &str = "fetch";
+const CHUNKS: &str = "chunks";
+
+/// Layout and hashes of a checkpoint split into chunks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkManifest {
+    /// Checkpoint name
+    pub name: String,
+    /// Checkpoint size in bytes
+    pub size: u64,
+    /// Bytes per chunk; only the last chunk may be shorter
+    pub chunk_size: u64,
+    /// SHA-256 of each chunk, in order
+    pub chunks: Vec<String>,
+    /// SHA-256 of the whole checkpoint
+    pub hash: String,
+}
+
+impl ChunkManifest {
+    /// Manifest of `checkpoint` split into `chunk_size`-byte chunks
+    pub fn new(name: &str, checkpoint: &[u8], chunk_size: usize) -> Self {
+        let chunk_size = chunk_size.max(1);
+        ChunkManifest {
+            name: name.to_string(),
+            size: checkpoint.len() as u64,
+            chunk_size: chunk_size as u64,
+            chunks: checkpoint.chunks(chunk_size).map(generate_hash).collect(),
+            hash: generate_hash(checkpoint),
+        }
+    }
+
+    /// Number of chunks
+    pub fn chunk_count(&self) -> u64 {
+        self.chunks.len() as u64
+    }
+
+    /// Length of chunk `index`
+    pub fn chunk_len(&self, index: u64) -> u64 {
+        self.chunk_size.min(self.size.saturating_sub(index * self.chunk_size))
+    }
+
+    /// Check chunk `index` against its hash
+    pub fn verify_chunk(&self, index: u64, chunk: &[u8]) -> Result<()> {
+        let expected = self
+            .chunks
+            .get(index as usize)
+            .ok_or_else(|| UmicpError::validation(format!("{} has no chunk {}", self.name, index)))?;
+        match constant_time_eq(generate_hash(chunk).as_bytes(), expected.as_bytes()) {
+            true => Ok(()),
+            false => Err(UmicpError::validation(format!("Chunk {} of {} does not match its hash", index, self.name))),
+        }
+    }
+
+    /// Whether the manifest describes a checkpoint it could be the manifest of
+    fn validate(&self) -> Result<()> {
+        let expected = match self.chunk_size {
+            0 => None,
+            chunk_size => Some(self.size.div_ceil(chunk_size)),
+        };
+        match expected == Some(self.chunk_count()) {
+            true => Ok(()),
+            false => Err(UmicpError::validation(format!("Inconsistent manifest for {}", self.name))),
+        }
+    }
+}
+
+fn capability<'a>(envelope: &'a Envelope, key: &str) -> Option<&'a str> {
+    envelope.capabilities()?.get(key).map(String::as_str)
+}
+
+fn manifest_of(envelope: &Envelope) -> Result<ChunkManifest> {
+    let json = capability(envelope, MANIFEST_CAPABILITY)
+        .ok_or_else(|| UmicpError::validation(format!("Message from {} carries no manifest", envelope.from())))?;
+    let manifest: ChunkManifest = serde_json::from_str(json)
+        .map_err(|e| UmicpError::serialization(format!("Invalid manifest from {}: {}", envelope.from(), e)))?;
+    manifest.validate()?;
+    Ok(manifest)
+}
+
+fn first_chunk(envelope: &Envelope) -> Result<u64> {
+    match capability(envelope, RESUME_CAPABILITY) {
+        None => Ok(0),
+        Some(chunk) => chunk
+            .parse()
+            .map_err(|_| UmicpError::validation(format!("Invalid chunk index from {}: {}", envelope.from(), chunk))),
+    }
+}
+
+/// A checkpoint served and its manifest
+type Checkpoint = (ChunkManifest, Arc<[u8]>);
+
+struct Shared {
+    id: String,
+    transport: WebSocketTransport,
+    chunk_size: usize,
+    checkpoints: RwLock<HashMap<String, Checkpoint>>,
+    cancel: RwLock<CancellationToken>,
+}
+
+/// Serves checkpoints to clients as verified, resumable chunked transfers
+#[derive(Clone)]
+pub struct ModelDistributor {
+    shared: Arc<Shared>,
+}
+
+impl ModelDistributor {
+    /// Distributor sending as `id` over `transport`, splitting checkpoints into `chunk_size` bytes
+    pub fn new(id: &str, transport: WebSocketTransport, chunk_size: usize) -> Self {
+        ModelDistributor {
+            shared: Arc::new(Shared {
+                id: id.to_string(),
+                transport,
+                chunk_size: chunk_size.max(1),
+                checkpoints: RwLock::new(HashMap::new()),
+                cancel: RwLock::new(CancellationToken::new()),
+            }),
+        }
+    }
+
+    /// Serve `checkpoint` as `name`, replacing any checkpoint served under that name
+    ///
+    /// Downloads of the replaced checkpoint start over on their next fetch.
+    pub fn add_checkpoint(&self, name: &str, checkpoint: Vec<u8>) -> ChunkManifest {
+        let manifest = ChunkManifest::new(name, &checkpoint, self.shared.chunk_size);
+        let entry = (manifest.clone(), checkpoint.into());
+        self.shared.checkpoints.write().unwrap().insert(name.to_string(), entry);
+        manifest
+    }
+
+    /// Stop serving `name`
+    pub fn remove_checkpoint(&self, name: &str) {
+        self.shared.checkpoints.write().unwrap().remove(name);
+    }
+
+    /// Manifest of the checkpoint served as `name`
+    pub fn manifest(&self, name: &str) -> Option<ChunkManifest> {
+        self.shared.checkpoints.read().unwrap().get(name).map(|(manifest, _)| manifest.clone())
+    }
+
+    /// Answer fetch requests; runs until [`stop`](ModelDistributor::stop)
+    pub fn start(&self) {
+        let cancel = CancellationToken::new();
+        *self.shared.cancel.write().unwrap() = cancel.clone();
+        let distributor = self.clone();
+        let transport = Arc::new(self.shared.transport.clone());
+        serve_requests_until(transport, cancel, move |request, conn_id| {
+            let distributor = distributor.clone();
+            async move { distributor.fetch(&request, &conn_id) }
+        });
+    }
+
+    /// Stop answering fetch requests and abort the transfers in progress
+    pub fn stop(&self) {
+        self.shared.cancel.read().unwrap().cancel();
+    }
+
+    /// Stream checkpoint `name` to `connection_id` from chunk `first_chunk` on, unasked
+    ///
+    /// Returns the number of bytes sent.
+    pub async fn send_checkpoint(&self, name: &str, connection_id: &str, to: &str, first_chunk: u64) -> Result<u64> {
+        let header = Envelope::builder().from(&self.shared.id).to(to).operation(OperationType::Data).build()?;
+        self.send(header, name, connection_id, first_chunk).await
+    }
+
+    /// Reply to a fetch request with the manifest, and start streaming the chunks asked for
+    fn fetch(&self, request: &Envelope, connection_id: &str) -> Result<Envelope> {
+        if capability(request, DISTRIBUTION_CAPABILITY) != Some(FETCH) {
+            return Err(UmicpError::validation(format!("Request from {} is not a fetch", request.from())));
+        }
+        let name = capability(request, CHECKPOINT_CAPABILITY)
+            .ok_or_else(|| UmicpError::validation(format!("Fetch from {} names no checkpoint", request.from())))?;
+        let manifest = self
+            .manifest(name)
+            .ok_or_else(|| UmicpError::validation(format!("No checkpoint named {}", name)))?;
+        // A download of an older checkpoint cannot be resumed
+        let first_chunk = match capability(request, CHECKPOINT_HASH_CAPABILITY) {
+            Some(hash) if hash == manifest.hash => first_chunk(request)?,
+            _ => 0,
+        };
+        if first_chunk > manifest.chunk_count() {
+            return Err(UmicpError::validation(format!("{} has only {} chunks", name, manifest.chunk_count())));
+        }
+
+        let mut header = request.reply(OperationType::Data);
+        header.set_from(&self.shared.id);
+        let distributor = self.clone();
+        let (name, connection_id) = (name.to_string(), connection_id.to_string());
+        tokio::spawn(async move {
+            let _ = distributor.send(header, &name, &connection_id, first_chunk).await;
+        });
+
+        let mut reply = request.reply(OperationType::Response);
+        reply.add_capability(MANIFEST_CAPABILITY, &serialize(&manifest)?);
+        reply.add_capability(RESUME_CAPABILITY, &first_chunk.to_string());
+        Ok(reply)
+    }
+
+    async fn send(&self, mut header: Envelope, name: &str, connection_id: &str, first_chunk: u64) -> Result<u64> {
+        let (manifest, checkpoint) = self
+            .shared
+            .checkpoints
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| UmicpError::validation(format!("No checkpoint named {}", name)))?;
+        let offset = (first_chunk * manifest.chunk_size).min(manifest.size) as usize;
+        header.add_capability(DISTRIBUTION_CAPABILITY, CHUNKS);
+        header.add_capability(MANIFEST_CAPABILITY, &serialize(&manifest)?);
+        header.add_capability(RESUME_CAPABILITY, &first_chunk.to_string());
+        let cancel = self.shared.cancel.read().unwrap().clone();
+        let transport = &self.shared.transport;
+        transport.send_stream_cancellable(header, &checkpoint[offset..], connection_id, &cancel).await
+    }
+}
+
+fn serialize(manifest: &ChunkManifest) -> Result<String> {
+    serde_json::to_string(manifest)
+        .map_err(|e| UmicpError::serialization(format!("Failed to serialize manifest: {}", e)))
+}
+
+/// A checkpoint being downloaded, kept across dropped connections
+#[derive(Debug, Clone)]
+pub struct ModelDownload {
+    client: String,
+    name: String,
+    manifest: Option<ChunkManifest>,
+    /// Verified chunks, in order
+    data: Vec<u8>,
+    next_chunk: u64,
+    complete: bool,
+}
+
+impl ModelDownload {
+    /// Download of checkpoint `name` by `client`
+    pub fn new(client: &str, name: &str) -> Self {
+        ModelDownload {
+            client: client.to_string(),
+            name: name.to_string(),
+            manifest: None,
+            data: Vec::new(),
+            next_chunk: 0,
+            complete: false,
+        }
+    }
+
+    /// Manifest of the checkpoint, once the distributor has sent it
+    pub fn manifest(&self) -> Option<&ChunkManifest> {
+        self.manifest.as_ref()
+    }
+
+    /// Index of the first chunk not yet received
+    pub fn next_chunk(&self) -> u64 {
+        self.next_chunk
+    }
+
+    /// Verified bytes received so far
+    pub fn received(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// The whole checkpoint, once downloaded and verified
+    pub fn checkpoint(&self) -> Option<&[u8]> {
+        self.complete.then_some(self.data.as_slice())
+    }
+
+    /// Fetch request to `to`, resuming from the first missing chunk
+    pub fn request(&self, to: &str) -> Result<Envelope> {
+        let mut builder = Envelope::builder()
+            .from(&self.client)
+            .to(to)
+            .operation(OperationType::Request)
+            .capability(DISTRIBUTION_CAPABILITY, FETCH)
+            .capability(CHECKPOINT_CAPABILITY, &self.name);
+        if let Some(manifest) = &self.manifest {
+            builder = builder
+                .capability(CHECKPOINT_HASH_CAPABILITY, &manifest.hash)
+                .capability(RESUME_CAPABILITY, &self.next_chunk.to_string());
+        }
+        builder.build()
+    }
+
+    /// Fetch the rest of the checkpoint from `to` over `transport`, whose transfers arrive on
+    /// `streams`
+    ///
+    /// Waits up to `timeout` for the reply and for the transfer to start. On failure the chunks
+    /// received so far are kept, and calling again once connected resumes after them.
+    pub async fn download(
+        &mut self,
+        transport: &WebSocketTransport,
+        streams: &mut mpsc::UnboundedReceiver<IncomingStream>,
+        to: &str,
+        timeout: Duration,
+    ) -> Result<()> {
+        if self.complete {
+            return Ok(());
+        }
+        let request = self.request(to)?;
+        let message_id = request.message_id().to_string();
+        let reply = crate::transport::Transport::request(transport, request, timeout).await?;
+        self.resume(&manifest_of(&reply)?, first_chunk(&reply)?)?;
+
+        let started = async {
+            loop {
+                let stream = streams.recv().await.ok_or_else(|| UmicpError::connection("Transport closed"))?;
+                // Transfers of earlier, abandoned fetches are dropped
+                if stream.header.correlation_id() == Some(message_id.as_str()) {
+                    return Ok::<_, UmicpError>(stream);
+                }
+            }
+        };
+        let mut stream = tokio::time::timeout(timeout, started).await.map_err(|_| {
+            UmicpError::timeout(format!("Transfer of {} did not start within {:?}", self.name, timeout))
+        })??;
+        self.receive(&mut stream).await
+    }
+
+    /// Receive a transfer of the checkpoint, keeping every chunk that matches its hash
+    pub async fn receive(&mut self, stream: &mut IncomingStream) -> Result<()> {
+        if capability(&stream.header, DISTRIBUTION_CAPABILITY) != Some(CHUNKS) {
+            return Err(UmicpError::validation("Transfer does not carry checkpoint chunks"));
+        }
+        self.resume(&manifest_of(&stream.header)?, first_chunk(&stream.header)?)?;
+        let mut pending = Vec::new();
+        while let Some(chunk) = stream.next_chunk().await {
+            pending.extend_from_slice(&chunk?);
+            pending = self.accept(pending)?;
+        }
+        self.finish(&pending)
+    }
+
+    /// Adopt `manifest`, starting over if it describes another checkpoint, for a transfer
+    /// starting at `first_chunk`
+    fn resume(&mut self, manifest: &ChunkManifest, first_chunk: u64) -> Result<()> {
+        if manifest.name != self.name {
+            return Err(UmicpError::validation(format!("Expected {}, received {}", self.name, manifest.name)));
+        }
+        if self.manifest.as_ref() != Some(manifest) {
+            *self = ModelDownload::new(&self.client, &self.name);
+            self.manifest = Some(manifest.clone());
+        }
+        match first_chunk == self.next_chunk {
+            true => Ok(()),
+            false => Err(UmicpError::validation(format!(
+                "Transfer of {} starts at chunk {}, expected {}",
+                self.name, first_chunk, self.next_chunk
+            ))),
+        }
+    }
+
+    /// Keep every whole chunk at the start of `pending`, returning the bytes left over
+    fn accept(&mut self, mut pending: Vec<u8>) -> Result<Vec<u8>> {
+        let manifest = self.manifest.as_ref().expect("manifest adopted before chunks arrive");
+        let mut start = 0;
+        while self.next_chunk < manifest.chunk_count() {
+            let len = manifest.chunk_len(self.next_chunk) as usize;
+            let Some(chunk) = pending.get(start..start + len) else {
+                break;
+            };
+            manifest.verify_chunk(self.next_chunk, chunk)?;
+            self.data.extend_from_slice(chunk);
+            self.next_chunk += 1;
+            start += len;
+        }
+        if self.next_chunk == manifest.chunk_count() && start < pending.len() {
+            return Err(UmicpError::validation(format!("Transfer of {} is longer than its manifest", self.name)));
+        }
+        pending.drain(..start);
+        Ok(pending)
+    }
+
+    /// Check the complete checkpoint once the transfer has ended with `pending` bytes left over
+    fn finish(&mut self, pending: &[u8]) -> Result<()> {
+        let manifest = self.manifest.as_ref().expect("manifest adopted before the transfer ends");
+        if !pending.is_empty() || self.next_chunk < manifest.chunk_count() {
+            return Err(UmicpError::validation(format!(
+                "Transfer of {} ended at chunk {} of {}",
+                self.name,
+                self.next_chunk,
+                manifest.chunk_count()
+            )));
+        }
+        if !constant_time_eq(generate_hash(&self.data).as_bytes(), manifest.hash.as_bytes()) {
+            // Every chunk matched, so the manifest itself is wrong: nothing is worth resuming
+            *self = ModelDownload::new(&self.client, &self.name);
+            return Err(UmicpError::validation(format!("{} does not match its manifest", self.name)));
+        }
+        self.complete = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::Transport;
+
+    #[tokio::test]
+    async fn test_resumable_checkpoint_download() {
+        let checkpoint: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        Transport::connect(&server).await.unwrap();
+        let distributor = ModelDistributor::new("coordinator", server.clone(), 1024);
+        let manifest = distributor.add_checkpoint("mnist", checkpoint.clone());
+        assert_eq!((manifest.chunk_count(), manifest.chunk_len(9)), (10, 784));
+        distributor.start();
+
+        // A connection that dropped after three and a half chunks resumes at the fourth
+        let mut download = ModelDownload::new("worker", "mnist");
+        download.resume(&manifest, 0).unwrap();
+        let left = download.accept(checkpoint[..3584].to_vec()).unwrap();
+        assert_eq!((download.next_chunk(), left.len()), (3, 512));
+        assert!(download.finish(&left).is_err());
+        assert!(download.checkpoint().is_none());
+        let request = download.request("coordinator").unwrap();
+        assert_eq!(capability(&request, RESUME_CAPABILITY), Some("3"));
+
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let client = WebSocketTransport::new_client(&url).await.unwrap();
+        let mut streams = client.incoming_streams();
+        Transport::connect(&client).await.unwrap();
+        let timeout = Duration::from_secs(5);
+        download.download(&client, &mut streams, "coordinator", timeout).await.unwrap();
+        assert_eq!(download.checkpoint(), Some(checkpoint.as_slice()));
+
+        // A corrupted chunk is rejected, keeping the chunks before it
+        let mut corrupted = checkpoint.clone();
+        corrupted[2500] ^= 1;
+        let mut download = ModelDownload::new("worker", "mnist");
+        download.resume(&manifest, 0).unwrap();
+        assert!(download.accept(corrupted).is_err());
+        assert_eq!(download.received(), 2048);
+
+        // A replaced checkpoint is downloaded from the start
+        let replaced = distributor.add_checkpoint("mnist", vec![7; 1500]);
+        download.download(&client, &mut streams, "coordinator", timeout).await.unwrap();
+        assert_eq!(download.manifest(), Some(&replaced));
+        assert_eq!(download.checkpoint(), Some(&[7; 1500][..]));
+
+        let error = ModelDownload::new("worker", "cifar")
+            .download(&client, &mut streams, "coordinator", timeout)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, UmicpError::Remote { .. }), "{}", error);
+        distributor.stop();
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+}
@@ -5,11 +5,15 @@ Type-safe message container with JSON serialization for UMICP protocol.
 */
 
 use crate::error::{Result, UmicpError};
+use crate::limits::{envelope_limits, EnvelopeLimits};
 use crate::types::*;
 use crate::utils::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+/// Capability setting an envelope's [`MessagePriority`] (`"low"`, `"normal"` or `"high"`)
+pub const PRIORITY_CAPABILITY: &str = "priority";
+
 /// Internal envelope structure for JSON serialization
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct EnvelopeData {
@@ -17,7 +21,8 @@ struct EnvelopeData {
     v: String,
     /// Message ID (UUID)
     msg_id: String,
-    /// Timestamp in ISO 8601 format
+    /// Timestamp in ISO 8601 format, left out when the sender omits it
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     ts: String,
     /// Sender identifier
     from: String,
@@ -25,6 +30,9 @@ struct EnvelopeData {
     to: String,
     /// Operation type
     op: String,
+    /// ID of the message this one answers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    corr_id: Option<String>,
     /// Optional capabilities (metadata)
     #[serde(skip_serializing_if = "Option::is_none")]
     capabilities: Option<HashMap<String, String>>,
@@ -40,6 +48,9 @@ struct EnvelopeData {
     /// Optional payload references
     #[serde(skip_serializing_if = "Option::is_none")]
     payload_refs: Option<Vec<HashMap<String, String>>>,
+    /// Optional raw payload, base64-encoded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<String>,
 }
 
 /// Payload hint structure for JSON serialization
@@ -74,6 +85,8 @@ pub struct Envelope {
     to: String,
     /// Operation type
     operation: OperationType,
+    /// ID of the message this one answers (acks, responses)
+    correlation_id: Option<String>,
     /// Optional capabilities/metadata
     capabilities: Option<Capabilities>,
     /// Optional schema URI
@@ -84,23 +97,30 @@ pub struct Envelope {
     payload_hint: Option<PayloadHint>,
     /// Optional payload references
     payload_refs: Option<PayloadRefs>,
+    /// Optional raw payload bytes, e.g. a tensor
+    payload: Option<Vec<u8>>,
 }
 
 impl Envelope {
     /// Create a new envelope with default values
+    ///
+    /// The message ID and timestamp follow the process-wide [`privacy`](crate::privacy) setting.
     pub fn new() -> Self {
+        let privacy = crate::privacy::privacy();
         Envelope {
             version: "1.0".to_string(),
-            message_id: generate_uuid(),
-            timestamp: get_current_timestamp(),
+            message_id: privacy.message_id(),
+            timestamp: privacy.timestamp(),
             from: String::new(),
             to: String::new(),
             operation: OperationType::Control,
+            correlation_id: None,
             capabilities: None,
             schema_uri: None,
             accept: None,
             payload_hint: None,
             payload_refs: None,
+            payload: None,
         }
     }
 
@@ -111,17 +131,76 @@ impl Envelope {
 
     /// Serialize envelope to JSON string
     pub fn serialize(&self) -> Result<String> {
-        let data = self.to_envelope_data();
+        let data = self.to_envelope_data(true);
         serde_json::to_string(&data)
             .map_err(|e| UmicpError::serialization(format!("Failed to serialize envelope: {}", e)))
     }
 
     /// Deserialize envelope from JSON string
+    ///
+    /// Envelopes over the process-wide [`limits`](crate::limits) are rejected with a `Validation`
+    /// error.
     pub fn deserialize(json: &str) -> Result<Self> {
+        Self::deserialize_with_limits(json, &envelope_limits())
+    }
+
+    fn deserialize_with_limits(json: &str, limits: &EnvelopeLimits) -> Result<Self> {
+        limits.check_json(json.as_bytes())?;
         let data: EnvelopeData = serde_json::from_str(json)
             .map_err(|e| UmicpError::serialization(format!("Failed to deserialize envelope: {}", e)))?;
 
-        Self::from_envelope_data(data)
+        Self::from_envelope_data(data, limits)
+    }
+
+    /// Encode the envelope with `codec`
+    pub fn encode(&self, codec: EnvelopeCodec) -> Result<Vec<u8>> {
+        Self::encode_data(&self.to_envelope_data(true), codec)
+    }
+
+    /// Decode an envelope encoded with `codec`, within the process-wide [`limits`](crate::limits)
+    pub fn decode(bytes: &[u8], codec: EnvelopeCodec) -> Result<Self> {
+        Self::decode_with_limits(bytes, codec, &envelope_limits())
+    }
+
+    /// Decode an envelope encoded with `codec`, rejecting it with a `Validation` error if it is
+    /// over `limits`
+    pub fn decode_with_limits(bytes: &[u8], codec: EnvelopeCodec, limits: &EnvelopeLimits) -> Result<Self> {
+        match codec {
+            EnvelopeCodec::Json => std::str::from_utf8(bytes)
+                .map_err(|_| UmicpError::serialization("Envelope is not valid UTF-8"))
+                .and_then(|json| Self::deserialize_with_limits(json, limits)),
+            #[cfg(feature = "cbor")]
+            EnvelopeCodec::Cbor => {
+                limits.check_size(bytes.len())?;
+                let data: EnvelopeData = ciborium::de::from_reader_with_recursion_limit(bytes, limits.max_depth)
+                    .map_err(|e| match e {
+                        ciborium::de::Error::RecursionLimitExceeded => UmicpError::validation(format!(
+                            "Envelope rejected: nesting deeper than {} levels",
+                            limits.max_depth
+                        )),
+                        e => UmicpError::serialization(format!("Failed to deserialize envelope: {}", e)),
+                    })?;
+                Self::from_envelope_data(data, limits)
+            }
+            #[cfg(not(feature = "cbor"))]
+            EnvelopeCodec::Cbor => Err(UmicpError::configuration("CBOR envelopes require the `cbor` feature")),
+        }
+    }
+
+    fn encode_data(data: &EnvelopeData, codec: EnvelopeCodec) -> Result<Vec<u8>> {
+        let failed =
+            |e: &dyn std::fmt::Display| UmicpError::serialization(format!("Failed to serialize envelope: {}", e));
+        match codec {
+            EnvelopeCodec::Json => serde_json::to_vec(data).map_err(|e| failed(&e)),
+            #[cfg(feature = "cbor")]
+            EnvelopeCodec::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(data, &mut bytes).map_err(|e| failed(&e))?;
+                Ok(bytes)
+            }
+            #[cfg(not(feature = "cbor"))]
+            EnvelopeCodec::Cbor => Err(UmicpError::configuration("CBOR envelopes require the `cbor` feature")),
+        }
     }
 
     /// Validate envelope data
@@ -133,6 +212,10 @@ impl Envelope {
             return Err(UmicpError::validation(format!("Invalid UUID format: {}", self.message_id)));
         }
 
+        if let Some(correlation_id) = &self.correlation_id {
+            validate_non_empty(correlation_id, "correlation_id")?;
+        }
+
         if let Some(capabilities) = &self.capabilities {
             for (key, value) in capabilities {
                 validate_non_empty(key, "capability key")?;
@@ -154,11 +237,28 @@ impl Envelope {
     }
 
     /// Generate hash of the envelope for integrity checking
+    ///
+    /// Check a hash received from elsewhere with [`verify_hash`](crate::utils::verify_hash), which
+    /// compares in constant time, rather than with `==`.
     pub fn hash(&self) -> Result<String> {
         let serialized = self.serialize()?;
         Ok(generate_hash(serialized.as_bytes()))
     }
 
+    /// Canonical bytes an envelope signature covers: every field but the signature itself, as
+    /// JSON with sorted keys, so both ends get the same bytes whatever codec carried it
+    #[cfg(feature = "crypto")]
+    pub(crate) fn signing_bytes(&self) -> Result<Vec<u8>> {
+        let mut data = self.to_envelope_data(true);
+        if let Some(capabilities) = &mut data.capabilities {
+            capabilities.remove(crate::keystore::SIGNATURE_CAPABILITY);
+        }
+        // Maps in a `Value` are ordered by key
+        let value = serde_json::to_value(&data)
+            .map_err(|e| UmicpError::serialization(format!("Failed to serialize envelope: {}", e)))?;
+        serde_json::to_vec(&value).map_err(|e| UmicpError::serialization(format!("Failed to serialize envelope: {}", e)))
+    }
+
     /// Get protocol version
     pub fn version(&self) -> &str {
         &self.version
@@ -169,7 +269,7 @@ impl Envelope {
         &self.message_id
     }
 
-    /// Get timestamp
+    /// Get timestamp; empty when the sender omitted it
     pub fn timestamp(&self) -> &str {
         &self.timestamp
     }
@@ -204,6 +304,29 @@ impl Envelope {
         self.operation = operation;
     }
 
+    /// Get the ID of the message this one answers
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation_id.as_deref()
+    }
+
+    /// Set the ID of the message this one answers
+    pub fn set_correlation_id(&mut self, correlation_id: &str) {
+        self.correlation_id = Some(correlation_id.to_string());
+    }
+
+    /// Create an envelope answering this one
+    ///
+    /// The reply goes back to the sender, carries this message's ID as its correlation ID and
+    /// gets a fresh message ID of its own.
+    pub fn reply(&self, operation: OperationType) -> Envelope {
+        let mut reply = Envelope::new();
+        reply.from = self.to.clone();
+        reply.to = self.from.clone();
+        reply.operation = operation;
+        reply.correlation_id = Some(self.message_id.clone());
+        reply
+    }
+
     /// Get capabilities
     pub fn capabilities(&self) -> Option<&Capabilities> {
         self.capabilities.as_ref()
@@ -224,6 +347,21 @@ impl Envelope {
         }
     }
 
+    /// Priority the envelope is sent with: its [`PRIORITY_CAPABILITY`], or else its operation's
+    /// default
+    pub fn priority(&self) -> MessagePriority {
+        self.capabilities
+            .as_ref()
+            .and_then(|capabilities| capabilities.get(PRIORITY_CAPABILITY))
+            .and_then(|name| MessagePriority::from_name(name))
+            .unwrap_or_else(|| self.operation.default_priority())
+    }
+
+    /// Set the priority the envelope is sent with
+    pub fn set_priority(&mut self, priority: MessagePriority) {
+        self.add_capability(PRIORITY_CAPABILITY, priority.as_str());
+    }
+
     /// Get schema URI
     pub fn schema_uri(&self) -> Option<&str> {
         self.schema_uri.as_deref()
@@ -264,8 +402,31 @@ impl Envelope {
         self.payload_refs = Some(refs);
     }
 
+    /// Get the raw payload
+    pub fn payload(&self) -> Option<&[u8]> {
+        self.payload.as_deref()
+    }
+
+    /// Set the raw payload
+    ///
+    /// JSON and CBOR documents carry it base64-encoded; binary frames (see
+    /// [`BinaryFrame`](crate::transport::BinaryFrame)) carry it as-is.
+    pub fn set_payload(&mut self, payload: Vec<u8>) {
+        self.payload = Some(payload);
+    }
+
+    /// Remove and return the raw payload
+    pub fn take_payload(&mut self) -> Option<Vec<u8>> {
+        self.payload.take()
+    }
+
+    /// Encode everything but the raw payload, for framing that carries the payload separately
+    pub(crate) fn encode_without_payload(&self, codec: EnvelopeCodec) -> Result<Vec<u8>> {
+        Self::encode_data(&self.to_envelope_data(false), codec)
+    }
+
     /// Convert to internal envelope data for serialization
-    fn to_envelope_data(&self) -> EnvelopeData {
+    fn to_envelope_data(&self, with_payload: bool) -> EnvelopeData {
         EnvelopeData {
             v: self.version.clone(),
             msg_id: self.message_id.clone(),
@@ -273,6 +434,7 @@ impl Envelope {
             from: self.from.clone(),
             to: self.to.clone(),
             op: self.operation.to_string(),
+            corr_id: self.correlation_id.clone(),
             capabilities: self.capabilities.clone(),
             schema_uri: self.schema_uri.clone(),
             accept: self.accept.clone(),
@@ -283,20 +445,14 @@ impl Envelope {
                 count: hint.count,
             }),
             payload_refs: self.payload_refs.clone(),
+            payload: self.payload.as_deref().filter(|_| with_payload).map(base64_encode),
         }
     }
 
     /// Convert from internal envelope data after deserialization
-    fn from_envelope_data(data: EnvelopeData) -> Result<Self> {
-        let operation = match data.op.as_str() {
-            "control" => OperationType::Control,
-            "data" => OperationType::Data,
-            "ack" => OperationType::Ack,
-            "error" => OperationType::Error,
-            "request" => OperationType::Request,
-            "response" => OperationType::Response,
-            _ => return Err(UmicpError::validation(format!("Unknown operation type: {}", data.op))),
-        };
+    fn from_envelope_data(data: EnvelopeData, limits: &EnvelopeLimits) -> Result<Self> {
+        limits.check_fields(data.capabilities.as_ref(), data.payload_refs.as_deref())?;
+        let operation = data.op.parse()?;
 
         let payload_hint = if let Some(hint) = data.payload_hint {
             let payload_type = match hint.payload_type.as_str() {
@@ -340,11 +496,13 @@ impl Envelope {
             from: data.from,
             to: data.to,
             operation,
+            correlation_id: data.corr_id,
             capabilities: data.capabilities,
             schema_uri: data.schema_uri,
             accept: data.accept,
             payload_hint,
             payload_refs: data.payload_refs,
+            payload: data.payload.as_deref().map(base64_decode).transpose()?,
         })
     }
 }
@@ -392,12 +550,24 @@ impl EnvelopeBuilder {
         self
     }
 
+    /// Set the ID of the message this one answers
+    pub fn correlation_id(mut self, correlation_id: &str) -> Self {
+        self.envelope.set_correlation_id(correlation_id);
+        self
+    }
+
     /// Add a capability
     pub fn capability(mut self, key: &str, value: &str) -> Self {
         self.envelope.add_capability(key, value);
         self
     }
 
+    /// Set the priority the envelope is sent with
+    pub fn priority(mut self, priority: MessagePriority) -> Self {
+        self.envelope.set_priority(priority);
+        self
+    }
+
     /// Set all capabilities
     pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
         self.envelope.set_capabilities(capabilities);
@@ -428,6 +598,12 @@ impl EnvelopeBuilder {
         self
     }
 
+    /// Set the raw payload
+    pub fn payload(mut self, payload: Vec<u8>) -> Self {
+        self.envelope.set_payload(payload);
+        self
+    }
+
     /// Build the envelope
     pub fn build(self) -> Result<Envelope> {
         self.envelope.validate()?;
@@ -468,6 +644,10 @@ mod tests {
         assert_eq!(envelope.to(), "recipient");
         assert_eq!(envelope.operation(), OperationType::Data);
         assert_eq!(envelope.capabilities().unwrap().get("priority").unwrap(), "high");
+        assert_eq!(envelope.priority(), MessagePriority::High);
+        // Without the capability, data is bulk traffic
+        let bulk = Envelope::builder().from("sender").to("recipient").operation(OperationType::Data).build().unwrap();
+        assert_eq!(bulk.priority(), MessagePriority::Low);
     }
 
     #[test]
@@ -489,6 +669,32 @@ mod tests {
         assert_eq!(deserialized.capabilities(), envelope.capabilities());
     }
 
+    #[test]
+    fn test_envelope_codecs() {
+        let envelope = Envelope::builder()
+            .from("test-from")
+            .to("test-to")
+            .operation(OperationType::Data)
+            .capability("test", "value")
+            .payload(vec![0, 1, 2, 255])
+            .build()
+            .unwrap();
+
+        for &codec in EnvelopeCodec::supported() {
+            let decoded = Envelope::decode(&envelope.encode(codec).unwrap(), codec).unwrap();
+            assert_eq!(decoded.message_id(), envelope.message_id());
+            assert_eq!(decoded.payload(), Some(&[0, 1, 2, 255][..]));
+            assert_eq!(decoded.operation(), envelope.operation());
+            assert_eq!(decoded.capabilities(), envelope.capabilities());
+        }
+        #[cfg(feature = "cbor")]
+        {
+            let cbor = envelope.encode(EnvelopeCodec::Cbor).unwrap();
+            assert!(cbor.len() < envelope.serialize().unwrap().len());
+            assert!(Envelope::decode(&cbor, EnvelopeCodec::Json).is_err());
+        }
+    }
+
     #[test]
     fn test_envelope_validation() {
         // Valid envelope
@@ -512,4 +718,43 @@ mod tests {
             .build();
         assert!(invalid.is_err());
     }
+
+    #[test]
+    fn test_envelope_hash_verification() {
+        let envelope = Envelope::builder().from("sender").to("recipient").build().unwrap();
+        let hash = envelope.hash().unwrap();
+        assert!(verify_hash(&envelope, &hash).is_ok());
+        assert!(verify_hash(&envelope, &hash.to_uppercase()).is_ok());
+
+        let mut tampered = hash.into_bytes();
+        tampered[0] = if tampered[0] == b'0' { b'1' } else { b'0' };
+        assert!(verify_hash(&envelope, std::str::from_utf8(&tampered).unwrap()).is_err());
+        assert!(verify_hash(&envelope, "not hex").is_err());
+        assert!(verify_hash(&envelope, "abcd").is_err());
+
+        assert!(constant_time_eq(b"same", b"same"));
+        assert!(!constant_time_eq(b"same", b"sane"));
+        assert!(!constant_time_eq(b"same", b"same!"));
+    }
+
+    #[test]
+    fn test_envelope_reply_correlation() {
+        let request = Envelope::builder()
+            .from("client")
+            .to("server")
+            .operation(OperationType::Request)
+            .build()
+            .unwrap();
+        assert_eq!(request.correlation_id(), None);
+        assert!(!request.serialize().unwrap().contains("corr_id"));
+
+        let response = request.reply(OperationType::Response);
+        assert_eq!((response.from(), response.to()), ("server", "client"));
+        assert_eq!(response.correlation_id(), Some(request.message_id()));
+        assert_ne!(response.message_id(), request.message_id());
+
+        let parsed = Envelope::deserialize(&response.serialize().unwrap()).unwrap();
+        assert_eq!(parsed.correlation_id(), Some(request.message_id()));
+        assert!(Envelope::builder().from("a").to("b").correlation_id("").build().is_err());
+    }
 }
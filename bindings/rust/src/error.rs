@@ -33,10 +33,34 @@ pub enum UmicpError {
     #[error("Authentication error: {message}")]
     Authentication { message: String },
 
+    /// TLS peer's certificate matched none of the pinned certificates or keys
+    #[error("Pin mismatch: {message}")]
+    PinMismatch { message: String },
+
+    /// Authenticated peer is not allowed to do what it asked
+    #[error("Permission denied: {message}")]
+    Forbidden { message: String },
+
+    /// Peer used up its quota for the current window
+    #[error("Quota exceeded: {message}")]
+    QuotaExceeded { message: String },
+
     /// Configuration error
     #[error("Configuration error: {message}")]
     Configuration { message: String },
 
+    /// Operation did not complete in time
+    #[error("Timeout: {message}")]
+    Timeout { message: String },
+
+    /// Operation stopped because its cancellation token was cancelled
+    #[error("Cancelled: {message}")]
+    Cancelled { message: String },
+
+    /// Peer answered a request with an error envelope
+    #[error("Remote error: {message}")]
+    Remote { message: String },
+
     /// I/O error
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
@@ -49,16 +73,21 @@ pub enum UmicpError {
     #[error("UUID error: {0}")]
     Uuid(#[from] uuid::Error),
 
-    /// WebSocket error
+    /// WebSocket error (boxed: tungstenite's error is large)
     #[cfg(feature = "websocket")]
     #[error("WebSocket error: {0}")]
-    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
 
     /// HTTP/2 error
     #[cfg(feature = "http2")]
     #[error("HTTP/2 error: {message}")]
     Http2 { message: String },
 
+    /// GPU backend error
+    #[cfg(feature = "gpu")]
+    #[error("GPU error: {message}")]
+    Gpu { message: String },
+
     /// Generic error
     #[error("Error: {message}")]
     Generic { message: String },
@@ -67,6 +96,13 @@ pub enum UmicpError {
 /// Result type alias for UMICP operations
 pub type Result<T> = std::result::Result<T, UmicpError>;
 
+#[cfg(feature = "websocket")]
+impl From<tokio_tungstenite::tungstenite::Error> for UmicpError {
+    fn from(error: tokio_tungstenite::tungstenite::Error) -> Self {
+        UmicpError::WebSocket(Box::new(error))
+    }
+}
+
 /// Convenience functions for creating errors
 impl UmicpError {
     /// Create a serialization error
@@ -111,6 +147,27 @@ impl UmicpError {
         }
     }
 
+    /// Create a pin mismatch error
+    pub fn pin_mismatch<S: Into<String>>(message: S) -> Self {
+        UmicpError::PinMismatch {
+            message: message.into(),
+        }
+    }
+
+    /// Create a permission error
+    pub fn forbidden<S: Into<String>>(message: S) -> Self {
+        UmicpError::Forbidden {
+            message: message.into(),
+        }
+    }
+
+    /// Create a quota error
+    pub fn quota_exceeded<S: Into<String>>(message: S) -> Self {
+        UmicpError::QuotaExceeded {
+            message: message.into(),
+        }
+    }
+
     /// Create a configuration error
     pub fn configuration<S: Into<String>>(message: S) -> Self {
         UmicpError::Configuration {
@@ -118,10 +175,69 @@ impl UmicpError {
         }
     }
 
+    /// Create a timeout error
+    pub fn timeout<S: Into<String>>(message: S) -> Self {
+        UmicpError::Timeout {
+            message: message.into(),
+        }
+    }
+
+    /// Create a cancellation error
+    pub fn cancelled<S: Into<String>>(message: S) -> Self {
+        UmicpError::Cancelled {
+            message: message.into(),
+        }
+    }
+
+    /// Create a remote error
+    pub fn remote<S: Into<String>>(message: S) -> Self {
+        UmicpError::Remote {
+            message: message.into(),
+        }
+    }
+
+    /// Create a GPU backend error
+    #[cfg(feature = "gpu")]
+    pub fn gpu<S: Into<String>>(message: S) -> Self {
+        UmicpError::Gpu {
+            message: message.into(),
+        }
+    }
+
     /// Create a generic error
     pub fn generic<S: Into<String>>(message: S) -> Self {
         UmicpError::Generic {
             message: message.into(),
         }
     }
+
+    /// Short name of the kind of error, such as `connection` or `serialization`, as errors are
+    /// counted by in [`TransportStats::errors`](crate::types::TransportStats::errors)
+    pub fn category(&self) -> &'static str {
+        match self {
+            UmicpError::Serialization { .. } => "serialization",
+            UmicpError::Transport { .. } => "transport",
+            UmicpError::Matrix { .. } => "matrix",
+            UmicpError::Validation { .. } => "validation",
+            UmicpError::Connection { .. } => "connection",
+            UmicpError::Authentication { .. } => "authentication",
+            UmicpError::PinMismatch { .. } => "pin_mismatch",
+            UmicpError::Forbidden { .. } => "forbidden",
+            UmicpError::QuotaExceeded { .. } => "quota_exceeded",
+            UmicpError::Configuration { .. } => "configuration",
+            UmicpError::Timeout { .. } => "timeout",
+            UmicpError::Cancelled { .. } => "cancelled",
+            UmicpError::Remote { .. } => "remote",
+            UmicpError::Io(_) => "io",
+            UmicpError::Json(_) => "json",
+            UmicpError::Uuid(_) => "uuid",
+            #[cfg(feature = "websocket")]
+            UmicpError::WebSocket(_) => "websocket",
+            #[cfg(feature = "http2")]
+            UmicpError::Http2 { .. } => "http2",
+            #[cfg(feature = "gpu")]
+            UmicpError::Gpu { .. } => "gpu",
+            UmicpError::Generic { .. } => "generic",
+        }
+    }
 }
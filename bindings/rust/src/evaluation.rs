@@ -0,0 +1,405 @@
+/*!
+# UMICP Federated Evaluation
+
+Evaluation metrics reported by clients on their local data, and their aggregation into one
+result per round.
+
+After a round, each client evaluates the new global model and sends an [`EvaluationReport`]: the
+number of samples it evaluated on, its loss and accuracy, any other metric by name, and
+optionally a [`ConfusionMatrix`]. A [`MetricsAggregator`] combines the reports of a round the way
+the metrics were computed: means are weighted by each client's sample count, among the clients
+that reported that metric, and confusion matrices are summed cell by cell, from which accuracy,
+precision and recall over all clients follow. Averaging clients' accuracies unweighted, or their
+precisions at all, would give the wrong answer whenever clients hold different amounts of data.
+
+Reports travel as JSON in the payload of an envelope whose [`FEDERATED_CAPABILITY`] is
+`evaluation`. A [`RoundCoordinator`](crate::round::RoundCoordinator) collects them from its
+registered clients and exposes the result with
+[`metrics`](crate::round::RoundCoordinator::metrics).
+
+```rust
+use umicp_core::evaluation::{ConfusionMatrix, EvaluationReport, MetricsAggregator};
+
+let mut small = EvaluationReport::new("worker-1", 3, 100);
+small.loss = Some(0.9);
+small.confusion = Some(ConfusionMatrix::from_rows(vec![vec![40, 10], vec![10, 40]])?);
+let mut large = EvaluationReport::new("worker-2", 3, 300);
+large.loss = Some(0.5);
+large.confusion = Some(ConfusionMatrix::from_rows(vec![vec![140, 10], vec![0, 150]])?);
+
+let mut aggregator = MetricsAggregator::new(3);
+aggregator.add(small)?;
+aggregator.add_envelope(&large.to_envelope("coordinator")?)?;
+let metrics = aggregator.summary();
+assert!((metrics.loss.unwrap() - 0.6).abs() < 1e-9);
+assert_eq!(metrics.confusion.unwrap().accuracy(), Some(0.925));
+# Ok::<(), umicp_core::UmicpError>(())
+```
+*/
+
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::round::{FEDERATED_CAPABILITY, ROUND_CAPABILITY};
+use crate::types::OperationType;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Kind of federated message carrying an evaluation report
+pub(crate) const EVALUATION: &str = "evaluation";
+
+/// Counts of predictions by actual class (rows) and predicted class (columns)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<Vec<u64>>", into = "Vec<Vec<u64>>")]
+pub struct ConfusionMatrix {
+    counts: Vec<Vec<u64>>,
+}
+
+impl ConfusionMatrix {
+    /// Empty matrix over `classes` classes
+    pub fn new(classes: usize) -> Self {
+        ConfusionMatrix {
+            counts: vec![vec![0; classes]; classes],
+        }
+    }
+
+    /// Matrix with the given rows, which must be square
+    pub fn from_rows(rows: Vec<Vec<u64>>) -> Result<Self> {
+        if rows.iter().any(|row| row.len() != rows.len()) {
+            return Err(UmicpError::validation("Confusion matrix is not square"));
+        }
+        Ok(ConfusionMatrix { counts: rows })
+    }
+
+    /// Number of classes
+    pub fn classes(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// Count one sample of class `actual` predicted as `predicted`
+    pub fn record(&mut self, actual: usize, predicted: usize) -> Result<()> {
+        let classes = self.classes();
+        let cell = self
+            .counts
+            .get_mut(actual)
+            .and_then(|row| row.get_mut(predicted))
+            .ok_or_else(|| UmicpError::validation(format!("Class out of range for {} classes", classes)))?;
+        *cell += 1;
+        Ok(())
+    }
+
+    /// Samples of class `actual` predicted as `predicted`
+    pub fn count(&self, actual: usize, predicted: usize) -> u64 {
+        self.counts.get(actual).and_then(|row| row.get(predicted)).copied().unwrap_or(0)
+    }
+
+    /// Add `other`'s counts to these
+    pub fn merge(&mut self, other: &ConfusionMatrix) -> Result<()> {
+        if other.classes() != self.classes() {
+            return Err(UmicpError::validation(format!(
+                "Confusion matrix has {} classes, expected {}",
+                other.classes(),
+                self.classes()
+            )));
+        }
+        for (row, other) in self.counts.iter_mut().zip(&other.counts) {
+            for (count, other) in row.iter_mut().zip(other) {
+                *count = count.saturating_add(*other);
+            }
+        }
+        Ok(())
+    }
+
+    /// Samples counted
+    pub fn total(&self) -> u64 {
+        self.counts.iter().flatten().sum()
+    }
+
+    /// Samples of class `class`
+    pub fn support(&self, class: usize) -> u64 {
+        self.counts.get(class).map_or(0, |row| row.iter().sum())
+    }
+
+    /// Samples predicted as class `class`
+    pub fn predicted(&self, class: usize) -> u64 {
+        self.counts.iter().map(|row| row.get(class).copied().unwrap_or(0)).sum()
+    }
+
+    /// Share of samples predicted correctly, if any were counted
+    pub fn accuracy(&self) -> Option<f64> {
+        let correct: u64 = (0..self.classes()).map(|class| self.count(class, class)).sum();
+        ratio(correct, self.total())
+    }
+
+    /// Share of the samples predicted as `class` that are of it
+    pub fn precision(&self, class: usize) -> Option<f64> {
+        ratio(self.count(class, class), self.predicted(class))
+    }
+
+    /// Share of the samples of `class` predicted as it
+    pub fn recall(&self, class: usize) -> Option<f64> {
+        ratio(self.count(class, class), self.support(class))
+    }
+}
+
+fn ratio(part: u64, whole: u64) -> Option<f64> {
+    (whole > 0).then(|| part as f64 / whole as f64)
+}
+
+impl TryFrom<Vec<Vec<u64>>> for ConfusionMatrix {
+    type Error = UmicpError;
+
+    fn try_from(rows: Vec<Vec<u64>>) -> Result<Self> {
+        ConfusionMatrix::from_rows(rows)
+    }
+}
+
+impl From<ConfusionMatrix> for Vec<Vec<u64>> {
+    fn from(matrix: ConfusionMatrix) -> Self {
+        matrix.counts
+    }
+}
+
+/// Metrics a client measured evaluating a round's global model on its own data
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EvaluationReport {
+    /// Client that evaluated
+    pub client: String,
+    /// Round whose model was evaluated
+    pub round: u64,
+    /// Samples evaluated on
+    pub sample_count: u64,
+    /// Mean loss over the samples
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loss: Option<f64>,
+    /// Share of the samples predicted correctly
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accuracy: Option<f64>,
+    /// Other per-sample means, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metrics: BTreeMap<String, f64>,
+    /// Predictions by actual and predicted class
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confusion: Option<ConfusionMatrix>,
+}
+
+impl EvaluationReport {
+    /// Report by `client` on the model of `round`, evaluated on `sample_count` samples
+    pub fn new(client: &str, round: u64, sample_count: u64) -> Self {
+        EvaluationReport {
+            client: client.to_string(),
+            round,
+            sample_count,
+            loss: None,
+            accuracy: None,
+            metrics: BTreeMap::new(),
+            confusion: None,
+        }
+    }
+
+    /// Means reported, by name, loss and accuracy included
+    fn means(&self) -> impl Iterator<Item = (&str, f64)> {
+        let named = self.metrics.iter().map(|(name, value)| (name.as_str(), *value));
+        let loss = self.loss.map(|loss| ("loss", loss));
+        let accuracy = self.accuracy.map(|accuracy| ("accuracy", accuracy));
+        loss.into_iter().chain(accuracy).chain(named)
+    }
+
+    /// Reject reports that would skew the aggregate
+    fn validate(&self) -> Result<()> {
+        if self.sample_count == 0 {
+            return Err(UmicpError::validation(format!("Evaluation by {} has no samples", self.client)));
+        }
+        if let Some((name, _)) = self.means().find(|(_, value)| !value.is_finite()) {
+            return Err(UmicpError::validation(format!("Evaluation by {} has a non-finite {}", self.client, name)));
+        }
+        if self.metrics.contains_key("loss") || self.metrics.contains_key("accuracy") {
+            return Err(UmicpError::validation(format!(
+                "Evaluation by {} names loss or accuracy among its other metrics",
+                self.client
+            )));
+        }
+        Ok(())
+    }
+
+    /// Envelope carrying the report from its client to `to`
+    pub fn to_envelope(&self, to: &str) -> Result<Envelope> {
+        self.validate()?;
+        let json = serde_json::to_vec(self)
+            .map_err(|e| UmicpError::serialization(format!("Failed to serialize evaluation: {}", e)))?;
+        Envelope::builder()
+            .from(&self.client)
+            .to(to)
+            .operation(OperationType::Data)
+            .capability(FEDERATED_CAPABILITY, EVALUATION)
+            .capability(ROUND_CAPABILITY, &self.round.to_string())
+            .payload(json)
+            .build()
+    }
+
+    /// Report carried by `envelope`, attributed to its sender
+    pub fn from_envelope(envelope: &Envelope) -> Result<Self> {
+        let mut report: EvaluationReport = serde_json::from_slice(envelope.payload().unwrap_or_default())
+            .map_err(|e| UmicpError::serialization(format!("Invalid evaluation from {}: {}", envelope.from(), e)))?;
+        report.client = envelope.from().to_string();
+        report.validate()?;
+        Ok(report)
+    }
+}
+
+/// Metrics of a round over every client that reported
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoundMetrics {
+    /// Round whose model was evaluated
+    pub round: u64,
+    /// Clients that reported, sorted
+    pub clients: Vec<String>,
+    /// Samples evaluated on across all clients
+    pub total_samples: u64,
+    /// Sample-weighted mean loss
+    pub loss: Option<f64>,
+    /// Sample-weighted mean accuracy
+    pub accuracy: Option<f64>,
+    /// Sample-weighted means of the other metrics, by name
+    pub metrics: BTreeMap<String, f64>,
+    /// Sum of the reported confusion matrices
+    pub confusion: Option<ConfusionMatrix>,
+}
+
+/// Combines the evaluation reports of one round
+#[derive(Debug, Clone)]
+pub struct MetricsAggregator {
+    round: u64,
+    /// Latest report by client
+    reports: BTreeMap<String, EvaluationReport>,
+}
+
+impl MetricsAggregator {
+    /// Aggregator for the reports on the model of `round`
+    pub fn new(round: u64) -> Self {
+        MetricsAggregator {
+            round,
+            reports: BTreeMap::new(),
+        }
+    }
+
+    /// Add `report`, replacing any earlier one from the same client
+    ///
+    /// Its confusion matrix must have as many classes as those already added.
+    pub fn add(&mut self, report: EvaluationReport) -> Result<()> {
+        report.validate()?;
+        if report.round != self.round {
+            return Err(UmicpError::validation(format!(
+                "Evaluation by {} is for round {}, not {}",
+                report.client, report.round, self.round
+            )));
+        }
+        let classes = self.reports.values().find_map(|report| report.confusion.as_ref()).map(ConfusionMatrix::classes);
+        let reported = report.confusion.as_ref().map(ConfusionMatrix::classes);
+        if let (Some(classes), Some(reported)) = (classes, reported) {
+            if classes != reported {
+                return Err(UmicpError::validation(format!(
+                    "Evaluation by {} covers {} classes, expected {}",
+                    report.client, reported, classes
+                )));
+            }
+        }
+        self.reports.insert(report.client.clone(), report);
+        Ok(())
+    }
+
+    /// Add the report carried by `envelope`
+    pub fn add_envelope(&mut self, envelope: &Envelope) -> Result<()> {
+        self.add(EvaluationReport::from_envelope(envelope)?)
+    }
+
+    /// Round the reports are on
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    /// Number of reports held
+    pub fn len(&self) -> usize {
+        self.reports.len()
+    }
+
+    /// Whether no reports are held
+    pub fn is_empty(&self) -> bool {
+        self.reports.is_empty()
+    }
+
+    /// Metrics over every report held
+    pub fn summary(&self) -> RoundMetrics {
+        // Weighted sum and weight of each mean, over the clients that reported it
+        let mut sums: BTreeMap<&str, (f64, f64)> = BTreeMap::new();
+        let mut confusion: Option<ConfusionMatrix> = None;
+        for report in self.reports.values() {
+            let weight = report.sample_count as f64;
+            for (name, value) in report.means() {
+                let (sum, total) = sums.entry(name).or_default();
+                *sum += value * weight;
+                *total += weight;
+            }
+            if let Some(matrix) = &report.confusion {
+                match &mut confusion {
+                    // Class counts were checked when the report was added
+                    Some(merged) => merged.merge(matrix).expect("confusion matrices of equal size"),
+                    None => confusion = Some(matrix.clone()),
+                }
+            }
+        }
+        let mut metrics: BTreeMap<String, f64> =
+            sums.into_iter().map(|(name, (sum, total))| (name.to_string(), sum / total)).collect();
+        RoundMetrics {
+            round: self.round,
+            clients: self.reports.keys().cloned().collect(),
+            total_samples: self.reports.values().map(|report| report.sample_count).fold(0, u64::saturating_add),
+            loss: metrics.remove("loss"),
+            accuracy: metrics.remove("accuracy"),
+            metrics,
+            confusion,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_weighted_metrics_and_summed_confusion() {
+        let mut aggregator = MetricsAggregator::new(2);
+        let mut a = EvaluationReport::new("a", 2, 100);
+        a.accuracy = Some(0.5);
+        a.metrics.insert("f1".to_string(), 0.4);
+        a.confusion = Some(ConfusionMatrix::from_rows(vec![vec![30, 20], vec![30, 20]]).unwrap());
+        let mut b = EvaluationReport::new("b", 2, 300);
+        b.accuracy = Some(0.9);
+        b.loss = Some(0.2);
+        let mut confusion = ConfusionMatrix::new(2);
+        confusion.record(1, 1).unwrap();
+        b.confusion = Some(confusion);
+        aggregator.add(a).unwrap();
+        let envelope = b.to_envelope("coordinator").unwrap();
+        aggregator.add_envelope(&Envelope::deserialize(&envelope.serialize().unwrap()).unwrap()).unwrap();
+
+        let metrics = aggregator.summary();
+        assert_eq!((metrics.clients.len(), metrics.total_samples), (2, 400));
+        assert!((metrics.accuracy.unwrap() - 0.8).abs() < 1e-9);
+        // Means are weighted among the clients that reported them
+        assert_eq!((metrics.loss, metrics.metrics["f1"]), (Some(0.2), 0.4));
+        let confusion = metrics.confusion.unwrap();
+        assert_eq!((confusion.count(1, 1), confusion.support(1), confusion.predicted(0)), (21, 51, 60));
+        assert_eq!(confusion.precision(0), Some(0.5));
+
+        let mut wrong_round = EvaluationReport::new("c", 3, 10);
+        assert!(aggregator.add(wrong_round.clone()).is_err());
+        wrong_round.round = 2;
+        wrong_round.confusion = Some(ConfusionMatrix::new(3));
+        assert!(aggregator.add(wrong_round).is_err());
+        let mut nan = EvaluationReport::new("c", 2, 10);
+        nan.loss = Some(f64::NAN);
+        assert!(aggregator.add(nan).is_err());
+        assert!(serde_json::from_str::<ConfusionMatrix>("[[1,2],[3]]").is_err());
+        assert_eq!(aggregator.len(), 2);
+    }
+}
@@ -0,0 +1,268 @@
+/*!
+# UMICP Federated Averaging
+
+Sample-weighted federated averaging (FedAvg) of model updates sent by clients over any
+transport.
+
+A client sends its locally trained weights as a [`ModelUpdate`]: the weights travel as the
+envelope's payload, little-endian `f32` with a vector payload hint, and the number of samples
+they were trained on in the [`SAMPLE_COUNT_CAPABILITY`], a positive integer. A [`FedAvg`]
+aggregator collects one update per client and averages them, weighting each by its share of
+the total sample count, so a client that trained on ten times the data moves the model ten times
+as far.
+
+Weights are normalized by the aggregator from the declared counts, never taken from clients as
+fractions. Counts that are missing, zero or not integers are rejected, as are updates whose
+length differs from the first update's or that hold non-finite values.
+
+```rust
+use umicp_core::federated::{FedAvg, ModelUpdate};
+
+let mut fedavg = FedAvg::new();
+for (client, weights, samples) in [("worker-1", vec![1.0, 2.0], 300), ("worker-2", vec![5.0, 6.0], 100)] {
+    let envelope = ModelUpdate::new(client, weights, samples).to_envelope("aggregator")?;
+    // ... sent over a transport, then on the aggregator:
+    fedavg.add_envelope(&envelope)?;
+}
+assert_eq!(fedavg.aggregate()?, vec![2.0, 3.0]);
+# Ok::<(), umicp_core::UmicpError>(())
+```
+*/
+
+use crate::delta::{self, ModelDelta};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::matrix::Matrix;
+use crate::types::{EncodingType, OperationType, PayloadHint, PayloadType};
+use std::collections::BTreeMap;
+
+/// Capability carrying the number of samples an update was trained on
+pub const SAMPLE_COUNT_CAPABILITY: &str = "sample_count";
+
+/// Sample count declared by `envelope`, which must be a positive integer
+pub fn sample_count(envelope: &Envelope) -> Result<u64> {
+    let declared = envelope
+        .capabilities()
+        .and_then(|capabilities| capabilities.get(SAMPLE_COUNT_CAPABILITY))
+        .ok_or_else(|| UmicpError::validation(format!("Update from {} declares no sample count", envelope.from())))?;
+    match declared.parse::<u64>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(UmicpError::validation(format!(
+            "Update from {} declares an invalid sample count: {}",
+            envelope.from(),
+            declared
+        ))),
+    }
+}
+
+/// Weights a client trained locally, with the number of samples they were trained on
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelUpdate {
+    /// Client that trained the weights
+    pub client: String,
+    /// Flattened model weights
+    pub weights: Vec<f32>,
+    /// Samples the weights were trained on
+    pub sample_count: u64,
+}
+
+impl ModelUpdate {
+    /// Update from `client`
+    pub fn new(client: &str, weights: Vec<f32>, sample_count: u64) -> Self {
+        ModelUpdate {
+            client: client.to_string(),
+            weights,
+            sample_count,
+        }
+    }
+
+    /// Envelope carrying the update from its client to `to`
+    pub fn to_envelope(&self, to: &str) -> Result<Envelope> {
+        if self.sample_count == 0 {
+            return Err(UmicpError::validation("Sample count must be positive"));
+        }
+        Envelope::builder()
+            .from(&self.client)
+            .to(to)
+            .operation(OperationType::Data)
+            .capability(SAMPLE_COUNT_CAPABILITY, &self.sample_count.to_string())
+            .build()
+            .map(|envelope| with_weights(envelope, &self.weights))
+    }
+
+    /// Update carried by `envelope`, from its sender
+    pub fn from_envelope(envelope: &Envelope) -> Result<Self> {
+        let sample_count = sample_count(envelope)?;
+        Ok(ModelUpdate::new(envelope.from(), weights(envelope)?, sample_count))
+    }
+
+    /// Update carried by `envelope` as full weights or as a [`ModelDelta`] against `base`
+    pub fn from_envelope_with_base(envelope: &Envelope, base: &[f32]) -> Result<Self> {
+        if !delta::is_delta(envelope) {
+            return Self::from_envelope(envelope);
+        }
+        let sample_count = sample_count(envelope)?;
+        let weights = ModelDelta::from_envelope(envelope)?.apply(base)?;
+        Ok(ModelUpdate::new(envelope.from(), weights, sample_count))
+    }
+}
+
+/// `envelope` carrying `weights` as its payload, little-endian `f32` with a vector hint
+pub(crate) fn with_weights(mut envelope: Envelope, weights: &[f32]) -> Envelope {
+    envelope.set_payload_hint(PayloadHint {
+        payload_type: PayloadType::Vector,
+        size: Some(weights.len() as u64 * 4),
+        encoding: Some(EncodingType::Float32),
+        count: Some(weights.len() as u64),
+    });
+    envelope.set_payload(weights.iter().flat_map(|weight| weight.to_le_bytes()).collect());
+    envelope
+}
+
+/// Weights carried by `envelope`, as written by [`with_weights`]
+pub(crate) fn weights(envelope: &Envelope) -> Result<Vec<f32>> {
+    let payload = envelope.payload().unwrap_or_default();
+    if !payload.len().is_multiple_of(4) {
+        return Err(UmicpError::validation(format!(
+            "Weights from {} are not a whole number of f32 values: {} bytes",
+            envelope.from(),
+            payload.len()
+        )));
+    }
+    Ok(payload
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect())
+}
+
+/// Sample-weighted average of client updates
+#[derive(Debug, Default)]
+pub struct FedAvg {
+    /// Latest update by client
+    updates: BTreeMap<String, ModelUpdate>,
+    matrix: Matrix,
+}
+
+impl FedAvg {
+    /// Aggregator holding no updates
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `update`, replacing any earlier one from the same client
+    pub fn add(&mut self, update: ModelUpdate) -> Result<()> {
+        if update.sample_count == 0 {
+            return Err(UmicpError::validation(format!("Update from {} has no samples", update.client)));
+        }
+        if let Some(expected) = self.dimension().filter(|expected| *expected != update.weights.len()) {
+            return Err(UmicpError::validation(format!(
+                "Update from {} has {} weights, expected {}",
+                update.client,
+                update.weights.len(),
+                expected
+            )));
+        }
+        if update.weights.iter().any(|weight| !weight.is_finite()) {
+            return Err(UmicpError::validation(format!("Update from {} has non-finite weights", update.client)));
+        }
+        self.updates.insert(update.client.clone(), update);
+        Ok(())
+    }
+
+    /// Add the update carried by `envelope`
+    pub fn add_envelope(&mut self, envelope: &Envelope) -> Result<()> {
+        self.add(ModelUpdate::from_envelope(envelope)?)
+    }
+
+    /// Number of weights every update must have, once one has been added
+    pub fn dimension(&self) -> Option<usize> {
+        self.updates.values().next().map(|update| update.weights.len())
+    }
+
+    /// Clients whose updates are held
+    pub fn clients(&self) -> impl Iterator<Item = &str> {
+        self.updates.keys().map(String::as_str)
+    }
+
+    /// Updates held, by client
+    pub(crate) fn updates(&self) -> impl Iterator<Item = &ModelUpdate> {
+        self.updates.values()
+    }
+
+    /// Number of updates held
+    pub fn len(&self) -> usize {
+        self.updates.len()
+    }
+
+    /// Whether no updates are held
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+
+    /// Samples declared across all updates
+    pub fn total_samples(&self) -> u64 {
+        self.updates.values().map(|update| update.sample_count).fold(0, u64::saturating_add)
+    }
+
+    /// Each client's share of the average, summing to 1
+    pub fn weights(&self) -> BTreeMap<String, f64> {
+        let total = self.total_samples() as f64;
+        let share = |update: &ModelUpdate| update.sample_count as f64 / total;
+        self.updates.iter().map(|(client, update)| (client.clone(), share(update))).collect()
+    }
+
+    /// Average of the updates, each weighted by its share of the samples
+    pub fn aggregate(&self) -> Result<Vec<f32>> {
+        let dimension = self
+            .dimension()
+            .ok_or_else(|| UmicpError::validation("No updates to aggregate"))?;
+        let shares = self.weights();
+        let vectors: Vec<&[f32]> = self.updates.values().map(|update| update.weights.as_slice()).collect();
+        let weights: Vec<f32> = self.updates.keys().map(|client| shares[client] as f32).collect();
+        let mut result = vec![0.0; dimension];
+        self.matrix.weighted_average(&vectors, &weights, &mut result)?;
+        Ok(result)
+    }
+
+    /// Forget every update, to start the next round
+    pub fn clear(&mut self) {
+        self.updates.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_weighted_average() {
+        let mut fedavg = FedAvg::new();
+        fedavg.add(ModelUpdate::new("small", vec![4.0, 0.0], 1)).unwrap();
+        let envelope = ModelUpdate::new("large", vec![0.0, 4.0], 3).to_envelope("aggregator").unwrap();
+        let received = Envelope::deserialize(&envelope.serialize().unwrap()).unwrap();
+        assert_eq!(sample_count(&received).unwrap(), 3);
+        fedavg.add_envelope(&received).unwrap();
+
+        assert_eq!(fedavg.total_samples(), 4);
+        assert_eq!(fedavg.weights()["large"], 0.75);
+        assert_eq!(fedavg.aggregate().unwrap(), vec![1.0, 3.0]);
+
+        // A resent update replaces the client's earlier one
+        fedavg.add(ModelUpdate::new("small", vec![4.0, 0.0], 3)).unwrap();
+        assert_eq!(fedavg.aggregate().unwrap(), vec![2.0, 2.0]);
+
+        let mut zero = envelope.clone();
+        zero.add_capability(SAMPLE_COUNT_CAPABILITY, "0");
+        let mut fractional = envelope.clone();
+        fractional.add_capability(SAMPLE_COUNT_CAPABILITY, "0.5");
+        for invalid in [zero, fractional] {
+            assert!(matches!(fedavg.add_envelope(&invalid), Err(UmicpError::Validation { .. })));
+        }
+        assert!(fedavg.add(ModelUpdate::new("short", vec![1.0], 1)).is_err());
+        assert!(fedavg.add(ModelUpdate::new("nan", vec![f32::NAN, 1.0], 1)).is_err());
+        assert_eq!(fedavg.len(), 2);
+
+        fedavg.clear();
+        assert!(fedavg.aggregate().is_err());
+    }
+}
@@ -0,0 +1,411 @@
+/*!
+# UMICP Gossip Membership
+
+Decentralized peer membership over any [`Transport`], for federated-learning topologies without a
+central coordinator.
+
+Every node keeps a heartbeat counter that it bumps each `heartbeat_interval`. On the same tick it
+sends its whole membership view (node ID, heartbeat, metadata of every live member) to up to
+`fanout` randomly chosen neighbours, as a `Control` envelope carrying the
+[`GOSSIP_CAPABILITY`]. A receiving node keeps, per member, the highest heartbeat it has heard of,
+so news spreads epidemically through the mesh even between nodes that never talk directly.
+
+A member whose heartbeat has not advanced for `suspect_after` is [`Suspect`](MemberStatus::Suspect),
+and after `dead_after` it is [`Dead`](MemberStatus::Dead); dead members stop being gossiped and are
+forgotten after another `dead_after`. Neighbours are the peers gossip arrived from directly, plus
+the connections passed to [`GossipNode::join`].
+*/
+
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::transport::Transport;
+use crate::types::OperationType;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Capability carrying a node's membership view
+pub const GOSSIP_CAPABILITY: &str = "gossip";
+
+/// Gossip settings
+#[derive(Debug, Clone)]
+pub struct GossipConfig {
+    /// This node's ID, unique in the cluster
+    pub node_id: String,
+    /// Application metadata gossiped with the node, e.g. its role
+    pub metadata: HashMap<String, String>,
+    /// Time between heartbeats and gossip rounds
+    pub heartbeat_interval: Duration,
+    /// Neighbours gossiped to per round
+    pub fanout: usize,
+    /// Silence after which a member is suspected
+    pub suspect_after: Duration,
+    /// Silence after which a member is declared dead
+    pub dead_after: Duration,
+}
+
+impl GossipConfig {
+    /// Defaults for a node called `node_id`
+    pub fn new(node_id: &str) -> Self {
+        GossipConfig {
+            node_id: node_id.to_string(),
+            metadata: HashMap::new(),
+            heartbeat_interval: Duration::from_secs(1),
+            fanout: 3,
+            suspect_after: Duration::from_secs(5),
+            dead_after: Duration::from_secs(15),
+        }
+    }
+}
+
+/// Liveness of a member as seen by this node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberStatus {
+    /// Heartbeat advanced recently
+    Alive,
+    /// No heartbeat for `suspect_after`
+    Suspect,
+    /// No heartbeat for `dead_after`
+    Dead,
+}
+
+/// One node in the membership view
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    /// Member's node ID
+    pub node_id: String,
+    /// Highest heartbeat heard of
+    pub heartbeat: u64,
+    /// Liveness derived from how long the heartbeat has been stuck
+    pub status: MemberStatus,
+    /// Metadata gossiped with the heartbeat
+    pub metadata: HashMap<String, String>,
+    /// Connection gossip from this member arrives on, when it is a direct neighbour
+    pub connection_id: Option<String>,
+}
+
+/// Wire form of a member
+#[derive(Serialize, Deserialize)]
+struct Digest {
+    id: String,
+    hb: u64,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    meta: HashMap<String, String>,
+}
+
+struct MemberState {
+    heartbeat: u64,
+    metadata: HashMap<String, String>,
+    connection_id: Option<String>,
+    /// When the heartbeat last advanced
+    updated: Instant,
+}
+
+struct State {
+    heartbeat: u64,
+    members: HashMap<String, MemberState>,
+    /// Connections to gossip to before knowing who is behind them
+    seeds: Vec<String>,
+}
+
+struct Shared {
+    transport: Arc<dyn Transport>,
+    config: GossipConfig,
+    state: Mutex<State>,
+    shutdown: watch::Sender<bool>,
+}
+
+/// Gossip participant
+#[derive(Clone)]
+pub struct GossipNode {
+    shared: Arc<Shared>,
+}
+
+impl GossipNode {
+    /// Create a node gossiping over `transport`
+    pub fn new(transport: Arc<dyn Transport>, config: GossipConfig) -> Self {
+        let (shutdown, _) = watch::channel(false);
+        GossipNode {
+            shared: Arc::new(Shared {
+                transport,
+                config,
+                state: Mutex::new(State {
+                    heartbeat: 0,
+                    members: HashMap::new(),
+                    seeds: Vec::new(),
+                }),
+                shutdown,
+            }),
+        }
+    }
+
+    /// This node's ID
+    pub fn node_id(&self) -> &str {
+        &self.shared.config.node_id
+    }
+
+    /// Gossip to `connection_id` until the node behind it introduces itself
+    pub fn join(&self, connection_id: &str) {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.seeds.iter().any(|seed| seed == connection_id) {
+            state.seeds.push(connection_id.to_string());
+        }
+    }
+
+    /// Start heartbeating and processing gossip; runs until [`stop`](GossipNode::stop)
+    pub fn start(&self) {
+        let mut incoming = self.shared.transport.subscribe();
+        let node = self.clone();
+        let mut shutdown = self.shared.shutdown.subscribe();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = shutdown.wait_for(|stopped| *stopped) => break,
+                    received = incoming.recv() => match received {
+                        Some((envelope, conn_id)) => {
+                            // Malformed gossip is ignored; the sender will be heard from again
+                            let _ = node.receive(&envelope, &conn_id);
+                        }
+                        None => break,
+                    },
+                }
+            }
+        });
+
+        let node = self.clone();
+        let mut shutdown = self.shared.shutdown.subscribe();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(node.shared.config.heartbeat_interval);
+            loop {
+                tokio::select! {
+                    _ = shutdown.wait_for(|stopped| *stopped) => break,
+                    _ = ticker.tick() => {}
+                }
+                node.round().await;
+            }
+        });
+    }
+
+    /// Stop gossiping; peers will see this node go suspect, then dead
+    pub fn stop(&self) {
+        self.shared.shutdown.send_replace(true);
+    }
+
+    /// Current view of the cluster, this node included, sorted by node ID
+    pub fn members(&self) -> Vec<Member> {
+        let state = self.shared.state.lock().unwrap();
+        let now = Instant::now();
+        let mut members: Vec<Member> = state
+            .members
+            .iter()
+            .map(|(node_id, member)| Member {
+                node_id: node_id.clone(),
+                heartbeat: member.heartbeat,
+                status: self.status(member, now),
+                metadata: member.metadata.clone(),
+                connection_id: member.connection_id.clone(),
+            })
+            .collect();
+        members.push(Member {
+            node_id: self.shared.config.node_id.clone(),
+            heartbeat: state.heartbeat,
+            status: MemberStatus::Alive,
+            metadata: self.shared.config.metadata.clone(),
+            connection_id: None,
+        });
+        members.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        members
+    }
+
+    /// IDs of the members currently considered alive, this node included
+    pub fn alive(&self) -> Vec<String> {
+        self.members()
+            .into_iter()
+            .filter(|member| member.status == MemberStatus::Alive)
+            .map(|member| member.node_id)
+            .collect()
+    }
+
+    fn status(&self, member: &MemberState, now: Instant) -> MemberStatus {
+        let silent = now.duration_since(member.updated);
+        if silent >= self.shared.config.dead_after {
+            MemberStatus::Dead
+        } else if silent >= self.shared.config.suspect_after {
+            MemberStatus::Suspect
+        } else {
+            MemberStatus::Alive
+        }
+    }
+
+    /// Merge a gossip envelope; other envelopes are ignored
+    fn receive(&self, envelope: &Envelope, conn_id: &str) -> Result<()> {
+        let Some(view) = envelope
+            .capabilities()
+            .and_then(|capabilities| capabilities.get(GOSSIP_CAPABILITY))
+        else {
+            return Ok(());
+        };
+        let digests: Vec<Digest> = serde_json::from_str(view)
+            .map_err(|e| UmicpError::serialization(format!("Invalid gossip from {}: {}", envelope.from(), e)))?;
+
+        let now = Instant::now();
+        let mut state = self.shared.state.lock().unwrap();
+        state.seeds.retain(|seed| seed != conn_id);
+        for digest in digests {
+            if digest.id == self.shared.config.node_id {
+                continue;
+            }
+            let member = state.members.entry(digest.id.clone()).or_insert_with(|| MemberState {
+                heartbeat: 0,
+                metadata: HashMap::new(),
+                connection_id: None,
+                updated: now,
+            });
+            if digest.hb > member.heartbeat {
+                member.heartbeat = digest.hb;
+                member.metadata = digest.meta;
+                member.updated = now;
+            }
+        }
+        if let Some(sender) = state.members.get_mut(envelope.from()) {
+            sender.connection_id = Some(conn_id.to_string());
+        }
+        Ok(())
+    }
+
+    /// One heartbeat: bump our counter, age the view and gossip it to a few neighbours
+    async fn round(&self) {
+        let (view, targets) = {
+            let now = Instant::now();
+            let forget_after = self.shared.config.dead_after * 2;
+            let mut state = self.shared.state.lock().unwrap();
+            state.heartbeat += 1;
+            state.members.retain(|_, member| now.duration_since(member.updated) < forget_after);
+
+            let mut view = vec![Digest {
+                id: self.shared.config.node_id.clone(),
+                hb: state.heartbeat,
+                meta: self.shared.config.metadata.clone(),
+            }];
+            let mut neighbours = state.seeds.clone();
+            for (node_id, member) in &state.members {
+                if self.status(member, now) == MemberStatus::Dead {
+                    continue;
+                }
+                view.push(Digest {
+                    id: node_id.clone(),
+                    hb: member.heartbeat,
+                    meta: member.metadata.clone(),
+                });
+                neighbours.extend(member.connection_id.clone());
+            }
+            neighbours.sort();
+            neighbours.dedup();
+            let targets: Vec<String> = neighbours
+                .choose_multiple(&mut rand::thread_rng(), self.shared.config.fanout)
+                .cloned()
+                .collect();
+            (view, targets)
+        };
+
+        let Ok(view) = serde_json::to_string(&view) else {
+            return;
+        };
+        for target in targets {
+            let envelope = Envelope::builder()
+                .from(&self.shared.config.node_id)
+                .to("*")
+                .operation(OperationType::Control)
+                .capability(GOSSIP_CAPABILITY, &view)
+                .build();
+            if let Ok(envelope) = envelope {
+                // An unreachable neighbour simply goes quiet and ages out
+                let _ = self.shared.transport.send(envelope, Some(&target)).await;
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for GossipNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GossipNode")
+            .field("node_id", &self.shared.config.node_id)
+            .field("members", &self.shared.state.lock().unwrap().members.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    fn gossip_from(node_id: &str, view: &[(&str, u64)]) -> Envelope {
+        let digests: Vec<Digest> = view
+            .iter()
+            .map(|(id, hb)| Digest {
+                id: id.to_string(),
+                hb: *hb,
+                meta: HashMap::new(),
+            })
+            .collect();
+        Envelope::builder()
+            .from(node_id)
+            .to("*")
+            .operation(OperationType::Control)
+            .capability(GOSSIP_CAPABILITY, &serde_json::to_string(&digests).unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_gossip_membership_and_failure_detection() {
+        let mock = MockTransport::with_peer_id("conn-b");
+        mock.connect().await.unwrap();
+        let mut config = GossipConfig::new("a");
+        config.metadata.insert("role".to_string(), "aggregator".to_string());
+        let node = GossipNode::new(Arc::new(mock.clone()), config);
+        node.join("conn-b");
+        node.start();
+
+        // The seed gets our view on the first tick
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let sent = mock.take_sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].connection_id.as_deref(), Some("conn-b"));
+        let view: Vec<Digest> =
+            serde_json::from_str(sent[0].envelope.capabilities().unwrap().get(GOSSIP_CAPABILITY).unwrap()).unwrap();
+        assert_eq!((view[0].id.as_str(), view[0].hb), ("a", 1));
+        assert_eq!(view[0].meta.get("role").map(String::as_str), Some("aggregator"));
+
+        // B answers with news of C, which we never talk to directly
+        mock.inject(gossip_from("b", &[("b", 4), ("c", 9)])).await.unwrap();
+        tokio::task::yield_now().await;
+        let members = node.members();
+        let ids: Vec<_> = members.iter().map(|m| m.node_id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b", "c"]);
+        assert_eq!(members[1].connection_id.as_deref(), Some("conn-b"));
+        assert_eq!(members[2].connection_id, None);
+        assert_eq!(node.alive(), vec!["a", "b", "c"]);
+
+        // B keeps beating, C goes quiet
+        for hb in 5..12 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            mock.inject(gossip_from("b", &[("b", hb), ("c", 9)])).await.unwrap();
+        }
+        tokio::task::yield_now().await;
+        let members = node.members();
+        assert_eq!(members[1].status, MemberStatus::Alive);
+        assert_eq!(members[2].status, MemberStatus::Suspect);
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(node.members()[2].status, MemberStatus::Dead);
+        // Only the direct neighbour was ever gossiped to
+        assert!(mock.take_sent().iter().all(|s| s.connection_id.as_deref() == Some("conn-b")));
+        node.stop();
+    }
+}
@@ -0,0 +1,462 @@
+/*!
+# UMICP GPU Backend
+
+Optional wgpu compute backend for the heavier matrix operations (requires the `gpu` feature).
+
+Each operation takes a [`ComputeDevice`] so callers can pin work to the CPU or GPU, or let
+[`ComputeDevice::Auto`] dispatch on problem size: small inputs stay on the CPU where the
+upload/readback round trip would dominate. Work that exceeds the device's dispatch or buffer
+limits also runs on the CPU, whatever the device asked for.
+*/
+
+use crate::error::{Result, UmicpError};
+use crate::matrix::Matrix;
+use crate::types::{MatrixResult, SimilarityMetric};
+use std::future::Future;
+use wgpu::util::DeviceExt;
+
+const MATMUL_SHADER: &str = r#"
+struct Dims { m: u32, n: u32, p: u32, pad: u32 }
+@group(0) @binding(0) var<uniform> dims: Dims;
+@group(0) @binding(1) var<storage, read> a: array<f32>;
+@group(0) @binding(2) var<storage, read> b: array<f32>;
+@group(0) @binding(3) var<storage, read_write> result: array<f32>;
+
+@compute @workgroup_size(8, 8)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let row = id.x;
+    let col = id.y;
+    if (row >= dims.m || col >= dims.p) {
+        return;
+    }
+    var sum = 0.0;
+    for (var k = 0u; k < dims.n; k = k + 1u) {
+        sum = sum + a[row * dims.n + k] * b[k * dims.p + col];
+    }
+    result[row * dims.p + col] = sum;
+}
+"#;
+
+const COSINE_SHADER: &str = r#"
+struct Dims { m: u32, n: u32, dim: u32, pad: u32 }
+@group(0) @binding(0) var<uniform> dims: Dims;
+@group(0) @binding(1) var<storage, read> a: array<f32>;
+@group(0) @binding(2) var<storage, read> b: array<f32>;
+@group(0) @binding(3) var<storage, read_write> result: array<f32>;
+
+@compute @workgroup_size(8, 8)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.x;
+    let j = id.y;
+    if (i >= dims.m || j >= dims.n) {
+        return;
+    }
+    var dot = 0.0;
+    var a_norm = 0.0;
+    var b_norm = 0.0;
+    for (var k = 0u; k < dims.dim; k = k + 1u) {
+        let x = a[i * dims.dim + k];
+        let y = b[j * dims.dim + k];
+        dot = dot + x * y;
+        a_norm = a_norm + x * x;
+        b_norm = b_norm + y * y;
+    }
+    let denom = sqrt(a_norm * b_norm);
+    result[i * dims.n + j] = select(0.0, dot / denom, denom > 0.0);
+}
+"#;
+
+const SOFTMAX_SHADER: &str = r#"
+struct Dims { rows: u32, cols: u32, inv_temperature: f32, pad: u32 }
+@group(0) @binding(0) var<uniform> dims: Dims;
+@group(0) @binding(1) var<storage, read> input: array<f32>;
+@group(0) @binding(2) var<storage, read_write> result: array<f32>;
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let row = id.x;
+    if (row >= dims.rows) {
+        return;
+    }
+    let start = row * dims.cols;
+    var max_val = input[start];
+    for (var j = 1u; j < dims.cols; j = j + 1u) {
+        max_val = max(max_val, input[start + j]);
+    }
+    var sum = 0.0;
+    for (var j = 0u; j < dims.cols; j = j + 1u) {
+        let e = exp((input[start + j] - max_val) * dims.inv_temperature);
+        result[start + j] = e;
+        sum = sum + e;
+    }
+    for (var j = 0u; j < dims.cols; j = j + 1u) {
+        result[start + j] = result[start + j] / sum;
+    }
+}
+"#;
+
+/// Where a GPU-capable operation should run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ComputeDevice {
+    /// Always run on the CPU
+    Cpu,
+    /// Always run on the GPU
+    Gpu,
+    /// Run on the GPU once the work size reaches the backend threshold
+    #[default]
+    Auto,
+}
+
+/// wgpu compute backend for matmul, batched cosine similarity and softmax
+pub struct GpuBackend {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    matmul: wgpu::ComputePipeline,
+    cosine: wgpu::ComputePipeline,
+    softmax: wgpu::ComputePipeline,
+    cpu: Matrix,
+    auto_threshold: usize,
+}
+
+impl GpuBackend {
+    /// Default work size (multiply-adds) above which `Auto` picks the GPU
+    pub const DEFAULT_AUTO_THRESHOLD: usize = 1 << 20;
+
+    /// Acquire a GPU adapter and compile the compute pipelines
+    pub async fn new() -> Result<Self> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                compatible_surface: None,
+                force_fallback_adapter: false,
+            })
+            .await
+            .ok_or_else(|| UmicpError::gpu("No compatible GPU adapter found"))?;
+
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: Some("umicp-gpu"),
+                    required_features: wgpu::Features::empty(),
+                    required_limits: wgpu::Limits::downlevel_defaults().using_resolution(adapter.limits()),
+                    memory_hints: wgpu::MemoryHints::Performance,
+                },
+                None,
+            )
+            .await
+            .map_err(|e| UmicpError::gpu(format!("Failed to create GPU device: {}", e)))?;
+
+        let matmul = Self::create_pipeline(&device, "umicp-matmul", MATMUL_SHADER);
+        let cosine = Self::create_pipeline(&device, "umicp-cosine", COSINE_SHADER);
+        let softmax = Self::create_pipeline(&device, "umicp-softmax", SOFTMAX_SHADER);
+
+        Ok(GpuBackend {
+            device,
+            queue,
+            matmul,
+            cosine,
+            softmax,
+            cpu: Matrix::new(),
+            auto_threshold: Self::DEFAULT_AUTO_THRESHOLD,
+        })
+    }
+
+    /// Blocking variant of [`GpuBackend::new`]
+    pub fn new_blocking() -> Result<Self> {
+        pollster::block_on(Self::new())
+    }
+
+    /// Set the work size at which `ComputeDevice::Auto` switches to the GPU
+    pub fn with_auto_threshold(mut self, threshold: usize) -> Self {
+        self.auto_threshold = threshold;
+        self
+    }
+
+    /// Get the `ComputeDevice::Auto` threshold
+    pub fn auto_threshold(&self) -> usize {
+        self.auto_threshold
+    }
+
+    /// Matrix multiplication: result = a * b (m x n) * (n x p) = (m x p)
+    #[allow(clippy::too_many_arguments)]
+    pub async fn multiply(
+        &self,
+        a: &[f32],
+        b: &[f32],
+        result: &mut [f32],
+        m: usize,
+        n: usize,
+        p: usize,
+        device: ComputeDevice,
+    ) -> Result<MatrixResult> {
+        let workgroups = (m.div_ceil(8), p.div_ceil(8));
+        if !self.use_gpu(device, m * n * p)
+            || a.len() != m * n
+            || b.len() != n * p
+            || result.len() != m * p
+            || !self.fits(workgroups, &[m * n, n * p, m * p])
+        {
+            return self.cpu.multiply(a, b, result, m, n, p);
+        }
+
+        let output = self
+            .dispatch(&self.matmul, [m as u32, n as u32, p as u32, 0], &[a, b], m * p, workgroups)
+            .await?;
+        result.copy_from_slice(&output);
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: None,
+        })
+    }
+
+    /// Batched cosine similarity between M vectors in `a` and N vectors in `b`
+    pub async fn cosine_similarity_batch(&self, a: &[f32], b: &[f32], dim: usize, device: ComputeDevice) -> Result<MatrixResult> {
+        if dim == 0 || !a.len().is_multiple_of(dim) || !b.len().is_multiple_of(dim) {
+            return self.cpu.similarity_matrix(a, b, dim, SimilarityMetric::Cosine);
+        }
+
+        let m = a.len() / dim;
+        let n = b.len() / dim;
+        let workgroups = (m.div_ceil(8), n.div_ceil(8));
+        if !self.use_gpu(device, m * n * dim) || !self.fits(workgroups, &[a.len(), b.len(), m * n]) {
+            return self.cpu.similarity_matrix(a, b, dim, SimilarityMetric::Cosine);
+        }
+
+        let scores = self
+            .dispatch(
+                &self.cosine,
+                [m as u32, n as u32, dim as u32, 0],
+                &[a, b],
+                m * n,
+                workgroups,
+            )
+            .await?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: Some(scores),
+        })
+    }
+
+    /// Row-wise softmax, computed in place
+    ///
+    /// Input holding non-finite logits is computed on the CPU.
+    pub async fn softmax(
+        &self,
+        matrix: &mut [f32],
+        rows: usize,
+        cols: usize,
+        temperature: Option<f32>,
+        device: ComputeDevice,
+    ) -> Result<MatrixResult> {
+        let temp = temperature.unwrap_or(1.0);
+        let workgroups = (rows.div_ceil(64), 1);
+        let valid = matrix.len() == rows * cols && temp > 0.0 && temp.is_finite();
+        let finite = matrix.iter().all(|x| x.is_finite());
+        if !(valid && finite && self.use_gpu(device, rows * cols) && self.fits(workgroups, &[matrix.len()])) {
+            return self.cpu.softmax(matrix, rows, cols, temperature);
+        }
+
+        let output = self
+            .dispatch(
+                &self.softmax,
+                [rows as u32, cols as u32, (1.0 / temp).to_bits(), 0],
+                &[matrix],
+                rows * cols,
+                workgroups,
+            )
+            .await?;
+        matrix.copy_from_slice(&output);
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: Some(output),
+        })
+    }
+
+    // Private helper methods
+
+    fn use_gpu(&self, device: ComputeDevice, work: usize) -> bool {
+        match device {
+            ComputeDevice::Cpu => false,
+            // wgpu rejects zero-sized bindings, so empty inputs always stay on the CPU
+            ComputeDevice::Gpu => work > 0,
+            ComputeDevice::Auto => work > 0 && work >= self.auto_threshold,
+        }
+    }
+
+    /// Whether `workgroups` and storage bindings of `lengths` floats are within the device limits
+    fn fits(&self, workgroups: (usize, usize), lengths: &[usize]) -> bool {
+        let limits = self.device.limits();
+        let max_workgroups = limits.max_compute_workgroups_per_dimension as usize;
+        let max_binding = (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size);
+        workgroups.0 <= max_workgroups
+            && workgroups.1 <= max_workgroups
+            && lengths
+                .iter()
+                .all(|&len| (len as u64).saturating_mul(std::mem::size_of::<f32>() as u64) <= max_binding)
+    }
+
+    fn create_pipeline(device: &wgpu::Device, label: &str, source: &str) -> wgpu::ComputePipeline {
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(label),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+
+        device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(label),
+            layout: None,
+            module: &module,
+            entry_point: "main",
+            compilation_options: Default::default(),
+            cache: None,
+        })
+    }
+
+    /// Upload inputs, run one dispatch and read back `output_len` floats.
+    /// Bindings are laid out as: 0 = uniform dims, 1..=N = inputs, N+1 = output.
+    async fn dispatch(
+        &self,
+        pipeline: &wgpu::ComputePipeline,
+        dims: [u32; 4],
+        inputs: &[&[f32]],
+        output_len: usize,
+        workgroups: (usize, usize),
+    ) -> Result<Vec<f32>> {
+        let output_size = (output_len * std::mem::size_of::<f32>()) as wgpu::BufferAddress;
+
+        let dims_buffer = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("umicp-dims"),
+            contents: bytemuck::cast_slice(&dims),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let input_buffers: Vec<wgpu::Buffer> = inputs
+            .iter()
+            .map(|data| {
+                self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("umicp-input"),
+                    contents: bytemuck::cast_slice(data),
+                    usage: wgpu::BufferUsages::STORAGE,
+                })
+            })
+            .collect();
+        let output_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("umicp-output"),
+            size: output_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let staging_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("umicp-staging"),
+            size: output_size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut entries = vec![wgpu::BindGroupEntry {
+            binding: 0,
+            resource: dims_buffer.as_entire_binding(),
+        }];
+        for (i, buffer) in input_buffers.iter().enumerate() {
+            entries.push(wgpu::BindGroupEntry {
+                binding: i as u32 + 1,
+                resource: buffer.as_entire_binding(),
+            });
+        }
+        entries.push(wgpu::BindGroupEntry {
+            binding: input_buffers.len() as u32 + 1,
+            resource: output_buffer.as_entire_binding(),
+        });
+
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("umicp-bind-group"),
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &entries,
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("umicp-encoder") });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("umicp-pass"),
+                timestamp_writes: None,
+            });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            // Checked against the device limits by `fits`
+            pass.dispatch_workgroups(workgroups.0 as u32, workgroups.1 as u32, 1);
+        }
+        encoder.copy_buffer_to_buffer(&output_buffer, 0, &staging_buffer, 0, output_size);
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = staging_buffer.slice(..);
+        let (sender, mut receiver) = futures_channel::oneshot::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        // Native backends only fire map callbacks while the device is polled. Waiting on the
+        // device would block the executor, so poll without blocking and yield between polls.
+        let mapped = std::future::poll_fn(|cx| {
+            self.device.poll(wgpu::Maintain::Poll);
+            let poll = Future::poll(std::pin::Pin::new(&mut receiver), cx);
+            if poll.is_pending() {
+                cx.waker().wake_by_ref();
+            }
+            poll
+        });
+
+        mapped
+            .await
+            .map_err(|_| UmicpError::gpu("GPU readback was cancelled"))?
+            .map_err(|e| UmicpError::gpu(format!("Failed to map GPU buffer: {}", e)))?;
+
+        let output = bytemuck::cast_slice::<u8, f32>(&slice.get_mapped_range()).to_vec();
+        staging_buffer.unmap();
+
+        Ok(output)
+    }
+}
+
+impl std::fmt::Debug for GpuBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GpuBackend")
+            .field("auto_threshold", &self.auto_threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gpu_matches_cpu() {
+        // CI machines frequently have no adapter; nothing to compare against then
+        let backend = match GpuBackend::new_blocking() {
+            Ok(backend) => backend,
+            Err(_) => return,
+        };
+
+        let a = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        let b = vec![7.0, 8.0, 9.0, 10.0, 11.0, 12.0];
+        let mut result = vec![0.0; 4];
+        pollster::block_on(backend.multiply(&a, &b, &mut result, 2, 3, 2, ComputeDevice::Gpu)).unwrap();
+        assert_eq!(result, vec![58.0, 64.0, 139.0, 154.0]);
+
+        let mut logits = vec![1.0, 2.0, 3.0];
+        pollster::block_on(backend.softmax(&mut logits, 1, 3, None, ComputeDevice::Gpu)).unwrap();
+        assert!((logits.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,871 @@
+/*!
+# UMICP Key Store
+
+Signing and encryption keys under stable key IDs, so peers can tell which key signed an envelope
+and keys can be replaced without breaking what was signed or encrypted with the old ones.
+
+Each [`KeyPurpose`] has at most one active key, the one new signatures and ciphertexts use.
+[`rotate`](KeyStore::rotate) generates a replacement and retires the previous key, which still
+verifies and decrypts until it is [`remove`](KeyStore::remove)d. Signing keys are Ed25519;
+encryption keys are 256-bit ChaCha20-Poly1305 keys for [`encrypt`](KeyStore::encrypt) and
+[`decrypt`](KeyStore::decrypt), such as an outbox encrypting its queue at rest. Public keys of
+peers are imported under the key IDs the peers sign with.
+
+[`KeyStore::open`] keeps the keys in a file encrypted with ChaCha20-Poly1305 under a key derived
+from a passphrase (PBKDF2-HMAC-SHA256), rewritten on every change; [`KeyStore::new`] keeps them
+in memory only. Secret key material is wiped from memory when dropped.
+
+[`sign_envelope`](KeyStore::sign_envelope) adds a [`SIGNATURE_CAPABILITY`] and the
+[`SIGNATURE_KEY_CAPABILITY`] naming the key; the signature covers every other field of the
+envelope, payload included, so [`verify_envelope`](KeyStore::verify_envelope) fails if any of
+them changed in transit.
+
+Every signature names its key ID (the `kid`), so a verifier can hold several keys of one signer
+at once and rotation needs no flag day. The signer [`generate`](KeyStore::generate)s its next key
+ahead of time, which stays inactive while another key is active, and publishes its
+[`public_key_set`](KeyStore::public_key_set), a JWK set that verifiers load with
+[`import_public_key_set`](KeyStore::import_public_key_set). Once they have it, the signer
+[`activate`](KeyStore::activate)s the new key and [`expire`](KeyStore::expire)s the old one after
+a grace period for envelopes still in flight; the next set it publishes carries that expiry, after
+which verifiers reject the old key too.
+
+Keys that must not leave their hardware sign through the [`Signer`] trait instead, which the
+store implements too; envelopes they sign with [`sign_envelope`] verify against a store holding
+their public keys like any other.
+
+```rust,no_run
+use umicp_core::keystore::{KeyPurpose, KeyStore};
+use umicp_core::Envelope;
+
+# fn main() -> umicp_core::Result<()> {
+let keys = KeyStore::open("keys.store", b"correct horse battery staple")?;
+if keys.active_key(KeyPurpose::Signing).is_none() {
+    keys.generate(KeyPurpose::Signing)?;
+}
+let mut envelope = Envelope::new();
+keys.sign_envelope(&mut envelope)?;
+let key_id = keys.verify_envelope(&envelope)?;
+# Ok(())
+# }
+```
+*/
+
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::utils::{base64_decode, base64_encode, generate_uuid};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+/// Capability carrying an envelope's signature, base64-encoded
+pub const SIGNATURE_CAPABILITY: &str = "signature";
+
+/// Capability naming the key an envelope was signed with
+pub const SIGNATURE_KEY_CAPABILITY: &str = "signature_key";
+
+/// Length of encryption keys, in bytes
+pub const ENCRYPTION_KEY_LEN: usize = 32;
+
+/// Format version of key store files
+const FILE_FORMAT: u32 = 1;
+/// PBKDF2 rounds for new key store files
+const KDF_ITERATIONS: u32 = 100_000;
+/// Binds the ciphertext to this use of the passphrase
+const FILE_AAD: &[u8] = b"umicp-keystore-v1";
+
+/// What a key is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyPurpose {
+    /// Ed25519 signatures
+    Signing,
+    /// Symmetric encryption
+    Encryption,
+}
+
+/// Whether a key is still used for new signatures and ciphertexts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyState {
+    /// Used for new signatures or ciphertexts; one per purpose
+    Active,
+    /// Kept to verify or decrypt what was made with it, or a peer's public key
+    Retired,
+}
+
+/// Description of a stored key, without its secret
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyInfo {
+    /// Key ID, as carried in [`SIGNATURE_KEY_CAPABILITY`]
+    pub id: String,
+    /// What the key is for
+    pub purpose: KeyPurpose,
+    /// Whether it is the active key of its purpose
+    pub state: KeyState,
+    /// When the key was generated or imported
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// When the key stops signing and verifying, if ever
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Public half of a signing key
+    pub public_key: Option<Vec<u8>>,
+    /// Whether the secret is held; `false` for a peer's public key
+    pub has_secret: bool,
+}
+
+/// Secret key material, wiped when dropped
+type Secret = Zeroizing<Vec<u8>>;
+
+/// A key as held in memory
+struct StoredKey {
+    info: KeyInfo,
+    /// PKCS#8 document of a signing key, or the raw encryption key
+    secret: Option<Secret>,
+}
+
+/// A key as written to the encrypted file
+#[derive(Serialize, Deserialize)]
+struct KeyRecord {
+    id: String,
+    purpose: KeyPurpose,
+    state: KeyState,
+    created_at: chrono::DateTime<chrono::Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    public_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+}
+
+/// Ed25519 public key in JWK form (RFC 8037), with `exp` for a key being phased out
+#[derive(Serialize, Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    crv: String,
+    #[serde(default)]
+    kid: String,
+    #[serde(default)]
+    x: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exp: Option<i64>,
+}
+
+/// A published set of public keys
+#[derive(Serialize, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+/// Layout of a key store file
+#[derive(Serialize, Deserialize)]
+struct StoreFile {
+    format: u32,
+    iterations: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Where a persistent store writes its keys, and the key it encrypts them with
+struct Persistence {
+    path: PathBuf,
+    iterations: u32,
+    salt: Vec<u8>,
+    key: LessSafeKey,
+}
+
+/// Makes Ed25519 signatures, wherever the private key lives
+///
+/// [`KeyStore`] signs with its active signing key; a PKCS#11 token or HSM signs through
+/// `Pkcs11Signer` (with the `pkcs11` feature), and cloud key services plug in the same way.
+pub trait Signer: Send + Sync {
+    /// ID of the key new signatures are made with, recorded in what they sign
+    fn key_id(&self) -> Result<String>;
+
+    /// Signature over `data` by key `key_id`
+    fn sign_with(&self, key_id: &str, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Sign `envelope` with `signer`'s current key, replacing any earlier signature
+pub fn sign_envelope(signer: &dyn Signer, envelope: &mut Envelope) -> Result<()> {
+    let key_id = signer.key_id()?;
+    // Named before signing, so the signature also covers which key made it
+    envelope.add_capability(SIGNATURE_KEY_CAPABILITY, &key_id);
+    let signature = signer.sign_with(&key_id, &envelope.signing_bytes()?)?;
+    envelope.add_capability(SIGNATURE_CAPABILITY, &base64_encode(&signature));
+    Ok(())
+}
+
+/// Signing and encryption keys by key ID
+pub struct KeyStore {
+    keys: Mutex<Vec<StoredKey>>,
+    persistence: Option<Persistence>,
+    rng: SystemRandom,
+}
+
+impl KeyStore {
+    /// Empty store kept in memory only
+    pub fn new() -> Self {
+        KeyStore {
+            keys: Mutex::new(Vec::new()),
+            persistence: None,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// Open the store at `path`, decrypting it with `passphrase`, or start an empty one there
+    ///
+    /// Fails with an authentication error if the passphrase is wrong or the file was tampered
+    /// with.
+    pub fn open(path: impl AsRef<Path>, passphrase: &[u8]) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let rng = SystemRandom::new();
+        if !path.exists() {
+            let mut salt = vec![0; 16];
+            fill(&rng, &mut salt)?;
+            let key = derive_key(passphrase, &salt, KDF_ITERATIONS)?;
+            let store = KeyStore {
+                keys: Mutex::new(Vec::new()),
+                persistence: Some(Persistence {
+                    path,
+                    iterations: KDF_ITERATIONS,
+                    salt,
+                    key,
+                }),
+                rng,
+            };
+            store.save(&[])?;
+            return Ok(store);
+        }
+
+        let file: StoreFile = serde_json::from_slice(&std::fs::read(&path)?)
+            .map_err(|e| UmicpError::serialization(format!("Failed to read key store: {}", e)))?;
+        if file.format != FILE_FORMAT {
+            return Err(UmicpError::serialization(format!("Unsupported key store format {}", file.format)));
+        }
+        let salt = base64_decode(&file.salt)?;
+        let key = derive_key(passphrase, &salt, file.iterations)?;
+        let nonce = Nonce::try_assume_unique_for_key(&base64_decode(&file.nonce)?)
+            .map_err(|_| UmicpError::serialization("Key store nonce has the wrong length"))?;
+        let mut sealed = Zeroizing::new(base64_decode(&file.ciphertext)?);
+        let plaintext = key
+            .open_in_place(nonce, Aad::from(FILE_AAD), &mut sealed)
+            .map_err(|_| UmicpError::authentication("Wrong passphrase, or the key store was tampered with"))?;
+        let records: Vec<KeyRecord> = serde_json::from_slice(plaintext)
+            .map_err(|e| UmicpError::serialization(format!("Failed to read key store: {}", e)))?;
+        let keys = records.into_iter().map(StoredKey::from_record).collect::<Result<_>>()?;
+
+        Ok(KeyStore {
+            keys: Mutex::new(keys),
+            persistence: Some(Persistence {
+                path,
+                iterations: file.iterations,
+                salt,
+                key,
+            }),
+            rng,
+        })
+    }
+
+    /// Generate a key for `purpose`; it becomes active if the purpose has no active key yet
+    pub fn generate(&self, purpose: KeyPurpose) -> Result<String> {
+        let (secret, public_key) = self.new_secret(purpose)?;
+        self.insert(purpose, secret, public_key, false)
+    }
+
+    /// Add an existing key: a PKCS#8 document for an Ed25519 signing key, or
+    /// [`ENCRYPTION_KEY_LEN`] raw bytes for an encryption key
+    ///
+    /// The key becomes active if the purpose has no active key yet.
+    pub fn import(&self, purpose: KeyPurpose, secret: &[u8]) -> Result<String> {
+        let public_key = match purpose {
+            KeyPurpose::Signing => Some(signing_key(secret)?.public_key().as_ref().to_vec()),
+            KeyPurpose::Encryption if secret.len() == ENCRYPTION_KEY_LEN => None,
+            KeyPurpose::Encryption => {
+                return Err(UmicpError::validation(format!(
+                    "Encryption keys are {} bytes, not {}",
+                    ENCRYPTION_KEY_LEN,
+                    secret.len()
+                )))
+            }
+        };
+        self.insert(purpose, Zeroizing::new(secret.to_vec()), public_key, false)
+    }
+
+    /// Trust a peer's Ed25519 public key for envelopes it signs as `id`
+    pub fn import_public_key(&self, id: &str, public_key: &[u8]) -> Result<()> {
+        if public_key.len() != 32 {
+            return Err(UmicpError::validation("Ed25519 public keys are 32 bytes"));
+        }
+        let mut keys = self.keys.lock().unwrap();
+        if keys.iter().any(|key| key.info.id == id) {
+            return Err(UmicpError::validation(format!("Key {} already exists", id)));
+        }
+        keys.push(StoredKey {
+            info: KeyInfo {
+                id: id.to_string(),
+                purpose: KeyPurpose::Signing,
+                state: KeyState::Retired,
+                created_at: chrono::Utc::now(),
+                expires_at: None,
+                public_key: Some(public_key.to_vec()),
+                has_secret: false,
+            },
+            secret: None,
+        });
+        self.save(&keys)
+    }
+
+    /// Generate a new active key for `purpose`, retiring the one it replaces
+    pub fn rotate(&self, purpose: KeyPurpose) -> Result<String> {
+        let (secret, public_key) = self.new_secret(purpose)?;
+        self.insert(purpose, secret, public_key, true)
+    }
+
+    /// Make key `id`, which must hold its secret, the active key of its purpose, retiring the
+    /// one it replaces
+    pub fn activate(&self, id: &str) -> Result<()> {
+        let mut keys = self.keys.lock().unwrap();
+        let key = keys
+            .iter()
+            .find(|key| key.info.id == id && key.secret.is_some())
+            .ok_or_else(|| UmicpError::configuration(format!("No key {} with a secret to activate", id)))?;
+        let purpose = key.info.purpose;
+        for key in keys.iter_mut().filter(|key| key.info.purpose == purpose) {
+            key.info.state = match key.info.id == id {
+                true => KeyState::Active,
+                false => KeyState::Retired,
+            };
+        }
+        self.save(&keys)
+    }
+
+    /// Stop key `id` from signing and verifying after `at`; `false` if there is no such key
+    pub fn expire(&self, id: &str, at: chrono::DateTime<chrono::Utc>) -> Result<bool> {
+        let mut keys = self.keys.lock().unwrap();
+        let Some(key) = keys.iter_mut().find(|key| key.info.id == id) else {
+            return Ok(false);
+        };
+        key.info.expires_at = Some(at);
+        self.save(&keys)?;
+        Ok(true)
+    }
+
+    /// This store's own unexpired signing keys, active or not, as a JWK set for verifiers
+    pub fn public_key_set(&self) -> String {
+        let now = chrono::Utc::now();
+        let keys = self.keys.lock().unwrap();
+        let published = keys
+            .iter()
+            .filter(|key| key.info.purpose == KeyPurpose::Signing && key.secret.is_some() && !key.info.expired(now))
+            .filter_map(|key| {
+                Some(Jwk {
+                    kty: "OKP".to_string(),
+                    crv: "Ed25519".to_string(),
+                    kid: key.info.id.clone(),
+                    x: base64::encode_config(key.info.public_key.as_ref()?, base64::URL_SAFE_NO_PAD),
+                    exp: key.info.expires_at.map(|at| at.timestamp()),
+                })
+            })
+            .collect();
+        serde_json::to_string(&JwkSet { keys: published }).expect("JWK sets serialize")
+    }
+
+    /// Trust the Ed25519 keys of a peer's [`public_key_set`](Self::public_key_set); returns the
+    /// IDs of keys not held before
+    ///
+    /// Keys already imported take on the expiry the set gives them. Keys of other types are
+    /// skipped.
+    pub fn import_public_key_set(&self, set: &str) -> Result<Vec<String>> {
+        let set: JwkSet = serde_json::from_str(set)
+            .map_err(|e| UmicpError::serialization(format!("Invalid public key set: {}", e)))?;
+        let mut keys = self.keys.lock().unwrap();
+        let mut added = Vec::new();
+        for jwk in set.keys.into_iter().filter(|jwk| jwk.kty == "OKP" && jwk.crv == "Ed25519") {
+            let public_key = base64::decode_config(&jwk.x, base64::URL_SAFE_NO_PAD)
+                .ok()
+                .filter(|key| key.len() == 32)
+                .ok_or_else(|| UmicpError::validation(format!("Invalid Ed25519 public key {}", jwk.kid)))?;
+            let expires_at = jwk.exp.and_then(|exp| chrono::DateTime::from_timestamp(exp, 0));
+            match keys.iter_mut().find(|key| key.info.id == jwk.kid) {
+                Some(key) if key.info.public_key.as_ref() != Some(&public_key) => {
+                    return Err(UmicpError::validation(format!("Key {} already exists", jwk.kid)));
+                }
+                Some(key) => key.info.expires_at = expires_at.or(key.info.expires_at),
+                None => {
+                    keys.push(StoredKey {
+                        info: KeyInfo {
+                            id: jwk.kid.clone(),
+                            purpose: KeyPurpose::Signing,
+                            state: KeyState::Retired,
+                            created_at: chrono::Utc::now(),
+                            expires_at,
+                            public_key: Some(public_key),
+                            has_secret: false,
+                        },
+                        secret: None,
+                    });
+                    added.push(jwk.kid);
+                }
+            }
+        }
+        self.save(&keys)?;
+        Ok(added)
+    }
+
+    /// Forget a key; `false` if there was none with that ID
+    ///
+    /// What it signed or encrypted can no longer be verified or decrypted here.
+    pub fn remove(&self, id: &str) -> Result<bool> {
+        let mut keys = self.keys.lock().unwrap();
+        let before = keys.len();
+        keys.retain(|key| key.info.id != id);
+        if keys.len() == before {
+            return Ok(false);
+        }
+        self.save(&keys)?;
+        Ok(true)
+    }
+
+    /// ID of the key new signatures or ciphertexts of `purpose` use
+    pub fn active_key(&self, purpose: KeyPurpose) -> Option<String> {
+        let keys = self.keys.lock().unwrap();
+        active(&keys, purpose).map(|key| key.info.id.clone())
+    }
+
+    /// Every stored key, oldest first
+    pub fn keys(&self) -> Vec<KeyInfo> {
+        self.keys.lock().unwrap().iter().map(|key| key.info.clone()).collect()
+    }
+
+    /// Public half of signing key `id`, for peers to verify its signatures with
+    pub fn public_key(&self, id: &str) -> Option<Vec<u8>> {
+        let keys = self.keys.lock().unwrap();
+        keys.iter().find(|key| key.info.id == id)?.info.public_key.clone()
+    }
+
+    /// Sign `data` with the active signing key; returns the key ID and the signature
+    pub fn sign(&self, data: &[u8]) -> Result<(String, Vec<u8>)> {
+        let keys = self.keys.lock().unwrap();
+        let key = active_signing_key(&keys)?;
+        Ok((key.info.id.clone(), key.sign(data)?))
+    }
+
+    /// Check that `signature` over `data` was made with signing key `id`
+    pub fn verify(&self, id: &str, data: &[u8], signature: &[u8]) -> Result<()> {
+        let keys = self.keys.lock().unwrap();
+        let info = keys
+            .iter()
+            .map(|key| &key.info)
+            .find(|info| info.id == id && info.purpose == KeyPurpose::Signing && info.public_key.is_some())
+            .ok_or_else(|| UmicpError::authentication(format!("Unknown signing key: {}", id)))?;
+        if info.expired(chrono::Utc::now()) {
+            return Err(UmicpError::authentication(format!("Signing key {} has expired", id)));
+        }
+        let public_key = info.public_key.as_deref().unwrap_or_default();
+        UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(data, signature)
+            .map_err(|_| UmicpError::authentication(format!("Invalid signature by key {}", id)))
+    }
+
+    /// Encrypt `plaintext` with the active encryption key (ChaCha20-Poly1305), authenticating
+    /// `aad` along with it; returns the key ID and the nonce followed by the ciphertext
+    pub fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<(String, Vec<u8>)> {
+        let keys = self.keys.lock().unwrap();
+        let key = active(&keys, KeyPurpose::Encryption)
+            .ok_or_else(|| UmicpError::configuration("No active encryption key"))?;
+        let mut nonce = [0; NONCE_LEN];
+        fill(&self.rng, &mut nonce)?;
+        let mut sealed = plaintext.to_vec();
+        key.cipher()?
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(aad), &mut sealed)
+            .map_err(|_| UmicpError::generic("Encryption failed"))?;
+        let mut output = nonce.to_vec();
+        output.extend(sealed);
+        Ok((key.info.id.clone(), output))
+    }
+
+    /// Decrypt what [`encrypt`](Self::encrypt) made with encryption key `id`, active or retired
+    ///
+    /// Fails with an authentication error if the ciphertext or `aad` was changed.
+    pub fn decrypt(&self, id: &str, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let keys = self.keys.lock().unwrap();
+        let key = keys
+            .iter()
+            .find(|key| key.info.id == id && key.info.purpose == KeyPurpose::Encryption)
+            .ok_or_else(|| UmicpError::authentication(format!("Unknown encryption key: {}", id)))?;
+        if sealed.len() < NONCE_LEN {
+            return Err(UmicpError::authentication("Ciphertext is too short"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).expect("nonce has the right length");
+        let mut buffer = ciphertext.to_vec();
+        let plaintext = key
+            .cipher()?
+            .open_in_place(nonce, Aad::from(aad), &mut buffer)
+            .map_err(|_| UmicpError::authentication(format!("Decryption with key {} failed", id)))?;
+        Ok(plaintext.to_vec())
+    }
+
+    /// Sign `envelope` with the active signing key, replacing any earlier signature
+    pub fn sign_envelope(&self, envelope: &mut Envelope) -> Result<()> {
+        sign_envelope(self, envelope)
+    }
+
+    /// Check the signature on `envelope`; returns the ID of the key that made it
+    pub fn verify_envelope(&self, envelope: &Envelope) -> Result<String> {
+        let capability = |name: &str| {
+            envelope
+                .capabilities()
+                .and_then(|capabilities| capabilities.get(name))
+                .ok_or_else(|| UmicpError::authentication("Envelope is not signed"))
+        };
+        let id = capability(SIGNATURE_KEY_CAPABILITY)?;
+        let signature = base64_decode(capability(SIGNATURE_CAPABILITY)?)?;
+        self.verify(id, &envelope.signing_bytes()?, &signature)?;
+        Ok(id.clone())
+    }
+
+    /// Fresh secret for `purpose`, with the public key of a signing key
+    fn new_secret(&self, purpose: KeyPurpose) -> Result<(Secret, Option<Vec<u8>>)> {
+        match purpose {
+            KeyPurpose::Signing => {
+                let pkcs8 = Ed25519KeyPair::generate_pkcs8(&self.rng)
+                    .map_err(|_| UmicpError::generic("Failed to generate a signing key"))?;
+                let secret = Zeroizing::new(pkcs8.as_ref().to_vec());
+                let public_key = signing_key(&secret)?.public_key().as_ref().to_vec();
+                Ok((secret, Some(public_key)))
+            }
+            KeyPurpose::Encryption => {
+                let mut secret = Zeroizing::new(vec![0; ENCRYPTION_KEY_LEN]);
+                fill(&self.rng, &mut secret)?;
+                Ok((secret, None))
+            }
+        }
+    }
+
+    /// Store a key with a new ID, making it active when `replace` is set or nothing else is
+    fn insert(
+        &self,
+        purpose: KeyPurpose,
+        secret: Secret,
+        public_key: Option<Vec<u8>>,
+        replace: bool,
+    ) -> Result<String> {
+        let mut keys = self.keys.lock().unwrap();
+        let current = keys
+            .iter_mut()
+            .find(|key| key.info.purpose == purpose && key.info.state == KeyState::Active);
+        let state = match current {
+            Some(current) if replace => {
+                current.info.state = KeyState::Retired;
+                KeyState::Active
+            }
+            Some(_) => KeyState::Retired,
+            None => KeyState::Active,
+        };
+        let id = generate_uuid();
+        keys.push(StoredKey {
+            info: KeyInfo {
+                id: id.clone(),
+                purpose,
+                state,
+                created_at: chrono::Utc::now(),
+                expires_at: None,
+                public_key,
+                has_secret: true,
+            },
+            secret: Some(secret),
+        });
+        self.save(&keys)?;
+        Ok(id)
+    }
+
+    /// Rewrite the file of a persistent store with `keys`
+    fn save(&self, keys: &[StoredKey]) -> Result<()> {
+        let Some(persistence) = &self.persistence else {
+            return Ok(());
+        };
+        let records: Vec<KeyRecord> = keys.iter().map(StoredKey::to_record).collect();
+        let mut sealed = Zeroizing::new(
+            serde_json::to_vec(&records)
+                .map_err(|e| UmicpError::serialization(format!("Failed to encode key store: {}", e)))?,
+        );
+        let mut nonce = [0; NONCE_LEN];
+        fill(&self.rng, &mut nonce)?;
+        persistence
+            .key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(FILE_AAD), &mut *sealed)
+            .map_err(|_| UmicpError::generic("Failed to encrypt key store"))?;
+        let file = StoreFile {
+            format: FILE_FORMAT,
+            iterations: persistence.iterations,
+            salt: base64_encode(&persistence.salt),
+            nonce: base64_encode(&nonce),
+            ciphertext: base64_encode(&sealed),
+        };
+        let encoded = serde_json::to_vec(&file)
+            .map_err(|e| UmicpError::serialization(format!("Failed to encode key store: {}", e)))?;
+
+        // Replaced in one step, so a crash leaves either the old keys or the new ones
+        let temp = persistence.path.with_extension("tmp");
+        std::fs::write(&temp, encoded)?;
+        std::fs::File::open(&temp)?.sync_all()?;
+        std::fs::rename(&temp, &persistence.path)?;
+        Ok(())
+    }
+}
+
+impl Default for KeyStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Signer for KeyStore {
+    fn key_id(&self) -> Result<String> {
+        Ok(active_signing_key(&self.keys.lock().unwrap())?.info.id.clone())
+    }
+
+    /// Signs with any signing key of the store that holds a secret, so a signature started just
+    /// before a rotation still completes with the key it named
+    fn sign_with(&self, key_id: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let keys = self.keys.lock().unwrap();
+        keys.iter()
+            .find(|key| key.info.id == key_id && key.info.purpose == KeyPurpose::Signing)
+            .ok_or_else(|| UmicpError::configuration(format!("Unknown signing key: {}", key_id)))?
+            .sign(data)
+    }
+}
+
+impl std::fmt::Debug for KeyStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyStore")
+            .field("keys", &self.keys())
+            .field("path", &self.persistence.as_ref().map(|persistence| &persistence.path))
+            .finish()
+    }
+}
+
+impl KeyInfo {
+    /// Whether the key's expiry has passed at `now`
+    fn expired(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+impl StoredKey {
+    /// Ed25519 signature over `data`
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if self.info.expired(chrono::Utc::now()) {
+            return Err(UmicpError::configuration(format!("Signing key {} has expired", self.info.id)));
+        }
+        let secret = self
+            .secret
+            .as_ref()
+            .ok_or_else(|| UmicpError::configuration(format!("Key {} has no secret to sign with", self.info.id)))?;
+        Ok(signing_key(secret)?.sign(data).as_ref().to_vec())
+    }
+
+    /// ChaCha20-Poly1305 key of an encryption key
+    fn cipher(&self) -> Result<LessSafeKey> {
+        let secret = self
+            .secret
+            .as_ref()
+            .ok_or_else(|| UmicpError::configuration(format!("Key {} has no secret", self.info.id)))?;
+        let key = UnboundKey::new(&CHACHA20_POLY1305, secret)
+            .map_err(|_| UmicpError::configuration(format!("Key {} is not an encryption key", self.info.id)))?;
+        Ok(LessSafeKey::new(key))
+    }
+
+    fn to_record(&self) -> KeyRecord {
+        KeyRecord {
+            id: self.info.id.clone(),
+            purpose: self.info.purpose,
+            state: self.info.state,
+            created_at: self.info.created_at,
+            expires_at: self.info.expires_at,
+            public_key: self.info.public_key.as_deref().map(base64_encode),
+            secret: self.secret.as_deref().map(|secret| base64_encode(secret)),
+        }
+    }
+
+    fn from_record(record: KeyRecord) -> Result<Self> {
+        let secret = record.secret.as_deref().map(base64_decode).transpose()?.map(Zeroizing::new);
+        Ok(StoredKey {
+            info: KeyInfo {
+                id: record.id,
+                purpose: record.purpose,
+                state: record.state,
+                created_at: record.created_at,
+                expires_at: record.expires_at,
+                public_key: record.public_key.as_deref().map(base64_decode).transpose()?,
+                has_secret: secret.is_some(),
+            },
+            secret,
+        })
+    }
+}
+
+/// The active key of `purpose`
+fn active(keys: &[StoredKey], purpose: KeyPurpose) -> Option<&StoredKey> {
+    keys.iter()
+        .find(|key| key.info.purpose == purpose && key.info.state == KeyState::Active)
+}
+
+fn active_signing_key(keys: &[StoredKey]) -> Result<&StoredKey> {
+    active(keys, KeyPurpose::Signing).ok_or_else(|| UmicpError::configuration("No active signing key"))
+}
+
+fn signing_key(pkcs8: &[u8]) -> Result<Ed25519KeyPair> {
+    Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8)
+        .map_err(|e| UmicpError::validation(format!("Invalid Ed25519 PKCS#8 key: {}", e)))
+}
+
+fn fill(rng: &SystemRandom, buffer: &mut [u8]) -> Result<()> {
+    rng.fill(buffer)
+        .map_err(|_| UmicpError::generic("The system random number generator failed"))
+}
+
+/// Key encrypting a store file, derived from its passphrase
+fn derive_key(passphrase: &[u8], salt: &[u8], iterations: u32) -> Result<LessSafeKey> {
+    let iterations =
+        NonZeroU32::new(iterations).ok_or_else(|| UmicpError::serialization("Key store has no KDF iterations"))?;
+    let mut key = Zeroizing::new([0; 32]);
+    ring::pbkdf2::derive(ring::pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase, &mut *key);
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &*key).expect("ChaCha20-Poly1305 keys are 32 bytes");
+    Ok(LessSafeKey::new(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::OperationType;
+
+    #[test]
+    fn test_keystore_rotation_and_envelope_signatures() {
+        let keys = KeyStore::new();
+        assert!(keys.sign(b"data").is_err());
+        let first = keys.generate(KeyPurpose::Signing).unwrap();
+        assert_eq!(keys.active_key(KeyPurpose::Signing), Some(first.clone()));
+
+        let mut envelope = Envelope::builder()
+            .from("node-a")
+            .to("node-b")
+            .operation(OperationType::Data)
+            .capability("round", "7")
+            .payload(vec![1, 2, 3])
+            .build()
+            .unwrap();
+        keys.sign_envelope(&mut envelope).unwrap();
+        let received = Envelope::deserialize(&envelope.serialize().unwrap()).unwrap();
+        assert_eq!(keys.verify_envelope(&received).unwrap(), first);
+
+        // Any change to the signed fields breaks the signature
+        let mut tampered = received.clone();
+        tampered.add_capability("round", "8");
+        assert!(keys.verify_envelope(&tampered).is_err());
+        let mut tampered = received.clone();
+        tampered.set_payload(vec![1, 2, 4]);
+        assert!(keys.verify_envelope(&tampered).is_err());
+        assert!(keys.verify_envelope(&Envelope::new()).is_err());
+
+        // The retired key still verifies; new signatures use its replacement
+        let second = keys.rotate(KeyPurpose::Signing).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(keys.verify_envelope(&received).unwrap(), first);
+        let (signed_by, signature) = keys.sign(b"data").unwrap();
+        assert_eq!(signed_by, second);
+        keys.verify(&second, b"data", &signature).unwrap();
+        assert!(keys.verify(&first, b"data", &signature).is_err());
+
+        // A peer verifies with the public key alone
+        let peer = KeyStore::new();
+        peer.import_public_key(&first, &keys.public_key(&first).unwrap()).unwrap();
+        assert_eq!(peer.verify_envelope(&received).unwrap(), first);
+        assert!(peer.sign(b"data").is_err());
+
+        assert!(keys.remove(&first).unwrap());
+        assert!(keys.verify_envelope(&received).is_err());
+        assert!(keys.import(KeyPurpose::Encryption, &[0; 16]).is_err());
+    }
+
+    #[test]
+    fn test_keystore_staged_rotation() {
+        let signer = KeyStore::new();
+        let verifier = KeyStore::new();
+        let first = signer.generate(KeyPurpose::Signing).unwrap();
+        assert_eq!(verifier.import_public_key_set(&signer.public_key_set()).unwrap(), vec![first.clone()]);
+
+        // The next key is published before anything is signed with it
+        let next = signer.generate(KeyPurpose::Signing).unwrap();
+        assert_eq!(signer.active_key(KeyPurpose::Signing), Some(first.clone()));
+        let mut old = Envelope::new();
+        signer.sign_envelope(&mut old).unwrap();
+        assert_eq!(verifier.import_public_key_set(&signer.public_key_set()).unwrap(), vec![next.clone()]);
+        assert!(verifier.import_public_key_set(&signer.public_key_set()).unwrap().is_empty());
+
+        signer.activate(&next).unwrap();
+        let mut new = Envelope::new();
+        signer.sign_envelope(&mut new).unwrap();
+        assert_eq!(verifier.verify_envelope(&old).unwrap(), first);
+        assert_eq!(verifier.verify_envelope(&new).unwrap(), next);
+
+        // Once the grace period is over the old key is refused on both sides
+        let grace_over = chrono::Utc::now() - chrono::Duration::seconds(1);
+        assert!(signer.expire(&first, grace_over).unwrap());
+        assert!(!signer.public_key_set().contains(&first));
+        signer.activate(&first).unwrap();
+        assert!(signer.sign_envelope(&mut Envelope::new()).is_err());
+        signer.activate(&next).unwrap();
+        verifier.expire(&first, grace_over).unwrap();
+        let error = verifier.verify_envelope(&old).unwrap_err();
+        assert!(error.to_string().contains("expired"), "{}", error);
+        assert_eq!(verifier.verify_envelope(&new).unwrap(), next);
+
+        // A set cannot swap the public key behind a known key ID
+        let impostor = KeyStore::new();
+        let impostor_key = impostor.generate(KeyPurpose::Signing).unwrap();
+        let forged = impostor.public_key_set().replace(&impostor_key, &next);
+        assert!(verifier.import_public_key_set(&forged).is_err());
+        assert!(verifier.activate(&next).is_err());
+    }
+
+    #[test]
+    fn test_keystore_persists_encrypted() {
+        let dir = std::env::temp_dir().join(format!("umicp-keystore-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("keys.store");
+        let secret = [7u8; ENCRYPTION_KEY_LEN];
+
+        let (signing, encryption) = {
+            let keys = KeyStore::open(&path, b"passphrase").unwrap();
+            let signing = keys.generate(KeyPurpose::Signing).unwrap();
+            let encryption = keys.import(KeyPurpose::Encryption, &secret).unwrap();
+            (signing, encryption)
+        };
+        // Nothing readable at rest
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains(&signing) && !contents.contains(&base64_encode(&secret)));
+        assert!(KeyStore::open(&path, b"wrong").is_err());
+
+        let keys = KeyStore::open(&path, b"passphrase").unwrap();
+        assert_eq!(keys.active_key(KeyPurpose::Signing), Some(signing));
+        assert_eq!(keys.active_key(KeyPurpose::Encryption), Some(encryption.clone()));
+        assert!(keys.keys().iter().all(|key| key.has_secret));
+        let rotated = keys.rotate(KeyPurpose::Encryption).unwrap();
+        drop(keys);
+
+        let keys = KeyStore::open(&path, b"passphrase").unwrap();
+        assert_eq!(keys.active_key(KeyPurpose::Encryption), Some(rotated));
+        let states: Vec<_> = keys.keys().into_iter().map(|key| (key.id, key.state)).collect();
+        assert!(states.contains(&(encryption, KeyState::Retired)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
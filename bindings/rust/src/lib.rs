@@ -45,44 +45,48 @@ let received = Envelope::deserialize(&serialized)?;
 ### WebSocket Transport
 
 ```rust,no_run
+# #[cfg(feature = "websocket")]
+# async fn example() -> Result<(), Box<dyn std::error::Error>> {
 use umicp_core::{WebSocketTransport, Envelope, OperationType};
 
-# fn example() -> Result<(), Box<dyn std::error::Error>> {
 // Server setup (requires websocket feature)
-#[cfg(feature = "websocket")]
-let server = WebSocketTransport::new_server("127.0.0.1:8080")?;
+let server = WebSocketTransport::new_server("127.0.0.1:8080").await?;
+
+// Message handling: the transport is a cloneable handle, so handlers can reply through it
+let responder = server.clone();
+server.set_message_handler(move |envelope, conn_id| {
+    let responder = responder.clone();
+    async move {
+        println!("Received: {:?}", envelope.capabilities());
+        // Echo response
+        let response = Envelope::builder()
+            .from("server")
+            .to(envelope.from())
+            .operation(OperationType::Ack)
+            .message_id(&uuid::Uuid::new_v4().to_string())
+            .build()?;
+
+        responder.send(response, &conn_id).await
+    }
+});
+let runner = server.clone();
+tokio::spawn(async move { runner.run().await });
 
 // Client setup (requires websocket feature)
-#[cfg(feature = "websocket")]
-let client = WebSocketTransport::new_client("ws://127.0.0.1:8080")?;
-
-// Message handling
-#[cfg(feature = "websocket")]
-server.set_message_handler(|envelope, conn_id| async move {
-    println!("Received: {:?}", envelope.capabilities());
-    // Echo response
-    let response = Envelope::builder()
-        .from("server")
-        .to(envelope.from())
-        .operation(OperationType::Ack)
-        .message_id(&format!("response-{}", uuid::Uuid::new_v4()))
-        .build()?;
-
-    server.send(response, &conn_id).await?;
-    Ok(())
-});
+let client = WebSocketTransport::new_client("ws://127.0.0.1:8080").await?;
+let runner = client.clone();
+tokio::spawn(async move { runner.run().await });
 
 // Send message
 let message = Envelope::builder()
     .from("client")
     .to("server")
     .operation(OperationType::Data)
-    .message_id("hello-001")
+    .message_id(&uuid::Uuid::new_v4().to_string())
     .capability("message", "Hello UMICP!")
     .build()?;
 
-#[cfg(feature = "websocket")]
-client.send(message, "")?;
+client.send_to_server(message).await?;
 # Ok(())
 # }
 ```
@@ -121,18 +125,99 @@ println!("Matrix multiplication: {:?}", matrix_result);
 ```
 */
 
+pub mod auth;
+pub mod delegation;
+pub mod delta;
+#[cfg(feature = "websocket")]
+pub mod distribution;
 pub mod envelope;
+pub mod evaluation;
+pub mod federated;
+pub mod gossip;
+pub mod limits;
 pub mod matrix;
+pub mod personalization;
+pub mod router;
 pub mod transport;
 pub mod types;
 pub mod error;
 pub mod utils;
-
-pub use envelope::Envelope;
-pub use matrix::Matrix;
-pub use transport::{WebSocketTransport, Http2Transport};
+pub mod view;
+#[cfg(feature = "gpu")]
+pub mod gpu;
+#[cfg(feature = "crypto")]
+pub mod keystore;
+#[cfg(feature = "oidc")]
+pub mod oidc;
+#[cfg(feature = "pkcs11")]
+pub mod pkcs11;
+pub mod policy;
+pub mod privacy;
+pub mod quota;
+#[cfg(feature = "crypto")]
+pub mod receipt;
+pub mod registry;
+pub mod round;
+#[cfg(feature = "safetensors")]
+pub mod safetensors;
+pub mod secret;
+#[cfg(feature = "crypto")]
+pub mod session;
+#[cfg(feature = "arrow")]
+pub mod arrow;
+
+pub use auth::{Authorizer, JwtValidator, Principal, TokenValidator};
+pub use delegation::{Caveat, DelegationAuthorizer, DelegationToken, DELEGATION_CAPABILITY};
+pub use policy::{Effect, Policy, Rule};
+pub use quota::{Quota, QuotaTracker};
+pub use secret::Secret;
+pub use envelope::{Envelope, PRIORITY_CAPABILITY};
+pub use delta::{DeltaEncoder, ModelDelta};
+pub use evaluation::{ConfusionMatrix, EvaluationReport, MetricsAggregator};
+pub use federated::{FedAvg, ModelUpdate};
+pub use gossip::{GossipConfig, GossipNode};
+pub use registry::{ModelRegistry, ModelVersion};
+pub use round::{RoundConfig, RoundCoordinator};
+pub use limits::EnvelopeLimits;
+pub use matrix::{Matrix, OpProfile, ProfileReport};
+pub use personalization::{AggregationPlan, TensorFedAvg, TensorUpdate};
+pub use router::MessageRouter;
+pub use view::{MatrixView, MatrixViewMut};
+pub use transport::{
+    BackendStatus, BinaryFrame, CancellationToken, CapabilityMatcher, DedupStore, DeliveryOutcome, DeliveryReceipt,
+    DropReason, EventStream, EvictionPolicy, FileDedupStore, FilterExpression, Http2Transport, Incoming,
+    LoadBalancedTransport, LoopbackTransport, MemoryDedupStore, MessageDirection, Middleware, MiddlewareContext,
+    MiddlewareTransport, MockFault, MockTransport, Next, OfflineQueueConfig, OfflineQueueTransport, OutboxConfig,
+    ObservedTransport, OutboxTransport, ReliableTransport, SentEnvelope, Subscribers, SubscriptionFilter,
+    Subscription, Transport, TransportEvent, TransportObserver, DELIVERY_CAPABILITY, ERROR_CAPABILITY,
+    ERROR_CODE_CAPABILITY, serve_requests, serve_requests_until,
+};
+#[cfg(feature = "websocket")]
+pub use distribution::{ModelDistributor, ModelDownload};
+#[cfg(feature = "websocket")]
+pub use transport::{IncomingStream, WebSocketTransport, ENDPOINT_CAPABILITY, IDLE_TIMEOUT_CAPABILITY};
+#[cfg(feature = "sse")]
+pub use transport::SseTransport;
+#[cfg(feature = "wasm")]
+pub use transport::BrowserWebSocketTransport;
+#[cfg(feature = "long-polling")]
+pub use transport::{FallbackTransport, LongPollingTransport};
+#[cfg(feature = "mqtt")]
+pub use transport::{MqttConfig, MqttQos, MqttTransport, MqttVersion};
+#[cfg(feature = "quic")]
+pub use transport::{QuicStreamMode, QuicTransport};
 pub use types::*;
 pub use error::*;
+#[cfg(feature = "gpu")]
+pub use gpu::{ComputeDevice, GpuBackend};
+#[cfg(feature = "crypto")]
+pub use keystore::{KeyPurpose, KeyStore, Signer, SIGNATURE_CAPABILITY, SIGNATURE_KEY_CAPABILITY};
+#[cfg(feature = "oidc")]
+pub use oidc::OidcValidator;
+#[cfg(feature = "pkcs11")]
+pub use pkcs11::Pkcs11Signer;
+#[cfg(feature = "crypto")]
+pub use session::{Cipher, Session, SessionInitiator};
 
 /// Version information
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -162,6 +247,36 @@ pub mod umicp {
         cfg!(feature = "http2")
     }
 
+    /// Check if QUIC transport is available
+    pub fn has_quic_transport() -> bool {
+        cfg!(feature = "quic")
+    }
+
+    /// Check if the MQTT transport adapter is available
+    pub fn has_mqtt_transport() -> bool {
+        cfg!(feature = "mqtt")
+    }
+
+    /// Check if zstd message compression is available
+    pub fn has_zstd_compression() -> bool {
+        cfg!(feature = "zstd")
+    }
+
+    /// Check if safetensors interop is available
+    pub fn has_safetensors() -> bool {
+        cfg!(feature = "safetensors")
+    }
+
+    /// Check if Apache Arrow interop is available
+    pub fn has_arrow() -> bool {
+        cfg!(feature = "arrow")
+    }
+
+    /// Check if the GPU compute backend is available
+    pub fn has_gpu_backend() -> bool {
+        cfg!(feature = "gpu")
+    }
+
     /// Get version information
     pub fn version() -> &'static str {
         VERSION
@@ -0,0 +1,195 @@
+/*!
+# UMICP Envelope Limits
+
+Bounds on what the envelope parser accepts, since envelopes arrive from untrusted peers. Without
+them a few kilobytes of nested brackets, or an envelope carrying a million capabilities, costs the
+receiver far more memory and time than it cost the sender.
+
+[`Envelope::deserialize`](crate::Envelope::deserialize) and [`Envelope::decode`](crate::Envelope::decode)
+check the [`EnvelopeLimits`] set with [`set_envelope_limits`], and
+[`Envelope::decode_with_limits`](crate::Envelope::decode_with_limits) takes its own. Size and
+nesting are checked before parsing starts, and capability and payload reference counts before
+the payload is decoded. An envelope over a limit is rejected with a `Validation` error naming it.
+
+The defaults are generous for envelopes this crate produces; a deployment moving larger
+envelopes raises `max_size` along with its transports' `max_payload_size`.
+
+```rust
+use umicp_core::limits::{envelope_limits, set_envelope_limits, EnvelopeLimits};
+use umicp_core::{Envelope, EnvelopeCodec, UmicpError};
+
+set_envelope_limits(EnvelopeLimits {
+    max_capabilities: 2,
+    ..EnvelopeLimits::default()
+});
+let mut envelope = Envelope::builder().from("a").to("b").build()?;
+for key in ["x", "y", "z"] {
+    envelope.add_capability(key, "1");
+}
+let error = Envelope::deserialize(&envelope.serialize()?).unwrap_err();
+assert!(matches!(error, UmicpError::Validation { .. }));
+
+// A single call can be held to other limits
+let relaxed = EnvelopeLimits { max_capabilities: 8, ..envelope_limits() };
+assert!(Envelope::decode_with_limits(&envelope.encode(EnvelopeCodec::Json)?, EnvelopeCodec::Json, &relaxed).is_ok());
+# set_envelope_limits(EnvelopeLimits::default());
+# Ok::<(), UmicpError>(())
+```
+*/
+
+use crate::error::{Result, UmicpError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// What the envelope parser accepts from a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvelopeLimits {
+    /// Largest encoded envelope, in bytes
+    pub max_size: usize,
+    /// Most capabilities one envelope may carry
+    pub max_capabilities: usize,
+    /// Longest capability key, in bytes
+    pub max_capability_key_len: usize,
+    /// Most payload references one envelope may carry
+    pub max_payload_refs: usize,
+    /// Deepest nesting of objects and arrays, the envelope itself counting as one
+    pub max_depth: usize,
+}
+
+impl EnvelopeLimits {
+    /// The limits applied unless others are set
+    pub const DEFAULT: EnvelopeLimits = EnvelopeLimits {
+        max_size: 64 * 1024 * 1024,
+        max_capabilities: 256,
+        max_capability_key_len: 256,
+        max_payload_refs: 1024,
+        max_depth: 16,
+    };
+
+    /// Reject `bytes` if they are too large or too deeply nested to be worth parsing as JSON
+    pub(crate) fn check_json(&self, bytes: &[u8]) -> Result<()> {
+        self.check_size(bytes.len())?;
+        if json_depth_exceeds(bytes, self.max_depth) {
+            return Err(exceeded(format!("nesting deeper than {} levels", self.max_depth)));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_size(&self, size: usize) -> Result<()> {
+        match size > self.max_size {
+            true => Err(exceeded(format!("{} bytes, over the limit of {}", size, self.max_size))),
+            false => Ok(()),
+        }
+    }
+
+    /// Reject a parsed envelope's fields before anything more is made of them
+    pub(crate) fn check_fields(
+        &self,
+        capabilities: Option<&HashMap<String, String>>,
+        payload_refs: Option<&[HashMap<String, String>]>,
+    ) -> Result<()> {
+        if let Some(capabilities) = capabilities {
+            if capabilities.len() > self.max_capabilities {
+                let count = capabilities.len();
+                return Err(exceeded(format!("{} capabilities, over the limit of {}", count, self.max_capabilities)));
+            }
+            if let Some(key) = capabilities.keys().find(|key| key.len() > self.max_capability_key_len) {
+                let limit = self.max_capability_key_len;
+                return Err(exceeded(format!("a {}-byte capability key, over the limit of {}", key.len(), limit)));
+            }
+        }
+        let refs = payload_refs.map_or(0, <[_]>::len);
+        if refs > self.max_payload_refs {
+            return Err(exceeded(format!("{} payload references, over the limit of {}", refs, self.max_payload_refs)));
+        }
+        Ok(())
+    }
+}
+
+impl Default for EnvelopeLimits {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+fn exceeded(what: String) -> UmicpError {
+    UmicpError::validation(format!("Envelope rejected: {}", what))
+}
+
+/// Whether arrays and objects in `json` nest deeper than `limit`, brackets in strings aside
+fn json_depth_exceeds(json: &[u8], limit: usize) -> bool {
+    let (mut depth, mut in_string, mut escaped) = (0usize, false, false);
+    for &byte in json {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > limit {
+                    return true;
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    false
+}
+
+static LIMITS: RwLock<EnvelopeLimits> = RwLock::new(EnvelopeLimits::DEFAULT);
+
+/// Hold every envelope parsed from now on, in the whole process, to `limits`
+pub fn set_envelope_limits(limits: EnvelopeLimits) {
+    *LIMITS.write().unwrap() = limits;
+}
+
+/// The limits envelopes are parsed with
+pub fn envelope_limits() -> EnvelopeLimits {
+    *LIMITS.read().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::Envelope;
+    use crate::types::EnvelopeCodec;
+
+    #[test]
+    fn test_pathological_envelopes_are_rejected() {
+        let limits = EnvelopeLimits {
+            max_size: 4096,
+            max_capabilities: 4,
+            max_capability_key_len: 8,
+            max_payload_refs: 2,
+            max_depth: 4,
+        };
+        let decode = |json: &str| Envelope::decode_with_limits(json.as_bytes(), EnvelopeCodec::Json, &limits);
+        let envelope = |extra: &str| {
+            format!(
+                r#"{{"v":"1.0","msg_id":"0c7f3a51-98e2-4d4b-9316-5e0a2b9c4d17","from":"a","to":"b","op":"data"{}}}"#,
+                extra
+            )
+        };
+        let rejected = |json: &str, reason: &str| match decode(json) {
+            Err(UmicpError::Validation { message }) => assert!(message.contains(reason), "{}", message),
+            other => panic!("{:?}", other),
+        };
+
+        assert!(decode(&envelope(r#","capabilities":{"k":"[[[[[[[\"}"}"#)).is_ok());
+        assert!(decode(&envelope(r#","payload_refs":[{"a":"1"},{"b":"2"}]"#)).is_ok());
+        rejected(&envelope(&" ".repeat(4096)), "bytes");
+        rejected(&envelope(r#","unknown":[[[[{}]]]]"#), "nesting");
+        rejected(&envelope(r#","capabilities":{"a":"1","b":"1","c":"1","d":"1","e":"1"}"#), "capabilities");
+        rejected(&envelope(r#","capabilities":{"much-too-long":"1"}"#), "capability key");
+        rejected(&envelope(r#","payload_refs":[{},{},{}]"#), "payload references");
+    }
+}
@@ -2,27 +2,290 @@
 # UMICP Matrix Operations
 
 High-performance matrix operations with SIMD optimization for UMICP protocol.
+
+A `Matrix` can profile its own operations: after [`Matrix::set_profiling`], every call of an
+arithmetic operation (not the random initializers or `check_finite`) records its time, element
+count and nominal floating-point operation count, and [`Matrix::profile_report`]
+summarizes them per operation, hottest first, with the achieved GFLOPS.
 */
 
 use crate::error::{Result, UmicpError};
-use crate::types::MatrixResult;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::view::{MatrixView, MatrixViewMut};
+use crate::types::{Activation, Axis, Conv2dParams, ElementwiseFn, MatrixResult, MultiplyLayout, NonFinitePolicy, NormKind, NormScope, Reduction, SimilarityMetric, SummationMode};
+
+/// Tile edge used by the blocked transpose kernels
+const TRANSPOSE_BLOCK: usize = 32;
+
+/// Smallest dimension at which `multiply` switches to Strassen
+const STRASSEN_THRESHOLD: usize = 1024;
+
+/// Sub-problem size at which Strassen recursion hands over to the blocked kernel
+const STRASSEN_LEAF: usize = 128;
+
+/// Largest ratio between the dimensions of a product that still goes to Strassen, which pads
+/// every operand to a square of the largest one
+const STRASSEN_MAX_ASPECT: usize = 2;
+
+/// Accumulated measurements of one operation
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OpProfile {
+    /// Calls that completed
+    pub calls: u64,
+    /// Time spent in those calls
+    pub total: Duration,
+    /// Longest single call
+    pub max: Duration,
+    /// Elements processed, counting each operand or output once per call
+    pub elements: u64,
+    /// Nominal floating-point operations performed, e.g. `2mnp` for a multiply
+    pub flops: f64,
+}
+
+impl OpProfile {
+    /// Average time per call
+    pub fn mean(&self) -> Duration {
+        match self.calls {
+            0 => Duration::ZERO,
+            calls => Duration::from_secs_f64(self.total.as_secs_f64() / calls as f64),
+        }
+    }
+
+    /// Achieved throughput in billions of floating-point operations per second
+    pub fn gflops(&self) -> f64 {
+        match self.total.as_secs_f64() {
+            secs if secs > 0.0 => self.flops / secs / 1e9,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Per-operation profile of a [`Matrix`], returned by [`Matrix::profile_report`]
+///
+/// Displays as a table of the operations, hottest first.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProfileReport {
+    /// Measurements by operation name
+    pub ops: BTreeMap<String, OpProfile>,
+}
+
+impl ProfileReport {
+    /// Operations by total time spent, hottest first
+    pub fn hottest(&self) -> Vec<(&str, &OpProfile)> {
+        let mut ops: Vec<(&str, &OpProfile)> = self.ops.iter().map(|(name, op)| (name.as_str(), op)).collect();
+        ops.sort_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(b.0)));
+        ops
+    }
+
+    /// Time spent in every operation together
+    pub fn total(&self) -> Duration {
+        self.ops.values().map(|op| op.total).sum()
+    }
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<20} {:>8} {:>12} {:>12} {:>14} {:>8}", "op", "calls", "total", "mean", "elements", "GFLOPS")?;
+        for (name, op) in self.hottest() {
+            writeln!(
+                f,
+                "{:<20} {:>8} {:>12.3?} {:>12.3?} {:>14} {:>8.2}",
+                name, op.calls, op.total, op.mean(), op.elements, op.gflops()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Measures one operation, recording it into the profile when dropped
+struct OpTimer<'a> {
+    profile: &'a Mutex<BTreeMap<&'static str, OpProfile>>,
+    op: &'static str,
+    elements: usize,
+    flops: usize,
+    started: Instant,
+}
+
+impl Drop for OpTimer<'_> {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        let mut profile = self.profile.lock().unwrap();
+        let op = profile.entry(self.op).or_default();
+        op.calls += 1;
+        op.total += elapsed;
+        op.max = op.max.max(elapsed);
+        op.elements += self.elements as u64;
+        op.flops += self.flops as f64;
+    }
+}
 
 /// Matrix operations class with high-performance implementations
 #[derive(Debug)]
 pub struct Matrix {
-    // Internal state can be added here if needed
+    /// Accumulation strategy for dot products and reductions
+    summation: SummationMode,
+    /// Treatment of NaN/Inf values produced by operations
+    non_finite_policy: NonFinitePolicy,
+    /// Measurements by operation, while profiling is on
+    profile: Option<Mutex<BTreeMap<&'static str, OpProfile>>>,
 }
 
 impl Matrix {
     /// Create a new matrix operations instance
     pub fn new() -> Self {
-        Matrix {}
+        Matrix {
+            summation: SummationMode::default(),
+            non_finite_policy: NonFinitePolicy::default(),
+            profile: None,
+        }
+    }
+
+    /// Create a matrix operations instance with the given summation mode
+    ///
+    /// `Kahan` and `Pairwise` trade some speed for accuracy on long vectors,
+    /// where plain f32 accumulation drifts visibly.
+    pub fn with_summation(summation: SummationMode) -> Self {
+        Matrix {
+            summation,
+            non_finite_policy: NonFinitePolicy::default(),
+            profile: None,
+        }
+    }
+
+    /// Get the summation mode
+    pub fn summation(&self) -> SummationMode {
+        self.summation
+    }
+
+    /// Get the NaN/Inf policy applied to operation outputs
+    pub fn non_finite_policy(&self) -> NonFinitePolicy {
+        self.non_finite_policy
+    }
+
+    /// Set the NaN/Inf policy applied to operation outputs
+    pub fn set_non_finite_policy(&mut self, policy: NonFinitePolicy) {
+        self.non_finite_policy = policy;
+    }
+
+    /// Turn operation profiling on or off
+    ///
+    /// Turning it off discards what was recorded. Calls rejected for invalid
+    /// arguments are not recorded.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        match enabled {
+            true => self.profile = self.profile.take().or_else(|| Some(Mutex::new(BTreeMap::new()))),
+            false => self.profile = None,
+        }
+    }
+
+    /// Whether operations are being profiled
+    pub fn is_profiling(&self) -> bool {
+        self.profile.is_some()
+    }
+
+    /// Timings, element counts and achieved GFLOPS per operation so far
+    ///
+    /// Empty unless profiling is on.
+    pub fn profile_report(&self) -> ProfileReport {
+        let ops = match &self.profile {
+            Some(profile) => profile.lock().unwrap().iter().map(|(name, op)| (name.to_string(), op.clone())).collect(),
+            None => BTreeMap::new(),
+        };
+        ProfileReport { ops }
+    }
+
+    /// Forget what was recorded, keeping profiling on if it was
+    pub fn reset_profile(&self) {
+        if let Some(profile) = &self.profile {
+            profile.lock().unwrap().clear();
+        }
+    }
+
+    /// Check that every value is finite
+    ///
+    /// Intended for validating tensors at the protocol boundary, before
+    /// corrupted values propagate into aggregation.
+    pub fn check_finite(&self, data: &[f32]) -> Result<()> {
+        match data.iter().position(|x| !x.is_finite()) {
+            None => Ok(()),
+            Some(index) => Err(UmicpError::matrix(format!(
+                "Non-finite value {} at index {} ({} of {} values non-finite)",
+                data[index],
+                index,
+                data.iter().filter(|x| !x.is_finite()).count(),
+                data.len()
+            ))),
+        }
+    }
+
+    /// Uniformly distributed values in `[low, high)`
+    ///
+    /// Passing a `seed` makes the output reproducible; `None` seeds from OS entropy.
+    pub fn random_uniform(len: usize, low: f32, high: f32, seed: Option<u64>) -> Result<Vec<f32>> {
+        if !low.is_finite() || !high.is_finite() || low >= high {
+            return Err(UmicpError::matrix(format!(
+                "Invalid uniform range: [{}, {})",
+                low, high
+            )));
+        }
+
+        let mut rng = Self::rng(seed);
+        Ok((0..len).map(|_| rng.gen_range(low, high)).collect())
+    }
+
+    /// Normally distributed values with the given mean and standard deviation
+    pub fn random_normal(len: usize, mean: f32, std: f32, seed: Option<u64>) -> Result<Vec<f32>> {
+        if !std.is_finite() || !mean.is_finite() || std < 0.0 {
+            return Err(UmicpError::matrix(format!(
+                "Invalid normal parameters: mean {}, std {}",
+                mean, std
+            )));
+        }
+
+        let mut rng = Self::rng(seed);
+        let mut values = Vec::with_capacity(len);
+        while values.len() < len {
+            // Box-Muller yields two independent samples per pair of uniforms
+            let u1: f64 = 1.0 - rng.gen::<f64>();
+            let u2: f64 = rng.gen();
+            let radius = (-2.0 * u1.ln()).sqrt();
+            let angle = 2.0 * std::f64::consts::PI * u2;
+
+            values.push(mean + std * (radius * angle.cos()) as f32);
+            if values.len() < len {
+                values.push(mean + std * (radius * angle.sin()) as f32);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Xavier/Glorot uniform initialization for a `fan_in` x `fan_out` weight matrix
+    pub fn xavier_uniform(fan_in: usize, fan_out: usize, seed: Option<u64>) -> Result<Vec<f32>> {
+        if fan_in + fan_out == 0 {
+            return Err(UmicpError::matrix("Xavier initialization requires non-zero fan_in + fan_out"));
+        }
+        let limit = (6.0 / (fan_in + fan_out) as f32).sqrt();
+        Self::random_uniform(fan_in * fan_out, -limit, limit, seed)
+    }
+
+    /// He/Kaiming normal initialization for a `fan_in` x `fan_out` weight matrix
+    pub fn he_normal(fan_in: usize, fan_out: usize, seed: Option<u64>) -> Result<Vec<f32>> {
+        if fan_in == 0 {
+            return Err(UmicpError::matrix("He initialization requires non-zero fan_in"));
+        }
+        Self::random_normal(fan_in * fan_out, 0.0, (2.0 / fan_in as f32).sqrt(), seed)
     }
 
     /// Matrix addition: result = a + b
     /// Matrices must have the same dimensions
     pub fn add(&self, a: &[f32], b: &[f32], result: &mut [f32], rows: usize, cols: usize) -> Result<MatrixResult> {
         self.validate_dimensions(a.len(), b.len(), result.len(), rows, cols)?;
+        let _profile = self.profile("add", rows * cols, rows * cols);
 
         // Use parallel processing for large matrices
         if rows * cols > 1000 {
@@ -31,6 +294,8 @@ impl Matrix {
             self.add_sequential(a, b, result);
         }
 
+        self.apply_non_finite_policy(result)?;
+
         Ok(MatrixResult {
             success: true,
             error: None,
@@ -53,14 +318,116 @@ impl Matrix {
             )));
         }
 
-        // Initialize result to zeros
+        let _profile = self.profile("multiply", a_len + b_len + result_len, 2 * m * n * p);
+        self.multiply_kernel(a, b, result, m, n, p);
+
+        self.apply_non_finite_policy(result)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: None,
+        })
+    }
+
+    /// Matrix multiplication with explicitly ordered operands: result = a * b
+    ///
+    /// `a` (m x n) and `b` (n x p) are read in their own layouts without a
+    /// transpose copy; `result` (m x p) is always row-major.
+    #[allow(clippy::too_many_arguments)]
+    pub fn multiply_with_layout(
+        &self,
+        a: &[f32],
+        a_layout: MultiplyLayout,
+        b: &[f32],
+        b_layout: MultiplyLayout,
+        result: &mut [f32],
+        m: usize,
+        n: usize,
+        p: usize,
+    ) -> Result<MatrixResult> {
+        if a_layout == MultiplyLayout::RowMajor && b_layout == MultiplyLayout::RowMajor {
+            return self.multiply(a, b, result, m, n, p);
+        }
+
+        if a.len() != m * n || b.len() != n * p || result.len() != m * p {
+            return Err(UmicpError::matrix(format!(
+                "Invalid matrix dimensions: a({}) != {}x{}, b({}) != {}x{}, result({}) != {}x{}",
+                a.len(), m, n, b.len(), n, p, result.len(), m, p
+            )));
+        }
+
+        let _profile = self.profile("multiply_with_layout", m * n + n * p + m * p, 2 * m * n * p);
+
         result.fill(0.0);
 
-        // Use parallel processing for large matrices
-        if m * n * p > 10000 {
-            self.multiply_parallel(a, b, result, m, n, p);
+        if b_layout == MultiplyLayout::ColumnMajor {
+            // Columns of B are contiguous, so each output is a dot product
+            for i in 0..m {
+                for j in 0..p {
+                    let column = &b[j * n..(j + 1) * n];
+                    let mut sum = 0.0f32;
+                    for (k, &b_kj) in column.iter().enumerate() {
+                        sum += a[a_layout.index(i, k, m, n)] * b_kj;
+                    }
+                    result[i * p + j] = sum;
+                }
+            }
         } else {
-            self.multiply_sequential(a, b, result, m, n, p);
+            // Rows of B are contiguous, so stream them into the output row
+            for i in 0..m {
+                for k in 0..n {
+                    let a_ik = a[a_layout.index(i, k, m, n)];
+                    let row = &b[k * p..(k + 1) * p];
+                    for (out, &b_kj) in result[i * p..(i + 1) * p].iter_mut().zip(row) {
+                        *out += a_ik * b_kj;
+                    }
+                }
+            }
+        }
+
+        self.apply_non_finite_policy(result)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: None,
+        })
+    }
+
+    /// Matrix multiplication over strided views: result = a * b
+    pub fn multiply_view(&self, a: &MatrixView<'_>, b: &MatrixView<'_>, result: &mut MatrixViewMut<'_>) -> Result<MatrixResult> {
+        let (m, n, p) = (a.rows(), a.cols(), b.cols());
+        if b.rows() != n || result.rows() != m || result.cols() != p {
+            return Err(UmicpError::matrix(format!(
+                "Invalid view dimensions: a({}x{}), b({}x{}), result({}x{})",
+                m, n, b.rows(), p, result.rows(), result.cols()
+            )));
+        }
+
+        // Dense windows can go through the regular dispatch
+        if let (Some(a), Some(b)) = (a.as_slice(), b.as_slice()) {
+            if let Some(out) = result.as_mut_slice() {
+                return self.multiply(a, b, out, m, n, p);
+            }
+        }
+
+        let _profile = self.profile("multiply_view", m * n + n * p + m * p, 2 * m * n * p);
+
+        result.fill(0.0);
+        for i in 0..m {
+            let a_row = a.row(i)?;
+            let out = result.row_mut(i)?;
+            for (k, &a_ik) in a_row.iter().enumerate() {
+                for (o, &b_kj) in out.iter_mut().zip(b.row(k)?) {
+                    *o += a_ik * b_kj;
+                }
+            }
+            self.apply_non_finite_policy(out)?;
         }
 
         Ok(MatrixResult {
@@ -84,10 +451,38 @@ impl Matrix {
             )));
         }
 
-        // Transpose operation
-        for i in 0..rows {
-            for j in 0..cols {
-                output[j * rows + i] = input[i * cols + j];
+        let _profile = self.profile("transpose", input_len, 0);
+        self.transpose_blocked(input, output, rows, cols);
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: None,
+        })
+    }
+
+    /// In-place transpose of a square matrix
+    pub fn transpose_in_place(&self, matrix: &mut [f32], size: usize) -> Result<MatrixResult> {
+        if matrix.len() != size * size {
+            return Err(UmicpError::matrix(format!(
+                "Invalid transpose dimensions: matrix({}) != {}x{}",
+                matrix.len(), size, size
+            )));
+        }
+
+        let _profile = self.profile("transpose_in_place", size * size, 0);
+
+        // Swap tiles across the diagonal; diagonal tiles swap within themselves
+        for i0 in (0..size).step_by(TRANSPOSE_BLOCK) {
+            for j0 in (i0..size).step_by(TRANSPOSE_BLOCK) {
+                for i in i0..(i0 + TRANSPOSE_BLOCK).min(size) {
+                    let j_start = if i0 == j0 { i + 1 } else { j0 };
+                    for j in j_start..(j0 + TRANSPOSE_BLOCK).min(size) {
+                        matrix.swap(i * size + j, j * size + i);
+                    }
+                }
             }
         }
 
@@ -109,17 +504,23 @@ impl Matrix {
             )));
         }
 
+        let _profile = self.profile("dot_product", 2 * a.len(), 2 * a.len());
+
         // Use SIMD for large vectors
-        let result = if a.len() >= 8 {
-            self.dot_product_simd(a, b)
+        let result = if self.summation != SummationMode::Fast {
+            self.compensated_sum(a.iter().zip(b.iter()).map(|(&x, &y)| x as f64 * y as f64))
+        } else if a.len() >= 8 {
+            self.dot_product_simd(a, b) as f64
         } else {
-            a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+            a.iter().zip(b.iter()).map(|(x, y)| x * y).sum::<f32>() as f64
         };
 
+        let result = self.apply_non_finite_policy_scalar(result)?;
+
         Ok(MatrixResult {
             success: true,
             error: None,
-            result: Some(result as f64),
+            result: Some(result),
             similarity: None,
             data: None,
         })
@@ -127,6 +528,55 @@ impl Matrix {
 
     /// Vector/matrix normalization (L2 normalization)
     pub fn normalize(&self, matrix: &mut [f32], rows: usize, cols: usize) -> Result<MatrixResult> {
+        self.normalize_with(matrix, rows, cols, NormKind::L2, NormScope::Row)
+    }
+
+    /// Normalization of the selected kind, per row or over the whole tensor
+    ///
+    /// Spans with no spread (zero norm, zero range or zero variance) are left
+    /// at zero for min-max and z-score, and unchanged for L2.
+    pub fn normalize_with(&self, matrix: &mut [f32], rows: usize, cols: usize, kind: NormKind, scope: NormScope) -> Result<MatrixResult> {
+        let matrix_len = rows * cols;
+        if matrix.len() != matrix_len {
+            return Err(UmicpError::matrix(format!(
+                "Invalid matrix dimensions: matrix({}) != {}x{}",
+                matrix.len(), rows, cols
+            )));
+        }
+
+        let _profile = self.profile("normalize", matrix_len, 3 * matrix_len);
+
+        let span = match scope {
+            NormScope::Row => cols,
+            NormScope::Tensor => matrix_len,
+        };
+
+        if span > 0 {
+            for slice in matrix.chunks_exact_mut(span) {
+                self.normalize_span(slice, kind);
+            }
+        }
+
+        self.apply_non_finite_policy(matrix)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: Some(matrix.to_vec()),
+        })
+    }
+
+    /// Row-wise softmax, computed in place
+    ///
+    /// Each row is shifted by its maximum before exponentiation so large
+    /// logits do not overflow. An optional `temperature` divides the inputs
+    /// first; values below 1.0 sharpen the distribution, above 1.0 flatten it.
+    ///
+    /// A row whose logits are all `-inf` becomes uniform, and one holding
+    /// `+inf` puts all its weight on those entries, split evenly between them.
+    pub fn softmax(&self, matrix: &mut [f32], rows: usize, cols: usize, temperature: Option<f32>) -> Result<MatrixResult> {
         let matrix_len = rows * cols;
         if matrix.len() != matrix_len {
             return Err(UmicpError::matrix(format!(
@@ -135,22 +585,54 @@ impl Matrix {
             )));
         }
 
-        for row in 0..rows {
-            let start = row * cols;
-            let end = start + cols;
-            let row_slice = &mut matrix[start..end];
+        let temperature = temperature.unwrap_or(1.0);
+        if !(temperature > 0.0 && temperature.is_finite()) {
+            return Err(UmicpError::matrix(format!(
+                "Softmax temperature must be positive and finite, got {}",
+                temperature
+            )));
+        }
+
+        let _profile = self.profile("softmax", matrix_len, 5 * matrix_len);
+
+        if cols > 0 {
+            for row_slice in matrix.chunks_exact_mut(cols) {
+                let max = row_slice.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+
+                // Shifting by an infinite max would give inf - inf = NaN, so
+                // the limits are written out instead; NaN logits stay NaN
+                if max == f32::INFINITY {
+                    let ties = row_slice.iter().filter(|&&x| x == f32::INFINITY).count() as f32;
+                    for val in row_slice.iter_mut().filter(|x| !x.is_nan()) {
+                        *val = if *val == f32::INFINITY { 1.0 / ties } else { 0.0 };
+                    }
+                    continue;
+                }
+                if max == f32::NEG_INFINITY {
+                    for val in row_slice.iter_mut().filter(|x| !x.is_nan()) {
+                        *val = 1.0 / cols as f32;
+                    }
+                    continue;
+                }
 
-            // Calculate L2 norm
-            let norm: f32 = row_slice.iter().map(|x| x * x).sum::<f32>().sqrt();
+                let mut sum = 0.0f32;
+                for val in row_slice.iter_mut() {
+                    *val = ((*val - max) / temperature).exp();
+                    sum += *val;
+                }
 
-            if norm > 0.0 {
-                // Normalize the row
+                if sum == 0.0 {
+                    row_slice.fill(1.0 / cols as f32);
+                    continue;
+                }
                 for val in row_slice.iter_mut() {
-                    *val /= norm;
+                    *val /= sum;
                 }
             }
         }
 
+        self.apply_non_finite_policy(matrix)?;
+
         Ok(MatrixResult {
             success: true,
             error: None,
@@ -160,6 +642,83 @@ impl Matrix {
         })
     }
 
+    /// Layer normalization over each row, computed in place
+    ///
+    /// Each row is shifted to zero mean and scaled to unit variance, then
+    /// multiplied by `gamma` and offset by `beta` (both of length `cols`)
+    /// when provided. `eps` is added to the variance; constant rows become
+    /// `beta` (or zero) even when it is 0.
+    pub fn layer_norm(
+        &self,
+        matrix: &mut [f32],
+        rows: usize,
+        cols: usize,
+        gamma: Option<&[f32]>,
+        beta: Option<&[f32]>,
+        eps: f32,
+    ) -> Result<MatrixResult> {
+        self.validate_norm_params(matrix.len(), rows, cols, gamma, beta, eps)?;
+        let _profile = self.profile("layer_norm", rows * cols, 8 * rows * cols);
+
+        if cols > 0 {
+            for row_slice in matrix.chunks_exact_mut(cols) {
+                let mean = row_slice.iter().sum::<f32>() / cols as f32;
+                let variance = row_slice.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / cols as f32;
+                // A constant row with no `eps` collapses to `beta` instead of 0/0
+                let denom = (variance + eps).sqrt();
+                let inv_std = if denom > 0.0 { 1.0 / denom } else { 0.0 };
+
+                for (j, val) in row_slice.iter_mut().enumerate() {
+                    let scale = gamma.map_or(1.0, |g| g[j]);
+                    let shift = beta.map_or(0.0, |b| b[j]);
+                    *val = (*val - mean) * inv_std * scale + shift;
+                }
+            }
+        }
+
+        self.apply_non_finite_policy(matrix)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: None,
+        })
+    }
+
+    /// RMS normalization over each row, computed in place
+    ///
+    /// Each row is divided by its root mean square and multiplied by
+    /// `gamma` (length `cols`) when provided. Unlike layer norm the mean is
+    /// not subtracted. All-zero rows stay zero even when `eps` is 0.
+    pub fn rms_norm(&self, matrix: &mut [f32], rows: usize, cols: usize, gamma: Option<&[f32]>, eps: f32) -> Result<MatrixResult> {
+        self.validate_norm_params(matrix.len(), rows, cols, gamma, None, eps)?;
+        let _profile = self.profile("rms_norm", rows * cols, 4 * rows * cols);
+
+        if cols > 0 {
+            for row_slice in matrix.chunks_exact_mut(cols) {
+                let mean_square = row_slice.iter().map(|x| x * x).sum::<f32>() / cols as f32;
+                let denom = (mean_square + eps).sqrt();
+                let inv_rms = if denom > 0.0 { 1.0 / denom } else { 0.0 };
+
+                for (j, val) in row_slice.iter_mut().enumerate() {
+                    *val *= inv_rms * gamma.map_or(1.0, |g| g[j]);
+                }
+            }
+        }
+
+        self.apply_non_finite_policy(matrix)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: None,
+        })
+    }
+
     /// Cosine similarity between two vectors
     pub fn cosine_similarity(&self, a: &[f32], b: &[f32]) -> Result<MatrixResult> {
         if a.len() != b.len() {
@@ -169,13 +728,19 @@ impl Matrix {
             )));
         }
 
-        // Calculate dot product
-        let dot_result = self.dot_product(a, b)?;
-        let dot_product = dot_result.result.unwrap() as f32;
+        let _profile = self.profile("cosine_similarity", 2 * a.len(), 6 * a.len());
+
+        let (dot_product, a_norm_sq, b_norm_sq) = if self.summation == SummationMode::Fast {
+            self.cosine_terms_simd(a, b)
+        } else {
+            // Accuracy modes keep separate compensated sums for each term
+            let dot = self.compensated_sum(a.iter().zip(b.iter()).map(|(&x, &y)| x as f64 * y as f64)) as f32;
+            (dot, self.sum_squares(a), self.sum_squares(b))
+        };
 
         // Calculate magnitudes
-        let a_magnitude: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
-        let b_magnitude: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let a_magnitude: f32 = a_norm_sq.sqrt();
+        let b_magnitude: f32 = b_norm_sq.sqrt();
 
         if a_magnitude == 0.0 || b_magnitude == 0.0 {
             return Ok(MatrixResult {
@@ -187,17 +752,97 @@ impl Matrix {
             });
         }
 
-        let similarity = dot_product / (a_magnitude * b_magnitude);
+        let similarity = self.apply_non_finite_policy_scalar((dot_product / (a_magnitude * b_magnitude)) as f64)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: Some(similarity),
+            data: None,
+        })
+    }
+
+    /// Outer product: result = a * b^T, an M x N matrix for |a| = M, |b| = N
+    pub fn outer_product(&self, a: &[f32], b: &[f32], result: &mut [f32]) -> Result<MatrixResult> {
+        if result.len() != a.len() * b.len() {
+            return Err(UmicpError::matrix(format!(
+                "Invalid outer product dimensions: result({}) != {}x{}",
+                result.len(), a.len(), b.len()
+            )));
+        }
+
+        let _profile = self.profile("outer_product", result.len(), result.len());
+
+        if !b.is_empty() {
+            for (row, &x) in result.chunks_exact_mut(b.len()).zip(a.iter()) {
+                for (out, &y) in row.iter_mut().zip(b.iter()) {
+                    *out = x * y;
+                }
+            }
+        }
+
+        self.apply_non_finite_policy(result)?;
 
         Ok(MatrixResult {
             success: true,
             error: None,
             result: None,
-            similarity: Some(similarity as f64),
+            similarity: None,
             data: None,
         })
     }
 
+    /// Pairwise similarity matrix between two sets of vectors
+    ///
+    /// `a` holds M row vectors and `b` holds N row vectors, all of length `dim`.
+    /// The returned data is the row-major M x N score matrix.
+    pub fn similarity_matrix(&self, a: &[f32], b: &[f32], dim: usize, metric: SimilarityMetric) -> Result<MatrixResult> {
+        if dim == 0 || !a.len().is_multiple_of(dim) || !b.len().is_multiple_of(dim) {
+            return Err(UmicpError::matrix(format!(
+                "Invalid similarity dimensions: a({}) and b({}) must be multiples of dim {}",
+                a.len(), b.len(), dim
+            )));
+        }
+
+        let m = a.len() / dim;
+        let n = b.len() / dim;
+        let _profile = self.profile("similarity_matrix", a.len() + b.len() + m * n, 2 * (m + n + m * n) * dim);
+
+        // Squared norms are computed once per row and reused by every pair
+        let a_norms: Vec<f32> = a.chunks_exact(dim).map(|row| row.iter().map(|x| x * x).sum()).collect();
+        let b_norms: Vec<f32> = b.chunks_exact(dim).map(|row| row.iter().map(|x| x * x).sum()).collect();
+
+        // Every dot product at once, as A·Bᵀ through the multiply kernels
+        let mut b_t = vec![0.0f32; b.len()];
+        self.transpose_blocked(b, &mut b_t, n, dim);
+        let mut scores = vec![0.0f32; m * n];
+        self.multiply_kernel(a, &b_t, &mut scores, m, dim, n);
+
+        for (i, row) in scores.chunks_exact_mut(n.max(1)).enumerate() {
+            for (j, score) in row.iter_mut().enumerate() {
+                let dot = *score;
+                *score = match metric {
+                    SimilarityMetric::Cosine => {
+                        let denom = (a_norms[i] * b_norms[j]).sqrt();
+                        if denom > 0.0 { dot / denom } else { 0.0 }
+                    }
+                    SimilarityMetric::Euclidean => (a_norms[i] + b_norms[j] - 2.0 * dot).max(0.0).sqrt(),
+                };
+            }
+        }
+
+        self.apply_non_finite_policy(&mut scores)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: Some(scores),
+        })
+    }
+
     /// Element-wise vector addition
     pub fn vector_add(&self, a: &[f32], b: &[f32], result: &mut [f32]) -> Result<MatrixResult> {
         if a.len() != b.len() || a.len() != result.len() {
@@ -207,10 +852,13 @@ impl Matrix {
             )));
         }
 
+        let _profile = self.profile("vector_add", a.len(), a.len());
         for i in 0..a.len() {
             result[i] = a[i] + b[i];
         }
 
+        self.apply_non_finite_policy(result)?;
+
         Ok(MatrixResult {
             success: true,
             error: None,
@@ -229,10 +877,13 @@ impl Matrix {
             )));
         }
 
+        let _profile = self.profile("vector_subtract", a.len(), a.len());
         for i in 0..a.len() {
             result[i] = a[i] - b[i];
         }
 
+        self.apply_non_finite_policy(result)?;
+
         Ok(MatrixResult {
             success: true,
             error: None,
@@ -251,10 +902,13 @@ impl Matrix {
             )));
         }
 
+        let _profile = self.profile("vector_multiply", a.len(), a.len());
         for i in 0..a.len() {
             result[i] = a[i] * b[i];
         }
 
+        self.apply_non_finite_policy(result)?;
+
         Ok(MatrixResult {
             success: true,
             error: None,
@@ -264,19 +918,83 @@ impl Matrix {
         })
     }
 
-    /// Scalar multiplication of vector
-    pub fn vector_scale(&self, vector: &[f32], scalar: f32, result: &mut [f32]) -> Result<MatrixResult> {
-        if vector.len() != result.len() {
-            return Err(UmicpError::matrix(format!(
-                "Vector length mismatch: vector({}), result({})",
+    /// Broadcast addition of a vector onto a matrix
+    ///
+    /// With `Axis::Row` the vector has `cols` elements and is added to every
+    /// row (e.g. a bias vector); with `Axis::Column` it has `rows` elements
+    /// and element `i` is added to every value of row `i`.
+    pub fn broadcast_add(&self, matrix: &[f32], vector: &[f32], result: &mut [f32], rows: usize, cols: usize, axis: Axis) -> Result<MatrixResult> {
+        self.broadcast("broadcast_add", matrix, vector, result, rows, cols, axis, |x, y| x + y)
+    }
+
+    /// Broadcast multiplication of a vector onto a matrix
+    ///
+    /// Uses the same axis convention as [`Matrix::broadcast_add`].
+    pub fn broadcast_multiply(&self, matrix: &[f32], vector: &[f32], result: &mut [f32], rows: usize, cols: usize, axis: Axis) -> Result<MatrixResult> {
+        self.broadcast("broadcast_multiply", matrix, vector, result, rows, cols, axis, |x, y| x * y)
+    }
+
+    /// Weighted average of a set of equal-length vectors
+    ///
+    /// Weights are normalized by their sum, so raw sample counts can be
+    /// passed directly (e.g. when averaging federated client updates).
+    pub fn weighted_average(&self, vectors: &[&[f32]], weights: &[f32], result: &mut [f32]) -> Result<MatrixResult> {
+        if vectors.is_empty() || vectors.len() != weights.len() {
+            return Err(UmicpError::matrix(format!(
+                "Weighted average needs one weight per vector: vectors({}), weights({})",
+                vectors.len(), weights.len()
+            )));
+        }
+        if let Some(vector) = vectors.iter().find(|v| v.len() != result.len()) {
+            return Err(UmicpError::matrix(format!(
+                "Vector length mismatch: vector({}), result({})",
                 vector.len(), result.len()
             )));
         }
 
+        let total_weight = self.sum(weights);
+        if weights.iter().any(|w| !w.is_finite() || *w < 0.0) || total_weight <= 0.0 {
+            return Err(UmicpError::matrix("Weights must be finite, non-negative and sum to a positive value"));
+        }
+
+        let elements = vectors.len() * result.len();
+        let _profile = self.profile("weighted_average", elements, 2 * elements);
+
+        result.fill(0.0);
+        for (vector, &weight) in vectors.iter().zip(weights.iter()) {
+            let scale = weight / total_weight;
+            for (out, &value) in result.iter_mut().zip(vector.iter()) {
+                *out += value * scale;
+            }
+        }
+
+        self.apply_non_finite_policy(result)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: None,
+        })
+    }
+
+    /// Scalar multiplication of vector
+    pub fn vector_scale(&self, vector: &[f32], scalar: f32, result: &mut [f32]) -> Result<MatrixResult> {
+        if vector.len() != result.len() {
+            return Err(UmicpError::matrix(format!(
+                "Vector length mismatch: vector({}), result({})",
+                vector.len(), result.len()
+            )));
+        }
+
+        let _profile = self.profile("vector_scale", vector.len(), vector.len());
         for i in 0..vector.len() {
             result[i] = vector[i] * scalar;
         }
 
+        self.apply_non_finite_policy(result)?;
+
         Ok(MatrixResult {
             success: true,
             error: None,
@@ -286,6 +1004,572 @@ impl Matrix {
         })
     }
 
+    /// Reduce a matrix along an axis
+    ///
+    /// `Axis::Row` collapses the rows and yields `cols` values; `Axis::Column`
+    /// collapses the columns and yields `rows` values.
+    pub fn reduce(&self, data: &[f32], rows: usize, cols: usize, axis: Axis, reduction: Reduction) -> Result<MatrixResult> {
+        if data.len() != rows * cols {
+            return Err(UmicpError::matrix(format!(
+                "Invalid matrix dimensions: data({}) != {}x{}",
+                data.len(), rows, cols
+            )));
+        }
+
+        let (outer, inner) = match axis {
+            Axis::Row => (cols, rows),
+            Axis::Column => (rows, cols),
+        };
+        if inner == 0 && reduction != Reduction::Sum {
+            return Err(UmicpError::matrix(format!("Cannot compute {:?} over an empty axis", reduction)));
+        }
+        if let Reduction::Percentile(q) = reduction {
+            Self::validate_percentile(q)?;
+        }
+
+        let _profile = self.profile("reduce", data.len(), data.len());
+
+        let mut reduced = Vec::with_capacity(outer);
+        let mut lane = Vec::with_capacity(inner);
+        for i in 0..outer {
+            lane.clear();
+            match axis {
+                Axis::Row => lane.extend((0..rows).map(|r| data[r * cols + i])),
+                Axis::Column => lane.extend_from_slice(&data[i * cols..(i + 1) * cols]),
+            }
+
+            reduced.push(self.reduce_lane(&mut lane, reduction)?);
+        }
+
+        self.apply_non_finite_policy(&mut reduced)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: Some(reduced),
+        })
+    }
+
+    /// Arithmetic mean of a slice
+    pub fn mean(&self, data: &[f32]) -> Result<MatrixResult> {
+        self.statistic(data, Reduction::Mean)
+    }
+
+    /// Population variance of a slice
+    pub fn variance(&self, data: &[f32]) -> Result<MatrixResult> {
+        self.statistic(data, Reduction::Variance)
+    }
+
+    /// Population standard deviation of a slice
+    pub fn std_dev(&self, data: &[f32]) -> Result<MatrixResult> {
+        self.statistic(data, Reduction::Std)
+    }
+
+    /// Median of a slice
+    pub fn median(&self, data: &[f32]) -> Result<MatrixResult> {
+        self.statistic(data, Reduction::Median)
+    }
+
+    /// Percentile of a slice, with `q` in `[0, 100]`
+    ///
+    /// Values between ranks are linearly interpolated, matching NumPy's
+    /// default `percentile` behaviour.
+    pub fn percentile(&self, data: &[f32], q: f32) -> Result<MatrixResult> {
+        Self::validate_percentile(q)?;
+        self.statistic(data, Reduction::Percentile(q))
+    }
+
+    /// 1D convolution of a signal with a kernel
+    ///
+    /// The signal is zero-padded by `padding` samples on each side and the
+    /// (flipped) kernel is applied every `stride` samples, giving
+    /// `(len + 2 * padding - kernel_len) / stride + 1` outputs.
+    pub fn conv1d(&self, signal: &[f32], kernel: &[f32], stride: usize, padding: usize) -> Result<MatrixResult> {
+        let flipped: Vec<f32> = kernel.iter().rev().cloned().collect();
+        self.correlate("conv1d", signal, &flipped, stride, padding)
+    }
+
+    /// 1D cross-correlation of a signal with a kernel
+    ///
+    /// Same as [`Matrix::conv1d`] without flipping the kernel, which is the
+    /// convention used by neural network convolution layers.
+    pub fn correlate1d(&self, signal: &[f32], kernel: &[f32], stride: usize, padding: usize) -> Result<MatrixResult> {
+        self.correlate("correlate1d", signal, kernel, stride, padding)
+    }
+
+    /// Cross-correlation shared by `conv1d` and `correlate1d`, profiled as `op`
+    fn correlate(
+        &self,
+        op: &'static str,
+        signal: &[f32],
+        kernel: &[f32],
+        stride: usize,
+        padding: usize,
+    ) -> Result<MatrixResult> {
+        let padded_len = signal.len() + 2 * padding;
+        if kernel.is_empty() || stride == 0 || kernel.len() > padded_len {
+            return Err(UmicpError::matrix(format!(
+                "Invalid convolution parameters: signal({}), kernel({}), stride {}, padding {}",
+                signal.len(), kernel.len(), stride, padding
+            )));
+        }
+
+        let output_len = (padded_len - kernel.len()) / stride + 1;
+        let _profile = self.profile(op, signal.len() + output_len, 2 * output_len * kernel.len());
+        let mut output = Vec::with_capacity(output_len);
+        for out_index in 0..output_len {
+            let start = out_index * stride;
+            let mut sum = 0.0f32;
+            for (k, &weight) in kernel.iter().enumerate() {
+                // Positions inside the padding contribute zero
+                let position = start + k;
+                if position >= padding && position - padding < signal.len() {
+                    sum += signal[position - padding] * weight;
+                }
+            }
+            output.push(sum);
+        }
+
+        self.apply_non_finite_policy(&mut output)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: Some(output),
+        })
+    }
+
+    /// 2D convolution (cross-correlation) over a single image
+    ///
+    /// `input` is laid out as `[in_channels, height, width]`, `kernel` as
+    /// `[out_channels, in_channels, kernel_height, kernel_width]` and the
+    /// optional `bias` has one value per output channel. The returned data is
+    /// `[out_channels, output_height, output_width]`.
+    pub fn conv2d(&self, input: &[f32], kernel: &[f32], bias: Option<&[f32]>, params: &Conv2dParams) -> Result<MatrixResult> {
+        let Conv2dParams { in_channels, out_channels, height, width, kernel_height, kernel_width, stride, padding } = *params;
+        let (out_h, out_w) = (params.output_height(), params.output_width());
+
+        if input.len() != in_channels * height * width
+            || kernel.len() != out_channels * in_channels * kernel_height * kernel_width
+            || bias.is_some_and(|b| b.len() != out_channels)
+            || out_h == 0
+            || out_w == 0
+        {
+            return Err(UmicpError::matrix(format!(
+                "Invalid conv2d parameters {:?}: input({}), kernel({}), bias({:?})",
+                params, input.len(), kernel.len(), bias.map(|b| b.len())
+            )));
+        }
+
+        let outputs = out_channels * out_h * out_w;
+        let flops = 2 * outputs * in_channels * kernel_height * kernel_width;
+        let _profile = self.profile("conv2d", input.len() + outputs, flops);
+
+        let mut output = vec![0.0f32; outputs];
+        for oc in 0..out_channels {
+            let initial = bias.map_or(0.0, |b| b[oc]);
+            for oy in 0..out_h {
+                for ox in 0..out_w {
+                    let mut sum = initial;
+                    for ic in 0..in_channels {
+                        let plane = &input[ic * height * width..(ic + 1) * height * width];
+                        let filter = &kernel[(oc * in_channels + ic) * kernel_height * kernel_width..][..kernel_height * kernel_width];
+                        for ky in 0..kernel_height {
+                            // Rows and columns falling in the padding contribute zero
+                            let y = (oy * stride + ky).wrapping_sub(padding);
+                            if y >= height {
+                                continue;
+                            }
+                            for kx in 0..kernel_width {
+                                let x = (ox * stride + kx).wrapping_sub(padding);
+                                if x < width {
+                                    sum += plane[y * width + x] * filter[ky * kernel_width + kx];
+                                }
+                            }
+                        }
+                    }
+                    output[(oc * out_h + oy) * out_w + ox] = sum;
+                }
+            }
+        }
+
+        self.apply_non_finite_policy(&mut output)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: Some(output),
+        })
+    }
+
+    /// Cumulative sum: result[i] = input[0] + ... + input[i]
+    pub fn cumsum(&self, input: &[f32], result: &mut [f32]) -> Result<MatrixResult> {
+        let len = input.len();
+        self.cumulative_rows(input, result, 1, len, false)
+    }
+
+    /// Cumulative product: result[i] = input[0] * ... * input[i]
+    pub fn cumprod(&self, input: &[f32], result: &mut [f32]) -> Result<MatrixResult> {
+        let len = input.len();
+        self.cumulative_rows(input, result, 1, len, true)
+    }
+
+    /// Per-row cumulative sum over a row-major matrix
+    pub fn cumsum_rows(&self, matrix: &[f32], result: &mut [f32], rows: usize, cols: usize) -> Result<MatrixResult> {
+        self.cumulative_rows(matrix, result, rows, cols, false)
+    }
+
+    /// Per-row cumulative product over a row-major matrix
+    pub fn cumprod_rows(&self, matrix: &[f32], result: &mut [f32], rows: usize, cols: usize) -> Result<MatrixResult> {
+        self.cumulative_rows(matrix, result, rows, cols, true)
+    }
+
+    /// Index of the largest value in a vector (NaN values are skipped)
+    pub fn argmax(&self, vector: &[f32]) -> Result<usize> {
+        let _profile = self.profile("argmax", vector.len(), vector.len());
+        Self::arg_extreme(vector, |candidate, best| candidate > best)
+    }
+
+    /// Index of the smallest value in a vector (NaN values are skipped)
+    pub fn argmin(&self, vector: &[f32]) -> Result<usize> {
+        let _profile = self.profile("argmin", vector.len(), vector.len());
+        Self::arg_extreme(vector, |candidate, best| candidate < best)
+    }
+
+    /// Per-row argmax over a row-major matrix
+    pub fn argmax_rows(&self, matrix: &[f32], rows: usize, cols: usize) -> Result<Vec<usize>> {
+        self.validate_rows(matrix.len(), rows, cols)?;
+        let _profile = self.profile("argmax_rows", matrix.len(), matrix.len());
+        matrix.chunks_exact(cols).map(|row| Self::arg_extreme(row, |candidate, best| candidate > best)).collect()
+    }
+
+    /// Per-row argmin over a row-major matrix
+    pub fn argmin_rows(&self, matrix: &[f32], rows: usize, cols: usize) -> Result<Vec<usize>> {
+        self.validate_rows(matrix.len(), rows, cols)?;
+        let _profile = self.profile("argmin_rows", matrix.len(), matrix.len());
+        matrix.chunks_exact(cols).map(|row| Self::arg_extreme(row, |candidate, best| candidate < best)).collect()
+    }
+
+    /// The `k` largest values of a vector as `(index, value)` pairs, largest first
+    ///
+    /// NaN values are never selected; fewer than `k` pairs are returned when
+    /// the vector is shorter than `k`.
+    pub fn top_k_values(&self, vector: &[f32], k: usize) -> Result<Vec<(usize, f32)>> {
+        let _profile = self.profile("top_k_values", vector.len(), vector.len());
+        Ok(Self::top_k(vector, k))
+    }
+
+    /// Per-row top-k over a row-major matrix
+    pub fn top_k_values_rows(&self, matrix: &[f32], rows: usize, cols: usize, k: usize) -> Result<Vec<Vec<(usize, f32)>>> {
+        self.validate_rows(matrix.len(), rows, cols)?;
+        let _profile = self.profile("top_k_values_rows", matrix.len(), matrix.len());
+        Ok(matrix.chunks_exact(cols).map(|row| Self::top_k(row, k)).collect())
+    }
+
+    fn top_k(vector: &[f32], k: usize) -> Vec<(usize, f32)> {
+        let mut indexed: Vec<(usize, f32)> = vector
+            .iter()
+            .cloned()
+            .enumerate()
+            .filter(|(_, v)| !v.is_nan())
+            .collect();
+
+        let k = k.min(indexed.len());
+        if k == 0 {
+            return Vec::new();
+        }
+
+        // Partition first so only the selected prefix needs a full sort
+        let by_value_desc = |x: &(usize, f32), y: &(usize, f32)| y.1.total_cmp(&x.1).then(x.0.cmp(&y.0));
+        indexed.select_nth_unstable_by(k - 1, by_value_desc);
+        indexed.truncate(k);
+        indexed.sort_by(by_value_desc);
+
+        indexed
+    }
+
+    /// Elementwise activation: result = f(input)
+    pub fn activation(&self, input: &[f32], result: &mut [f32], activation: Activation) -> Result<MatrixResult> {
+        if input.len() != result.len() {
+            return Err(UmicpError::matrix(format!(
+                "Vector length mismatch: input({}), result({})",
+                input.len(), result.len()
+            )));
+        }
+
+        let _profile = self.profile("activation", input.len(), input.len());
+        result.copy_from_slice(input);
+        self.activation_simd(result, activation);
+
+        self.apply_non_finite_policy(result)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: None,
+        })
+    }
+
+    /// Elementwise activation applied in place: data = f(data)
+    pub fn activation_in_place(&self, data: &mut [f32], activation: Activation) -> Result<MatrixResult> {
+        let _profile = self.profile("activation", data.len(), data.len());
+        self.activation_simd(data, activation);
+
+        self.apply_non_finite_policy(data)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: None,
+        })
+    }
+
+    /// Rectified linear unit: result = max(0, input)
+    pub fn relu(&self, input: &[f32], result: &mut [f32]) -> Result<MatrixResult> {
+        self.activation(input, result, Activation::Relu)
+    }
+
+    /// Gaussian error linear unit (tanh approximation)
+    pub fn gelu(&self, input: &[f32], result: &mut [f32]) -> Result<MatrixResult> {
+        self.activation(input, result, Activation::Gelu)
+    }
+
+    /// Logistic sigmoid: result = 1 / (1 + e^-input)
+    pub fn sigmoid(&self, input: &[f32], result: &mut [f32]) -> Result<MatrixResult> {
+        self.activation(input, result, Activation::Sigmoid)
+    }
+
+    /// Hyperbolic tangent: result = tanh(input)
+    pub fn tanh(&self, input: &[f32], result: &mut [f32]) -> Result<MatrixResult> {
+        self.activation(input, result, Activation::Tanh)
+    }
+
+    /// Rectified linear unit applied in place
+    pub fn relu_in_place(&self, data: &mut [f32]) -> Result<MatrixResult> {
+        self.activation_in_place(data, Activation::Relu)
+    }
+
+    /// Gaussian error linear unit (tanh approximation) applied in place
+    pub fn gelu_in_place(&self, data: &mut [f32]) -> Result<MatrixResult> {
+        self.activation_in_place(data, Activation::Gelu)
+    }
+
+    /// Logistic sigmoid applied in place
+    pub fn sigmoid_in_place(&self, data: &mut [f32]) -> Result<MatrixResult> {
+        self.activation_in_place(data, Activation::Sigmoid)
+    }
+
+    /// Hyperbolic tangent applied in place
+    pub fn tanh_in_place(&self, data: &mut [f32]) -> Result<MatrixResult> {
+        self.activation_in_place(data, Activation::Tanh)
+    }
+
+    /// Matrix trace: sum of the main diagonal (square matrices only)
+    pub fn trace(&self, matrix: &[f32], size: usize) -> Result<MatrixResult> {
+        if matrix.len() != size * size {
+            return Err(UmicpError::matrix(format!(
+                "Invalid matrix dimensions for trace: matrix({}) != {}x{}",
+                matrix.len(), size, size
+            )));
+        }
+
+        let _profile = self.profile("trace", size, size);
+        let diagonal: Vec<f32> = (0..size).map(|i| matrix[i * size + i]).collect();
+        let trace = self.apply_non_finite_policy_scalar(self.sum(&diagonal) as f64)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: Some(trace),
+            similarity: None,
+            data: None,
+        })
+    }
+
+    /// Numerical rank via Gaussian elimination with partial pivoting
+    ///
+    /// Pivots with magnitude at or below `tolerance` count as zero. When no
+    /// tolerance is given it defaults to `max(rows, cols) * f32::EPSILON *
+    /// max|a_ij|`, which suits values that were transmitted as f32.
+    pub fn rank(&self, matrix: &[f32], rows: usize, cols: usize, tolerance: Option<f64>) -> Result<MatrixResult> {
+        if matrix.len() != rows * cols {
+            return Err(UmicpError::matrix(format!(
+                "Invalid matrix dimensions for rank: matrix({}) != {}x{}",
+                matrix.len(), rows, cols
+            )));
+        }
+        self.check_finite(matrix)?;
+
+        let _profile = self.profile("rank", rows * cols, 2 * rows * cols * rows.min(cols));
+        let mut work: Vec<f64> = matrix.iter().map(|&x| x as f64).collect();
+        let max_abs = work.iter().fold(0.0f64, |acc, x| acc.max(x.abs()));
+        let tolerance = tolerance.unwrap_or(rows.max(cols) as f64 * f32::EPSILON as f64 * max_abs);
+
+        let mut rank = 0;
+        for col in 0..cols {
+            if rank == rows {
+                break;
+            }
+
+            let pivot_row = (rank..rows)
+                .max_by(|&x, &y| work[x * cols + col].abs().total_cmp(&work[y * cols + col].abs()))
+                .unwrap_or(rank);
+            if work[pivot_row * cols + col].abs() <= tolerance {
+                continue;
+            }
+
+            for j in 0..cols {
+                work.swap(rank * cols + j, pivot_row * cols + j);
+            }
+            let pivot = work[rank * cols + col];
+            for row in rank + 1..rows {
+                let factor = work[row * cols + col] / pivot;
+                if factor != 0.0 {
+                    for j in col..cols {
+                        work[row * cols + j] -= factor * work[rank * cols + j];
+                    }
+                }
+            }
+            rank += 1;
+        }
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: Some(rank as f64),
+            similarity: None,
+            data: None,
+        })
+    }
+
+    /// Elementwise math function: result = f(input)
+    pub fn elementwise(&self, input: &[f32], result: &mut [f32], function: ElementwiseFn) -> Result<MatrixResult> {
+        if input.len() != result.len() {
+            return Err(UmicpError::matrix(format!(
+                "Vector length mismatch: input({}), result({})",
+                input.len(), result.len()
+            )));
+        }
+
+        result.copy_from_slice(input);
+        self.elementwise_in_place(result, function)
+    }
+
+    /// Elementwise math function applied in place: data = f(data)
+    pub fn elementwise_in_place(&self, data: &mut [f32], function: ElementwiseFn) -> Result<MatrixResult> {
+        let _profile = self.profile("elementwise", data.len(), data.len());
+        match function {
+            ElementwiseFn::Exp => self.map_in_place(data, f32::exp),
+            ElementwiseFn::Log => self.map_in_place(data, f32::ln),
+            ElementwiseFn::Sqrt => self.map_in_place(data, f32::sqrt),
+            ElementwiseFn::Abs => self.map_in_place(data, f32::abs),
+        }
+        self.apply_non_finite_policy(data)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: None,
+        })
+    }
+
+    /// Elementwise exponential: result = e^input
+    pub fn exp(&self, input: &[f32], result: &mut [f32]) -> Result<MatrixResult> {
+        self.elementwise(input, result, ElementwiseFn::Exp)
+    }
+
+    /// Elementwise natural logarithm: result = ln(input)
+    pub fn log(&self, input: &[f32], result: &mut [f32]) -> Result<MatrixResult> {
+        self.elementwise(input, result, ElementwiseFn::Log)
+    }
+
+    /// Elementwise square root: result = sqrt(input)
+    pub fn sqrt(&self, input: &[f32], result: &mut [f32]) -> Result<MatrixResult> {
+        self.elementwise(input, result, ElementwiseFn::Sqrt)
+    }
+
+    /// Elementwise absolute value: result = |input|
+    pub fn abs(&self, input: &[f32], result: &mut [f32]) -> Result<MatrixResult> {
+        self.elementwise(input, result, ElementwiseFn::Abs)
+    }
+
+    /// Clamp every value into `[min, max]`
+    pub fn clamp(&self, input: &[f32], result: &mut [f32], min: f32, max: f32) -> Result<MatrixResult> {
+        if input.len() != result.len() {
+            return Err(UmicpError::matrix(format!(
+                "Vector length mismatch: input({}), result({})",
+                input.len(), result.len()
+            )));
+        }
+
+        result.copy_from_slice(input);
+        self.clamp_in_place(result, min, max)
+    }
+
+    /// Clamp every value into `[min, max]` in place
+    pub fn clamp_in_place(&self, data: &mut [f32], min: f32, max: f32) -> Result<MatrixResult> {
+        if min.is_nan() || max.is_nan() || min > max {
+            return Err(UmicpError::matrix(format!("Invalid clamp range: [{}, {}]", min, max)));
+        }
+
+        let _profile = self.profile("clamp", data.len(), data.len());
+        self.map_in_place(data, |x| x.clamp(min, max));
+
+        self.apply_non_finite_policy(data)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: None,
+        })
+    }
+
+    /// Rescale a gradient in place so its L2 norm does not exceed `max_norm`
+    ///
+    /// The returned result holds the norm measured before clipping, which is
+    /// useful for logging gradient health.
+    pub fn clip_by_norm(&self, gradient: &mut [f32], max_norm: f32) -> Result<MatrixResult> {
+        if !max_norm.is_finite() || max_norm < 0.0 {
+            return Err(UmicpError::matrix(format!("max_norm must be finite and non-negative, got {}", max_norm)));
+        }
+
+        let _profile = self.profile("clip_by_norm", gradient.len(), 3 * gradient.len());
+        let norm = self.sum_squares(gradient).sqrt();
+        if norm > max_norm {
+            let scale = max_norm / norm;
+            self.map_in_place(gradient, |x| x * scale);
+        }
+
+        self.apply_non_finite_policy(gradient)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: Some(norm as f64),
+            similarity: None,
+            data: None,
+        })
+    }
+
+    /// Clamp every gradient component into `[min, max]` in place
+    pub fn clip_by_value(&self, gradient: &mut [f32], min: f32, max: f32) -> Result<MatrixResult> {
+        self.clamp_in_place(gradient, min, max)
+    }
+
     /// Calculate matrix determinant (for square matrices only)
     pub fn determinant(&self, matrix: &[f32], size: usize) -> Result<MatrixResult> {
         let matrix_len = size * size;
@@ -296,6 +1580,8 @@ impl Matrix {
             )));
         }
 
+        let _profile = self.profile("determinant", matrix_len, 2 * matrix_len * size / 3);
+
         if size == 1 {
             return Ok(MatrixResult {
                 success: true,
@@ -358,14 +1644,358 @@ impl Matrix {
 
     // Private helper methods
 
+    /// Start timing `op` if profiling is on; it is recorded when the timer is dropped
+    fn profile(&self, op: &'static str, elements: usize, flops: usize) -> Option<OpTimer<'_>> {
+        self.profile.as_ref().map(|profile| OpTimer {
+            profile,
+            op,
+            elements,
+            flops,
+            started: Instant::now(),
+        })
+    }
+
     fn validate_dimensions(&self, a_len: usize, b_len: usize, result_len: usize, rows: usize, cols: usize) -> Result<()> {
         let expected_len = rows * cols;
         if a_len != expected_len || b_len != expected_len || result_len != expected_len {
             return Err(UmicpError::matrix(format!(
-                "Invalid matrix dimensions: expected {}x{} ({} elements), got a({}), b({}), result({})",
-                rows, cols, expected_len, a_len, b_len, result_len
+                "Invalid matrix dimensions: expected {}x{} ({} elements), got a({}), b({}), result({})",
+                rows, cols, expected_len, a_len, b_len, result_len
+            )));
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn broadcast(
+        &self,
+        name: &'static str,
+        matrix: &[f32],
+        vector: &[f32],
+        result: &mut [f32],
+        rows: usize,
+        cols: usize,
+        axis: Axis,
+        op: impl Fn(f32, f32) -> f32,
+    ) -> Result<MatrixResult> {
+        let expected_vector_len = match axis {
+            Axis::Row => cols,
+            Axis::Column => rows,
+        };
+        if matrix.len() != rows * cols || result.len() != rows * cols || vector.len() != expected_vector_len {
+            return Err(UmicpError::matrix(format!(
+                "Invalid broadcast dimensions: matrix({}) and result({}) must be {}x{}, vector({}) must be {}",
+                matrix.len(), result.len(), rows, cols, vector.len(), expected_vector_len
+            )));
+        }
+        let _profile = self.profile(name, rows * cols, rows * cols);
+
+        if cols > 0 {
+            for (i, (out_row, in_row)) in result.chunks_exact_mut(cols).zip(matrix.chunks_exact(cols)).enumerate() {
+                for (j, (out, &value)) in out_row.iter_mut().zip(in_row.iter()).enumerate() {
+                    let operand = match axis {
+                        Axis::Row => vector[j],
+                        Axis::Column => vector[i],
+                    };
+                    *out = op(value, operand);
+                }
+            }
+        }
+
+        self.apply_non_finite_policy(result)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: None,
+        })
+    }
+
+    fn apply_non_finite_policy(&self, data: &mut [f32]) -> Result<()> {
+        match self.non_finite_policy {
+            NonFinitePolicy::Ignore => Ok(()),
+            NonFinitePolicy::Error => self.check_finite(data),
+            NonFinitePolicy::Sanitize => {
+                for val in data.iter_mut().filter(|x| !x.is_finite()) {
+                    *val = 0.0;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn apply_non_finite_policy_scalar(&self, value: f64) -> Result<f64> {
+        if value.is_finite() {
+            return Ok(value);
+        }
+        match self.non_finite_policy {
+            NonFinitePolicy::Ignore => Ok(value),
+            NonFinitePolicy::Error => Err(UmicpError::matrix(format!("Non-finite scalar result {}", value))),
+            NonFinitePolicy::Sanitize => Ok(0.0),
+        }
+    }
+
+    fn sum(&self, values: &[f32]) -> f32 {
+        match self.summation {
+            SummationMode::Fast => values.iter().sum(),
+            _ => self.compensated_sum(values.iter().map(|&x| x as f64)) as f32,
+        }
+    }
+
+    fn sum_squares(&self, values: &[f32]) -> f32 {
+        match self.summation {
+            SummationMode::Fast => values.iter().map(|x| x * x).sum(),
+            _ => self.compensated_sum(values.iter().map(|&x| x as f64 * x as f64)) as f32,
+        }
+    }
+
+    fn compensated_sum(&self, values: impl Iterator<Item = f64>) -> f64 {
+        match self.summation {
+            SummationMode::Fast => values.sum(),
+            SummationMode::Kahan => {
+                // Neumaier's variant also handles addends larger than the running sum
+                let mut sum = 0.0f64;
+                let mut compensation = 0.0f64;
+                for value in values {
+                    let t = sum + value;
+                    if sum.abs() >= value.abs() {
+                        compensation += (sum - t) + value;
+                    } else {
+                        compensation += (value - t) + sum;
+                    }
+                    sum = t;
+                }
+                sum + compensation
+            }
+            SummationMode::Pairwise => {
+                // Cascade summation: fixed-size blocks are merged like a binary
+                // counter, so error grows with log(n) without buffering the input
+                const BLOCK: usize = 64;
+                let mut levels = [0.0f64; usize::BITS as usize];
+                let mut occupied = [false; usize::BITS as usize];
+                let mut block_sum = 0.0f64;
+                let mut block_len = 0;
+
+                for value in values {
+                    block_sum += value;
+                    block_len += 1;
+                    if block_len == BLOCK {
+                        let mut carry = block_sum;
+                        let mut level = 0;
+                        while occupied[level] {
+                            carry += levels[level];
+                            occupied[level] = false;
+                            level += 1;
+                        }
+                        levels[level] = carry;
+                        occupied[level] = true;
+                        block_sum = 0.0;
+                        block_len = 0;
+                    }
+                }
+
+                levels
+                    .iter()
+                    .zip(occupied.iter())
+                    .filter(|(_, &used)| used)
+                    .fold(block_sum, |acc, (level, _)| acc + level)
+            }
+        }
+    }
+
+    fn rng(seed: Option<u64>) -> StdRng {
+        match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        }
+    }
+
+    fn statistic(&self, data: &[f32], reduction: Reduction) -> Result<MatrixResult> {
+        if data.is_empty() {
+            return Err(UmicpError::matrix(format!("Cannot compute {:?} of an empty slice", reduction)));
+        }
+        let _profile = self.profile("statistic", data.len(), data.len());
+
+        let mut values = data.to_vec();
+        let value = self.apply_non_finite_policy_scalar(self.reduce_lane(&mut values, reduction)? as f64)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: Some(value),
+            similarity: None,
+            data: None,
+        })
+    }
+
+    /// Reduce one lane of values; order-statistic reductions sort `lane` in place
+    fn reduce_lane(&self, lane: &mut [f32], reduction: Reduction) -> Result<f32> {
+        let len = lane.len() as f32;
+        Ok(match reduction {
+            Reduction::Sum => self.sum(lane),
+            Reduction::Mean => self.sum(lane) / len,
+            Reduction::Max => lane.iter().cloned().fold(f32::NEG_INFINITY, f32::max),
+            Reduction::Min => lane.iter().cloned().fold(f32::INFINITY, f32::min),
+            Reduction::Variance | Reduction::Std => {
+                let mean = self.sum(lane) / len;
+                let deviations: Vec<f32> = lane.iter().map(|x| (x - mean) * (x - mean)).collect();
+                let variance = self.sum(&deviations) / len;
+                if reduction == Reduction::Std { variance.sqrt() } else { variance }
+            }
+            Reduction::Median => Self::sorted_percentile(lane, 50.0),
+            Reduction::Percentile(q) => Self::sorted_percentile(lane, q),
+        })
+    }
+
+    fn sorted_percentile(lane: &mut [f32], q: f32) -> f32 {
+        lane.sort_by(|a, b| a.total_cmp(b));
+        let rank = q / 100.0 * (lane.len() - 1) as f32;
+        let lower = rank.floor() as usize;
+        let upper = rank.ceil() as usize;
+        lane[lower] + (lane[upper] - lane[lower]) * (rank - lower as f32)
+    }
+
+    fn validate_percentile(q: f32) -> Result<()> {
+        if !(0.0..=100.0).contains(&q) {
+            return Err(UmicpError::matrix(format!("Percentile must be within [0, 100], got {}", q)));
+        }
+        Ok(())
+    }
+
+    fn cumulative_rows(&self, input: &[f32], result: &mut [f32], rows: usize, cols: usize, product: bool) -> Result<MatrixResult> {
+        if input.len() != rows * cols || result.len() != input.len() {
+            return Err(UmicpError::matrix(format!(
+                "Invalid matrix dimensions: input({}) and result({}) must be {}x{}",
+                input.len(), result.len(), rows, cols
+            )));
+        }
+        let _profile = self.profile(if product { "cumprod" } else { "cumsum" }, input.len(), input.len());
+
+        if cols > 0 {
+            for (in_row, out_row) in input.chunks_exact(cols).zip(result.chunks_exact_mut(cols)) {
+                if product {
+                    let mut running = 1.0f64;
+                    for (out, &value) in out_row.iter_mut().zip(in_row.iter()) {
+                        running *= value as f64;
+                        *out = running as f32;
+                    }
+                } else if self.summation == SummationMode::Fast {
+                    let mut running = 0.0f32;
+                    for (out, &value) in out_row.iter_mut().zip(in_row.iter()) {
+                        running += value;
+                        *out = running;
+                    }
+                } else {
+                    // Running Neumaier sum so long streams keep their precision
+                    let (mut sum, mut compensation) = (0.0f64, 0.0f64);
+                    for (out, &value) in out_row.iter_mut().zip(in_row.iter()) {
+                        let value = value as f64;
+                        let t = sum + value;
+                        compensation += if sum.abs() >= value.abs() { (sum - t) + value } else { (value - t) + sum };
+                        sum = t;
+                        *out = (sum + compensation) as f32;
+                    }
+                }
+            }
+        }
+
+        self.apply_non_finite_policy(result)?;
+
+        Ok(MatrixResult {
+            success: true,
+            error: None,
+            result: None,
+            similarity: None,
+            data: None,
+        })
+    }
+
+    fn normalize_span(&self, span: &mut [f32], kind: NormKind) {
+        match kind {
+            NormKind::L2 => {
+                let norm = self.sum_squares(span).sqrt();
+                if norm > 0.0 {
+                    for val in span.iter_mut() {
+                        *val /= norm;
+                    }
+                }
+            }
+            NormKind::MinMax => {
+                let min = span.iter().cloned().fold(f32::INFINITY, f32::min);
+                let max = span.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                let range = max - min;
+                for val in span.iter_mut() {
+                    *val = if range > 0.0 { (*val - min) / range } else { 0.0 };
+                }
+            }
+            NormKind::ZScore => {
+                let len = span.len() as f32;
+                let mean = self.sum(span) / len;
+                let deviations: Vec<f32> = span.iter().map(|x| (x - mean) * (x - mean)).collect();
+                let std = (self.sum(&deviations) / len).sqrt();
+                for val in span.iter_mut() {
+                    *val = if std > 0.0 { (*val - mean) / std } else { 0.0 };
+                }
+            }
+        }
+    }
+
+    fn validate_rows(&self, len: usize, rows: usize, cols: usize) -> Result<()> {
+        if len != rows * cols || cols == 0 {
+            return Err(UmicpError::matrix(format!(
+                "Invalid matrix dimensions: matrix({}) != {}x{}",
+                len, rows, cols
+            )));
+        }
+        Ok(())
+    }
+
+    fn arg_extreme(vector: &[f32], better: impl Fn(f32, f32) -> bool) -> Result<usize> {
+        let mut best: Option<(usize, f32)> = None;
+        for (i, &value) in vector.iter().enumerate() {
+            if value.is_nan() {
+                continue;
+            }
+            match best {
+                Some((_, current)) if !better(value, current) => {}
+                _ => best = Some((i, value)),
+            }
+        }
+
+        best.map(|(i, _)| i)
+            .ok_or_else(|| UmicpError::matrix("Cannot compute arg extreme of an empty or all-NaN vector"))
+    }
+
+    fn validate_norm_params(
+        &self,
+        len: usize,
+        rows: usize,
+        cols: usize,
+        gamma: Option<&[f32]>,
+        beta: Option<&[f32]>,
+        eps: f32,
+    ) -> Result<()> {
+        if !(eps >= 0.0 && eps.is_finite()) {
+            return Err(UmicpError::matrix(format!("Invalid eps {}: must be finite and non-negative", eps)));
+        }
+        if len != rows * cols {
+            return Err(UmicpError::matrix(format!(
+                "Invalid matrix dimensions: matrix({}) != {}x{}",
+                len, rows, cols
             )));
         }
+        for (name, param) in [("gamma", gamma), ("beta", beta)] {
+            if let Some(param) = param {
+                if param.len() != cols {
+                    return Err(UmicpError::matrix(format!(
+                        "Invalid {} length: {} != {} columns",
+                        name, param.len(), cols
+                    )));
+                }
+            }
+        }
         Ok(())
     }
 
@@ -382,6 +2012,24 @@ impl Matrix {
         }
     }
 
+    /// Product of validated operands with the kernel suited to their shape
+    fn multiply_kernel(&self, a: &[f32], b: &[f32], result: &mut [f32], m: usize, n: usize, p: usize) {
+        // Initialize result to zeros
+        result.fill(0.0);
+
+        // Strassen pays off only once every dimension is very large; below
+        // that the cache-blocked kernel wins. Skewed shapes would be padded
+        // out to a square many times their size, so they stay blocked too
+        let smallest = m.min(n).min(p);
+        if smallest >= STRASSEN_THRESHOLD && m.max(n).max(p) <= smallest * STRASSEN_MAX_ASPECT {
+            self.multiply_strassen(a, b, result, m, n, p, STRASSEN_LEAF);
+        } else if m * n * p > 10000 {
+            self.multiply_blocked(a, b, result, m, n, p);
+        } else {
+            self.multiply_sequential(a, b, result, m, n, p);
+        }
+    }
+
     fn multiply_sequential(&self, a: &[f32], b: &[f32], result: &mut [f32], m: usize, n: usize, p: usize) {
         for i in 0..m {
             for j in 0..p {
@@ -392,19 +2040,202 @@ impl Matrix {
         }
     }
 
-    fn multiply_parallel(&self, a: &[f32], b: &[f32], result: &mut [f32], m: usize, n: usize, p: usize) {
-        // Sequential implementation for compatibility
-        for i in 0..m {
-            for j in 0..p {
-                let mut sum = 0.0;
-                for k in 0..n {
-                    sum += a[i * n + k] * b[k * p + j];
+    fn transpose_blocked(&self, input: &[f32], output: &mut [f32], rows: usize, cols: usize) {
+        // Walking tile by tile keeps both the read and the strided write
+        // within a handful of cache lines
+        for i0 in (0..rows).step_by(TRANSPOSE_BLOCK) {
+            let i_end = (i0 + TRANSPOSE_BLOCK).min(rows);
+            for j0 in (0..cols).step_by(TRANSPOSE_BLOCK) {
+                let j_end = (j0 + TRANSPOSE_BLOCK).min(cols);
+                for i in i0..i_end {
+                    for j in j0..j_end {
+                        output[j * rows + i] = input[i * cols + j];
+                    }
+                }
+            }
+        }
+    }
+
+    fn multiply_blocked(&self, a: &[f32], b: &[f32], result: &mut [f32], m: usize, n: usize, p: usize) {
+        // Tile edge chosen so an A tile, a B^T tile and the output tile fit in L1/L2
+        const BLOCK: usize = 64;
+
+        // Transposing B makes the inner kernel walk both operands contiguously
+        let mut b_t = vec![0.0f32; n * p];
+        self.transpose_blocked(b, &mut b_t, n, p);
+
+        for i0 in (0..m).step_by(BLOCK) {
+            let i_end = (i0 + BLOCK).min(m);
+            for j0 in (0..p).step_by(BLOCK) {
+                let j_end = (j0 + BLOCK).min(p);
+                for k0 in (0..n).step_by(BLOCK) {
+                    let k_end = (k0 + BLOCK).min(n);
+                    for i in i0..i_end {
+                        let a_row = &a[i * n + k0..i * n + k_end];
+                        for j in j0..j_end {
+                            let b_col = &b_t[j * n + k0..j * n + k_end];
+                            result[i * p + j] += a_row.iter().zip(b_col.iter()).map(|(x, y)| x * y).sum::<f32>();
+                        }
+                    }
                 }
-                result[i * p + j] = sum;
             }
         }
     }
 
+    fn map_in_place(&self, data: &mut [f32], f: impl Fn(f32) -> f32) {
+        for val in data.iter_mut() {
+            *val = f(*val);
+        }
+    }
+
+    /// Apply `activation` to `data` in lane-sized chunks
+    fn activation_simd(&self, data: &mut [f32], activation: Activation) {
+        match activation {
+            Activation::Relu => Self::map_lanes(data, Self::relu_scalar),
+            Activation::Gelu => Self::map_lanes(data, Self::gelu_scalar),
+            Activation::Sigmoid => Self::map_lanes(data, Self::sigmoid_scalar),
+            Activation::Tanh => Self::map_lanes(data, Self::tanh_scalar),
+        }
+    }
+
+    fn map_lanes(data: &mut [f32], f: impl Fn(f32) -> f32) {
+        // Each chunk is copied into a fixed-size lane array so the per-lane
+        // body has no bounds checks and the compiler can vectorize it
+        const LANES: usize = 8;
+        let mut chunks = data.chunks_exact_mut(LANES);
+        for chunk in &mut chunks {
+            let mut lanes = [0.0f32; LANES];
+            lanes.copy_from_slice(chunk);
+            for lane in lanes.iter_mut() {
+                *lane = f(*lane);
+            }
+            chunk.copy_from_slice(&lanes);
+        }
+
+        for val in chunks.into_remainder() {
+            *val = f(*val);
+        }
+    }
+
+    fn relu_scalar(x: f32) -> f32 {
+        x.max(0.0)
+    }
+
+    fn gelu_scalar(x: f32) -> f32 {
+        const SQRT_2_OVER_PI: f32 = 0.797_884_6;
+        0.5 * x * (1.0 + (SQRT_2_OVER_PI * (x + 0.044_715 * x * x * x)).tanh())
+    }
+
+    fn sigmoid_scalar(x: f32) -> f32 {
+        1.0 / (1.0 + (-x).exp())
+    }
+
+    fn tanh_scalar(x: f32) -> f32 {
+        x.tanh()
+    }
+
+    /// Single pass over both vectors computing (a.b, |a|^2, |b|^2)
+    fn cosine_terms_simd(&self, a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+        // Independent per-lane accumulators break the dependency chain so the
+        // compiler can keep all three sums in vector registers
+        const LANES: usize = 8;
+        let mut dot = [0.0f32; LANES];
+        let mut a_sq = [0.0f32; LANES];
+        let mut b_sq = [0.0f32; LANES];
+
+        let a_chunks = a.chunks_exact(LANES);
+        let b_chunks = b.chunks_exact(LANES);
+        let (a_tail, b_tail) = (a_chunks.remainder(), b_chunks.remainder());
+        for (x, y) in a_chunks.zip(b_chunks) {
+            for lane in 0..LANES {
+                dot[lane] += x[lane] * y[lane];
+                a_sq[lane] += x[lane] * x[lane];
+                b_sq[lane] += y[lane] * y[lane];
+            }
+        }
+
+        let (mut dot_sum, mut a_sum, mut b_sum) = (dot.iter().sum::<f32>(), a_sq.iter().sum::<f32>(), b_sq.iter().sum::<f32>());
+        for (&x, &y) in a_tail.iter().zip(b_tail.iter()) {
+            dot_sum += x * y;
+            a_sum += x * x;
+            b_sum += y * y;
+        }
+
+        (dot_sum, a_sum, b_sum)
+    }
+
+    /// Strassen multiplication on zero-padded square operands
+    #[allow(clippy::too_many_arguments)]
+    fn multiply_strassen(&self, a: &[f32], b: &[f32], result: &mut [f32], m: usize, n: usize, p: usize, leaf: usize) {
+        // Pad to leaf_size * 2^k so every level splits evenly, picking k so the
+        // leaf lands in [leaf, 2 * leaf) and padding stays small
+        let largest = m.max(n).max(p);
+        let mut levels = 0;
+        while largest.div_ceil(1 << levels) >= 2 * leaf {
+            levels += 1;
+        }
+        let size = largest.div_ceil(1 << levels) << levels;
+
+        let pad = |src: &[f32], rows: usize, cols: usize| {
+            let mut padded = vec![0.0f32; size * size];
+            for (r, row) in src.chunks_exact(cols).take(rows).enumerate() {
+                padded[r * size..r * size + cols].copy_from_slice(row);
+            }
+            padded
+        };
+
+        let product = self.strassen_square(&pad(a, m, n), &pad(b, n, p), size, leaf);
+        for (r, row) in result.chunks_exact_mut(p).enumerate() {
+            row.copy_from_slice(&product[r * size..r * size + p]);
+        }
+    }
+
+    fn strassen_square(&self, a: &[f32], b: &[f32], size: usize, leaf: usize) -> Vec<f32> {
+        if size <= leaf || !size.is_multiple_of(2) {
+            let mut c = vec![0.0f32; size * size];
+            self.multiply_blocked(a, b, &mut c, size, size, size);
+            return c;
+        }
+
+        let half = size / 2;
+        let quadrant = |m: &[f32], qr: usize, qc: usize| {
+            let mut q = Vec::with_capacity(half * half);
+            for r in 0..half {
+                let start = (qr * half + r) * size + qc * half;
+                q.extend_from_slice(&m[start..start + half]);
+            }
+            q
+        };
+        let add = |x: &[f32], y: &[f32]| x.iter().zip(y.iter()).map(|(u, v)| u + v).collect::<Vec<f32>>();
+        let sub = |x: &[f32], y: &[f32]| x.iter().zip(y.iter()).map(|(u, v)| u - v).collect::<Vec<f32>>();
+
+        let (a11, a12, a21, a22) = (quadrant(a, 0, 0), quadrant(a, 0, 1), quadrant(a, 1, 0), quadrant(a, 1, 1));
+        let (b11, b12, b21, b22) = (quadrant(b, 0, 0), quadrant(b, 0, 1), quadrant(b, 1, 0), quadrant(b, 1, 1));
+
+        // Seven half-size products instead of eight
+        let m1 = self.strassen_square(&add(&a11, &a22), &add(&b11, &b22), half, leaf);
+        let m2 = self.strassen_square(&add(&a21, &a22), &b11, half, leaf);
+        let m3 = self.strassen_square(&a11, &sub(&b12, &b22), half, leaf);
+        let m4 = self.strassen_square(&a22, &sub(&b21, &b11), half, leaf);
+        let m5 = self.strassen_square(&add(&a11, &a12), &b22, half, leaf);
+        let m6 = self.strassen_square(&sub(&a21, &a11), &add(&b11, &b12), half, leaf);
+        let m7 = self.strassen_square(&sub(&a12, &a22), &add(&b21, &b22), half, leaf);
+
+        let mut c = vec![0.0f32; size * size];
+        for r in 0..half {
+            for col in 0..half {
+                let i = r * half + col;
+                let top = r * size + col;
+                let bottom = (r + half) * size + col;
+                c[top] = m1[i] + m4[i] - m5[i] + m7[i];
+                c[top + half] = m3[i] + m5[i];
+                c[bottom] = m2[i] + m4[i];
+                c[bottom + half] = m1[i] - m2[i] + m3[i] + m6[i];
+            }
+        }
+        c
+    }
+
     fn dot_product_simd(&self, a: &[f32], b: &[f32]) -> f32 {
         // Fallback to regular implementation for now
         // In a real implementation, this would use SIMD intrinsics
@@ -426,7 +2257,6 @@ mod tests {
     fn test_matrix_creation() {
         let _matrix = Matrix::new();
         // Just verify it can be created
-        assert!(true);
     }
 
     #[test]
@@ -480,6 +2310,42 @@ mod tests {
         assert_eq!(result[3], 50.0);
     }
 
+    #[test]
+    fn test_matrix_multiply_blocked_matches_naive() {
+        let matrix = Matrix::new();
+        // Sizes straddle the tile edge to exercise partial blocks
+        let (m, n, p) = (70, 65, 130);
+        let a: Vec<f32> = (0..m * n).map(|i| ((i % 17) as f32 - 8.0) * 0.25).collect();
+        let b: Vec<f32> = (0..n * p).map(|i| ((i % 13) as f32 - 6.0) * 0.5).collect();
+
+        let mut result = vec![0.0; m * p];
+        matrix.multiply(&a, &b, &mut result, m, n, p).unwrap();
+
+        let mut expected = vec![0.0; m * p];
+        matrix.multiply_sequential(&a, &b, &mut expected, m, n, p);
+        for (x, y) in result.iter().zip(expected.iter()) {
+            assert!((x - y).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_matrix_multiply_strassen_matches_blocked() {
+        let matrix = Matrix::new();
+        // A tiny leaf forces several recursion levels plus padding
+        let (m, n, p) = (50, 37, 45);
+        let a: Vec<f32> = (0..m * n).map(|i| ((i % 11) as f32 - 5.0) * 0.5).collect();
+        let b: Vec<f32> = (0..n * p).map(|i| ((i % 7) as f32 - 3.0) * 0.25).collect();
+
+        let mut strassen = vec![0.0; m * p];
+        matrix.multiply_strassen(&a, &b, &mut strassen, m, n, p, 8);
+
+        let mut expected = vec![0.0; m * p];
+        matrix.multiply_sequential(&a, &b, &mut expected, m, n, p);
+        for (x, y) in strassen.iter().zip(expected.iter()) {
+            assert!((x - y).abs() < 1e-3);
+        }
+    }
+
     #[test]
     fn test_matrix_transpose() {
         let matrix = Matrix::new();
@@ -491,6 +2357,643 @@ mod tests {
         assert_eq!(output, vec![1.0, 3.0, 2.0, 4.0]);
     }
 
+    #[test]
+    fn test_similarity_matrix() {
+        let matrix = Matrix::new();
+        let a = vec![1.0, 0.0, 0.0, 1.0]; // 2 vectors of dim 2
+        let b = vec![1.0, 0.0, 3.0, 4.0, -1.0, 0.0]; // 3 vectors of dim 2
+
+        let cosine = matrix.similarity_matrix(&a, &b, 2, SimilarityMetric::Cosine).unwrap();
+        let scores = cosine.data.unwrap();
+        assert_eq!(scores.len(), 6);
+        let expected = [1.0, 0.6, -1.0, 0.0, 0.8, 0.0];
+        for (score, expected) in scores.iter().zip(expected.iter()) {
+            assert!((score - expected).abs() < 1e-6);
+        }
+
+        let euclidean = matrix.similarity_matrix(&a, &b, 2, SimilarityMetric::Euclidean).unwrap();
+        let distances = euclidean.data.unwrap();
+        assert!(distances[0].abs() < 1e-6);
+        assert!((distances[1] - 20.0f32.sqrt()).abs() < 1e-5);
+        assert!((distances[5] - 2.0f32.sqrt()).abs() < 1e-6);
+
+        assert!(matrix.similarity_matrix(&a, &[1.0, 2.0, 3.0], 2, SimilarityMetric::Cosine).is_err());
+    }
+
+    #[test]
+    fn test_softmax() {
+        let matrix = Matrix::new();
+        let mut logits = vec![1.0, 2.0, 3.0, 1000.0, 1000.0, 1000.0];
+
+        let result = matrix.softmax(&mut logits, 2, 3, None).unwrap();
+        assert!(result.success);
+        assert!((logits[0..3].iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        assert!((logits[2] - 0.665_240_9).abs() < 1e-6);
+        // Large logits stay finite thanks to the max shift
+        for val in &logits[3..6] {
+            assert!((val - 1.0 / 3.0).abs() < 1e-6);
+        }
+
+        let mut sharp = vec![1.0, 2.0];
+        matrix.softmax(&mut sharp, 1, 2, Some(0.1)).unwrap();
+        assert!(sharp[1] > 0.9999);
+
+        assert!(matrix.softmax(&mut [1.0, 2.0], 1, 2, Some(0.0)).is_err());
+
+        // Infinite logits give the limiting distributions rather than NaN
+        let mut logits = vec![
+            f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY,
+            1.0, f32::INFINITY, f32::NEG_INFINITY, f32::INFINITY,
+        ];
+        let mut strict = Matrix::new();
+        strict.set_non_finite_policy(NonFinitePolicy::Error);
+        strict.softmax(&mut logits, 2, 4, None).unwrap();
+        assert_eq!(logits, vec![0.25, 0.25, 0.25, 0.25, 0.0, 0.5, 0.0, 0.5]);
+    }
+
+    #[test]
+    fn test_activations() {
+        let matrix = Matrix::new();
+        let input = vec![-2.0, -0.5, 0.0, 0.5, 2.0, -1.0, 1.0, 3.0, -3.0];
+        let mut result = vec![0.0; input.len()];
+
+        matrix.relu(&input, &mut result).unwrap();
+        assert_eq!(result, vec![0.0, 0.0, 0.0, 0.5, 2.0, 0.0, 1.0, 3.0, 0.0]);
+
+        matrix.sigmoid(&input, &mut result).unwrap();
+        assert!((result[2] - 0.5).abs() < 1e-6);
+        assert!((result[4] - 0.880_797).abs() < 1e-5);
+
+        matrix.tanh(&input, &mut result).unwrap();
+        assert!((result[8] - (-3.0f32).tanh()).abs() < 1e-6);
+
+        matrix.gelu(&input, &mut result).unwrap();
+        assert!(result[2].abs() < 1e-6);
+        assert!((result[6] - 0.841_192).abs() < 1e-4);
+
+        let mut data = input.clone();
+        matrix.activation_in_place(&mut data, Activation::Relu).unwrap();
+        assert_eq!(data[4], 2.0);
+        assert_eq!(data[0], 0.0);
+        let mut data = input.clone();
+        matrix.gelu_in_place(&mut data).unwrap();
+        assert_eq!(data, result);
+
+        assert!(matrix.relu(&input, &mut [0.0; 2]).is_err());
+    }
+
+    #[test]
+    fn test_activation_lanes() {
+        let matrix = Matrix::new();
+        // Length not a multiple of the lane width exercises the tail loop
+        let input: Vec<f32> = (0..29).map(|i| (i as f32 - 14.0) * 0.37).collect();
+
+        let cases = [
+            (Activation::Relu, Matrix::relu_scalar as fn(f32) -> f32),
+            (Activation::Gelu, Matrix::gelu_scalar),
+            (Activation::Sigmoid, Matrix::sigmoid_scalar),
+            (Activation::Tanh, Matrix::tanh_scalar),
+        ];
+        for (activation, reference) in cases {
+            let expected: Vec<f32> = input.iter().map(|&x| reference(x)).collect();
+            let mut result = vec![0.0; input.len()];
+            matrix.activation(&input, &mut result, activation).unwrap();
+            assert_eq!(result, expected);
+            let mut data = input.clone();
+            matrix.activation_in_place(&mut data, activation).unwrap();
+            assert_eq!(data, expected);
+        }
+    }
+
+    #[test]
+    fn test_layer_norm_and_rms_norm() {
+        let matrix = Matrix::new();
+
+        let mut data = vec![1.0, 2.0, 3.0, 4.0, 10.0, 10.0, 10.0, 10.0];
+        matrix.layer_norm(&mut data, 2, 4, None, None, 1e-5).unwrap();
+        let mean: f32 = data[0..4].iter().sum::<f32>() / 4.0;
+        let variance: f32 = data[0..4].iter().map(|x| x * x).sum::<f32>() / 4.0;
+        assert!(mean.abs() < 1e-6);
+        assert!((variance - 1.0).abs() < 1e-3);
+        // Constant rows collapse to zero rather than dividing by zero
+        assert!(data[4..8].iter().all(|x| x.abs() < 1e-6));
+
+        let mut data = vec![1.0, 3.0];
+        matrix.layer_norm(&mut data, 1, 2, Some(&[2.0, 2.0]), Some(&[0.5, 0.5]), 0.0).unwrap();
+        assert_eq!(data, vec![-1.5, 2.5]);
+
+        let mut data = vec![3.0, 4.0];
+        matrix.rms_norm(&mut data, 1, 2, Some(&[1.0, 2.0]), 0.0).unwrap();
+        let rms = 12.5f32.sqrt();
+        assert!((data[0] - 3.0 / rms).abs() < 1e-6);
+        assert!((data[1] - 8.0 / rms).abs() < 1e-6);
+
+        // Without eps, constant rows still give finite values
+        let mut data = vec![5.0, 5.0];
+        matrix.layer_norm(&mut data, 1, 2, None, Some(&[0.5, 0.5]), 0.0).unwrap();
+        assert_eq!(data, vec![0.5, 0.5]);
+        let mut data = vec![0.0, 0.0];
+        matrix.rms_norm(&mut data, 1, 2, None, 0.0).unwrap();
+        assert_eq!(data, vec![0.0, 0.0]);
+
+        assert!(matrix.rms_norm(&mut [1.0, 2.0], 1, 2, Some(&[1.0]), 1e-5).is_err());
+        assert!(matrix.layer_norm(&mut [1.0, 2.0], 1, 2, None, None, -1e-5).is_err());
+    }
+
+    #[test]
+    fn test_reduce() {
+        let matrix = Matrix::new();
+        // 2x3 matrix
+        let data = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+
+        let pooled = matrix.reduce(&data, 2, 3, Axis::Row, Reduction::Mean).unwrap();
+        assert_eq!(pooled.data.unwrap(), vec![2.5, 3.5, 4.5]);
+
+        let row_sums = matrix.reduce(&data, 2, 3, Axis::Column, Reduction::Sum).unwrap();
+        assert_eq!(row_sums.data.unwrap(), vec![6.0, 15.0]);
+
+        let col_max = matrix.reduce(&data, 2, 3, Axis::Row, Reduction::Max).unwrap();
+        assert_eq!(col_max.data.unwrap(), vec![4.0, 5.0, 6.0]);
+
+        let row_min = matrix.reduce(&data, 2, 3, Axis::Column, Reduction::Min).unwrap();
+        assert_eq!(row_min.data.unwrap(), vec![1.0, 4.0]);
+
+        assert!(matrix.reduce(&data, 3, 3, Axis::Row, Reduction::Sum).is_err());
+    }
+
+    #[test]
+    fn test_argmax_argmin_top_k() {
+        let matrix = Matrix::new();
+        let logits = vec![0.1, 2.5, f32::NAN, -1.0, 2.5, 0.7];
+
+        assert_eq!(matrix.argmax(&logits).unwrap(), 1);
+        assert_eq!(matrix.argmin(&logits).unwrap(), 3);
+        assert!(matrix.argmax(&[]).is_err());
+
+        let top = matrix.top_k_values(&logits, 3).unwrap();
+        assert_eq!(top, vec![(1, 2.5), (4, 2.5), (5, 0.7)]);
+        assert_eq!(matrix.top_k_values(&logits, 10).unwrap().len(), 5);
+
+        // 2x3 matrix
+        let scores = vec![0.2, 0.5, 0.3, 0.9, 0.05, 0.05];
+        assert_eq!(matrix.argmax_rows(&scores, 2, 3).unwrap(), vec![1, 0]);
+        assert_eq!(matrix.argmin_rows(&scores, 2, 3).unwrap(), vec![0, 1]);
+        let per_row = matrix.top_k_values_rows(&scores, 2, 3, 1).unwrap();
+        assert_eq!(per_row, vec![vec![(1, 0.5)], vec![(0, 0.9)]]);
+    }
+
+    #[test]
+    fn test_broadcast_operations() {
+        let matrix = Matrix::new();
+        // 2x3 matrix
+        let data = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        let mut result = vec![0.0; 6];
+
+        matrix.broadcast_add(&data, &[10.0, 20.0, 30.0], &mut result, 2, 3, Axis::Row).unwrap();
+        assert_eq!(result, vec![11.0, 22.0, 33.0, 14.0, 25.0, 36.0]);
+
+        matrix.broadcast_multiply(&data, &[2.0, -1.0], &mut result, 2, 3, Axis::Column).unwrap();
+        assert_eq!(result, vec![2.0, 4.0, 6.0, -4.0, -5.0, -6.0]);
+
+        assert!(matrix.broadcast_add(&data, &[1.0, 2.0], &mut result, 2, 3, Axis::Row).is_err());
+    }
+
+    #[test]
+    fn test_compensated_summation() {
+        // 1.0 followed by many values below half an f32 ulp of 1.0
+        let mut values = vec![1.0f32];
+        values.extend(std::iter::repeat_n(1e-8f32, 1_000_000));
+        let ones = vec![1.0f32; values.len()];
+        let expected = 1.01f64;
+
+        let fast = Matrix::new().dot_product(&values, &ones).unwrap().result.unwrap();
+        assert!((fast - expected).abs() > 1e-3);
+
+        for mode in [SummationMode::Kahan, SummationMode::Pairwise] {
+            let matrix = Matrix::with_summation(mode);
+            assert_eq!(matrix.summation(), mode);
+
+            let dot = matrix.dot_product(&values, &ones).unwrap().result.unwrap();
+            assert!((dot - expected).abs() < 1e-9, "{:?}: {}", mode, dot);
+
+            let total = matrix.reduce(&values, 1, values.len(), Axis::Column, Reduction::Sum).unwrap();
+            assert!((total.data.unwrap()[0] as f64 - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_non_finite_policy() {
+        let mut matrix = Matrix::new();
+        let a = vec![1.0, f32::NAN, f32::INFINITY];
+        let b = vec![1.0, 1.0, 1.0];
+        let mut result = vec![0.0; 3];
+
+        assert!(matrix.check_finite(&b).is_ok());
+        assert!(matrix.check_finite(&a).is_err());
+
+        // Default policy lets values through unchanged
+        assert_eq!(matrix.non_finite_policy(), NonFinitePolicy::Ignore);
+        matrix.vector_add(&a, &b, &mut result).unwrap();
+        assert!(result[1].is_nan());
+
+        matrix.set_non_finite_policy(NonFinitePolicy::Error);
+        assert!(matrix.vector_add(&a, &b, &mut result).is_err());
+        assert!(matrix.dot_product(&a, &b).is_err());
+
+        matrix.set_non_finite_policy(NonFinitePolicy::Sanitize);
+        matrix.vector_add(&a, &b, &mut result).unwrap();
+        assert_eq!(result, vec![2.0, 0.0, 0.0]);
+        assert_eq!(matrix.dot_product(&a, &b).unwrap().result.unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_random_generation() {
+        let a = Matrix::random_normal(10_000, 2.0, 0.5, Some(42)).unwrap();
+        let b = Matrix::random_normal(10_000, 2.0, 0.5, Some(42)).unwrap();
+        assert_eq!(a, b);
+
+        let mean = a.iter().sum::<f32>() / a.len() as f32;
+        let variance = a.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / a.len() as f32;
+        assert!((mean - 2.0).abs() < 0.05);
+        assert!((variance.sqrt() - 0.5).abs() < 0.05);
+
+        let uniform = Matrix::random_uniform(1000, -1.0, 1.0, Some(7)).unwrap();
+        assert!(uniform.iter().all(|x| (-1.0..1.0).contains(x)));
+        assert!(Matrix::random_uniform(10, 1.0, 1.0, None).is_err());
+
+        let limit = (6.0f32 / 30.0).sqrt();
+        let xavier = Matrix::xavier_uniform(10, 20, Some(1)).unwrap();
+        assert_eq!(xavier.len(), 200);
+        assert!(xavier.iter().all(|x| x.abs() <= limit));
+
+        assert_eq!(Matrix::he_normal(16, 4, Some(3)).unwrap().len(), 64);
+        assert!(Matrix::he_normal(0, 4, None).is_err());
+    }
+
+    #[test]
+    fn test_descriptive_statistics() {
+        let matrix = Matrix::new();
+        let data = vec![2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+
+        assert_eq!(matrix.mean(&data).unwrap().result.unwrap(), 5.0);
+        assert_eq!(matrix.variance(&data).unwrap().result.unwrap(), 4.0);
+        assert_eq!(matrix.std_dev(&data).unwrap().result.unwrap(), 2.0);
+        assert_eq!(matrix.median(&data).unwrap().result.unwrap(), 4.5);
+        assert_eq!(matrix.percentile(&data, 0.0).unwrap().result.unwrap(), 2.0);
+        assert_eq!(matrix.percentile(&data, 100.0).unwrap().result.unwrap(), 9.0);
+        assert!((matrix.percentile(&data, 90.0).unwrap().result.unwrap() - 7.6).abs() < 1e-5);
+        assert!(matrix.percentile(&data, 101.0).is_err());
+        assert!(matrix.mean(&[]).is_err());
+
+        // 2x3 matrix, statistics per column and per row
+        let grid = vec![1.0, 5.0, 3.0, 3.0, 1.0, 9.0];
+        let medians = matrix.reduce(&grid, 2, 3, Axis::Column, Reduction::Median).unwrap();
+        assert_eq!(medians.data.unwrap(), vec![3.0, 3.0]);
+        let variances = matrix.reduce(&grid, 2, 3, Axis::Row, Reduction::Variance).unwrap();
+        assert_eq!(variances.data.unwrap(), vec![1.0, 4.0, 9.0]);
+    }
+
+    #[test]
+    fn test_conv1d_and_correlate1d() {
+        let matrix = Matrix::new();
+        let signal = vec![1.0, 2.0, 3.0, 4.0, 5.0];
+
+        // Moving average keeps length with padding 1
+        let smoothed = matrix.conv1d(&signal, &[1.0 / 3.0; 3], 1, 1).unwrap().data.unwrap();
+        assert_eq!(smoothed.len(), 5);
+        assert!((smoothed[0] - 1.0).abs() < 1e-6);
+        assert!((smoothed[2] - 3.0).abs() < 1e-6);
+
+        // Asymmetric kernel distinguishes convolution from correlation
+        let conv = matrix.conv1d(&signal, &[1.0, 0.0, -1.0], 1, 0).unwrap().data.unwrap();
+        let corr = matrix.correlate1d(&signal, &[1.0, 0.0, -1.0], 1, 0).unwrap().data.unwrap();
+        assert_eq!(conv, vec![2.0, 2.0, 2.0]);
+        assert_eq!(corr, vec![-2.0, -2.0, -2.0]);
+
+        let strided = matrix.correlate1d(&signal, &[1.0, 1.0], 2, 0).unwrap().data.unwrap();
+        assert_eq!(strided, vec![3.0, 7.0]);
+
+        assert!(matrix.conv1d(&signal, &[1.0], 0, 0).is_err());
+        assert!(matrix.conv1d(&[1.0], &[1.0, 1.0, 1.0], 1, 0).is_err());
+    }
+
+    #[test]
+    fn test_conv2d() {
+        let matrix = Matrix::new();
+        // 1 channel 3x3 image, 2x2 kernel
+        let image = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0];
+        let kernel = vec![1.0, 0.0, 0.0, -1.0];
+        let params = Conv2dParams::new(1, 1, 3, 3, 2);
+
+        let output = matrix.conv2d(&image, &kernel, None, &params).unwrap().data.unwrap();
+        assert_eq!(output, vec![-4.0, -4.0, -4.0, -4.0]);
+
+        // Padding and stride, with bias
+        let params = Conv2dParams::new(1, 1, 3, 3, 3).with_padding(1).with_stride(2);
+        assert_eq!((params.output_height(), params.output_width()), (2, 2));
+        let ones = vec![1.0; 9];
+        let output = matrix.conv2d(&image, &ones, Some(&[0.5]), &params).unwrap().data.unwrap();
+        assert_eq!(output, vec![12.5, 16.5, 24.5, 28.5]);
+
+        // Two input channels summed into two output channels
+        let two_channels: Vec<f32> = image.iter().chain(image.iter()).cloned().collect();
+        let params = Conv2dParams::new(2, 2, 3, 3, 1);
+        let kernel = vec![1.0, 1.0, 1.0, -1.0];
+        let output = matrix.conv2d(&two_channels, &kernel, None, &params).unwrap().data.unwrap();
+        assert_eq!(output[0..9], [2.0, 4.0, 6.0, 8.0, 10.0, 12.0, 14.0, 16.0, 18.0]);
+        assert!(output[9..].iter().all(|&x| x == 0.0));
+
+        assert!(matrix.conv2d(&image, &[1.0], None, &Conv2dParams::new(1, 1, 3, 3, 2)).is_err());
+    }
+
+    #[test]
+    fn test_outer_product() {
+        let matrix = Matrix::new();
+        let a = vec![1.0, 2.0];
+        let b = vec![3.0, 4.0, 5.0];
+        let mut result = vec![0.0; 6];
+
+        matrix.outer_product(&a, &b, &mut result).unwrap();
+        assert_eq!(result, vec![3.0, 4.0, 5.0, 6.0, 8.0, 10.0]);
+
+        assert!(matrix.outer_product(&a, &b, &mut [0.0; 5]).is_err());
+    }
+
+    #[test]
+    fn test_trace_and_rank() {
+        let matrix = Matrix::new();
+        let square = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 10.0];
+        assert_eq!(matrix.trace(&square, 3).unwrap().result.unwrap(), 16.0);
+        assert!(matrix.trace(&square, 2).is_err());
+
+        assert_eq!(matrix.rank(&square, 3, 3, None).unwrap().result.unwrap(), 3.0);
+
+        // Third row is the sum of the first two
+        let deficient = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 5.0, 7.0, 9.0];
+        assert_eq!(matrix.rank(&deficient, 3, 3, None).unwrap().result.unwrap(), 2.0);
+
+        // Rectangular and zero matrices
+        let wide = vec![1.0, 2.0, 3.0, 2.0, 4.0, 6.0];
+        assert_eq!(matrix.rank(&wide, 2, 3, None).unwrap().result.unwrap(), 1.0);
+        assert_eq!(matrix.rank(&[0.0; 4], 2, 2, None).unwrap().result.unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_elementwise_math() {
+        let matrix = Matrix::new();
+        let input = vec![1.0, 4.0, 9.0];
+        let mut result = vec![0.0; 3];
+
+        matrix.sqrt(&input, &mut result).unwrap();
+        assert_eq!(result, vec![1.0, 2.0, 3.0]);
+
+        matrix.exp(&[0.0, 1.0, 2.0], &mut result).unwrap();
+        assert!((result[1] - std::f32::consts::E).abs() < 1e-6);
+
+        matrix.log(&[1.0, std::f32::consts::E, 1.0], &mut result).unwrap();
+        assert!((result[1] - 1.0).abs() < 1e-6);
+
+        matrix.abs(&[-1.5, 0.0, 2.0], &mut result).unwrap();
+        assert_eq!(result, vec![1.5, 0.0, 2.0]);
+
+        let mut data = vec![-3.0, 0.5, 7.0];
+        matrix.clamp_in_place(&mut data, -1.0, 1.0).unwrap();
+        assert_eq!(data, vec![-1.0, 0.5, 1.0]);
+        assert!(matrix.clamp(&input, &mut result, 2.0, 1.0).is_err());
+
+        // NaN is not clamped, so it is left to the non-finite policy
+        let mut data = vec![f32::NAN, 3.0];
+        matrix.clamp_in_place(&mut data, -1.0, 1.0).unwrap();
+        assert!(data[0].is_nan());
+        let mut strict = Matrix::new();
+        strict.set_non_finite_policy(NonFinitePolicy::Error);
+        assert!(strict.clamp(&[f32::NAN, 3.0], &mut [0.0; 2], -1.0, 1.0).is_err());
+        let mut sanitizing = Matrix::new();
+        sanitizing.set_non_finite_policy(NonFinitePolicy::Sanitize);
+        let mut data = vec![f32::NAN, 3.0];
+        sanitizing.clamp_in_place(&mut data, -1.0, 1.0).unwrap();
+        assert_eq!(data, vec![0.0, 1.0]);
+
+        let mut data = vec![1.0, 100.0];
+        matrix.elementwise_in_place(&mut data, ElementwiseFn::Log).unwrap();
+        assert!((data[1] - 100.0f32.ln()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_cumulative_operations() {
+        let matrix = Matrix::new();
+        let mut result = vec![0.0; 4];
+
+        matrix.cumsum(&[1.0, 2.0, 3.0, 4.0], &mut result).unwrap();
+        assert_eq!(result, vec![1.0, 3.0, 6.0, 10.0]);
+
+        matrix.cumprod(&[1.0, 2.0, 3.0, 4.0], &mut result).unwrap();
+        assert_eq!(result, vec![1.0, 2.0, 6.0, 24.0]);
+
+        // 2x2 matrix, rows accumulate independently
+        matrix.cumsum_rows(&[1.0, 1.0, 5.0, 5.0], &mut result, 2, 2).unwrap();
+        assert_eq!(result, vec![1.0, 2.0, 5.0, 10.0]);
+        matrix.cumprod_rows(&[2.0, 3.0, 4.0, 0.5], &mut result, 2, 2).unwrap();
+        assert_eq!(result, vec![2.0, 6.0, 4.0, 2.0]);
+
+        let mut stream = vec![1.0f32];
+        stream.extend(std::iter::repeat_n(1e-8f32, 100_000));
+        let mut running = vec![0.0; stream.len()];
+        matrix.cumsum(&stream, &mut running).unwrap();
+        assert_eq!(running[100_000], 1.0);
+        Matrix::with_summation(SummationMode::Kahan).cumsum(&stream, &mut running).unwrap();
+        assert!((running[100_000] - 1.001).abs() < 1e-6);
+
+        assert!(matrix.cumsum(&[1.0, 2.0], &mut result).is_err());
+    }
+
+    #[test]
+    fn test_normalize_with_kinds() {
+        let matrix = Matrix::new();
+
+        let mut data = vec![0.0, 5.0, 10.0, 2.0, 2.0, 2.0];
+        matrix.normalize_with(&mut data, 2, 3, NormKind::MinMax, NormScope::Row).unwrap();
+        assert_eq!(data, vec![0.0, 0.5, 1.0, 0.0, 0.0, 0.0]);
+
+        let mut data = vec![0.0, 5.0, 10.0, 15.0];
+        matrix.normalize_with(&mut data, 2, 2, NormKind::MinMax, NormScope::Tensor).unwrap();
+        assert_eq!(data, vec![0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0]);
+
+        let mut data = vec![2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+        matrix.normalize_with(&mut data, 2, 4, NormKind::ZScore, NormScope::Tensor).unwrap();
+        assert_eq!(data[0], -1.5);
+        assert_eq!(data[7], 2.0);
+
+        let mut data = vec![1.0, 3.0, 10.0, 20.0];
+        matrix.normalize_with(&mut data, 2, 2, NormKind::ZScore, NormScope::Row).unwrap();
+        assert_eq!(data, vec![-1.0, 1.0, -1.0, 1.0]);
+
+        let mut data = vec![3.0, 4.0];
+        matrix.normalize_with(&mut data, 1, 2, NormKind::L2, NormScope::Row).unwrap();
+        assert_eq!(data, vec![0.6, 0.8]);
+    }
+
+    #[test]
+    fn test_weighted_average() {
+        let matrix = Matrix::new();
+        let a = [1.0, 2.0];
+        let b = [3.0, 6.0];
+        let mut result = vec![0.0; 2];
+
+        matrix.weighted_average(&[&a, &b], &[1.0, 1.0], &mut result).unwrap();
+        assert_eq!(result, vec![2.0, 4.0]);
+
+        // Sample counts weight the second client three times as heavily
+        matrix.weighted_average(&[&a, &b], &[100.0, 300.0], &mut result).unwrap();
+        assert_eq!(result, vec![2.5, 5.0]);
+
+        assert!(matrix.weighted_average(&[&a, &b], &[1.0], &mut result).is_err());
+        assert!(matrix.weighted_average(&[&a, &b], &[0.0, 0.0], &mut result).is_err());
+        assert!(matrix.weighted_average(&[&a, &[1.0]], &[1.0, 1.0], &mut result).is_err());
+    }
+
+    #[test]
+    fn test_gradient_clipping() {
+        let matrix = Matrix::new();
+
+        let mut gradient = vec![3.0, 4.0];
+        let result = matrix.clip_by_norm(&mut gradient, 1.0).unwrap();
+        assert_eq!(result.result.unwrap(), 5.0);
+        assert!((gradient[0] - 0.6).abs() < 1e-6);
+        assert!((gradient[1] - 0.8).abs() < 1e-6);
+
+        // Gradients already within the bound are untouched
+        let mut small = vec![0.3, 0.4];
+        matrix.clip_by_norm(&mut small, 1.0).unwrap();
+        assert_eq!(small, vec![0.3, 0.4]);
+        assert!(matrix.clip_by_norm(&mut small, -1.0).is_err());
+
+        let mut gradient = vec![-5.0, 0.1, 5.0];
+        matrix.clip_by_value(&mut gradient, -1.0, 1.0).unwrap();
+        assert_eq!(gradient, vec![-1.0, 0.1, 1.0]);
+    }
+
+    #[test]
+    fn test_cosine_similarity_single_pass() {
+        let matrix = Matrix::new();
+        // Length not a multiple of the lane width exercises the tail loop
+        let a: Vec<f32> = (0..37).map(|i| (i as f32 * 0.3).sin()).collect();
+        let b: Vec<f32> = (0..37).map(|i| (i as f32 * 0.7).cos()).collect();
+
+        let dot: f32 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+        let a_norm: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let b_norm: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let expected = (dot / (a_norm * b_norm)) as f64;
+
+        let fast = matrix.cosine_similarity(&a, &b).unwrap().similarity.unwrap();
+        let accurate = Matrix::with_summation(SummationMode::Pairwise).cosine_similarity(&a, &b).unwrap().similarity.unwrap();
+        assert!((fast - expected).abs() < 1e-5);
+        assert!((accurate - expected).abs() < 1e-5);
+
+        assert_eq!(matrix.cosine_similarity(&[0.0; 9], &a[..9]).unwrap().similarity.unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_blocked_transpose() {
+        let matrix = Matrix::new();
+        // Non-square and larger than one tile in both directions
+        let (rows, cols) = (45, 70);
+        let input: Vec<f32> = (0..rows * cols).map(|i| i as f32).collect();
+        let mut output = vec![0.0; rows * cols];
+        matrix.transpose(&input, &mut output, rows, cols).unwrap();
+        for i in 0..rows {
+            for j in 0..cols {
+                assert_eq!(output[j * rows + i], input[i * cols + j]);
+            }
+        }
+
+        let size = 67;
+        let original: Vec<f32> = (0..size * size).map(|i| i as f32).collect();
+        let mut square = original.clone();
+        matrix.transpose_in_place(&mut square, size).unwrap();
+        for i in 0..size {
+            for j in 0..size {
+                assert_eq!(square[j * size + i], original[i * size + j]);
+            }
+        }
+
+        assert!(matrix.transpose_in_place(&mut [0.0; 6], 2).is_err());
+    }
+
+    #[test]
+    fn test_multiply_with_layout() {
+        let matrix = Matrix::new();
+        // a = [[1, 2, 3], [4, 5, 6]], b = [[7, 8], [9, 10], [11, 12]]
+        let a_row = vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        let a_col = vec![1.0, 4.0, 2.0, 5.0, 3.0, 6.0];
+        let b_row = vec![7.0, 8.0, 9.0, 10.0, 11.0, 12.0];
+        let b_col = vec![7.0, 9.0, 11.0, 8.0, 10.0, 12.0];
+        let expected = vec![58.0, 64.0, 139.0, 154.0];
+
+        for (a, a_layout) in [(&a_row, MultiplyLayout::RowMajor), (&a_col, MultiplyLayout::ColumnMajor)] {
+            for (b, b_layout) in [(&b_row, MultiplyLayout::RowMajor), (&b_col, MultiplyLayout::ColumnMajor)] {
+                let mut result = vec![0.0; 4];
+                matrix.multiply_with_layout(a, a_layout, b, b_layout, &mut result, 2, 3, 2).unwrap();
+                assert_eq!(result, expected);
+            }
+        }
+
+        assert_eq!(MultiplyLayout::ColumnMajor.index(1, 2, 2, 3), 5);
+    }
+
+    #[test]
+    fn test_multiply_view() {
+        let matrix = Matrix::new();
+        // 3x4 buffer; multiply its top-left 2x2 block by its bottom-right 2x2 block
+        let buffer = vec![
+            1.0, 2.0, 0.0, 0.0,
+            3.0, 4.0, 5.0, 6.0,
+            0.0, 0.0, 7.0, 8.0,
+        ];
+        let view = MatrixView::new(&buffer, 3, 4).unwrap();
+        let a = view.block(0, 0, 2, 2).unwrap();
+        let b = view.block(1, 2, 2, 2).unwrap();
+
+        let mut out = vec![0.0; 6];
+        let mut result = MatrixViewMut::with_stride(&mut out, 2, 2, 3).unwrap();
+        matrix.multiply_view(&a, &b, &mut result).unwrap();
+        assert_eq!(out, vec![19.0, 22.0, 0.0, 43.0, 50.0, 0.0]);
+    }
+
+    #[test]
+    fn test_profile_report() {
+        let mut matrix = Matrix::new();
+        let a = vec![1.0; 16];
+        let mut out = vec![0.0; 16];
+        matrix.multiply(&a, &a, &mut out, 4, 4, 4).unwrap();
+        assert!(matrix.profile_report().ops.is_empty());
+
+        matrix.set_profiling(true);
+        for _ in 0..3 {
+            matrix.multiply(&a, &a, &mut out, 4, 4, 4).unwrap();
+        }
+        matrix.relu(&a, &mut out).unwrap();
+        // Ops built on others are recorded under their own name only
+        matrix.conv1d(&a, &[1.0, 0.0], 1, 0).unwrap();
+        matrix.argmax_rows(&a, 4, 4).unwrap();
+        // Rejected calls are not recorded
+        assert!(matrix.vector_add(&a, &a[..2], &mut out).is_err());
+
+        let report = matrix.profile_report();
+        let multiply = &report.ops["multiply"];
+        assert_eq!((multiply.calls, multiply.elements, multiply.flops), (3, 144, 384.0));
+        assert!(multiply.mean() <= multiply.max && multiply.max <= multiply.total);
+        assert_eq!(report.ops["activation"].calls, 1);
+        assert_eq!((report.ops["conv1d"].calls, report.ops["argmax_rows"].calls), (1, 1));
+        for op in ["vector_add", "correlate1d", "argmax"] {
+            assert!(!report.ops.contains_key(op), "{}", op);
+        }
+        assert_eq!(report.to_string().lines().count(), 5);
+
+        matrix.reset_profile();
+        assert!(matrix.is_profiling() && matrix.profile_report().ops.is_empty());
+    }
+
     #[test]
     fn test_determinant_2x2() {
         let matrix = Matrix::new();
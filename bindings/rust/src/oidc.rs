@@ -0,0 +1,584 @@
+/*!
+# UMICP OpenID Connect
+
+An [`OidcValidator`] authenticates peers with tokens from an existing identity provider, such
+as Keycloak, Auth0, Okta, Entra ID or Google. It plugs into the JWT handshake like any other
+[`TokenValidator`].
+
+[`OidcValidator::builder`] takes the provider's issuer URL. [`discover`](OidcValidatorBuilder::discover)
+then does the following:
+
+1. Fetches `/.well-known/openid-configuration` below that URL.
+2. Checks that the document names the same issuer.
+3. Loads the signing keys from its `jwks_uri`.
+
+A token is accepted when all of the following hold:
+
+- It is signed by one of those keys with RS256/384/512, PS256/384/512, ES256/384 or EdDSA.
+  Symmetric and `none` algorithms are always rejected.
+- Its `iss` is the issuer.
+- Its `aud` contains the configured audience.
+- Its `exp` and `nbf` hold, allowing the configured leeway for clock skew.
+
+Validation happens during the handshake and never waits on the network. Instead, the keys are
+refreshed in the background on the refresh interval (an hour by default). A token naming an
+unknown `kid` is rejected, but it also triggers an early refresh, at most once a minute. That
+way a peer whose provider has just rotated its keys succeeds on its next attempt.
+
+```rust,no_run
+use std::time::Duration;
+use umicp_core::oidc::OidcValidator;
+use umicp_core::WebSocketTransport;
+
+# async fn run(server: WebSocketTransport) -> umicp_core::Result<()> {
+let validator = OidcValidator::builder("https://login.example.com/realms/umicp")
+    .audience("umicp-coordinator")
+    .leeway(Duration::from_secs(30))
+    .discover()
+    .await?;
+server.set_token_validator(validator);
+# Ok(())
+# }
+```
+*/
+
+use crate::auth::{ClaimRules, DecodedJwt, Principal, TokenValidator};
+use crate::error::{Result, UmicpError};
+use crate::transport::{http, tls};
+use crate::types::TransportConfig;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey, VerificationAlgorithm};
+use serde_json::Value;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader};
+use tokio::sync::Notify;
+
+/// Upper bound on a discovery document or key set
+const MAX_DOCUMENT: usize = 1024 * 1024;
+
+/// Time allowed for one fetch, from connecting to the end of the response
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Minimum time between refreshes triggered by unknown key IDs
+const MIN_DEMAND_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Builder for an [`OidcValidator`]
+#[derive(Debug, Clone)]
+pub struct OidcValidatorBuilder {
+    issuer: String,
+    rules: ClaimRules,
+    jwks_uri: Option<String>,
+    refresh_interval: Duration,
+    tls_ca_path: Option<String>,
+}
+
+impl OidcValidatorBuilder {
+    /// Require the `aud` claim to be, or to contain, `audience`
+    pub fn audience(mut self, audience: &str) -> Self {
+        self.rules.audience = Some(audience.to_string());
+        self
+    }
+
+    /// Tolerated clock skew when checking `exp` and `nbf`
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.rules.leeway = leeway;
+        self
+    }
+
+    /// Whether tokens without an `exp` claim are rejected
+    pub fn require_expiry(mut self, required: bool) -> Self {
+        self.rules.require_expiry = required;
+        self
+    }
+
+    /// How often the signing keys are fetched again
+    pub fn refresh_interval(mut self, interval: Duration) -> Self {
+        self.refresh_interval = interval;
+        self
+    }
+
+    /// Take the keys from `uri` rather than from the discovery document
+    pub fn jwks_uri(mut self, uri: &str) -> Self {
+        self.jwks_uri = Some(uri.to_string());
+        self
+    }
+
+    /// Check the provider's certificates against this CA bundle instead of the webpki roots
+    pub fn tls_ca_path(mut self, path: &str) -> Self {
+        self.tls_ca_path = Some(path.to_string());
+        self
+    }
+
+    /// Fetch the discovery document and signing keys, and start refreshing the keys
+    ///
+    /// Must be called within a Tokio runtime. The refresh task ends once the validator and
+    /// all of its clones are dropped.
+    pub async fn discover(self) -> Result<OidcValidator> {
+        let config = TransportConfig {
+            tls_ca_path: self.tls_ca_path,
+            ..Default::default()
+        };
+        let fetcher = Fetcher {
+            tls: Arc::new(tls::client_config(&config)?),
+        };
+        let jwks_uri = match self.jwks_uri {
+            Some(uri) => uri,
+            None => {
+                let url = format!("{}/.well-known/openid-configuration", self.issuer.trim_end_matches('/'));
+                let document = fetcher.get_json(&url).await?;
+                if document.get("issuer").and_then(Value::as_str) != Some(self.issuer.as_str()) {
+                    return Err(UmicpError::configuration(format!(
+                        "Discovery document at {} is for a different issuer",
+                        url
+                    )));
+                }
+                document
+                    .get("jwks_uri")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| UmicpError::configuration(format!("Discovery document at {} has no jwks_uri", url)))?
+                    .to_string()
+            }
+        };
+
+        let mut rules = self.rules;
+        rules.issuer = Some(self.issuer);
+        let wake = Arc::new(Notify::new());
+        let shared = Arc::new(Shared {
+            rules,
+            jwks_uri,
+            fetcher,
+            keys: RwLock::new(Vec::new()),
+            wake: wake.clone(),
+        });
+        shared.refresh().await?;
+        tokio::spawn(refresh_keys(Arc::downgrade(&shared), wake, self.refresh_interval));
+        Ok(OidcValidator { shared })
+    }
+}
+
+/// Validator for JWTs issued by an OpenID Connect provider
+///
+/// Clones share the keys and their refresh task.
+#[derive(Clone)]
+pub struct OidcValidator {
+    shared: Arc<Shared>,
+}
+
+impl OidcValidator {
+    /// Start configuring a validator for tokens from `issuer`
+    pub fn builder(issuer: &str) -> OidcValidatorBuilder {
+        OidcValidatorBuilder {
+            issuer: issuer.to_string(),
+            rules: ClaimRules::default(),
+            jwks_uri: None,
+            refresh_interval: Duration::from_secs(3600),
+            tls_ca_path: None,
+        }
+    }
+
+    /// The issuer tokens must come from
+    pub fn issuer(&self) -> &str {
+        self.shared.rules.issuer.as_deref().unwrap_or_default()
+    }
+
+    /// Where the signing keys are fetched from
+    pub fn jwks_uri(&self) -> &str {
+        &self.shared.jwks_uri
+    }
+
+    /// IDs of the signing keys currently held
+    pub fn key_ids(&self) -> Vec<String> {
+        let keys = self.shared.keys.read().unwrap();
+        keys.iter().filter_map(|key| key.kid.clone()).collect()
+    }
+
+    /// Fetch the signing keys now; on failure the keys held so far stay in use
+    pub async fn refresh(&self) -> Result<()> {
+        self.shared.refresh().await
+    }
+}
+
+impl std::fmt::Debug for OidcValidator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OidcValidator")
+            .field("issuer", &self.issuer())
+            .field("jwks_uri", &self.shared.jwks_uri)
+            .field("keys", &self.key_ids())
+            .finish()
+    }
+}
+
+impl TokenValidator for OidcValidator {
+    fn validate(&self, token: &str) -> Result<Principal> {
+        let jwt = DecodedJwt::decode(token)?;
+        let algorithm = jwt.algorithm();
+        let kid = jwt.header.get("kid").and_then(Value::as_str);
+        {
+            let keys = self.shared.keys.read().unwrap();
+            let candidates: Vec<_> = keys
+                .iter()
+                .filter(|key| kid.is_none() || key.kid.as_deref() == kid)
+                .filter(|key| key.alg.as_deref().is_none_or(|alg| alg == algorithm))
+                .filter_map(|key| key.verifier(algorithm))
+                .collect();
+            if candidates.is_empty() {
+                let known = kid.is_none_or(|kid| keys.iter().any(|key| key.kid.as_deref() == Some(kid)));
+                if !known {
+                    self.shared.wake.notify_one();
+                    return Err(UmicpError::authentication(format!(
+                        "Unknown JWT signing key {}",
+                        kid.unwrap_or_default()
+                    )));
+                }
+                return Err(UmicpError::authentication(format!(
+                    "Unsupported JWT algorithm {:?} for the issuer's keys",
+                    algorithm
+                )));
+            }
+            let message = jwt.signing_input.as_bytes();
+            if !candidates.iter().any(|verify| verify(message, &jwt.signature)) {
+                return Err(UmicpError::authentication("Invalid JWT signature"));
+            }
+        }
+        self.shared.rules.check(&jwt.claims)?;
+        jwt.into_principal()
+    }
+}
+
+struct Shared {
+    rules: ClaimRules,
+    jwks_uri: String,
+    fetcher: Fetcher,
+    keys: RwLock<Vec<SigningKey>>,
+    /// Wakes the refresh task early; also signalled when the validator goes away
+    wake: Arc<Notify>,
+}
+
+impl Shared {
+    async fn refresh(&self) -> Result<()> {
+        let set = self.fetcher.get_json(&self.jwks_uri).await?;
+        let keys: Vec<_> = set
+            .get("keys")
+            .and_then(Value::as_array)
+            .ok_or_else(|| UmicpError::serialization(format!("No key set at {}", self.jwks_uri)))?
+            .iter()
+            .filter_map(SigningKey::from_jwk)
+            .collect();
+        if keys.is_empty() {
+            return Err(UmicpError::configuration(format!(
+                "Key set at {} has no usable signing keys",
+                self.jwks_uri
+            )));
+        }
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.wake.notify_one();
+    }
+}
+
+/// Refetch the keys every `interval`, and when a validation asks for it
+async fn refresh_keys(shared: Weak<Shared>, wake: Arc<Notify>, interval: Duration) {
+    let mut last_demanded: Option<Instant> = None;
+    loop {
+        let demanded = tokio::select! {
+            _ = tokio::time::sleep(interval) => false,
+            _ = wake.notified() => true,
+        };
+        if shared.strong_count() == 0 {
+            return;
+        }
+        if demanded {
+            // Tokens with made-up key IDs must not turn into a stream of fetches
+            if let Some(at) = last_demanded {
+                tokio::time::sleep(MIN_DEMAND_INTERVAL.saturating_sub(at.elapsed())).await;
+            }
+            last_demanded = Some(Instant::now());
+        }
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        // A failed refresh keeps the current keys; the next one tries again
+        let _ = shared.refresh().await;
+    }
+}
+
+/// Public key material of a JWK
+enum KeyMaterial {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    /// Uncompressed point of a P-256 or P-384 key
+    Ec { curve: String, point: Vec<u8> },
+    Ed25519(Vec<u8>),
+}
+
+/// A signing key from the provider's key set
+struct SigningKey {
+    kid: Option<String>,
+    alg: Option<String>,
+    material: KeyMaterial,
+}
+
+type Verify<'a> = Box<dyn Fn(&[u8], &[u8]) -> bool + 'a>;
+
+impl SigningKey {
+    /// Parse a JWK, skipping encryption keys and key types that are not supported
+    fn from_jwk(jwk: &Value) -> Option<Self> {
+        let field = |name: &str| jwk.get(name).and_then(Value::as_str);
+        let bytes = |name: &str| base64::decode_config(field(name)?, base64::URL_SAFE_NO_PAD).ok();
+        if field("use").is_some_and(|usage| usage != "sig") {
+            return None;
+        }
+        let material = match (field("kty")?, field("crv")) {
+            ("RSA", _) => KeyMaterial::Rsa {
+                n: bytes("n")?,
+                e: bytes("e")?,
+            },
+            ("EC", Some(curve @ ("P-256" | "P-384"))) => {
+                let mut point = vec![0x04];
+                point.extend(bytes("x")?);
+                point.extend(bytes("y")?);
+                KeyMaterial::Ec {
+                    curve: curve.to_string(),
+                    point,
+                }
+            }
+            ("OKP", Some("Ed25519")) => KeyMaterial::Ed25519(bytes("x")?),
+            _ => return None,
+        };
+        Some(SigningKey {
+            kid: field("kid").map(str::to_string),
+            alg: field("alg").map(str::to_string),
+            material,
+        })
+    }
+
+    /// Signature check for `algorithm` with this key, if the two go together
+    fn verifier(&self, algorithm: &str) -> Option<Verify<'_>> {
+        match &self.material {
+            KeyMaterial::Rsa { n, e } => {
+                let parameters: &'static signature::RsaParameters = match algorithm {
+                    "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+                    "RS384" => &signature::RSA_PKCS1_2048_8192_SHA384,
+                    "RS512" => &signature::RSA_PKCS1_2048_8192_SHA512,
+                    "PS256" => &signature::RSA_PSS_2048_8192_SHA256,
+                    "PS384" => &signature::RSA_PSS_2048_8192_SHA384,
+                    "PS512" => &signature::RSA_PSS_2048_8192_SHA512,
+                    _ => return None,
+                };
+                let key = RsaPublicKeyComponents {
+                    n: n.as_slice(),
+                    e: e.as_slice(),
+                };
+                Some(Box::new(move |message, signature| {
+                    key.verify(parameters, message, signature).is_ok()
+                }))
+            }
+            KeyMaterial::Ec { curve, point } => {
+                let algorithm: &'static dyn VerificationAlgorithm = match (algorithm, curve.as_str()) {
+                    ("ES256", "P-256") => &signature::ECDSA_P256_SHA256_FIXED,
+                    ("ES384", "P-384") => &signature::ECDSA_P384_SHA384_FIXED,
+                    _ => return None,
+                };
+                Some(verify_with(algorithm, point))
+            }
+            KeyMaterial::Ed25519(key) => match algorithm {
+                "EdDSA" => Some(verify_with(&signature::ED25519, key)),
+                _ => None,
+            },
+        }
+    }
+}
+
+fn verify_with<'a>(algorithm: &'static dyn VerificationAlgorithm, key: &'a [u8]) -> Verify<'a> {
+    Box::new(move |message, signature| UnparsedPublicKey::new(algorithm, key).verify(message, signature).is_ok())
+}
+
+/// Fetches JSON documents over HTTP(S)
+struct Fetcher {
+    tls: Arc<rustls::ClientConfig>,
+}
+
+impl Fetcher {
+    async fn get_json(&self, url: &str) -> Result<Value> {
+        let body = tokio::time::timeout(FETCH_TIMEOUT, self.get(url))
+            .await
+            .map_err(|_| UmicpError::timeout(format!("Fetching {} timed out", url)))??;
+        serde_json::from_slice(&body)
+            .map_err(|e| UmicpError::serialization(format!("Invalid JSON from {}: {}", url, e)))
+    }
+
+    async fn get(&self, url: &str) -> Result<Vec<u8>> {
+        let invalid = |reason: &str| UmicpError::configuration(format!("Invalid URL {}: {}", url, reason));
+        let (secure, rest) = match (url.strip_prefix("https://"), url.strip_prefix("http://")) {
+            (Some(rest), _) => (true, rest),
+            (None, Some(rest)) => (false, rest),
+            _ => return Err(invalid("expected an http:// or https:// URL")),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        // An IPv6 host is bracketed
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, rest) = bracketed.split_once(']').ok_or_else(|| invalid("unclosed bracket"))?;
+                (host, rest.strip_prefix(':'))
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        let address = match port {
+            Some(_) => authority.to_string(),
+            None => format!("{}:{}", authority, if secure { 443 } else { 80 }),
+        };
+
+        let tcp = http::connect(&address, FETCH_TIMEOUT).await?;
+        match secure {
+            true => {
+                let stream = tokio_rustls::TlsConnector::from(self.tls.clone())
+                    .connect(tls::server_name(host)?, tcp)
+                    .await
+                    .map_err(|e| UmicpError::connection(format!("TLS handshake with {} failed: {}", host, e)))?;
+                exchange(stream, authority, path, url).await
+            }
+            false => exchange(tcp, authority, path, url).await,
+        }
+    }
+}
+
+/// Send a GET for `path` and read the whole response body
+async fn exchange<S>(mut stream: S, authority: &str, path: &str, url: &str) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // HTTP/1.0 keeps servers from answering with a chunked body
+    let start_line = format!("GET {} HTTP/1.0", path);
+    let headers = [("Host", authority), ("Accept", "application/json"), ("Connection", "close")];
+    http::write_message(&mut stream, &start_line, &headers, b"").await?;
+
+    let mut reader = BufReader::new(stream);
+    let head = http::read_head(&mut reader)
+        .await?
+        .ok_or_else(|| UmicpError::connection(format!("{} closed the connection", authority)))?;
+    if head.status() != Some(200) {
+        return Err(UmicpError::connection(format!("Fetching {} failed: {}", url, head.start_line)));
+    }
+    if head.header("content-length").is_some() || head.header("transfer-encoding").is_some() {
+        return http::read_body(&mut reader, &head, MAX_DOCUMENT).await;
+    }
+    let mut body = Vec::new();
+    reader.take(MAX_DOCUMENT as u64 + 1).read_to_end(&mut body).await?;
+    if body.len() > MAX_DOCUMENT {
+        return Err(UmicpError::validation(format!(
+            "Response from {} exceeds the {} byte limit",
+            url, MAX_DOCUMENT
+        )));
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair};
+    use serde_json::json;
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+
+    fn segment(value: &Value) -> String {
+        base64::encode_config(serde_json::to_vec(value).unwrap(), base64::URL_SAFE_NO_PAD)
+    }
+
+    fn token(header: Value, claims: Value, sign: impl Fn(&[u8]) -> Vec<u8>) -> String {
+        let signing_input = format!("{}.{}", segment(&header), segment(&claims));
+        let signature = sign(signing_input.as_bytes());
+        format!("{}.{}", signing_input, base64::encode_config(signature, base64::URL_SAFE_NO_PAD))
+    }
+
+    /// An identity provider serving its discovery document and the key set in `keys`
+    async fn provider(keys: Arc<Mutex<Value>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let discovery = json!({ "issuer": issuer, "jwks_uri": format!("{}/jwks", issuer) });
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                let head = http::read_head(&mut stream).await.unwrap().unwrap();
+                let (status, body) = match head.path() {
+                    "/.well-known/openid-configuration" => (200, discovery.to_string()),
+                    "/jwks" => (200, keys.lock().unwrap().to_string()),
+                    _ => (404, String::new()),
+                };
+                let status_line = http::status_line(status);
+                http::write_message(stream.get_mut(), &status_line, &[], body.as_bytes()).await.unwrap();
+            }
+        });
+        issuer
+    }
+
+    #[tokio::test]
+    async fn test_oidc_validator_discovers_and_rotates_keys() {
+        let rng = SystemRandom::new();
+        let ed25519 = Ed25519KeyPair::from_pkcs8(Ed25519KeyPair::generate_pkcs8(&rng).unwrap().as_ref()).unwrap();
+        let x = base64::encode_config(ed25519.public_key(), base64::URL_SAFE_NO_PAD);
+        let keys = Arc::new(Mutex::new(json!({ "keys": [
+            { "kty": "OKP", "crv": "Ed25519", "kid": "k1", "use": "sig", "x": x },
+            { "kty": "OKP", "crv": "Ed25519", "kid": "enc", "use": "enc", "x": x },
+        ]})));
+        let issuer = provider(keys.clone()).await;
+
+        let validator = OidcValidator::builder(&issuer).audience("umicp").discover().await.unwrap();
+        assert_eq!(validator.jwks_uri(), format!("{}/jwks", issuer));
+        assert_eq!(validator.key_ids(), vec!["k1"]);
+        let claims = |aud: &str, exp: u64| json!({ "sub": "worker-7", "iss": issuer, "aud": [aud], "exp": exp });
+        let eddsa = |kid: &str, claims: Value| {
+            token(json!({ "alg": "EdDSA", "kid": kid }), claims, |input| ed25519.sign(input).as_ref().to_vec())
+        };
+
+        let principal = validator.validate(&eddsa("k1", claims("umicp", 4102444800))).unwrap();
+        assert_eq!(principal.subject, "worker-7");
+        assert!(validator.validate(&eddsa("k1", claims("other", 4102444800))).is_err());
+        assert!(validator.validate(&eddsa("k1", claims("umicp", 1000))).is_err());
+        let forged = token(json!({ "alg": "HS256", "kid": "k1" }), claims("umicp", 4102444800), |_| vec![0; 32]);
+        assert!(validator.validate(&forged).unwrap_err().to_string().contains("Unsupported JWT algorithm"));
+
+        // The provider rotates to a P-256 key; the first token naming it triggers a refresh
+        let alg = &signature::ECDSA_P256_SHA256_FIXED_SIGNING;
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &rng).unwrap();
+        let p256 = EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref(), &rng).unwrap();
+        let point = p256.public_key().as_ref();
+        let coordinate = |bytes: &[u8]| base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+        keys.lock().unwrap()["keys"].as_array_mut().unwrap().push(json!({
+            "kty": "EC", "crv": "P-256", "kid": "k2", "alg": "ES256",
+            "x": coordinate(&point[1..33]), "y": coordinate(&point[33..]),
+        }));
+        let es256 = token(json!({ "alg": "ES256", "kid": "k2" }), claims("umicp", 4102444800), |input| {
+            p256.sign(&rng, input).unwrap().as_ref().to_vec()
+        });
+        assert!(validator.validate(&es256).unwrap_err().to_string().contains("Unknown JWT signing key k2"));
+        let mut accepted = false;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            if validator.validate(&es256).is_ok() {
+                accepted = true;
+                break;
+            }
+        }
+        assert!(accepted);
+        assert_eq!(validator.key_ids(), vec!["k1", "k2"]);
+
+        // An EdDSA token cannot be passed off under the ES256 key
+        assert!(validator.validate(&eddsa("k2", claims("umicp", 4102444800))).is_err());
+    }
+}
@@ -0,0 +1,352 @@
+/*!
+# UMICP Personalized Aggregation
+
+Federated averaging over named tensors, where only some parameter groups are shared and the rest
+stay on each client, such as the personalization layers that adapt a shared model to one client's
+data.
+
+An [`AggregationPlan`] gives each tensor a [`ParameterScope`] by the longest name prefix it was
+configured with, every tensor being global unless the plan says otherwise. Clients upload only
+their global tensors ([`AggregationPlan::shared`]) as a [`TensorUpdate`], and install the
+aggregate over their own tensors with [`AggregationPlan::merge`], which leaves local tensors
+untouched. A [`TensorFedAvg`] averages each global tensor separately, weighted by the clients'
+sample counts as [`FedAvg`](crate::federated::FedAvg) does, and drops any local tensor an update
+carries rather than aggregating it.
+
+On the wire a tensor update is a `Data` envelope whose payload holds every tensor's elements as
+little-endian `f32`, one tensor after another in name order, with the names and shapes as JSON in
+the [`TENSOR_LAYOUT_CAPABILITY`] and the sample count in the
+[`SAMPLE_COUNT_CAPABILITY`](crate::federated::SAMPLE_COUNT_CAPABILITY).
+
+```rust
+use std::collections::BTreeMap;
+use umicp_core::personalization::{AggregationPlan, TensorFedAvg, TensorUpdate};
+use umicp_core::Tensor;
+
+let plan = AggregationPlan::new().local("head.");
+let model = |encoder: f32, head: f32| -> umicp_core::Result<BTreeMap<String, Tensor>> {
+    Ok(BTreeMap::from([
+        ("encoder.weight".to_string(), Tensor::new(vec![2], vec![encoder; 2])?),
+        ("head.weight".to_string(), Tensor::new(vec![1], vec![head])?),
+    ]))
+};
+
+let mut fedavg = TensorFedAvg::new(plan.clone());
+for (client, encoder, samples) in [("worker-1", 1.0, 100), ("worker-2", 5.0, 300)] {
+    let shared = plan.shared(&model(encoder, 9.0)?);
+    let envelope = TensorUpdate::new(client, shared, samples).to_envelope("aggregator")?;
+    fedavg.add_envelope(&envelope)?;
+}
+
+// Each client keeps its own head
+let mut local = model(1.0, 7.0)?;
+plan.merge(&mut local, fedavg.aggregate()?);
+assert_eq!(local["encoder.weight"].data, vec![4.0, 4.0]);
+assert_eq!(local["head.weight"].data, vec![7.0]);
+# Ok::<(), umicp_core::UmicpError>(())
+```
+*/
+
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::federated::{self, sample_count, SAMPLE_COUNT_CAPABILITY};
+use crate::matrix::Matrix;
+use crate::types::{OperationType, Tensor};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Capability carrying the names and shapes of a tensor update's tensors as JSON
+pub const TENSOR_LAYOUT_CAPABILITY: &str = "tensor_layout";
+
+/// Whether a parameter group is averaged across clients or kept by each one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParameterScope {
+    /// Averaged across clients
+    #[default]
+    Global,
+    /// Kept on each client, never uploaded or averaged
+    Local,
+}
+
+/// Which tensors are aggregated globally and which stay local, by name prefix
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AggregationPlan {
+    /// Scope of the tensors whose names start with each prefix
+    #[serde(default)]
+    pub groups: BTreeMap<String, ParameterScope>,
+    /// Scope of tensors no prefix matches
+    #[serde(default)]
+    pub default: ParameterScope,
+}
+
+impl AggregationPlan {
+    /// Plan aggregating every tensor globally
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep tensors whose names start with `prefix` local
+    pub fn local(mut self, prefix: &str) -> Self {
+        self.groups.insert(prefix.to_string(), ParameterScope::Local);
+        self
+    }
+
+    /// Aggregate tensors whose names start with `prefix` globally, e.g. within a local group
+    pub fn global(mut self, prefix: &str) -> Self {
+        self.groups.insert(prefix.to_string(), ParameterScope::Global);
+        self
+    }
+
+    /// Scope of tensor `name`, from the longest prefix it starts with
+    pub fn scope(&self, name: &str) -> ParameterScope {
+        self.groups
+            .iter()
+            .filter(|(prefix, _)| name.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, scope)| *scope)
+    }
+
+    /// Whether tensor `name` is aggregated globally
+    pub fn is_global(&self, name: &str) -> bool {
+        self.scope(name) == ParameterScope::Global
+    }
+
+    /// The global tensors of `tensors`, the ones a client uploads
+    pub fn shared(&self, tensors: &BTreeMap<String, Tensor>) -> BTreeMap<String, Tensor> {
+        let global = tensors.iter().filter(|(name, _)| self.is_global(name));
+        global.map(|(name, tensor)| (name.clone(), tensor.clone())).collect()
+    }
+
+    /// Install the global tensors of `aggregate` into a client's `tensors`, keeping its local ones
+    pub fn merge(&self, tensors: &mut BTreeMap<String, Tensor>, aggregate: BTreeMap<String, Tensor>) {
+        tensors.extend(aggregate.into_iter().filter(|(name, _)| self.is_global(name)));
+    }
+}
+
+/// Named tensors a client trained, with the number of samples they were trained on
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorUpdate {
+    /// Client that trained the tensors
+    pub client: String,
+    /// Tensors by name
+    pub tensors: BTreeMap<String, Tensor>,
+    /// Samples the tensors were trained on
+    pub sample_count: u64,
+}
+
+impl TensorUpdate {
+    /// Update from `client`
+    pub fn new(client: &str, tensors: BTreeMap<String, Tensor>, sample_count: u64) -> Self {
+        TensorUpdate {
+            client: client.to_string(),
+            tensors,
+            sample_count,
+        }
+    }
+
+    /// Envelope carrying the update from its client to `to`
+    pub fn to_envelope(&self, to: &str) -> Result<Envelope> {
+        if self.sample_count == 0 {
+            return Err(UmicpError::validation("Sample count must be positive"));
+        }
+        let layout: Vec<(&str, &[usize])> = self
+            .tensors
+            .iter()
+            .map(|(name, tensor)| (name.as_str(), tensor.shape.as_slice()))
+            .collect();
+        let layout = serde_json::to_string(&layout)
+            .map_err(|e| UmicpError::serialization(format!("Failed to serialize tensor layout: {}", e)))?;
+        let elements: Vec<f32> = self.tensors.values().flat_map(|tensor| tensor.data.iter().copied()).collect();
+        Envelope::builder()
+            .from(&self.client)
+            .to(to)
+            .operation(OperationType::Data)
+            .capability(SAMPLE_COUNT_CAPABILITY, &self.sample_count.to_string())
+            .capability(TENSOR_LAYOUT_CAPABILITY, &layout)
+            .build()
+            .map(|envelope| federated::with_weights(envelope, &elements))
+    }
+
+    /// Update carried by `envelope`, from its sender
+    pub fn from_envelope(envelope: &Envelope) -> Result<Self> {
+        let invalid = |what: String| UmicpError::validation(format!("Tensor update from {} {}", envelope.from(), what));
+        let sample_count = sample_count(envelope)?;
+        let layout = envelope
+            .capabilities()
+            .and_then(|capabilities| capabilities.get(TENSOR_LAYOUT_CAPABILITY))
+            .ok_or_else(|| invalid("has no tensor layout".to_string()))?;
+        let layout: Vec<(String, Vec<usize>)> =
+            serde_json::from_str(layout).map_err(|e| invalid(format!("has an invalid tensor layout: {}", e)))?;
+        let elements = federated::weights(envelope)?;
+        let mut tensors = BTreeMap::new();
+        let mut offset = 0usize;
+        for (name, shape) in layout {
+            let end = shape
+                .iter()
+                .try_fold(1usize, |len, dimension| len.checked_mul(*dimension))
+                .and_then(|len| offset.checked_add(len))
+                .filter(|end| *end <= elements.len())
+                .ok_or_else(|| invalid(format!("is too short for tensor {}", name)))?;
+            let tensor = Tensor::new(shape, elements[offset..end].to_vec())?;
+            if tensors.insert(name.clone(), tensor).is_some() {
+                return Err(invalid(format!("repeats tensor {}", name)));
+            }
+            offset = end;
+        }
+        if offset != elements.len() {
+            return Err(invalid(format!("has {} elements beyond its tensors", elements.len() - offset)));
+        }
+        Ok(TensorUpdate::new(envelope.from(), tensors, sample_count))
+    }
+}
+
+/// Sample-weighted average of the global tensors of client updates
+#[derive(Debug, Default)]
+pub struct TensorFedAvg {
+    plan: AggregationPlan,
+    /// Latest update by client, its local tensors dropped
+    updates: BTreeMap<String, TensorUpdate>,
+    matrix: Matrix,
+}
+
+impl TensorFedAvg {
+    /// Aggregator following `plan`
+    pub fn new(plan: AggregationPlan) -> Self {
+        TensorFedAvg {
+            plan,
+            ..Default::default()
+        }
+    }
+
+    /// Plan the aggregator follows
+    pub fn plan(&self) -> &AggregationPlan {
+        &self.plan
+    }
+
+    /// Add the global tensors of `update`, replacing any earlier update from the same client
+    ///
+    /// Every update must hold the same global tensors, with the same shapes.
+    pub fn add(&mut self, mut update: TensorUpdate) -> Result<()> {
+        if update.sample_count == 0 {
+            return Err(UmicpError::validation(format!("Update from {} has no samples", update.client)));
+        }
+        update.tensors.retain(|name, _| self.plan.is_global(name));
+        if update.tensors.is_empty() {
+            return Err(UmicpError::validation(format!("Update from {} has no global tensors", update.client)));
+        }
+        if let Some(first) = self.updates.values().find(|first| first.client != update.client) {
+            let shapes = |update: &TensorUpdate| -> Vec<(String, Vec<usize>)> {
+                let tensors = update.tensors.iter();
+                tensors.map(|(name, tensor)| (name.clone(), tensor.shape.clone())).collect()
+            };
+            if shapes(first) != shapes(&update) {
+                return Err(UmicpError::validation(format!(
+                    "Update from {} has other global tensors than {}",
+                    update.client, first.client
+                )));
+            }
+        }
+        let tensors = update.tensors.values();
+        if tensors.flat_map(|tensor| &tensor.data).any(|value| !value.is_finite()) {
+            return Err(UmicpError::validation(format!("Update from {} has non-finite weights", update.client)));
+        }
+        self.updates.insert(update.client.clone(), update);
+        Ok(())
+    }
+
+    /// Add the update carried by `envelope`
+    pub fn add_envelope(&mut self, envelope: &Envelope) -> Result<()> {
+        self.add(TensorUpdate::from_envelope(envelope)?)
+    }
+
+    /// Clients whose updates are held
+    pub fn clients(&self) -> impl Iterator<Item = &str> {
+        self.updates.keys().map(String::as_str)
+    }
+
+    /// Number of updates held
+    pub fn len(&self) -> usize {
+        self.updates.len()
+    }
+
+    /// Whether no updates are held
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+
+    /// Samples declared across all updates
+    pub fn total_samples(&self) -> u64 {
+        self.updates.values().map(|update| update.sample_count).fold(0, u64::saturating_add)
+    }
+
+    /// Average of each global tensor, every update weighted by its share of the samples
+    pub fn aggregate(&self) -> Result<BTreeMap<String, Tensor>> {
+        let first = self
+            .updates
+            .values()
+            .next()
+            .ok_or_else(|| UmicpError::validation("No updates to aggregate"))?;
+        let total = self.total_samples() as f64;
+        let weights: Vec<f32> = self
+            .updates
+            .values()
+            .map(|update| (update.sample_count as f64 / total) as f32)
+            .collect();
+        let mut aggregate = BTreeMap::new();
+        for (name, tensor) in &first.tensors {
+            let tensors = self.updates.values().map(|update| update.tensors[name].data.as_slice());
+            let vectors: Vec<&[f32]> = tensors.collect();
+            let mut data = vec![0.0; tensor.len()];
+            self.matrix.weighted_average(&vectors, &weights, &mut data)?;
+            aggregate.insert(name.clone(), Tensor::new(tensor.shape.clone(), data)?);
+        }
+        Ok(aggregate)
+    }
+
+    /// Forget every update, to start the next round
+    pub fn clear(&mut self) {
+        self.updates.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tensors(entries: &[(&str, Vec<usize>, Vec<f32>)]) -> BTreeMap<String, Tensor> {
+        let tensor = |shape: &Vec<usize>, data: &Vec<f32>| Tensor::new(shape.clone(), data.clone()).unwrap();
+        entries.iter().map(|(name, shape, data)| (name.to_string(), tensor(shape, data))).collect()
+    }
+
+    #[test]
+    fn test_local_groups_are_not_aggregated() {
+        let plan = AggregationPlan::new().local("head.").global("head.shared.");
+        assert_eq!(plan.scope("head.bias"), ParameterScope::Local);
+        assert_eq!(plan.scope("head.shared.weight"), ParameterScope::Global);
+        assert_eq!(plan.scope("encoder.weight"), ParameterScope::Global);
+
+        let mut fedavg = TensorFedAvg::new(plan.clone());
+        let a = tensors(&[("encoder.weight", vec![2, 2], vec![4.0; 4]), ("head.bias", vec![1], vec![1.0])]);
+        let b = tensors(&[("encoder.weight", vec![2, 2], vec![0.0; 4]), ("head.bias", vec![1], vec![9.0])]);
+        // A client that uploads its local tensors anyway does not get them averaged
+        fedavg.add(TensorUpdate::new("a", a, 1)).unwrap();
+        let envelope = TensorUpdate::new("b", plan.shared(&b), 3).to_envelope("aggregator").unwrap();
+        fedavg.add_envelope(&Envelope::deserialize(&envelope.serialize().unwrap()).unwrap()).unwrap();
+
+        let aggregate = fedavg.aggregate().unwrap();
+        assert_eq!(aggregate.keys().collect::<Vec<_>>(), vec!["encoder.weight"]);
+        let encoder = &aggregate["encoder.weight"];
+        assert_eq!((&encoder.shape, &encoder.data), (&vec![2, 2], &vec![1.0; 4]));
+        let mut local = b.clone();
+        plan.merge(&mut local, aggregate);
+        assert_eq!((local["encoder.weight"].data[0], local["head.bias"].data[0]), (1.0, 9.0));
+
+        let reshaped = tensors(&[("encoder.weight", vec![4], vec![0.0; 4])]);
+        assert!(fedavg.add(TensorUpdate::new("c", reshaped, 1)).is_err());
+        let mut truncated = envelope.clone();
+        truncated.add_capability(TENSOR_LAYOUT_CAPABILITY, r#"[["encoder.weight",[2,3]]]"#);
+        assert!(TensorUpdate::from_envelope(&truncated).is_err());
+        assert_eq!(fedavg.len(), 2);
+    }
+}
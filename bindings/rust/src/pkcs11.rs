@@ -0,0 +1,450 @@
+/*!
+# UMICP PKCS#11 Signing
+
+A [`Signer`] backed by a PKCS#11 token, such as an HSM, a smart card, SoftHSM or a cloud KMS's
+PKCS#11 library, so envelope signatures are made without the private key ever entering this
+process.
+
+[`Pkcs11Signer::open`] loads the vendor's module (a shared library), finds the token by its
+label, logs in with the user PIN and looks up the Ed25519 private key by its `CKA_LABEL`. Signing
+uses the `CKM_EDDSA` mechanism, so the token has to support PKCS#11 3.0 EdDSA; the signatures are
+plain Ed25519 and verify with a [`KeyStore`](crate::keystore::KeyStore) holding the key's
+[`public_key`](Pkcs11Signer::public_key), like any other. Envelopes name the key by its label.
+
+```rust,no_run
+use umicp_core::keystore::{sign_envelope, KeyStore};
+use umicp_core::pkcs11::Pkcs11Signer;
+use umicp_core::{Envelope, Secret};
+
+# fn main() -> umicp_core::Result<()> {
+let pin = Secret::new(std::env::var("HSM_PIN").unwrap_or_default());
+let signer = Pkcs11Signer::open("/usr/lib/softhsm/libsofthsm2.so", "umicp", &pin, "coordinator-2024")?;
+
+let mut envelope = Envelope::new();
+sign_envelope(&signer, &mut envelope)?;
+
+let verifier = KeyStore::new();
+verifier.import_public_key(signer.label(), &signer.public_key()?)?;
+verifier.verify_envelope(&envelope)?;
+# Ok(())
+# }
+```
+
+One session is opened per signer and signatures are made one at a time on it.
+*/
+
+use crate::error::{Result, UmicpError};
+use crate::keystore::Signer;
+use crate::secret::Secret;
+use std::ffi::c_void;
+use std::os::raw::c_ulong;
+use std::path::Path;
+use std::sync::Mutex;
+
+type CkUlong = c_ulong;
+type CkRv = CkUlong;
+type CkSlotId = CkUlong;
+type CkSessionHandle = CkUlong;
+type CkObjectHandle = CkUlong;
+
+const CKR_OK: CkRv = 0;
+const CKR_USER_ALREADY_LOGGED_IN: CkRv = 0x100;
+const CKR_CRYPTOKI_ALREADY_INITIALIZED: CkRv = 0x191;
+const CKF_SERIAL_SESSION: CkUlong = 0x4;
+const CKU_USER: CkUlong = 1;
+const CKA_CLASS: CkUlong = 0x0;
+const CKA_LABEL: CkUlong = 0x3;
+const CKA_EC_POINT: CkUlong = 0x181;
+const CKO_PUBLIC_KEY: CkUlong = 2;
+const CKO_PRIVATE_KEY: CkUlong = 3;
+const CKM_EDDSA: CkUlong = 0x1057;
+
+/// Length of an Ed25519 public key, in bytes
+const ED25519_PUBLIC_KEY_LEN: usize = 32;
+
+// The structures are packed on Windows and naturally aligned elsewhere, per the standard's headers
+#[cfg_attr(windows, repr(C, packed(1)))]
+#[cfg_attr(not(windows), repr(C))]
+#[derive(Clone, Copy)]
+struct CkVersion {
+    major: u8,
+    minor: u8,
+}
+
+#[cfg_attr(windows, repr(C, packed(1)))]
+#[cfg_attr(not(windows), repr(C))]
+struct CkAttribute {
+    kind: CkUlong,
+    value: *mut c_void,
+    value_len: CkUlong,
+}
+
+#[cfg_attr(windows, repr(C, packed(1)))]
+#[cfg_attr(not(windows), repr(C))]
+struct CkMechanism {
+    mechanism: CkUlong,
+    parameter: *mut c_void,
+    parameter_len: CkUlong,
+}
+
+#[cfg_attr(windows, repr(C, packed(1)))]
+#[cfg_attr(not(windows), repr(C))]
+struct CkTokenInfo {
+    label: [u8; 32],
+    manufacturer_id: [u8; 32],
+    model: [u8; 16],
+    serial_number: [u8; 16],
+    flags: CkUlong,
+    counts: [CkUlong; 10],
+    hardware_version: CkVersion,
+    firmware_version: CkVersion,
+    utc_time: [u8; 16],
+}
+
+type Unused = Option<unsafe extern "C" fn()>;
+type OpenSession =
+    unsafe extern "C" fn(CkSlotId, CkUlong, *mut c_void, Option<unsafe extern "C" fn()>, *mut CkSessionHandle) -> CkRv;
+type GetAttributeValue = unsafe extern "C" fn(CkSessionHandle, CkObjectHandle, *mut CkAttribute, CkUlong) -> CkRv;
+type FindObjects = unsafe extern "C" fn(CkSessionHandle, *mut CkObjectHandle, CkUlong, *mut CkUlong) -> CkRv;
+type Sign = unsafe extern "C" fn(CkSessionHandle, *const u8, CkUlong, *mut u8, *mut CkUlong) -> CkRv;
+
+/// The start of `CK_FUNCTION_LIST`, up to `C_Sign`; later entries are never read
+#[cfg_attr(windows, repr(C, packed(1)))]
+#[cfg_attr(not(windows), repr(C))]
+struct CkFunctionList {
+    version: CkVersion,
+    initialize: Option<unsafe extern "C" fn(*mut c_void) -> CkRv>,
+    finalize: Option<unsafe extern "C" fn(*mut c_void) -> CkRv>,
+    get_info: Unused,
+    get_function_list: Unused,
+    get_slot_list: Option<unsafe extern "C" fn(u8, *mut CkSlotId, *mut CkUlong) -> CkRv>,
+    get_slot_info: Unused,
+    get_token_info: Option<unsafe extern "C" fn(CkSlotId, *mut CkTokenInfo) -> CkRv>,
+    get_mechanism_list: Unused,
+    get_mechanism_info: Unused,
+    init_token: Unused,
+    init_pin: Unused,
+    set_pin: Unused,
+    open_session: Option<OpenSession>,
+    close_session: Option<unsafe extern "C" fn(CkSessionHandle) -> CkRv>,
+    close_all_sessions: Unused,
+    get_session_info: Unused,
+    get_operation_state: Unused,
+    set_operation_state: Unused,
+    login: Option<unsafe extern "C" fn(CkSessionHandle, CkUlong, *const u8, CkUlong) -> CkRv>,
+    logout: Option<unsafe extern "C" fn(CkSessionHandle) -> CkRv>,
+    create_object: Unused,
+    copy_object: Unused,
+    destroy_object: Unused,
+    get_object_size: Unused,
+    get_attribute_value: Option<GetAttributeValue>,
+    set_attribute_value: Unused,
+    find_objects_init: Option<unsafe extern "C" fn(CkSessionHandle, *mut CkAttribute, CkUlong) -> CkRv>,
+    find_objects: Option<FindObjects>,
+    find_objects_final: Option<unsafe extern "C" fn(CkSessionHandle) -> CkRv>,
+    encrypt_init: Unused,
+    encrypt: Unused,
+    encrypt_update: Unused,
+    encrypt_final: Unused,
+    decrypt_init: Unused,
+    decrypt: Unused,
+    decrypt_update: Unused,
+    decrypt_final: Unused,
+    digest_init: Unused,
+    digest: Unused,
+    digest_update: Unused,
+    digest_key: Unused,
+    digest_final: Unused,
+    sign_init: Option<unsafe extern "C" fn(CkSessionHandle, *mut CkMechanism, CkObjectHandle) -> CkRv>,
+    sign: Option<Sign>,
+}
+
+/// The module's entry points this signer calls, copied out of its function list
+#[derive(Clone, Copy)]
+struct Functions {
+    finalize: unsafe extern "C" fn(*mut c_void) -> CkRv,
+    close_session: unsafe extern "C" fn(CkSessionHandle) -> CkRv,
+    logout: unsafe extern "C" fn(CkSessionHandle) -> CkRv,
+    get_attribute_value: GetAttributeValue,
+    find_objects_init: unsafe extern "C" fn(CkSessionHandle, *mut CkAttribute, CkUlong) -> CkRv,
+    find_objects: FindObjects,
+    find_objects_final: unsafe extern "C" fn(CkSessionHandle) -> CkRv,
+    sign_init: unsafe extern "C" fn(CkSessionHandle, *mut CkMechanism, CkObjectHandle) -> CkRv,
+    sign: Sign,
+}
+
+/// Signs with an Ed25519 key held by a PKCS#11 token
+pub struct Pkcs11Signer {
+    functions: Functions,
+    session: Mutex<CkSessionHandle>,
+    private_key: CkObjectHandle,
+    label: String,
+    /// Whether this signer initialized the module, and so finalizes it
+    finalize: bool,
+    // Declared last so the module is unloaded after the session is closed
+    _module: libloading::Library,
+}
+
+impl Pkcs11Signer {
+    /// Log in to the token labelled `token_label` in `module` with `pin`, and sign with the
+    /// private key labelled `key_label`
+    pub fn open(module: impl AsRef<Path>, token_label: &str, pin: &Secret<String>, key_label: &str) -> Result<Self> {
+        let path = module.as_ref();
+        // SAFETY: loading a PKCS#11 module runs its initializers, which is what the caller asks for
+        let library = unsafe { libloading::Library::new(path) }.map_err(|e| {
+            UmicpError::configuration(format!("Failed to load PKCS#11 module {}: {}", path.display(), e))
+        })?;
+        // SAFETY: `C_GetFunctionList` has this signature in every PKCS#11 module
+        let list = unsafe {
+            let get_function_list = library
+                .get::<unsafe extern "C" fn(*mut *const CkFunctionList) -> CkRv>(b"C_GetFunctionList\0")
+                .map_err(|e| UmicpError::configuration(format!("{} is not a PKCS#11 module: {}", path.display(), e)))?;
+            let mut list = std::ptr::null();
+            check("C_GetFunctionList", get_function_list(&mut list))?;
+            list.as_ref().ok_or_else(|| UmicpError::configuration("PKCS#11 module returned no function list"))?
+        };
+        let missing = |name: &str| UmicpError::configuration(format!("PKCS#11 module lacks {}", name));
+        let functions = Functions {
+            finalize: list.finalize.ok_or_else(|| missing("C_Finalize"))?,
+            close_session: list.close_session.ok_or_else(|| missing("C_CloseSession"))?,
+            logout: list.logout.ok_or_else(|| missing("C_Logout"))?,
+            get_attribute_value: list.get_attribute_value.ok_or_else(|| missing("C_GetAttributeValue"))?,
+            find_objects_init: list.find_objects_init.ok_or_else(|| missing("C_FindObjectsInit"))?,
+            find_objects: list.find_objects.ok_or_else(|| missing("C_FindObjects"))?,
+            find_objects_final: list.find_objects_final.ok_or_else(|| missing("C_FindObjectsFinal"))?,
+            sign_init: list.sign_init.ok_or_else(|| missing("C_SignInit"))?,
+            sign: list.sign.ok_or_else(|| missing("C_Sign"))?,
+        };
+        let initialize = list.initialize.ok_or_else(|| missing("C_Initialize"))?;
+        let get_slot_list = list.get_slot_list.ok_or_else(|| missing("C_GetSlotList"))?;
+        let get_token_info = list.get_token_info.ok_or_else(|| missing("C_GetTokenInfo"))?;
+        let open_session = list.open_session.ok_or_else(|| missing("C_OpenSession"))?;
+        let login = list.login.ok_or_else(|| missing("C_Login"))?;
+
+        // SAFETY: every call below follows the PKCS#11 calling conventions, with buffers of the
+        // lengths passed alongside them
+        unsafe {
+            // Another part of the process may already use the module
+            let finalize = match initialize(std::ptr::null_mut()) {
+                CKR_CRYPTOKI_ALREADY_INITIALIZED => false,
+                rv => check("C_Initialize", rv).map(|_| true)?,
+            };
+            let finalize_on_error = |error: UmicpError| {
+                if finalize {
+                    (functions.finalize)(std::ptr::null_mut());
+                }
+                error
+            };
+
+            let mut count: CkUlong = 0;
+            check("C_GetSlotList", get_slot_list(1, std::ptr::null_mut(), &mut count)).map_err(finalize_on_error)?;
+            let mut slots = vec![0; count as usize];
+            check("C_GetSlotList", get_slot_list(1, slots.as_mut_ptr(), &mut count)).map_err(finalize_on_error)?;
+            slots.truncate(count as usize);
+            let labelled = |slot: CkSlotId| {
+                let mut info = std::mem::MaybeUninit::<CkTokenInfo>::zeroed();
+                get_token_info(slot, info.as_mut_ptr()) == CKR_OK && padded(&info.assume_init().label) == token_label
+            };
+            let slot = slots.into_iter().find(|&slot| labelled(slot)).ok_or_else(|| {
+                finalize_on_error(UmicpError::configuration(format!("No PKCS#11 token labelled {}", token_label)))
+            })?;
+
+            let mut session = 0;
+            let opened = open_session(slot, CKF_SERIAL_SESSION, std::ptr::null_mut(), None, &mut session);
+            check("C_OpenSession", opened).map_err(finalize_on_error)?;
+            let pin = pin.expose();
+            match login(session, CKU_USER, pin.as_ptr(), pin.len() as CkUlong) {
+                CKR_OK | CKR_USER_ALREADY_LOGGED_IN => {}
+                rv => {
+                    (functions.close_session)(session);
+                    let error = UmicpError::authentication(format!("PKCS#11 login failed: {}", describe(rv)));
+                    return Err(finalize_on_error(error));
+                }
+            }
+
+            let mut signer = Pkcs11Signer {
+                functions,
+                session: Mutex::new(session),
+                private_key: 0,
+                label: key_label.to_string(),
+                finalize,
+                _module: library,
+            };
+            // From here on, dropping the signer logs out and cleans up
+            signer.private_key = signer.find(CKO_PRIVATE_KEY)?;
+            Ok(signer)
+        }
+    }
+
+    /// Label of the key, which is also the key ID envelopes name
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Raw Ed25519 public key, from the public key object with the same label
+    pub fn public_key(&self) -> Result<Vec<u8>> {
+        let public_key = self.find(CKO_PUBLIC_KEY)?;
+        let session = self.session.lock().unwrap();
+        // SAFETY: the first call reads the length, the second fills a buffer of that length
+        let point = unsafe {
+            let get_attribute_value = self.functions.get_attribute_value;
+            let mut attribute = CkAttribute {
+                kind: CKA_EC_POINT,
+                value: std::ptr::null_mut(),
+                value_len: 0,
+            };
+            check("C_GetAttributeValue", get_attribute_value(*session, public_key, &mut attribute, 1))?;
+            let mut point = vec![0u8; attribute.value_len as usize];
+            attribute.value = point.as_mut_ptr().cast();
+            check("C_GetAttributeValue", get_attribute_value(*session, public_key, &mut attribute, 1))?;
+            point.truncate(attribute.value_len as usize);
+            point
+        };
+        // Tokens return the point either raw or wrapped in a DER OCTET STRING
+        match point.as_slice() {
+            [0x04, 0x20, key @ ..] if key.len() == ED25519_PUBLIC_KEY_LEN => Ok(key.to_vec()),
+            key if key.len() == ED25519_PUBLIC_KEY_LEN => Ok(key.to_vec()),
+            _ => Err(UmicpError::validation(format!("Key {} is not an Ed25519 key", self.label))),
+        }
+    }
+
+    /// The one object of `class` labelled with the key's label
+    fn find(&self, class: CkUlong) -> Result<CkObjectHandle> {
+        let session = self.session.lock().unwrap();
+        let mut class = class;
+        let mut label = self.label.clone().into_bytes();
+        let mut template = [
+            CkAttribute {
+                kind: CKA_CLASS,
+                value: (&mut class as *mut CkUlong).cast(),
+                value_len: std::mem::size_of::<CkUlong>() as CkUlong,
+            },
+            CkAttribute {
+                kind: CKA_LABEL,
+                value: label.as_mut_ptr().cast(),
+                value_len: label.len() as CkUlong,
+            },
+        ];
+        let mut objects = [0; 2];
+        let mut count = 0;
+        // SAFETY: the template and output buffers outlive the search, which is always finished
+        unsafe {
+            check("C_FindObjectsInit", (self.functions.find_objects_init)(*session, template.as_mut_ptr(), 2))?;
+            let found = (self.functions.find_objects)(*session, objects.as_mut_ptr(), 2, &mut count);
+            (self.functions.find_objects_final)(*session);
+            check("C_FindObjects", found)?;
+        }
+        let kind = if class == CKO_PRIVATE_KEY { "private" } else { "public" };
+        match count {
+            1 => Ok(objects[0]),
+            0 => Err(UmicpError::configuration(format!("No {} key labelled {} on the token", kind, self.label))),
+            _ => Err(UmicpError::configuration(format!("Several {} keys labelled {}", kind, self.label))),
+        }
+    }
+}
+
+impl Signer for Pkcs11Signer {
+    fn key_id(&self) -> Result<String> {
+        Ok(self.label.clone())
+    }
+
+    fn sign_with(&self, key_id: &str, data: &[u8]) -> Result<Vec<u8>> {
+        if key_id != self.label {
+            return Err(UmicpError::configuration(format!("Unknown signing key: {}", key_id)));
+        }
+        let session = self.session.lock().unwrap();
+        let mut mechanism = CkMechanism {
+            mechanism: CKM_EDDSA,
+            parameter: std::ptr::null_mut(),
+            parameter_len: 0,
+        };
+        // SAFETY: the first `C_Sign` reads the signature length without ending the operation; the
+        // second fills a buffer of that length
+        unsafe {
+            check("C_SignInit", (self.functions.sign_init)(*session, &mut mechanism, self.private_key))?;
+            let mut len: CkUlong = 0;
+            let (sign, data_len) = (self.functions.sign, data.len() as CkUlong);
+            check("C_Sign", sign(*session, data.as_ptr(), data_len, std::ptr::null_mut(), &mut len))?;
+            let mut signature = vec![0u8; len as usize];
+            check("C_Sign", sign(*session, data.as_ptr(), data_len, signature.as_mut_ptr(), &mut len))?;
+            signature.truncate(len as usize);
+            Ok(signature)
+        }
+    }
+}
+
+impl Drop for Pkcs11Signer {
+    fn drop(&mut self) {
+        let session = *self.session.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // SAFETY: the session is open and nothing else uses it any more
+        unsafe {
+            (self.functions.logout)(session);
+            (self.functions.close_session)(session);
+            if self.finalize {
+                (self.functions.finalize)(std::ptr::null_mut());
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Pkcs11Signer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pkcs11Signer").field("label", &self.label).finish()
+    }
+}
+
+// SAFETY: the handles are plain integers and the session is only used under its lock; PKCS#11
+// modules initialized without locking arguments are safe to call from any thread
+unsafe impl Send for Pkcs11Signer {}
+unsafe impl Sync for Pkcs11Signer {}
+
+fn check(function: &str, rv: CkRv) -> Result<()> {
+    match rv {
+        CKR_OK => Ok(()),
+        rv => Err(UmicpError::generic(format!("PKCS#11 {} failed: {}", function, describe(rv)))),
+    }
+}
+
+/// Name of a PKCS#11 return value
+fn describe(rv: CkRv) -> String {
+    let name = match rv {
+        0x3 => "CKR_SLOT_ID_INVALID",
+        0x5 => "CKR_GENERAL_ERROR",
+        0x6 => "CKR_FUNCTION_FAILED",
+        0x30 => "CKR_DEVICE_ERROR",
+        0x32 => "CKR_DEVICE_REMOVED",
+        0x63 => "CKR_KEY_TYPE_INCONSISTENT",
+        0x68 => "CKR_KEY_FUNCTION_NOT_PERMITTED",
+        0x70 => "CKR_MECHANISM_INVALID",
+        0xA0 => "CKR_PIN_INCORRECT",
+        0xA4 => "CKR_PIN_LOCKED",
+        0xB3 => "CKR_SESSION_HANDLE_INVALID",
+        0xE0 => "CKR_TOKEN_NOT_PRESENT",
+        0x101 => "CKR_USER_NOT_LOGGED_IN",
+        0x190 => "CKR_CRYPTOKI_NOT_INITIALIZED",
+        _ => return format!("CKR {:#x}", rv),
+    };
+    name.to_string()
+}
+
+/// A fixed-width PKCS#11 text field without its space padding
+fn padded(field: &[u8]) -> &str {
+    std::str::from_utf8(field).unwrap_or_default().trim_end_matches(' ')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pkcs11_signer_reports_unusable_modules() {
+        let pin = Secret::new("1234".to_string());
+        let error = Pkcs11Signer::open("/nonexistent/libpkcs11.so", "umicp", &pin, "key").unwrap_err();
+        assert!(matches!(error, UmicpError::Configuration { .. }), "{}", error);
+        assert!(!error.to_string().contains("1234"));
+
+        assert_eq!(padded(b"umicp   "), "umicp");
+        assert_eq!(describe(0xA0), "CKR_PIN_INCORRECT");
+        assert_eq!(describe(0x1234), "CKR 0x1234");
+    }
+}
@@ -0,0 +1,400 @@
+/*!
+# UMICP Access Policies
+
+Role-based access rules for a server, kept in configuration rather than spread through handler
+code. A [`Policy`] is an ordered list of [`Rule`]s: the first rule matching an envelope decides
+whether it is allowed, and envelopes no rule matches get the policy's default, deny unless set
+otherwise. A rule matches when every constraint it sets does:
+
+- `sub`: the subject of the connection's [`Principal`], one of a list of names; a trailing `*`
+  matches any suffix
+- `role`: one of a list of roles, looked up in the principal's `roles` claim (a string or an array
+  of strings)
+- `op`: one of a list of operations
+- `to`: one of a list of destinations, again with trailing `*` wildcards
+- `cap:<name>`: the envelope's capability `name` equal to a value
+
+Subject and role constraints never match connections that did not authenticate. Policies are
+built with [`Rule`]'s builder methods or parsed from text, one rule per line:
+
+```text
+# Operators may do anything; workers may only publish metrics
+allow role=operator
+allow sub=worker-* op=data,request to=metrics.*
+deny op=control
+allow cap:tier=gold to=premium.*
+default deny
+```
+
+A policy is an [`Authorizer`], so a server runs it on every envelope before dispatch. Its
+decisions depend only on the principal, operation, destination and the capabilities the rules
+name, so the server caches them per connection (see [`Authorizer::decision_key`]).
+
+```rust
+use umicp_core::auth::{Authorizer, Principal};
+use umicp_core::policy::Policy;
+use umicp_core::{Envelope, OperationType};
+
+# fn main() -> umicp_core::Result<()> {
+let policy: Policy = "allow sub=worker-* op=data to=metrics.*".parse()?;
+let envelope = Envelope::builder().from("w").to("metrics.cpu").operation(OperationType::Data).build()?;
+policy.authorize(Some(&Principal::new("worker-1")), &envelope)?;
+assert!(policy.authorize(None, &envelope).is_err());
+# Ok(())
+# }
+```
+*/
+
+use crate::auth::{Authorizer, Principal};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::OperationType;
+
+/// What a rule does with the envelopes it matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    /// Let them through
+    Allow,
+    /// Reject them as forbidden
+    Deny,
+}
+
+impl std::fmt::Display for Effect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Effect::Allow => write!(f, "allow"),
+            Effect::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+impl std::str::FromStr for Effect {
+    type Err = UmicpError;
+
+    fn from_str(effect: &str) -> Result<Self> {
+        match effect {
+            "allow" => Ok(Effect::Allow),
+            "deny" => Ok(Effect::Deny),
+            _ => Err(UmicpError::validation(format!("Unknown policy effect: {}", effect))),
+        }
+    }
+}
+
+/// One line of a policy: an effect and the constraints an envelope must meet for it to apply
+///
+/// A rule without constraints matches every envelope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    effect: Effect,
+    subjects: Vec<String>,
+    roles: Vec<String>,
+    operations: Vec<OperationType>,
+    destinations: Vec<String>,
+    capabilities: Vec<(String, String)>,
+}
+
+impl Rule {
+    /// Rule allowing what it matches
+    pub fn allow() -> Self {
+        Rule::new(Effect::Allow)
+    }
+
+    /// Rule denying what it matches
+    pub fn deny() -> Self {
+        Rule::new(Effect::Deny)
+    }
+
+    fn new(effect: Effect) -> Self {
+        Rule {
+            effect,
+            subjects: Vec::new(),
+            roles: Vec::new(),
+            operations: Vec::new(),
+            destinations: Vec::new(),
+            capabilities: Vec::new(),
+        }
+    }
+
+    /// Also match principals with subject `pattern`; a trailing `*` matches any suffix
+    pub fn subject(mut self, pattern: &str) -> Self {
+        self.subjects.push(pattern.to_string());
+        self
+    }
+
+    /// Also match principals holding `role`
+    pub fn role(mut self, role: &str) -> Self {
+        self.roles.push(role.to_string());
+        self
+    }
+
+    /// Also match envelopes with `operation`
+    pub fn operation(mut self, operation: OperationType) -> Self {
+        self.operations.push(operation);
+        self
+    }
+
+    /// Also match envelopes addressed to `pattern`; a trailing `*` matches any suffix
+    pub fn destination(mut self, pattern: &str) -> Self {
+        self.destinations.push(pattern.to_string());
+        self
+    }
+
+    /// Only match envelopes whose capability `name` is `value`
+    pub fn capability(mut self, name: &str, value: &str) -> Self {
+        self.capabilities.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// What the rule does with the envelopes it matches
+    pub fn effect(&self) -> Effect {
+        self.effect
+    }
+
+    /// Whether `envelope`, sent by `principal`, meets every constraint
+    pub fn matches(&self, principal: Option<&Principal>, envelope: &Envelope) -> bool {
+        let subject = principal.map(|principal| principal.subject.as_str());
+        let roles = principal.map(roles).unwrap_or_default();
+        let capabilities = envelope.capabilities();
+        (self.subjects.is_empty() || subject.is_some_and(|subject| matches_any(&self.subjects, subject)))
+            && (self.roles.is_empty() || self.roles.iter().any(|role| roles.contains(&role.as_str())))
+            && (self.operations.is_empty() || self.operations.contains(&envelope.operation()))
+            && (self.destinations.is_empty() || matches_any(&self.destinations, envelope.to()))
+            && self.capabilities.iter().all(|(name, value)| {
+                capabilities.and_then(|capabilities| capabilities.get(name)).is_some_and(|actual| actual == value)
+            })
+    }
+}
+
+/// Rules print as the line they parse from, e.g. `allow sub=worker-* op=data to=metrics.*`
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.effect)?;
+        let operations: Vec<String> = self.operations.iter().map(ToString::to_string).collect();
+        let lists = [("sub", &self.subjects), ("role", &self.roles), ("op", &operations), ("to", &self.destinations)];
+        for (key, values) in lists {
+            if !values.is_empty() {
+                write!(f, " {}={}", key, values.join(","))?;
+            }
+        }
+        for (name, value) in &self.capabilities {
+            write!(f, " cap:{}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Rule {
+    type Err = UmicpError;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let mut words = line.split_whitespace();
+        let effect = words.next().ok_or_else(|| UmicpError::validation("Empty policy rule"))?;
+        let mut rule = Rule::new(effect.parse()?);
+        for word in words {
+            let invalid = || UmicpError::validation(format!("Invalid policy constraint: {}", word));
+            let (key, value) = word.split_once('=').filter(|(_, value)| !value.is_empty()).ok_or_else(invalid)?;
+            let list = || value.split(',').map(str::to_string);
+            match key {
+                "sub" => rule.subjects.extend(list()),
+                "role" => rule.roles.extend(list()),
+                "op" => {
+                    for operation in value.split(',') {
+                        rule.operations.push(operation.parse()?);
+                    }
+                }
+                "to" => rule.destinations.extend(list()),
+                _ => match key.strip_prefix("cap:") {
+                    Some(name) if !name.is_empty() => rule.capabilities.push((name.to_string(), value.to_string())),
+                    _ => return Err(invalid()),
+                },
+            }
+        }
+        Ok(rule)
+    }
+}
+
+/// Ordered access rules with a default for envelopes none of them match
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Policy {
+    rules: Vec<Rule>,
+    default: Effect,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            rules: Vec::new(),
+            default: Effect::Deny,
+        }
+    }
+}
+
+impl Policy {
+    /// Policy without rules, denying everything
+    pub fn new() -> Self {
+        Policy::default()
+    }
+
+    /// Append `rule`, checked after the rules already added
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// What happens to envelopes no rule matches
+    pub fn default_effect(mut self, effect: Effect) -> Self {
+        self.default = effect;
+        self
+    }
+
+    /// The rules, in the order they are checked
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// The first rule matching `envelope` sent by `principal`, if any
+    pub fn matching_rule(&self, principal: Option<&Principal>, envelope: &Envelope) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.matches(principal, envelope))
+    }
+
+    /// Whether `envelope` sent by `principal` is allowed
+    pub fn evaluate(&self, principal: Option<&Principal>, envelope: &Envelope) -> Effect {
+        self.matching_rule(principal, envelope).map_or(self.default, Rule::effect)
+    }
+}
+
+/// Policies print as the text they parse from, ending with their `default` line
+impl std::fmt::Display for Policy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for rule in &self.rules {
+            writeln!(f, "{}", rule)?;
+        }
+        write!(f, "default {}", self.default)
+    }
+}
+
+impl std::str::FromStr for Policy {
+    type Err = UmicpError;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut policy = Policy::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let parsed = match line.strip_prefix("default ") {
+                Some(effect) => effect.trim().parse().map(|effect| policy.default = effect),
+                None if line.is_empty() => Ok(()),
+                None => line.parse().map(|rule| policy.rules.push(rule)),
+            };
+            parsed.map_err(|error| UmicpError::validation(format!("Policy line {}: {}", number + 1, error)))?;
+        }
+        Ok(policy)
+    }
+}
+
+impl Authorizer for Policy {
+    fn authorize(&self, principal: Option<&Principal>, envelope: &Envelope) -> Result<()> {
+        let rule = self.matching_rule(principal, envelope);
+        match rule.map_or(self.default, Rule::effect) {
+            Effect::Allow => Ok(()),
+            Effect::Deny => Err(UmicpError::forbidden(format!(
+                "Policy denies {} to {}: {}",
+                envelope.operation(),
+                envelope.to(),
+                rule.map_or_else(|| "no rule allows it".to_string(), |rule| format!("rule `{}`", rule))
+            ))),
+        }
+    }
+
+    fn decision_key(&self, envelope: &Envelope) -> Option<String> {
+        let capabilities = envelope.capabilities();
+        let named: Vec<Option<&String>> = self
+            .rules
+            .iter()
+            .flat_map(|rule| &rule.capabilities)
+            .map(|(name, _)| capabilities.and_then(|capabilities| capabilities.get(name)))
+            .collect();
+        let key = serde_json::json!([envelope.operation().to_string(), envelope.to(), named]);
+        Some(key.to_string())
+    }
+}
+
+/// Roles in the principal's `roles` claim
+fn roles(principal: &Principal) -> Vec<&str> {
+    match principal.claim("roles") {
+        Some(serde_json::Value::String(role)) => vec![role.as_str()],
+        Some(serde_json::Value::Array(roles)) => roles.iter().filter_map(|role| role.as_str()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn matches_any(patterns: &[String], name: &str) -> bool {
+    patterns.iter().any(|pattern| match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(operation: OperationType, to: &str) -> Envelope {
+        Envelope::builder().from("client").to(to).operation(operation).build().unwrap()
+    }
+
+    #[test]
+    fn test_policy_rules_and_text_form() {
+        let text = "
+            # Operators may do anything
+            allow role=operator
+            allow sub=worker-* op=data,request to=metrics.*
+            deny op=control
+            allow cap:tier=gold to=premium.*
+            default deny
+        ";
+        let policy: Policy = text.parse().unwrap();
+        let built = Policy::new()
+            .rule(Rule::allow().role("operator"))
+            .rule(
+                Rule::allow()
+                    .subject("worker-*")
+                    .operation(OperationType::Data)
+                    .operation(OperationType::Request)
+                    .destination("metrics.*"),
+            )
+            .rule(Rule::deny().operation(OperationType::Control))
+            .rule(Rule::allow().capability("tier", "gold").destination("premium.*"));
+        assert_eq!(policy, built);
+        assert_eq!(policy.to_string().parse::<Policy>().unwrap(), policy);
+
+        let worker = Principal::new("worker-3");
+        let mut operator = Principal::new("alice");
+        operator.claims.insert("roles".to_string(), serde_json::json!(["auditor", "operator"]));
+
+        assert!(policy.authorize(Some(&worker), &envelope(OperationType::Data, "metrics.cpu")).is_ok());
+        assert!(policy.authorize(Some(&worker), &envelope(OperationType::Data, "models.resnet")).is_err());
+        assert!(policy.authorize(Some(&operator), &envelope(OperationType::Control, "admin")).is_ok());
+        // Anonymous connections match neither subject nor role rules
+        assert!(policy.authorize(None, &envelope(OperationType::Data, "metrics.cpu")).is_err());
+
+        let error = policy.authorize(Some(&worker), &envelope(OperationType::Control, "metrics.cpu")).unwrap_err();
+        assert!(matches!(error, UmicpError::Forbidden { .. }));
+        assert!(error.to_string().contains("rule `deny op=control`"), "{}", error);
+
+        let mut gold = envelope(OperationType::Data, "premium.feed");
+        assert!(policy.authorize(None, &gold).is_err());
+        gold.add_capability("tier", "gold");
+        assert!(policy.authorize(None, &gold).is_ok());
+
+        // Only the operation, destination and named capabilities set the decision key
+        let mut noisy = gold.clone();
+        noisy.add_capability("trace", "abc");
+        assert_eq!(policy.decision_key(&noisy), policy.decision_key(&gold));
+        assert_ne!(policy.decision_key(&gold), policy.decision_key(&envelope(OperationType::Data, "premium.feed")));
+
+        let error = "allow op=data\npermit sub=x".parse::<Policy>().unwrap_err();
+        assert!(error.to_string().contains("line 2"), "{}", error);
+        assert!("allow op=launch".parse::<Policy>().is_err());
+        assert!("allow to=".parse::<Policy>().is_err());
+    }
+}
@@ -0,0 +1,162 @@
+/*!
+# UMICP Envelope Privacy
+
+How new envelopes are identified and timestamped, for deployments where that metadata must not
+link messages to the host that sent them.
+
+By default message IDs are UUIDv4s and timestamps carry the sender's clock to the nanosecond,
+whose offset from true time can fingerprint a host. With [`Privacy::random_ids`], IDs are 128
+bits read straight from the operating system's CSPRNG, written in UUID form but without the version
+and variant bits that identify a generator. [`TimestampPrecision`] truncates timestamps to the
+second, minute or hour, or leaves them out of the envelope altogether.
+
+The setting is process-wide, as envelopes are also created inside transports (acks, errors,
+control messages), and applies to envelopes created after [`set_privacy`] is called. Peers accept
+envelopes without a timestamp whatever their own setting.
+
+```rust
+use umicp_core::privacy::{set_privacy, Privacy, TimestampPrecision};
+use umicp_core::Envelope;
+
+set_privacy(Privacy {
+    random_ids: true,
+    timestamps: TimestampPrecision::Omitted,
+});
+assert!(Envelope::new().timestamp().is_empty());
+# set_privacy(Privacy::default());
+```
+*/
+
+use chrono::{DurationRound, SecondsFormat, TimeDelta, Utc};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU8, Ordering};
+use uuid::Uuid;
+
+/// How precisely new envelopes record when they were created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampPrecision {
+    /// The full clock reading
+    #[default]
+    Full,
+    /// Truncated to the second
+    Seconds,
+    /// Truncated to the minute
+    Minutes,
+    /// Truncated to the hour
+    Hours,
+    /// No timestamp at all
+    Omitted,
+}
+
+/// How new envelopes are identified and timestamped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Privacy {
+    /// Message IDs from the OS CSPRNG rather than UUIDv4s
+    #[serde(default)]
+    pub random_ids: bool,
+    /// Precision of envelope timestamps
+    #[serde(default)]
+    pub timestamps: TimestampPrecision,
+}
+
+impl Privacy {
+    /// ID for a new envelope
+    pub fn message_id(&self) -> String {
+        if !self.random_ids {
+            return Uuid::new_v4().to_string();
+        }
+        let mut bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut bytes);
+        Uuid::from_bytes(bytes).to_string()
+    }
+
+    /// Timestamp for a new envelope, empty when timestamps are omitted
+    pub fn timestamp(&self) -> String {
+        let unit = match self.timestamps {
+            TimestampPrecision::Full => return Utc::now().to_rfc3339(),
+            TimestampPrecision::Omitted => return String::new(),
+            TimestampPrecision::Seconds => TimeDelta::seconds(1),
+            TimestampPrecision::Minutes => TimeDelta::minutes(1),
+            TimestampPrecision::Hours => TimeDelta::hours(1),
+        };
+        let now = Utc::now();
+        now.duration_trunc(unit)
+            .unwrap_or(now)
+            .to_rfc3339_opts(SecondsFormat::Secs, true)
+    }
+
+    fn to_bits(self) -> u8 {
+        let precision = match self.timestamps {
+            TimestampPrecision::Full => 0,
+            TimestampPrecision::Seconds => 1,
+            TimestampPrecision::Minutes => 2,
+            TimestampPrecision::Hours => 3,
+            TimestampPrecision::Omitted => 4,
+        };
+        precision << 1 | self.random_ids as u8
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        let timestamps = match bits >> 1 {
+            1 => TimestampPrecision::Seconds,
+            2 => TimestampPrecision::Minutes,
+            3 => TimestampPrecision::Hours,
+            4 => TimestampPrecision::Omitted,
+            _ => TimestampPrecision::Full,
+        };
+        Privacy {
+            random_ids: bits & 1 == 1,
+            timestamps,
+        }
+    }
+}
+
+static PRIVACY: AtomicU8 = AtomicU8::new(0);
+
+/// Apply `privacy` to every envelope created from now on, in the whole process
+pub fn set_privacy(privacy: Privacy) {
+    PRIVACY.store(privacy.to_bits(), Ordering::Relaxed);
+}
+
+/// The setting new envelopes are created with
+pub fn privacy() -> Privacy {
+    Privacy::from_bits(PRIVACY.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{parse_timestamp, validate_uuid};
+
+    #[test]
+    fn test_private_ids_and_timestamps() {
+        let private = Privacy {
+            random_ids: true,
+            timestamps: TimestampPrecision::Minutes,
+        };
+        assert_eq!(Privacy::from_bits(private.to_bits()), private);
+        assert_eq!(Privacy::from_bits(Privacy::default().to_bits()), Privacy::default());
+
+        let id = private.message_id();
+        assert!(validate_uuid(&id));
+        assert_ne!(id, private.message_id());
+        let timestamp = private.timestamp();
+        assert_eq!(parse_timestamp(&timestamp).unwrap().timestamp() % 60, 0);
+        assert!(timestamp.ends_with(":00Z"));
+
+        let omitted = Privacy {
+            timestamps: TimestampPrecision::Omitted,
+            ..private
+        };
+        assert!(omitted.timestamp().is_empty());
+
+        // Envelopes without a timestamp travel without the field
+        let json = r#"{"v":"1.0","msg_id":"0c7f3a51-98e2-0d4b-f316-5e0a2b9c4d17","from":"a","to":"b","op":"data"}"#;
+        let envelope = crate::Envelope::deserialize(json).unwrap();
+        assert!(envelope.timestamp().is_empty());
+        assert!(!envelope.serialize().unwrap().contains("\"ts\""));
+    }
+}
@@ -0,0 +1,318 @@
+/*!
+# UMICP Quotas
+
+Per-peer usage limits for servers shared by many parties, such as an aggregation server that
+several organisations' workers report to. A [`Quota`] caps how many envelopes a peer may send a
+day and how many bytes an hour.
+
+A [`QuotaTracker`] counts usage by principal subject rather than by connection, so reconnecting
+does not reset it; servers count peers that did not authenticate by IP address. Each limit is a
+fixed window that opens with the first envelope counted in it. Individual principals can be
+given a quota other than the default with [`set_quota`](QuotaTracker::set_quota).
+
+An envelope over either limit is not counted, and it is answered with a standard quota-exceeded
+`Error` envelope ([`QuotaExceeded::error_reply`]):
+
+- its `error_code` is `quota_exceeded`;
+- [`QUOTA_CAPABILITY`] names the limit;
+- [`RETRY_AFTER_CAPABILITY`] says in how many milliseconds that limit's window reopens.
+
+```rust
+use umicp_core::quota::{Quota, QuotaLimit, QuotaTracker};
+
+let tracker = QuotaTracker::new(Quota::new().messages_per_day(2).bytes_per_hour(1_000_000));
+tracker.set_quota("coordinator", Quota::unlimited());
+
+assert!(tracker.charge("worker-7", 512).is_ok());
+assert!(tracker.charge("worker-7", 512).is_ok());
+let exceeded = tracker.charge("worker-7", 512).unwrap_err();
+assert_eq!(exceeded.limit, QuotaLimit::MessagesPerDay);
+assert!(tracker.charge("coordinator", 512).is_ok());
+```
+*/
+
+use crate::envelope::Envelope;
+use crate::error::UmicpError;
+use crate::transport::{ERROR_CAPABILITY, ERROR_CODE_CAPABILITY};
+use crate::types::OperationType;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Capability of a quota-exceeded `Error` envelope naming the limit that was reached
+pub const QUOTA_CAPABILITY: &str = "quota";
+
+/// Capability of a quota-exceeded `Error` envelope giving the milliseconds until it is worth retrying
+pub const RETRY_AFTER_CAPABILITY: &str = "retry_after_ms";
+
+/// `error_code` of quota-exceeded `Error` envelopes
+pub const QUOTA_EXCEEDED_CODE: &str = "quota_exceeded";
+
+const DAY: Duration = Duration::from_secs(24 * 3600);
+const HOUR: Duration = Duration::from_secs(3600);
+
+/// Usage tracked for this many peers before idle ones are forgotten
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Limits on what one peer may send; `None` means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    /// Envelopes per day
+    #[serde(default)]
+    pub messages_per_day: Option<u64>,
+    /// Bytes per hour, as received on the wire
+    #[serde(default)]
+    pub bytes_per_hour: Option<u64>,
+}
+
+impl Quota {
+    /// No limits, to be narrowed with the builder methods
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// No limits, for principals exempt from the default quota
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    /// Allow at most `limit` envelopes a day
+    pub fn messages_per_day(mut self, limit: u64) -> Self {
+        self.messages_per_day = Some(limit);
+        self
+    }
+
+    /// Allow at most `limit` bytes an hour
+    pub fn bytes_per_hour(mut self, limit: u64) -> Self {
+        self.bytes_per_hour = Some(limit);
+        self
+    }
+}
+
+/// Which limit of a [`Quota`] was reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaLimit {
+    MessagesPerDay,
+    BytesPerHour,
+}
+
+impl QuotaLimit {
+    /// Name carried in [`QUOTA_CAPABILITY`]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QuotaLimit::MessagesPerDay => "messages_per_day",
+            QuotaLimit::BytesPerHour => "bytes_per_hour",
+        }
+    }
+}
+
+impl std::fmt::Display for QuotaLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An envelope a peer was not allowed to send
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    /// The limit that was reached
+    pub limit: QuotaLimit,
+    /// What the quota allows per window
+    pub allowed: u64,
+    /// Time until the window reopens
+    pub retry_after: Duration,
+}
+
+impl QuotaExceeded {
+    /// The error to report or return
+    pub fn error(&self) -> UmicpError {
+        UmicpError::quota_exceeded(format!(
+            "Quota of {} {} reached; retry in {}s",
+            self.allowed,
+            self.limit,
+            self.retry_after.as_secs()
+        ))
+    }
+
+    /// The `Error` reply telling the sender of `envelope` it is over quota
+    pub fn error_reply(&self, envelope: &Envelope) -> Envelope {
+        let mut reply = envelope.reply(OperationType::Error);
+        reply.add_capability(ERROR_CAPABILITY, &self.error().to_string());
+        reply.add_capability(ERROR_CODE_CAPABILITY, QUOTA_EXCEEDED_CODE);
+        reply.add_capability(QUOTA_CAPABILITY, self.limit.as_str());
+        reply.add_capability(RETRY_AFTER_CAPABILITY, &self.retry_after.as_millis().to_string());
+        reply
+    }
+}
+
+/// What a peer has used of its quota in the current windows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    /// Envelopes counted in the current day
+    pub messages_today: u64,
+    /// Bytes counted in the current hour
+    pub bytes_this_hour: u64,
+}
+
+/// Fixed window counting usage from its first use
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    opened: Instant,
+    used: u64,
+}
+
+impl Window {
+    fn new(now: Instant) -> Self {
+        Window { opened: now, used: 0 }
+    }
+
+    /// Usage in the window holding `now`, opening a new window if the last one has closed
+    fn current(&mut self, now: Instant, length: Duration) -> u64 {
+        if now.duration_since(self.opened) >= length {
+            *self = Window::new(now);
+        }
+        self.used
+    }
+
+    fn reopens_in(&self, now: Instant, length: Duration) -> Duration {
+        length.saturating_sub(now.duration_since(self.opened))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Usage {
+    messages: Window,
+    bytes: Window,
+}
+
+/// Counts each peer's usage against its quota
+#[derive(Debug, Default)]
+pub struct QuotaTracker {
+    default: Quota,
+    overrides: RwLock<HashMap<String, Quota>>,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl QuotaTracker {
+    /// Hold every peer to `default`
+    pub fn new(default: Quota) -> Self {
+        QuotaTracker {
+            default,
+            ..Default::default()
+        }
+    }
+
+    /// Hold `peer` to `quota` instead of the default
+    pub fn set_quota(&self, peer: &str, quota: Quota) {
+        self.overrides.write().unwrap().insert(peer.to_string(), quota);
+    }
+
+    /// Hold `peer` to the default quota again
+    pub fn clear_quota(&self, peer: &str) {
+        self.overrides.write().unwrap().remove(peer);
+    }
+
+    /// The quota `peer` is held to
+    pub fn quota(&self, peer: &str) -> Quota {
+        self.overrides.read().unwrap().get(peer).copied().unwrap_or(self.default)
+    }
+
+    /// Count an envelope of `bytes` from `peer`, unless it would take the peer over its quota
+    pub fn charge(&self, peer: &str, bytes: u64) -> Result<(), QuotaExceeded> {
+        self.charge_at(peer, bytes, Instant::now())
+    }
+
+    fn charge_at(&self, peer: &str, bytes: u64, now: Instant) -> Result<(), QuotaExceeded> {
+        let quota = self.quota(peer);
+        if quota == Quota::unlimited() {
+            return Ok(());
+        }
+        let mut all = self.usage.lock().unwrap();
+        if all.len() >= PRUNE_THRESHOLD && !all.contains_key(peer) {
+            // Peers whose windows have all closed would start afresh anyway
+            all.retain(|_, usage| {
+                now.duration_since(usage.messages.opened) < DAY || now.duration_since(usage.bytes.opened) < HOUR
+            });
+        }
+        let usage = all.entry(peer.to_string()).or_insert(Usage {
+            messages: Window::new(now),
+            bytes: Window::new(now),
+        });
+
+        let messages = usage.messages.current(now, DAY);
+        if let Some(allowed) = quota.messages_per_day.filter(|allowed| messages >= *allowed) {
+            return Err(QuotaExceeded {
+                limit: QuotaLimit::MessagesPerDay,
+                allowed,
+                retry_after: usage.messages.reopens_in(now, DAY),
+            });
+        }
+        let used = usage.bytes.current(now, HOUR);
+        if let Some(allowed) = quota.bytes_per_hour.filter(|allowed| used.saturating_add(bytes) > *allowed) {
+            return Err(QuotaExceeded {
+                limit: QuotaLimit::BytesPerHour,
+                allowed,
+                retry_after: usage.bytes.reopens_in(now, HOUR),
+            });
+        }
+        usage.messages.used += 1;
+        usage.bytes.used += bytes;
+        Ok(())
+    }
+
+    /// What `peer` has used in its current windows
+    pub fn usage(&self, peer: &str) -> QuotaUsage {
+        let now = Instant::now();
+        let mut all = self.usage.lock().unwrap();
+        match all.get_mut(peer) {
+            Some(usage) => QuotaUsage {
+                messages_today: usage.messages.current(now, DAY),
+                bytes_this_hour: usage.bytes.current(now, HOUR),
+            },
+            None => QuotaUsage::default(),
+        }
+    }
+
+    /// Forget what `peer` has used, giving it a full quota again
+    pub fn reset(&self, peer: &str) {
+        self.usage.lock().unwrap().remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota_windows() {
+        let tracker = QuotaTracker::new(Quota::new().messages_per_day(3).bytes_per_hour(1000));
+        tracker.set_quota("coordinator", Quota::unlimited());
+        let start = Instant::now();
+
+        assert!(tracker.charge_at("worker", 600, start).is_ok());
+        let exceeded = tracker.charge_at("worker", 600, start + Duration::from_secs(60)).unwrap_err();
+        assert_eq!(exceeded.limit, QuotaLimit::BytesPerHour);
+        assert_eq!(exceeded.retry_after, Duration::from_secs(3540));
+        // The rejected envelope was not counted, so a smaller one still fits
+        assert!(tracker.charge_at("worker", 400, start + Duration::from_secs(60)).is_ok());
+
+        assert!(tracker.charge_at("worker", 600, start + HOUR).is_ok());
+        let exceeded = tracker.charge_at("worker", 1, start + HOUR).unwrap_err();
+        assert_eq!(exceeded.limit, QuotaLimit::MessagesPerDay);
+        assert_eq!(exceeded.retry_after, DAY - HOUR);
+        assert!(tracker.charge_at("worker", 1, start + DAY).is_ok());
+        assert!(tracker.charge_at("coordinator", 1_000_000, start).is_ok());
+
+        let reply = exceeded.error_reply(&Envelope::new());
+        let capabilities = reply.capabilities().unwrap();
+        assert_eq!(reply.operation(), OperationType::Error);
+        assert_eq!(capabilities[ERROR_CODE_CAPABILITY], QUOTA_EXCEEDED_CODE);
+        assert_eq!(capabilities[QUOTA_CAPABILITY], "messages_per_day");
+        assert_eq!(capabilities[RETRY_AFTER_CAPABILITY], (DAY - HOUR).as_millis().to_string());
+
+        tracker.reset("worker");
+        assert_eq!(tracker.usage("worker"), QuotaUsage::default());
+    }
+}
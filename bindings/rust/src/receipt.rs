@@ -0,0 +1,125 @@
+/*!
+# UMICP Signed Delivery Receipts
+
+Proof that a peer received an envelope, which the sender can keep and show to a third party:
+the peer cannot later deny having received it, and the sender cannot forge the proof.
+
+A sender asks for a receipt by marking the envelope with the [`RECEIPT_CAPABILITY`]. The
+receiver answers with an `Ack` whose [`RECEIPT_DIGEST_CAPABILITY`] holds the [`digest`] of what
+it received, signed by its [`Signer`] like any other envelope. Checking that
+signature with the receiver's public key, and the digest against the envelope sent, proves
+delivery of exactly that envelope.
+
+The digest covers the envelope's message and correlation IDs, sender, destination, operation,
+timestamp and payload, but not its capabilities, which transports and middleware may add to on
+the way.
+
+[`ReliableTransport`](crate::transport::ReliableTransport) does both sides: with
+[`set_receipt_signer`](crate::transport::ReliableTransport::set_receipt_signer) it signs the
+acks of envelopes asking for a receipt, and
+[`send_with_receipt`](crate::transport::ReliableTransport::send_with_receipt) sends one and
+checks the receipt it gets back.
+*/
+
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::keystore::{sign_envelope, KeyStore, Signer};
+use crate::types::OperationType;
+use crate::utils::{base64_encode, constant_time_eq};
+use sha2::{Digest, Sha256};
+
+/// Capability asking the receiver for a signed receipt
+pub const RECEIPT_CAPABILITY: &str = "receipt";
+
+/// Capability of a receipt holding the digest of the envelope received, hex-encoded
+pub const RECEIPT_DIGEST_CAPABILITY: &str = "receipt_digest";
+
+const SIGNED: &str = "signed";
+
+/// SHA-256 over the parts of `envelope` a receipt vouches for, hex-encoded
+pub fn digest(envelope: &Envelope) -> String {
+    let parts = serde_json::json!([
+        envelope.message_id(),
+        envelope.correlation_id(),
+        envelope.from(),
+        envelope.to(),
+        envelope.operation().to_string(),
+        envelope.timestamp(),
+        envelope.payload().map(base64_encode),
+    ]);
+    hex::encode(Sha256::digest(&serde_json::to_vec(&parts).expect("JSON values serialize")))
+}
+
+/// Mark `envelope` as asking for a signed receipt
+pub fn request_receipt(envelope: &mut Envelope) {
+    envelope.add_capability(RECEIPT_CAPABILITY, SIGNED);
+}
+
+/// Whether `envelope` asks for a signed receipt
+pub fn wants_receipt(envelope: &Envelope) -> bool {
+    envelope
+        .capabilities()
+        .and_then(|capabilities| capabilities.get(RECEIPT_CAPABILITY))
+        .is_some_and(|value| value == SIGNED)
+}
+
+/// `Ack` for `delivered`, signed by `signer`
+pub fn sign_receipt(signer: &dyn Signer, delivered: &Envelope) -> Result<Envelope> {
+    let mut receipt = delivered.reply(OperationType::Ack);
+    receipt.add_capability(RECEIPT_DIGEST_CAPABILITY, &digest(delivered));
+    sign_envelope(signer, &mut receipt)?;
+    Ok(receipt)
+}
+
+/// Check that `receipt` is a receipt for `sent`, signed by a key in `keys`; returns the key ID
+pub fn verify_receipt(keys: &KeyStore, sent: &Envelope, receipt: &Envelope) -> Result<String> {
+    let key_id = keys.verify_envelope(receipt)?;
+    let vouched = receipt
+        .capabilities()
+        .and_then(|capabilities| capabilities.get(RECEIPT_DIGEST_CAPABILITY))
+        .ok_or_else(|| UmicpError::authentication("Ack is not a receipt"))?;
+    let vouches_for_sent = constant_time_eq(vouched.as_bytes(), digest(sent).as_bytes());
+    if receipt.correlation_id() != Some(sent.message_id()) || !vouches_for_sent {
+        return Err(UmicpError::authentication(format!(
+            "Receipt is not for envelope {}",
+            sent.message_id()
+        )));
+    }
+    Ok(key_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::KeyPurpose;
+
+    #[test]
+    fn test_receipt_signing_and_verification() {
+        let receiver = KeyStore::new();
+        let key_id = receiver.generate(KeyPurpose::Signing).unwrap();
+        let sender = KeyStore::new();
+        sender.import_public_key(&key_id, &receiver.public_key(&key_id).unwrap()).unwrap();
+
+        let mut sent = Envelope::builder().from("a").to("b").operation(OperationType::Data).build().unwrap();
+        sent.set_payload(b"invoice".to_vec());
+        request_receipt(&mut sent);
+        assert!(wants_receipt(&sent));
+
+        // Capabilities added in transit do not change the digest
+        let mut delivered = sent.clone();
+        delivered.add_capability("hop", "relay-1");
+        let receipt = sign_receipt(&receiver, &delivered).unwrap();
+        assert_eq!(verify_receipt(&sender, &sent, &receipt).unwrap(), key_id);
+
+        // Another envelope, another payload or an altered receipt does not verify
+        let mut other = sent.clone();
+        other.set_payload(b"different".to_vec());
+        assert!(verify_receipt(&sender, &other, &receipt).is_err());
+        let unrelated = Envelope::builder().from("a").to("b").build().unwrap();
+        assert!(verify_receipt(&sender, &unrelated, &receipt).is_err());
+        let mut forged = receipt.clone();
+        forged.add_capability(RECEIPT_DIGEST_CAPABILITY, &digest(&other));
+        assert!(verify_receipt(&sender, &other, &forged).is_err());
+        assert!(verify_receipt(&KeyStore::new(), &sent, &receipt).is_err());
+    }
+}
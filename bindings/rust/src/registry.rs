@@ -0,0 +1,345 @@
+/*!
+# UMICP Model Registry
+
+Versioned storage of model weights, addressed by name and version, so every party in a
+federated deployment can check it trains against the intended global model.
+
+Each version published under a name gets the next version number, starting at 1. It records the
+SHA-256 hash of its weights ([`model_hash`]), free-form metadata and, optionally, the version it
+was derived from. Versions are immutable once published.
+
+A [`ModelRegistry`] answers requests over any transport through
+[`serve_requests`](crate::transport::serve_requests). The [`MODEL_REGISTRY_CAPABILITY`] names the
+operation, and the [`MODEL_CAPABILITY`] carries its arguments as JSON:
+
+- `publish` stores the weights in the payload; the reply describes the new version;
+- `fetch` answers with a version's weights, the latest one unless a version is given;
+- `list` answers with the versions of one model, or of all of them.
+
+Requests are built with [`publish_request`], [`fetch_request`] and [`list_request`]. Replies are
+read with [`FetchedModel::from_reply`], which checks the weights against their recorded hash, and
+with [`ModelVersion::from_reply`] and [`ModelVersion::list_from_reply`].
+
+```rust
+use std::collections::HashMap;
+use umicp_core::registry::{model_hash, ModelRegistry};
+
+let registry = ModelRegistry::new();
+let first = registry.publish("mnist", vec![0.1, 0.2], None, HashMap::new())?;
+let second = registry.publish("mnist", vec![0.3, 0.4], Some(first.version), HashMap::new())?;
+assert_eq!((second.version, second.parent), (2, Some(1)));
+
+// A client handed weights for round 2 checks they are the published ones
+let (version, weights) = registry.get("mnist", Some(2)).unwrap();
+assert_eq!(model_hash(&weights), version.hash);
+# Ok::<(), umicp_core::UmicpError>(())
+```
+*/
+
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::federated;
+use crate::types::OperationType;
+use crate::utils::{constant_time_eq, generate_hash};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+
+/// Capability naming the registry operation: `publish`, `fetch` or `list`
+pub const MODEL_REGISTRY_CAPABILITY: &str = "model_registry";
+
+/// Capability carrying a registry request's arguments, or a reply's model version, as JSON
+pub const MODEL_CAPABILITY: &str = "model";
+
+const PUBLISH: &str = "publish";
+const FETCH: &str = "fetch";
+const LIST: &str = "list";
+
+/// SHA-256 of `weights` as little-endian `f32`, in hex
+pub fn model_hash(weights: &[f32]) -> String {
+    let bytes: Vec<u8> = weights.iter().flat_map(|weight| weight.to_le_bytes()).collect();
+    generate_hash(&bytes)
+}
+
+/// One published version of a model
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelVersion {
+    /// Model name
+    pub name: String,
+    /// Version number, from 1
+    pub version: u64,
+    /// [`model_hash`] of the weights
+    pub hash: String,
+    /// Version this one was derived from
+    #[serde(default)]
+    pub parent: Option<u64>,
+    /// Free-form metadata, such as the round that produced the version
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    /// When the version was published
+    pub published_at: DateTime<Utc>,
+}
+
+impl ModelVersion {
+    /// Check that `weights` are this version's, comparing hashes in constant time
+    pub fn verify(&self, weights: &[f32]) -> Result<()> {
+        match constant_time_eq(model_hash(weights).as_bytes(), self.hash.as_bytes()) {
+            true => Ok(()),
+            false => Err(UmicpError::validation(format!(
+                "Weights do not match {} version {}",
+                self.name, self.version
+            ))),
+        }
+    }
+
+    /// Version described by a `publish` or `fetch` reply
+    pub fn from_reply(reply: &Envelope) -> Result<Self> {
+        argument(reply)
+    }
+
+    /// Versions listed by a `list` reply
+    pub fn list_from_reply(reply: &Envelope) -> Result<Vec<Self>> {
+        serde_json::from_slice(reply.payload().unwrap_or_default())
+            .map_err(|e| UmicpError::serialization(format!("Invalid model list from {}: {}", reply.from(), e)))
+    }
+}
+
+/// A version and its weights, as fetched from a registry
+#[derive(Debug, Clone, PartialEq)]
+pub struct FetchedModel {
+    /// The version fetched
+    pub version: ModelVersion,
+    /// Its weights, checked against its hash
+    pub weights: Vec<f32>,
+}
+
+impl FetchedModel {
+    /// Model carried by a `fetch` reply, rejected if the weights do not match its hash
+    pub fn from_reply(reply: &Envelope) -> Result<Self> {
+        let version = ModelVersion::from_reply(reply)?;
+        let weights = federated::weights(reply)?;
+        version.verify(&weights)?;
+        Ok(FetchedModel { version, weights })
+    }
+}
+
+/// Arguments of a registry request
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Query {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent: Option<u64>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    metadata: HashMap<String, String>,
+}
+
+fn argument<T: serde::de::DeserializeOwned>(envelope: &Envelope) -> Result<T> {
+    let json = envelope
+        .capabilities()
+        .and_then(|capabilities| capabilities.get(MODEL_CAPABILITY))
+        .ok_or_else(|| UmicpError::validation(format!("Registry message from {} has no model", envelope.from())))?;
+    serde_json::from_str(json)
+        .map_err(|e| UmicpError::serialization(format!("Invalid model in message from {}: {}", envelope.from(), e)))
+}
+
+fn request(from: &str, to: &str, operation: &str, query: &Query) -> Result<Envelope> {
+    let query = serde_json::to_string(query)
+        .map_err(|e| UmicpError::serialization(format!("Failed to serialize registry request: {}", e)))?;
+    Envelope::builder()
+        .from(from)
+        .to(to)
+        .operation(OperationType::Request)
+        .capability(MODEL_REGISTRY_CAPABILITY, operation)
+        .capability(MODEL_CAPABILITY, &query)
+        .build()
+}
+
+/// Request publishing `weights` as the next version of `name`
+pub fn publish_request(
+    from: &str,
+    to: &str,
+    name: &str,
+    weights: &[f32],
+    parent: Option<u64>,
+    metadata: HashMap<String, String>,
+) -> Result<Envelope> {
+    let query = Query {
+        name: Some(name.to_string()),
+        parent,
+        metadata,
+        ..Default::default()
+    };
+    request(from, to, PUBLISH, &query).map(|envelope| federated::with_weights(envelope, weights))
+}
+
+/// Request the weights of `version` of `name`, or of its latest version
+pub fn fetch_request(from: &str, to: &str, name: &str, version: Option<u64>) -> Result<Envelope> {
+    let query = Query {
+        name: Some(name.to_string()),
+        version,
+        ..Default::default()
+    };
+    request(from, to, FETCH, &query)
+}
+
+/// Request the versions of `name`, or of every model
+pub fn list_request(from: &str, to: &str, name: Option<&str>) -> Result<Envelope> {
+    let query = Query {
+        name: name.map(str::to_string),
+        ..Default::default()
+    };
+    request(from, to, LIST, &query)
+}
+
+/// Versions of one model and their weights, by version number
+type Versions = BTreeMap<u64, (ModelVersion, Arc<[f32]>)>;
+
+/// Model versions by name and version number
+#[derive(Debug, Default)]
+pub struct ModelRegistry {
+    models: RwLock<BTreeMap<String, Versions>>,
+}
+
+impl ModelRegistry {
+    /// Empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store `weights` as the next version of `name`, derived from version `parent` if given
+    pub fn publish(
+        &self,
+        name: &str,
+        weights: Vec<f32>,
+        parent: Option<u64>,
+        metadata: HashMap<String, String>,
+    ) -> Result<ModelVersion> {
+        if name.is_empty() {
+            return Err(UmicpError::validation("Model name must not be empty"));
+        }
+        let mut models = self.models.write().unwrap();
+        let versions = models.entry(name.to_string()).or_default();
+        if let Some(parent) = parent.filter(|parent| !versions.contains_key(parent)) {
+            return Err(UmicpError::validation(format!("{} has no version {} to derive from", name, parent)));
+        }
+        let version = ModelVersion {
+            name: name.to_string(),
+            version: versions.keys().next_back().map_or(1, |latest| latest + 1),
+            hash: model_hash(&weights),
+            parent,
+            metadata,
+            published_at: Utc::now(),
+        };
+        versions.insert(version.version, (version.clone(), weights.into()));
+        Ok(version)
+    }
+
+    /// `version` of `name` and its weights, or the latest version without one
+    pub fn get(&self, name: &str, version: Option<u64>) -> Option<(ModelVersion, Vec<f32>)> {
+        let models = self.models.read().unwrap();
+        let versions = models.get(name)?;
+        let (version, weights) = match version {
+            Some(version) => versions.get(&version)?,
+            None => versions.values().next_back()?,
+        };
+        Some((version.clone(), weights.to_vec()))
+    }
+
+    /// Latest version of `name`
+    pub fn latest(&self, name: &str) -> Option<ModelVersion> {
+        let models = self.models.read().unwrap();
+        models.get(name)?.values().next_back().map(|(version, _)| version.clone())
+    }
+
+    /// Every version of `name`, oldest first
+    pub fn versions(&self, name: &str) -> Vec<ModelVersion> {
+        let models = self.models.read().unwrap();
+        let versions = models.get(name).into_iter().flat_map(|versions| versions.values());
+        versions.map(|(version, _)| version.clone()).collect()
+    }
+
+    /// Names of the models held, sorted
+    pub fn names(&self) -> Vec<String> {
+        self.models.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Answer a registry request, for use with [`serve_requests`](crate::transport::serve_requests)
+    pub fn handle(&self, request: &Envelope) -> Result<Envelope> {
+        let operation = request
+            .capabilities()
+            .and_then(|capabilities| capabilities.get(MODEL_REGISTRY_CAPABILITY))
+            .ok_or_else(|| UmicpError::validation(format!("Request from {} names no operation", request.from())))?;
+        let query: Query = argument(request)?;
+        let name = query.name.as_deref();
+        let describe = |version: &ModelVersion| {
+            serde_json::to_string(version)
+                .map_err(|e| UmicpError::serialization(format!("Failed to serialize model version: {}", e)))
+        };
+        let mut reply = request.reply(OperationType::Response);
+        match operation.as_str() {
+            PUBLISH => {
+                let name = name.ok_or_else(|| UmicpError::validation("Publish request names no model"))?;
+                let version = self.publish(name, federated::weights(request)?, query.parent, query.metadata)?;
+                reply.add_capability(MODEL_CAPABILITY, &describe(&version)?);
+            }
+            FETCH => {
+                let name = name.ok_or_else(|| UmicpError::validation("Fetch request names no model"))?;
+                let (version, weights) = self.get(name, query.version).ok_or_else(|| {
+                    let version = query.version.map_or("any version".to_string(), |v| format!("version {}", v));
+                    UmicpError::validation(format!("Registry has no {} of {}", version, name))
+                })?;
+                reply.add_capability(MODEL_CAPABILITY, &describe(&version)?);
+                reply = federated::with_weights(reply, &weights);
+            }
+            LIST => {
+                let names = match name {
+                    Some(name) => vec![name.to_string()],
+                    None => self.names(),
+                };
+                let versions: Vec<ModelVersion> = names.iter().flat_map(|name| self.versions(name)).collect();
+                let json = serde_json::to_vec(&versions)
+                    .map_err(|e| UmicpError::serialization(format!("Failed to serialize model list: {}", e)))?;
+                reply.set_payload(json);
+            }
+            other => return Err(UmicpError::validation(format!("Unknown registry operation: {}", other))),
+        }
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_requests() {
+        let registry = ModelRegistry::new();
+        let metadata = HashMap::from([("round".to_string(), "1".to_string())]);
+        let publish = publish_request("coordinator", "registry", "mnist", &[1.0, 2.0], None, metadata).unwrap();
+        let first = ModelVersion::from_reply(&registry.handle(&publish).unwrap()).unwrap();
+        assert_eq!((first.version, first.hash.as_str()), (1, model_hash(&[1.0, 2.0]).as_str()));
+        assert_eq!(first.metadata["round"], "1");
+        registry.publish("mnist", vec![3.0, 4.0], Some(1), HashMap::new()).unwrap();
+        assert!(registry.publish("mnist", vec![0.0], Some(9), HashMap::new()).is_err());
+
+        // Fetched weights are checked against their hash
+        let fetch = fetch_request("worker", "registry", "mnist", Some(1)).unwrap();
+        let reply = registry.handle(&fetch).unwrap();
+        assert_eq!(reply.correlation_id(), Some(fetch.message_id()));
+        let fetched = FetchedModel::from_reply(&reply).unwrap();
+        assert_eq!((fetched.version, fetched.weights), (first, vec![1.0, 2.0]));
+        let tampered = federated::with_weights(reply.clone(), &[1.0, 2.5]);
+        assert!(matches!(FetchedModel::from_reply(&tampered), Err(UmicpError::Validation { .. })));
+        let latest = registry.handle(&fetch_request("worker", "registry", "mnist", None).unwrap()).unwrap();
+        assert_eq!(FetchedModel::from_reply(&latest).unwrap().version.parent, Some(1));
+        assert!(registry.handle(&fetch_request("worker", "registry", "cifar", None).unwrap()).is_err());
+
+        let list = registry.handle(&list_request("worker", "registry", None).unwrap()).unwrap();
+        let versions = ModelVersion::list_from_reply(&list).unwrap();
+        assert_eq!(versions.iter().map(|version| version.version).collect::<Vec<_>>(), vec![1, 2]);
+    }
+}
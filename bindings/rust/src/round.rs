@@ -0,0 +1,1193 @@
+/*!
+# UMICP Federated Rounds
+
+A [`RoundCoordinator`] runs federated training rounds over any [`Transport`]. It replaces the
+registration, collection and broadcast code every deployment would otherwise write around
+[`FedAvg`].
+
+Messages are told apart by their [`FEDERATED_CAPABILITY`]:
+
+- `register`: a client joins. The coordinator remembers the connection it came from and
+  acknowledges it. Envelopes built by [`registration`] carry this.
+- `round`: the coordinator announces a round to every registered client. The envelope carries
+  the current global model as its payload, the round number in [`ROUND_CAPABILITY`], and the
+  milliseconds left to answer in [`DEADLINE_CAPABILITY`]. Clients read it with
+  [`RoundAnnouncement::from_envelope`].
+- `update`: a client answers with the weights it trained and its sample count. Build the reply
+  with [`RoundAnnouncement::update_envelope`], or with [`RoundAnnouncement::delta_envelope`] to
+  send only the changes to the announced model as a [`ModelDelta`].
+- `model`: once enough updates are in, the coordinator aggregates them and sends the new global
+  model to every registered client. Clients read it with [`GlobalModel::from_envelope`].
+- `evaluation`: a client reports how the model of a past round did on its data, as built by
+  [`EvaluationReport::to_envelope`]. The coordinator aggregates the reports of each round, see
+  [`metrics`](RoundCoordinator::metrics).
+
+A round closes as soon as `quorum` updates have arrived, by default one from every client it was
+announced to. If the deadline passes first, [`run_round`](RoundCoordinator::run_round) fails with
+a `Timeout` error and the global model is left as it was. Updates are only taken from clients the
+round was announced to, on the connection they registered from. Updates for another round are
+ignored.
+
+A coordinator created with [`open`](RoundCoordinator::open) checkpoints its state to a file:
+registered clients, the global model, and the round being collected with every update accepted
+so far, from which the partial aggregate is rebuilt. The file is a snapshot followed by a JSON
+line per registration and per update, synced as each is written, and is rewritten as a single
+snapshot whenever a round starts or ends. A restarted coordinator reopens the file and the next
+[`run_round`](RoundCoordinator::run_round) resumes the interrupted round: it announces it again
+to the clients that have not sent an update yet and waits a full deadline for the rest, keeping
+the updates received before the crash. Clients that reconnect register again, which moves the
+round to their new connection. A registration or update that cannot be written to the file is
+refused with an `Error` reply carrying the reason in its [`ERROR_CAPABILITY`], so the client can
+send it again.
+
+```rust,no_run
+use std::sync::Arc;
+use std::time::Duration;
+use umicp_core::round::{RoundConfig, RoundCoordinator};
+use umicp_core::Transport;
+
+# async fn example(server: impl Transport + 'static) -> umicp_core::Result<()> {
+// `server` is a connected server transport the clients reach
+let config = RoundConfig {
+    quorum: 8,
+    deadline: Duration::from_secs(300),
+    ..RoundConfig::new("coordinator")
+};
+let coordinator = RoundCoordinator::new(Arc::new(server), config, vec![0.0; 1024]);
+coordinator.start();
+for _ in 0..10 {
+    let summary = coordinator.run_round().await?;
+    println!("round {}: {} updates, {} samples", summary.round, summary.clients.len(), summary.total_samples);
+}
+# Ok(())
+# }
+```
+*/
+
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::delta::ModelDelta;
+use crate::evaluation::{EvaluationReport, MetricsAggregator, RoundMetrics, EVALUATION};
+use crate::federated::{self, FedAvg, ModelUpdate, SAMPLE_COUNT_CAPABILITY};
+use crate::transport::{Transport, ERROR_CAPABILITY};
+use crate::types::OperationType;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, watch};
+
+/// Capability naming the kind of federated round message
+pub const FEDERATED_CAPABILITY: &str = "federated";
+
+/// Capability carrying the round number
+pub const ROUND_CAPABILITY: &str = "round";
+
+/// Capability of a round announcement giving the milliseconds left to send an update
+pub const DEADLINE_CAPABILITY: &str = "round_deadline_ms";
+
+const REGISTER: &str = "register";
+const ANNOUNCE: &str = "round";
+const UPDATE: &str = "update";
+const MODEL: &str = "model";
+
+fn kind(envelope: &Envelope) -> Option<&str> {
+    envelope
+        .capabilities()?
+        .get(FEDERATED_CAPABILITY)
+        .map(String::as_str)
+}
+
+fn round_of(envelope: &Envelope) -> Result<u64> {
+    envelope
+        .capabilities()
+        .and_then(|capabilities| capabilities.get(ROUND_CAPABILITY))
+        .and_then(|round| round.parse().ok())
+        .ok_or_else(|| UmicpError::validation(format!("Round message from {} has no round number", envelope.from())))
+}
+
+/// Envelope registering `client` with `coordinator` for the rounds to come
+pub fn registration(client: &str, coordinator: &str) -> Result<Envelope> {
+    Envelope::builder()
+        .from(client)
+        .to(coordinator)
+        .operation(OperationType::Control)
+        .capability(FEDERATED_CAPABILITY, REGISTER)
+        .build()
+}
+
+/// A round as announced to a client
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoundAnnouncement {
+    /// Round number
+    pub round: u64,
+    /// Global model to train from
+    pub model: Vec<f32>,
+    /// Time left to send an update when the announcement was sent
+    pub deadline: Duration,
+    /// Coordinator to send the update to
+    pub coordinator: String,
+    /// Client the announcement was addressed to
+    pub client: String,
+}
+
+impl RoundAnnouncement {
+    /// The announcement `envelope` carries, if it is one
+    pub fn from_envelope(envelope: &Envelope) -> Result<Option<Self>> {
+        if kind(envelope) != Some(ANNOUNCE) {
+            return Ok(None);
+        }
+        let deadline = envelope
+            .capabilities()
+            .and_then(|capabilities| capabilities.get(DEADLINE_CAPABILITY))
+            .and_then(|deadline| deadline.parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or_default();
+        Ok(Some(RoundAnnouncement {
+            round: round_of(envelope)?,
+            model: federated::weights(envelope)?,
+            deadline,
+            coordinator: envelope.from().to_string(),
+            client: envelope.to().to_string(),
+        }))
+    }
+
+    /// Envelope answering the announcement with `weights` trained on `sample_count` samples
+    pub fn update_envelope(&self, weights: Vec<f32>, sample_count: u64) -> Result<Envelope> {
+        let mut envelope = ModelUpdate::new(&self.client, weights, sample_count).to_envelope(&self.coordinator)?;
+        envelope.add_capability(FEDERATED_CAPABILITY, UPDATE);
+        envelope.add_capability(ROUND_CAPABILITY, &self.round.to_string());
+        Ok(envelope)
+    }
+
+    /// Envelope answering the announcement with `delta`, computed against the announced model,
+    /// trained on `sample_count` samples
+    pub fn delta_envelope(&self, delta: &ModelDelta, sample_count: u64) -> Result<Envelope> {
+        if sample_count == 0 {
+            return Err(UmicpError::validation("Sample count must be positive"));
+        }
+        let mut envelope = delta.to_envelope(&self.client, &self.coordinator)?;
+        envelope.add_capability(SAMPLE_COUNT_CAPABILITY, &sample_count.to_string());
+        envelope.add_capability(FEDERATED_CAPABILITY, UPDATE);
+        envelope.add_capability(ROUND_CAPABILITY, &self.round.to_string());
+        Ok(envelope)
+    }
+}
+
+/// Global model sent to clients at the end of a round
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalModel {
+    /// Round that produced the model
+    pub round: u64,
+    /// Aggregated weights
+    pub weights: Vec<f32>,
+}
+
+impl GlobalModel {
+    /// The global model `envelope` carries, if it is one
+    pub fn from_envelope(envelope: &Envelope) -> Result<Option<Self>> {
+        if kind(envelope) != Some(MODEL) {
+            return Ok(None);
+        }
+        Ok(Some(GlobalModel {
+            round: round_of(envelope)?,
+            weights: federated::weights(envelope)?,
+        }))
+    }
+}
+
+/// Round settings
+#[derive(Debug, Clone)]
+pub struct RoundConfig {
+    /// ID the coordinator sends as
+    pub coordinator_id: String,
+    /// Updates that close a round (0 for one from every client it was announced to)
+    pub quorum: usize,
+    /// How long a round waits for its quorum
+    pub deadline: Duration,
+}
+
+impl RoundConfig {
+    /// Defaults for a coordinator called `coordinator_id`
+    pub fn new(coordinator_id: &str) -> Self {
+        RoundConfig {
+            coordinator_id: coordinator_id.to_string(),
+            quorum: 0,
+            deadline: Duration::from_secs(60),
+        }
+    }
+}
+
+/// How a round went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoundSummary {
+    /// Round number
+    pub round: u64,
+    /// Clients whose updates were aggregated
+    pub clients: Vec<String>,
+    /// Clients the round was announced to that sent no update
+    pub missing: Vec<String>,
+    /// Samples declared across the aggregated updates
+    pub total_samples: u64,
+}
+
+/// The round collecting updates
+struct Collecting {
+    round: u64,
+    /// Clients announced to, by the connection they registered from
+    announced: BTreeMap<String, String>,
+    quorum: usize,
+    fedavg: FedAvg,
+    /// Fired once the quorum is reached
+    done: Option<oneshot::Sender<()>>,
+    /// Read back from a checkpoint, waiting for `run_round` to resume it
+    recovered: bool,
+}
+
+struct State {
+    /// Connection of every registered client
+    clients: BTreeMap<String, String>,
+    round: u64,
+    model: Vec<f32>,
+    collecting: Option<Collecting>,
+    journal: Option<Journal>,
+    /// Evaluation reports by the round they are on
+    evaluations: BTreeMap<u64, MetricsAggregator>,
+}
+
+impl State {
+    /// Record `record` in the checkpoint, if there is one
+    fn journal(&mut self, record: Record) -> Result<()> {
+        match &mut self.journal {
+            Some(journal) => journal.append(&record),
+            None => Ok(()),
+        }
+    }
+
+    /// Replace the checkpoint with a snapshot of the state
+    fn checkpoint(&mut self) -> Result<()> {
+        let Some(mut journal) = self.journal.take() else {
+            return Ok(());
+        };
+        let result = journal.rewrite(self);
+        self.journal = Some(journal);
+        result
+    }
+
+    /// Apply a record read back from a checkpoint
+    fn replay(&mut self, record: Record) {
+        match record {
+            Record::State {
+                round,
+                model,
+                clients,
+                collecting,
+            } => {
+                self.round = round;
+                self.model = model;
+                self.clients = clients;
+                self.collecting = collecting.map(|pending| Collecting {
+                    round: pending.round,
+                    announced: pending.announced,
+                    quorum: pending.quorum,
+                    fedavg: FedAvg::new(),
+                    done: None,
+                    recovered: true,
+                });
+            }
+            Record::Register { client, connection_id } => self.register(client, connection_id),
+            Record::Unregister { client } => self.unregister(&client),
+            Record::Update {
+                round,
+                client,
+                weights,
+                sample_count,
+            } => {
+                if let Some(collecting) = self.collecting.as_mut().filter(|collecting| collecting.round == round) {
+                    let _ = collecting.fedavg.add(ModelUpdate::new(&client, weights, sample_count));
+                }
+            }
+        }
+    }
+
+    /// Reach `client` on `connection_id`, for the round being collected too
+    fn register(&mut self, client: String, connection_id: String) {
+        if let Some(announced) = self.collecting.as_mut().and_then(|collecting| collecting.announced.get_mut(&client)) {
+            announced.clone_from(&connection_id);
+        }
+        self.clients.insert(client, connection_id);
+    }
+
+    fn unregister(&mut self, client: &str) {
+        self.clients.remove(client);
+        if let Some(collecting) = &mut self.collecting {
+            collecting.announced.remove(client);
+        }
+    }
+}
+
+/// Leaves the round to be resumed if `run_round` is dropped before closing it
+struct RoundGuard<'a> {
+    shared: &'a Shared,
+    round: u64,
+}
+
+impl Drop for RoundGuard<'_> {
+    fn drop(&mut self) {
+        let Ok(mut state) = self.shared.state.lock() else {
+            return;
+        };
+        if let Some(collecting) = state.collecting.as_mut().filter(|collecting| collecting.round == self.round) {
+            collecting.done = None;
+            collecting.recovered = true;
+        }
+    }
+}
+
+/// Round being collected, as checkpointed
+#[derive(Debug, Serialize, Deserialize)]
+struct Pending {
+    round: u64,
+    announced: BTreeMap<String, String>,
+    quorum: usize,
+}
+
+/// One line of a checkpoint
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Record {
+    /// Everything but the updates, written first
+    State {
+        round: u64,
+        model: Vec<f32>,
+        clients: BTreeMap<String, String>,
+        collecting: Option<Pending>,
+    },
+    Register {
+        client: String,
+        connection_id: String,
+    },
+    Unregister {
+        client: String,
+    },
+    Update {
+        round: u64,
+        client: String,
+        weights: Vec<f32>,
+        sample_count: u64,
+    },
+}
+
+impl Record {
+    fn update(round: u64, update: &ModelUpdate) -> Self {
+        Record::Update {
+            round,
+            client: update.client.clone(),
+            weights: update.weights.clone(),
+            sample_count: update.sample_count,
+        }
+    }
+}
+
+/// Checkpoint file: a snapshot followed by the changes since
+struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Read the checkpoint at `path` into `state`, or start one from `state` if there is none
+    fn open(path: &Path, state: &mut State) -> Result<Self> {
+        if path.exists() {
+            let lines: Vec<String> = BufReader::new(File::open(path)?).lines().collect::<std::io::Result<_>>()?;
+            for (index, line) in lines.iter().enumerate() {
+                let record = match serde_json::from_str(line) {
+                    Ok(record) => record,
+                    // Only an append can be cut short by a crash; snapshots are written whole
+                    Err(_) if index > 0 && index + 1 == lines.len() => break,
+                    Err(e) => {
+                        return Err(UmicpError::serialization(format!(
+                            "Round checkpoint {} is corrupt at line {}: {}",
+                            path.display(),
+                            index + 1,
+                            e
+                        )))
+                    }
+                };
+                if index == 0 && !matches!(record, Record::State { .. }) {
+                    return Err(UmicpError::serialization(format!(
+                        "Round checkpoint {} does not start with a snapshot",
+                        path.display()
+                    )));
+                }
+                state.replay(record);
+            }
+        }
+        let mut journal = Journal {
+            path: path.to_path_buf(),
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+        };
+        journal.rewrite(state)?;
+        Ok(journal)
+    }
+
+    fn append(&mut self, record: &Record) -> Result<()> {
+        writeln!(self.file, "{}", encode(record)?)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Replace the file with a snapshot of `state`
+    fn rewrite(&mut self, state: &State) -> Result<()> {
+        let temp = self.path.with_extension("compact");
+        let mut file = File::create(&temp)?;
+        let snapshot = Record::State {
+            round: state.round,
+            model: state.model.clone(),
+            clients: state.clients.clone(),
+            collecting: state.collecting.as_ref().map(|collecting| Pending {
+                round: collecting.round,
+                announced: collecting.announced.clone(),
+                quorum: collecting.quorum,
+            }),
+        };
+        writeln!(file, "{}", encode(&snapshot)?)?;
+        if let Some(collecting) = &state.collecting {
+            for update in collecting.fedavg.updates() {
+                writeln!(file, "{}", encode(&Record::update(collecting.round, update))?)?;
+            }
+        }
+        file.sync_all()?;
+        std::fs::rename(&temp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// `Error` reply refusing `envelope` for `error`
+fn refusal(envelope: &Envelope, error: &UmicpError) -> Envelope {
+    let mut reply = envelope.reply(OperationType::Error);
+    reply.add_capability(ERROR_CAPABILITY, &error.to_string());
+    reply
+}
+
+fn encode(record: &Record) -> Result<String> {
+    serde_json::to_string(record)
+        .map_err(|e| UmicpError::serialization(format!("Failed to encode round checkpoint: {}", e)))
+}
+
+struct Shared {
+    transport: Arc<dyn Transport>,
+    config: RoundConfig,
+    state: Mutex<State>,
+    shutdown: watch::Sender<bool>,
+}
+
+/// Runs federated rounds over a transport
+#[derive(Clone)]
+pub struct RoundCoordinator {
+    shared: Arc<Shared>,
+}
+
+impl RoundCoordinator {
+    /// Create a coordinator over `transport` starting from the global model `model`
+    pub fn new(transport: Arc<dyn Transport>, config: RoundConfig, model: Vec<f32>) -> Self {
+        let (shutdown, _) = watch::channel(false);
+        RoundCoordinator {
+            shared: Arc::new(Shared {
+                transport,
+                config,
+                state: Mutex::new(State {
+                    clients: BTreeMap::new(),
+                    round: 0,
+                    model,
+                    collecting: None,
+                    journal: None,
+                    evaluations: BTreeMap::new(),
+                }),
+                shutdown,
+            }),
+        }
+    }
+
+    /// Create a coordinator checkpointing its state to `path`, picking up where the coordinator
+    /// that last used the file left off
+    ///
+    /// `model` is only used when the file does not exist yet. A last line cut short by a crash
+    /// is dropped; any other line that cannot be read fails with a `Serialization` error and
+    /// leaves the file as it is.
+    pub fn open(
+        transport: Arc<dyn Transport>,
+        config: RoundConfig,
+        model: Vec<f32>,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let coordinator = RoundCoordinator::new(transport, config, model);
+        {
+            let mut state = coordinator.shared.state.lock().unwrap();
+            let journal = Journal::open(path.as_ref(), &mut state)?;
+            state.journal = Some(journal);
+        }
+        Ok(coordinator)
+    }
+
+    /// Start taking registrations and updates; runs until [`stop`](RoundCoordinator::stop)
+    pub fn start(&self) {
+        let mut incoming = self.shared.transport.subscribe();
+        let coordinator = self.clone();
+        let mut shutdown = self.shared.shutdown.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let received = tokio::select! {
+                    _ = shutdown.wait_for(|stopped| *stopped) => break,
+                    received = incoming.recv() => received,
+                };
+                let Some((envelope, conn_id)) = received else {
+                    break;
+                };
+                coordinator.receive(envelope, &conn_id).await;
+            }
+        });
+    }
+
+    /// Stop taking registrations and updates
+    pub fn stop(&self) {
+        self.shared.shutdown.send_replace(true);
+    }
+
+    /// Registered clients, sorted
+    pub fn clients(&self) -> Vec<String> {
+        self.shared.state.lock().unwrap().clients.keys().cloned().collect()
+    }
+
+    /// Register `client` as reachable on `connection_id`, as a registration envelope would
+    ///
+    /// A client the current round was announced to sends its update on the new connection. Fails,
+    /// leaving the client as it was, if the registration cannot be checkpointed.
+    pub fn register(&self, client: &str, connection_id: &str) -> Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.clients.get(client).map(String::as_str) == Some(connection_id) {
+            return Ok(());
+        }
+        state.journal(Record::Register {
+            client: client.to_string(),
+            connection_id: connection_id.to_string(),
+        })?;
+        state.register(client.to_string(), connection_id.to_string());
+        Ok(())
+    }
+
+    /// Stop announcing rounds to `client`
+    ///
+    /// Fails, leaving the client registered, if the change cannot be checkpointed.
+    pub fn unregister(&self, client: &str) -> Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.journal(Record::Unregister {
+            client: client.to_string(),
+        })?;
+        state.unregister(client);
+        Ok(())
+    }
+
+    /// Number of the last round started, 0 before the first
+    pub fn round(&self) -> u64 {
+        self.shared.state.lock().unwrap().round
+    }
+
+    /// Current global model
+    pub fn model(&self) -> Vec<f32> {
+        self.shared.state.lock().unwrap().model.clone()
+    }
+
+    /// Metrics reported by clients on the model of `round`, once any have
+    pub fn metrics(&self, round: u64) -> Option<RoundMetrics> {
+        let state = self.shared.state.lock().unwrap();
+        state.evaluations.get(&round).map(MetricsAggregator::summary)
+    }
+
+    /// Announce a round, collect updates until the quorum or the deadline, then aggregate them
+    /// and send the new global model to every registered client
+    ///
+    /// Clients that cannot be reached when the round is announced are unregistered, and the
+    /// default quorum stops counting them; a configured quorum the rest cannot meet fails the
+    /// round. A round read back from a checkpoint is resumed instead, announced again only to the
+    /// clients that have not sent an update; those that cannot be reached stay registered, to be
+    /// reached on the connection they register from next. A round whose future is dropped before
+    /// it closes is resumed the same way by the next call.
+    pub async fn run_round(&self) -> Result<RoundSummary> {
+        let (done, quorum_reached) = oneshot::channel();
+        let (round, announced, model, resumed) = {
+            let mut state = self.shared.state.lock().unwrap();
+            let state = &mut *state;
+            match &mut state.collecting {
+                Some(collecting) if collecting.recovered => {
+                    collecting.recovered = false;
+                    if collecting.fedavg.len() >= collecting.quorum {
+                        let _ = done.send(());
+                    } else {
+                        collecting.done = Some(done);
+                    }
+                    let mut waiting = collecting.announced.clone();
+                    for client in collecting.fedavg.clients() {
+                        waiting.remove(client);
+                    }
+                    (collecting.round, waiting, state.model.clone(), true)
+                }
+                Some(_) => return Err(UmicpError::validation(format!("Round {} is still running", state.round))),
+                None => {
+                    let (round, announced, model) = self.open_round(state, done)?;
+                    (round, announced, model, false)
+                }
+            }
+        };
+        let _cancelled = RoundGuard { shared: &self.shared, round };
+
+        let deadline = self.shared.config.deadline;
+        for (client, conn_id) in &announced {
+            let announcement = self.envelope(client, ANNOUNCE, round, &model).map(|mut envelope| {
+                envelope.add_capability(DEADLINE_CAPABILITY, &deadline.as_millis().to_string());
+                envelope
+            });
+            if self.send(announcement, conn_id).await.is_err() && !resumed {
+                self.unregister(client)?;
+            }
+        }
+        if !resumed {
+            self.settle_quorum(round)?;
+        }
+
+        let _ = tokio::time::timeout(deadline, quorum_reached).await;
+        let collecting = self.shared.state.lock().unwrap().collecting.take();
+        let Some(collecting) = collecting else {
+            return Err(UmicpError::generic(format!("Round {} was abandoned", round)));
+        };
+        if collecting.fedavg.len() < collecting.quorum {
+            self.shared.state.lock().unwrap().checkpoint()?;
+            return Err(UmicpError::timeout(format!(
+                "Round {} closed with {} of the {} updates needed",
+                round,
+                collecting.fedavg.len(),
+                collecting.quorum
+            )));
+        }
+
+        let weights = collecting.fedavg.aggregate()?;
+        let clients: Vec<String> = collecting.fedavg.clients().map(str::to_string).collect();
+        let missing = collecting
+            .announced
+            .keys()
+            .filter(|client| !clients.contains(client))
+            .cloned()
+            .collect();
+        let summary = RoundSummary {
+            round,
+            clients,
+            missing,
+            total_samples: collecting.fedavg.total_samples(),
+        };
+        let registered = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.model = weights.clone();
+            state.checkpoint()?;
+            state.clients.clone()
+        };
+        for (client, conn_id) in &registered {
+            // A client that missed the model gets the next round's announcement
+            let _ = self.send(self.envelope(client, MODEL, round, &weights), conn_id).await;
+        }
+        Ok(summary)
+    }
+
+    /// Start the next round, to be announced to every registered client
+    fn open_round(
+        &self,
+        state: &mut State,
+        done: oneshot::Sender<()>,
+    ) -> Result<(u64, BTreeMap<String, String>, Vec<f32>)> {
+        if state.clients.is_empty() {
+            return Err(UmicpError::validation("No clients are registered"));
+        }
+        let quorum = match self.shared.config.quorum {
+            0 => state.clients.len(),
+            quorum => quorum,
+        };
+        if quorum > state.clients.len() {
+            return Err(UmicpError::validation(format!(
+                "Quorum of {} exceeds the {} registered clients",
+                quorum,
+                state.clients.len()
+            )));
+        }
+        state.round += 1;
+        state.collecting = Some(Collecting {
+            round: state.round,
+            announced: state.clients.clone(),
+            quorum,
+            fedavg: FedAvg::new(),
+            done: Some(done),
+            recovered: false,
+        });
+        if let Err(error) = state.checkpoint() {
+            state.collecting = None;
+            state.round -= 1;
+            return Err(error);
+        }
+        Ok((state.round, state.clients.clone(), state.model.clone()))
+    }
+
+    /// Fit the quorum of `round` to the clients its announcement reached
+    ///
+    /// The default quorum shrinks to the clients left; a configured one that they can no longer
+    /// meet ends the round.
+    fn settle_quorum(&self, round: u64) -> Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        let Some(collecting) = state.collecting.as_mut().filter(|collecting| collecting.round == round) else {
+            return Ok(());
+        };
+        let reached = collecting.announced.len();
+        if self.shared.config.quorum == 0 {
+            collecting.quorum = reached;
+        }
+        if collecting.quorum == 0 || collecting.quorum > reached {
+            let quorum = collecting.quorum;
+            state.collecting = None;
+            state.checkpoint()?;
+            return Err(UmicpError::validation(format!(
+                "Quorum of {} exceeds the {} clients round {} reached",
+                quorum, reached, round
+            )));
+        }
+        if collecting.fedavg.len() >= collecting.quorum {
+            if let Some(done) = collecting.done.take() {
+                let _ = done.send(());
+            }
+        }
+        state.checkpoint()
+    }
+
+    fn envelope(&self, client: &str, kind: &str, round: u64, weights: &[f32]) -> Result<Envelope> {
+        let operation = match kind {
+            MODEL => OperationType::Data,
+            _ => OperationType::Control,
+        };
+        Envelope::builder()
+            .from(&self.shared.config.coordinator_id)
+            .to(client)
+            .operation(operation)
+            .capability(FEDERATED_CAPABILITY, kind)
+            .capability(ROUND_CAPABILITY, &round.to_string())
+            .build()
+            .map(|envelope| federated::with_weights(envelope, weights))
+    }
+
+    async fn send(&self, envelope: Result<Envelope>, conn_id: &str) -> Result<()> {
+        self.shared.transport.send(envelope?, Some(conn_id)).await
+    }
+
+    /// Take a registration or an update; other envelopes are ignored
+    async fn receive(&self, envelope: Envelope, conn_id: &str) {
+        match kind(&envelope) {
+            Some(REGISTER) => {
+                let reply = match self.register(envelope.from(), conn_id) {
+                    Ok(()) => envelope.reply(OperationType::Ack),
+                    Err(error) => refusal(&envelope, &error),
+                };
+                let _ = self.send(Ok(reply), conn_id).await;
+            }
+            Some(UPDATE) => {
+                // Malformed or unexpected updates are dropped; the round's deadline covers them.
+                // One that could not be checkpointed was not counted either, and is refused
+                if let Err(error @ UmicpError::Io(_)) = self.collect(&envelope, conn_id) {
+                    let _ = self.send(Ok(refusal(&envelope, &error)), conn_id).await;
+                }
+            }
+            Some(EVALUATION) => {
+                let _ = self.evaluate(&envelope, conn_id);
+            }
+            _ => {}
+        }
+    }
+
+    fn collect(&self, envelope: &Envelope, conn_id: &str) -> Result<()> {
+        let round = round_of(envelope)?;
+        let mut state = self.shared.state.lock().unwrap();
+        let state = &mut *state;
+        let Some(collecting) = state.collecting.as_mut().filter(|collecting| collecting.round == round) else {
+            return Err(UmicpError::validation(format!("Update from {} is for round {}", envelope.from(), round)));
+        };
+        if collecting.announced.get(envelope.from()).map(String::as_str) != Some(conn_id) {
+            return Err(UmicpError::forbidden(format!("Round {} was not announced to {}", round, envelope.from())));
+        }
+        // The model only changes once the round is over, so deltas are against the one announced
+        let update = ModelUpdate::from_envelope_with_base(envelope, &state.model)?;
+        // Checkpointed before it is counted, so a restart never lacks an update that was; one
+        // the aggregate then refuses is refused again on replay
+        if let Some(journal) = &mut state.journal {
+            journal.append(&Record::update(round, &update))?;
+        }
+        collecting.fedavg.add(update)?;
+        if collecting.fedavg.len() >= collecting.quorum {
+            if let Some(done) = collecting.done.take() {
+                let _ = done.send(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Take an evaluation report from a registered client on a finished round
+    fn evaluate(&self, envelope: &Envelope, conn_id: &str) -> Result<()> {
+        let report = EvaluationReport::from_envelope(envelope)?;
+        let mut state = self.shared.state.lock().unwrap();
+        if state.clients.get(&report.client).map(String::as_str) != Some(conn_id) {
+            return Err(UmicpError::forbidden(format!("{} is not registered on {}", report.client, conn_id)));
+        }
+        let finished = match &state.collecting {
+            Some(collecting) => collecting.round - 1,
+            None => state.round,
+        };
+        if report.round == 0 || report.round > finished {
+            return Err(UmicpError::validation(format!("Round {} has no model to evaluate", report.round)));
+        }
+        let round = report.round;
+        state
+            .evaluations
+            .entry(round)
+            .or_insert_with(|| MetricsAggregator::new(round))
+            .add(report)
+    }
+}
+
+impl std::fmt::Debug for RoundCoordinator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.shared.state.lock().unwrap();
+        f.debug_struct("RoundCoordinator")
+            .field("coordinator_id", &self.shared.config.coordinator_id)
+            .field("round", &state.round)
+            .field("clients", &state.clients.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{MockFault, MockTransport};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_round_lifecycle() {
+        let mock = MockTransport::with_peer_id("conn-1");
+        mock.connect().await.unwrap();
+        let config = RoundConfig {
+            deadline: Duration::from_secs(30),
+            ..RoundConfig::new("coordinator")
+        };
+        let coordinator = RoundCoordinator::new(Arc::new(mock.clone()), config, vec![0.0, 0.0]);
+        coordinator.start();
+        for client in ["a", "b"] {
+            mock.inject(registration(client, "coordinator").unwrap()).await.unwrap();
+        }
+        tokio::task::yield_now().await;
+        assert_eq!(coordinator.clients(), vec!["a", "b"]);
+        assert_eq!(mock.take_sent().len(), 2);
+
+        // Both clients answer the announcement, one with a delta; a stale update is ignored
+        let running = tokio::spawn({
+            let coordinator = coordinator.clone();
+            async move { coordinator.run_round().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let sent = mock.take_sent();
+        assert_eq!(sent.len(), 2);
+        let announcement = RoundAnnouncement::from_envelope(&sent[0].envelope).unwrap().unwrap();
+        assert_eq!((announcement.round, announcement.client.as_str()), (1, "a"));
+        assert_eq!((&announcement.model[..], announcement.deadline), (&[0.0, 0.0][..], Duration::from_secs(30)));
+        let announcement_b = RoundAnnouncement::from_envelope(&sent[1].envelope).unwrap().unwrap();
+        let stale = RoundAnnouncement { round: 7, ..announcement_b.clone() };
+        mock.inject(stale.update_envelope(vec![9.0, 9.0], 100).unwrap()).await.unwrap();
+        mock.inject(announcement.update_envelope(vec![4.0, 0.0], 1).unwrap()).await.unwrap();
+        let delta = ModelDelta::compute(&announcement_b.model, &[0.0, 4.0]).unwrap();
+        mock.inject(announcement_b.delta_envelope(&delta, 3).unwrap()).await.unwrap();
+
+        let summary = running.await.unwrap().unwrap();
+        assert_eq!(summary.clients, vec!["a", "b"]);
+        assert_eq!(summary.total_samples, 4);
+        assert_eq!(coordinator.model(), vec![1.0, 3.0]);
+        let models: Vec<GlobalModel> = mock
+            .take_sent()
+            .iter()
+            .filter_map(|sent| GlobalModel::from_envelope(&sent.envelope).unwrap())
+            .collect();
+        assert_eq!(models.len(), 2);
+        assert_eq!(models[0], GlobalModel { round: 1, weights: vec![1.0, 3.0] });
+
+        // Clients report how the new model does; reports on rounds not yet run are ignored
+        let mut report = EvaluationReport::new("a", 1, 50);
+        report.loss = Some(0.25);
+        mock.inject(report.to_envelope("coordinator").unwrap()).await.unwrap();
+        report.round = 2;
+        mock.inject(report.to_envelope("coordinator").unwrap()).await.unwrap();
+        tokio::task::yield_now().await;
+        let metrics = coordinator.metrics(1).unwrap();
+        assert_eq!((metrics.clients, metrics.loss), (vec!["a".to_string()], Some(0.25)));
+        assert!(coordinator.metrics(2).is_none());
+
+        // Without a quorum by the deadline the round fails and the model stays
+        let running = tokio::spawn({
+            let coordinator = coordinator.clone();
+            async move { coordinator.run_round().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let announcement = RoundAnnouncement::from_envelope(&mock.take_sent()[0].envelope).unwrap().unwrap();
+        mock.inject(announcement.update_envelope(vec![5.0, 5.0], 1).unwrap()).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        let error = running.await.unwrap().unwrap_err();
+        assert!(matches!(error, UmicpError::Timeout { .. }), "{}", error);
+        assert_eq!((coordinator.round(), coordinator.model()), (2, vec![1.0, 3.0]));
+
+        // A round whose caller gives up is resumed by the next call, keeping its updates
+        let running = tokio::spawn({
+            let coordinator = coordinator.clone();
+            async move { coordinator.run_round().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let announcement = RoundAnnouncement::from_envelope(&mock.take_sent()[0].envelope).unwrap().unwrap();
+        mock.inject(announcement.update_envelope(vec![5.0, 5.0], 1).unwrap()).await.unwrap();
+        running.abort();
+        let _ = running.await;
+        let running = tokio::spawn({
+            let coordinator = coordinator.clone();
+            async move { coordinator.run_round().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let sent = mock.take_sent();
+        assert_eq!(sent.len(), 1);
+        let announcement = RoundAnnouncement::from_envelope(&sent[0].envelope).unwrap().unwrap();
+        assert_eq!((announcement.round, announcement.client.as_str()), (3, "b"));
+        mock.inject(announcement.update_envelope(vec![1.0, 1.0], 1).unwrap()).await.unwrap();
+        assert_eq!(running.await.unwrap().unwrap().clients, vec!["a", "b"]);
+        assert_eq!(coordinator.model(), vec![3.0, 3.0]);
+        coordinator.stop();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_round_quorum_with_unreachable_client() {
+        for quorum in [0, 3] {
+            let mock = MockTransport::with_peer_id("conn-1");
+            mock.connect().await.unwrap();
+            let config = RoundConfig {
+                quorum,
+                deadline: Duration::from_secs(30),
+                ..RoundConfig::new("coordinator")
+            };
+            let coordinator = RoundCoordinator::new(Arc::new(mock.clone()), config, vec![0.0]);
+            coordinator.start();
+            for client in ["a", "b", "c"] {
+                mock.inject(registration(client, "coordinator").unwrap()).await.unwrap();
+            }
+            tokio::task::yield_now().await;
+            mock.take_sent();
+
+            // The announcement to "a" fails, so the round is only announced to the other two
+            mock.script_send(MockFault::Error);
+            let running = tokio::spawn({
+                let coordinator = coordinator.clone();
+                async move { coordinator.run_round().await }
+            });
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert_eq!(coordinator.clients(), vec!["b", "c"]);
+            if quorum == 3 {
+                // A configured quorum the clients left cannot meet ends the round at once
+                let error = running.await.unwrap().unwrap_err();
+                assert!(matches!(error, UmicpError::Validation { .. }), "{}", error);
+                coordinator.stop();
+                continue;
+            }
+            for sent in mock.take_sent() {
+                let announcement = RoundAnnouncement::from_envelope(&sent.envelope).unwrap().unwrap();
+                mock.inject(announcement.update_envelope(vec![1.0], 1).unwrap()).await.unwrap();
+            }
+            let summary = running.await.unwrap().unwrap();
+            assert_eq!((summary.clients, summary.missing), (vec!["b".to_string(), "c".to_string()], vec![]));
+            coordinator.stop();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_round_refuses_what_it_cannot_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("umicp-round-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("round.log");
+        let mock = MockTransport::with_peer_id("conn-1");
+        mock.connect().await.unwrap();
+        let coordinator =
+            RoundCoordinator::open(Arc::new(mock.clone()), RoundConfig::new("coordinator"), vec![0.0], &path).unwrap();
+        coordinator.register("a", "conn-1").unwrap();
+        coordinator.start();
+        let running = tokio::spawn({
+            let coordinator = coordinator.clone();
+            async move { coordinator.run_round().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let announcement = RoundAnnouncement::from_envelope(&mock.take_sent()[0].envelope).unwrap().unwrap();
+
+        // With the checkpoint unwritable, nothing is taken and the senders are told
+        let set_journal_file = |file: File| {
+            coordinator.shared.state.lock().unwrap().journal.as_mut().unwrap().file = file;
+        };
+        set_journal_file(File::open(&path).unwrap());
+        assert!(matches!(coordinator.register("b", "conn-1"), Err(UmicpError::Io(_))));
+        assert!(matches!(coordinator.unregister("a"), Err(UmicpError::Io(_))));
+        mock.inject(registration("c", "coordinator").unwrap()).await.unwrap();
+        mock.inject(announcement.update_envelope(vec![4.0], 1).unwrap()).await.unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(coordinator.clients(), vec!["a"]);
+        let replies = mock.take_sent();
+        assert_eq!(replies.len(), 2);
+        assert!(replies.iter().all(|sent| sent.envelope.operation() == OperationType::Error));
+        assert!(!running.is_finished());
+
+        // Sent again once the checkpoint can be written, the update closes the round
+        set_journal_file(OpenOptions::new().append(true).open(&path).unwrap());
+        mock.inject(announcement.update_envelope(vec![4.0], 1).unwrap()).await.unwrap();
+        assert_eq!(running.await.unwrap().unwrap().clients, vec!["a"]);
+        assert_eq!(coordinator.model(), vec![4.0]);
+        coordinator.stop();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_round_resumes_after_restart() {
+        let dir = std::env::temp_dir().join(format!("umicp-round-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("round.log");
+        let config = RoundConfig::new("coordinator");
+
+        let mock = MockTransport::with_peer_id("conn-1");
+        mock.connect().await.unwrap();
+        let transport = Arc::new(mock.clone());
+        let coordinator = RoundCoordinator::open(transport, config.clone(), vec![0.0, 0.0], &path).unwrap();
+        coordinator.start();
+        for client in ["a", "b"] {
+            mock.inject(registration(client, "coordinator").unwrap()).await.unwrap();
+        }
+        tokio::task::yield_now().await;
+        let running = tokio::spawn({
+            let coordinator = coordinator.clone();
+            async move { coordinator.run_round().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let sent = mock.take_sent();
+        let announcement = RoundAnnouncement::from_envelope(&sent[2].envelope).unwrap().unwrap();
+        mock.inject(announcement.update_envelope(vec![4.0, 0.0], 1).unwrap()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // The aggregator dies with one of the two updates in
+        running.abort();
+        coordinator.stop();
+        drop(coordinator);
+
+        let mock = MockTransport::with_peer_id("conn-2");
+        mock.connect().await.unwrap();
+        let transport = Arc::new(mock.clone());
+        let coordinator = RoundCoordinator::open(transport, config.clone(), vec![9.0, 9.0], &path).unwrap();
+        assert_eq!((coordinator.round(), coordinator.model()), (1, vec![0.0, 0.0]));
+        coordinator.start();
+        mock.inject(registration("b", "coordinator").unwrap()).await.unwrap();
+        tokio::task::yield_now().await;
+        let running = tokio::spawn({
+            let coordinator = coordinator.clone();
+            async move { coordinator.run_round().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // Only the client still owing an update hears of the round again, on its new connection
+        let sent = mock.take_sent();
+        assert_eq!(sent.len(), 2);
+        let announcement = RoundAnnouncement::from_envelope(&sent[1].envelope).unwrap().unwrap();
+        assert_eq!((announcement.round, announcement.client.as_str()), (1, "b"));
+        mock.inject(announcement.update_envelope(vec![0.0, 4.0], 3).unwrap()).await.unwrap();
+        let summary = running.await.unwrap().unwrap();
+        assert_eq!((summary.clients, summary.total_samples), (vec!["a".to_string(), "b".to_string()], 4));
+        assert_eq!(coordinator.model(), vec![1.0, 3.0]);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        coordinator.stop();
+
+        // A torn last line is dropped, but a bad line before it leaves the file alone
+        let snapshot = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("{}{{\"op\":\"unreg", snapshot)).unwrap();
+        let transport = Arc::new(mock.clone());
+        assert!(RoundCoordinator::open(transport.clone(), config.clone(), vec![], &path).is_ok());
+        let corrupt = format!("{}garbage\n{}", snapshot, snapshot);
+        std::fs::write(&path, &corrupt).unwrap();
+        let error = RoundCoordinator::open(transport, config, vec![], &path).unwrap_err();
+        assert!(matches!(error, UmicpError::Serialization { .. }), "{}", error);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), corrupt);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_round_resent_update_after_restart() {
+        let dir = std::env::temp_dir().join(format!("umicp-round-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("round.log");
+        let config = RoundConfig::new("coordinator");
+        let restart = |conn_id: &str, clients: &[&str]| {
+            let mock = MockTransport::with_peer_id(conn_id);
+            let transport = Arc::new(mock.clone());
+            let coordinator = RoundCoordinator::open(transport, config.clone(), vec![0.0, 0.0], &path).unwrap();
+            for client in clients {
+                coordinator.register(client, conn_id).unwrap();
+            }
+            coordinator.start();
+            let running = tokio::spawn({
+                let coordinator = coordinator.clone();
+                async move { coordinator.run_round().await }
+            });
+            (mock, coordinator, running)
+        };
+
+        let (mock, coordinator, running) = restart("conn-1", &["a", "b"]);
+        mock.connect().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let announcement = RoundAnnouncement::from_envelope(&mock.take_sent()[0].envelope).unwrap().unwrap();
+        assert_eq!(announcement.client, "a");
+        let update = announcement.update_envelope(vec![4.0, 0.0], 1).unwrap();
+        mock.inject(update.clone()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // The process dies once the update is journaled
+        running.abort();
+        coordinator.stop();
+        drop(coordinator);
+
+        // Not having seen the model, the client sends its update again to the restarted process;
+        // counted once, it does not make the quorum of two, and the process dies again
+        let (mock, coordinator, running) = restart("conn-2", &["a"]);
+        mock.connect().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        mock.inject(update).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!running.is_finished());
+        running.abort();
+        coordinator.stop();
+        drop(coordinator);
+
+        // Replaying both copies of the update still counts it once
+        let (mock, coordinator, running) = restart("conn-3", &["b"]);
+        mock.connect().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let sent = mock.take_sent();
+        assert_eq!(sent.len(), 1);
+        let announcement = RoundAnnouncement::from_envelope(&sent[0].envelope).unwrap().unwrap();
+        assert_eq!((announcement.round, announcement.client.as_str()), (1, "b"));
+        mock.inject(announcement.update_envelope(vec![0.0, 4.0], 3).unwrap()).await.unwrap();
+        let summary = running.await.unwrap().unwrap();
+        assert_eq!((summary.clients, summary.total_samples), (vec!["a".to_string(), "b".to_string()], 4));
+        assert_eq!(coordinator.model(), vec![1.0, 3.0]);
+        coordinator.stop();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,311 @@
+/*!
+# UMICP Message Router
+
+Rule-based dispatch of envelopes to handlers, other connections, or nowhere.
+
+A [`MessageRouter`] holds an ordered list of routes. Each route pairs a [`Rule`], matching on the
+envelope's `to` address, operation and capabilities, with an [`Action`]. Routes are tried in the
+order they were added and the first match wins, unless it was added with
+[`route_through`](MessageRouter::route_through): its action runs and matching falls through to
+the routes after it. Envelopes no route claims go to the fallback action, which drops them unless
+set otherwise.
+
+```rust,no_run
+# async fn example(upstream: std::sync::Arc<dyn umicp_core::Transport>, clients: std::sync::Arc<dyn umicp_core::Transport>) {
+use umicp_core::router::{Action, MessageRouter, Rule};
+use umicp_core::OperationType;
+
+// A small broker: audit everything, drop heartbeats, relay the rest by address
+let router = MessageRouter::new()
+    .route_through(Rule::any(), Action::handler(|envelope, conn_id| async move {
+        println!("{} -> {} via {}", envelope.from(), envelope.to(), conn_id);
+        Ok(())
+    }))
+    .route(Rule::any().capability("kind", "heartbeat"), Action::Drop)
+    .route(Rule::any().to("upstream.*"), Action::forward(upstream, None))
+    .route(Rule::any().operation(OperationType::Data), Action::forward_by_address(clients.clone()));
+router.attach(clients);
+# }
+```
+*/
+
+use crate::envelope::Envelope;
+use crate::error::Result;
+use crate::transport::{HandlerFuture, MessageHandler, Transport};
+use crate::types::OperationType;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+/// Conditions an envelope must meet for a route to apply; every condition set must hold
+#[derive(Debug, Clone, Default)]
+pub struct Rule {
+    to: Option<String>,
+    operation: Option<OperationType>,
+    /// Required capabilities; `None` values only require the key
+    capabilities: Vec<(String, Option<String>)>,
+}
+
+impl Rule {
+    /// Rule matching every envelope, to be narrowed with the builder methods
+    pub fn any() -> Self {
+        Rule::default()
+    }
+
+    /// Match the `to` address exactly, or by prefix when the pattern ends in `*`
+    pub fn to(mut self, pattern: &str) -> Self {
+        self.to = Some(pattern.to_string());
+        self
+    }
+
+    /// Match the operation
+    pub fn operation(mut self, operation: OperationType) -> Self {
+        self.operation = Some(operation);
+        self
+    }
+
+    /// Require a capability with the given value
+    pub fn capability(mut self, key: &str, value: &str) -> Self {
+        self.capabilities.push((key.to_string(), Some(value.to_string())));
+        self
+    }
+
+    /// Require a capability, whatever its value
+    pub fn has_capability(mut self, key: &str) -> Self {
+        self.capabilities.push((key.to_string(), None));
+        self
+    }
+
+    /// Whether `envelope` satisfies the rule
+    pub fn matches(&self, envelope: &Envelope) -> bool {
+        if let Some(pattern) = &self.to {
+            let matched = match pattern.strip_suffix('*') {
+                Some(prefix) => envelope.to().starts_with(prefix),
+                None => envelope.to() == pattern,
+            };
+            if !matched {
+                return false;
+            }
+        }
+        if self.operation.is_some_and(|operation| operation != envelope.operation()) {
+            return false;
+        }
+        self.capabilities.iter().all(|(key, expected)| {
+            let actual = envelope.capabilities().and_then(|capabilities| capabilities.get(key));
+            match (actual, expected) {
+                (Some(actual), Some(expected)) => actual == expected,
+                (Some(_), None) => true,
+                (None, _) => false,
+            }
+        })
+    }
+}
+
+/// What to do with an envelope matched by a route
+#[derive(Clone)]
+pub enum Action {
+    /// Pass it to a handler, with the ID of the connection it arrived on
+    Handle(MessageHandler),
+    /// Send it on through a transport; `None` uses the transport's default peer
+    Forward {
+        transport: Arc<dyn Transport>,
+        connection_id: Option<String>,
+    },
+    /// Send it through a transport to the connection named by its `to` address
+    ForwardByAddress(Arc<dyn Transport>),
+    /// Discard it
+    Drop,
+}
+
+impl Action {
+    /// Handle matched envelopes with an async closure
+    pub fn handler<F, Fut>(handler: F) -> Self
+    where
+        F: Fn(Envelope, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        Action::Handle(Arc::new(move |envelope, conn_id| -> HandlerFuture<Result<()>> {
+            Box::pin(handler(envelope, conn_id))
+        }))
+    }
+
+    /// Forward matched envelopes through `transport`
+    pub fn forward(transport: Arc<dyn Transport>, connection_id: Option<&str>) -> Self {
+        Action::Forward {
+            transport,
+            connection_id: connection_id.map(str::to_string),
+        }
+    }
+
+    /// Forward matched envelopes to the connection named by their `to` address
+    pub fn forward_by_address(transport: Arc<dyn Transport>) -> Self {
+        Action::ForwardByAddress(transport)
+    }
+
+    async fn apply(&self, envelope: &Envelope, conn_id: &str) -> Result<()> {
+        match self {
+            Action::Handle(handler) => handler(envelope.clone(), conn_id.to_string()).await,
+            Action::Forward {
+                transport,
+                connection_id,
+            } => transport.send(envelope.clone(), connection_id.as_deref()).await,
+            Action::ForwardByAddress(transport) => transport.send(envelope.clone(), Some(envelope.to())).await,
+            Action::Drop => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Debug for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Handle(_) => f.write_str("Handle"),
+            Action::Forward { connection_id, .. } => {
+                f.debug_struct("Forward").field("connection_id", connection_id).finish()
+            }
+            Action::ForwardByAddress(_) => f.write_str("ForwardByAddress"),
+            Action::Drop => f.write_str("Drop"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    rule: Rule,
+    action: Action,
+    fall_through: bool,
+}
+
+/// Ordered routing table
+#[derive(Debug, Clone)]
+pub struct MessageRouter {
+    routes: Vec<Route>,
+    fallback: Action,
+}
+
+impl Default for MessageRouter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MessageRouter {
+    /// Empty router that drops everything
+    pub fn new() -> Self {
+        MessageRouter {
+            routes: Vec::new(),
+            fallback: Action::Drop,
+        }
+    }
+
+    /// Add a route that ends matching when it applies
+    pub fn route(mut self, rule: Rule, action: Action) -> Self {
+        self.routes.push(Route {
+            rule,
+            action,
+            fall_through: false,
+        });
+        self
+    }
+
+    /// Add a route whose action runs before matching continues with the next routes
+    pub fn route_through(mut self, rule: Rule, action: Action) -> Self {
+        self.routes.push(Route {
+            rule,
+            action,
+            fall_through: true,
+        });
+        self
+    }
+
+    /// Action for envelopes no route ends on
+    pub fn fallback(mut self, action: Action) -> Self {
+        self.fallback = action;
+        self
+    }
+
+    /// Route one envelope, returning how many actions ran (the fallback included)
+    ///
+    /// Stops at the first failing action and returns its error.
+    pub async fn dispatch(&self, envelope: &Envelope, conn_id: &str) -> Result<usize> {
+        let mut applied = 0;
+        for route in self.routes.iter().filter(|route| route.rule.matches(envelope)) {
+            route.action.apply(envelope, conn_id).await?;
+            applied += 1;
+            if !route.fall_through {
+                return Ok(applied);
+            }
+        }
+        self.fallback.apply(envelope, conn_id).await?;
+        Ok(applied + 1)
+    }
+
+    /// Route every envelope arriving on `transport` from now on
+    ///
+    /// Errors from individual envelopes are ignored so one bad route does not stop the rest. The
+    /// task ends when the transport stops delivering envelopes.
+    pub fn attach(self, transport: Arc<dyn Transport>) -> JoinHandle<()> {
+        let mut incoming = transport.subscribe();
+        tokio::spawn(async move {
+            while let Some((envelope, conn_id)) = incoming.recv().await {
+                let _ = self.dispatch(&envelope, &conn_id).await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+    use std::sync::Mutex;
+
+    fn make_envelope(to: &str, operation: OperationType) -> Envelope {
+        Envelope::builder()
+            .from("client")
+            .to(to)
+            .operation(operation)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_router_ordering_and_fall_through() {
+        let upstream = MockTransport::new();
+        upstream.connect().await.unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = |tag: &'static str| {
+            let seen = seen.clone();
+            Action::handler(move |envelope: Envelope, _| {
+                let seen = seen.clone();
+                async move {
+                    seen.lock().unwrap().push((tag, envelope.to().to_string()));
+                    Ok(())
+                }
+            })
+        };
+
+        let router = MessageRouter::new()
+            .route_through(Rule::any(), log("audit"))
+            .route(Rule::any().has_capability("heartbeat"), Action::Drop)
+            .route(Rule::any().to("models/*").operation(OperationType::Data), log("models"))
+            .route(Rule::any().to("upstream"), Action::forward(Arc::new(upstream.clone()), Some("mock-peer")))
+            .fallback(log("fallback"));
+
+        let mut heartbeat = make_envelope("models/a", OperationType::Data);
+        heartbeat.add_capability("heartbeat", "1");
+        assert_eq!(router.dispatch(&heartbeat, "c1").await.unwrap(), 2);
+        assert_eq!(router.dispatch(&make_envelope("models/a", OperationType::Data), "c1").await.unwrap(), 2);
+        assert_eq!(router.dispatch(&make_envelope("models/a", OperationType::Control), "c1").await.unwrap(), 2);
+        assert_eq!(router.dispatch(&make_envelope("upstream", OperationType::Data), "c1").await.unwrap(), 2);
+
+        let tags: Vec<_> = seen.lock().unwrap().iter().map(|(tag, _)| *tag).collect();
+        assert_eq!(tags, vec!["audit", "audit", "models", "audit", "fallback", "audit"]);
+        let forwarded = upstream.take_sent();
+        assert_eq!(forwarded.len(), 1);
+        assert_eq!(forwarded[0].envelope.to(), "upstream");
+
+        // Forwarding failures surface to the caller
+        upstream.disconnect().await;
+        assert!(router.dispatch(&make_envelope("upstream", OperationType::Data), "c1").await.is_err());
+    }
+}
@@ -0,0 +1,150 @@
+/*!
+# UMICP safetensors Interop
+
+Read and write the [safetensors](https://github.com/huggingface/safetensors) format (requires the
+`safetensors` feature), so model weights exchanged over UMICP can be dumped to or loaded from
+files that HuggingFace tooling understands.
+
+Tensors are written as `F32`. On load, `F32`, `F64`, `F16` and `BF16` tensors are converted to
+[`Tensor`]; other dtypes are rejected.
+*/
+
+use crate::error::{Result, UmicpError};
+use crate::types::Tensor;
+use ::safetensors::tensor::{Dtype, SafeTensors, TensorView};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// Named tensors plus the free-form `__metadata__` header of a safetensors file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SafetensorsFile {
+    /// Tensors keyed by name
+    pub tensors: BTreeMap<String, Tensor>,
+    /// String metadata stored in the file header
+    pub metadata: HashMap<String, String>,
+}
+
+/// Serialize named tensors into a safetensors byte buffer
+pub fn serialize(tensors: &BTreeMap<String, Tensor>, metadata: Option<HashMap<String, String>>) -> Result<Vec<u8>> {
+    let bytes: Vec<(&String, Vec<u8>)> = tensors
+        .iter()
+        .map(|(name, tensor)| (name, tensor.data.iter().flat_map(|v| v.to_le_bytes()).collect()))
+        .collect();
+
+    let mut views = Vec::with_capacity(bytes.len());
+    for (name, data) in &bytes {
+        let view = TensorView::new(Dtype::F32, tensors[*name].shape.clone(), data)
+            .map_err(|e| UmicpError::serialization(format!("Invalid tensor '{}': {}", name, e)))?;
+        views.push((name.as_str(), view));
+    }
+
+    ::safetensors::serialize(views, &metadata)
+        .map_err(|e| UmicpError::serialization(format!("safetensors serialization failed: {}", e)))
+}
+
+/// Parse a safetensors byte buffer
+pub fn deserialize(buffer: &[u8]) -> Result<SafetensorsFile> {
+    let (_, header) = SafeTensors::read_metadata(buffer)
+        .map_err(|e| UmicpError::serialization(format!("Invalid safetensors header: {}", e)))?;
+    let parsed = SafeTensors::deserialize(buffer)
+        .map_err(|e| UmicpError::serialization(format!("Invalid safetensors buffer: {}", e)))?;
+
+    let mut tensors = BTreeMap::new();
+    for (name, view) in parsed.tensors() {
+        let data = decode(&name, view.dtype(), view.data())?;
+        tensors.insert(name, Tensor::new(view.shape().to_vec(), data)?);
+    }
+
+    Ok(SafetensorsFile {
+        tensors,
+        metadata: header.metadata().clone().unwrap_or_default(),
+    })
+}
+
+/// Write named tensors to a safetensors file
+pub fn save<P: AsRef<Path>>(path: P, tensors: &BTreeMap<String, Tensor>, metadata: Option<HashMap<String, String>>) -> Result<()> {
+    let bytes = serialize(tensors, metadata)?;
+    std::fs::write(path, bytes)?;
+    Ok(())
+}
+
+/// Read a safetensors file
+pub fn load<P: AsRef<Path>>(path: P) -> Result<SafetensorsFile> {
+    let bytes = std::fs::read(path)?;
+    deserialize(&bytes)
+}
+
+fn decode(name: &str, dtype: Dtype, bytes: &[u8]) -> Result<Vec<f32>> {
+    let data = match dtype {
+        Dtype::F32 => bytes
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect(),
+        Dtype::F64 => bytes
+            .chunks_exact(8)
+            .map(|c| f64::from_le_bytes([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]]) as f32)
+            .collect(),
+        Dtype::BF16 => bytes
+            .chunks_exact(2)
+            .map(|c| f32::from_bits((u16::from_le_bytes([c[0], c[1]]) as u32) << 16))
+            .collect(),
+        Dtype::F16 => bytes
+            .chunks_exact(2)
+            .map(|c| f16_to_f32(u16::from_le_bytes([c[0], c[1]])))
+            .collect(),
+        other => {
+            return Err(UmicpError::serialization(format!(
+                "Tensor '{}' has unsupported dtype {:?}",
+                name, other
+            )))
+        }
+    };
+    Ok(data)
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let negative = bits & 0x8000 != 0;
+    let exponent = ((bits >> 10) & 0x1f) as u32;
+    let mantissa = (bits & 0x3ff) as u32;
+
+    let magnitude = match exponent {
+        // Zero and subnormals: mantissa * 2^-24, exact in f32
+        0 => mantissa as f32 * 2f32.powi(-24),
+        0x1f => f32::from_bits(0xff << 23 | mantissa << 13),
+        _ => f32::from_bits((exponent + 127 - 15) << 23 | mantissa << 13),
+    };
+    if negative { -magnitude } else { magnitude }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safetensors_round_trip() {
+        let mut tensors = BTreeMap::new();
+        tensors.insert("layer.weight".to_string(), Tensor::new(vec![2, 3], vec![1.0, -2.0, 3.5, 0.0, 5.0, 6.25]).unwrap());
+        tensors.insert("layer.bias".to_string(), Tensor::new(vec![3], vec![0.1, 0.2, 0.3]).unwrap());
+        let metadata = HashMap::from([("format".to_string(), "pt".to_string())]);
+
+        let bytes = serialize(&tensors, Some(metadata.clone())).unwrap();
+        let file = deserialize(&bytes).unwrap();
+        assert_eq!(file.tensors, tensors);
+        assert_eq!(file.metadata, metadata);
+
+        assert!(deserialize(&bytes[..4]).is_err());
+    }
+
+    #[test]
+    fn test_half_precision_decode() {
+        // 1.0, -2.0, 65504 (max f16), 2^-24 (smallest subnormal)
+        let halves: [u16; 4] = [0x3c00, 0xc000, 0x7bff, 0x0001];
+        let bytes: Vec<u8> = halves.iter().flat_map(|h| h.to_le_bytes()).collect();
+        let decoded = decode("h", Dtype::F16, &bytes).unwrap();
+        assert_eq!(decoded, vec![1.0, -2.0, 65504.0, 2f32.powi(-24)]);
+
+        // bf16 1.5 is the top half of f32 1.5
+        let bf = (1.5f32.to_bits() >> 16) as u16;
+        assert_eq!(decode("b", Dtype::BF16, &bf.to_le_bytes()).unwrap(), vec![1.5]);
+    }
+}
@@ -0,0 +1,519 @@
+/*!
+# UMICP Encrypted Sessions
+
+End-to-end encryption of payloads between two peers, for deployments where envelopes pass
+through brokers or relays that should route them without being able to read them.
+
+A session is set up with one round trip of `Control` envelopes. The initiator sends a fresh
+X25519 public key in the [`KEY_EXCHANGE_CAPABILITY`] of an offer, together with a new session ID
+in the [`SESSION_CAPABILITY`]; the responder answers with its own public key. Each side then
+runs X25519 and derives one key per direction with HKDF-SHA256, salted with both public keys and
+bound to the session ID. The private keys are ephemeral and never leave this process, so a later
+compromise of either peer does not expose earlier sessions.
+
+[`Session::encrypt`] replaces an envelope's payload with a ciphertext and names the session and
+cipher in capabilities; everything else stays readable so the envelope can still be routed. The
+ciphertext is bound to the message ID, so it cannot be replayed under another one.
+
+The cipher is negotiated during the exchange: the offer lists the initiator's [`Cipher`]s in its
+[`CIPHERS_CAPABILITY`], and the responder picks the first of its own it finds there, naming it in
+the reply's [`CIPHER_CAPABILITY`]. Both default to [`Cipher::preferred`], which puts
+ChaCha20-Poly1305 first on CPUs without AES instructions, such as many ARM devices, where it is
+several times faster than AES-GCM. An offer without the capability gets AES-256-GCM.
+
+The key exchange itself is not authenticated: a relay able to rewrite envelopes could answer both
+offers itself. Sign both handshake envelopes with
+[`KeyStore::sign_envelope`](crate::keystore::KeyStore::sign_envelope) and check them with
+[`verify_envelope`](crate::keystore::KeyStore::verify_envelope) before accepting or completing
+the exchange.
+
+```rust
+use umicp_core::session::{Session, SessionInitiator};
+use umicp_core::Envelope;
+
+# fn main() -> umicp_core::Result<()> {
+let (initiator, offer) = SessionInitiator::new("alice", "bob")?;
+// `offer` travels to bob, who answers it
+let (bob, reply) = Session::accept(&offer)?;
+// `reply` travels back to alice
+let alice = initiator.complete(&reply)?;
+
+let mut envelope = Envelope::builder().from("alice").to("bob").build()?;
+envelope.set_payload(b"secret".to_vec());
+alice.encrypt(&mut envelope)?;
+bob.decrypt(&mut envelope)?;
+assert_eq!(envelope.payload(), Some(&b"secret"[..]));
+# Ok(())
+# }
+```
+*/
+
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::OperationType;
+use crate::utils::{base64_decode, base64_encode, generate_uuid};
+use ring::aead::{Aad, Algorithm, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, CHACHA20_POLY1305, NONCE_LEN};
+use ring::agreement::{agree_ephemeral, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::SystemRandom;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Capability carrying a sender's X25519 public key, base64-encoded; marks key exchange envelopes
+pub const KEY_EXCHANGE_CAPABILITY: &str = "key_exchange";
+
+/// Capability naming the session of a key exchange or encrypted envelope
+pub const SESSION_CAPABILITY: &str = "session";
+
+/// Capability naming the cipher of a session, on its reply and its encrypted envelopes
+pub const CIPHER_CAPABILITY: &str = "cipher";
+
+/// Capability listing the ciphers an offer accepts, comma-separated, most preferred first
+pub const CIPHERS_CAPABILITY: &str = "ciphers";
+
+/// [`CIPHER_CAPABILITY`] value of AES-256-GCM
+pub const AES_256_GCM_CIPHER: &str = "aes-256-gcm";
+
+/// [`CIPHER_CAPABILITY`] value of ChaCha20-Poly1305
+pub const CHACHA20_POLY1305_CIPHER: &str = "chacha20-poly1305";
+
+/// Prefix of the HKDF info deriving each direction's key
+const KEY_INFO: &[u8] = b"umicp-session-v1";
+
+/// Authenticated cipher encrypting a session's payloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Cipher {
+    /// AES-256-GCM; fastest where the CPU has AES instructions
+    Aes256Gcm,
+    /// ChaCha20-Poly1305; fastest where it does not
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Name carried in [`CIPHER_CAPABILITY`]
+    pub fn name(self) -> &'static str {
+        match self {
+            Cipher::Aes256Gcm => AES_256_GCM_CIPHER,
+            Cipher::ChaCha20Poly1305 => CHACHA20_POLY1305_CIPHER,
+        }
+    }
+
+    /// Cipher called `name`, if it is one
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            AES_256_GCM_CIPHER => Some(Cipher::Aes256Gcm),
+            CHACHA20_POLY1305_CIPHER => Some(Cipher::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    /// Every cipher, fastest on this CPU first
+    pub fn preferred() -> Vec<Cipher> {
+        match aes_hardware() {
+            true => vec![Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305],
+            false => vec![Cipher::ChaCha20Poly1305, Cipher::Aes256Gcm],
+        }
+    }
+
+    fn algorithm(self) -> &'static Algorithm {
+        match self {
+            Cipher::Aes256Gcm => &AES_256_GCM,
+            Cipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+        }
+    }
+}
+
+/// Which end of the key exchange a session is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Initiator,
+    Responder,
+}
+
+/// The initiator's side of a key exchange awaiting its reply
+#[derive(Debug)]
+pub struct SessionInitiator {
+    session_id: String,
+    peer: String,
+    private_key: EphemeralPrivateKey,
+    public_key: Vec<u8>,
+    ciphers: Vec<Cipher>,
+}
+
+impl SessionInitiator {
+    /// Start a session from `from` to `to`; send the returned offer to the peer
+    pub fn new(from: &str, to: &str) -> Result<(Self, Envelope)> {
+        Self::with_ciphers(from, to, &Cipher::preferred())
+    }
+
+    /// Start a session offering only `ciphers`, most preferred first
+    pub fn with_ciphers(from: &str, to: &str, ciphers: &[Cipher]) -> Result<(Self, Envelope)> {
+        if ciphers.is_empty() {
+            return Err(UmicpError::configuration("A session needs at least one cipher"));
+        }
+        let (private_key, public_key) = key_pair()?;
+        let session_id = generate_uuid();
+
+        let mut offer = Envelope::new();
+        offer.set_from(from);
+        offer.set_to(to);
+        offer.set_operation(OperationType::Control);
+        offer.add_capability(SESSION_CAPABILITY, &session_id);
+        offer.add_capability(KEY_EXCHANGE_CAPABILITY, &base64_encode(&public_key));
+        offer.add_capability(CIPHERS_CAPABILITY, &cipher_list(ciphers));
+
+        let initiator = SessionInitiator {
+            session_id,
+            peer: to.to_string(),
+            private_key,
+            public_key,
+            ciphers: ciphers.to_vec(),
+        };
+        Ok((initiator, offer))
+    }
+
+    /// ID of the session being set up
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// Finish the exchange with the peer's reply to the offer
+    pub fn complete(self, reply: &Envelope) -> Result<Session> {
+        let (session_id, peer_key) = parse(reply)?;
+        if session_id != self.session_id {
+            return Err(UmicpError::validation(format!(
+                "Reply is for session {}, not {}",
+                session_id, self.session_id
+            )));
+        }
+        // A responder that predates negotiation uses AES-256-GCM
+        let cipher = match capability(reply, CIPHER_CAPABILITY) {
+            Some(name) => Cipher::from_name(name)
+                .ok_or_else(|| UmicpError::validation(format!("Unsupported cipher: {}", name)))?,
+            None => Cipher::Aes256Gcm,
+        };
+        if !self.ciphers.contains(&cipher) {
+            return Err(UmicpError::validation(format!("Peer chose {}, which was not offered", cipher.name())));
+        }
+        Session::derive(
+            Role::Initiator,
+            cipher,
+            self.session_id,
+            self.peer,
+            self.private_key,
+            &self.public_key,
+            &peer_key,
+        )
+    }
+}
+
+/// Keys of an established session, one per direction
+#[derive(Debug)]
+pub struct Session {
+    id: String,
+    peer: String,
+    cipher: Cipher,
+    seal_key: LessSafeKey,
+    open_key: LessSafeKey,
+    /// Nonce of the next envelope encrypted
+    sent: AtomicU64,
+}
+
+impl Session {
+    /// Answer a key exchange offer; returns the session and the reply to send back
+    pub fn accept(offer: &Envelope) -> Result<(Session, Envelope)> {
+        Self::accept_with_ciphers(offer, &Cipher::preferred())
+    }
+
+    /// Answer a key exchange offer with the first of `ciphers` it accepts
+    ///
+    /// Fails if it accepts none of them.
+    pub fn accept_with_ciphers(offer: &Envelope, ciphers: &[Cipher]) -> Result<(Session, Envelope)> {
+        let (session_id, peer_key) = parse(offer)?;
+        // An initiator that predates negotiation only knows AES-256-GCM
+        let offered: Vec<Cipher> = match capability(offer, CIPHERS_CAPABILITY) {
+            Some(list) => list.split(',').filter_map(|name| Cipher::from_name(name.trim())).collect(),
+            None => vec![Cipher::Aes256Gcm],
+        };
+        let cipher = *ciphers.iter().find(|cipher| offered.contains(cipher)).ok_or_else(|| {
+            UmicpError::validation(format!("No common cipher; peer offered {}", cipher_list(&offered)))
+        })?;
+        let (private_key, public_key) = key_pair()?;
+
+        let mut reply = offer.reply(OperationType::Control);
+        reply.add_capability(SESSION_CAPABILITY, &session_id);
+        reply.add_capability(KEY_EXCHANGE_CAPABILITY, &base64_encode(&public_key));
+        reply.add_capability(CIPHER_CAPABILITY, cipher.name());
+
+        let session = Session::derive(
+            Role::Responder,
+            cipher,
+            session_id,
+            offer.from().to_string(),
+            private_key,
+            &public_key,
+            &peer_key,
+        )?;
+        Ok((session, reply))
+    }
+
+    /// Session ID, as carried in [`SESSION_CAPABILITY`]
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The peer the session was set up with
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    /// Cipher the session encrypts with
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// Encrypt `envelope`'s payload for the peer, leaving its other fields readable
+    ///
+    /// An envelope without a payload gets an encrypted empty one, so the peer can tell it came
+    /// through the session.
+    pub fn encrypt(&self, envelope: &mut Envelope) -> Result<()> {
+        let counter = self.sent.fetch_add(1, Ordering::Relaxed);
+        if counter == u64::MAX {
+            return Err(UmicpError::configuration("Session has used up its nonces; set up a new one"));
+        }
+        let mut nonce = [0; NONCE_LEN];
+        nonce[NONCE_LEN - 8..].copy_from_slice(&counter.to_be_bytes());
+
+        let mut sealed = envelope.take_payload().unwrap_or_default();
+        let aad = self.aad(envelope);
+        self.seal_key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(aad), &mut sealed)
+            .map_err(|_| UmicpError::generic("Failed to encrypt payload"))?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend_from_slice(&sealed);
+        envelope.set_payload(payload);
+        envelope.add_capability(SESSION_CAPABILITY, &self.id);
+        envelope.add_capability(CIPHER_CAPABILITY, self.cipher.name());
+        Ok(())
+    }
+
+    /// Decrypt the payload of an envelope the peer encrypted in this session
+    ///
+    /// Fails with an authentication error if the payload or message ID was changed in transit.
+    pub fn decrypt(&self, envelope: &mut Envelope) -> Result<()> {
+        match capability(envelope, SESSION_CAPABILITY) {
+            Some(id) if id == self.id => {}
+            Some(id) => return Err(UmicpError::validation(format!("Envelope is from session {}, not {}", id, self.id))),
+            None => return Err(UmicpError::validation("Envelope is not encrypted")),
+        }
+        match capability(envelope, CIPHER_CAPABILITY) {
+            Some(name) if name == self.cipher.name() => {}
+            name => {
+                return Err(UmicpError::validation(format!(
+                    "Envelope is encrypted with {}, not the session's {}",
+                    name.unwrap_or("no cipher"),
+                    self.cipher.name()
+                )))
+            }
+        }
+
+        let mut sealed = envelope.take_payload().unwrap_or_default();
+        if sealed.len() < NONCE_LEN {
+            return Err(UmicpError::authentication("Encrypted payload is truncated"));
+        }
+        let nonce = Nonce::try_assume_unique_for_key(&sealed[..NONCE_LEN]).expect("nonce has its length");
+        let aad = self.aad(envelope);
+        let plaintext = self
+            .open_key
+            .open_within(nonce, Aad::from(aad), &mut sealed, NONCE_LEN..)
+            .map_err(|_| UmicpError::authentication("Encrypted payload failed authentication"))?;
+        let plaintext_len = plaintext.len();
+        sealed.truncate(plaintext_len);
+        envelope.set_payload(sealed);
+        Ok(())
+    }
+
+    fn derive(
+        role: Role,
+        cipher: Cipher,
+        id: String,
+        peer: String,
+        private_key: EphemeralPrivateKey,
+        public_key: &[u8],
+        peer_key: &[u8],
+    ) -> Result<Session> {
+        // Salted with both public keys in the same order on both sides
+        let salt = match role {
+            Role::Initiator => [public_key, peer_key].concat(),
+            Role::Responder => [peer_key, public_key].concat(),
+        };
+        let peer_key = UnparsedPublicKey::new(&X25519, peer_key);
+        let (to_responder, to_initiator) = agree_ephemeral(private_key, &peer_key, |shared| {
+            let prk = Salt::new(HKDF_SHA256, &salt).extract(shared);
+            let key = |direction: &[u8]| -> LessSafeKey {
+                let info = [KEY_INFO, direction, id.as_bytes()];
+                let okm = prk.expand(&info, cipher.algorithm()).expect("cipher keys are a valid HKDF length");
+                LessSafeKey::new(UnboundKey::from(okm))
+            };
+            (key(b" initiator "), key(b" responder "))
+        })
+        .map_err(|_| UmicpError::authentication("Invalid key exchange public key"))?;
+
+        let (seal_key, open_key) = match role {
+            Role::Initiator => (to_responder, to_initiator),
+            Role::Responder => (to_initiator, to_responder),
+        };
+        Ok(Session {
+            id,
+            peer,
+            cipher,
+            seal_key,
+            open_key,
+            sent: AtomicU64::new(0),
+        })
+    }
+
+    /// Associated data binding a ciphertext to its session and message
+    fn aad(&self, envelope: &Envelope) -> Vec<u8> {
+        format!("{}:{}", self.id, envelope.message_id()).into_bytes()
+    }
+}
+
+/// Whether `envelope` is a key exchange offer or reply rather than application traffic
+pub fn is_key_exchange(envelope: &Envelope) -> bool {
+    envelope.operation() == OperationType::Control
+        && envelope
+            .capabilities()
+            .is_some_and(|capabilities| capabilities.contains_key(KEY_EXCHANGE_CAPABILITY))
+}
+
+/// Value of capability `name` on `envelope`
+fn capability<'a>(envelope: &'a Envelope, name: &str) -> Option<&'a str> {
+    envelope.capabilities()?.get(name).map(String::as_str)
+}
+
+fn cipher_list(ciphers: &[Cipher]) -> String {
+    ciphers.iter().map(|cipher| cipher.name()).collect::<Vec<_>>().join(",")
+}
+
+/// Whether the CPU has instructions that make AES-GCM fast
+#[allow(unreachable_code)]
+fn aes_hardware() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    return std::arch::is_x86_feature_detected!("aes") && std::arch::is_x86_feature_detected!("pclmulqdq");
+    #[cfg(target_arch = "aarch64")]
+    return std::arch::is_aarch64_feature_detected!("aes") && std::arch::is_aarch64_feature_detected!("pmull");
+    false
+}
+
+/// Fresh X25519 key pair
+fn key_pair() -> Result<(EphemeralPrivateKey, Vec<u8>)> {
+    let failed = |_| UmicpError::generic("Failed to generate a key exchange key");
+    let private_key = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new()).map_err(failed)?;
+    let public_key = private_key.compute_public_key().map_err(failed)?.as_ref().to_vec();
+    Ok((private_key, public_key))
+}
+
+/// Session ID and public key of a key exchange envelope
+fn parse(envelope: &Envelope) -> Result<(String, Vec<u8>)> {
+    if !is_key_exchange(envelope) {
+        return Err(UmicpError::validation("Envelope is not a key exchange"));
+    }
+    let capabilities = envelope.capabilities().expect("key exchanges have capabilities");
+    let session_id = capabilities
+        .get(SESSION_CAPABILITY)
+        .ok_or_else(|| UmicpError::validation("Key exchange has no session ID"))?;
+    let public_key = base64_decode(&capabilities[KEY_EXCHANGE_CAPABILITY])?;
+    Ok((session_id.clone(), public_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_key_exchange_and_encryption() {
+        let (initiator, offer) = SessionInitiator::new("alice", "bob").unwrap();
+        assert!(is_key_exchange(&offer));
+        let (bob, reply) = Session::accept(&offer).unwrap();
+        assert_eq!((bob.peer(), bob.id()), ("alice", initiator.session_id()));
+        assert_eq!(reply.to(), "alice");
+        let alice = initiator.complete(&reply).unwrap();
+
+        // Both directions round trip, and each envelope gets its own nonce
+        let mut first = Envelope::builder().from("alice").to("bob").build().unwrap();
+        first.set_payload(b"hello bob".to_vec());
+        let mut second = first.clone();
+        alice.encrypt(&mut first).unwrap();
+        alice.encrypt(&mut second).unwrap();
+        assert_ne!(first.payload(), second.payload());
+        assert!(!first.payload().unwrap().windows(5).any(|window| window == b"hello"));
+        bob.decrypt(&mut first).unwrap();
+        assert_eq!(first.payload(), Some(&b"hello bob"[..]));
+
+        let mut answer = first.reply(OperationType::Data);
+        answer.set_payload(b"hello alice".to_vec());
+        bob.encrypt(&mut answer).unwrap();
+        // The sender's own key cannot open what it sealed
+        assert!(bob.decrypt(&mut answer.clone()).is_err());
+        alice.decrypt(&mut answer).unwrap();
+        assert_eq!(answer.payload(), Some(&b"hello alice"[..]));
+
+        // A tampered payload, or one moved under another message ID, is rejected
+        let mut tampered = Envelope::new();
+        tampered.set_payload(b"data".to_vec());
+        alice.encrypt(&mut tampered).unwrap();
+        let mut payload = tampered.payload().unwrap().to_vec();
+        let mut moved = Envelope::new();
+        moved.set_capabilities(tampered.capabilities().unwrap().clone());
+        moved.set_payload(payload.clone());
+        *payload.last_mut().unwrap() ^= 1;
+        tampered.set_payload(payload);
+        assert!(matches!(bob.decrypt(&mut tampered), Err(UmicpError::Authentication { .. })));
+        assert!(matches!(bob.decrypt(&mut moved), Err(UmicpError::Authentication { .. })));
+
+        // A reply for another session does not complete this one
+        let (other, _) = SessionInitiator::new("alice", "bob").unwrap();
+        assert!(other.complete(&reply).is_err());
+        assert!(Session::accept(&Envelope::new()).is_err());
+    }
+
+    #[test]
+    fn test_session_cipher_negotiation() {
+        // The responder's preference wins among what was offered
+        let all = [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305];
+        let (initiator, offer) = SessionInitiator::with_ciphers("arm", "server", &all).unwrap();
+        let (server, reply) = Session::accept_with_ciphers(&offer, &[Cipher::ChaCha20Poly1305]).unwrap();
+        let arm = initiator.complete(&reply).unwrap();
+        assert_eq!((arm.cipher(), server.cipher()), (Cipher::ChaCha20Poly1305, Cipher::ChaCha20Poly1305));
+
+        let mut envelope = Envelope::new();
+        envelope.set_payload(vec![7; 100]);
+        arm.encrypt(&mut envelope).unwrap();
+        assert_eq!(envelope.capabilities().unwrap()[CIPHER_CAPABILITY], CHACHA20_POLY1305_CIPHER);
+        let mut relabelled = envelope.clone();
+        relabelled.add_capability(CIPHER_CAPABILITY, AES_256_GCM_CIPHER);
+        assert!(server.decrypt(&mut relabelled).is_err());
+        server.decrypt(&mut envelope).unwrap();
+        assert_eq!(envelope.payload(), Some(&[7; 100][..]));
+
+        // An offer without the capability predates negotiation and gets AES-256-GCM
+        let (initiator, mut offer) = SessionInitiator::new("old", "server").unwrap();
+        let mut capabilities = offer.capabilities().unwrap().clone();
+        capabilities.remove(CIPHERS_CAPABILITY);
+        offer.set_capabilities(capabilities);
+        let (server, reply) = Session::accept(&offer).unwrap();
+        assert_eq!(server.cipher(), Cipher::Aes256Gcm);
+        assert_eq!(initiator.complete(&reply).unwrap().cipher(), Cipher::Aes256Gcm);
+
+        // Nothing in common fails on both sides
+        let (_, offer) = SessionInitiator::with_ciphers("a", "b", &[Cipher::Aes256Gcm]).unwrap();
+        assert!(Session::accept_with_ciphers(&offer, &[Cipher::ChaCha20Poly1305]).is_err());
+        let (initiator, offer) = SessionInitiator::with_ciphers("a", "b", &[Cipher::Aes256Gcm]).unwrap();
+        let (_, mut reply) = Session::accept(&offer).unwrap();
+        reply.add_capability(CIPHER_CAPABILITY, CHACHA20_POLY1305_CIPHER);
+        assert!(initiator.complete(&reply).is_err());
+        assert_eq!(Cipher::from_name(Cipher::ChaCha20Poly1305.name()), Some(Cipher::ChaCha20Poly1305));
+        assert_eq!(Cipher::preferred().len(), 2);
+    }
+}
@@ -1,66 +1,428 @@
 /*!
 # UMICP Transport Layer
 
-WebSocket and HTTP/2 transport implementations for UMICP protocol.
+WebSocket (native and browser), Server-Sent Events, HTTP long-polling, HTTP/2, QUIC, MQTT,
+in-memory loopback and mock transport implementations for UMICP protocol, plus wrappers adding
+reliable delivery, outboxes, offline queueing, load balancing and middleware to any of them.
 */
 
+use crate::envelope::Envelope;
 use crate::error::{Result, UmicpError};
 use crate::types::*;
+use async_trait::async_trait;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+mod balancer;
+#[cfg(feature = "websocket")]
+mod batch;
+#[cfg(feature = "wasm")]
+mod browser;
+mod cancel;
+#[cfg(feature = "websocket")]
+mod compression;
+mod dedup;
+#[cfg(feature = "websocket")]
+mod endpoint;
+#[cfg(feature = "long-polling")]
+mod fallback;
+mod filter;
+mod frame;
+#[cfg(any(feature = "websocket", feature = "wasm"))]
+mod handshake;
+#[cfg(any(feature = "sse", feature = "long-polling", feature = "oidc"))]
+// OIDC discovery only fetches documents
+#[cfg_attr(not(any(feature = "sse", feature = "long-polling")), allow(dead_code))]
+pub(crate) mod http;
+#[cfg(feature = "websocket")]
+mod latency;
+mod loopback;
+mod middleware;
+mod mock;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(any(feature = "websocket", feature = "wasm"))]
+// The browser client only reads mux frames
+#[cfg_attr(not(feature = "websocket"), allow(dead_code))]
+mod mux;
+#[cfg(feature = "websocket")]
+mod net;
+mod observer;
+mod offline;
+mod outbox;
+#[cfg(feature = "long-polling")]
+mod polling;
+#[cfg(feature = "websocket")]
+mod priority;
+#[cfg(feature = "websocket")]
+mod proxy;
+#[cfg(feature = "quic")]
+mod quic;
+mod reliable;
+mod rpc;
+#[cfg(feature = "websocket")]
+mod sequence;
+#[cfg(feature = "sse")]
+mod sse;
+#[cfg(feature = "websocket")]
+mod transfer;
+#[cfg(any(feature = "tls", feature = "quic"))]
+pub(crate) mod tls;
+#[cfg(feature = "websocket")]
+mod websocket;
+
+pub use balancer::{BackendStatus, LoadBalancedTransport};
+#[cfg(feature = "wasm")]
+pub use browser::BrowserWebSocketTransport;
+pub use cancel::CancellationToken;
+pub use dedup::{DedupStore, FileDedupStore, MemoryDedupStore};
+#[cfg(feature = "long-polling")]
+pub use fallback::{FallbackKind, FallbackTransport};
+pub use filter::{CapabilityMatcher, FilterExpression, SubscriptionFilter, FILTER_CAPABILITY};
+pub use frame::{read_frame, write_frame, BinaryFrame, FRAME_MARKER};
+pub use loopback::LoopbackTransport;
+pub use middleware::{MessageDirection, Middleware, MiddlewareContext, MiddlewareTransport, Next};
+pub use mock::{MockFault, MockTransport, SentEnvelope};
+pub use observer::{
+    AuthRecord, ConnectionRecord, DropRecord, ErrorRecord, MessageRecord, ObservedTransport, RetryRecord,
+    TransportObserver,
+};
+#[cfg(feature = "mqtt")]
+pub use mqtt::{MqttConfig, MqttQos, MqttTransport, MqttVersion};
+pub use offline::{
+    DeliveryOutcome, DeliveryReceipt, DropReason, EvictionPolicy, OfflineQueueConfig, OfflineQueueTransport,
+};
+pub use outbox::{OutboxConfig, OutboxTransport};
+#[cfg(feature = "long-polling")]
+pub use polling::LongPollingTransport;
+#[cfg(feature = "quic")]
+pub use quic::{QuicStreamMode, QuicTransport, QUIC_ALPN};
+pub use reliable::{ReliableTransport, DELIVERY_CAPABILITY};
+pub use rpc::{serve_requests, serve_requests_until, ERROR_CAPABILITY, ERROR_CODE_CAPABILITY};
+
+#[cfg(feature = "websocket")]
+pub use compression::COMPRESSION_HEADER;
+#[cfg(feature = "websocket")]
+pub use endpoint::ENDPOINT_CAPABILITY;
+#[cfg(any(feature = "websocket", feature = "wasm"))]
+pub use handshake::{FEATURE_BATCHES, FEATURE_BINARY_FRAMES, FEATURE_TRANSFERS, HANDSHAKE_CAPABILITY};
+#[cfg(feature = "sse")]
+pub use sse::{SseTransport, SSE_SESSION_HEADER};
+#[cfg(feature = "websocket")]
+pub use transfer::{IncomingStream, TRANSFER_CAPABILITY, TRANSFER_PART_CAPABILITY, TRANSFER_SIZE_CAPABILITY};
+#[cfg(feature = "websocket")]
+pub use websocket::{WebSocketTransport, IDLE_TIMEOUT_CAPABILITY};
+
+/// Boxed future returned by async transport handlers
+pub type HandlerFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
 
 /// Message handler type for incoming messages
-pub type MessageHandler = Box<dyn Fn(crate::Envelope, String) -> Result<()> + Send + Sync>;
+pub type MessageHandler = Arc<dyn Fn(crate::Envelope, String) -> HandlerFuture<Result<()>> + Send + Sync>;
 
 /// Connection handler type for connection events
-pub type ConnectionHandler = Box<dyn Fn(bool, String) -> Result<()> + Send + Sync>;
+pub type ConnectionHandler = Arc<dyn Fn(bool, String) -> HandlerFuture<()> + Send + Sync>;
+
+/// State handler type for client connection state transitions
+pub type StateHandler = Arc<dyn Fn(ConnectionState) -> HandlerFuture<()> + Send + Sync>;
+
+/// Box an async closure into a [`MessageHandler`]
+pub(crate) fn message_handler<F, Fut>(handler: F) -> MessageHandler
+where
+    F: Fn(Envelope, String) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    Arc::new(move |envelope, conn_id| -> HandlerFuture<Result<()>> { Box::pin(handler(envelope, conn_id)) })
+}
+
+/// Box an async closure into a [`ConnectionHandler`]
+pub(crate) fn connection_handler<F, Fut>(handler: F) -> ConnectionHandler
+where
+    F: Fn(bool, String) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    Arc::new(move |connected, conn_id| -> HandlerFuture<()> { Box::pin(handler(connected, conn_id)) })
+}
+
+/// Box an async closure into a [`StateHandler`]
+#[cfg(any(feature = "websocket", feature = "wasm"))]
+pub(crate) fn state_handler<F, Fut>(handler: F) -> StateHandler
+where
+    F: Fn(ConnectionState) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    Arc::new(move |state| -> HandlerFuture<()> { Box::pin(handler(state)) })
+}
+
+/// How long a server waits after a failed accept before accepting again
+///
+/// Most failures concern only the connection being accepted. Running out of file descriptors
+/// lasts until connections close, so it waits longer rather than spinning on the listener.
+#[cfg(any(feature = "websocket", feature = "sse", feature = "long-polling"))]
+pub(crate) fn accept_backoff(error: &std::io::Error) -> std::time::Duration {
+    const EMFILE: i32 = if cfg!(windows) { 10024 } else { 24 };
+    const ENFILE: i32 = 23;
+    let exhausted =
+        matches!(error.raw_os_error(), Some(EMFILE | ENFILE)) || error.kind() == std::io::ErrorKind::OutOfMemory;
+    std::time::Duration::from_millis(if exhausted { 500 } else { 20 })
+}
+
+/// Incoming envelope paired with the ID of the connection it arrived on
+pub type Incoming = (Envelope, String);
 
-/// Placeholder WebSocket transport implementation
-pub struct WebSocketTransport;
+/// Receiving end of a [`Transport::subscribe`] call
+pub type Subscription = mpsc::UnboundedReceiver<Incoming>;
+
+/// Something that happened on a transport, as delivered by [`Transport::events`]
+#[derive(Debug, Clone)]
+pub enum TransportEvent {
+    /// A connection was established; carries its ID
+    Connected(String),
+    /// A connection was closed or lost; carries its ID
+    Disconnected(String),
+    /// A failure that did not surface through a call, such as a read error or a failing handler
+    Error {
+        /// Connection the failure happened on, if any
+        connection_id: Option<String>,
+        /// What went wrong
+        message: String,
+    },
+    /// An envelope arrived on the given connection (boxed: envelopes are large)
+    Message(Box<Envelope>, String),
+    /// Sequenced envelopes on a stream did not arrive in order: `expected..received` are missing
+    ///
+    /// Reported once per gap, when it is first seen, so the receiver can ask for a retransmission.
+    SequenceGap {
+        /// Connection the stream belongs to
+        connection_id: String,
+        /// Stream whose [`FrameOptions::sequence`] numbers skipped ahead
+        stream_id: u32,
+        /// First missing sequence number
+        expected: u64,
+        /// Sequence number that arrived instead
+        received: u64,
+    },
+    /// A server accepted the credentials a peer presented
+    Authenticated {
+        /// Connection the peer opened
+        connection_id: String,
+        /// Subject of the principal the peer authenticated as
+        subject: String,
+    },
+    /// A server refused the credentials a peer presented, or their absence, and turned it away
+    AuthenticationFailed {
+        /// ID the peer's connection would have had
+        connection_id: String,
+        /// Why the credentials were refused
+        message: String,
+    },
+    /// An envelope that was not acknowledged in time is being sent again
+    Retry {
+        /// Connection the envelope is sent on, if one was given
+        connection_id: Option<String>,
+        /// ID of the envelope, the same on every attempt
+        message_id: String,
+        /// Retransmission number, from 1
+        attempt: u32,
+        /// Why the previous attempt's send failed, if it did rather than going unanswered
+        error: Option<String>,
+    },
+    /// An envelope was given up on without being sent
+    Dropped {
+        /// Connection the envelope was meant for, if one was given
+        connection_id: Option<String>,
+        /// ID of the envelope
+        message_id: String,
+        /// Why it was dropped
+        reason: DropReason,
+    },
+}
 
-impl WebSocketTransport {
-    /// Create a new WebSocket server transport
-    pub fn new_server(_addr: &str) -> Result<Self> {
-        Err(UmicpError::generic("WebSocket transport not implemented in this build"))
+impl TransportEvent {
+    pub(crate) fn error(connection_id: Option<&str>, error: impl std::fmt::Display) -> Self {
+        TransportEvent::Error {
+            connection_id: connection_id.map(str::to_string),
+            message: error.to_string(),
+        }
     }
+}
+
+/// Stream of [`TransportEvent`]s returned by [`Transport::events`]
+///
+/// Poll it as a [`futures_core::Stream`], or await [`recv`](EventStream::recv) inside
+/// `tokio::select!`. It ends when the transport is dropped.
+#[derive(Debug)]
+pub struct EventStream {
+    inner: EventSource,
+}
 
-    /// Create a new WebSocket client transport
-    pub fn new_client(_url: &str) -> Result<Self> {
-        Err(UmicpError::generic("WebSocket transport not implemented in this build"))
+#[derive(Debug)]
+enum EventSource {
+    Events(mpsc::UnboundedReceiver<TransportEvent>),
+    /// Transports without event support only report messages
+    Messages(Subscription),
+}
+
+impl EventStream {
+    /// Adapt a message subscription into an event stream of `Message` events
+    pub fn from_subscription(subscription: Subscription) -> Self {
+        EventStream {
+            inner: EventSource::Messages(subscription),
+        }
     }
 
-    /// Set message handler for incoming messages
-    pub fn set_message_handler<F>(&mut self, _handler: F)
-    where
-        F: Fn(crate::Envelope, String) -> Result<()> + Send + Sync + 'static,
-    {
-        // Placeholder implementation
+    /// Wait for the next event; `None` once the transport is gone
+    pub async fn recv(&mut self) -> Option<TransportEvent> {
+        std::future::poll_fn(|cx| self.poll_recv(cx)).await
     }
 
-    /// Set connection handler for connection events
-    pub fn set_connection_handler<F>(&mut self, _handler: F)
-    where
-        F: Fn(bool, String) -> Result<()> + Send + Sync + 'static,
-    {
-        // Placeholder implementation
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<TransportEvent>> {
+        match &mut self.inner {
+            EventSource::Events(receiver) => receiver.poll_recv(cx),
+            EventSource::Messages(receiver) => receiver.poll_recv(cx).map(|incoming| {
+                incoming.map(|(envelope, conn_id)| TransportEvent::Message(Box::new(envelope), conn_id))
+            }),
+        }
     }
+}
+
+impl futures_core::Stream for EventStream {
+    type Item = TransportEvent;
 
-    /// Send message to a specific connection (server mode)
-    pub fn send(&self, _envelope: crate::Envelope, _connection_id: &str) -> Result<()> {
-        Err(UmicpError::generic("WebSocket transport not implemented in this build"))
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<TransportEvent>> {
+        self.get_mut().poll_recv(cx)
     }
+}
+
+/// Transport-agnostic interface for moving envelopes
+///
+/// Application code and higher layers (RPC, pub/sub) should be written against this trait so
+/// any transport can be plugged in underneath.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Start the transport: begin accepting (server) or reading from the peer (client)
+    async fn connect(&self) -> Result<()>;
 
-    /// Send message to server (client mode)
-    pub fn send_to_server(&self, _envelope: crate::Envelope) -> Result<()> {
-        Err(UmicpError::generic("WebSocket transport not implemented in this build"))
+    /// Send an envelope; `connection_id` picks the peer on transports that have several
+    async fn send(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<()>;
+
+    /// [`send`](Transport::send), giving up with [`UmicpError::Cancelled`] once `cancel` is
+    /// cancelled
+    ///
+    /// Nothing is sent if the token is already cancelled. Transports queue an envelope whole, so
+    /// giving up never leaves part of one on the wire.
+    async fn send_cancellable(
+        &self,
+        envelope: Envelope,
+        connection_id: Option<&str>,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        cancel
+            .run_until_cancelled(self.send(envelope, connection_id))
+            .await
+            .unwrap_or_else(|| Err(UmicpError::cancelled("Send cancelled")))
     }
 
-    /// Get transport statistics
-    pub fn get_stats(&self) -> TransportStats {
-        TransportStats::default()
+    /// Receive every envelope that arrives after this call
+    fn subscribe(&self) -> Subscription;
+
+    /// Receive connection changes, errors and envelopes from this call on
+    ///
+    /// The default only reports messages, as [`TransportEvent::Message`]; transports that track
+    /// connections override it.
+    fn events(&self) -> EventStream {
+        EventStream::from_subscription(self.subscribe())
     }
 
-    /// Shutdown the transport
-    pub fn shutdown(&self) -> Result<()> {
-        Ok(())
+    /// Current transport statistics
+    async fn stats(&self) -> TransportStats;
+
+    /// Close all connections and stop the transport
+    async fn shutdown(&self) -> Result<()>;
+
+    /// Send a request and wait up to `timeout` for the correlated response
+    ///
+    /// Resolves with the first `Response` or `Ack` answering `envelope`; an `Error` reply fails
+    /// with [`UmicpError::Remote`].
+    async fn request(&self, envelope: Envelope, timeout: std::time::Duration) -> Result<Envelope> {
+        rpc::request(self, envelope, None, timeout).await
+    }
+
+    /// [`request`](Transport::request) over a specific connection
+    async fn request_to(
+        &self,
+        envelope: Envelope,
+        connection_id: &str,
+        timeout: std::time::Duration,
+    ) -> Result<Envelope> {
+        rpc::request(self, envelope, Some(connection_id), timeout).await
+    }
+
+    /// [`request`](Transport::request), giving up with [`UmicpError::Cancelled`] once `cancel`
+    /// is cancelled
+    async fn request_cancellable(
+        &self,
+        envelope: Envelope,
+        timeout: std::time::Duration,
+        cancel: &CancellationToken,
+    ) -> Result<Envelope> {
+        let message_id = envelope.message_id().to_string();
+        cancel
+            .run_until_cancelled(rpc::request(self, envelope, None, timeout))
+            .await
+            .unwrap_or_else(|| Err(UmicpError::cancelled(format!("Request {} cancelled", message_id))))
+    }
+}
+
+/// Fan-out list backing [`Transport::subscribe`] and [`Transport::events`], for reuse by
+/// transport implementations
+#[derive(Debug, Default)]
+pub struct Subscribers {
+    senders: Mutex<Vec<mpsc::UnboundedSender<Incoming>>>,
+    events: Mutex<Vec<mpsc::UnboundedSender<TransportEvent>>>,
+}
+
+impl Subscribers {
+    /// Register a new subscriber
+    pub fn subscribe(&self) -> Subscription {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.senders.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Register a new event subscriber
+    pub fn events(&self) -> EventStream {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.events.lock().unwrap().push(sender);
+        EventStream {
+            inner: EventSource::Events(receiver),
+        }
+    }
+
+    /// Deliver to every live subscriber, forgetting the ones that were dropped
+    ///
+    /// Event subscribers get it as a [`TransportEvent::Message`].
+    pub fn publish(&self, envelope: &Envelope, connection_id: &str) {
+        self.senders
+            .lock()
+            .unwrap()
+            .retain(|sender| sender.send((envelope.clone(), connection_id.to_string())).is_ok());
+
+        let mut events = self.events.lock().unwrap();
+        if !events.is_empty() {
+            let event = TransportEvent::Message(Box::new(envelope.clone()), connection_id.to_string());
+            events.retain(|sender| sender.send(event.clone()).is_ok());
+        }
+    }
+
+    /// Deliver an event to every live event subscriber
+    pub fn notify(&self, event: TransportEvent) {
+        self.events
+            .lock()
+            .unwrap()
+            .retain(|sender| sender.send(event.clone()).is_ok());
     }
 }
 
@@ -89,17 +451,26 @@ impl Http2Transport {
     }
 }
 
-impl Default for TransportStats {
-    fn default() -> Self {
-        TransportStats {
-            messages_sent: 0,
-            messages_received: 0,
-            bytes_sent: 0,
-            bytes_received: 0,
-            active_connections: 0,
-            total_connections: 0,
-            uptime_seconds: 0,
-            avg_latency_ms: None,
-        }
+#[async_trait]
+impl Transport for Http2Transport {
+    async fn connect(&self) -> Result<()> {
+        Http2Transport::connect(self)
+    }
+
+    async fn send(&self, _envelope: Envelope, _connection_id: Option<&str>) -> Result<()> {
+        Err(UmicpError::generic("HTTP/2 transport not implemented in this build"))
+    }
+
+    fn subscribe(&self) -> Subscription {
+        // Nothing is ever received, so the sender is dropped immediately
+        mpsc::unbounded_channel().1
+    }
+
+    async fn stats(&self) -> TransportStats {
+        self.get_stats()
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        Ok(())
     }
 }
@@ -0,0 +1,505 @@
+/*!
+# UMICP Client-Side Load Balancing
+
+[`Transport`] spreading a client's traffic over a pool of servers, e.g. a worker talking to
+several aggregators.
+
+Each backend is a named transport; for [`LoadBalancedTransport::websocket`] the names are the
+server URLs. Sends without a connection ID go to a backend chosen by the
+[`BalanceStrategy`]: in turn, or the one with the fewest sends and requests in flight. A send
+that fails is retried on the next backend, so one server going away costs no messages.
+
+Backends are tracked for health. One whose connection drops leaves the rotation until it
+reconnects; one that fails `failure_threshold` sends in a row leaves it for `cooldown_ms`, after
+which it gets another chance. When no backend is healthy, all of them are tried anyway.
+
+Envelopes and events from every backend are merged; their connection ID is the backend's name,
+which also addresses that backend in `send` and `request_to`.
+*/
+
+use super::{EventStream, Subscribers, Subscription, Transport, TransportEvent};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::{BalanceStrategy, BalancerConfig, TransportStats};
+use async_trait::async_trait;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Debug, Default)]
+struct Health {
+    /// Set when the backend reports a disconnect, cleared when it reconnects
+    disconnected: bool,
+    /// Consecutive failed sends
+    failures: u32,
+    /// Out of rotation until then, after too many failures
+    cooldown_until: Option<Instant>,
+}
+
+struct Backend {
+    name: String,
+    transport: Arc<dyn Transport>,
+    outstanding: AtomicUsize,
+    health: Mutex<Health>,
+    /// Taken by `connect`
+    events: Mutex<Option<EventStream>>,
+}
+
+impl Health {
+    fn in_rotation(&self) -> bool {
+        !self.disconnected && self.cooldown_until.is_none_or(|until| Instant::now() >= until)
+    }
+}
+
+/// Health snapshot of one backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendStatus {
+    /// Backend name, used as its connection ID
+    pub name: String,
+    /// Whether the backend is in rotation
+    pub healthy: bool,
+    /// Sends and requests in flight
+    pub outstanding: usize,
+    /// Consecutive failed sends
+    pub failures: u32,
+}
+
+/// Counts an operation as in flight on a backend for as long as it lives
+struct Outstanding<'a>(&'a Backend);
+
+impl<'a> Outstanding<'a> {
+    fn new(backend: &'a Backend) -> Self {
+        backend.outstanding.fetch_add(1, Ordering::SeqCst);
+        Outstanding(backend)
+    }
+}
+
+impl Drop for Outstanding<'_> {
+    fn drop(&mut self) {
+        self.0.outstanding.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct Shared {
+    backends: Vec<Backend>,
+    config: BalancerConfig,
+    /// Rotation position for round-robin and for breaking ties
+    next: AtomicUsize,
+    subscribers: Subscribers,
+}
+
+/// Client transport balancing sends over several backends
+#[derive(Clone)]
+pub struct LoadBalancedTransport {
+    shared: Arc<Shared>,
+}
+
+impl LoadBalancedTransport {
+    /// Balance over named backends; events arriving from now on are seen by the balancer
+    pub fn new(backends: Vec<(String, Arc<dyn Transport>)>, config: BalancerConfig) -> Result<Self> {
+        if backends.is_empty() {
+            return Err(UmicpError::configuration("Load balancing needs at least one backend"));
+        }
+        let backends = backends
+            .into_iter()
+            .map(|(name, transport)| Backend {
+                name,
+                events: Mutex::new(Some(transport.events())),
+                transport,
+                outstanding: AtomicUsize::new(0),
+                health: Mutex::new(Health::default()),
+            })
+            .collect();
+        Ok(LoadBalancedTransport {
+            shared: Arc::new(Shared {
+                backends,
+                config,
+                next: AtomicUsize::new(0),
+                subscribers: Subscribers::default(),
+            }),
+        })
+    }
+
+    /// Connect a WebSocket client to each of `urls` and balance over those that answer
+    ///
+    /// Fails only if none of the servers can be reached; the clients reconnect on their own as
+    /// configured in `config`.
+    #[cfg(feature = "websocket")]
+    pub async fn websocket(
+        urls: &[&str],
+        config: &crate::types::TransportConfig,
+        balancer: BalancerConfig,
+    ) -> Result<Self> {
+        let mut backends: Vec<(String, Arc<dyn Transport>)> = Vec::new();
+        let mut last_error = None;
+        for url in urls {
+            match super::WebSocketTransport::new_client_with_config(url, config).await {
+                Ok(client) => backends.push((url.to_string(), Arc::new(client))),
+                Err(error) => last_error = Some(error),
+            }
+        }
+        match (backends.is_empty(), last_error) {
+            (true, Some(error)) => Err(error),
+            _ => Self::new(backends, balancer),
+        }
+    }
+
+    /// Health of every backend, in the order they were given
+    pub fn backends(&self) -> Vec<BackendStatus> {
+        self.shared
+            .backends
+            .iter()
+            .map(|backend| {
+                let health = backend.health.lock().unwrap();
+                BackendStatus {
+                    name: backend.name.clone(),
+                    healthy: health.in_rotation(),
+                    outstanding: backend.outstanding.load(Ordering::SeqCst),
+                    failures: health.failures,
+                }
+            })
+            .collect()
+    }
+
+    /// Backends in the order to try them for the next send: healthy ones by strategy, then the rest
+    fn candidates(&self) -> Vec<&Backend> {
+        let backends = &self.shared.backends;
+        let start = self.shared.next.fetch_add(1, Ordering::Relaxed) % backends.len();
+        let rotation = backends.iter().cycle().skip(start).take(backends.len());
+        let (mut healthy, unhealthy): (Vec<&Backend>, Vec<&Backend>) =
+            rotation.partition(|backend| backend.health.lock().unwrap().in_rotation());
+        if self.shared.config.strategy == BalanceStrategy::LeastOutstanding {
+            // Stable, so ties keep the round-robin order
+            healthy.sort_by_key(|backend| backend.outstanding.load(Ordering::SeqCst));
+        }
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    fn backend(&self, name: &str) -> Result<&Backend> {
+        self.shared
+            .backends
+            .iter()
+            .find(|backend| backend.name == name)
+            .ok_or_else(|| UmicpError::connection(format!("Unknown connection: {}", name)))
+    }
+
+    fn record(&self, backend: &Backend, succeeded: bool) {
+        let mut health = backend.health.lock().unwrap();
+        if succeeded {
+            health.failures = 0;
+            health.cooldown_until = None;
+            return;
+        }
+        health.failures += 1;
+        if health.failures >= self.shared.config.failure_threshold {
+            health.cooldown_until = Some(Instant::now() + Duration::from_millis(self.shared.config.cooldown_ms));
+        }
+    }
+
+    /// Run `operation` on each candidate backend until one succeeds
+    ///
+    /// Only connection errors move on to the next backend; other errors, e.g. a timeout on a
+    /// request the server may have acted on, are returned as they are.
+    async fn with_failover<'a, F, Fut, T>(&'a self, operation: F) -> Result<T>
+    where
+        F: Fn(&'a Backend) -> Fut,
+        Fut: std::future::Future<Output = Result<T>>,
+    {
+        let mut last_error = None;
+        for backend in self.candidates() {
+            let result = {
+                let _outstanding = Outstanding::new(backend);
+                operation(backend).await
+            };
+            match result {
+                Ok(value) => {
+                    self.record(backend, true);
+                    return Ok(value);
+                }
+                Err(error @ UmicpError::Connection { .. }) => {
+                    self.record(backend, false);
+                    last_error = Some(error);
+                }
+                Err(error) => return Err(error),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| UmicpError::connection("No backend available")))
+    }
+
+    async fn forward_events(&self, index: usize, mut events: EventStream) {
+        let backend = &self.shared.backends[index];
+        while let Some(event) = events.recv().await {
+            let event = match event {
+                TransportEvent::Message(envelope, _) => {
+                    self.shared.subscribers.publish(&envelope, &backend.name);
+                    continue;
+                }
+                TransportEvent::Connected(_) => {
+                    *backend.health.lock().unwrap() = Health::default();
+                    TransportEvent::Connected(backend.name.clone())
+                }
+                TransportEvent::Disconnected(_) => {
+                    backend.health.lock().unwrap().disconnected = true;
+                    TransportEvent::Disconnected(backend.name.clone())
+                }
+                TransportEvent::Error { message, .. } => TransportEvent::Error {
+                    connection_id: Some(backend.name.clone()),
+                    message,
+                },
+                TransportEvent::SequenceGap {
+                    stream_id,
+                    expected,
+                    received,
+                    ..
+                } => TransportEvent::SequenceGap {
+                    connection_id: backend.name.clone(),
+                    stream_id,
+                    expected,
+                    received,
+                },
+                TransportEvent::Authenticated { subject, .. } => TransportEvent::Authenticated {
+                    connection_id: backend.name.clone(),
+                    subject,
+                },
+                TransportEvent::AuthenticationFailed { message, .. } => TransportEvent::AuthenticationFailed {
+                    connection_id: backend.name.clone(),
+                    message,
+                },
+                TransportEvent::Retry {
+                    message_id,
+                    attempt,
+                    error,
+                    ..
+                } => TransportEvent::Retry {
+                    connection_id: Some(backend.name.clone()),
+                    message_id,
+                    attempt,
+                    error,
+                },
+                TransportEvent::Dropped { message_id, reason, .. } => TransportEvent::Dropped {
+                    connection_id: Some(backend.name.clone()),
+                    message_id,
+                    reason,
+                },
+            };
+            self.shared.subscribers.notify(event);
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for LoadBalancedTransport {
+    /// Connect every backend and start merging their envelopes and events
+    ///
+    /// Fails only if no backend connects.
+    async fn connect(&self) -> Result<()> {
+        let mut last_error = None;
+        let mut connected = 0;
+        for (index, backend) in self.shared.backends.iter().enumerate() {
+            let events = backend
+                .events
+                .lock()
+                .unwrap()
+                .take()
+                .ok_or_else(|| UmicpError::transport("Load-balanced transport is already connected"))?;
+            let transport = self.clone();
+            tokio::spawn(async move { transport.forward_events(index, events).await });
+            match backend.transport.connect().await {
+                Ok(()) => connected += 1,
+                Err(error) => {
+                    backend.health.lock().unwrap().disconnected = true;
+                    last_error = Some(error);
+                }
+            }
+        }
+        match (connected, last_error) {
+            (0, Some(error)) => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    /// Send to the named backend, or to one chosen by the strategy with failover
+    async fn send(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<()> {
+        if let Some(name) = connection_id {
+            let backend = self.backend(name)?;
+            let _outstanding = Outstanding::new(backend);
+            let result = backend.transport.send(envelope, None).await;
+            self.record(backend, result.is_ok());
+            return result;
+        }
+        self.with_failover(|backend| backend.transport.send(envelope.clone(), None)).await
+    }
+
+    fn subscribe(&self) -> Subscription {
+        self.shared.subscribers.subscribe()
+    }
+
+    fn events(&self) -> EventStream {
+        self.shared.subscribers.events()
+    }
+
+    /// Totals over all backends; latency figures are left out, since they do not add up
+    async fn stats(&self) -> TransportStats {
+        let mut total = TransportStats::default();
+        for backend in &self.shared.backends {
+            let stats = backend.transport.stats().await;
+            total.messages_sent += stats.messages_sent;
+            total.messages_received += stats.messages_received;
+            total.bytes_sent += stats.bytes_sent;
+            total.bytes_received += stats.bytes_received;
+            total.compressed_bytes_sent += stats.compressed_bytes_sent;
+            total.uncompressed_bytes_sent += stats.uncompressed_bytes_sent;
+            total.compressed_bytes_received += stats.compressed_bytes_received;
+            total.uncompressed_bytes_received += stats.uncompressed_bytes_received;
+            total.active_connections += stats.active_connections;
+            total.total_connections += stats.total_connections;
+            total.uptime_seconds = total.uptime_seconds.max(stats.uptime_seconds);
+        }
+        total
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        for backend in &self.shared.backends {
+            backend.transport.shutdown().await?;
+        }
+        Ok(())
+    }
+
+    /// Request from a backend chosen by the strategy, counted as outstanding until answered
+    async fn request(&self, envelope: Envelope, timeout: Duration) -> Result<Envelope> {
+        self.with_failover(|backend| backend.transport.request(envelope.clone(), timeout)).await
+    }
+
+    async fn request_to(&self, envelope: Envelope, connection_id: &str, timeout: Duration) -> Result<Envelope> {
+        let backend = self.backend(connection_id)?;
+        let _outstanding = Outstanding::new(backend);
+        backend.transport.request(envelope, timeout).await
+    }
+}
+
+impl std::fmt::Debug for LoadBalancedTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoadBalancedTransport")
+            .field("config", &self.shared.config)
+            .field("backends", &self.backends())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{serve_requests, LoopbackTransport};
+    use crate::types::OperationType;
+
+    fn make_envelope(operation: OperationType) -> Envelope {
+        Envelope::builder()
+            .from("worker")
+            .to("aggregator")
+            .operation(operation)
+            .build()
+            .unwrap()
+    }
+
+    type Backends = Vec<(String, Arc<dyn Transport>)>;
+
+    /// Client ends for the balancer, named "s0", "s1", ..., and the server end of each pair
+    fn pool(size: usize) -> (Backends, Vec<LoopbackTransport>) {
+        let mut clients: Backends = Vec::new();
+        let mut servers = Vec::new();
+        for index in 0..size {
+            let name = format!("s{}", index);
+            let (server, client) = LoopbackTransport::pair_with_ids(&name, &format!("c{}", index));
+            clients.push((name, Arc::new(client)));
+            servers.push(server);
+        }
+        (clients, servers)
+    }
+
+    async fn receive(inbox: &mut Subscription, count: usize) {
+        for _ in 0..count {
+            tokio::time::timeout(Duration::from_secs(5), inbox.recv()).await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_with_failover() {
+        let (clients, servers) = pool(3);
+        let mut inboxes = Vec::new();
+        for server in &servers {
+            inboxes.push(server.subscribe());
+            server.connect().await.unwrap();
+        }
+        let balancer = LoadBalancedTransport::new(clients, BalancerConfig::default()).unwrap();
+        let mut events = balancer.events();
+        balancer.connect().await.unwrap();
+
+        for _ in 0..6 {
+            balancer.send(make_envelope(OperationType::Data), None).await.unwrap();
+        }
+        for inbox in &mut inboxes {
+            receive(inbox, 2).await;
+        }
+
+        // A dropped server leaves the rotation; nothing sent is lost meanwhile
+        servers[1].shutdown().await.unwrap();
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+            if matches!(event, TransportEvent::Disconnected(ref name) if name == "s1") {
+                break;
+            }
+        }
+        assert!(!balancer.backends()[1].healthy);
+        for _ in 0..4 {
+            balancer.send(make_envelope(OperationType::Data), None).await.unwrap();
+        }
+        receive(&mut inboxes[0], 2).await;
+        receive(&mut inboxes[2], 2).await;
+
+        // Replies carry the backend's name, which addresses it again
+        let mut incoming = balancer.subscribe();
+        servers[0].send(make_envelope(OperationType::Ack), None).await.unwrap();
+        let (_, from) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+        assert_eq!(from, "s0");
+        balancer.send(make_envelope(OperationType::Data), Some("s2")).await.unwrap();
+        receive(&mut inboxes[2], 1).await;
+        assert!(inboxes[0].try_recv().is_err());
+        assert!(balancer.send(make_envelope(OperationType::Data), Some("s9")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_least_outstanding_avoids_busy_backend() {
+        let (clients, servers) = pool(2);
+        // s0 sits on requests; s1 answers at once
+        let _slow = serve_requests(Arc::new(servers[0].clone()), |_, _| std::future::pending());
+        let _fast = serve_requests(Arc::new(servers[1].clone()), |request, _| async move {
+            Ok(request.reply(OperationType::Response))
+        });
+        for server in &servers {
+            server.connect().await.unwrap();
+        }
+        let config = BalancerConfig {
+            strategy: BalanceStrategy::LeastOutstanding,
+            ..Default::default()
+        };
+        let balancer = LoadBalancedTransport::new(clients, config).unwrap();
+        balancer.connect().await.unwrap();
+
+        let stuck = {
+            let balancer = balancer.clone();
+            let request = make_envelope(OperationType::Request);
+            tokio::spawn(async move { balancer.request(request, Duration::from_secs(60)).await })
+        };
+        while balancer.backends()[0].outstanding == 0 {
+            tokio::task::yield_now().await;
+        }
+        for _ in 0..3 {
+            let response = balancer.request(make_envelope(OperationType::Request), Duration::from_secs(5)).await;
+            assert_eq!(response.unwrap().operation(), OperationType::Response);
+        }
+        assert_eq!(balancer.backends()[0].outstanding, 1);
+        stuck.abort();
+    }
+}
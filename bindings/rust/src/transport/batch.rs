@@ -0,0 +1,154 @@
+/*!
+# UMICP Message Batches
+
+Nagle-style coalescing of small messages into one WebSocket frame. A connection sending many
+small envelopes, such as a 1 kHz telemetry stream, otherwise pays a frame header, a write and
+usually a syscall for each of them.
+
+A [`Coalescer`] holds the messages of a connection's writer until the oldest has waited the
+configured delay or the held bytes reach the configured size, then hands them out as one binary
+message:
+
+| Field | Size | Notes |
+|-------|------|-------|
+| marker | 1 | `0x04` |
+| length | 4 | big-endian length of the first message |
+| message | n | the message exactly as it would have been sent alone |
+| ... | | further length and message pairs |
+
+Every message a batch can hold starts with a byte telling how to decode it (see
+`transport::frame`), so batches do not record whether a message was text or binary. The marker
+never starts a JSON document, a CBOR map, a compressed message, a binary frame or a mux frame.
+*/
+
+use crate::error::{Result, UmicpError};
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
+
+/// First byte of every batch
+const BATCH_MARKER: u8 = 0x04;
+
+/// Messages held back to go out together
+pub(crate) struct Coalescer {
+    delay: Duration,
+    max_bytes: usize,
+    held: Vec<Message>,
+    bytes: usize,
+    /// When the oldest held message was queued
+    since: Option<Instant>,
+}
+
+impl Coalescer {
+    /// Hold messages for up to `delay`, or until `max_bytes` of them are held
+    pub(crate) fn new(delay: Duration, max_bytes: usize) -> Self {
+        Coalescer {
+            delay,
+            max_bytes,
+            held: Vec::new(),
+            bytes: 0,
+            since: None,
+        }
+    }
+
+    /// Whether `message` is small enough to be held rather than sent at once
+    pub(crate) fn accepts(&self, message: &Message) -> bool {
+        matches!(message, Message::Text(_) | Message::Binary(_)) && message.len() < self.max_bytes
+    }
+
+    /// Hold `message`; returns `true` once enough is held that the batch should go out
+    pub(crate) fn push(&mut self, message: Message, now: Instant) -> bool {
+        self.bytes += message.len();
+        self.held.push(message);
+        self.since.get_or_insert(now);
+        self.bytes >= self.max_bytes
+    }
+
+    /// When the held messages are due to go out
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.since.map(|since| since + self.delay)
+    }
+
+    /// Number of messages held
+    pub(crate) fn len(&self) -> usize {
+        self.held.len()
+    }
+
+    /// The held messages as one message, or the message itself when only one is held
+    pub(crate) fn take(&mut self) -> Option<Message> {
+        self.since = None;
+        let bytes = std::mem::take(&mut self.bytes);
+        let mut held = std::mem::take(&mut self.held);
+        if held.len() <= 1 {
+            return held.pop();
+        }
+        let mut batch = Vec::with_capacity(1 + 4 * held.len() + bytes);
+        batch.push(BATCH_MARKER);
+        for message in held {
+            let data = message.into_data();
+            batch.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            batch.extend_from_slice(&data);
+        }
+        Some(Message::Binary(batch))
+    }
+}
+
+/// Whether `bytes` are a batch
+pub(crate) fn is_batch(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&BATCH_MARKER)
+}
+
+/// The messages in a batch, in the order they were sent
+pub(crate) fn decode(bytes: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut rest = bytes
+        .strip_prefix(&[BATCH_MARKER])
+        .ok_or_else(|| UmicpError::serialization("Not a message batch"))?;
+    let mut messages = Vec::new();
+    while !rest.is_empty() {
+        let truncated = || UmicpError::serialization("Message batch is truncated");
+        let (length, tail) = rest.split_first_chunk::<4>().ok_or_else(truncated)?;
+        let length = u32::from_be_bytes(*length) as usize;
+        if tail.len() < length {
+            return Err(truncated());
+        }
+        let (message, tail) = tail.split_at(length);
+        messages.push(message.to_vec());
+        rest = tail;
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coalescer_batches_until_full_or_due() {
+        let now = Instant::now();
+        let mut coalescer = Coalescer::new(Duration::from_millis(5), 64);
+        assert!(coalescer.take().is_none());
+        assert!(!coalescer.accepts(&Message::Binary(vec![0; 64])));
+        assert!(!coalescer.accepts(&Message::Ping(Vec::new())));
+
+        // A lone message goes out as it was
+        assert!(!coalescer.push(Message::Text("{\"a\":1}".into()), now));
+        assert_eq!(coalescer.deadline(), Some(now + Duration::from_millis(5)));
+        assert_eq!(coalescer.take(), Some(Message::Text("{\"a\":1}".into())));
+        assert_eq!(coalescer.deadline(), None);
+
+        let messages = [b"{\"seq\":1}".to_vec(), vec![0x03; 30], b"{\"seq\":2}".to_vec()];
+        assert!(!coalescer.push(Message::Text(String::from_utf8(messages[0].clone()).unwrap()), now));
+        assert!(!coalescer.push(Message::Binary(messages[1].clone()), now));
+        // The byte limit sends the batch before the delay is up
+        assert!(coalescer.push(Message::Binary(vec![0x01; 30]), now));
+        assert_eq!(coalescer.len(), 3);
+        let Some(Message::Binary(batch)) = coalescer.take() else {
+            panic!("expected a binary batch");
+        };
+        assert!(is_batch(&batch));
+        assert_eq!(decode(&batch).unwrap(), vec![messages[0].clone(), messages[1].clone(), vec![0x01; 30]]);
+        assert_eq!(coalescer.len(), 0);
+
+        assert!(decode(&batch[..batch.len() - 1]).is_err());
+        assert!(decode(&messages[2]).is_err());
+    }
+}
@@ -0,0 +1,610 @@
+/*!
+# UMICP Browser WebSocket Client
+
+WebSocket client for `wasm32-unknown-unknown` (requires the `wasm` feature), built on the
+browser's own `WebSocket` through web-sys so dashboards running in a page can talk to a
+`WebSocketTransport` server directly.
+
+It speaks the native client's wire protocol: the codecs in [`TransportConfig::codecs`] are
+offered as subprotocols, both ends open with a handshake envelope (see `transport::handshake`),
+payloads go out as binary frames once the server's handshake allows them, and envelopes the
+server sends on logical streams are reassembled from their mux chunks. Pages cannot set headers
+on the upgrade request, so nothing is compressed and an `auth_token` is presented as the first
+envelope after the handshake, a `Control` envelope carrying it in its
+[`AUTH_CAPABILITY`](crate::auth::AUTH_CAPABILITY). The browser answers pings itself.
+
+Everything runs on the page's thread. Socket callbacks queue what arrives, and
+[`run`](BrowserWebSocketTransport::run), spawned with `wasm_bindgen_futures::spawn_local` (which
+is what [`Transport::connect`] does), hands it to subscribers and handlers in order. A lost
+connection is retried up to `max_reconnect_attempts` times with the backoff of
+[`TransportConfig::reconnect_delay`]. tokio's timer needs a tokio runtime, which pages do not
+have, so the backoff, the connection timeout and [`Transport::request`] timeouts use gloo timers.
+*/
+
+use super::{frame, handshake, mux, rpc};
+use super::{
+    ConnectionHandler, EventStream, MessageHandler, StateHandler, Subscribers, Subscription, Transport, TransportEvent,
+};
+use crate::auth::AUTH_CAPABILITY;
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::{
+    ConnectionState, EnvelopeCodec, FrameOptions, OperationType, PeerCapabilities, TransportConfig, TransportStats,
+};
+use async_trait::async_trait;
+use gloo_timers::future::sleep;
+use send_wrapper::SendWrapper;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{BinaryType, CloseEvent, Event, MessageEvent, WebSocket};
+
+/// What a socket's callbacks report
+enum SocketEvent {
+    Open,
+    Text(String),
+    Binary(Vec<u8>),
+    /// Browsers give no details about socket errors
+    Error,
+    /// Close code and reason
+    Closed(u16, String),
+}
+
+/// Browser socket together with the callbacks feeding its events to the transport
+struct Socket {
+    socket: WebSocket,
+    _on_open: Closure<dyn FnMut(Event)>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_error: Closure<dyn FnMut(Event)>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>,
+}
+
+impl Socket {
+    /// Start connecting to `url`, offering `codecs` as subprotocols
+    fn open(url: &str, codecs: &[EnvelopeCodec], events: mpsc::UnboundedSender<SocketEvent>) -> Result<Self> {
+        let protocols = codecs
+            .iter()
+            .map(|codec| JsValue::from_str(codec.subprotocol()))
+            .collect::<js_sys::Array>();
+        let socket = WebSocket::new_with_str_sequence(url, &protocols)
+            .map_err(|e| UmicpError::configuration(format!("Invalid WebSocket URL {}: {}", url, describe(&e))))?;
+        socket.set_binary_type(BinaryType::Arraybuffer);
+
+        let sender = events.clone();
+        let on_open = Closure::<dyn FnMut(Event)>::new(move |_| {
+            let _ = sender.send(SocketEvent::Open);
+        });
+        let sender = events.clone();
+        let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+            let data = event.data();
+            let message = match data.as_string() {
+                Some(text) => SocketEvent::Text(text),
+                None => match data.dyn_into::<js_sys::ArrayBuffer>() {
+                    Ok(buffer) => SocketEvent::Binary(js_sys::Uint8Array::new(&buffer).to_vec()),
+                    Err(_) => return,
+                },
+            };
+            let _ = sender.send(message);
+        });
+        let sender = events.clone();
+        let on_error = Closure::<dyn FnMut(Event)>::new(move |_| {
+            let _ = sender.send(SocketEvent::Error);
+        });
+        let on_close = Closure::<dyn FnMut(CloseEvent)>::new(move |event: CloseEvent| {
+            let _ = events.send(SocketEvent::Closed(event.code(), event.reason()));
+        });
+        socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        socket.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+        Ok(Socket {
+            socket,
+            _on_open: on_open,
+            _on_message: on_message,
+            _on_error: on_error,
+            _on_close: on_close,
+        })
+    }
+
+    /// Send an encoded envelope; only plain JSON goes out as text
+    fn send(&self, encoded: Vec<u8>, framed: bool, codec: EnvelopeCodec) -> Result<()> {
+        let sent = match (codec, framed) {
+            // Serialized JSON is always valid UTF-8
+            (EnvelopeCodec::Json, false) => self.socket.send_with_str(&String::from_utf8(encoded).unwrap()),
+            _ => self.socket.send_with_u8_array(&encoded),
+        };
+        sent.map_err(|e| UmicpError::connection(format!("Failed to send to {}: {}", self.socket.url(), describe(&e))))
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        // Detached first, so the callbacks are not invoked once their closures are freed
+        self.socket.set_onopen(None);
+        self.socket.set_onmessage(None);
+        self.socket.set_onerror(None);
+        self.socket.set_onclose(None);
+        let _ = self.socket.close();
+    }
+}
+
+/// How to write to the server, per the subprotocol it picked and its handshake
+struct Peer {
+    codec: EnvelopeCodec,
+    binary_frames: bool,
+    max_payload_size: usize,
+    capabilities: Option<PeerCapabilities>,
+}
+
+impl Peer {
+    fn new(codec: EnvelopeCodec) -> Self {
+        Peer {
+            codec,
+            binary_frames: false,
+            max_payload_size: usize::MAX,
+            capabilities: None,
+        }
+    }
+
+    /// Apply what the server advertised in its handshake; `codecs` are ours, most preferred first
+    fn adopt(&mut self, capabilities: PeerCapabilities, codecs: &[EnvelopeCodec]) {
+        self.binary_frames = capabilities.supports(handshake::FEATURE_BINARY_FRAMES);
+        self.max_payload_size = capabilities.max_payload_size;
+        if let Some(codec) = codecs.iter().find(|codec| capabilities.codecs.contains(codec)) {
+            self.codec = *codec;
+        }
+        self.capabilities = Some(capabilities);
+    }
+}
+
+struct Shared {
+    url: String,
+    config: TransportConfig,
+    /// What this end advertises in its handshake
+    capabilities: PeerCapabilities,
+    /// Socket of the current connection; only ever touched from the page's thread
+    socket: Mutex<Option<SendWrapper<Socket>>>,
+    /// Events of the first connection, until `run` takes them
+    events: Mutex<Option<mpsc::UnboundedReceiver<SocketEvent>>>,
+    peer: Mutex<Peer>,
+    state: Mutex<ConnectionState>,
+    message_handler: RwLock<Option<MessageHandler>>,
+    connection_handler: RwLock<Option<ConnectionHandler>>,
+    state_handler: RwLock<Option<StateHandler>>,
+    subscribers: Subscribers,
+    stats: Mutex<TransportStats>,
+    /// When the transport was created, in milliseconds since the epoch
+    started: f64,
+    shutdown: watch::Sender<bool>,
+}
+
+/// WebSocket client for UMICP envelopes in the browser
+#[derive(Clone)]
+pub struct BrowserWebSocketTransport {
+    shared: Arc<Shared>,
+}
+
+impl BrowserWebSocketTransport {
+    /// Connect to the server at `url` (`ws://` or `wss://`) with the default configuration
+    pub async fn new_client(url: &str) -> Result<Self> {
+        Self::new_client_with_config(url, &TransportConfig::default()).await
+    }
+
+    /// Connect to the server at `url`; TLS is used for `wss://` URLs, by the browser
+    ///
+    /// The TLS, proxy and compression settings of `config` do not apply: the browser decides
+    /// those.
+    pub async fn new_client_with_config(url: &str, config: &TransportConfig) -> Result<Self> {
+        if let Some(codec) = config.codecs.iter().find(|codec| !EnvelopeCodec::supported().contains(codec)) {
+            return Err(UmicpError::configuration(format!("{} is not supported by this build", codec.subprotocol())));
+        }
+        if config.codecs.is_empty() {
+            return Err(UmicpError::configuration("No envelope codecs configured"));
+        }
+
+        let (shutdown, _) = watch::channel(false);
+        let transport = BrowserWebSocketTransport {
+            shared: Arc::new(Shared {
+                url: url.to_string(),
+                config: config.clone(),
+                capabilities: PeerCapabilities {
+                    protocol_version: crate::UMICP_VERSION.to_string(),
+                    codecs: config.codecs.clone(),
+                    compression: Vec::new(),
+                    max_payload_size: config.max_payload_size,
+                    features: vec![handshake::FEATURE_BINARY_FRAMES.to_string()],
+                },
+                socket: Mutex::new(None),
+                events: Mutex::new(None),
+                peer: Mutex::new(Peer::new(EnvelopeCodec::default())),
+                state: Mutex::new(ConnectionState::Connected),
+                message_handler: RwLock::new(None),
+                connection_handler: RwLock::new(None),
+                state_handler: RwLock::new(None),
+                subscribers: Subscribers::default(),
+                stats: Mutex::new(TransportStats::default()),
+                started: js_sys::Date::now(),
+                shutdown,
+            }),
+        };
+        let events = transport.open().await?;
+        *transport.shared.events.lock().unwrap() = Some(events);
+        Ok(transport)
+    }
+
+    /// Open a socket, wait for it to connect and send our handshake (and token) on it
+    async fn open(&self) -> Result<mpsc::UnboundedReceiver<SocketEvent>> {
+        let url = &self.shared.url;
+        let config = &self.shared.config;
+        let (sender, mut events) = mpsc::unbounded_channel();
+        let socket = Socket::open(url, &config.codecs, sender)?;
+
+        let opened = async {
+            loop {
+                match events.recv().await {
+                    Some(SocketEvent::Open) => return Ok(()),
+                    Some(SocketEvent::Closed(code, reason)) => {
+                        return Err(UmicpError::connection(format!(
+                            "Failed to connect to {} (close code {}): {}",
+                            url, code, reason
+                        )))
+                    }
+                    // An error event is followed by the close event
+                    Some(_) => continue,
+                    None => return Err(UmicpError::connection(format!("Failed to connect to {}", url))),
+                }
+            }
+        };
+        tokio::select! {
+            opened = opened => opened?,
+            _ = sleep(Duration::from_secs(config.connection_timeout)) => {
+                return Err(UmicpError::timeout(format!("Connecting to {} timed out", url)));
+            }
+        }
+
+        // The browser already checked the answer is one we offered
+        let codec = EnvelopeCodec::from_subprotocol(&socket.socket.protocol()).unwrap_or_default();
+        socket.send(handshake::hello(&self.shared.capabilities).encode(codec)?, false, codec)?;
+        if let Some(token) = &config.auth_token {
+            let mut auth = Envelope::new();
+            auth.set_operation(OperationType::Control);
+            auth.add_capability(AUTH_CAPABILITY, token.expose());
+            socket.send(auth.encode(codec)?, false, codec)?;
+        }
+
+        *self.shared.peer.lock().unwrap() = Peer::new(codec);
+        *self.shared.socket.lock().unwrap() = Some(SendWrapper::new(socket));
+        {
+            let mut stats = self.shared.stats.lock().unwrap();
+            stats.active_connections = 1;
+            stats.total_connections += 1;
+        }
+        Ok(events)
+    }
+
+    /// Set message handler for incoming messages
+    ///
+    /// The handler is awaited before the next envelope is dispatched.
+    pub fn set_message_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(Envelope, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        *self.shared.message_handler.write().unwrap() = Some(super::message_handler(handler));
+    }
+
+    /// Set connection handler for connection events (`true` when connected, `false` when lost)
+    pub fn set_connection_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(bool, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        *self.shared.connection_handler.write().unwrap() = Some(super::connection_handler(handler));
+    }
+
+    /// Set a handler for connection state transitions
+    pub fn set_state_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(ConnectionState) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        *self.shared.state_handler.write().unwrap() = Some(super::state_handler(handler));
+    }
+
+    /// Current connection state
+    pub fn connection_state(&self) -> ConnectionState {
+        *self.shared.state.lock().unwrap()
+    }
+
+    /// What the server advertised in its handshake; `None` until it has arrived
+    pub fn peer_capabilities(&self) -> Option<PeerCapabilities> {
+        self.shared.peer.lock().unwrap().capabilities.clone()
+    }
+
+    /// Drive the transport until [`shutdown`](Self::shutdown) is called
+    ///
+    /// Dispatches what the server sends, reconnecting when the connection drops, and fails once
+    /// `max_reconnect_attempts` attempts in a row have failed.
+    pub async fn run(&self) -> Result<()> {
+        let mut events = self
+            .shared
+            .events
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| UmicpError::transport("Client is already running"))?;
+        let url = &self.shared.url;
+        let config = &self.shared.config;
+        loop {
+            self.notify_connection(true).await;
+            self.read_loop(events).await;
+            self.close_socket();
+            self.notify_connection(false).await;
+
+            if *self.shared.shutdown.borrow() || config.max_reconnect_attempts == 0 {
+                self.set_state(ConnectionState::Disconnected).await;
+                return Ok(());
+            }
+            events = match self.reconnect().await {
+                Some(events) => events,
+                None if *self.shared.shutdown.borrow() => {
+                    self.set_state(ConnectionState::Disconnected).await;
+                    return Ok(());
+                }
+                None => {
+                    self.set_state(ConnectionState::Failed).await;
+                    return Err(UmicpError::connection(format!(
+                        "Gave up reconnecting to {} after {} attempts",
+                        url, config.max_reconnect_attempts
+                    )));
+                }
+            };
+        }
+    }
+
+    /// Retry the connection with backoff; `None` when attempts run out or shutdown is requested
+    async fn reconnect(&self) -> Option<mpsc::UnboundedReceiver<SocketEvent>> {
+        self.set_state(ConnectionState::Reconnecting).await;
+        let mut shutdown = self.shared.shutdown.subscribe();
+        let config = &self.shared.config;
+
+        for attempt in 1..=config.max_reconnect_attempts {
+            tokio::select! {
+                _ = sleep(config.reconnect_delay(attempt)) => {}
+                _ = shutdown.wait_for(|stopped| *stopped) => return None,
+            }
+            if let Ok(events) = self.open().await {
+                // A shutdown during the attempt wins over the fresh connection
+                if *self.shared.shutdown.borrow() {
+                    self.close_socket();
+                    return None;
+                }
+                self.set_state(ConnectionState::Connected).await;
+                return Some(events);
+            }
+        }
+        None
+    }
+
+    async fn read_loop(&self, mut events: mpsc::UnboundedReceiver<SocketEvent>) {
+        let conn_id = &self.shared.url;
+        let mut shutdown = self.shared.shutdown.subscribe();
+        let mut reassembler = mux::Reassembler::new(self.shared.config.max_payload_size);
+        let codec = self.shared.peer.lock().unwrap().codec;
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = shutdown.wait_for(|stopped| *stopped) => return,
+            };
+
+            let bytes = match event {
+                Some(SocketEvent::Text(text)) => text.into_bytes(),
+                Some(SocketEvent::Binary(bytes)) => match mux::decode(&bytes) {
+                    Some((stream_id, fin, chunk)) => match reassembler.push(stream_id, fin, chunk) {
+                        Some(message) => message,
+                        None => continue,
+                    },
+                    None => bytes,
+                },
+                Some(SocketEvent::Error) => {
+                    self.shared.subscribers.notify(TransportEvent::error(Some(conn_id), "WebSocket error"));
+                    continue;
+                }
+                Some(SocketEvent::Open) => continue,
+                Some(SocketEvent::Closed(..)) | None => return,
+            };
+
+            // Frames that are not valid envelopes are dropped rather than closing the connection
+            let envelope = match frame::decode_envelope(&bytes, codec) {
+                Ok(envelope) => envelope,
+                Err(error) => {
+                    self.shared.subscribers.notify(TransportEvent::error(Some(conn_id), error));
+                    continue;
+                }
+            };
+
+            if let Some(capabilities) = handshake::parse(&envelope) {
+                self.shared.peer.lock().unwrap().adopt(capabilities, &self.shared.config.codecs);
+                continue;
+            }
+
+            {
+                let mut stats = self.shared.stats.lock().unwrap();
+                stats.messages_received += 1;
+                stats.bytes_received += bytes.len() as u64;
+            }
+
+            self.shared.subscribers.publish(&envelope, conn_id);
+
+            let handler = self.shared.message_handler.read().unwrap().clone();
+            if let Some(handler) = handler {
+                if let Err(error) = handler(envelope, conn_id.clone()).await {
+                    self.shared.subscribers.notify(TransportEvent::error(Some(conn_id), error));
+                }
+            }
+        }
+    }
+
+    /// Close the current socket, if any
+    fn close_socket(&self) {
+        if self.shared.socket.lock().unwrap().take().is_some() {
+            self.shared.stats.lock().unwrap().active_connections = 0;
+        }
+    }
+
+    async fn set_state(&self, next: ConnectionState) {
+        if std::mem::replace(&mut *self.shared.state.lock().unwrap(), next) == next {
+            return;
+        }
+        let handler = self.shared.state_handler.read().unwrap().clone();
+        if let Some(handler) = handler {
+            handler(next).await;
+        }
+    }
+
+    async fn notify_connection(&self, connected: bool) {
+        let conn_id = self.shared.url.clone();
+        self.shared.subscribers.notify(match connected {
+            true => TransportEvent::Connected(conn_id.clone()),
+            false => TransportEvent::Disconnected(conn_id.clone()),
+        });
+        let handler = self.shared.connection_handler.read().unwrap().clone();
+        if let Some(handler) = handler {
+            handler(connected, conn_id).await;
+        }
+    }
+
+    /// Send message to the server
+    ///
+    /// Envelopes with a raw payload go out as a binary frame once the server's handshake says it
+    /// takes them.
+    pub async fn send_to_server(&self, envelope: Envelope) -> Result<()> {
+        let (encoded, framed, codec) = {
+            let peer = self.shared.peer.lock().unwrap();
+            let (encoded, framed) = match envelope.payload().is_some() && peer.binary_frames {
+                true => (frame::encode(&envelope, &FrameOptions::default(), peer.codec)?, true),
+                false => (envelope.encode(peer.codec)?, false),
+            };
+            if encoded.len() > peer.max_payload_size {
+                return Err(UmicpError::validation(format!(
+                    "Envelope of {} bytes exceeds the {} bytes {} accepts",
+                    encoded.len(),
+                    peer.max_payload_size,
+                    self.shared.url
+                )));
+            }
+            (encoded, framed, peer.codec)
+        };
+
+        let size = encoded.len();
+        self.shared
+            .socket
+            .lock()
+            .unwrap()
+            .as_ref()
+            .ok_or_else(|| UmicpError::connection(format!("Not connected to {}", self.shared.url)))?
+            .send(encoded, framed, codec)?;
+
+        let mut stats = self.shared.stats.lock().unwrap();
+        stats.messages_sent += 1;
+        stats.bytes_sent += size as u64;
+        Ok(())
+    }
+
+    /// Get transport statistics
+    pub async fn get_stats(&self) -> TransportStats {
+        let mut stats = self.shared.stats.lock().unwrap().clone();
+        stats.uptime_seconds = ((js_sys::Date::now() - self.shared.started) / 1000.0) as u64;
+        stats
+    }
+
+    /// Close the connection and stop `run()`
+    pub async fn shutdown(&self) -> Result<()> {
+        self.shared.shutdown.send_replace(true);
+        self.close_socket();
+        Ok(())
+    }
+
+    /// [`Transport::request`] timed with a browser timer
+    async fn timed_request(&self, envelope: Envelope, timeout: Duration) -> Result<Envelope> {
+        let message_id = envelope.message_id().to_string();
+        // Subscribed before sending, so a fast reply cannot be missed
+        let incoming = self.shared.subscribers.subscribe();
+        self.send_to_server(envelope).await?;
+        tokio::select! {
+            reply = rpc::wait_for_reply(incoming, &message_id) => reply,
+            _ = SendWrapper::new(sleep(timeout)) => {
+                Err(UmicpError::timeout(format!("No response to {} within {:?}", message_id, timeout)))
+            }
+        }
+    }
+
+    fn check_connection(&self, connection_id: &str) -> Result<()> {
+        match connection_id == self.shared.url {
+            true => Ok(()),
+            false => Err(UmicpError::connection(format!("Unknown connection: {}", connection_id))),
+        }
+    }
+}
+
+/// Readable text for an exception thrown by a browser API
+fn describe(error: &JsValue) -> String {
+    error.as_string().unwrap_or_else(|| format!("{:?}", error))
+}
+
+#[async_trait]
+impl Transport for BrowserWebSocketTransport {
+    /// Spawn [`run`](BrowserWebSocketTransport::run) on the page's event loop
+    async fn connect(&self) -> Result<()> {
+        let transport = self.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            let _ = transport.run().await;
+        });
+        Ok(())
+    }
+
+    async fn send(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<()> {
+        if let Some(connection_id) = connection_id {
+            self.check_connection(connection_id)?;
+        }
+        self.send_to_server(envelope).await
+    }
+
+    fn subscribe(&self) -> Subscription {
+        self.shared.subscribers.subscribe()
+    }
+
+    fn events(&self) -> EventStream {
+        self.shared.subscribers.events()
+    }
+
+    async fn stats(&self) -> TransportStats {
+        self.get_stats().await
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        BrowserWebSocketTransport::shutdown(self).await
+    }
+
+    async fn request(&self, envelope: Envelope, timeout: Duration) -> Result<Envelope> {
+        self.timed_request(envelope, timeout).await
+    }
+
+    async fn request_to(&self, envelope: Envelope, connection_id: &str, timeout: Duration) -> Result<Envelope> {
+        self.check_connection(connection_id)?;
+        self.timed_request(envelope, timeout).await
+    }
+}
+
+impl std::fmt::Debug for BrowserWebSocketTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BrowserWebSocketTransport")
+            .field("url", &self.shared.url)
+            .field("state", &self.connection_state())
+            .finish()
+    }
+}
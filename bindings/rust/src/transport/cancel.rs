@@ -0,0 +1,129 @@
+/*!
+# UMICP Cancellation
+
+Cooperative cancellation for sends, requests and handlers, so an application shutting down can
+stop work it started without leaking the tasks doing it.
+
+A [`CancellationToken`] is cheap to clone; every clone observes the same [`cancel`]. A
+[`child_token`] is cancelled along with its parent but can also be cancelled on its own, which
+suits giving each request or connection a token that a transport-wide one still overrides.
+
+Operations taking a token stop at a point where nothing is left half done: an envelope is either
+queued whole or not at all, and a chunked transfer that is cancelled sends its receiver an abort
+rather than simply stopping.
+
+[`cancel`]: CancellationToken::cancel
+[`child_token`]: CancellationToken::child_token
+*/
+
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Signal that work should stop, shared by every clone
+#[derive(Debug, Clone)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    cancelled: watch::Sender<bool>,
+    parent: Option<CancellationToken>,
+}
+
+impl CancellationToken {
+    /// A token that is not cancelled
+    pub fn new() -> Self {
+        Self::with_parent(None)
+    }
+
+    fn with_parent(parent: Option<CancellationToken>) -> Self {
+        CancellationToken {
+            inner: Arc::new(Inner {
+                cancelled: watch::channel(false).0,
+                parent,
+            }),
+        }
+    }
+
+    /// A token cancelled along with this one, that can also be cancelled by itself
+    pub fn child_token(&self) -> Self {
+        Self::with_parent(Some(self.clone()))
+    }
+
+    /// Cancel this token and its children; cancelling again does nothing
+    pub fn cancel(&self) {
+        self.inner.cancelled.send_replace(true);
+    }
+
+    /// Whether this token or one of its parents was cancelled
+    pub fn is_cancelled(&self) -> bool {
+        *self.inner.cancelled.borrow() || self.inner.parent.as_ref().is_some_and(Self::is_cancelled)
+    }
+
+    /// Wait until the token is cancelled; at once if it already is
+    pub async fn cancelled(&self) {
+        let mut cancelled = self.inner.cancelled.subscribe();
+        // The sender lives as long as `self`, so waiting cannot fail
+        let own = async move {
+            let _ = cancelled.wait_for(|cancelled| *cancelled).await;
+        };
+        match &self.inner.parent {
+            Some(parent) => {
+                let inherited = Box::pin(parent.cancelled());
+                tokio::select! {
+                    _ = own => {}
+                    _ = inherited => {}
+                }
+            }
+            None => own.await,
+        }
+    }
+
+    /// Run `future` to completion unless the token is cancelled first; `None` if it was
+    ///
+    /// The future is dropped on cancellation, so it should only be interrupted where it can be.
+    pub async fn run_until_cancelled<F: Future>(&self, future: F) -> Option<F::Output> {
+        if self.is_cancelled() {
+            return None;
+        }
+        tokio::select! {
+            biased;
+            _ = self.cancelled() => None,
+            output = future => Some(output),
+        }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_cancellation_reaches_clones_and_children() {
+        let token = CancellationToken::new();
+        let child = token.child_token();
+        let clone = child.clone();
+        let waiting = tokio::spawn(async move { clone.cancelled().await });
+
+        // A child cancelled on its own leaves the parent alone
+        let sibling = token.child_token();
+        sibling.cancel();
+        assert!(sibling.is_cancelled() && !token.is_cancelled() && !child.is_cancelled());
+
+        assert_eq!(child.run_until_cancelled(async { 7 }).await, Some(7));
+        token.cancel();
+        tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+        assert!(child.is_cancelled());
+        assert_eq!(child.run_until_cancelled(std::future::pending::<()>()).await, None);
+        child.cancelled().await;
+    }
+}
@@ -0,0 +1,129 @@
+/*!
+# UMICP Message Compression
+
+Negotiation and framing of compressed messages for the WebSocket transport.
+
+The client lists the algorithms it accepts, in preference order, in the
+[`COMPRESSION_HEADER`] of the WebSocket upgrade request; the server answers with the first one it
+also supports, or leaves the header out. Both sides then compress messages of at least
+`compression_threshold` bytes with the agreed algorithm.
+
+A compressed message is a binary payload: a one-byte algorithm marker followed by the compressed
+envelope JSON. The markers never start a JSON document or a mux frame, so compressed and plain
+messages can be mixed freely on one connection.
+*/
+
+use crate::error::{Result, UmicpError};
+use crate::types::Compression;
+use std::io::{Read, Write};
+
+/// Upgrade header carrying the compression offer and answer
+pub const COMPRESSION_HEADER: &str = "x-umicp-compression";
+
+const DEFLATE_MARKER: u8 = 0x01;
+const ZSTD_MARKER: u8 = 0x02;
+
+/// Algorithms this build can use, most preferred first
+pub(crate) fn supported() -> &'static [Compression] {
+    if cfg!(feature = "zstd") {
+        &[Compression::Zstd, Compression::Deflate]
+    } else {
+        &[Compression::Deflate]
+    }
+}
+
+fn name(compression: Compression) -> &'static str {
+    match compression {
+        Compression::None => "none",
+        Compression::Deflate => "deflate",
+        Compression::Zstd => "zstd",
+    }
+}
+
+/// Header value offering every supported algorithm
+pub(crate) fn offer() -> String {
+    supported().iter().map(|c| name(*c)).collect::<Vec<_>>().join(", ")
+}
+
+/// First algorithm in a peer's offer (or answer) that this build supports
+pub(crate) fn negotiate(offer: &str) -> Compression {
+    offer
+        .split(',')
+        .map(str::trim)
+        .find_map(|token| supported().iter().copied().find(|c| name(*c).eq_ignore_ascii_case(token)))
+        .unwrap_or_default()
+}
+
+/// Header value announcing the chosen algorithm
+pub(crate) fn answer(compression: Compression) -> &'static str {
+    name(compression)
+}
+
+/// Compress `data` into a marked payload
+pub(crate) fn compress(compression: Compression, data: &[u8]) -> Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Deflate => {
+            let mut encoder = flate2::write::DeflateEncoder::new(vec![DEFLATE_MARKER], flate2::Compression::fast());
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            let mut payload = vec![ZSTD_MARKER];
+            zstd::stream::copy_encode(data, &mut payload, 0)?;
+            Ok(payload)
+        }
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => Err(UmicpError::configuration("zstd compression requires the `zstd` feature")),
+    }
+}
+
+/// Decompress a marked payload; `Ok(None)` when `payload` is not compressed
+///
+/// Output beyond `max_size` bytes is an error, so a small payload cannot expand without bound.
+pub(crate) fn decompress(payload: &[u8], max_size: usize) -> Result<Option<Vec<u8>>> {
+    let mut output = Vec::new();
+    let limit = max_size as u64 + 1;
+    match payload.first() {
+        Some(&DEFLATE_MARKER) => {
+            flate2::read::DeflateDecoder::new(&payload[1..])
+                .take(limit)
+                .read_to_end(&mut output)?;
+        }
+        #[cfg(feature = "zstd")]
+        Some(&ZSTD_MARKER) => {
+            zstd::stream::read::Decoder::new(&payload[1..])?
+                .take(limit)
+                .read_to_end(&mut output)?;
+        }
+        #[cfg(not(feature = "zstd"))]
+        Some(&ZSTD_MARKER) => return Err(UmicpError::serialization("zstd payload received without the `zstd` feature")),
+        _ => return Ok(None),
+    }
+    if output.len() > max_size {
+        return Err(UmicpError::validation(format!("Decompressed message exceeds {} bytes", max_size)));
+    }
+    Ok(Some(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_negotiation_and_round_trip() {
+        assert_eq!(negotiate("brotli, deflate"), Compression::Deflate);
+        assert_eq!(negotiate("brotli"), Compression::None);
+        assert_eq!(negotiate(&offer()), supported()[0]);
+
+        let json = format!("{{\"data\":\"{}\"}}", "abc".repeat(1000));
+        for &compression in supported() {
+            let payload = compress(compression, json.as_bytes()).unwrap();
+            assert!(payload.len() < json.len() / 10);
+            assert_eq!(decompress(&payload, json.len()).unwrap().unwrap(), json.as_bytes());
+            assert!(decompress(&payload, json.len() - 1).is_err());
+        }
+        assert_eq!(decompress(json.as_bytes(), json.len()).unwrap(), None);
+    }
+}
@@ -0,0 +1,200 @@
+/*!
+# UMICP Duplicate Detection
+
+Stores of recently seen message IDs, used by [`ReliableTransport`](super::ReliableTransport) to
+turn at-least-once delivery into exactly-once dispatch. A retransmitted envelope is still acked,
+but it reaches subscribers only the first time its ID is seen.
+
+Both stores keep a sliding window of the most recent IDs: a duplicate arriving after more than
+`window` newer envelopes is no longer recognised, so the window should cover everything that can
+arrive within the sender's retry budget. [`FileDedupStore`] also survives restarts, which matters
+when a receiver crashes after dispatching an envelope but before its ack reached the sender.
+*/
+
+use crate::error::{Result, UmicpError};
+use std::collections::{HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Record of message IDs that have already been dispatched
+pub trait DedupStore: Send + Sync {
+    /// Remember `id`, returning `false` if it was already in the window
+    fn insert(&self, id: &str) -> Result<bool>;
+}
+
+/// Bounded set of IDs, forgetting the oldest once full
+struct Window {
+    seen: HashSet<String>,
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl Window {
+    fn new(capacity: usize) -> Self {
+        Window {
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    fn insert(&mut self, id: &str) -> bool {
+        if !self.seen.insert(id.to_string()) {
+            return false;
+        }
+        self.order.push_back(id.to_string());
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// In-memory dedup store; forgotten on restart
+pub struct MemoryDedupStore {
+    window: Mutex<Window>,
+}
+
+impl MemoryDedupStore {
+    /// Remember up to `window` IDs
+    pub fn new(window: usize) -> Self {
+        MemoryDedupStore {
+            window: Mutex::new(Window::new(window)),
+        }
+    }
+}
+
+impl DedupStore for MemoryDedupStore {
+    fn insert(&self, id: &str) -> Result<bool> {
+        Ok(self.window.lock().unwrap().insert(id))
+    }
+}
+
+impl std::fmt::Debug for MemoryDedupStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let window = self.window.lock().unwrap();
+        f.debug_struct("MemoryDedupStore")
+            .field("len", &window.order.len())
+            .field("capacity", &window.capacity)
+            .finish()
+    }
+}
+
+struct FileState {
+    window: Window,
+    file: File,
+    /// Lines in the file, including ones that have left the window
+    lines: usize,
+}
+
+/// Dedup store persisted as an append-only file of IDs, one per line
+///
+/// The file is rewritten with just the current window once it holds twice as many lines.
+pub struct FileDedupStore {
+    path: PathBuf,
+    state: Mutex<FileState>,
+}
+
+impl FileDedupStore {
+    /// Open (or create) the store at `path`, reloading the most recent `window` IDs
+    pub fn open(path: impl AsRef<Path>, window: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut state = Window::new(window);
+        let mut lines = 0;
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+                if !line.is_empty() {
+                    state.insert(&line);
+                    lines += 1;
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(FileDedupStore {
+            path,
+            state: Mutex::new(FileState {
+                window: state,
+                file,
+                lines,
+            }),
+        })
+    }
+
+    fn compact(&self, state: &mut FileState) -> Result<()> {
+        let temp = self.path.with_extension("compact");
+        let mut file = File::create(&temp)?;
+        for id in &state.window.order {
+            writeln!(file, "{}", id)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&temp, &self.path)?;
+        state.file = OpenOptions::new().append(true).open(&self.path)?;
+        state.lines = state.window.order.len();
+        Ok(())
+    }
+}
+
+impl DedupStore for FileDedupStore {
+    fn insert(&self, id: &str) -> Result<bool> {
+        if id.contains('\n') {
+            return Err(UmicpError::validation("Message ID cannot contain a newline"));
+        }
+        let mut state = self.state.lock().unwrap();
+        if !state.window.insert(id) {
+            return Ok(false);
+        }
+        // Persist before dispatch, so a crash cannot let the retransmission through
+        writeln!(state.file, "{}", id)?;
+        state.file.sync_data()?;
+        state.lines += 1;
+        if state.lines >= state.window.capacity * 2 {
+            self.compact(&mut state)?;
+        }
+        Ok(true)
+    }
+}
+
+impl std::fmt::Debug for FileDedupStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileDedupStore").field("path", &self.path).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup_window_and_persistence() {
+        let memory = MemoryDedupStore::new(2);
+        assert!(memory.insert("a").unwrap());
+        assert!(!memory.insert("a").unwrap());
+        assert!(memory.insert("b").unwrap());
+        assert!(memory.insert("c").unwrap());
+        // "a" has slid out of the window
+        assert!(memory.insert("a").unwrap());
+
+        let dir = std::env::temp_dir().join(format!("umicp-dedup-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("seen.log");
+        {
+            let store = FileDedupStore::open(&path, 3).unwrap();
+            for id in ["1", "2", "3", "4", "5", "6"] {
+                assert!(store.insert(id).unwrap());
+            }
+            assert!(!store.insert("5").unwrap());
+        }
+        // Compaction kept only the window
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+        let reopened = FileDedupStore::open(&path, 3).unwrap();
+        assert!(!reopened.insert("6").unwrap());
+        assert!(reopened.insert("1").unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
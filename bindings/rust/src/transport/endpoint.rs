@@ -0,0 +1,133 @@
+/*!
+# UMICP Named Endpoints
+
+Registry of the names connections go by, so a server can address a peer as `worker-17` rather
+than by a connection ID it would otherwise have to track itself.
+
+A peer registers its names with a `Control` envelope whose [`ENDPOINT_CAPABILITY`] lists them,
+comma-separated; each request replaces the names the connection had, and an empty list drops
+them. A name claimed by another connection moves to the one registering it last, so a client
+that reconnects takes its names back even before the server has noticed the old connection is
+gone. Names are released when their connection closes.
+*/
+
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::OperationType;
+use std::collections::HashMap;
+
+/// Capability of the `Control` envelope registering a connection's endpoint names; holds them
+/// comma-separated
+pub const ENDPOINT_CAPABILITY: &str = "endpoint";
+
+/// Which connection each endpoint name is registered to
+#[derive(Debug, Default)]
+pub(crate) struct EndpointRegistry {
+    connections: HashMap<String, String>,
+    /// Names of each connection, in the order it registered them
+    names: HashMap<String, Vec<String>>,
+}
+
+impl EndpointRegistry {
+    /// Make `names` the endpoints of `connection_id`, taking them from any other connection
+    pub(crate) fn register(&mut self, connection_id: &str, names: Vec<String>) {
+        self.remove(connection_id);
+        for name in &names {
+            if let Some(previous) = self.connections.insert(name.clone(), connection_id.to_string()) {
+                if let Some(taken) = self.names.get_mut(&previous) {
+                    taken.retain(|taken| taken != name);
+                }
+            }
+        }
+        if !names.is_empty() {
+            self.names.insert(connection_id.to_string(), names);
+        }
+    }
+
+    /// Release every name of `connection_id`
+    pub(crate) fn remove(&mut self, connection_id: &str) {
+        for name in self.names.remove(connection_id).unwrap_or_default() {
+            self.connections.remove(&name);
+        }
+    }
+
+    /// Connection registered as `name`
+    pub(crate) fn resolve(&self, name: &str) -> Option<&str> {
+        self.connections.get(name).map(String::as_str)
+    }
+
+    /// Names registered by `connection_id`
+    pub(crate) fn names(&self, connection_id: &str) -> Vec<String> {
+        self.names.get(connection_id).cloned().unwrap_or_default()
+    }
+}
+
+/// `Control` envelope registering `names` as the sender's endpoints
+pub(crate) fn request(names: &[String]) -> Envelope {
+    let mut envelope = Envelope::new();
+    envelope.set_operation(OperationType::Control);
+    envelope.add_capability(ENDPOINT_CAPABILITY, &names.join(","));
+    envelope
+}
+
+/// The names a `Control` envelope registers; `None` for any other envelope
+pub(crate) fn parse_request(envelope: &Envelope) -> Option<Result<Vec<String>>> {
+    if envelope.operation() != OperationType::Control {
+        return None;
+    }
+    let listed = envelope.capabilities()?.get(ENDPOINT_CAPABILITY)?;
+    let names = listed.split(',').map(str::trim).filter(|name| !name.is_empty());
+    Some(names.map(validate).collect())
+}
+
+/// `name` if it can be registered: not empty, and free of commas and whitespace
+pub(crate) fn validate(name: &str) -> Result<String> {
+    if name.is_empty() || name.contains(',') || name.contains(char::is_whitespace) {
+        return Err(UmicpError::validation(format!("Invalid endpoint name: {:?}", name)));
+    }
+    Ok(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint_registry() {
+        let mut registry = EndpointRegistry::default();
+        registry.register("conn-1", vec!["worker-1".to_string(), "gpu".to_string()]);
+        registry.register("conn-2", vec!["worker-2".to_string()]);
+        assert_eq!(registry.resolve("worker-1"), Some("conn-1"));
+        assert_eq!(registry.resolve("worker-2"), Some("conn-2"));
+        assert_eq!(registry.resolve("worker-3"), None);
+
+        // The last connection to claim a name gets it
+        registry.register("conn-3", vec!["worker-1".to_string()]);
+        assert_eq!(registry.resolve("worker-1"), Some("conn-3"));
+        assert_eq!(registry.names("conn-1"), vec!["gpu"]);
+
+        // Closing the old connection does not release the name it lost
+        registry.remove("conn-1");
+        assert_eq!(registry.resolve("worker-1"), Some("conn-3"));
+        assert_eq!(registry.resolve("gpu"), None);
+
+        // Registering again replaces a connection's names
+        registry.register("conn-2", Vec::new());
+        assert_eq!(registry.resolve("worker-2"), None);
+        assert!(registry.names("conn-2").is_empty());
+    }
+
+    #[test]
+    fn test_endpoint_request_round_trip() {
+        let names = vec!["worker-17".to_string(), "eu-west".to_string()];
+        let envelope = Envelope::deserialize(&request(&names).serialize().unwrap()).unwrap();
+        assert_eq!(parse_request(&envelope).unwrap().unwrap(), names);
+        assert!(parse_request(&request(&[])).unwrap().unwrap().is_empty());
+
+        let mut invalid = Envelope::new();
+        invalid.set_operation(OperationType::Control);
+        invalid.add_capability(ENDPOINT_CAPABILITY, "worker 1");
+        assert!(parse_request(&invalid).unwrap().is_err());
+        assert!(parse_request(&Envelope::new()).is_none());
+    }
+}
@@ -0,0 +1,175 @@
+/*!
+# UMICP Transport Fallback
+
+Client [`Transport`] that connects over WebSocket when the network allows it and degrades to
+HTTP long-polling when it does not (requires the `long-polling` feature), so applications behind
+proxies that strip `Upgrade` headers or silently drop upgraded connections need no changes.
+
+The WebSocket connection is tried first and given `connection_timeout` seconds to complete its
+handshake. Any failure other than a refused authentication or a bad configuration, both of which
+long-polling would hit too, moves on to the long-polling URL. The choice is made once, when
+connecting; [`kind`](FallbackTransport::kind) and
+[`fallback_reason`](FallbackTransport::fallback_reason) tell which transport won and why.
+*/
+
+use super::{EventStream, LongPollingTransport, Subscription, Transport, WebSocketTransport};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::{TransportConfig, TransportStats};
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Transport a [`FallbackTransport`] is using
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallbackKind {
+    /// Connected over WebSocket
+    WebSocket,
+    /// Fell back to HTTP long-polling
+    LongPolling,
+}
+
+/// Client transport trying WebSocket first and long-polling second
+#[derive(Clone)]
+pub struct FallbackTransport {
+    inner: Arc<dyn Transport>,
+    kind: FallbackKind,
+    reason: Option<String>,
+}
+
+impl FallbackTransport {
+    /// Connect to `ws_url` over WebSocket, or to `poll_url` by long-polling if that fails
+    ///
+    /// `poll_url` is the `http://` URL of a [`LongPollingTransport`] server, typically served
+    /// next to the WebSocket endpoint.
+    pub async fn new_client(ws_url: &str, poll_url: &str, config: &TransportConfig) -> Result<Self> {
+        let timeout = Duration::from_secs(config.connection_timeout);
+        let websocket = WebSocketTransport::new_client_with_config(ws_url, config);
+        let reason = match tokio::time::timeout(timeout, websocket).await {
+            Ok(Ok(websocket)) => {
+                return Ok(FallbackTransport {
+                    inner: Arc::new(websocket),
+                    kind: FallbackKind::WebSocket,
+                    reason: None,
+                })
+            }
+            Ok(Err(error @ (UmicpError::Authentication { .. } | UmicpError::Configuration { .. }))) => {
+                return Err(error)
+            }
+            Ok(Err(error)) => error.to_string(),
+            Err(_) => format!("WebSocket handshake with {} timed out", ws_url),
+        };
+
+        let polling = LongPollingTransport::new_client(poll_url, config).await.map_err(|error| {
+            UmicpError::connection(format!("{}; long-polling fallback failed too: {}", reason, error))
+        })?;
+        Ok(FallbackTransport {
+            inner: Arc::new(polling),
+            kind: FallbackKind::LongPolling,
+            reason: Some(reason),
+        })
+    }
+
+    /// Transport in use
+    pub fn kind(&self) -> FallbackKind {
+        self.kind
+    }
+
+    /// Why WebSocket was given up on, if it was
+    pub fn fallback_reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+}
+
+#[async_trait]
+impl Transport for FallbackTransport {
+    async fn connect(&self) -> Result<()> {
+        self.inner.connect().await
+    }
+
+    async fn send(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<()> {
+        self.inner.send(envelope, connection_id).await
+    }
+
+    fn subscribe(&self) -> Subscription {
+        self.inner.subscribe()
+    }
+
+    fn events(&self) -> EventStream {
+        self.inner.events()
+    }
+
+    async fn stats(&self) -> TransportStats {
+        self.inner.stats().await
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.inner.shutdown().await
+    }
+}
+
+impl std::fmt::Debug for FallbackTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FallbackTransport")
+            .field("kind", &self.kind)
+            .field("reason", &self.reason)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::OperationType;
+
+    fn make_envelope(from: &str) -> Envelope {
+        Envelope::builder()
+            .from(from)
+            .to("peer")
+            .operation(OperationType::Data)
+            .message_id(&uuid::Uuid::new_v4().to_string())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_fallback_prefers_websocket() {
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        server.connect().await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+
+        let client = FallbackTransport::new_client(&url, "http://127.0.0.1:1", &TransportConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(client.kind(), FallbackKind::WebSocket);
+        assert!(client.fallback_reason().is_none());
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fallback_to_long_polling() {
+        let server = LongPollingTransport::new_server("127.0.0.1:0", &TransportConfig::default()).await.unwrap();
+        let mut incoming = server.subscribe();
+        server.connect().await.unwrap();
+        let addr = server.local_addr().unwrap();
+
+        // The upgrade reaches a plain HTTP server, as when a proxy strips it
+        let config = TransportConfig {
+            max_reconnect_attempts: 0,
+            ..Default::default()
+        };
+        let client = FallbackTransport::new_client(&format!("ws://{}", addr), &format!("http://{}", addr), &config)
+            .await
+            .unwrap();
+        assert_eq!(client.kind(), FallbackKind::LongPolling);
+        assert!(client.fallback_reason().is_some());
+
+        client.connect().await.unwrap();
+        client.send(make_envelope("client"), None).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap();
+        assert_eq!(received.unwrap().0.from(), "client");
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+}
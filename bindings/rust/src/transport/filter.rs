@@ -0,0 +1,446 @@
+/*!
+# UMICP Subscription Filters
+
+Filters a client registers with a server so broadcasts it does not care about never cross the
+link, such as a mobile consumer that only follows one model's updates.
+
+A [`SubscriptionFilter`] matches an envelope when every part it sets does: one of its
+operations, all of its capability matchers, and its expression. An empty filter matches
+everything. Expressions are a small boolean language over the envelope's fields:
+
+```text
+operation == data && (capabilities.model == "resnet-50" || capabilities.model ^= "vit-")
+!capabilities.debug && from != "monitor"
+```
+
+`operation`, `from`, `to` and `capabilities.<name>` compare with `==`, `!=` or `^=` (starts with)
+against a quoted string or a bare word; a field on its own tests that it is present. `!`, `&&`,
+`||` and parentheses combine tests, with the usual precedence.
+
+The filter travels as JSON in the [`FILTER_CAPABILITY`] of a `Control` envelope; the server
+answers with an `Ack`, or an `Error` reply when the filter does not parse.
+*/
+
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::OperationType;
+use serde::{Deserialize, Serialize};
+
+/// Capability of a `Control` envelope carrying a [`SubscriptionFilter`] as JSON; empty clears it
+pub const FILTER_CAPABILITY: &str = "subscription_filter";
+
+/// Which envelopes a server should forward to a connection
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SubscriptionFilter {
+    /// Operations to forward; empty forwards every operation
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub operations: Vec<OperationType>,
+    /// Capability tests that must all pass
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<CapabilityMatcher>,
+    /// Expression that must hold as well
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expression: Option<FilterExpression>,
+}
+
+/// Test on one capability of an envelope
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CapabilityMatcher {
+    /// The capability is set, to anything
+    Present(String),
+    /// The capability has exactly this value
+    Equals(String, String),
+    /// The capability's value starts with this prefix
+    Prefix(String, String),
+    /// The capability has one of these values
+    OneOf(String, Vec<String>),
+}
+
+impl CapabilityMatcher {
+    fn matches(&self, envelope: &Envelope) -> bool {
+        let value = |name: &str| envelope.capabilities().and_then(|capabilities| capabilities.get(name));
+        match self {
+            CapabilityMatcher::Present(name) => value(name).is_some(),
+            CapabilityMatcher::Equals(name, expected) => value(name) == Some(expected),
+            CapabilityMatcher::Prefix(name, prefix) => value(name).is_some_and(|value| value.starts_with(prefix)),
+            CapabilityMatcher::OneOf(name, values) => value(name).is_some_and(|value| values.contains(value)),
+        }
+    }
+}
+
+impl SubscriptionFilter {
+    /// Filter matching every envelope
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also forward envelopes with `operation`
+    pub fn operation(mut self, operation: OperationType) -> Self {
+        self.operations.push(operation);
+        self
+    }
+
+    /// Only forward envelopes that pass `matcher` as well
+    pub fn capability(mut self, matcher: CapabilityMatcher) -> Self {
+        self.capabilities.push(matcher);
+        self
+    }
+
+    /// Only forward envelopes for which `source` holds as well; fails if it does not parse
+    pub fn expression(mut self, source: &str) -> Result<Self> {
+        self.expression = Some(FilterExpression::parse(source)?);
+        Ok(self)
+    }
+
+    /// Whether `envelope` should be forwarded
+    pub fn matches(&self, envelope: &Envelope) -> bool {
+        (self.operations.is_empty() || self.operations.contains(&envelope.operation()))
+            && self.capabilities.iter().all(|matcher| matcher.matches(envelope))
+            && self.expression.as_ref().is_none_or(|expression| expression.matches(envelope))
+    }
+}
+
+/// `Control` envelope asking the peer to apply `filter`, or to drop its filter when `None`
+#[cfg(feature = "websocket")]
+pub(crate) fn request(filter: Option<&SubscriptionFilter>) -> Result<Envelope> {
+    let encoded = match filter {
+        Some(filter) => serde_json::to_string(filter)?,
+        None => String::new(),
+    };
+    let mut envelope = Envelope::new();
+    envelope.set_operation(OperationType::Control);
+    envelope.add_capability(FILTER_CAPABILITY, &encoded);
+    Ok(envelope)
+}
+
+/// The filter a `Control` envelope asks for (`Ok(None)` to clear it); `None` for any other envelope
+#[cfg(feature = "websocket")]
+pub(crate) fn parse_request(envelope: &Envelope) -> Option<Result<Option<SubscriptionFilter>>> {
+    if envelope.operation() != OperationType::Control {
+        return None;
+    }
+    let encoded = envelope.capabilities()?.get(FILTER_CAPABILITY)?;
+    Some(match encoded.is_empty() {
+        true => Ok(None),
+        false => serde_json::from_str(encoded)
+            .map(Some)
+            .map_err(|error| UmicpError::validation(format!("Invalid subscription filter: {}", error))),
+    })
+}
+
+/// Parsed filter expression; serialized as its source text
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct FilterExpression {
+    source: String,
+    root: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Present(Field),
+    Compare(Field, Comparison, String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Field {
+    Operation,
+    From,
+    To,
+    Capability(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Equals,
+    NotEquals,
+    StartsWith,
+}
+
+impl FilterExpression {
+    /// Parse `source`, failing with a validation error that says where it went wrong
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            position: 0,
+        };
+        let root = parser.or()?;
+        if let Some(token) = parser.peek() {
+            return Err(UmicpError::validation(format!("Unexpected {:?} in filter expression", token)));
+        }
+        Ok(FilterExpression {
+            source: source.to_string(),
+            root,
+        })
+    }
+
+    /// Whether the expression holds for `envelope`
+    pub fn matches(&self, envelope: &Envelope) -> bool {
+        evaluate(&self.root, envelope)
+    }
+
+    /// The expression as it was written
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+impl TryFrom<String> for FilterExpression {
+    type Error = UmicpError;
+
+    fn try_from(source: String) -> Result<Self> {
+        Self::parse(&source)
+    }
+}
+
+impl From<FilterExpression> for String {
+    fn from(expression: FilterExpression) -> String {
+        expression.source
+    }
+}
+
+impl std::fmt::Display for FilterExpression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn evaluate(expr: &Expr, envelope: &Envelope) -> bool {
+    match expr {
+        Expr::Or(left, right) => evaluate(left, envelope) || evaluate(right, envelope),
+        Expr::And(left, right) => evaluate(left, envelope) && evaluate(right, envelope),
+        Expr::Not(inner) => !evaluate(inner, envelope),
+        Expr::Present(field) => field_value(field, envelope).is_some(),
+        Expr::Compare(field, comparison, expected) => {
+            let value = field_value(field, envelope);
+            match comparison {
+                Comparison::Equals => value.as_deref() == Some(expected),
+                Comparison::NotEquals => value.as_deref() != Some(expected),
+                Comparison::StartsWith => value.is_some_and(|value| value.starts_with(expected.as_str())),
+            }
+        }
+    }
+}
+
+fn field_value(field: &Field, envelope: &Envelope) -> Option<String> {
+    match field {
+        Field::Operation => Some(envelope.operation().to_string()),
+        Field::From => Some(envelope.from().to_string()),
+        Field::To => Some(envelope.to().to_string()),
+        Field::Capability(name) => envelope.capabilities().and_then(|capabilities| capabilities.get(name)).cloned(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Text(String),
+    Compare(Comparison),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let mut pair = |second: char, token: Token| match chars.next_if(|(_, next)| *next == second) {
+            Some(_) => Ok(token),
+            None => Err(UmicpError::validation(format!("Expected `{}{}` at offset {} of filter", c, second, start))),
+        };
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '&' => pair('&', Token::And)?,
+            '|' => pair('|', Token::Or)?,
+            '=' => pair('=', Token::Compare(Comparison::Equals))?,
+            '^' => pair('=', Token::Compare(Comparison::StartsWith))?,
+            '!' => match chars.next_if(|(_, next)| *next == '=') {
+                Some(_) => Token::Compare(Comparison::NotEquals),
+                None => Token::Not,
+            },
+            '"' | '\'' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, end)) if end == c => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, escaped)) => text.push(escaped),
+                            None => break,
+                        },
+                        Some((_, other)) => text.push(other),
+                        None => {
+                            return Err(UmicpError::validation(format!(
+                                "Unterminated string at offset {} of filter",
+                                start
+                            )))
+                        }
+                    }
+                }
+                Token::Text(text)
+            }
+            c if is_word_char(c) => {
+                let mut word = c.to_string();
+                while let Some((_, next)) = chars.next_if(|(_, next)| is_word_char(*next)) {
+                    word.push(next);
+                }
+                Token::Word(word)
+            }
+            other => {
+                return Err(UmicpError::validation(format!(
+                    "Unexpected `{}` at offset {} of filter",
+                    other, start
+                )))
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ':' | '/')
+}
+
+/// Recursive-descent parser: `or := and ("||" and)*`, `and := unary ("&&" unary)*`,
+/// `unary := "!" unary | "(" or ")" | field (comparison value)?`
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let matched = self.peek() == Some(token);
+        if matched {
+            self.position += 1;
+        }
+        matched
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.eat(&Token::Or) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        while self.eat(&Token::And) {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                match self.eat(&Token::Close) {
+                    true => Ok(expr),
+                    false => Err(UmicpError::validation("Missing `)` in filter expression")),
+                }
+            }
+            Some(Token::Word(name)) => {
+                let field = parse_field(&name)?;
+                let Some(Token::Compare(comparison)) = self.peek().cloned() else {
+                    return Ok(Expr::Present(field));
+                };
+                self.position += 1;
+                match self.next() {
+                    Some(Token::Word(value) | Token::Text(value)) => Ok(Expr::Compare(field, comparison, value)),
+                    _ => Err(UmicpError::validation(format!("Missing value to compare `{}` with", name))),
+                }
+            }
+            Some(token) => Err(UmicpError::validation(format!("Unexpected {:?} in filter expression", token))),
+            None => Err(UmicpError::validation("Filter expression ends too early")),
+        }
+    }
+}
+
+fn parse_field(name: &str) -> Result<Field> {
+    match name {
+        "operation" => Ok(Field::Operation),
+        "from" => Ok(Field::From),
+        "to" => Ok(Field::To),
+        _ => match name.strip_prefix("capabilities.") {
+            Some(capability) if !capability.is_empty() => Ok(Field::Capability(capability.to_string())),
+            _ => Err(UmicpError::validation(format!("Unknown field `{}` in filter expression", name))),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_envelope(operation: OperationType, model: Option<&str>) -> Envelope {
+        let mut envelope = Envelope::builder()
+            .from("trainer")
+            .to("mobile")
+            .operation(operation)
+            .build()
+            .unwrap();
+        if let Some(model) = model {
+            envelope.add_capability("model", model);
+        }
+        envelope
+    }
+
+    #[test]
+    fn test_filter_expression() {
+        let source = r#"operation == data && (capabilities.model == "resnet-50" || capabilities.model ^= vit-)"#;
+        let expression = FilterExpression::parse(source).unwrap();
+        assert!(expression.matches(&make_envelope(OperationType::Data, Some("resnet-50"))));
+        assert!(expression.matches(&make_envelope(OperationType::Data, Some("vit-b16"))));
+        assert!(!expression.matches(&make_envelope(OperationType::Data, Some("bert"))));
+        assert!(!expression.matches(&make_envelope(OperationType::Control, Some("resnet-50"))));
+
+        // `&&` binds tighter than `||`; a bare field tests presence
+        let expression = FilterExpression::parse("from != 'trainer' || !capabilities.model && to == mobile").unwrap();
+        assert!(expression.matches(&make_envelope(OperationType::Data, None)));
+        assert!(!expression.matches(&make_envelope(OperationType::Data, Some("bert"))));
+
+        for invalid in ["operation ==", "(from", "size == 3", "from = x", "to == \"open", "from == a b"] {
+            assert!(FilterExpression::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_subscription_filter_round_trip() {
+        let filter = SubscriptionFilter::new()
+            .operation(OperationType::Data)
+            .capability(CapabilityMatcher::OneOf("model".into(), vec!["resnet-50".into(), "bert".into()]))
+            .expression("!capabilities.draft")
+            .unwrap();
+        assert!(filter.matches(&make_envelope(OperationType::Data, Some("bert"))));
+        assert!(!filter.matches(&make_envelope(OperationType::Data, Some("gpt"))));
+        assert!(!filter.matches(&make_envelope(OperationType::Request, Some("bert"))));
+        assert!(SubscriptionFilter::new().matches(&make_envelope(OperationType::Ack, None)));
+
+        let decoded: SubscriptionFilter = serde_json::from_str(&serde_json::to_string(&filter).unwrap()).unwrap();
+        assert_eq!(decoded, filter);
+        assert!(serde_json::from_str::<SubscriptionFilter>(r#"{"expression": "from =="}"#).is_err());
+    }
+}
@@ -0,0 +1,305 @@
+/*!
+# UMICP Binary Frames
+
+Binary wire format for envelopes carrying a raw payload, such as a tensor. JSON and CBOR
+documents can only carry the payload base64-encoded, a third larger than the data; a binary frame
+puts the encoded envelope and the payload bytes side by side instead.
+
+Layout, with integers big-endian:
+
+| Field | Size | Notes |
+|-------|------|-------|
+| marker | 1 | [`FRAME_MARKER`] |
+| version | 1 | currently 1 |
+| codec | 1 | 0 for JSON, 1 for CBOR |
+| flags | 1 | which [`FrameOptions`] follow, plus `compressed` and `encrypted` |
+| options | 0-20 | `frame_type` (u32), `stream_id` (u32), `sequence` (u64), `flags` (u32), each if present |
+| envelope length | 4 | |
+| envelope | n | encoded with the codec, without its payload |
+| payload | rest | raw bytes |
+
+The marker never starts a JSON document, a CBOR map, a compressed message or a mux frame, so the
+WebSocket transport sends envelopes with a payload as binary frames alongside its other
+messages. Over a plain byte stream such as TCP, [`write_frame`] and [`read_frame`] prefix each
+frame with its length.
+*/
+
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::{EnvelopeCodec, FrameOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// First byte of every binary frame
+pub const FRAME_MARKER: u8 = 0x03;
+
+const VERSION: u8 = 1;
+
+const COMPRESSED: u8 = 0x01;
+const ENCRYPTED: u8 = 0x02;
+const HAS_FRAME_TYPE: u8 = 0x04;
+const HAS_STREAM_ID: u8 = 0x08;
+const HAS_SEQUENCE: u8 = 0x10;
+const HAS_FLAGS: u8 = 0x20;
+
+/// Envelope with its frame options, as carried by a binary frame
+#[derive(Debug, Clone)]
+pub struct BinaryFrame {
+    /// Header fields of the frame
+    pub options: FrameOptions,
+    /// The envelope; its [`payload`](Envelope::payload) travels as raw bytes
+    pub envelope: Envelope,
+}
+
+impl BinaryFrame {
+    /// Frame `envelope` with `options`
+    pub fn new(envelope: Envelope, options: FrameOptions) -> Self {
+        BinaryFrame { options, envelope }
+    }
+
+    /// Whether `bytes` start like a binary frame
+    pub fn is_frame(bytes: &[u8]) -> bool {
+        bytes.first() == Some(&FRAME_MARKER)
+    }
+
+    /// Encode the frame, with the envelope encoded by `codec`
+    pub fn encode(&self, codec: EnvelopeCodec) -> Result<Vec<u8>> {
+        encode(&self.envelope, &self.options, codec)
+    }
+
+    /// Decode a frame produced by [`encode`](Self::encode)
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader(bytes);
+        let header = reader.take(4)?;
+        if header[0] != FRAME_MARKER {
+            return Err(UmicpError::serialization("Not a binary frame"));
+        }
+        if header[1] != VERSION {
+            return Err(UmicpError::serialization(format!("Unsupported binary frame version {}", header[1])));
+        }
+        let codec = match header[2] {
+            0 => EnvelopeCodec::Json,
+            1 => EnvelopeCodec::Cbor,
+            other => return Err(UmicpError::serialization(format!("Unknown envelope codec {}", other))),
+        };
+        let flags = header[3];
+
+        let options = FrameOptions {
+            frame_type: (flags & HAS_FRAME_TYPE != 0).then(|| reader.u32()).transpose()?,
+            stream_id: (flags & HAS_STREAM_ID != 0).then(|| reader.u32()).transpose()?,
+            sequence: (flags & HAS_SEQUENCE != 0).then(|| reader.u64()).transpose()?,
+            flags: (flags & HAS_FLAGS != 0).then(|| reader.u32()).transpose()?,
+            compressed: flags & COMPRESSED != 0,
+            encrypted: flags & ENCRYPTED != 0,
+        };
+        let envelope_length = reader.u32()? as usize;
+        let mut envelope = Envelope::decode(reader.take(envelope_length)?, codec)?;
+        if !reader.0.is_empty() {
+            envelope.set_payload(reader.0.to_vec());
+        }
+        Ok(BinaryFrame { options, envelope })
+    }
+}
+
+/// Encode `envelope` as a binary frame without taking ownership of it
+pub(crate) fn encode(envelope: &Envelope, options: &FrameOptions, codec: EnvelopeCodec) -> Result<Vec<u8>> {
+    let payload = envelope.payload().unwrap_or_default();
+    let envelope = envelope.encode_without_payload(codec)?;
+    let envelope_length = u32::try_from(envelope.len())
+        .map_err(|_| UmicpError::validation("Encoded envelope is too large for a binary frame"))?;
+
+    let mut flags = 0;
+    for (set, flag) in [
+        (options.compressed, COMPRESSED),
+        (options.encrypted, ENCRYPTED),
+        (options.frame_type.is_some(), HAS_FRAME_TYPE),
+        (options.stream_id.is_some(), HAS_STREAM_ID),
+        (options.sequence.is_some(), HAS_SEQUENCE),
+        (options.flags.is_some(), HAS_FLAGS),
+    ] {
+        if set {
+            flags |= flag;
+        }
+    }
+
+    let mut frame = Vec::with_capacity(28 + envelope.len() + payload.len());
+    frame.extend_from_slice(&[FRAME_MARKER, VERSION, codec_id(codec), flags]);
+    if let Some(frame_type) = options.frame_type {
+        frame.extend_from_slice(&frame_type.to_be_bytes());
+    }
+    if let Some(stream_id) = options.stream_id {
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+    }
+    if let Some(sequence) = options.sequence {
+        frame.extend_from_slice(&sequence.to_be_bytes());
+    }
+    if let Some(frame_flags) = options.flags {
+        frame.extend_from_slice(&frame_flags.to_be_bytes());
+    }
+    frame.extend_from_slice(&envelope_length.to_be_bytes());
+    frame.extend_from_slice(&envelope);
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+fn codec_id(codec: EnvelopeCodec) -> u8 {
+    match codec {
+        EnvelopeCodec::Json => 0,
+        EnvelopeCodec::Cbor => 1,
+    }
+}
+
+/// Cursor over a frame being decoded
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        if self.0.len() < count {
+            return Err(UmicpError::serialization("Binary frame is truncated"));
+        }
+        let (taken, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+/// Decode an envelope from a message that is either a binary frame or a document
+///
+/// JSON documents start with `{` and CBOR ones with a map header, so peers may switch codecs
+/// after their handshake; anything else is taken to be `codec`.
+#[cfg(any(feature = "websocket", feature = "wasm"))]
+pub(crate) fn decode_envelope(bytes: &[u8], codec: EnvelopeCodec) -> Result<Envelope> {
+    decode_message(bytes, codec).map(|(envelope, _)| envelope)
+}
+
+/// Like [`decode_envelope`], also returning the frame options (default for documents)
+#[cfg(any(feature = "websocket", feature = "wasm"))]
+pub(crate) fn decode_message(bytes: &[u8], codec: EnvelopeCodec) -> Result<(Envelope, FrameOptions)> {
+    let envelope = match bytes.first() {
+        Some(&FRAME_MARKER) => return BinaryFrame::decode(bytes).map(|frame| (frame.envelope, frame.options)),
+        Some(b'{') => Envelope::decode(bytes, EnvelopeCodec::Json),
+        Some(0xA0..=0xBF) => Envelope::decode(bytes, EnvelopeCodec::Cbor),
+        _ => Envelope::decode(bytes, codec),
+    };
+    envelope.map(|envelope| (envelope, FrameOptions::default()))
+}
+
+/// Write `frame` to a byte stream, prefixed with its length
+pub async fn write_frame<W>(writer: &mut W, frame: &BinaryFrame, codec: EnvelopeCodec) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let bytes = frame.encode(codec)?;
+    let length =
+        u32::try_from(bytes.len()).map_err(|_| UmicpError::validation("Binary frame exceeds 4 GiB"))?;
+    writer.write_all(&length.to_be_bytes()).await?;
+    writer.write_all(&bytes).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read the next length-prefixed frame from a byte stream; `Ok(None)` at a clean end of stream
+///
+/// Frames longer than `max_size` bytes are rejected before they are read.
+pub async fn read_frame<R>(reader: &mut R, max_size: usize) -> Result<Option<BinaryFrame>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error.into()),
+    }
+    let length = u32::from_be_bytes(length) as usize;
+    if length > max_size {
+        return Err(UmicpError::validation(format!(
+            "Binary frame of {} bytes exceeds the {} byte limit",
+            length, max_size
+        )));
+    }
+    let mut bytes = vec![0; length];
+    reader.read_exact(&mut bytes).await?;
+    BinaryFrame::decode(&bytes).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::OperationType;
+
+    fn tensor_envelope() -> Envelope {
+        let weights: Vec<u8> = (0..4096u32).flat_map(|i| (i as f32).to_le_bytes()).collect();
+        Envelope::builder()
+            .from("worker")
+            .to("aggregator")
+            .operation(OperationType::Data)
+            .capability("tensor", "layer.0.weight")
+            .payload(weights)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_binary_frame_round_trip() {
+        let envelope = tensor_envelope();
+        let options = FrameOptions {
+            frame_type: Some(2),
+            stream_id: Some(7),
+            sequence: Some(u64::MAX),
+            compressed: true,
+            ..Default::default()
+        };
+
+        for &codec in EnvelopeCodec::supported() {
+            let bytes = BinaryFrame::new(envelope.clone(), options.clone()).encode(codec).unwrap();
+            assert!(BinaryFrame::is_frame(&bytes));
+            // Raw payload plus a small header, where a document would carry it base64-encoded
+            assert!(bytes.len() < envelope.payload().unwrap().len() + 512);
+            assert!(envelope.encode(codec).unwrap().len() > envelope.payload().unwrap().len() * 4 / 3);
+
+            let frame = BinaryFrame::decode(&bytes).unwrap();
+            assert_eq!(frame.options.frame_type, Some(2));
+            assert_eq!(frame.options.stream_id, Some(7));
+            assert_eq!(frame.options.sequence, Some(u64::MAX));
+            assert_eq!(frame.options.flags, None);
+            assert!(frame.options.compressed && !frame.options.encrypted);
+            assert_eq!(frame.envelope.message_id(), envelope.message_id());
+            assert_eq!(frame.envelope.capabilities(), envelope.capabilities());
+            assert_eq!(frame.envelope.payload(), envelope.payload());
+
+            assert!(BinaryFrame::decode(&bytes[..20]).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_binary_frames_over_byte_stream() {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let frame = BinaryFrame::new(tensor_envelope(), FrameOptions::default());
+        let writer = tokio::spawn(async move {
+            for _ in 0..3 {
+                write_frame(&mut client, &frame, EnvelopeCodec::Json).await.unwrap();
+            }
+        });
+
+        for _ in 0..3 {
+            let received = read_frame(&mut server, 1024 * 1024).await.unwrap().unwrap();
+            assert_eq!(received.envelope.payload().unwrap().len(), 4096 * 4);
+        }
+        writer.await.unwrap();
+        assert!(read_frame(&mut server, 1024 * 1024).await.unwrap().is_none());
+
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        write_frame(&mut client, &BinaryFrame::new(tensor_envelope(), FrameOptions::default()), EnvelopeCodec::Json)
+            .await
+            .unwrap();
+        assert!(read_frame(&mut server, 1024).await.is_err());
+    }
+}
@@ -0,0 +1,136 @@
+/*!
+# UMICP Capability Handshake
+
+The first envelope each side of a connection sends: a `Control` envelope whose
+[`HANDSHAKE_CAPABILITY`] carries the protocol version, with further capabilities listing the
+sender's envelope codecs, compression algorithms, largest accepted message and optional
+features. The peer records it as [`PeerCapabilities`] and uses it to pick how to serialize
+what it sends; handshake envelopes are never delivered to handlers or subscribers.
+
+Peers that send no handshake are treated conservatively: envelopes go out in the codec agreed
+during the upgrade, with payloads embedded rather than in binary frames.
+*/
+
+use crate::envelope::Envelope;
+use crate::types::{Compression, EnvelopeCodec, OperationType, PeerCapabilities};
+
+/// Capability marking a handshake envelope; holds the sender's protocol version
+pub const HANDSHAKE_CAPABILITY: &str = "handshake";
+
+/// Feature: envelopes with a raw payload can be sent as binary frames
+pub const FEATURE_BINARY_FRAMES: &str = "binary_frames";
+
+/// Feature: chunked stream transfers
+pub const FEATURE_TRANSFERS: &str = "transfers";
+
+/// Feature: several small messages can arrive coalesced into one batch
+pub const FEATURE_BATCHES: &str = "batches";
+
+const CODECS: &str = "codecs";
+const COMPRESSION: &str = "compression";
+const MAX_PAYLOAD: &str = "max_payload";
+const FEATURES: &str = "features";
+
+/// Handshake envelope advertising `local`
+pub(crate) fn hello(local: &PeerCapabilities) -> Envelope {
+    let codecs: Vec<&str> = local.codecs.iter().map(|codec| codec_name(*codec)).collect();
+    let compression: Vec<&str> = local.compression.iter().filter_map(|c| compression_name(*c)).collect();
+
+    let mut envelope = Envelope::new();
+    envelope.set_operation(OperationType::Control);
+    envelope.add_capability(HANDSHAKE_CAPABILITY, &local.protocol_version);
+    envelope.add_capability(CODECS, &codecs.join(","));
+    envelope.add_capability(COMPRESSION, &compression.join(","));
+    envelope.add_capability(MAX_PAYLOAD, &local.max_payload_size.to_string());
+    envelope.add_capability(FEATURES, &local.features.join(","));
+    envelope
+}
+
+/// Capabilities advertised by a handshake envelope; `None` for any other envelope
+pub(crate) fn parse(envelope: &Envelope) -> Option<PeerCapabilities> {
+    if envelope.operation() != OperationType::Control {
+        return None;
+    }
+    let capabilities = envelope.capabilities()?;
+    let protocol_version = capabilities.get(HANDSHAKE_CAPABILITY)?.clone();
+    let list = |name: &str| -> Vec<String> {
+        capabilities
+            .get(name)
+            .map(|value| value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect())
+            .unwrap_or_default()
+    };
+
+    Some(PeerCapabilities {
+        protocol_version,
+        // Names this build does not know are skipped
+        codecs: list(CODECS).iter().filter_map(|name| parse_codec(name)).collect(),
+        compression: list(COMPRESSION).iter().filter_map(|name| parse_compression(name)).collect(),
+        max_payload_size: capabilities
+            .get(MAX_PAYLOAD)
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(usize::MAX),
+        features: list(FEATURES),
+    })
+}
+
+fn codec_name(codec: EnvelopeCodec) -> &'static str {
+    match codec {
+        EnvelopeCodec::Json => "json",
+        EnvelopeCodec::Cbor => "cbor",
+    }
+}
+
+fn parse_codec(name: &str) -> Option<EnvelopeCodec> {
+    [EnvelopeCodec::Json, EnvelopeCodec::Cbor]
+        .into_iter()
+        .find(|codec| codec_name(*codec) == name)
+}
+
+fn compression_name(compression: Compression) -> Option<&'static str> {
+    match compression {
+        Compression::None => None,
+        Compression::Deflate => Some("deflate"),
+        Compression::Zstd => Some("zstd"),
+    }
+}
+
+fn parse_compression(name: &str) -> Option<Compression> {
+    [Compression::Deflate, Compression::Zstd]
+        .into_iter()
+        .find(|compression| compression_name(*compression) == Some(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake_round_trip() {
+        let local = PeerCapabilities {
+            protocol_version: crate::UMICP_VERSION.to_string(),
+            codecs: vec![EnvelopeCodec::Cbor, EnvelopeCodec::Json],
+            compression: vec![Compression::Zstd, Compression::Deflate],
+            max_payload_size: 4 * 1024 * 1024,
+            features: vec![FEATURE_BINARY_FRAMES.to_string(), "from-the-future".to_string()],
+        };
+        let hello = Envelope::deserialize(&hello(&local).serialize().unwrap()).unwrap();
+        let parsed = parse(&hello).unwrap();
+        assert_eq!(parsed, local);
+        assert!(parsed.supports(FEATURE_BINARY_FRAMES) && !parsed.supports(FEATURE_TRANSFERS));
+
+        // Unknown codecs are skipped and missing fields fall back to permissive defaults
+        let mut sparse = Envelope::new();
+        sparse.add_capability(HANDSHAKE_CAPABILITY, "1.1");
+        sparse.add_capability(CODECS, "msgpack, json");
+        let parsed = parse(&sparse).unwrap();
+        assert_eq!(parsed.codecs, vec![EnvelopeCodec::Json]);
+        assert!(parsed.compression.is_empty() && parsed.features.is_empty());
+        assert_eq!(parsed.max_payload_size, usize::MAX);
+
+        let mut data = Envelope::new();
+        data.set_operation(OperationType::Data);
+        data.add_capability(HANDSHAKE_CAPABILITY, "1.0");
+        assert!(parse(&data).is_none());
+        assert!(parse(&Envelope::new()).is_none());
+    }
+}
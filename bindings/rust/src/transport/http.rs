@@ -0,0 +1,343 @@
+/*!
+# UMICP HTTP/1.1 Plumbing
+
+Just enough HTTP/1.1 for the HTTP-based transports: reading and writing message heads, and
+bodies sized by `Content-Length`. Connections stay open between requests unless a side sends
+`Connection: close`; chunked transfer encoding is not supported.
+*/
+
+use crate::error::{Result, UmicpError};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Upper bound on a message head, start line and headers together
+const MAX_HEAD: usize = 16 * 1024;
+
+/// Header carrying the session a request belongs to
+pub(crate) const SESSION_HEADER: &str = "X-UMICP-Session";
+
+/// Headers letting browser code on any origin reach the endpoints
+pub(crate) const CORS_HEADERS: &[(&str, &str)] = &[
+    ("Access-Control-Allow-Origin", "*"),
+    ("Access-Control-Allow-Methods", "GET, POST, DELETE, OPTIONS"),
+    ("Access-Control-Allow-Headers", "Content-Type, X-UMICP-Session"),
+];
+
+/// Start line and headers of a request or response
+#[derive(Debug, Clone)]
+pub(crate) struct Head {
+    pub start_line: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Head {
+    /// Value of a header, matched case-insensitively
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Method and target of a request
+    pub(crate) fn request(&self) -> (&str, &str) {
+        let mut parts = self.start_line.split_whitespace();
+        (parts.next().unwrap_or_default(), parts.next().unwrap_or_default())
+    }
+
+    /// Status code of a response
+    pub(crate) fn status(&self) -> Option<u16> {
+        self.start_line.split_whitespace().nth(1)?.parse().ok()
+    }
+
+    /// Path of a request, without its query
+    pub(crate) fn path(&self) -> &str {
+        let (_, target) = self.request();
+        target.split('?').next().unwrap_or_default()
+    }
+
+    /// Value of a query parameter of a request, as sent
+    pub(crate) fn query(&self, name: &str) -> Option<&str> {
+        let (_, target) = self.request();
+        target
+            .split_once('?')?
+            .1
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Whether the connection stays open after this message
+    pub(crate) fn keep_alive(&self) -> bool {
+        !self.header("connection").is_some_and(|value| value.eq_ignore_ascii_case("close"))
+    }
+}
+
+/// Read the next message head; `Ok(None)` if the connection closed cleanly before it
+pub(crate) async fn read_head<R>(reader: &mut R) -> Result<Option<Head>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut lines = Vec::new();
+    let mut read = 0;
+    loop {
+        let mut line = String::new();
+        let limit = (MAX_HEAD - read) as u64;
+        let count = (&mut *reader).take(limit).read_line(&mut line).await?;
+        read += count;
+        if count == 0 {
+            return match lines.is_empty() && read == 0 {
+                true => Ok(None),
+                false => Err(UmicpError::transport("Connection closed inside an HTTP message head")),
+            };
+        }
+        if !line.ends_with('\n') {
+            return Err(UmicpError::validation("HTTP message head is too large"));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            // Stray line breaks between messages are allowed
+            if lines.is_empty() {
+                continue;
+            }
+            break;
+        }
+        lines.push(line.to_string());
+    }
+
+    let mut lines = lines.into_iter();
+    let start_line = lines.next().unwrap_or_default();
+    let headers = lines
+        .map(|line| match line.split_once(':') {
+            Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+            None => Err(UmicpError::serialization(format!("Malformed HTTP header: {}", line))),
+        })
+        .collect::<Result<_>>()?;
+    Ok(Some(Head { start_line, headers }))
+}
+
+/// Read the body following `head`, refusing bodies over `max_size` bytes
+pub(crate) async fn read_body<R>(reader: &mut R, head: &Head, max_size: usize) -> Result<Vec<u8>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    if head.header("transfer-encoding").is_some() {
+        return Err(UmicpError::validation("Chunked HTTP bodies are not supported"));
+    }
+    let length: usize = match head.header("content-length") {
+        Some(length) => length
+            .parse()
+            .map_err(|_| UmicpError::serialization(format!("Invalid Content-Length: {}", length)))?,
+        None => 0,
+    };
+    if length > max_size {
+        return Err(UmicpError::validation(format!(
+            "HTTP body of {} bytes exceeds the {} byte limit",
+            length, max_size
+        )));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok(body)
+}
+
+/// Write a message head; the body, if any, follows it
+pub(crate) async fn write_head<W>(writer: &mut W, start_line: &str, headers: &[(&str, &str)]) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut head = format!("{}\r\n", start_line);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes()).await?;
+    Ok(())
+}
+
+/// Write a complete message, with a `Content-Length` for `body`
+pub(crate) async fn write_message<W>(
+    writer: &mut W,
+    start_line: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let length = body.len().to_string();
+    let mut all = headers.to_vec();
+    all.push(("Content-Length", &length));
+    write_head(writer, start_line, &all).await?;
+    writer.write_all(body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Status line for `status`
+pub(crate) fn status_line(status: u16) -> String {
+    let reason = match status {
+        200 => "OK",
+        202 => "Accepted",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    };
+    format!("HTTP/1.1 {} {}", status, reason)
+}
+
+/// `host:port` authority and base path of an `http://` URL
+pub(crate) fn parse_url(url: &str) -> Result<(String, String)> {
+    let invalid = |reason: &str| UmicpError::configuration(format!("Invalid HTTP URL {}: {}", url, reason));
+    let rest = url.strip_prefix("http://").ok_or_else(|| invalid("expected an http:// URL"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], rest[slash..].trim_end_matches('/')),
+        None => (rest, ""),
+    };
+    // An IPv6 host is bracketed
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed.split_once(']').ok_or_else(|| invalid("unclosed bracket"))?;
+            (host, rest.strip_prefix(':'))
+        }
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| invalid("bad port"))?,
+        None => 80,
+    };
+    if host.is_empty() {
+        return Err(invalid("missing host"));
+    }
+    let authority = match host.contains(':') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    };
+    Ok((authority, path.to_string()))
+}
+
+/// Open a TCP connection to `authority`, giving up after `timeout`
+pub(crate) async fn connect(authority: &str, timeout: Duration) -> Result<TcpStream> {
+    tokio::time::timeout(timeout, TcpStream::connect(authority))
+        .await
+        .map_err(|_| UmicpError::timeout(format!("Connecting to {} timed out", authority)))?
+        .map_err(|e| UmicpError::connection(format!("Failed to connect to {}: {}", authority, e)))
+}
+
+/// Client connection to one server, kept alive between requests
+pub(crate) struct ClientConnection {
+    authority: String,
+    timeout: Duration,
+    idle: tokio::sync::Mutex<Option<BufReader<TcpStream>>>,
+}
+
+impl ClientConnection {
+    pub(crate) fn new(authority: &str, timeout: Duration) -> Self {
+        ClientConnection {
+            authority: authority.to_string(),
+            timeout,
+            idle: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Send a request and read the response, whose body may be up to `max_response` bytes
+    ///
+    /// Requests wait for each other. A kept-alive connection the server has since closed is
+    /// retried once on a fresh one; a request that is cancelled midway drops its connection.
+    pub(crate) async fn request(
+        &self,
+        start_line: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+        max_response: usize,
+    ) -> Result<(Head, Vec<u8>)> {
+        let mut idle = self.idle.lock().await;
+        let mut all = vec![("Host", self.authority.as_str())];
+        all.extend_from_slice(headers);
+        loop {
+            let reused = idle.is_some();
+            // Taken out for the exchange, so it is only put back after a complete response
+            let mut connection = match idle.take() {
+                Some(connection) => connection,
+                None => BufReader::new(connect(&self.authority, self.timeout).await?),
+            };
+            let exchange = async {
+                write_message(connection.get_mut(), start_line, &all, body).await?;
+                let head = read_head(&mut connection)
+                    .await?
+                    .ok_or_else(|| UmicpError::connection(format!("{} closed the connection", self.authority)))?;
+                let response = read_body(&mut connection, &head, max_response).await?;
+                Ok::<_, UmicpError>((head, response))
+            };
+            match exchange.await {
+                Ok((head, response)) => {
+                    if head.keep_alive() {
+                        *idle = Some(connection);
+                    }
+                    return Ok((head, response));
+                }
+                Err(_) if reused => continue,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Close the kept-alive connection, if any
+    pub(crate) async fn close(&self) {
+        self.idle.lock().await.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    #[tokio::test]
+    async fn test_http_messages() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (_, mut writer) = tokio::io::split(client);
+        let mut reader = BufReader::new(server);
+
+        write_message(
+            &mut writer,
+            "POST /umicp/messages?session=abc&x=1 HTTP/1.1",
+            &[("Host", "localhost"), ("Content-Type", "application/json")],
+            b"{}",
+        )
+        .await
+        .unwrap();
+        write_head(&mut writer, "GET /events HTTP/1.1", &[("Connection", "close")]).await.unwrap();
+        drop(writer);
+
+        let head = read_head(&mut reader).await.unwrap().unwrap();
+        assert_eq!(head.request(), ("POST", "/umicp/messages?session=abc&x=1"));
+        assert_eq!(head.path(), "/umicp/messages");
+        assert_eq!(head.query("session"), Some("abc"));
+        assert_eq!(head.header("content-type"), Some("application/json"));
+        assert!(head.keep_alive());
+        assert!(read_body(&mut reader, &head, 1).await.is_err());
+        assert_eq!(read_body(&mut reader, &head, 1024).await.unwrap(), b"{}");
+
+        let head = read_head(&mut reader).await.unwrap().unwrap();
+        assert!(!head.keep_alive());
+        assert!(read_head(&mut reader).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_http_url_parsing() {
+        assert_eq!(parse_url("http://localhost:8080/umicp/").unwrap(), ("localhost:8080".into(), "/umicp".into()));
+        assert_eq!(parse_url("http://[::1]").unwrap(), ("[::1]:80".into(), String::new()));
+        assert!(parse_url("https://localhost").is_err());
+        assert!(parse_url("http://host:port").is_err());
+    }
+}
@@ -0,0 +1,121 @@
+/*!
+# UMICP Latency Histograms
+
+Fixed-size log-scale histogram of round-trip times. Bucket bounds grow by a factor of 2^(1/4)
+from 10µs, so any percentile is reported within about 19% of the true sample while recording
+stays O(1) and memory stays constant however many pings a connection lives through.
+*/
+
+use crate::types::LatencyStats;
+use std::time::Duration;
+
+const BUCKETS: usize = 128;
+/// Upper bound of the first bucket, in microseconds
+const BASE_MICROS: f64 = 10.0;
+/// Buckets per doubling of the RTT
+const STEPS_PER_DOUBLING: f64 = 4.0;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Histogram {
+    counts: [u64; BUCKETS],
+    samples: u64,
+    sum_micros: f64,
+    min_micros: f64,
+    max_micros: f64,
+    last_micros: f64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: [0; BUCKETS],
+            samples: 0,
+            sum_micros: 0.0,
+            min_micros: f64::MAX,
+            max_micros: 0.0,
+            last_micros: 0.0,
+        }
+    }
+}
+
+fn bucket(micros: f64) -> usize {
+    if micros <= BASE_MICROS {
+        return 0;
+    }
+    ((micros / BASE_MICROS).log2() * STEPS_PER_DOUBLING).ceil().min((BUCKETS - 1) as f64) as usize
+}
+
+fn upper_bound(bucket: usize) -> f64 {
+    BASE_MICROS * (bucket as f64 / STEPS_PER_DOUBLING).exp2()
+}
+
+impl Histogram {
+    pub(crate) fn record(&mut self, rtt: Duration) {
+        let micros = rtt.as_secs_f64() * 1e6;
+        self.counts[bucket(micros)] += 1;
+        self.samples += 1;
+        self.sum_micros += micros;
+        self.min_micros = self.min_micros.min(micros);
+        self.max_micros = self.max_micros.max(micros);
+        self.last_micros = micros;
+    }
+
+    /// RTT in milliseconds below which a `quantile` fraction of samples fall
+    fn percentile(&self, quantile: f64) -> f64 {
+        let rank = ((quantile * self.samples as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                // The bucket bound may overshoot the slowest sample actually seen
+                return upper_bound(index).clamp(self.min_micros, self.max_micros) / 1000.0;
+            }
+        }
+        self.max_micros / 1000.0
+    }
+
+    /// Summary, or `None` before the first sample
+    pub(crate) fn stats(&self) -> Option<LatencyStats> {
+        if self.samples == 0 {
+            return None;
+        }
+        Some(LatencyStats {
+            samples: self.samples,
+            last_ms: self.last_micros / 1000.0,
+            avg_ms: self.sum_micros / self.samples as f64 / 1000.0,
+            min_ms: self.min_micros / 1000.0,
+            max_ms: self.max_micros / 1000.0,
+            p50_ms: self.percentile(0.50),
+            p95_ms: self.percentile(0.95),
+            p99_ms: self.percentile(0.99),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_percentiles() {
+        let mut histogram = Histogram::default();
+        assert!(histogram.stats().is_none());
+
+        // 1..=100 ms: true percentiles are 50, 95 and 99 ms
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        let stats = histogram.stats().unwrap();
+        assert_eq!(stats.samples, 100);
+        assert!((stats.avg_ms - 50.5).abs() < 1e-6);
+        assert_eq!((stats.min_ms, stats.max_ms, stats.last_ms), (1.0, 100.0, 100.0));
+        for (reported, actual) in [(stats.p50_ms, 50.0), (stats.p95_ms, 95.0), (stats.p99_ms, 99.0)] {
+            assert!(reported >= actual && reported <= actual * 1.19, "{} vs {}", reported, actual);
+        }
+
+        // Out-of-range samples land in the edge buckets
+        histogram.record(Duration::from_nanos(1));
+        histogram.record(Duration::from_secs(1_000_000));
+        assert_eq!(histogram.stats().unwrap().samples, 102);
+    }
+}
@@ -0,0 +1,340 @@
+/*!
+# UMICP Loopback Transport
+
+In-process [`Transport`] backed by channels, for exercising handlers, acks and stats in tests
+and examples without opening sockets.
+
+[`LoopbackTransport::pair`] returns two connected endpoints. Envelopes are serialized on send
+and parsed on receipt, so serialization errors and byte counts behave as on a real wire. Each
+endpoint sees the other's ID as the connection ID.
+*/
+
+use super::{ConnectionHandler, EventStream, MessageHandler, Subscribers, Subscription, Transport, TransportEvent};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::TransportStats;
+use async_trait::async_trait;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use tokio::sync::{mpsc, watch};
+
+struct Endpoint {
+    id: String,
+    peer_id: String,
+    /// Queue into the peer; `None` once this endpoint has shut down
+    outbound: Mutex<Option<mpsc::UnboundedSender<String>>>,
+    /// Queue from the peer; taken by `connect`
+    inbound: Mutex<Option<mpsc::UnboundedReceiver<String>>>,
+    message_handler: RwLock<Option<MessageHandler>>,
+    connection_handler: RwLock<Option<ConnectionHandler>>,
+    subscribers: Subscribers,
+    stats: Mutex<TransportStats>,
+    started: Instant,
+    shutdown: watch::Sender<bool>,
+}
+
+/// One end of an in-memory transport pair
+#[derive(Clone)]
+pub struct LoopbackTransport {
+    endpoint: Arc<Endpoint>,
+}
+
+impl LoopbackTransport {
+    /// Create two connected endpoints with the IDs `"loopback-a"` and `"loopback-b"`
+    pub fn pair() -> (Self, Self) {
+        Self::pair_with_ids("loopback-a", "loopback-b")
+    }
+
+    /// Create two connected endpoints with the given IDs
+    pub fn pair_with_ids(a: &str, b: &str) -> (Self, Self) {
+        let (a_to_b, b_inbound) = mpsc::unbounded_channel();
+        let (b_to_a, a_inbound) = mpsc::unbounded_channel();
+        (
+            Self::endpoint(a, b, a_to_b, a_inbound),
+            Self::endpoint(b, a, b_to_a, b_inbound),
+        )
+    }
+
+    fn endpoint(
+        id: &str,
+        peer_id: &str,
+        outbound: mpsc::UnboundedSender<String>,
+        inbound: mpsc::UnboundedReceiver<String>,
+    ) -> Self {
+        let (shutdown, _) = watch::channel(false);
+        LoopbackTransport {
+            endpoint: Arc::new(Endpoint {
+                id: id.to_string(),
+                peer_id: peer_id.to_string(),
+                outbound: Mutex::new(Some(outbound)),
+                inbound: Mutex::new(Some(inbound)),
+                message_handler: RwLock::new(None),
+                connection_handler: RwLock::new(None),
+                subscribers: Subscribers::default(),
+                stats: Mutex::new(TransportStats::default()),
+                started: Instant::now(),
+                shutdown,
+            }),
+        }
+    }
+
+    /// ID of this endpoint
+    pub fn id(&self) -> &str {
+        &self.endpoint.id
+    }
+
+    /// ID of the endpoint on the other side
+    pub fn peer_id(&self) -> &str {
+        &self.endpoint.peer_id
+    }
+
+    /// Set message handler for incoming messages
+    pub fn set_message_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(Envelope, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        *self.endpoint.message_handler.write().unwrap() = Some(super::message_handler(handler));
+    }
+
+    /// Set connection handler for connection events (`true` on connect, `false` on disconnect)
+    pub fn set_connection_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(bool, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        *self.endpoint.connection_handler.write().unwrap() = Some(super::connection_handler(handler));
+    }
+
+    async fn notify_connection(&self, connected: bool) {
+        let peer_id = self.endpoint.peer_id.clone();
+        self.endpoint.subscribers.notify(match connected {
+            true => TransportEvent::Connected(peer_id),
+            false => TransportEvent::Disconnected(peer_id),
+        });
+        let handler = self.endpoint.connection_handler.read().unwrap().clone();
+        if let Some(handler) = handler {
+            handler(connected, self.endpoint.peer_id.clone()).await;
+        }
+    }
+
+    async fn receive_loop(&self, mut inbound: mpsc::UnboundedReceiver<String>) {
+        let mut shutdown = self.endpoint.shutdown.subscribe();
+        loop {
+            let json = tokio::select! {
+                json = inbound.recv() => match json {
+                    Some(json) => json,
+                    // Peer shut down or was dropped
+                    None => break,
+                },
+                _ = shutdown.wait_for(|stopped| *stopped) => break,
+            };
+
+            {
+                let mut stats = self.endpoint.stats.lock().unwrap();
+                stats.messages_received += 1;
+                stats.bytes_received += json.len() as u64;
+            }
+
+            let peer_id = &self.endpoint.peer_id;
+            let envelope = match Envelope::deserialize(&json) {
+                Ok(envelope) => envelope,
+                Err(error) => {
+                    self.endpoint.subscribers.notify(TransportEvent::error(Some(peer_id), error));
+                    continue;
+                }
+            };
+
+            self.endpoint.subscribers.publish(&envelope, peer_id);
+            let handler = self.endpoint.message_handler.read().unwrap().clone();
+            if let Some(handler) = handler {
+                if let Err(error) = handler(envelope, peer_id.clone()).await {
+                    self.endpoint.subscribers.notify(TransportEvent::error(Some(peer_id), error));
+                }
+            }
+        }
+
+        self.endpoint.stats.lock().unwrap().active_connections = 0;
+        self.notify_connection(false).await;
+    }
+}
+
+#[async_trait]
+impl Transport for LoopbackTransport {
+    /// Start delivering envelopes from the peer; ones sent earlier are delivered first
+    async fn connect(&self) -> Result<()> {
+        let inbound = self
+            .endpoint
+            .inbound
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| UmicpError::transport(format!("Loopback endpoint {} is already connected", self.endpoint.id)))?;
+
+        {
+            let mut stats = self.endpoint.stats.lock().unwrap();
+            stats.active_connections = 1;
+            stats.total_connections += 1;
+        }
+        self.notify_connection(true).await;
+
+        let transport = self.clone();
+        tokio::spawn(async move { transport.receive_loop(inbound).await });
+        Ok(())
+    }
+
+    async fn send(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<()> {
+        if let Some(connection_id) = connection_id {
+            if connection_id != self.endpoint.peer_id {
+                return Err(UmicpError::connection(format!("Unknown connection: {}", connection_id)));
+            }
+        }
+
+        let json = envelope.serialize()?;
+        let bytes = json.len() as u64;
+        let sent = match self.endpoint.outbound.lock().unwrap().as_ref() {
+            Some(outbound) => outbound.send(json).is_ok(),
+            None => false,
+        };
+        if !sent {
+            return Err(UmicpError::connection(format!("Connection closed: {}", self.endpoint.peer_id)));
+        }
+
+        let mut stats = self.endpoint.stats.lock().unwrap();
+        stats.messages_sent += 1;
+        stats.bytes_sent += bytes;
+        Ok(())
+    }
+
+    fn subscribe(&self) -> Subscription {
+        self.endpoint.subscribers.subscribe()
+    }
+
+    fn events(&self) -> EventStream {
+        self.endpoint.subscribers.events()
+    }
+
+    async fn stats(&self) -> TransportStats {
+        let mut stats = self.endpoint.stats.lock().unwrap().clone();
+        stats.uptime_seconds = self.endpoint.started.elapsed().as_secs();
+        stats
+    }
+
+    /// Stop receiving and close the channel, which disconnects the peer
+    async fn shutdown(&self) -> Result<()> {
+        self.endpoint.shutdown.send_replace(true);
+        self.endpoint.outbound.lock().unwrap().take();
+        self.endpoint.stats.lock().unwrap().active_connections = 0;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for LoopbackTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoopbackTransport")
+            .field("id", &self.endpoint.id)
+            .field("peer_id", &self.endpoint.peer_id)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::OperationType;
+    use std::time::Duration;
+
+    fn make_envelope(from: &str, to: &str, operation: OperationType) -> Envelope {
+        Envelope::builder()
+            .from(from)
+            .to(to)
+            .operation(operation)
+            .message_id(&uuid::Uuid::new_v4().to_string())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_loopback_ack_round_trip() {
+        let (server, client) = LoopbackTransport::pair();
+
+        let responder = server.clone();
+        server.set_message_handler(move |envelope, conn_id| {
+            let responder = responder.clone();
+            async move {
+                let ack = make_envelope("server", envelope.from(), OperationType::Ack);
+                responder.send(ack, Some(&conn_id)).await
+            }
+        });
+        let (events, mut disconnected) = mpsc::unbounded_channel();
+        server.set_connection_handler(move |connected, conn_id| {
+            let events = events.clone();
+            async move {
+                if !connected {
+                    let _ = events.send(conn_id);
+                }
+            }
+        });
+        server.connect().await.unwrap();
+
+        // Sent before the client connects: queued, not lost
+        client.send(make_envelope("client", "server", OperationType::Data), None).await.unwrap();
+        let mut acks = client.subscribe();
+        client.connect().await.unwrap();
+
+        let (ack, from) = tokio::time::timeout(Duration::from_secs(5), acks.recv()).await.unwrap().unwrap();
+        assert_eq!(ack.operation(), OperationType::Ack);
+        assert_eq!(from, "loopback-a");
+
+        let stats = server.stats().await;
+        assert_eq!((stats.messages_received, stats.messages_sent), (1, 1));
+        assert_eq!(stats.bytes_received, client.stats().await.bytes_sent);
+        assert!(client.send(make_envelope("c", "s", OperationType::Data), Some("nobody")).await.is_err());
+
+        client.shutdown().await.unwrap();
+        let peer = tokio::time::timeout(Duration::from_secs(5), disconnected.recv()).await.unwrap();
+        assert_eq!(peer.as_deref(), Some("loopback-b"));
+        assert!(client.send(make_envelope("c", "s", OperationType::Data), None).await.is_err());
+    }
+
+    async fn next_event(events: &mut EventStream) -> TransportEvent {
+        use futures_core::Stream;
+        let polled = std::future::poll_fn(|cx| std::pin::Pin::new(&mut *events).poll_next(cx));
+        tokio::time::timeout(Duration::from_secs(5), polled).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_loopback_events() {
+        let (server, client) = LoopbackTransport::pair();
+        server.set_message_handler(|envelope, _| async move {
+            match envelope.operation() {
+                OperationType::Control => Err(UmicpError::validation("control not accepted")),
+                _ => Ok(()),
+            }
+        });
+        let mut events = server.events();
+        server.connect().await.unwrap();
+        client.connect().await.unwrap();
+
+        let event = next_event(&mut events).await;
+        assert!(matches!(event, TransportEvent::Connected(peer) if peer == "loopback-b"));
+
+        client.send(make_envelope("client", "server", OperationType::Data), None).await.unwrap();
+        client.send(make_envelope("client", "server", OperationType::Control), None).await.unwrap();
+        for expected in [OperationType::Data, OperationType::Control] {
+            let event = next_event(&mut events).await;
+            assert!(matches!(event, TransportEvent::Message(envelope, _) if envelope.operation() == expected));
+        }
+        // The failing handler is reported, not lost
+        let event = next_event(&mut events).await;
+        assert!(matches!(
+            event,
+            TransportEvent::Error { connection_id: Some(peer), message } if peer == "loopback-b" && message.contains("control")
+        ));
+
+        client.shutdown().await.unwrap();
+        let event = next_event(&mut events).await;
+        assert!(matches!(event, TransportEvent::Disconnected(peer) if peer == "loopback-b"));
+    }
+}
@@ -0,0 +1,324 @@
+/*!
+# UMICP Middleware
+
+[`Transport`] wrapper running every envelope through a chain of middleware, so logging, auth,
+metrics or schema validation can be layered onto any transport once instead of in each handler.
+
+A middleware is an async function of the envelope, a [`MiddlewareContext`] saying which way it
+is going, and the [`Next`] step of the chain. It can inspect or rewrite the envelope before
+passing it on with [`Next::run`], act on the result afterwards, or stop the envelope by returning
+without calling it. The same chain sees both directions, in the order it was built: outgoing
+envelopes reach the wrapped transport at its end, incoming ones reach subscribers at its end.
+
+An error from the chain fails the `send` of an outgoing envelope; for an incoming one it is
+reported as a [`TransportEvent::Error`] and the envelope is dropped.
+*/
+
+use super::{EventStream, HandlerFuture, Subscribers, Subscription, Transport, TransportEvent};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::TransportStats;
+use async_trait::async_trait;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Which way an envelope is going through a [`MiddlewareTransport`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageDirection {
+    /// Received from the wrapped transport, on its way to subscribers
+    Inbound,
+    /// Passed to `send`, on its way to the wrapped transport
+    Outbound,
+}
+
+/// What a middleware knows about the envelope it was handed
+#[derive(Debug, Clone)]
+pub struct MiddlewareContext {
+    /// Which way the envelope is going
+    pub direction: MessageDirection,
+    /// Connection it arrived on, or the one `send` was given
+    pub connection_id: Option<String>,
+}
+
+/// One step of a middleware chain
+pub type Middleware = Arc<dyn Fn(Envelope, MiddlewareContext, Next) -> HandlerFuture<Result<()>> + Send + Sync>;
+
+/// Where an envelope goes once it has passed every middleware
+type Endpoint = Arc<dyn Fn(Envelope, MiddlewareContext) -> HandlerFuture<Result<()>> + Send + Sync>;
+
+/// The rest of a middleware chain, handed to each middleware
+pub struct Next {
+    chain: Arc<Vec<Middleware>>,
+    position: usize,
+    endpoint: Endpoint,
+}
+
+impl Next {
+    /// Pass `envelope` on to the next middleware, or out of the chain after the last one
+    pub async fn run(self, envelope: Envelope, context: MiddlewareContext) -> Result<()> {
+        match self.chain.get(self.position).cloned() {
+            Some(middleware) => {
+                let next = Next {
+                    chain: self.chain,
+                    position: self.position + 1,
+                    endpoint: self.endpoint,
+                };
+                middleware(envelope, context, next).await
+            }
+            None => (self.endpoint)(envelope, context).await,
+        }
+    }
+}
+
+impl std::fmt::Debug for Next {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Next")
+            .field("remaining", &(self.chain.len() - self.position))
+            .finish()
+    }
+}
+
+struct Shared {
+    inner: Arc<dyn Transport>,
+    /// Replaced, not modified, so envelopes already in the chain keep the one they started with
+    chain: RwLock<Arc<Vec<Middleware>>>,
+    /// Events of the wrapped transport; taken by `connect`
+    incoming: Mutex<Option<EventStream>>,
+    subscribers: Subscribers,
+}
+
+/// Transport wrapper passing envelopes in both directions through a middleware chain
+#[derive(Clone)]
+pub struct MiddlewareTransport {
+    shared: Arc<Shared>,
+}
+
+impl MiddlewareTransport {
+    /// Wrap `inner` with an empty chain; envelopes arriving from now on are seen by the wrapper
+    pub fn new(inner: Arc<dyn Transport>) -> Self {
+        let incoming = inner.events();
+        MiddlewareTransport {
+            shared: Arc::new(Shared {
+                inner,
+                chain: RwLock::new(Arc::new(Vec::new())),
+                incoming: Mutex::new(Some(incoming)),
+                subscribers: Subscribers::default(),
+            }),
+        }
+    }
+
+    /// Add `middleware` to the end of the chain, closest to the wrapped transport on the way out
+    /// and to subscribers on the way in
+    pub fn with<F, Fut>(self, middleware: F) -> Self
+    where
+        F: Fn(Envelope, MiddlewareContext, Next) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.push(Arc::new(move |envelope, context, next| -> HandlerFuture<Result<()>> {
+            Box::pin(middleware(envelope, context, next))
+        }));
+        self
+    }
+
+    /// Add an already boxed `middleware` to the end of the chain
+    pub fn push(&self, middleware: Middleware) {
+        let mut chain = self.shared.chain.write().unwrap();
+        let mut extended = Vec::clone(&chain);
+        extended.push(middleware);
+        *chain = Arc::new(extended);
+    }
+
+    /// Number of middleware in the chain
+    pub fn len(&self) -> usize {
+        self.shared.chain.read().unwrap().len()
+    }
+
+    /// Whether the chain is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    async fn run_chain(&self, envelope: Envelope, context: MiddlewareContext, endpoint: Endpoint) -> Result<()> {
+        let next = Next {
+            chain: self.shared.chain.read().unwrap().clone(),
+            position: 0,
+            endpoint,
+        };
+        next.run(envelope, context).await
+    }
+
+    async fn receive_loop(&self, mut incoming: EventStream) {
+        let shared = self.shared.clone();
+        let deliver: Endpoint = Arc::new(move |envelope, context| -> HandlerFuture<Result<()>> {
+            let connection_id = context.connection_id.unwrap_or_default();
+            shared.subscribers.publish(&envelope, &connection_id);
+            Box::pin(async { Ok(()) })
+        });
+
+        while let Some(event) = incoming.recv().await {
+            let (envelope, conn_id) = match event {
+                TransportEvent::Message(envelope, conn_id) => (*envelope, conn_id),
+                other => {
+                    self.shared.subscribers.notify(other);
+                    continue;
+                }
+            };
+            let context = MiddlewareContext {
+                direction: MessageDirection::Inbound,
+                connection_id: Some(conn_id.clone()),
+            };
+            if let Err(error) = self.run_chain(envelope, context, deliver.clone()).await {
+                self.shared.subscribers.notify(TransportEvent::error(Some(&conn_id), error));
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for MiddlewareTransport {
+    /// Connect the wrapped transport and start passing incoming envelopes through the chain
+    async fn connect(&self) -> Result<()> {
+        let incoming = self
+            .shared
+            .incoming
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| UmicpError::transport("Middleware transport is already connected"))?;
+        self.shared.inner.connect().await?;
+
+        let transport = self.clone();
+        tokio::spawn(async move { transport.receive_loop(incoming).await });
+        Ok(())
+    }
+
+    /// Pass `envelope` through the chain, then to the wrapped transport
+    async fn send(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<()> {
+        let inner = self.shared.inner.clone();
+        let forward: Endpoint = Arc::new(move |envelope, context| -> HandlerFuture<Result<()>> {
+            let inner = inner.clone();
+            Box::pin(async move { inner.send(envelope, context.connection_id.as_deref()).await })
+        });
+        let context = MiddlewareContext {
+            direction: MessageDirection::Outbound,
+            connection_id: connection_id.map(str::to_string),
+        };
+        self.run_chain(envelope, context, forward).await
+    }
+
+    fn subscribe(&self) -> Subscription {
+        self.shared.subscribers.subscribe()
+    }
+
+    fn events(&self) -> EventStream {
+        self.shared.subscribers.events()
+    }
+
+    async fn stats(&self) -> TransportStats {
+        self.shared.inner.stats().await
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.shared.inner.shutdown().await
+    }
+}
+
+impl std::fmt::Debug for MiddlewareTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MiddlewareTransport").field("middleware", &self.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+    use crate::types::OperationType;
+
+    fn make_envelope(operation: OperationType) -> Envelope {
+        Envelope::builder()
+            .from("peer")
+            .to("test")
+            .operation(operation)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_middleware_chain_order_and_short_circuit() {
+        let mock = MockTransport::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        let transport = MiddlewareTransport::new(Arc::new(mock.clone()))
+            .with(move |envelope, context, next| {
+                let log = log.clone();
+                async move {
+                    log.lock().unwrap().push((context.direction, envelope.operation()));
+                    next.run(envelope, context).await
+                }
+            })
+            .with(|mut envelope: Envelope, context: MiddlewareContext, next: Next| async move {
+                match context.direction {
+                    MessageDirection::Outbound => envelope.add_capability("trace", "on"),
+                    // Inbound control envelopes stop here
+                    MessageDirection::Inbound if envelope.operation() == OperationType::Control => {
+                        return Err(UmicpError::validation("Control envelopes are not accepted"))
+                    }
+                    MessageDirection::Inbound => {}
+                }
+                next.run(envelope, context).await
+            });
+        assert_eq!(transport.len(), 2);
+        let mut delivered = transport.subscribe();
+        let mut events = transport.events();
+        transport.connect().await.unwrap();
+
+        // Outbound envelopes are rewritten before they reach the wrapped transport
+        transport.send(make_envelope(OperationType::Data), Some("peer-1")).await.unwrap();
+        let sent = mock.take_sent();
+        assert_eq!(sent[0].connection_id.as_deref(), Some("peer-1"));
+        assert_eq!(sent[0].envelope.capabilities().unwrap().get("trace").map(String::as_str), Some("on"));
+
+        mock.inject(make_envelope(OperationType::Control)).await.unwrap();
+        mock.inject(make_envelope(OperationType::Data)).await.unwrap();
+        let (received, _) = delivered.recv().await.unwrap();
+        assert_eq!(received.operation(), OperationType::Data);
+        assert!(delivered.try_recv().is_err());
+        loop {
+            if let Some(TransportEvent::Error { message, .. }) = events.recv().await {
+                assert!(message.contains("not accepted"));
+                break;
+            }
+        }
+
+        // The first middleware saw every envelope, in both directions
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (MessageDirection::Outbound, OperationType::Data),
+                (MessageDirection::Inbound, OperationType::Control),
+                (MessageDirection::Inbound, OperationType::Data),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_middleware_failure_fails_send() {
+        let mock = MockTransport::new();
+        let transport = MiddlewareTransport::new(Arc::new(mock.clone())).with(
+            |envelope: Envelope, context, next: Next| async move {
+                if envelope.to().is_empty() {
+                    return Err(UmicpError::validation("Envelope has no recipient"));
+                }
+                next.run(envelope, context).await
+            },
+        );
+        transport.connect().await.unwrap();
+
+        let mut envelope = make_envelope(OperationType::Data);
+        assert!(transport.send(envelope.clone(), None).await.is_ok());
+        envelope.set_to("");
+        assert!(transport.send(envelope, None).await.is_err());
+        assert_eq!(mock.take_sent().len(), 1);
+    }
+}
@@ -0,0 +1,337 @@
+/*!
+# UMICP Mock Transport
+
+Scriptable [`Transport`] for tests: inject incoming envelopes, inspect what was sent, and queue
+faults so retry and deduplication logic can be exercised deterministically.
+
+Faults are scripted per direction and consumed in FIFO order, one per operation: each `send`
+pops the next send fault, each `inject` pops the next receive fault. Operations with no fault
+queued behave normally.
+*/
+
+use super::{ConnectionHandler, EventStream, MessageHandler, Subscribers, Subscription, Transport, TransportEvent};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::TransportStats;
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// Failure applied to a single mock operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockFault {
+    /// Report success but lose the envelope
+    Drop,
+    /// Wait before completing the operation
+    Delay(Duration),
+    /// Deliver or record the envelope twice
+    Duplicate,
+    /// Fail with a connection error and stay disconnected until `reconnect`
+    Disconnect,
+    /// Fail with a transport error, leaving the connection up
+    Error,
+}
+
+/// Envelope recorded by [`MockTransport::send`]
+#[derive(Debug, Clone)]
+pub struct SentEnvelope {
+    /// The envelope as passed to `send`
+    pub envelope: Envelope,
+    /// Target connection, if one was given
+    pub connection_id: Option<String>,
+}
+
+#[derive(Default)]
+struct State {
+    connected: bool,
+    sent: Vec<SentEnvelope>,
+    send_faults: VecDeque<MockFault>,
+    receive_faults: VecDeque<MockFault>,
+    stats: TransportStats,
+}
+
+struct Inner {
+    peer_id: String,
+    state: Mutex<State>,
+    message_handler: RwLock<Option<MessageHandler>>,
+    connection_handler: RwLock<Option<ConnectionHandler>>,
+    subscribers: Subscribers,
+}
+
+/// Scriptable in-memory transport for tests
+#[derive(Clone)]
+pub struct MockTransport {
+    inner: Arc<Inner>,
+}
+
+impl MockTransport {
+    /// Create a disconnected mock whose single peer is `"mock-peer"`
+    pub fn new() -> Self {
+        Self::with_peer_id("mock-peer")
+    }
+
+    /// Create a disconnected mock with the given peer ID
+    pub fn with_peer_id(peer_id: &str) -> Self {
+        MockTransport {
+            inner: Arc::new(Inner {
+                peer_id: peer_id.to_string(),
+                state: Mutex::new(State::default()),
+                message_handler: RwLock::new(None),
+                connection_handler: RwLock::new(None),
+                subscribers: Subscribers::default(),
+            }),
+        }
+    }
+
+    /// Set message handler for injected messages
+    pub fn set_message_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(Envelope, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        *self.inner.message_handler.write().unwrap() = Some(super::message_handler(handler));
+    }
+
+    /// Set connection handler for connection events
+    pub fn set_connection_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(bool, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        *self.inner.connection_handler.write().unwrap() = Some(super::connection_handler(handler));
+    }
+
+    /// Queue a fault for an upcoming `send`
+    pub fn script_send(&self, fault: MockFault) {
+        self.inner.state.lock().unwrap().send_faults.push_back(fault);
+    }
+
+    /// Queue a fault for an upcoming `inject`
+    pub fn script_receive(&self, fault: MockFault) {
+        self.inner.state.lock().unwrap().receive_faults.push_back(fault);
+    }
+
+    /// Deliver an envelope as if it arrived from the peer
+    ///
+    /// Subscribers are fed and the message handler is awaited before this returns. Fails when
+    /// the mock is disconnected or a scripted fault says so.
+    pub async fn inject(&self, envelope: Envelope) -> Result<()> {
+        let copies = match self.next_fault(false).await? {
+            Some(MockFault::Drop) => 0,
+            Some(MockFault::Duplicate) => 2,
+            _ => 1,
+        };
+
+        for _ in 0..copies {
+            {
+                let mut state = self.inner.state.lock().unwrap();
+                state.stats.messages_received += 1;
+                state.stats.bytes_received += envelope.serialize()?.len() as u64;
+            }
+            self.inner.subscribers.publish(&envelope, &self.inner.peer_id);
+            let handler = self.inner.message_handler.read().unwrap().clone();
+            if let Some(handler) = handler {
+                handler(envelope.clone(), self.inner.peer_id.clone()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Envelopes sent so far
+    pub fn sent(&self) -> Vec<SentEnvelope> {
+        self.inner.state.lock().unwrap().sent.clone()
+    }
+
+    /// Envelopes sent so far, clearing the record
+    pub fn take_sent(&self) -> Vec<SentEnvelope> {
+        std::mem::take(&mut self.inner.state.lock().unwrap().sent)
+    }
+
+    /// Whether the mock is currently connected
+    pub fn is_connected(&self) -> bool {
+        self.inner.state.lock().unwrap().connected
+    }
+
+    /// Simulate the peer dropping the connection
+    pub async fn disconnect(&self) {
+        let was_connected = std::mem::replace(&mut self.inner.state.lock().unwrap().connected, false);
+        if was_connected {
+            self.inner.state.lock().unwrap().stats.active_connections = 0;
+            self.notify_connection(false).await;
+        }
+    }
+
+    /// Re-establish the connection after a disconnect
+    pub async fn reconnect(&self) -> Result<()> {
+        Transport::connect(self).await
+    }
+
+    async fn notify_connection(&self, connected: bool) {
+        let peer_id = self.inner.peer_id.clone();
+        self.inner.subscribers.notify(match connected {
+            true => TransportEvent::Connected(peer_id),
+            false => TransportEvent::Disconnected(peer_id),
+        });
+        let handler = self.inner.connection_handler.read().unwrap().clone();
+        if let Some(handler) = handler {
+            handler(connected, self.inner.peer_id.clone()).await;
+        }
+    }
+
+    /// Pop and apply the next fault for one direction; delays are served here
+    async fn next_fault(&self, sending: bool) -> Result<Option<MockFault>> {
+        let fault = {
+            let mut state = self.inner.state.lock().unwrap();
+            if !state.connected {
+                return Err(UmicpError::connection(format!("Mock connection to {} is down", self.inner.peer_id)));
+            }
+            if sending {
+                state.send_faults.pop_front()
+            } else {
+                state.receive_faults.pop_front()
+            }
+        };
+
+        match fault {
+            Some(MockFault::Delay(delay)) => tokio::time::sleep(delay).await,
+            Some(MockFault::Disconnect) => {
+                self.disconnect().await;
+                return Err(UmicpError::connection(format!("Scripted disconnect from {}", self.inner.peer_id)));
+            }
+            Some(MockFault::Error) => return Err(UmicpError::transport("Scripted transport failure")),
+            _ => {}
+        }
+        Ok(fault)
+    }
+}
+
+impl Default for MockTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Transport for MockTransport {
+    async fn connect(&self) -> Result<()> {
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            if state.connected {
+                return Ok(());
+            }
+            state.connected = true;
+            state.stats.active_connections = 1;
+            state.stats.total_connections += 1;
+        }
+        self.notify_connection(true).await;
+        Ok(())
+    }
+
+    async fn send(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<()> {
+        let bytes = envelope.serialize()?.len() as u64;
+        let copies = match self.next_fault(true).await? {
+            Some(MockFault::Drop) => 0,
+            Some(MockFault::Duplicate) => 2,
+            _ => 1,
+        };
+
+        let mut state = self.inner.state.lock().unwrap();
+        // A dropped envelope still counts as sent: the caller saw success
+        state.stats.messages_sent += 1;
+        state.stats.bytes_sent += bytes;
+        for _ in 0..copies {
+            state.sent.push(SentEnvelope {
+                envelope: envelope.clone(),
+                connection_id: connection_id.map(str::to_string),
+            });
+        }
+        Ok(())
+    }
+
+    fn subscribe(&self) -> Subscription {
+        self.inner.subscribers.subscribe()
+    }
+
+    fn events(&self) -> EventStream {
+        self.inner.subscribers.events()
+    }
+
+    async fn stats(&self) -> TransportStats {
+        self.inner.state.lock().unwrap().stats.clone()
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.disconnect().await;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for MockTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.inner.state.lock().unwrap();
+        f.debug_struct("MockTransport")
+            .field("peer_id", &self.inner.peer_id)
+            .field("connected", &state.connected)
+            .field("sent", &state.sent.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::OperationType;
+
+    fn make_envelope(operation: OperationType) -> Envelope {
+        Envelope::builder()
+            .from("test")
+            .to("mock-peer")
+            .operation(operation)
+            .message_id(&uuid::Uuid::new_v4().to_string())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mock_scripted_faults() {
+        let mock = MockTransport::new();
+        assert!(mock.send(make_envelope(OperationType::Data), None).await.is_err());
+        mock.connect().await.unwrap();
+
+        mock.script_send(MockFault::Drop);
+        mock.script_send(MockFault::Duplicate);
+        mock.script_send(MockFault::Error);
+        mock.script_send(MockFault::Delay(Duration::from_secs(30)));
+        mock.send(make_envelope(OperationType::Data), None).await.unwrap();
+        mock.send(make_envelope(OperationType::Data), Some("mock-peer")).await.unwrap();
+        assert!(mock.send(make_envelope(OperationType::Data), None).await.is_err());
+
+        let before = tokio::time::Instant::now();
+        mock.send(make_envelope(OperationType::Data), None).await.unwrap();
+        assert!(before.elapsed() >= Duration::from_secs(30));
+
+        let sent = mock.take_sent();
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[0].envelope.message_id(), sent[1].envelope.message_id());
+        assert_eq!(sent[0].connection_id.as_deref(), Some("mock-peer"));
+
+        let mut incoming = mock.subscribe();
+        mock.script_receive(MockFault::Duplicate);
+        mock.inject(make_envelope(OperationType::Ack)).await.unwrap();
+        assert_eq!(incoming.try_recv().unwrap().0.operation(), OperationType::Ack);
+        assert!(incoming.try_recv().is_ok());
+        assert!(incoming.try_recv().is_err());
+
+        mock.script_send(MockFault::Disconnect);
+        assert!(mock.send(make_envelope(OperationType::Data), None).await.is_err());
+        assert!(!mock.is_connected());
+        assert!(mock.inject(make_envelope(OperationType::Ack)).await.is_err());
+        mock.reconnect().await.unwrap();
+        mock.send(make_envelope(OperationType::Data), None).await.unwrap();
+
+        let stats = mock.stats().await;
+        assert_eq!((stats.messages_sent, stats.messages_received, stats.total_connections), (4, 2, 2));
+    }
+}
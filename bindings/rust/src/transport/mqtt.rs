@@ -0,0 +1,545 @@
+/*!
+# UMICP MQTT Transport
+
+rumqttc based adapter (requires the `mqtt` feature) that carries envelopes through an MQTT 3.1.1
+or MQTT 5 broker, so fleets already speaking MQTT can exchange UMICP envelopes without a custom
+gateway.
+
+Every participant subscribes to its own inbox topic, `{topic_prefix}/{client_id}`. An envelope is
+published to the inbox of its `to` address (or of the connection ID passed to `send`), as JSON,
+with a QoS picked from its operation (see [`MqttQos::for_operation`]). MQTT hides the publisher,
+so handlers receive the envelope's `from` address as the connection ID; sending a reply to that
+ID reaches the sender's inbox.
+
+The broker connection is re-established automatically until [`shutdown`](Transport::shutdown);
+each reconnect is reported through the connection handler and resubscribes every topic.
+*/
+
+use super::{ConnectionHandler, EventStream, MessageHandler, Subscribers, Subscription, Transport, TransportEvent};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::secret::Secret;
+use crate::types::{OperationType, TransportStats};
+use async_trait::async_trait;
+use rumqttc::v5::mqttbytes::v5::Packet as PacketV5;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// MQTT protocol version spoken to the broker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MqttVersion {
+    /// MQTT 3.1.1
+    #[default]
+    V311,
+    /// MQTT 5
+    V5,
+}
+
+/// MQTT delivery guarantee
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MqttQos {
+    /// QoS 0: fire and forget
+    AtMostOnce,
+    /// QoS 1: acknowledged, may be redelivered
+    AtLeastOnce,
+    /// QoS 2: four-way handshake, delivered once
+    ExactlyOnce,
+}
+
+impl MqttQos {
+    /// Default QoS for an operation
+    ///
+    /// Data and acks use QoS 0: data streams tolerate loss and acks already exist to detect it.
+    /// Control, request, response and error envelopes use QoS 1 because losing them stalls the
+    /// protocol.
+    pub fn for_operation(operation: OperationType) -> Self {
+        match operation {
+            OperationType::Data | OperationType::Ack => MqttQos::AtMostOnce,
+            OperationType::Control | OperationType::Request | OperationType::Response | OperationType::Error => {
+                MqttQos::AtLeastOnce
+            }
+        }
+    }
+
+    fn v4(self) -> rumqttc::QoS {
+        match self {
+            MqttQos::AtMostOnce => rumqttc::QoS::AtMostOnce,
+            MqttQos::AtLeastOnce => rumqttc::QoS::AtLeastOnce,
+            MqttQos::ExactlyOnce => rumqttc::QoS::ExactlyOnce,
+        }
+    }
+
+    fn v5(self) -> rumqttc::v5::mqttbytes::QoS {
+        match self {
+            MqttQos::AtMostOnce => rumqttc::v5::mqttbytes::QoS::AtMostOnce,
+            MqttQos::AtLeastOnce => rumqttc::v5::mqttbytes::QoS::AtLeastOnce,
+            MqttQos::ExactlyOnce => rumqttc::v5::mqttbytes::QoS::ExactlyOnce,
+        }
+    }
+}
+
+/// MQTT adapter configuration
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    /// Broker host name or address
+    pub host: String,
+    /// Broker port
+    pub port: u16,
+    /// MQTT client ID; also this participant's UMICP address
+    pub client_id: String,
+    /// Protocol version
+    pub version: MqttVersion,
+    /// Prefix prepended to every address to form its topic (empty for none)
+    pub topic_prefix: String,
+    /// Keep-alive interval
+    pub keep_alive: Duration,
+    /// Start without broker-side session state (clean start in MQTT 5)
+    pub clean_session: bool,
+    /// Broker credentials
+    pub username: Option<String>,
+    /// Broker credentials
+    pub password: Option<Secret<String>>,
+    /// Maximum packet size in bytes, in both directions
+    pub max_packet_size: usize,
+    /// Pause between reconnection attempts
+    pub reconnect_delay: Duration,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            host: "127.0.0.1".to_string(),
+            port: 1883,
+            client_id: uuid::Uuid::new_v4().to_string(),
+            version: MqttVersion::V311,
+            topic_prefix: "umicp".to_string(),
+            keep_alive: Duration::from_secs(30),
+            clean_session: true,
+            username: None,
+            password: None,
+            max_packet_size: 1024 * 1024, // 1MB
+            reconnect_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+enum Client {
+    V311(rumqttc::AsyncClient),
+    V5(rumqttc::v5::AsyncClient),
+}
+
+impl Client {
+    async fn publish(&self, topic: String, qos: MqttQos, payload: Vec<u8>) -> Result<()> {
+        match self {
+            Client::V311(client) => client.publish(topic, qos.v4(), false, payload).await.map_err(client_error),
+            Client::V5(client) => client.publish(topic, qos.v5(), false, payload).await.map_err(client_error),
+        }
+    }
+
+    async fn subscribe(&self, topic: String, qos: MqttQos) -> Result<()> {
+        match self {
+            Client::V311(client) => client.subscribe(topic, qos.v4()).await.map_err(client_error),
+            Client::V5(client) => client.subscribe(topic, qos.v5()).await.map_err(client_error),
+        }
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        match self {
+            Client::V311(client) => client.disconnect().await.map_err(client_error),
+            Client::V5(client) => client.disconnect().await.map_err(client_error),
+        }
+    }
+}
+
+fn client_error(error: impl std::fmt::Display) -> UmicpError {
+    UmicpError::transport(format!("MQTT client request failed: {}", error))
+}
+
+/// Boxed: rumqttc event loops are large and the variants differ in size
+enum EventLoop {
+    V311(Box<rumqttc::EventLoop>),
+    V5(Box<rumqttc::v5::EventLoop>),
+}
+
+/// Broker events the adapter cares about
+enum Polled {
+    Connected,
+    Publish(Vec<u8>),
+    Other,
+}
+
+impl EventLoop {
+    async fn poll(&mut self) -> std::result::Result<Polled, String> {
+        match self {
+            EventLoop::V311(events) => match events.poll().await {
+                Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => Ok(Polled::Connected),
+                Ok(rumqttc::Event::Incoming(rumqttc::Packet::Publish(publish))) => {
+                    Ok(Polled::Publish(publish.payload.to_vec()))
+                }
+                Ok(_) => Ok(Polled::Other),
+                Err(e) => Err(e.to_string()),
+            },
+            EventLoop::V5(events) => match events.poll().await {
+                Ok(rumqttc::v5::Event::Incoming(PacketV5::ConnAck(_))) => Ok(Polled::Connected),
+                Ok(rumqttc::v5::Event::Incoming(PacketV5::Publish(publish))) => Ok(Polled::Publish(publish.payload.to_vec())),
+                Ok(_) => Ok(Polled::Other),
+                Err(e) => Err(e.to_string()),
+            },
+        }
+    }
+}
+
+struct Shared {
+    config: MqttConfig,
+    client: Client,
+    /// Taken by `connect`
+    events: Mutex<Option<EventLoop>>,
+    /// Topics to (re)subscribe on every broker connection
+    topics: RwLock<HashMap<String, MqttQos>>,
+    qos_overrides: RwLock<HashMap<OperationType, MqttQos>>,
+    connected: Mutex<bool>,
+    message_handler: RwLock<Option<MessageHandler>>,
+    connection_handler: RwLock<Option<ConnectionHandler>>,
+    subscribers: Subscribers,
+    stats: Mutex<TransportStats>,
+    started: Instant,
+    shutdown: watch::Sender<bool>,
+}
+
+/// MQTT transport adapter for UMICP envelopes
+#[derive(Clone)]
+pub struct MqttTransport {
+    shared: Arc<Shared>,
+}
+
+impl MqttTransport {
+    /// Create an adapter for the broker in `config`; nothing is sent until `connect`
+    pub fn new(config: MqttConfig) -> Result<Self> {
+        if config.client_id.is_empty() {
+            return Err(UmicpError::configuration("MQTT client_id must not be empty"));
+        }
+
+        let (client, events) = match config.version {
+            MqttVersion::V311 => {
+                let mut options = rumqttc::MqttOptions::new(&config.client_id, &config.host, config.port);
+                options
+                    .set_keep_alive(config.keep_alive)
+                    .set_clean_session(config.clean_session)
+                    .set_max_packet_size(config.max_packet_size, config.max_packet_size);
+                if let (Some(username), Some(password)) = (&config.username, &config.password) {
+                    options.set_credentials(username, password.expose());
+                }
+                let (client, events) = rumqttc::AsyncClient::new(options, 64);
+                (Client::V311(client), EventLoop::V311(Box::new(events)))
+            }
+            MqttVersion::V5 => {
+                let mut options = rumqttc::v5::MqttOptions::new(&config.client_id, &config.host, config.port);
+                options
+                    .set_keep_alive(config.keep_alive)
+                    .set_clean_start(config.clean_session)
+                    .set_max_packet_size(Some(config.max_packet_size as u32));
+                if let (Some(username), Some(password)) = (&config.username, &config.password) {
+                    options.set_credentials(username, password.expose());
+                }
+                let (client, events) = rumqttc::v5::AsyncClient::new(options, 64);
+                (Client::V5(client), EventLoop::V5(Box::new(events)))
+            }
+        };
+
+        let transport = MqttTransport {
+            shared: Arc::new(Shared {
+                client,
+                events: Mutex::new(Some(events)),
+                topics: RwLock::new(HashMap::new()),
+                qos_overrides: RwLock::new(HashMap::new()),
+                connected: Mutex::new(false),
+                message_handler: RwLock::new(None),
+                connection_handler: RwLock::new(None),
+                subscribers: Subscribers::default(),
+                stats: Mutex::new(TransportStats::default()),
+                started: Instant::now(),
+                shutdown: watch::channel(false).0,
+                config,
+            }),
+        };
+        let inbox = transport.topic_for(&transport.shared.config.client_id)?;
+        transport.shared.topics.write().unwrap().insert(inbox, MqttQos::ExactlyOnce);
+        Ok(transport)
+    }
+
+    /// This participant's address (the MQTT client ID)
+    pub fn client_id(&self) -> &str {
+        &self.shared.config.client_id
+    }
+
+    /// Topic that envelopes addressed to `address` are published on
+    pub fn topic_for(&self, address: &str) -> Result<String> {
+        if address.is_empty() || address.contains(['+', '#']) {
+            return Err(UmicpError::validation(format!("Invalid MQTT address: {:?}", address)));
+        }
+        Ok(match self.shared.config.topic_prefix.as_str() {
+            "" => address.to_string(),
+            prefix => format!("{}/{}", prefix.trim_end_matches('/'), address),
+        })
+    }
+
+    /// QoS used when publishing an envelope with this operation
+    pub fn qos_for(&self, operation: OperationType) -> MqttQos {
+        self.shared
+            .qos_overrides
+            .read()
+            .unwrap()
+            .get(&operation)
+            .copied()
+            .unwrap_or_else(|| MqttQos::for_operation(operation))
+    }
+
+    /// Override the default QoS for an operation
+    pub fn set_qos(&self, operation: OperationType, qos: MqttQos) {
+        self.shared.qos_overrides.write().unwrap().insert(operation, qos);
+    }
+
+    /// Also receive envelopes addressed to `address`, e.g. a group shared by several clients
+    ///
+    /// The subscription is kept across reconnects. MQTT wildcards are not accepted.
+    pub async fn listen(&self, address: &str, qos: MqttQos) -> Result<()> {
+        let topic = self.topic_for(address)?;
+        self.shared.topics.write().unwrap().insert(topic.clone(), qos);
+        if *self.shared.connected.lock().unwrap() {
+            self.shared.client.subscribe(topic, qos).await?;
+        }
+        Ok(())
+    }
+
+    /// Set message handler for incoming messages; the connection ID is the envelope's `from`
+    pub fn set_message_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(Envelope, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        *self.shared.message_handler.write().unwrap() = Some(super::message_handler(handler));
+    }
+
+    /// Set connection handler for broker connection events; the connection ID is the broker address
+    pub fn set_connection_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(bool, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        *self.shared.connection_handler.write().unwrap() = Some(super::connection_handler(handler));
+    }
+
+    async fn run(&self, mut events: EventLoop) {
+        let mut shutdown = self.shared.shutdown.subscribe();
+        loop {
+            let polled = tokio::select! {
+                polled = events.poll() => polled,
+                _ = shutdown.wait_for(|stopped| *stopped) => break,
+            };
+
+            match polled {
+                Ok(Polled::Connected) => {
+                    *self.shared.connected.lock().unwrap() = true;
+                    {
+                        let mut stats = self.shared.stats.lock().unwrap();
+                        stats.active_connections = 1;
+                        stats.total_connections += 1;
+                    }
+                    // Subscribing goes through the request queue this loop drains, so never await it here
+                    let topics: Vec<_> = self.shared.topics.read().unwrap().clone().into_iter().collect();
+                    let transport = self.clone();
+                    tokio::spawn(async move {
+                        for (topic, qos) in topics {
+                            let _ = transport.shared.client.subscribe(topic, qos).await;
+                        }
+                    });
+                    self.notify_connection(true).await;
+                }
+                Ok(Polled::Publish(payload)) => self.receive(payload).await,
+                Ok(Polled::Other) => {}
+                Err(error) => {
+                    self.shared.subscribers.notify(TransportEvent::error(None, error));
+                    self.mark_disconnected().await;
+                    // rumqttc reconnects on the next poll
+                    tokio::select! {
+                        _ = tokio::time::sleep(self.shared.config.reconnect_delay) => {}
+                        _ = shutdown.wait_for(|stopped| *stopped) => break,
+                    }
+                }
+            }
+        }
+        self.mark_disconnected().await;
+    }
+
+    async fn receive(&self, payload: Vec<u8>) {
+        {
+            let mut stats = self.shared.stats.lock().unwrap();
+            stats.messages_received += 1;
+            stats.bytes_received += payload.len() as u64;
+        }
+
+        // Payloads that are not envelopes are dropped; other publishers may share the broker
+        let envelope = match std::str::from_utf8(&payload).map(Envelope::deserialize) {
+            Ok(Ok(envelope)) => envelope,
+            _ => return,
+        };
+
+        let from = envelope.from().to_string();
+        self.shared.subscribers.publish(&envelope, &from);
+        let handler = self.shared.message_handler.read().unwrap().clone();
+        if let Some(handler) = handler {
+            // The handler may publish, which needs this loop to keep polling
+            let shared = self.shared.clone();
+            tokio::spawn(async move {
+                if let Err(error) = handler(envelope, from.clone()).await {
+                    shared.subscribers.notify(TransportEvent::error(Some(&from), error));
+                }
+            });
+        }
+    }
+
+    async fn mark_disconnected(&self) {
+        let was_connected = std::mem::replace(&mut *self.shared.connected.lock().unwrap(), false);
+        if was_connected {
+            self.shared.stats.lock().unwrap().active_connections = 0;
+            self.notify_connection(false).await;
+        }
+    }
+
+    async fn notify_connection(&self, connected: bool) {
+        let broker = format!("{}:{}", self.shared.config.host, self.shared.config.port);
+        self.shared.subscribers.notify(match connected {
+            true => TransportEvent::Connected(broker.clone()),
+            false => TransportEvent::Disconnected(broker.clone()),
+        });
+        let handler = self.shared.connection_handler.read().unwrap().clone();
+        if let Some(handler) = handler {
+            handler(connected, broker).await;
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for MqttTransport {
+    /// Start the broker event loop in the background
+    async fn connect(&self) -> Result<()> {
+        let events = self
+            .shared
+            .events
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| UmicpError::transport("MQTT transport is already connected"))?;
+        let transport = self.clone();
+        tokio::spawn(async move { transport.run(events).await });
+        Ok(())
+    }
+
+    /// Publish to the inbox of `connection_id`, or of the envelope's `to` address
+    async fn send(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<()> {
+        let topic = self.topic_for(connection_id.unwrap_or(envelope.to()))?;
+        let json = envelope.serialize()?;
+        if json.len() > self.shared.config.max_packet_size {
+            return Err(UmicpError::validation(format!(
+                "Envelope of {} bytes exceeds max packet size {}",
+                json.len(),
+                self.shared.config.max_packet_size
+            )));
+        }
+
+        let bytes = json.len() as u64;
+        self.shared
+            .client
+            .publish(topic, self.qos_for(envelope.operation()), json.into_bytes())
+            .await?;
+
+        let mut stats = self.shared.stats.lock().unwrap();
+        stats.messages_sent += 1;
+        stats.bytes_sent += bytes;
+        Ok(())
+    }
+
+    fn subscribe(&self) -> Subscription {
+        self.shared.subscribers.subscribe()
+    }
+
+    fn events(&self) -> EventStream {
+        self.shared.subscribers.events()
+    }
+
+    async fn stats(&self) -> TransportStats {
+        let mut stats = self.shared.stats.lock().unwrap().clone();
+        stats.uptime_seconds = self.shared.started.elapsed().as_secs();
+        stats
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        if *self.shared.connected.lock().unwrap() {
+            // Best effort: the event loop stops below whether or not the broker hears this
+            let _ = self.shared.client.disconnect().await;
+        }
+        self.shared.shutdown.send_replace(true);
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for MqttTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MqttTransport")
+            .field("broker", &format!("{}:{}", self.shared.config.host, self.shared.config.port))
+            .field("client_id", &self.shared.config.client_id)
+            .field("version", &self.shared.config.version)
+            .field("connected", &*self.shared.connected.lock().unwrap())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mqtt_topic_and_qos_mapping() {
+        let transport = MqttTransport::new(MqttConfig {
+            client_id: "sensor-7".to_string(),
+            version: MqttVersion::V5,
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(transport.topic_for("gateway").unwrap(), "umicp/gateway");
+        assert!(transport.topic_for("fleet/#").is_err());
+        assert!(transport.shared.topics.read().unwrap().contains_key("umicp/sensor-7"));
+
+        assert_eq!(transport.qos_for(OperationType::Data), MqttQos::AtMostOnce);
+        assert_eq!(transport.qos_for(OperationType::Request), MqttQos::AtLeastOnce);
+        transport.set_qos(OperationType::Data, MqttQos::ExactlyOnce);
+        assert_eq!(transport.qos_for(OperationType::Data), MqttQos::ExactlyOnce);
+
+        let unprefixed = MqttTransport::new(MqttConfig {
+            topic_prefix: String::new(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(unprefixed.topic_for("gateway").unwrap(), "gateway");
+        assert!(MqttTransport::new(MqttConfig {
+            client_id: String::new(),
+            ..Default::default()
+        })
+        .is_err());
+
+        // Publishing only queues the request, so it succeeds before the broker is reached
+        let envelope = Envelope::builder()
+            .from("sensor-7")
+            .to("gateway")
+            .operation(OperationType::Data)
+            .message_id(&uuid::Uuid::new_v4().to_string())
+            .build()
+            .unwrap();
+        transport.send(envelope, None).await.unwrap();
+        assert_eq!(transport.stats().await.messages_sent, 1);
+    }
+}
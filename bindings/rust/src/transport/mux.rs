@@ -0,0 +1,192 @@
+/*!
+# UMICP Stream Multiplexing
+
+Framing for logical streams sharing one connection. A serialized envelope sent on a stream is
+split into chunks of at most [`CHUNK_SIZE`] bytes; the writer interleaves chunks from different
+streams round-robin, so a small control envelope waits for at most one chunk of a multi-MB
+transfer instead of the whole transfer.
+
+Each chunk is one binary frame:
+
+```text
++------+-----------------+-------+---------+
+| 0x00 | stream id (u32) | flags | payload |
++------+-----------------+-------+---------+
+```
+
+The leading zero byte can never start a JSON document, which keeps mux frames distinguishable
+from envelopes sent as plain binary JSON. Flag bit 0 marks the last chunk of an envelope.
+*/
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ops::Bound;
+
+/// Largest payload carried by a single mux frame
+pub(crate) const CHUNK_SIZE: usize = 16 * 1024;
+
+const MAGIC: u8 = 0x00;
+pub(crate) const HEADER_LEN: usize = 6;
+const FLAG_FIN: u8 = 0x01;
+
+fn encode(stream_id: u32, fin: bool, chunk: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + chunk.len());
+    frame.push(MAGIC);
+    frame.extend_from_slice(&stream_id.to_be_bytes());
+    frame.push(if fin { FLAG_FIN } else { 0 });
+    frame.extend_from_slice(chunk);
+    frame
+}
+
+/// Split a mux frame into stream ID, last-chunk flag and payload; `None` if it is not one
+pub(crate) fn decode(frame: &[u8]) -> Option<(u32, bool, &[u8])> {
+    if frame.len() < HEADER_LEN || frame[0] != MAGIC {
+        return None;
+    }
+    let stream_id = u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]);
+    Some((stream_id, frame[5] & FLAG_FIN != 0, &frame[HEADER_LEN..]))
+}
+
+struct Pending {
+    data: Vec<u8>,
+    offset: usize,
+}
+
+/// Outgoing side: queues messages per stream and emits their chunks round-robin
+#[derive(Default)]
+pub(crate) struct Scheduler {
+    queues: BTreeMap<u32, VecDeque<Pending>>,
+    /// Stream that produced the previous frame
+    last: Option<u32>,
+}
+
+impl Scheduler {
+    /// Queue a message behind earlier ones on the same stream
+    pub(crate) fn push(&mut self, stream_id: u32, data: Vec<u8>) {
+        self.queues
+            .entry(stream_id)
+            .or_default()
+            .push_back(Pending { data, offset: 0 });
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    /// Next frame to put on the wire, taking turns between streams with pending data
+    pub(crate) fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let after = self.last.map_or(Bound::Unbounded, Bound::Excluded);
+        let stream_id = self
+            .queues
+            .range((after, Bound::Unbounded))
+            .next()
+            .or_else(|| self.queues.iter().next())
+            .map(|(stream_id, _)| *stream_id)?;
+
+        let queue = self.queues.get_mut(&stream_id)?;
+        let pending = queue.front_mut()?;
+        let end = (pending.offset + CHUNK_SIZE).min(pending.data.len());
+        let fin = end == pending.data.len();
+        let frame = encode(stream_id, fin, &pending.data[pending.offset..end]);
+        pending.offset = end;
+
+        if fin {
+            queue.pop_front();
+            if queue.is_empty() {
+                self.queues.remove(&stream_id);
+            }
+        }
+        self.last = Some(stream_id);
+        Some(frame)
+    }
+}
+
+/// Incoming side: rebuilds messages from the chunks of each stream
+pub(crate) struct Reassembler {
+    partial: HashMap<u32, Vec<u8>>,
+    /// Streams whose current message outgrew the limit; skipped until its last chunk
+    discarding: HashSet<u32>,
+    max_size: usize,
+}
+
+impl Reassembler {
+    pub(crate) fn new(max_size: usize) -> Self {
+        Reassembler {
+            partial: HashMap::new(),
+            discarding: HashSet::new(),
+            max_size,
+        }
+    }
+
+    /// Add a chunk, returning the message it completes
+    ///
+    /// Messages larger than the limit are dropped whole rather than delivered truncated.
+    pub(crate) fn push(&mut self, stream_id: u32, fin: bool, chunk: &[u8]) -> Option<Vec<u8>> {
+        if self.discarding.contains(&stream_id) {
+            if fin {
+                self.discarding.remove(&stream_id);
+            }
+            return None;
+        }
+
+        let buffer = self.partial.entry(stream_id).or_default();
+        if buffer.len() + chunk.len() > self.max_size {
+            self.partial.remove(&stream_id);
+            if !fin {
+                self.discarding.insert(stream_id);
+            }
+            return None;
+        }
+        buffer.extend_from_slice(chunk);
+
+        match fin {
+            true => self.partial.remove(&stream_id),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mux_interleaving_and_reassembly() {
+        let bulk = vec![b'x'; CHUNK_SIZE * 3 + 10];
+        let mut scheduler = Scheduler::default();
+        scheduler.push(7, bulk.clone());
+        scheduler.push(1, b"first".to_vec());
+        scheduler.push(1, b"second".to_vec());
+
+        let frames: Vec<_> = std::iter::from_fn(|| scheduler.next_frame()).collect();
+        let streams: Vec<_> = frames.iter().map(|frame| decode(frame).unwrap().0).collect();
+        // Control stream gets a turn between each bulk chunk
+        assert_eq!(streams, vec![1, 7, 1, 7, 7, 7]);
+        assert!(scheduler.is_empty());
+
+        let mut reassembler = Reassembler::new(bulk.len());
+        let messages: Vec<_> = frames
+            .iter()
+            .filter_map(|frame| {
+                let (stream_id, fin, chunk) = decode(frame).unwrap();
+                reassembler.push(stream_id, fin, chunk).map(|message| (stream_id, message))
+            })
+            .collect();
+        assert_eq!(messages[0], (1, b"first".to_vec()));
+        assert_eq!(messages[1], (1, b"second".to_vec()));
+        assert_eq!(messages[2], (7, bulk.clone()));
+
+        // Oversized messages are dropped, and the stream recovers for the next one
+        let mut small = Reassembler::new(CHUNK_SIZE);
+        let mut scheduler = Scheduler::default();
+        scheduler.push(3, bulk);
+        scheduler.push(3, b"ok".to_vec());
+        let delivered: Vec<_> = std::iter::from_fn(|| scheduler.next_frame())
+            .filter_map(|frame| {
+                let (stream_id, fin, chunk) = decode(&frame).unwrap();
+                small.push(stream_id, fin, chunk)
+            })
+            .collect();
+        assert_eq!(delivered, vec![b"ok".to_vec()]);
+        assert!(decode(b"{\"v\":\"1.0\"}").is_none());
+    }
+}
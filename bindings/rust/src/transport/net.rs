@@ -0,0 +1,158 @@
+/*!
+# UMICP Socket Setup
+
+Listening on both IP families and connecting to whichever answers first, for deployments where
+some sites only have IPv4 and others only IPv6.
+
+[`bind`] with `dual_stack` listens on every address the bind address resolves to, and on both
+`0.0.0.0` and `[::]` for an unspecified one, all on the same port. The IPv6 sockets are made
+IPv6-only so the two families never compete for the port, whatever the platform's default.
+
+[`connect`] follows Happy Eyeballs (RFC 8305): the resolved addresses are tried alternating
+between families, starting with the family of the first one, and a new attempt starts whenever
+the previous one fails or has not succeeded within the attempt delay. The first connection to
+succeed is used and the others are dropped, so a host whose IPv6 route is broken costs the delay
+rather than a full connect timeout.
+*/
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpListener, TcpStream};
+
+/// Listeners for `addr`; on every address it resolves to, and both families, with `dual_stack`
+pub(crate) async fn bind(addr: &str, dual_stack: bool) -> io::Result<Vec<TcpListener>> {
+    if !dual_stack {
+        return Ok(vec![TcpListener::bind(addr).await?]);
+    }
+
+    let mut addrs = Vec::new();
+    for resolved in lookup_host(addr).await? {
+        let unspecified: &[IpAddr] = &[Ipv4Addr::UNSPECIFIED.into(), Ipv6Addr::UNSPECIFIED.into()];
+        let ips = match resolved.ip().is_unspecified() {
+            true => unspecified,
+            false => &[resolved.ip()],
+        };
+        for ip in ips {
+            let addr = SocketAddr::new(*ip, resolved.port());
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+
+    let mut listeners: Vec<TcpListener> = Vec::new();
+    for mut addr in addrs {
+        // Port 0 picks a port for the first listener; the rest share it
+        if let Some(first) = listeners.first() {
+            if addr.port() == 0 {
+                addr.set_port(first.local_addr()?.port());
+            }
+        }
+        listeners.push(listen(addr)?);
+    }
+    match listeners.is_empty() {
+        true => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} resolved to no address", addr))),
+        false => Ok(listeners),
+    }
+}
+
+fn listen(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    // As std does, so a restarted server can bind while old connections linger in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Connect to `host`, racing its addresses as Happy Eyeballs does
+pub(crate) async fn connect(host: &str, port: u16, attempt_delay: Duration) -> io::Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = lookup_host((host, port)).await?.collect();
+    connect_to(interleave(addrs), attempt_delay).await
+}
+
+/// Connect to the first of `addrs` to answer, starting a new attempt every `attempt_delay`
+async fn connect_to(addrs: Vec<SocketAddr>, attempt_delay: Duration) -> io::Result<TcpStream> {
+    let mut waiting = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    match waiting.next() {
+        Some(addr) => attempts.push(TcpStream::connect(addr)),
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, "Host resolved to no address")),
+    }
+
+    loop {
+        let more = waiting.len() > 0;
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                // A failure starts the next attempt at once
+                Err(error) => match waiting.next() {
+                    Some(addr) => attempts.push(TcpStream::connect(addr)),
+                    None if attempts.is_empty() => return Err(error),
+                    None => {}
+                },
+            },
+            _ = tokio::time::sleep(attempt_delay), if more => {
+                attempts.extend(waiting.next().map(TcpStream::connect));
+            }
+        }
+    }
+}
+
+/// Order `addrs` alternating between IP families, starting with the family of the first
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(preferred_v6) = addrs.first().map(SocketAddr::is_ipv6) else {
+        return addrs;
+    };
+    let mut ordered = Vec::with_capacity(addrs.len());
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6() == preferred_v6);
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return ordered,
+            (first, second) => ordered.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interleave_alternates_families() {
+        let v6 = |last: u16| SocketAddr::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, last).into(), 80);
+        let v4 = |last: u8| SocketAddr::new(Ipv4Addr::new(192, 0, 2, last).into(), 80);
+
+        let ordered = interleave(vec![v6(1), v6(2), v6(3), v4(1), v4(2)]);
+        assert_eq!(ordered, vec![v6(1), v4(1), v6(2), v4(2), v6(3)]);
+        let ordered = interleave(vec![v4(1), v4(2), v6(1)]);
+        assert_eq!(ordered, vec![v4(1), v6(1), v4(2)]);
+        assert!(interleave(Vec::new()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_falls_back_and_binds_dual_stack() {
+        let listeners = bind("[::]:0", true).await.unwrap();
+        assert_eq!(listeners.len(), 2);
+        let port = listeners[0].local_addr().unwrap().port();
+        assert!(listeners.iter().all(|listener| listener.local_addr().unwrap().port() == port));
+
+        // Nothing listens on the first address; the second family still gets through
+        let closed = TcpListener::bind("[::1]:0").await.unwrap().local_addr().unwrap();
+        let reachable = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+        let stream = connect_to(vec![closed, reachable], Duration::from_secs(10)).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), reachable);
+        let stream = connect("localhost", port, Duration::from_millis(250)).await.unwrap();
+        assert!(stream.peer_addr().unwrap().ip().is_loopback());
+
+        assert!(connect_to(vec![closed], Duration::from_millis(10)).await.is_err());
+    }
+}
@@ -0,0 +1,470 @@
+/*!
+# UMICP Transport Observers
+
+Callbacks for the lifecycle of a transport, so applications can ship telemetry to their own
+systems without parsing logs.
+
+A [`TransportObserver`] has a method for each kind of event, handed a structured record:
+connections opening and closing ([`ConnectionRecord`]), peers authenticating ([`AuthRecord`]),
+envelopes sent and received ([`MessageRecord`]), retransmissions ([`RetryRecord`]), envelopes
+given up on ([`DropRecord`]) and failures that did not surface through a call ([`ErrorRecord`]).
+Every method does nothing by default, so an observer implements only the ones it needs. Records
+are `Serialize`, ready to be exported as JSON.
+
+An [`ObservedTransport`] calls its observers for every envelope it sends and every
+[`TransportEvent`] of the transport it wraps; sequence gaps are only reported through
+[`Transport::events`]. Retries and drops come from the wrappers that make them, so it goes
+outside those: around a [`ReliableTransport`](super::ReliableTransport) to see retransmissions,
+or an [`OfflineQueueTransport`](super::OfflineQueueTransport) to see the envelopes it drops.
+
+Observers are called inline, on the task sending or receiving; one doing slow work, such as a
+network export, should hand its records off to a channel.
+*/
+
+use super::{DropReason, EventStream, Subscribers, Subscription, Transport, TransportEvent};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::{OperationType, TransportStats};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// A connection opening or closing
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionRecord {
+    /// Connection that opened or closed
+    pub connection_id: String,
+    /// When it was reported
+    pub at: DateTime<Utc>,
+}
+
+/// A server accepting or refusing the credentials of a peer
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthRecord {
+    /// Connection the peer opened, or would have had
+    pub connection_id: String,
+    /// Subject of the principal the peer authenticated as, if it was accepted
+    pub subject: Option<String>,
+    /// Why the credentials were refused, if they were
+    pub error: Option<String>,
+    /// When it was reported
+    pub at: DateTime<Utc>,
+}
+
+/// An envelope sent or received
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageRecord {
+    /// Connection the envelope arrived on, or the one `send` was given
+    pub connection_id: Option<String>,
+    /// ID of the envelope
+    pub message_id: String,
+    /// Sender named by the envelope
+    pub from: String,
+    /// Recipient named by the envelope
+    pub to: String,
+    /// Operation of the envelope
+    pub operation: OperationType,
+    /// Size of the envelope's payload in bytes
+    pub payload_size: usize,
+    /// Why the send failed, if it did; always `None` for received envelopes
+    pub error: Option<String>,
+    /// When the envelope arrived or its send started
+    pub at: DateTime<Utc>,
+}
+
+impl MessageRecord {
+    fn new(envelope: &Envelope, connection_id: Option<&str>) -> Self {
+        MessageRecord {
+            connection_id: connection_id.map(str::to_string),
+            message_id: envelope.message_id().to_string(),
+            from: envelope.from().to_string(),
+            to: envelope.to().to_string(),
+            operation: envelope.operation(),
+            payload_size: envelope.payload().map_or(0, <[u8]>::len),
+            error: None,
+            at: Utc::now(),
+        }
+    }
+}
+
+/// An envelope being sent again after going unacknowledged
+#[derive(Debug, Clone, Serialize)]
+pub struct RetryRecord {
+    /// Connection the envelope is sent on, if one was given
+    pub connection_id: Option<String>,
+    /// ID of the envelope, the same on every attempt
+    pub message_id: String,
+    /// Retransmission number, from 1
+    pub attempt: u32,
+    /// Why the previous attempt's send failed, if it did rather than going unanswered
+    pub error: Option<String>,
+    /// When it was reported
+    pub at: DateTime<Utc>,
+}
+
+/// An envelope given up on without being sent
+#[derive(Debug, Clone, Serialize)]
+pub struct DropRecord {
+    /// Connection the envelope was meant for, if one was given
+    pub connection_id: Option<String>,
+    /// ID of the envelope
+    pub message_id: String,
+    /// Why it was dropped
+    pub reason: DropReason,
+    /// When it was reported
+    pub at: DateTime<Utc>,
+}
+
+/// A failure that did not surface through a call, or a failed `connect`
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorRecord {
+    /// Connection the failure happened on, if any
+    pub connection_id: Option<String>,
+    /// What went wrong
+    pub message: String,
+    /// When it was reported
+    pub at: DateTime<Utc>,
+}
+
+/// Receives a structured record of each lifecycle event of an [`ObservedTransport`]
+///
+/// Every method does nothing by default.
+pub trait TransportObserver: Send + Sync {
+    /// A connection was established
+    fn on_connect(&self, _record: &ConnectionRecord) {}
+
+    /// A connection was closed or lost
+    fn on_disconnect(&self, _record: &ConnectionRecord) {}
+
+    /// A server accepted or refused a peer's credentials
+    fn on_auth(&self, _record: &AuthRecord) {}
+
+    /// An envelope was handed to the wrapped transport, successfully or not
+    fn on_send(&self, _record: &MessageRecord) {}
+
+    /// An envelope arrived
+    fn on_receive(&self, _record: &MessageRecord) {}
+
+    /// An unacknowledged envelope is being sent again
+    fn on_retry(&self, _record: &RetryRecord) {}
+
+    /// An envelope was dropped without being sent
+    fn on_drop(&self, _record: &DropRecord) {}
+
+    /// Something failed outside of any call
+    fn on_error(&self, _record: &ErrorRecord) {}
+}
+
+struct Shared {
+    inner: Arc<dyn Transport>,
+    /// Replaced, not modified, so an observer can add another without deadlocking
+    observers: RwLock<Arc<Vec<Arc<dyn TransportObserver>>>>,
+    /// Events of the wrapped transport; taken by `connect`
+    incoming: Mutex<Option<EventStream>>,
+    subscribers: Subscribers,
+}
+
+/// Transport wrapper reporting what happens on the wrapped transport to observers
+#[derive(Clone)]
+pub struct ObservedTransport {
+    shared: Arc<Shared>,
+}
+
+impl ObservedTransport {
+    /// Wrap `inner` with no observers; events from now on are seen by the wrapper
+    pub fn new(inner: Arc<dyn Transport>) -> Self {
+        let incoming = inner.events();
+        ObservedTransport {
+            shared: Arc::new(Shared {
+                inner,
+                observers: RwLock::new(Arc::new(Vec::new())),
+                incoming: Mutex::new(Some(incoming)),
+                subscribers: Subscribers::default(),
+            }),
+        }
+    }
+
+    /// Add `observer`, called after the ones added before it
+    pub fn with<O: TransportObserver + 'static>(self, observer: O) -> Self {
+        self.push(Arc::new(observer));
+        self
+    }
+
+    /// Add an already shared `observer`
+    pub fn push(&self, observer: Arc<dyn TransportObserver>) {
+        let mut observers = self.shared.observers.write().unwrap();
+        let mut extended = Vec::clone(&observers);
+        extended.push(observer);
+        *observers = Arc::new(extended);
+    }
+
+    /// Number of observers
+    pub fn len(&self) -> usize {
+        self.shared.observers.read().unwrap().len()
+    }
+
+    /// Whether there are no observers
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn each(&self, call: impl Fn(&dyn TransportObserver)) {
+        let observers = self.shared.observers.read().unwrap().clone();
+        for observer in observers.iter() {
+            call(observer.as_ref());
+        }
+    }
+
+    fn observe(&self, event: &TransportEvent) {
+        let at = Utc::now();
+        match event {
+            TransportEvent::Connected(connection_id) | TransportEvent::Disconnected(connection_id) => {
+                let record = ConnectionRecord {
+                    connection_id: connection_id.clone(),
+                    at,
+                };
+                match event {
+                    TransportEvent::Connected(_) => self.each(|observer| observer.on_connect(&record)),
+                    _ => self.each(|observer| observer.on_disconnect(&record)),
+                }
+            }
+            TransportEvent::Authenticated { connection_id, subject } => {
+                let record = AuthRecord {
+                    connection_id: connection_id.clone(),
+                    subject: Some(subject.clone()),
+                    error: None,
+                    at,
+                };
+                self.each(|observer| observer.on_auth(&record));
+            }
+            TransportEvent::AuthenticationFailed { connection_id, message } => {
+                let record = AuthRecord {
+                    connection_id: connection_id.clone(),
+                    subject: None,
+                    error: Some(message.clone()),
+                    at,
+                };
+                self.each(|observer| observer.on_auth(&record));
+            }
+            TransportEvent::Message(envelope, connection_id) => {
+                let record = MessageRecord::new(envelope, Some(connection_id));
+                self.each(|observer| observer.on_receive(&record));
+            }
+            TransportEvent::Retry {
+                connection_id,
+                message_id,
+                attempt,
+                error,
+            } => {
+                let record = RetryRecord {
+                    connection_id: connection_id.clone(),
+                    message_id: message_id.clone(),
+                    attempt: *attempt,
+                    error: error.clone(),
+                    at,
+                };
+                self.each(|observer| observer.on_retry(&record));
+            }
+            TransportEvent::Dropped {
+                connection_id,
+                message_id,
+                reason,
+            } => {
+                let record = DropRecord {
+                    connection_id: connection_id.clone(),
+                    message_id: message_id.clone(),
+                    reason: reason.clone(),
+                    at,
+                };
+                self.each(|observer| observer.on_drop(&record));
+            }
+            TransportEvent::Error { connection_id, message } => {
+                let record = ErrorRecord {
+                    connection_id: connection_id.clone(),
+                    message: message.clone(),
+                    at,
+                };
+                self.each(|observer| observer.on_error(&record));
+            }
+            TransportEvent::SequenceGap { .. } => {}
+        }
+    }
+
+    async fn receive_loop(&self, mut incoming: EventStream) {
+        while let Some(event) = incoming.recv().await {
+            self.observe(&event);
+            match event {
+                TransportEvent::Message(envelope, conn_id) => self.shared.subscribers.publish(&envelope, &conn_id),
+                other => self.shared.subscribers.notify(other),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for ObservedTransport {
+    /// Connect the wrapped transport and start observing its events
+    ///
+    /// A failed connect is reported to observers as an error.
+    async fn connect(&self) -> Result<()> {
+        let incoming = self
+            .shared
+            .incoming
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| UmicpError::transport("Observed transport is already connected"))?;
+        if let Err(error) = self.shared.inner.connect().await {
+            self.observe(&TransportEvent::error(None, &error));
+            return Err(error);
+        }
+
+        let transport = self.clone();
+        tokio::spawn(async move { transport.receive_loop(incoming).await });
+        Ok(())
+    }
+
+    /// Send through the wrapped transport, then report the outcome to observers
+    async fn send(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<()> {
+        let mut record = MessageRecord::new(&envelope, connection_id);
+        let result = self.shared.inner.send(envelope, connection_id).await;
+        record.error = result.as_ref().err().map(ToString::to_string);
+        self.each(|observer| observer.on_send(&record));
+        result
+    }
+
+    fn subscribe(&self) -> Subscription {
+        self.shared.subscribers.subscribe()
+    }
+
+    fn events(&self) -> EventStream {
+        self.shared.subscribers.events()
+    }
+
+    async fn stats(&self) -> TransportStats {
+        self.shared.inner.stats().await
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.shared.inner.shutdown().await
+    }
+}
+
+impl std::fmt::Debug for ObservedTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObservedTransport").field("observers", &self.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{EvictionPolicy, MockTransport, OfflineQueueConfig, OfflineQueueTransport};
+    use crate::transport::ReliableTransport;
+    use crate::types::DeliveryConfig;
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl TransportObserver for Recorder {
+        fn on_connect(&self, record: &ConnectionRecord) {
+            self.0.lock().unwrap().push(format!("connect {}", record.connection_id));
+        }
+
+        fn on_disconnect(&self, record: &ConnectionRecord) {
+            self.0.lock().unwrap().push(format!("disconnect {}", record.connection_id));
+        }
+
+        fn on_send(&self, record: &MessageRecord) {
+            let failed = record.error.as_ref().map_or("ok", |_| "failed");
+            self.0.lock().unwrap().push(format!("send {:?} {}", record.operation, failed));
+        }
+
+        fn on_receive(&self, record: &MessageRecord) {
+            self.0.lock().unwrap().push(format!("receive {:?} from {}", record.operation, record.from));
+        }
+
+        fn on_retry(&self, record: &RetryRecord) {
+            self.0.lock().unwrap().push(format!("retry {}", record.attempt));
+        }
+
+        fn on_drop(&self, record: &DropRecord) {
+            self.0.lock().unwrap().push(format!("drop {}", serde_json::to_string(&record.reason).unwrap()));
+        }
+    }
+
+    fn make_envelope(from: &str, operation: OperationType) -> Envelope {
+        Envelope::builder()
+            .from(from)
+            .to("mock-peer")
+            .operation(operation)
+            .build()
+            .unwrap()
+    }
+
+    async fn wait_for(recorder: &Recorder, count: usize) -> Vec<String> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let seen = recorder.0.lock().unwrap().clone();
+                if seen.len() >= count {
+                    return seen;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_observers_see_every_lifecycle_event() {
+        let mock = MockTransport::new();
+        let config = DeliveryConfig {
+            ack_timeout_ms: 20,
+            max_retries: 1,
+            ..DeliveryConfig::default()
+        };
+        let reliable = ReliableTransport::new(Arc::new(mock.clone()), config);
+        let recorder = Arc::new(Recorder::default());
+        let transport = ObservedTransport::new(Arc::new(reliable));
+        transport.push(recorder.clone());
+        let mut delivered = transport.subscribe();
+        transport.connect().await.unwrap();
+        assert_eq!(wait_for(&recorder, 1).await, vec!["connect mock-peer"]);
+
+        // Never acked: sent, retransmitted once, then given up on
+        assert!(transport.send(make_envelope("test", OperationType::Data), None).await.is_err());
+        mock.inject(make_envelope("peer", OperationType::Control)).await.unwrap();
+        delivered.recv().await.unwrap();
+        mock.disconnect().await;
+        let seen = wait_for(&recorder, 5).await;
+        assert_eq!(
+            seen[1..],
+            ["retry 1", "send Data failed", "receive Control from peer", "disconnect mock-peer"]
+        );
+
+        // Drops are reported by the offline queue the observer wraps
+        let mock = MockTransport::new();
+        let queue_config = OfflineQueueConfig {
+            max_messages: 1,
+            eviction: EvictionPolicy::DropOldest,
+            ..OfflineQueueConfig::default()
+        };
+        let queue = OfflineQueueTransport::new(Arc::new(mock.clone()), queue_config);
+        let recorder = Arc::new(Recorder::default());
+        let transport = ObservedTransport::new(Arc::new(queue));
+        transport.push(recorder.clone());
+        transport.connect().await.unwrap();
+        mock.disconnect().await;
+        assert_eq!(wait_for(&recorder, 2).await, vec!["connect mock-peer", "disconnect mock-peer"]);
+        for _ in 0..2 {
+            transport.send(make_envelope("test", OperationType::Data), None).await.unwrap();
+        }
+        // The drop is reported from the queue's task, so it may come before or after the send
+        let mut seen = wait_for(&recorder, 5).await.split_off(2);
+        seen.sort();
+        assert_eq!(seen, ["drop \"evicted\"", "send Data ok", "send Data ok"]);
+    }
+}
@@ -0,0 +1,494 @@
+/*!
+# UMICP Offline Queue
+
+[`Transport`] wrapper that holds outgoing envelopes in memory while the wrapped client is
+disconnected, for applications that would rather send late than fail.
+
+A send that finds the connection down, or fails with a connection or timeout error, queues the
+envelope and succeeds. When the wrapped transport reports [`TransportEvent::Connected`], the
+queue is flushed in the order envelopes were accepted; envelopes sent while the queue is not
+empty go behind it, so order is kept across the outage. Other send errors are returned as usual
+while online, and drop the envelope when it is being flushed.
+
+The queue is capped by envelope count and by serialized size. When it is full, the
+[`EvictionPolicy`] picks what gives way: the oldest queued envelope or the one being sent.
+[`send_tracked`](OfflineQueueTransport::send_tracked) returns a [`DeliveryReceipt`] that tells
+whether an envelope was eventually handed to the wrapped transport or dropped, and why; every drop
+is also reported as a [`TransportEvent::Dropped`].
+
+Nothing is persisted: queued envelopes are lost with the process. Use the outbox (see
+`transport::outbox`) when they must survive a restart.
+*/
+
+use super::{EventStream, Subscribers, Subscription, Transport, TransportEvent};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::TransportStats;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// Which envelope gives way when the offline queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Evict the oldest queued envelopes to make room
+    #[default]
+    DropOldest,
+    /// Keep the queue as it is and drop the envelope being sent
+    DropNewest,
+}
+
+/// Offline queue settings
+#[derive(Debug, Clone)]
+pub struct OfflineQueueConfig {
+    /// Most envelopes held at once (0 for no limit)
+    pub max_messages: usize,
+    /// Most serialized bytes held at once (0 for no limit)
+    pub max_bytes: usize,
+    /// What to drop when a new envelope does not fit
+    pub eviction: EvictionPolicy,
+}
+
+impl Default for OfflineQueueConfig {
+    fn default() -> Self {
+        OfflineQueueConfig {
+            max_messages: 1000,
+            max_bytes: 16 * 1024 * 1024,
+            eviction: EvictionPolicy::DropOldest,
+        }
+    }
+}
+
+/// Why a queued envelope was never sent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropReason {
+    /// Evicted to make room for a newer envelope
+    Evicted,
+    /// The queue was full and the policy keeps what is already queued
+    QueueFull,
+    /// Larger than the whole queue may hold
+    TooLarge,
+    /// The wrapped transport refused it during a flush with an error other than a lost connection
+    Rejected(String),
+    /// The transport was shut down with the envelope still queued
+    Shutdown,
+}
+
+/// Final fate of an envelope given to [`OfflineQueueTransport::send_tracked`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeliveryOutcome {
+    /// Handed to the wrapped transport
+    Delivered,
+    /// Never sent
+    Dropped(DropReason),
+}
+
+/// Resolves to the [`DeliveryOutcome`] of one envelope
+#[derive(Debug)]
+pub struct DeliveryReceipt {
+    message_id: String,
+    outcome: oneshot::Receiver<DeliveryOutcome>,
+}
+
+impl DeliveryReceipt {
+    fn settled(message_id: String, outcome: DeliveryOutcome) -> Self {
+        let (sender, receiver) = oneshot::channel();
+        let _ = sender.send(outcome);
+        DeliveryReceipt {
+            message_id,
+            outcome: receiver,
+        }
+    }
+
+    /// ID of the envelope this receipt is for
+    pub fn message_id(&self) -> &str {
+        &self.message_id
+    }
+
+    /// Wait until the envelope has been delivered or dropped
+    pub async fn outcome(self) -> DeliveryOutcome {
+        // The sender only disappears with the transport
+        self.outcome.await.unwrap_or(DeliveryOutcome::Dropped(DropReason::Shutdown))
+    }
+}
+
+struct Entry {
+    connection_id: Option<String>,
+    envelope: Envelope,
+    size: usize,
+    notify: oneshot::Sender<DeliveryOutcome>,
+}
+
+struct Queue {
+    entries: VecDeque<Entry>,
+    bytes: usize,
+    /// Whether the wrapped transport is believed to be connected
+    online: bool,
+}
+
+struct Shared {
+    inner: Arc<dyn Transport>,
+    config: OfflineQueueConfig,
+    queue: Mutex<Queue>,
+    /// Serializes flushes so envelopes leave in order
+    flushing: tokio::sync::Mutex<()>,
+    /// Events of the wrapped transport; taken by `connect`
+    events: Mutex<Option<EventStream>>,
+    subscribers: Subscribers,
+}
+
+impl Shared {
+    /// Report what became of `entry` to its receipt, and to event subscribers if it was dropped
+    fn settle(&self, entry: Entry, outcome: DeliveryOutcome) {
+        if let DeliveryOutcome::Dropped(reason) = &outcome {
+            self.subscribers.notify(TransportEvent::Dropped {
+                connection_id: entry.connection_id,
+                message_id: entry.envelope.message_id().to_string(),
+                reason: reason.clone(),
+            });
+        }
+        // The receipt may have been dropped
+        let _ = entry.notify.send(outcome);
+    }
+}
+
+/// Transport wrapper queueing outgoing envelopes while the wrapped client is offline
+#[derive(Clone)]
+pub struct OfflineQueueTransport {
+    shared: Arc<Shared>,
+}
+
+impl OfflineQueueTransport {
+    /// Wrap `inner`, which is taken to be connected until a send says otherwise
+    pub fn new(inner: Arc<dyn Transport>, config: OfflineQueueConfig) -> Self {
+        let events = inner.events();
+        OfflineQueueTransport {
+            shared: Arc::new(Shared {
+                inner,
+                config,
+                queue: Mutex::new(Queue {
+                    entries: VecDeque::new(),
+                    bytes: 0,
+                    online: true,
+                }),
+                flushing: tokio::sync::Mutex::new(()),
+                events: Mutex::new(Some(events)),
+                subscribers: Subscribers::default(),
+            }),
+        }
+    }
+
+    /// Number of envelopes waiting for the connection
+    pub fn len(&self) -> usize {
+        self.shared.queue.lock().unwrap().entries.len()
+    }
+
+    /// Whether nothing is waiting for the connection
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Serialized size of the queued envelopes
+    pub fn queued_bytes(&self) -> usize {
+        self.shared.queue.lock().unwrap().bytes
+    }
+
+    /// Send now if connected, otherwise queue; the receipt reports what became of the envelope
+    ///
+    /// Fails only when the wrapped transport rejects the envelope for a reason other than a lost
+    /// connection while online.
+    pub async fn send_tracked(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<DeliveryReceipt> {
+        let message_id = envelope.message_id().to_string();
+        let (notify, outcome) = oneshot::channel();
+        let receipt = DeliveryReceipt {
+            message_id: message_id.clone(),
+            outcome,
+        };
+
+        let direct = {
+            let queue = self.shared.queue.lock().unwrap();
+            queue.online && queue.entries.is_empty()
+        };
+        if direct {
+            match self.shared.inner.send(envelope.clone(), connection_id).await {
+                Ok(()) => return Ok(DeliveryReceipt::settled(message_id, DeliveryOutcome::Delivered)),
+                Err(error) if !is_offline(&error) => return Err(error),
+                Err(_) => self.shared.queue.lock().unwrap().online = false,
+            }
+        }
+
+        self.enqueue(Entry {
+            connection_id: connection_id.map(str::to_string),
+            size: envelope.serialize()?.len(),
+            envelope,
+            notify,
+        });
+        // Reconnected while this was being queued: nothing else will flush it
+        if self.shared.queue.lock().unwrap().online {
+            let _ = self.flush().await;
+        }
+        Ok(receipt)
+    }
+
+    /// Queue `entry`, making room per the eviction policy
+    fn enqueue(&self, entry: Entry) {
+        let config = &self.shared.config;
+        let fits = |queue: &Queue, size: usize| {
+            (config.max_messages == 0 || queue.entries.len() < config.max_messages)
+                && (config.max_bytes == 0 || queue.bytes + size <= config.max_bytes)
+        };
+        if config.max_bytes != 0 && entry.size > config.max_bytes {
+            return self.shared.settle(entry, DeliveryOutcome::Dropped(DropReason::TooLarge));
+        }
+
+        let mut queue = self.shared.queue.lock().unwrap();
+        while !fits(&queue, entry.size) {
+            if config.eviction == EvictionPolicy::DropNewest {
+                drop(queue);
+                return self.shared.settle(entry, DeliveryOutcome::Dropped(DropReason::QueueFull));
+            }
+            let Some(evicted) = queue.entries.pop_front() else {
+                break;
+            };
+            queue.bytes -= evicted.size;
+            self.shared.settle(evicted, DeliveryOutcome::Dropped(DropReason::Evicted));
+        }
+        queue.bytes += entry.size;
+        queue.entries.push_back(entry);
+    }
+
+    /// Send queued envelopes in order, stopping when the connection turns out to be down
+    ///
+    /// Returns how many were delivered. Envelopes the wrapped transport refuses for any other
+    /// reason are dropped.
+    pub async fn flush(&self) -> Result<usize> {
+        let _flushing = self.shared.flushing.lock().await;
+        let mut delivered = 0;
+        loop {
+            let next = {
+                let queue = self.shared.queue.lock().unwrap();
+                queue
+                    .entries
+                    .front()
+                    .map(|entry| (entry.envelope.clone(), entry.connection_id.clone()))
+            };
+            let Some((envelope, connection_id)) = next else {
+                return Ok(delivered);
+            };
+
+            let outcome = match self.shared.inner.send(envelope, connection_id.as_deref()).await {
+                Ok(()) => DeliveryOutcome::Delivered,
+                Err(error) if is_offline(&error) => {
+                    self.shared.queue.lock().unwrap().online = false;
+                    return if delivered == 0 { Err(error) } else { Ok(delivered) };
+                }
+                Err(error) => DeliveryOutcome::Dropped(DropReason::Rejected(error.to_string())),
+            };
+            if outcome == DeliveryOutcome::Delivered {
+                delivered += 1;
+            }
+            // Only a shutdown empties the queue behind a flush's back
+            let entry = {
+                let mut queue = self.shared.queue.lock().unwrap();
+                let entry = queue.entries.pop_front();
+                if let Some(entry) = &entry {
+                    queue.bytes -= entry.size;
+                }
+                entry
+            };
+            if let Some(entry) = entry {
+                self.shared.settle(entry, outcome);
+            }
+        }
+    }
+
+    /// Follow the wrapped transport's connection and flush when it comes back, passing its
+    /// events on
+    async fn watch_connection(&self, mut events: EventStream) {
+        while let Some(event) = events.recv().await {
+            let reconnected = matches!(event, TransportEvent::Connected(_));
+            if let TransportEvent::Disconnected(_) = event {
+                self.shared.queue.lock().unwrap().online = false;
+            }
+            self.shared.subscribers.notify(event);
+            if reconnected {
+                self.shared.queue.lock().unwrap().online = true;
+                let _ = self.flush().await;
+            }
+        }
+    }
+}
+
+/// Whether a send failed because the connection is down rather than because of the envelope
+fn is_offline(error: &UmicpError) -> bool {
+    matches!(error, UmicpError::Connection { .. } | UmicpError::Timeout { .. })
+}
+
+#[async_trait]
+impl Transport for OfflineQueueTransport {
+    /// Connect the wrapped transport and start flushing on every reconnection
+    ///
+    /// A failed connect still starts watching, so the queue drains once the client gets through.
+    async fn connect(&self) -> Result<()> {
+        let events = self
+            .shared
+            .events
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| UmicpError::transport("Offline queue is already connected"))?;
+        let transport = self.clone();
+        tokio::spawn(async move { transport.watch_connection(events).await });
+        self.shared.inner.connect().await
+    }
+
+    /// Send now if connected, otherwise queue
+    ///
+    /// An envelope the full queue turns away fails the send.
+    async fn send(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<()> {
+        let mut receipt = self.send_tracked(envelope, connection_id).await?;
+        match receipt.outcome.try_recv() {
+            Ok(DeliveryOutcome::Dropped(DropReason::QueueFull)) => Err(UmicpError::transport(format!(
+                "Offline queue is full; dropped {}",
+                receipt.message_id
+            ))),
+            Ok(DeliveryOutcome::Dropped(DropReason::TooLarge)) => Err(UmicpError::validation(format!(
+                "Envelope {} is larger than the offline queue",
+                receipt.message_id
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn subscribe(&self) -> Subscription {
+        self.shared.inner.subscribe()
+    }
+
+    /// Events of the wrapped transport, once connected, along with the envelopes dropped
+    fn events(&self) -> EventStream {
+        self.shared.subscribers.events()
+    }
+
+    async fn stats(&self) -> TransportStats {
+        self.shared.inner.stats().await
+    }
+
+    /// Shut the wrapped transport down, dropping whatever is still queued
+    async fn shutdown(&self) -> Result<()> {
+        let entries = {
+            let mut queue = self.shared.queue.lock().unwrap();
+            queue.bytes = 0;
+            std::mem::take(&mut queue.entries)
+        };
+        for entry in entries {
+            self.shared.settle(entry, DeliveryOutcome::Dropped(DropReason::Shutdown));
+        }
+        self.shared.inner.shutdown().await
+    }
+}
+
+impl std::fmt::Debug for OfflineQueueTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OfflineQueueTransport")
+            .field("config", &self.shared.config)
+            .field("queued", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{MockFault, MockTransport};
+    use crate::types::OperationType;
+    use std::time::Duration;
+
+    fn make_envelope() -> Envelope {
+        Envelope::builder()
+            .from("test")
+            .to("mock-peer")
+            .operation(OperationType::Data)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_offline_queue_flushes_in_order_on_reconnect() {
+        let mock = MockTransport::new();
+        let client = OfflineQueueTransport::new(Arc::new(mock.clone()), OfflineQueueConfig::default());
+        client.connect().await.unwrap();
+
+        // Online: straight through
+        let first = make_envelope();
+        let receipt = client.send_tracked(first.clone(), None).await.unwrap();
+        assert_eq!(receipt.outcome().await, DeliveryOutcome::Delivered);
+
+        // Offline: queued, and so is everything behind the failed send
+        mock.script_send(MockFault::Disconnect);
+        let queued: Vec<_> = (0..3).map(|_| make_envelope()).collect();
+        let mut receipts = Vec::new();
+        for envelope in &queued {
+            receipts.push(client.send_tracked(envelope.clone(), Some("mock-peer")).await.unwrap());
+        }
+        assert_eq!(client.len(), 3);
+        assert!(client.queued_bytes() > 0);
+
+        mock.reconnect().await.unwrap();
+        for receipt in receipts {
+            assert_eq!(
+                tokio::time::timeout(Duration::from_secs(1), receipt.outcome()).await.unwrap(),
+                DeliveryOutcome::Delivered
+            );
+        }
+        let ids: Vec<_> = mock.take_sent().into_iter().map(|sent| sent.envelope.message_id().to_string()).collect();
+        let mut expected = vec![first.message_id().to_string()];
+        expected.extend(queued.iter().map(|envelope| envelope.message_id().to_string()));
+        assert_eq!(ids, expected);
+        assert!(client.is_empty());
+        assert_eq!(client.queued_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_offline_queue_eviction_and_shutdown() {
+        // Never connected: every send is queued
+        let mock = MockTransport::new();
+        let config = OfflineQueueConfig {
+            max_messages: 2,
+            ..Default::default()
+        };
+        let client = OfflineQueueTransport::new(Arc::new(mock.clone()), config.clone());
+        let oldest = client.send_tracked(make_envelope(), None).await.unwrap();
+        let kept = client.send_tracked(make_envelope(), None).await.unwrap();
+        let newest = client.send_tracked(make_envelope(), None).await.unwrap();
+        assert_eq!(oldest.outcome().await, DeliveryOutcome::Dropped(DropReason::Evicted));
+        assert_eq!(client.len(), 2);
+
+        client.shutdown().await.unwrap();
+        assert_eq!(kept.outcome().await, DeliveryOutcome::Dropped(DropReason::Shutdown));
+        assert_eq!(newest.outcome().await, DeliveryOutcome::Dropped(DropReason::Shutdown));
+
+        // Keeping the oldest turns the newcomer away, which fails a plain send
+        let config = OfflineQueueConfig {
+            eviction: EvictionPolicy::DropNewest,
+            ..config
+        };
+        let client = OfflineQueueTransport::new(Arc::new(MockTransport::new()), config);
+        client.send(make_envelope(), None).await.unwrap();
+        client.send(make_envelope(), None).await.unwrap();
+        let refused = client.send_tracked(make_envelope(), None).await.unwrap();
+        assert_eq!(refused.outcome().await, DeliveryOutcome::Dropped(DropReason::QueueFull));
+        assert!(client.send(make_envelope(), None).await.is_err());
+        assert_eq!(client.len(), 2);
+
+        // Errors other than a lost connection still reach the caller while online
+        let mock = MockTransport::new();
+        mock.connect().await.unwrap();
+        let client = OfflineQueueTransport::new(Arc::new(mock.clone()), OfflineQueueConfig::default());
+        mock.script_send(MockFault::Error);
+        assert!(client.send(make_envelope(), None).await.is_err());
+        assert!(client.is_empty());
+    }
+}
@@ -0,0 +1,485 @@
+/*!
+# UMICP Persistent Outbox
+
+[`Transport`] wrapper with a write-ahead outbox on disk, for senders on flaky uplinks.
+
+`send` appends the envelope to the outbox file and syncs it before returning, so an accepted
+envelope survives a crash or restart. Envelopes are then handed to the wrapped transport in the
+order they were accepted; when a send fails, the envelope and everything behind it stay queued
+and are retried every `flush_interval`, or as soon as [`OutboxTransport::flush`] is called (for
+instance from a connection handler). Reopening the outbox on start-up picks up where it left off.
+
+Delivery is at-least-once: an envelope whose send failed after the peer got it, or whose
+completion was not yet recorded when the process died, is sent again. Pair the outbox with
+exactly-once delivery on the receiver when duplicates matter.
+
+The file is a log of JSON lines, one per accepted or completed envelope; it is truncated whenever
+the outbox drains and compacted when completed records dominate it.
+
+With the `crypto` feature, an outbox given a [`KeyStore`](crate::keystore::KeyStore) in
+`encryption` encrypts each queued envelope with the store's active encryption key, so a stolen
+device does not give away the payloads it was still holding. Each record names its key, so
+records written before a key rotation still open. Compaction re-encrypts them under the current
+key. An outbox that was written unencrypted is encrypted when it is next opened with a key
+store; an encrypted one cannot be opened without it.
+*/
+
+use super::{EventStream, Subscription, Transport};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::TransportStats;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+#[cfg(feature = "crypto")]
+use crate::keystore::{KeyPurpose, KeyStore};
+#[cfg(feature = "crypto")]
+use crate::utils::{base64_decode, base64_encode};
+
+/// Outbox settings
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    /// Outbox file, created if missing
+    pub path: PathBuf,
+    /// Delay between retries while envelopes are queued
+    pub flush_interval: Duration,
+    /// Queued envelopes accepted before `send` starts failing
+    pub max_entries: usize,
+    /// Key store whose active encryption key encrypts queued envelopes at rest
+    #[cfg(feature = "crypto")]
+    pub encryption: Option<Arc<KeyStore>>,
+}
+
+impl OutboxConfig {
+    /// Defaults for an outbox stored at `path`
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        OutboxConfig {
+            path: path.into(),
+            flush_interval: Duration::from_secs(1),
+            max_entries: 10_000,
+            #[cfg(feature = "crypto")]
+            encryption: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Record {
+    Put {
+        seq: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        connection_id: Option<String>,
+        /// The serialized envelope, or its base64 ciphertext when `key` is set
+        envelope: String,
+        /// Encryption key the envelope was encrypted with
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    Done {
+        seq: u64,
+    },
+}
+
+struct Entry {
+    seq: u64,
+    connection_id: Option<String>,
+    envelope: Envelope,
+}
+
+struct Log {
+    file: File,
+    queue: VecDeque<Entry>,
+    next_seq: u64,
+    /// Records in the file, queued or not
+    records: usize,
+}
+
+struct Shared {
+    inner: Arc<dyn Transport>,
+    config: OutboxConfig,
+    log: Mutex<Log>,
+    /// Serializes flushes so envelopes leave in order
+    flushing: tokio::sync::Mutex<()>,
+    shutdown: watch::Sender<bool>,
+}
+
+/// Transport wrapper persisting outgoing envelopes until the wrapped transport accepts them
+#[derive(Clone)]
+pub struct OutboxTransport {
+    shared: Arc<Shared>,
+}
+
+impl OutboxTransport {
+    /// Wrap `inner`, reloading envelopes left queued in the outbox file
+    pub fn open(inner: Arc<dyn Transport>, config: OutboxConfig) -> Result<Self> {
+        let mut queue: VecDeque<Entry> = VecDeque::new();
+        let mut next_seq = 0;
+        let mut records = 0;
+        #[cfg(feature = "crypto")]
+        if let Some(keys) = &config.encryption {
+            if keys.active_key(KeyPurpose::Encryption).is_none() {
+                return Err(UmicpError::configuration("Outbox encryption needs an active encryption key"));
+            }
+        }
+        if config.path.exists() {
+            for line in BufReader::new(File::open(&config.path)?).lines() {
+                // A torn final line is an append the crash interrupted; it was never accepted
+                let Ok(record) = serde_json::from_str::<Record>(&line?) else {
+                    break;
+                };
+                records += 1;
+                match record {
+                    Record::Put {
+                        seq,
+                        connection_id,
+                        envelope,
+                        key,
+                    } => {
+                        let envelope = open_envelope(&config, seq, connection_id.as_deref(), envelope, key)?;
+                        queue.push_back(Entry {
+                            seq,
+                            connection_id,
+                            envelope,
+                        });
+                        next_seq = seq + 1;
+                    }
+                    Record::Done { seq } => queue.retain(|entry| entry.seq != seq),
+                }
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let mut log = Log {
+            file,
+            queue,
+            next_seq,
+            records,
+        };
+        // Drop completed records and anything after a torn line
+        rewrite(&config, &mut log)?;
+
+        let (shutdown, _) = watch::channel(false);
+        Ok(OutboxTransport {
+            shared: Arc::new(Shared {
+                inner,
+                config,
+                log: Mutex::new(log),
+                flushing: tokio::sync::Mutex::new(()),
+                shutdown,
+            }),
+        })
+    }
+
+    /// Number of envelopes waiting to be sent
+    pub fn len(&self) -> usize {
+        self.shared.log.lock().unwrap().queue.len()
+    }
+
+    /// Whether every accepted envelope has been sent
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Send queued envelopes in order, stopping at the first failure
+    ///
+    /// Returns how many were sent; the error of a failed send is returned only when nothing was.
+    pub async fn flush(&self) -> Result<usize> {
+        let _flushing = self.shared.flushing.lock().await;
+        let mut sent = 0;
+        loop {
+            let next = {
+                let log = self.shared.log.lock().unwrap();
+                log.queue
+                    .front()
+                    .map(|entry| (entry.seq, entry.connection_id.clone(), entry.envelope.clone()))
+            };
+            let Some((seq, connection_id, envelope)) = next else {
+                return Ok(sent);
+            };
+
+            if let Err(error) = self.shared.inner.send(envelope, connection_id.as_deref()).await {
+                return if sent == 0 { Err(error) } else { Ok(sent) };
+            }
+            sent += 1;
+            self.complete(seq)?;
+        }
+    }
+
+    /// Drop the sent front entry from the queue and the file
+    fn complete(&self, seq: u64) -> Result<()> {
+        let mut log = self.shared.log.lock().unwrap();
+        log.queue.pop_front();
+        if log.queue.is_empty() {
+            log.file.set_len(0)?;
+            log.records = 0;
+        } else {
+            // Not synced: losing this record only means one redelivery
+            append(&mut log, &Record::Done { seq })?;
+            if log.records > 1024 && log.records > log.queue.len() * 4 {
+                rewrite(&self.shared.config, &mut log)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush_loop(&self) {
+        let mut shutdown = self.shared.shutdown.subscribe();
+        loop {
+            tokio::select! {
+                _ = shutdown.wait_for(|stopped| *stopped) => break,
+                _ = tokio::time::sleep(self.shared.config.flush_interval) => {}
+            }
+            if !self.is_empty() {
+                let _ = self.flush().await;
+            }
+        }
+    }
+}
+
+fn append(log: &mut Log, record: &Record) -> Result<()> {
+    let line = serde_json::to_string(record)
+        .map_err(|e| UmicpError::serialization(format!("Failed to encode outbox record: {}", e)))?;
+    writeln!(log.file, "{}", line)?;
+    log.records += 1;
+    Ok(())
+}
+
+/// The record storing `envelope`, encrypted if the outbox is
+#[cfg_attr(not(feature = "crypto"), allow(unused_variables))]
+fn put_record(config: &OutboxConfig, seq: u64, connection_id: Option<&str>, envelope: &Envelope) -> Result<Record> {
+    let serialized = envelope.serialize()?;
+    #[cfg(feature = "crypto")]
+    if let Some(keys) = &config.encryption {
+        let (key, sealed) = keys.encrypt(serialized.as_bytes(), &record_aad(seq, connection_id))?;
+        return Ok(Record::Put {
+            seq,
+            connection_id: connection_id.map(str::to_string),
+            envelope: base64_encode(&sealed),
+            key: Some(key),
+        });
+    }
+    Ok(Record::Put {
+        seq,
+        connection_id: connection_id.map(str::to_string),
+        envelope: serialized,
+        key: None,
+    })
+}
+
+/// The envelope stored in a `Put` record
+#[cfg_attr(not(feature = "crypto"), allow(unused_variables))]
+fn open_envelope(
+    config: &OutboxConfig,
+    seq: u64,
+    connection_id: Option<&str>,
+    envelope: String,
+    key: Option<String>,
+) -> Result<Envelope> {
+    let Some(key) = key else {
+        return Envelope::deserialize(&envelope);
+    };
+    #[cfg(feature = "crypto")]
+    if let Some(keys) = &config.encryption {
+        let plaintext = keys.decrypt(&key, &base64_decode(&envelope)?, &record_aad(seq, connection_id))?;
+        let json = String::from_utf8(plaintext)
+            .map_err(|_| UmicpError::serialization("Decrypted outbox envelope is not valid UTF-8"))?;
+        return Envelope::deserialize(&json);
+    }
+    Err(UmicpError::configuration(format!(
+        "Outbox {} is encrypted with key {}; open it with its key store",
+        config.path.display(),
+        key
+    )))
+}
+
+/// What a record's ciphertext is bound to, so records cannot be swapped or redirected
+#[cfg(feature = "crypto")]
+fn record_aad(seq: u64, connection_id: Option<&str>) -> Vec<u8> {
+    format!("umicp-outbox-v1:{}:{}", seq, connection_id.unwrap_or_default()).into_bytes()
+}
+
+/// Replace the file with just the queued envelopes
+fn rewrite(config: &OutboxConfig, log: &mut Log) -> Result<()> {
+    let path = &config.path;
+    let temp = path.with_extension("compact");
+    let mut file = File::create(&temp)?;
+    for entry in &log.queue {
+        let record = put_record(config, entry.seq, entry.connection_id.as_deref(), &entry.envelope)?;
+        let line = serde_json::to_string(&record)
+            .map_err(|e| UmicpError::serialization(format!("Failed to encode outbox record: {}", e)))?;
+        writeln!(file, "{}", line)?;
+    }
+    file.sync_all()?;
+    std::fs::rename(&temp, path)?;
+    log.file = OpenOptions::new().append(true).open(path)?;
+    log.records = log.queue.len();
+    Ok(())
+}
+
+#[async_trait]
+impl Transport for OutboxTransport {
+    /// Connect the wrapped transport and start retrying queued envelopes
+    ///
+    /// A failed connect still starts the retries, since the outbox exists to outlast the uplink.
+    async fn connect(&self) -> Result<()> {
+        let transport = self.clone();
+        tokio::spawn(async move { transport.flush_loop().await });
+        self.shared.inner.connect().await?;
+        let _ = self.flush().await;
+        Ok(())
+    }
+
+    /// Persist the envelope, then try to send everything queued
+    ///
+    /// Succeeds once the envelope is on disk, whether or not the wrapped transport took it.
+    async fn send(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<()> {
+        {
+            let mut log = self.shared.log.lock().unwrap();
+            if log.queue.len() >= self.shared.config.max_entries {
+                return Err(UmicpError::transport(format!(
+                    "Outbox is full ({} envelopes queued)",
+                    log.queue.len()
+                )));
+            }
+            let seq = log.next_seq;
+            let record = put_record(&self.shared.config, seq, connection_id, &envelope)?;
+            append(&mut log, &record)?;
+            log.file.sync_data()?;
+            log.next_seq += 1;
+            log.queue.push_back(Entry {
+                seq,
+                connection_id: connection_id.map(str::to_string),
+                envelope,
+            });
+        }
+        let _ = self.flush().await;
+        Ok(())
+    }
+
+    fn subscribe(&self) -> Subscription {
+        self.shared.inner.subscribe()
+    }
+
+    fn events(&self) -> EventStream {
+        self.shared.inner.events()
+    }
+
+    async fn stats(&self) -> TransportStats {
+        self.shared.inner.stats().await
+    }
+
+    /// Stop retrying and shut the wrapped transport down; queued envelopes stay on disk
+    async fn shutdown(&self) -> Result<()> {
+        self.shared.shutdown.send_replace(true);
+        self.shared.inner.shutdown().await
+    }
+}
+
+impl std::fmt::Debug for OutboxTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OutboxTransport")
+            .field("path", &self.shared.config.path)
+            .field("queued", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{MockFault, MockTransport};
+    use crate::types::OperationType;
+
+    fn make_envelope(operation: OperationType) -> Envelope {
+        Envelope::builder()
+            .from("test")
+            .to("mock-peer")
+            .operation(operation)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_outbox_survives_restart() {
+        let dir = std::env::temp_dir().join(format!("umicp-outbox-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = OutboxConfig::new(dir.join("outbox.log"));
+
+        // Uplink down: sends are accepted but stay queued
+        let offline = MockTransport::new();
+        let outbox = OutboxTransport::open(Arc::new(offline.clone()), config.clone()).unwrap();
+        let envelopes: Vec<_> = (0..3).map(|_| make_envelope(OperationType::Data)).collect();
+        for envelope in &envelopes {
+            outbox.send(envelope.clone(), Some("mock-peer")).await.unwrap();
+        }
+        assert_eq!(outbox.len(), 3);
+        assert!(outbox.flush().await.is_err());
+
+        // One goes out before the process dies
+        offline.connect().await.unwrap();
+        offline.script_send(MockFault::Error);
+        assert!(outbox.flush().await.is_err());
+        offline.script_send(MockFault::Delay(Duration::ZERO));
+        offline.script_send(MockFault::Error);
+        assert_eq!(outbox.flush().await.unwrap(), 1);
+        assert_eq!(outbox.len(), 2);
+        drop(outbox);
+
+        // After a restart the rest is flushed in order on connect
+        let online = MockTransport::new();
+        let outbox = OutboxTransport::open(Arc::new(online.clone()), config.clone()).unwrap();
+        assert_eq!(outbox.len(), 2);
+        outbox.connect().await.unwrap();
+        let sent = online.take_sent();
+        let ids: Vec<_> = sent.iter().map(|s| s.envelope.message_id()).collect();
+        assert_eq!(ids, vec![envelopes[1].message_id(), envelopes[2].message_id()]);
+        assert_eq!(sent[0].connection_id.as_deref(), Some("mock-peer"));
+        assert!(outbox.is_empty());
+        assert_eq!(std::fs::metadata(&config.path).unwrap().len(), 0);
+
+        outbox.shutdown().await.unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "crypto")]
+    #[tokio::test]
+    async fn test_outbox_encrypts_at_rest() {
+        let dir = std::env::temp_dir().join(format!("umicp-outbox-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let keys = Arc::new(KeyStore::new());
+        keys.generate(KeyPurpose::Encryption).unwrap();
+        let mut config = OutboxConfig::new(dir.join("outbox.log"));
+        config.encryption = Some(keys.clone());
+
+        let mut envelope = make_envelope(OperationType::Data);
+        envelope.set_payload(b"gradient shard 7".to_vec());
+        let outbox = OutboxTransport::open(Arc::new(MockTransport::new()), config.clone()).unwrap();
+        outbox.send(envelope.clone(), Some("mock-peer")).await.unwrap();
+        keys.rotate(KeyPurpose::Encryption).unwrap();
+        outbox.send(make_envelope(OperationType::Data), None).await.unwrap();
+        drop(outbox);
+        let stored = std::fs::read_to_string(&config.path).unwrap();
+        assert!(!stored.contains(envelope.message_id()));
+        assert!(!stored.contains(&crate::utils::base64_encode(b"gradient shard 7")));
+
+        // Records from before the rotation still open, but only with the key store
+        let error = OutboxTransport::open(Arc::new(MockTransport::new()), OutboxConfig::new(&config.path)).unwrap_err();
+        assert!(error.to_string().contains("open it with its key store"));
+        let online = MockTransport::new();
+        let outbox = OutboxTransport::open(Arc::new(online.clone()), config.clone()).unwrap();
+        assert_eq!(outbox.len(), 2);
+        outbox.connect().await.unwrap();
+        assert_eq!(online.take_sent()[0].envelope.payload(), Some(&b"gradient shard 7"[..]));
+        assert!(outbox.is_empty());
+        outbox.shutdown().await.unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
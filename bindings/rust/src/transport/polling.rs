@@ -0,0 +1,842 @@
+/*!
+# UMICP Long-Polling Transport
+
+HTTP request/response server and client (requires the `long-polling` feature), for networks
+whose proxies break both WebSocket upgrades and long-lived streaming responses. Every exchange is
+an ordinary request that completes within a bounded time, which is the one thing such
+middleboxes reliably let through.
+
+A client opens a session with `POST /sessions`; the response body is the session ID, which is
+also the connection ID on the server side. It then keeps one `GET /poll?session=ID&ack=N` in
+flight. The server holds each poll until it has envelopes for the session or `heartbeat_interval`
+seconds pass (at least one), and answers with JSON:
+
+```json
+{"ack": 7, "envelopes": [{"v": "1.0", "...": "..."}]}
+```
+
+`ack` is the sequence number of the last envelope in the batch. The client passes it back in its
+next poll, and the server drops envelopes only once they are acknowledged, so a poll whose
+response is lost in transit is simply answered again. Upstream, envelopes are posted to
+`POST /messages` exactly as for the SSE transport, with the session in the `X-UMICP-Session`
+header or a `session` query parameter. `DELETE /sessions` ends a session; one whose client stops
+polling for twice the hold time plus `connection_timeout` is ended by the server.
+
+A client keeps one HTTP connection alive for its polls and another for its posts. A poll that
+fails is retried up to `max_reconnect_attempts` times in a row, backing off as the WebSocket
+client does; the session and its unacknowledged envelopes survive the gap.
+
+[`FallbackTransport`](super::FallbackTransport) connects over WebSocket when it can and falls
+back to this transport when it cannot.
+*/
+
+use super::http::{self, Head, CORS_HEADERS, SESSION_HEADER};
+use super::{
+    accept_backoff, ConnectionHandler, EventStream, MessageHandler, Subscribers, Subscription, Transport,
+    TransportEvent,
+};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::{TransportConfig, TransportStats};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Notify};
+
+const SESSIONS_PATH: &str = "/sessions";
+const POLL_PATH: &str = "/poll";
+const MESSAGES_PATH: &str = "/messages";
+
+/// Envelopes queued for a session before sends to it fail
+const MAX_PENDING: usize = 10_000;
+
+/// Upper bound on the body of a response that only carries a session ID or an error message
+const MAX_RESPONSE_BODY: usize = 64 * 1024;
+
+enum Role {
+    Server {
+        listener: Mutex<Option<TcpListener>>,
+        local_addr: SocketAddr,
+    },
+    /// Boxed: a client holds two connections' worth of state
+    Client(Box<Client>),
+}
+
+struct Client {
+    url: String,
+    base_path: String,
+    session: String,
+    /// Connection for polls, each held open by the server for up to the hold time
+    polls: http::ClientConnection,
+    /// Connection for posts, so they do not wait behind a held poll
+    upstream: http::ClientConnection,
+    /// Set once `run` has started, so it only runs once
+    running: Mutex<bool>,
+}
+
+/// Server-side state of one client's session
+struct Session {
+    /// Envelopes not yet acknowledged, oldest first, with their sequence numbers
+    pending: VecDeque<(u64, String)>,
+    next_sequence: u64,
+    /// Polls currently held for the session
+    polling: usize,
+    /// When the last poll ended, or the session opened
+    last_seen: Instant,
+    /// Wakes a held poll when an envelope is queued
+    queued: Arc<Notify>,
+}
+
+struct Shared {
+    role: Role,
+    max_payload_size: usize,
+    /// How long a poll is held waiting for envelopes
+    hold: Duration,
+    /// How long a session may go without polls before the server ends it
+    session_timeout: Duration,
+    config: TransportConfig,
+    message_handler: RwLock<Option<MessageHandler>>,
+    connection_handler: RwLock<Option<ConnectionHandler>>,
+    subscribers: Subscribers,
+    /// Every open session (server mode)
+    sessions: Mutex<HashMap<String, Session>>,
+    stats: Mutex<TransportStats>,
+    started: Instant,
+    shutdown: watch::Sender<bool>,
+}
+
+/// Batch of envelopes answering a poll
+#[derive(Deserialize)]
+struct Batch {
+    ack: u64,
+    envelopes: Vec<serde_json::Value>,
+}
+
+/// HTTP long-polling transport for UMICP envelopes
+#[derive(Clone)]
+pub struct LongPollingTransport {
+    shared: Arc<Shared>,
+}
+
+impl LongPollingTransport {
+    /// Bind a long-polling server to `addr`; call [`run`](Self::run) to start accepting
+    pub async fn new_server(addr: &str, config: &TransportConfig) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| UmicpError::connection(format!("Failed to bind {}: {}", addr, e)))?;
+        let local_addr = listener.local_addr()?;
+        let role = Role::Server {
+            listener: Mutex::new(Some(listener)),
+            local_addr,
+        };
+        Ok(Self::with_role(role, config))
+    }
+
+    /// Open a session on the server at `url` (`http://host:port[/base]`)
+    pub async fn new_client(url: &str, config: &TransportConfig) -> Result<Self> {
+        let (authority, base_path) = http::parse_url(url)?;
+        let timeout = Duration::from_secs(config.connection_timeout);
+        let upstream = http::ClientConnection::new(&authority, timeout);
+
+        let start_line = format!("POST {}{} HTTP/1.1", base_path, SESSIONS_PATH);
+        let opened = upstream.request(&start_line, &[], b"", MAX_RESPONSE_BODY);
+        let (head, body) = tokio::time::timeout(timeout, opened)
+            .await
+            .map_err(|_| UmicpError::timeout(format!("Opening a session on {} timed out", url)))??;
+        let session = String::from_utf8_lossy(&body).trim().to_string();
+        if head.status() != Some(200) || session.is_empty() {
+            return Err(UmicpError::connection(format!("{} refused a session: {}", url, head.start_line)));
+        }
+
+        let role = Role::Client(Box::new(Client {
+            url: url.to_string(),
+            base_path,
+            session,
+            polls: http::ClientConnection::new(&authority, timeout),
+            upstream,
+            running: Mutex::new(false),
+        }));
+        let transport = Self::with_role(role, config);
+        {
+            let mut stats = transport.shared.stats.lock().unwrap();
+            stats.active_connections = 1;
+            stats.total_connections = 1;
+        }
+        Ok(transport)
+    }
+
+    fn with_role(role: Role, config: &TransportConfig) -> Self {
+        let (shutdown, _) = watch::channel(false);
+        let hold = Duration::from_secs(config.heartbeat_interval.max(1));
+        LongPollingTransport {
+            shared: Arc::new(Shared {
+                role,
+                max_payload_size: config.max_payload_size,
+                hold,
+                session_timeout: hold * 2 + Duration::from_secs(config.connection_timeout),
+                config: config.clone(),
+                message_handler: RwLock::new(None),
+                connection_handler: RwLock::new(None),
+                subscribers: Subscribers::default(),
+                sessions: Mutex::new(HashMap::new()),
+                stats: Mutex::new(TransportStats::default()),
+                started: Instant::now(),
+                shutdown,
+            }),
+        }
+    }
+
+    /// Set message handler for incoming messages
+    ///
+    /// On a server the handler runs before the post that carried the envelope is answered, so
+    /// each client's envelopes are handled in the order it sent them.
+    pub fn set_message_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(Envelope, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        *self.shared.message_handler.write().unwrap() = Some(super::message_handler(handler));
+    }
+
+    /// Set connection handler for session events (`true` when opened, `false` when closed)
+    pub fn set_connection_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(bool, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        *self.shared.connection_handler.write().unwrap() = Some(super::connection_handler(handler));
+    }
+
+    /// Address a server is bound to
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.shared.role {
+            Role::Server { local_addr, .. } => Some(*local_addr),
+            Role::Client(_) => None,
+        }
+    }
+
+    /// Session ID the server assigned to this client
+    pub fn session_id(&self) -> Option<&str> {
+        match &self.shared.role {
+            Role::Client(client) => Some(&client.session),
+            Role::Server { .. } => None,
+        }
+    }
+
+    /// Drive the transport until [`shutdown`](Transport::shutdown) is called
+    ///
+    /// A server accepts connections and ends sessions that stop polling; a client polls until
+    /// its session ends or `max_reconnect_attempts` polls in a row have failed.
+    pub async fn run(&self) -> Result<()> {
+        let mut shutdown = self.shared.shutdown.subscribe();
+        match &self.shared.role {
+            Role::Server { listener, .. } => {
+                let listener = listener
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .ok_or_else(|| UmicpError::transport("Server is already running"))?;
+                let mut sweep = tokio::time::interval(self.shared.hold.min(Duration::from_secs(1)));
+                loop {
+                    let accepted = tokio::select! {
+                        accepted = listener.accept() => Some(accepted),
+                        _ = sweep.tick() => None,
+                        _ = shutdown.wait_for(|stopped| *stopped) => return Ok(()),
+                    };
+                    match accepted {
+                        None => self.expire_sessions().await,
+                        Some(Ok((stream, _))) => {
+                            let transport = self.clone();
+                            tokio::spawn(async move { transport.serve_connection(stream).await });
+                        }
+                        Some(Err(error)) => {
+                            // The listener is still open; failing here would stop the whole server
+                            let backoff = accept_backoff(&error);
+                            self.shared.subscribers.notify(TransportEvent::error(None, error));
+                            tokio::select! {
+                                _ = tokio::time::sleep(backoff) => {}
+                                _ = shutdown.wait_for(|stopped| *stopped) => return Ok(()),
+                            }
+                        }
+                    }
+                }
+            }
+            Role::Client(client) => {
+                if std::mem::replace(&mut *client.running.lock().unwrap(), true) {
+                    return Err(UmicpError::transport("Client is already running"));
+                }
+                let result = tokio::select! {
+                    result = self.poll_loop(client) => result,
+                    _ = shutdown.wait_for(|stopped| *stopped) => Ok(()),
+                };
+                self.shared.stats.lock().unwrap().active_connections = 0;
+                self.notify_connection(false, client.url.clone()).await;
+                result
+            }
+        }
+    }
+
+    /// Poll the server and dispatch what arrives, until the session ends
+    async fn poll_loop(&self, client: &Client) -> Result<()> {
+        let Client {
+            url,
+            base_path,
+            session,
+            polls,
+            ..
+        } = client;
+        let config = &self.shared.config;
+        let hold = self.shared.hold.as_secs();
+        // Room for the connection to be slow on top of the server holding the poll
+        let timeout = self.shared.hold + Duration::from_secs(config.connection_timeout);
+        // Batches carry JSON-escaped envelopes and a little framing
+        let max_batch = self.shared.max_payload_size.saturating_mul(2).saturating_add(MAX_RESPONSE_BODY);
+        self.notify_connection(true, url.clone()).await;
+
+        let mut ack = 0;
+        let mut failures = 0;
+        loop {
+            let start_line = format!(
+                "GET {}{}?session={}&ack={}&wait={} HTTP/1.1",
+                base_path, POLL_PATH, session, ack, hold
+            );
+            let polled = tokio::time::timeout(timeout, polls.request(&start_line, &[], b"", max_batch))
+                .await
+                .unwrap_or_else(|_| Err(UmicpError::timeout(format!("Polling {} timed out", url))));
+            let error = match polled {
+                Ok((head, body)) if head.status() == Some(200) => match serde_json::from_slice::<Batch>(&body) {
+                    Ok(batch) => {
+                        failures = 0;
+                        for envelope in batch.envelopes {
+                            self.dispatch(envelope.to_string().as_bytes(), url).await;
+                        }
+                        ack = batch.ack;
+                        continue;
+                    }
+                    Err(error) => UmicpError::serialization(format!("Malformed poll response: {}", error)),
+                },
+                Ok((head, _)) if head.status() == Some(404) => {
+                    return Err(UmicpError::connection(format!("Session {} is not open on {}", session, url)));
+                }
+                Ok((head, body)) => UmicpError::transport(format!(
+                    "{} answered {}: {}",
+                    url,
+                    head.start_line,
+                    String::from_utf8_lossy(&body)
+                )),
+                Err(error) => error,
+            };
+
+            failures += 1;
+            self.shared.subscribers.notify(TransportEvent::error(Some(url), error));
+            if failures > config.max_reconnect_attempts {
+                return Err(UmicpError::connection(format!(
+                    "Gave up polling {} after {} failed attempts",
+                    url, failures
+                )));
+            }
+            tokio::time::sleep(config.reconnect_delay(failures)).await;
+        }
+    }
+
+    /// Queue an envelope for a session's next poll (server mode)
+    pub async fn send_to(&self, envelope: Envelope, connection_id: &str) -> Result<()> {
+        let json = envelope.serialize()?;
+        let bytes = json.len() as u64;
+        {
+            let mut sessions = self.shared.sessions.lock().unwrap();
+            let session = sessions
+                .get_mut(connection_id)
+                .ok_or_else(|| UmicpError::connection(format!("Unknown connection: {}", connection_id)))?;
+            if session.pending.len() >= MAX_PENDING {
+                return Err(UmicpError::transport(format!(
+                    "Session {} has {} envelopes waiting to be polled",
+                    connection_id, MAX_PENDING
+                )));
+            }
+            let sequence = session.next_sequence;
+            session.next_sequence += 1;
+            session.pending.push_back((sequence, json));
+            session.queued.notify_one();
+        }
+
+        let mut stats = self.shared.stats.lock().unwrap();
+        stats.messages_sent += 1;
+        stats.bytes_sent += bytes;
+        Ok(())
+    }
+
+    /// Post an envelope to the server (client mode)
+    pub async fn send_to_server(&self, envelope: Envelope) -> Result<()> {
+        let Role::Client(client) = &self.shared.role else {
+            return Err(UmicpError::transport("send_to_server is only available in client mode"));
+        };
+        let Client {
+            url,
+            base_path,
+            session,
+            upstream,
+            ..
+        } = client.as_ref();
+        let body = envelope.serialize()?;
+        if body.len() > self.shared.max_payload_size {
+            return Err(UmicpError::validation(format!(
+                "Envelope of {} bytes exceeds max payload size {}",
+                body.len(),
+                self.shared.max_payload_size
+            )));
+        }
+
+        let start_line = format!("POST {}{} HTTP/1.1", base_path, MESSAGES_PATH);
+        let headers = [("Content-Type", "application/json"), (SESSION_HEADER, session.as_str())];
+        let (head, response) = upstream.request(&start_line, &headers, body.as_bytes(), MAX_RESPONSE_BODY).await?;
+        let reason = String::from_utf8_lossy(&response);
+        match head.status() {
+            Some(200..=299) => {
+                let mut stats = self.shared.stats.lock().unwrap();
+                stats.messages_sent += 1;
+                stats.bytes_sent += body.len() as u64;
+                Ok(())
+            }
+            Some(404) => Err(UmicpError::connection(format!("Session {} is not open on {}", session, url))),
+            Some(413) => Err(UmicpError::validation(reason.into_owned())),
+            _ => Err(UmicpError::transport(format!("{} answered {}: {}", url, head.start_line, reason))),
+        }
+    }
+
+    /// Serve the requests of one HTTP connection
+    async fn serve_connection(&self, stream: TcpStream) {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut shutdown = self.shared.shutdown.subscribe();
+        loop {
+            let head = tokio::select! {
+                head = http::read_head(&mut reader) => match head {
+                    Ok(Some(head)) => head,
+                    _ => return,
+                },
+                _ = shutdown.wait_for(|stopped| *stopped) => return,
+            };
+            let (method, _) = head.request();
+            // Whether the request's body, if any, was read, so the next request can follow
+            let mut consumed = head.header("content-length").unwrap_or("0") == "0";
+            let mut content_type = "text/plain; charset=utf-8";
+            let (status, message) = match (method, head.path()) {
+                ("OPTIONS", _) => (204, String::new()),
+                ("POST", SESSIONS_PATH) => (200, self.open_session().await),
+                ("DELETE", SESSIONS_PATH) => self.close_session(session_of(&head)).await,
+                ("GET", POLL_PATH) => {
+                    content_type = "application/json";
+                    self.poll(&head).await
+                }
+                ("POST", MESSAGES_PATH) => {
+                    match http::read_body(&mut reader, &head, self.shared.max_payload_size).await {
+                        Ok(body) => {
+                            consumed = true;
+                            self.receive_post(&head, &body).await
+                        }
+                        Err(error) => (413, error.to_string()),
+                    }
+                }
+                (_, SESSIONS_PATH | POLL_PATH | MESSAGES_PATH) => (405, "Method not allowed".to_string()),
+                _ => (404, "Not found".to_string()),
+            };
+
+            let keep_alive = head.keep_alive() && consumed;
+            let mut headers = CORS_HEADERS.to_vec();
+            headers.extend([("Content-Type", content_type), ("Cache-Control", "no-store")]);
+            if !keep_alive {
+                headers.push(("Connection", "close"));
+            }
+            let status_line = http::status_line(status);
+            let written = http::write_message(&mut writer, &status_line, &headers, message.as_bytes()).await;
+            if written.is_err() || !keep_alive {
+                return;
+            }
+        }
+    }
+
+    /// Open a session, returning its ID
+    async fn open_session(&self) -> String {
+        let id = uuid::Uuid::new_v4().to_string();
+        let session = Session {
+            pending: VecDeque::new(),
+            next_sequence: 1,
+            polling: 0,
+            last_seen: Instant::now(),
+            queued: Arc::new(Notify::new()),
+        };
+        self.shared.sessions.lock().unwrap().insert(id.clone(), session);
+        {
+            let mut stats = self.shared.stats.lock().unwrap();
+            stats.active_connections += 1;
+            stats.total_connections += 1;
+        }
+        self.notify_connection(true, id.clone()).await;
+        id
+    }
+
+    /// End a session at its client's request
+    async fn close_session(&self, id: &str) -> (u16, String) {
+        let removed = self.shared.sessions.lock().unwrap().remove(id);
+        match removed {
+            Some(session) => {
+                self.session_closed(id, session).await;
+                (204, String::new())
+            }
+            None => (404, format!("Unknown session: {}", id)),
+        }
+    }
+
+    /// End the sessions nobody has polled for `session_timeout`
+    async fn expire_sessions(&self) {
+        let expired: Vec<(String, Session)> = {
+            let mut sessions = self.shared.sessions.lock().unwrap();
+            let ids: Vec<String> = sessions
+                .iter()
+                .filter(|(_, session)| {
+                    session.polling == 0 && session.last_seen.elapsed() > self.shared.session_timeout
+                })
+                .map(|(id, _)| id.clone())
+                .collect();
+            ids.into_iter()
+                .filter_map(|id| sessions.remove(&id).map(|session| (id, session)))
+                .collect()
+        };
+        for (id, session) in expired {
+            self.session_closed(&id, session).await;
+        }
+    }
+
+    async fn session_closed(&self, id: &str, session: Session) {
+        // A poll still held for it answers 404
+        session.queued.notify_one();
+        {
+            let mut stats = self.shared.stats.lock().unwrap();
+            stats.active_connections = stats.active_connections.saturating_sub(1);
+        }
+        self.notify_connection(false, id.to_string()).await;
+    }
+
+    /// Answer a poll with the session's pending envelopes, waiting for some if there are none
+    async fn poll(&self, head: &Head) -> (u16, String) {
+        let id = session_of(head);
+        let ack: u64 = head.query("ack").and_then(|ack| ack.parse().ok()).unwrap_or(0);
+        let hold = head
+            .query("wait")
+            .and_then(|wait| wait.parse().ok())
+            .map_or(self.shared.hold, |wait| self.shared.hold.min(Duration::from_secs(wait)));
+        let deadline = tokio::time::Instant::now() + hold;
+        let mut shutdown = self.shared.shutdown.subscribe();
+
+        let mut held = false;
+        let answer = loop {
+            let queued = {
+                let mut sessions = self.shared.sessions.lock().unwrap();
+                let Some(session) = sessions.get_mut(id) else {
+                    break (404, format!("Unknown session: {}", id));
+                };
+                if !held {
+                    session.polling += 1;
+                    held = true;
+                }
+                while session.pending.front().is_some_and(|(sequence, _)| *sequence <= ack) {
+                    session.pending.pop_front();
+                }
+                if !session.pending.is_empty() {
+                    break (200, batch(&session.pending, self.shared.max_payload_size));
+                }
+                session.queued.clone()
+            };
+            tokio::select! {
+                _ = queued.notified() => {}
+                _ = tokio::time::sleep_until(deadline) => break (200, format!("{{\"ack\":{},\"envelopes\":[]}}", ack)),
+                _ = shutdown.wait_for(|stopped| *stopped) => break (503, "Server is shutting down".to_string()),
+            }
+        };
+
+        if held {
+            if let Some(session) = self.shared.sessions.lock().unwrap().get_mut(id) {
+                session.polling -= 1;
+                session.last_seen = Instant::now();
+            }
+        }
+        answer
+    }
+
+    /// Handle a posted envelope, answering with a status and a message
+    async fn receive_post(&self, head: &Head, body: &[u8]) -> (u16, String) {
+        let session = session_of(head);
+        if !self.shared.sessions.lock().unwrap().contains_key(session) {
+            return (404, format!("Unknown session: {}", session));
+        }
+        let session = session.to_string();
+        {
+            let mut stats = self.shared.stats.lock().unwrap();
+            stats.messages_received += 1;
+            stats.bytes_received += body.len() as u64;
+        }
+        match self.dispatch(body, &session).await {
+            true => (202, String::new()),
+            false => (400, "Body is not a valid envelope".to_string()),
+        }
+    }
+
+    /// Decode an envelope and hand it to subscribers and the handler; `false` if it is invalid
+    async fn dispatch(&self, bytes: &[u8], conn_id: &str) -> bool {
+        let parsed = std::str::from_utf8(bytes)
+            .map_err(|_| UmicpError::serialization("Envelope is not valid UTF-8"))
+            .and_then(Envelope::deserialize);
+        let envelope = match parsed {
+            Ok(envelope) => envelope,
+            Err(error) => {
+                self.shared.subscribers.notify(TransportEvent::error(Some(conn_id), error));
+                return false;
+            }
+        };
+        if let Role::Client(_) = self.shared.role {
+            let mut stats = self.shared.stats.lock().unwrap();
+            stats.messages_received += 1;
+            stats.bytes_received += bytes.len() as u64;
+        }
+
+        self.shared.subscribers.publish(&envelope, conn_id);
+        let handler = self.shared.message_handler.read().unwrap().clone();
+        if let Some(handler) = handler {
+            if let Err(error) = handler(envelope, conn_id.to_string()).await {
+                self.shared.subscribers.notify(TransportEvent::error(Some(conn_id), error));
+            }
+        }
+        true
+    }
+
+    async fn notify_connection(&self, connected: bool, conn_id: String) {
+        self.shared.subscribers.notify(match connected {
+            true => TransportEvent::Connected(conn_id.clone()),
+            false => TransportEvent::Disconnected(conn_id.clone()),
+        });
+        let handler = self.shared.connection_handler.read().unwrap().clone();
+        if let Some(handler) = handler {
+            handler(connected, conn_id).await;
+        }
+    }
+}
+
+/// Session a request names, by header or query parameter
+fn session_of(head: &Head) -> &str {
+    head.header(SESSION_HEADER).or_else(|| head.query("session")).unwrap_or_default()
+}
+
+/// JSON batch of the oldest pending envelopes, about `max_size` bytes of them but at least one
+fn batch(pending: &VecDeque<(u64, String)>, max_size: usize) -> String {
+    let mut envelopes = Vec::new();
+    let mut size = 0;
+    let mut ack = 0;
+    for (sequence, json) in pending {
+        if !envelopes.is_empty() && size + json.len() > max_size {
+            break;
+        }
+        size += json.len();
+        ack = *sequence;
+        envelopes.push(json.as_str());
+    }
+    format!("{{\"ack\":{},\"envelopes\":[{}]}}", ack, envelopes.join(","))
+}
+
+#[async_trait]
+impl Transport for LongPollingTransport {
+    /// Spawn [`run`](LongPollingTransport::run) in the background
+    async fn connect(&self) -> Result<()> {
+        let transport = self.clone();
+        tokio::spawn(async move { transport.run().await });
+        Ok(())
+    }
+
+    async fn send(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<()> {
+        match (&self.shared.role, connection_id) {
+            (Role::Server { .. }, Some(connection_id)) => self.send_to(envelope, connection_id).await,
+            (Role::Server { .. }, None) => Err(UmicpError::transport("A connection ID is required in server mode")),
+            (Role::Client(_), _) => self.send_to_server(envelope).await,
+        }
+    }
+
+    fn subscribe(&self) -> Subscription {
+        self.shared.subscribers.subscribe()
+    }
+
+    fn events(&self) -> EventStream {
+        self.shared.subscribers.events()
+    }
+
+    async fn stats(&self) -> TransportStats {
+        let mut stats = self.shared.stats.lock().unwrap().clone();
+        stats.uptime_seconds = self.shared.started.elapsed().as_secs();
+        stats
+    }
+
+    /// Stop the transport; a client also ends its session on the server, if it can
+    async fn shutdown(&self) -> Result<()> {
+        let stopping = !self.shared.shutdown.send_replace(true);
+        let sessions: Vec<Session> = self.shared.sessions.lock().unwrap().drain().map(|(_, session)| session).collect();
+        for session in sessions {
+            session.queued.notify_one();
+        }
+        if let Role::Client(client) = &self.shared.role {
+            client.polls.close().await;
+            if stopping {
+                let start_line = format!("DELETE {}{} HTTP/1.1", client.base_path, SESSIONS_PATH);
+                let headers = [(SESSION_HEADER, client.session.as_str())];
+                let closed = client.upstream.request(&start_line, &headers, b"", MAX_RESPONSE_BODY);
+                let _ = tokio::time::timeout(Duration::from_secs(self.shared.config.connection_timeout), closed).await;
+            }
+            client.upstream.close().await;
+        }
+        self.shared.stats.lock().unwrap().active_connections = 0;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for LongPollingTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LongPollingTransport")
+            .field("local_addr", &self.local_addr())
+            .field("session", &self.session_id())
+            .field("sessions", &self.shared.sessions.lock().unwrap().len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::OperationType;
+
+    fn make_envelope(from: &str, operation: OperationType) -> Envelope {
+        Envelope::builder()
+            .from(from)
+            .to("peer")
+            .operation(operation)
+            .message_id(&uuid::Uuid::new_v4().to_string())
+            .build()
+            .unwrap()
+    }
+
+    async fn next_disconnect(events: &mut EventStream) -> String {
+        let disconnected = async {
+            while let Some(event) = events.recv().await {
+                if let TransportEvent::Disconnected(id) = event {
+                    return id;
+                }
+            }
+            unreachable!()
+        };
+        tokio::time::timeout(Duration::from_secs(10), disconnected).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_long_polling_round_trip() {
+        let config = TransportConfig {
+            max_payload_size: 4096,
+            heartbeat_interval: 1,
+            ..Default::default()
+        };
+        let server = LongPollingTransport::new_server("127.0.0.1:0", &config).await.unwrap();
+        let mut incoming = server.subscribe();
+        let mut events = server.events();
+        server.connect().await.unwrap();
+        let url = format!("http://{}", server.local_addr().unwrap());
+
+        let client = LongPollingTransport::new_client(&url, &config).await.unwrap();
+        let session = client.session_id().unwrap().to_string();
+        let mut replies = client.subscribe();
+        client.connect().await.unwrap();
+
+        for _ in 0..3 {
+            client.send(make_envelope("client", OperationType::Data), None).await.unwrap();
+        }
+        for _ in 0..3 {
+            let received = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap();
+            let (envelope, conn_id) = received.unwrap();
+            assert_eq!(envelope.from(), "client");
+            assert_eq!(conn_id, session);
+        }
+
+        // Replies queued while no poll is held, and across empty polls, arrive in order
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        for index in 0..5 {
+            let mut reply = make_envelope("server", OperationType::Ack);
+            reply.add_capability("index", &index.to_string());
+            server.send(reply, Some(&session)).await.unwrap();
+        }
+        for index in 0..5 {
+            let (reply, _) = tokio::time::timeout(Duration::from_secs(5), replies.recv()).await.unwrap().unwrap();
+            assert_eq!(reply.capabilities().unwrap()["index"], index.to_string());
+        }
+        assert_eq!(server.stats().await.messages_received, 3);
+        assert_eq!(client.stats().await.messages_received, 5);
+
+        let mut oversized = make_envelope("client", OperationType::Data);
+        oversized.set_payload(vec![0; 8192]);
+        assert!(matches!(client.send_to_server(oversized).await, Err(UmicpError::Validation { .. })));
+
+        // Shutting the client down ends its session right away
+        client.shutdown().await.unwrap();
+        assert_eq!(next_disconnect(&mut events).await, session);
+        assert!(server.send(make_envelope("server", OperationType::Ack), Some(&session)).await.is_err());
+        server.shutdown().await.unwrap();
+    }
+
+    /// One request and its response on a kept-alive connection
+    async fn exchange(connection: &mut BufReader<TcpStream>, start_line: &str) -> (Head, String) {
+        http::write_message(connection.get_mut(), start_line, &[], b"").await.unwrap();
+        let head = http::read_head(connection).await.unwrap().unwrap();
+        let body = http::read_body(connection, &head, 64 * 1024).await.unwrap();
+        (head, String::from_utf8(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_long_polling_acknowledgement_and_expiry() {
+        let config = TransportConfig {
+            heartbeat_interval: 1,
+            connection_timeout: 1,
+            ..Default::default()
+        };
+        let server = LongPollingTransport::new_server("127.0.0.1:0", &config).await.unwrap();
+        let mut events = server.events();
+        server.connect().await.unwrap();
+        let mut connection = BufReader::new(TcpStream::connect(server.local_addr().unwrap()).await.unwrap());
+
+        let (head, session) = exchange(&mut connection, "POST /sessions HTTP/1.1").await;
+        assert_eq!(head.status(), Some(200));
+        for _ in 0..2 {
+            server.send(make_envelope("server", OperationType::Data), Some(&session)).await.unwrap();
+        }
+
+        // Unacknowledged envelopes are answered again, acknowledged ones are dropped
+        let poll = |ack: u64| format!("GET /poll?session={}&ack={} HTTP/1.1", session, ack);
+        for _ in 0..2 {
+            let (_, body) = exchange(&mut connection, &poll(0)).await;
+            let batch: Batch = serde_json::from_str(&body).unwrap();
+            assert_eq!((batch.ack, batch.envelopes.len()), (2, 2));
+        }
+        let (head, body) = exchange(&mut connection, &poll(2)).await;
+        assert_eq!(head.header("content-type"), Some("application/json"));
+        assert_eq!(body, r#"{"ack":2,"envelopes":[]}"#);
+
+        let (head, _) = exchange(&mut connection, "GET /poll?session=nope HTTP/1.1").await;
+        assert_eq!(head.status(), Some(404));
+        assert_eq!(exchange(&mut connection, "PUT /poll HTTP/1.1").await.0.status(), Some(405));
+
+        // A session nobody polls is ended by the server
+        assert_eq!(next_disconnect(&mut events).await, session);
+        let (head, _) = exchange(&mut connection, &poll(2)).await;
+        assert_eq!(head.status(), Some(404));
+        server.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+/*!
+# UMICP Priority Queues
+
+Outgoing queue of a connection, with one lane per [`MessagePriority`]. The highest non-empty
+lane goes first, so an ack queued behind a burst of bulk data goes out next rather than last.
+
+Strict priority would let a steady stream of high-priority messages hold bulk data back
+forever. Instead each lane counts the messages taken from higher lanes while it had one
+waiting; once that reaches the burst limit, its oldest message goes next and the count starts
+over. Within a lane messages keep the order they were queued in.
+*/
+
+use crate::types::MessagePriority;
+use std::collections::VecDeque;
+
+const LANES: usize = 3;
+
+fn lane(priority: MessagePriority) -> usize {
+    match priority {
+        MessagePriority::High => 0,
+        MessagePriority::Normal => 1,
+        MessagePriority::Low => 2,
+    }
+}
+
+/// Messages waiting to be written, by priority
+pub(crate) struct PriorityQueue<T> {
+    lanes: [VecDeque<T>; LANES],
+    /// Per lane, messages sent from higher lanes while it was waiting
+    passed_over: [usize; LANES],
+    /// Passes a waiting lane tolerates; 0 for strict priority
+    max_burst: usize,
+}
+
+impl<T> PriorityQueue<T> {
+    pub(crate) fn new(max_burst: usize) -> Self {
+        PriorityQueue {
+            lanes: Default::default(),
+            passed_over: [0; LANES],
+            max_burst,
+        }
+    }
+
+    pub(crate) fn push(&mut self, priority: MessagePriority, item: T) {
+        self.lanes[lane(priority)].push_back(item);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.lanes.iter().all(VecDeque::is_empty)
+    }
+
+    /// Next message to write
+    pub(crate) fn pop(&mut self) -> Option<T> {
+        let waiting = |index: &usize| !self.lanes[*index].is_empty();
+        // The lowest lane that has waited long enough goes first, then the highest with messages
+        let starved = (0..LANES)
+            .rev()
+            .filter(waiting)
+            .find(|index| self.max_burst > 0 && self.passed_over[*index] >= self.max_burst);
+        let chosen = starved.or_else(|| (0..LANES).find(waiting))?;
+
+        self.passed_over[chosen] = 0;
+        for index in chosen + 1..LANES {
+            if !self.lanes[index].is_empty() {
+                self.passed_over[index] += 1;
+            }
+        }
+        self.lanes[chosen].pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_priority_queue_preempts_and_prevents_starvation() {
+        let mut queue = PriorityQueue::new(2);
+        for item in ["bulk-1", "bulk-2"] {
+            queue.push(MessagePriority::Low, item);
+        }
+        queue.push(MessagePriority::Normal, "request");
+        for item in ["ack-1", "ack-2", "ack-3"] {
+            queue.push(MessagePriority::High, item);
+        }
+
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop()).collect();
+        // After two acks passed them, the waiting lanes each get a turn, lowest first
+        assert_eq!(order, ["ack-1", "ack-2", "bulk-1", "request", "ack-3", "bulk-2"]);
+        assert!(queue.is_empty());
+
+        // Strict priority never lets bulk data through while acks are queued
+        let mut queue = PriorityQueue::new(0);
+        queue.push(MessagePriority::Low, "bulk");
+        for item in ["ack-1", "ack-2", "ack-3"] {
+            queue.push(MessagePriority::High, item);
+        }
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(order, ["ack-1", "ack-2", "ack-3", "bulk"]);
+    }
+}
@@ -0,0 +1,334 @@
+/*!
+# UMICP Outbound Proxies
+
+Tunnels client connections through the proxy named by `TransportConfig::proxy_url`, for
+networks that only reach the outside through one.
+
+- `http://[user:password@]host[:port]` opens the tunnel with an HTTP `CONNECT` request, sending
+  the credentials as `Proxy-Authorization: Basic`. The port defaults to 8080.
+- `socks5://[user:password@]host[:port]` speaks SOCKS5 (RFC 1928), authenticating with a
+  username and password (RFC 1929) when given. The target host name is passed to the proxy
+  unresolved, so DNS happens on the proxy's side. The port defaults to 1080.
+
+TLS for `wss://` URLs runs inside the tunnel, end to end with the server.
+*/
+
+use crate::error::{Result, UmicpError};
+use crate::secret::Secret;
+use std::net::IpAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Upper bound on the proxy's `CONNECT` response head, which carries no payload
+const MAX_RESPONSE_HEAD: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    HttpConnect,
+    Socks5,
+}
+
+/// Parsed proxy URL
+#[derive(Debug, Clone)]
+pub(crate) struct Proxy {
+    protocol: Protocol,
+    host: String,
+    port: u16,
+    credentials: Option<(String, Secret<String>)>,
+}
+
+impl Proxy {
+    pub(crate) fn parse(url: &str) -> Result<Self> {
+        // The URL may hold a password, so errors show it without its user info
+        let shown = match url.split_once("://").zip(url.rsplit_once('@')) {
+            Some(((scheme, _), (_, address))) => format!("{}://[REDACTED]@{}", scheme, address),
+            None => url.to_string(),
+        };
+        let invalid = |reason: &str| UmicpError::configuration(format!("Invalid proxy URL {}: {}", shown, reason));
+        let (scheme, rest) = url.split_once("://").ok_or_else(|| invalid("missing scheme"))?;
+        let (protocol, default_port) = match scheme.to_ascii_lowercase().as_str() {
+            "http" => (Protocol::HttpConnect, 8080),
+            "socks5" | "socks5h" => (Protocol::Socks5, 1080),
+            _ => return Err(invalid("expected an http:// or socks5:// URL")),
+        };
+        let authority = rest.trim_end_matches('/');
+        let (credentials, address) = match authority.rsplit_once('@') {
+            Some((userinfo, address)) => {
+                let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                (Some((user.to_string(), Secret::new(password.to_string()))), address)
+            }
+            None => (None, authority),
+        };
+        let (host, port) = split_host_port(address, default_port).ok_or_else(|| invalid("bad host or port"))?;
+        Ok(Proxy {
+            protocol,
+            host,
+            port,
+            credentials,
+        })
+    }
+
+    /// Open a TCP tunnel through the proxy to `host:port`
+    pub(crate) async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| UmicpError::connection(format!("Failed to reach proxy {}:{}: {}", self.host, self.port, e)))?;
+        match self.protocol {
+            Protocol::HttpConnect => self.http_connect(&mut stream, host, port).await?,
+            Protocol::Socks5 => self.socks5_connect(&mut stream, host, port).await?,
+        }
+        Ok(stream)
+    }
+
+    async fn http_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+        let target = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+            _ => format!("{}:{}", host, port),
+        };
+        let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some((user, password)) = &self.credentials {
+            let basic = base64::encode(format!("{}:{}", user, password.expose()));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", basic));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read byte by byte so nothing past the head is consumed from the tunnel
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_RESPONSE_HEAD {
+                return Err(UmicpError::connection("Proxy response head is too large"));
+            }
+            head.push(read_u8(stream).await?);
+        }
+        let status_line = String::from_utf8_lossy(&head);
+        let status_line = status_line.lines().next().unwrap_or_default();
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            Some("407") => Err(UmicpError::authentication(format!("Proxy rejected the credentials: {}", status_line))),
+            _ => Err(UmicpError::connection(format!("Proxy refused CONNECT to {}: {}", target, status_line))),
+        }
+    }
+
+    async fn socks5_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+        const VERSION: u8 = 5;
+        const NO_AUTH: u8 = 0;
+        const USER_PASSWORD: u8 = 2;
+
+        let greeting: &[u8] = match self.credentials {
+            Some(_) => &[VERSION, 2, NO_AUTH, USER_PASSWORD],
+            None => &[VERSION, 1, NO_AUTH],
+        };
+        stream.write_all(greeting).await?;
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+        match (choice, &self.credentials) {
+            ([VERSION, NO_AUTH], _) => {}
+            ([VERSION, USER_PASSWORD], Some((user, password))) => {
+                let password = password.expose();
+                if user.len() > 255 || password.len() > 255 {
+                    return Err(UmicpError::configuration("SOCKS5 credentials are limited to 255 bytes each"));
+                }
+                let mut auth = vec![1, user.len() as u8];
+                auth.extend_from_slice(user.as_bytes());
+                auth.push(password.len() as u8);
+                auth.extend_from_slice(password.as_bytes());
+                stream.write_all(&auth).await?;
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status).await?;
+                if status[1] != 0 {
+                    return Err(UmicpError::authentication("SOCKS5 proxy rejected the credentials"));
+                }
+            }
+            _ => return Err(UmicpError::authentication("SOCKS5 proxy offered no acceptable authentication method")),
+        }
+
+        let mut request = vec![VERSION, 1, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                let name = host.as_bytes();
+                if name.len() > 255 {
+                    return Err(UmicpError::configuration(format!("Host name too long for SOCKS5: {}", host)));
+                }
+                request.push(3);
+                request.push(name.len() as u8);
+                request.extend_from_slice(name);
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(UmicpError::connection(format!(
+                "SOCKS5 proxy refused the connection to {}:{}: {}",
+                host,
+                port,
+                socks5_reply_reason(reply[1])
+            )));
+        }
+        // Skip the bound address that follows
+        let address_len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => read_u8(stream).await? as usize,
+            other => return Err(UmicpError::connection(format!("Unknown SOCKS5 address type {}", other))),
+        };
+        let mut bound = vec![0u8; address_len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+}
+
+async fn read_u8<S: AsyncRead + Unpin>(stream: &mut S) -> Result<u8> {
+    let mut byte = [0u8; 1];
+    stream
+        .read_exact(&mut byte)
+        .await
+        .map_err(|_| UmicpError::connection("Proxy closed the connection during the handshake"))?;
+    Ok(byte[0])
+}
+
+/// Split `host[:port]`, where an IPv6 host is bracketed
+fn split_host_port(address: &str, default_port: u16) -> Option<(String, u16)> {
+    let (host, port) = match address.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed.split_once(']')?;
+            (host, rest.strip_prefix(':'))
+        }
+        None => match address.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (address, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => default_port,
+    };
+    (!host.is_empty()).then(|| (host.to_string(), port))
+}
+
+fn socks5_reply_reason(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::Envelope;
+    use crate::transport::{Transport, WebSocketTransport};
+    use crate::types::{OperationType, TransportConfig};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    /// Minimal proxy accepting one tunnel: answers the handshake, then relays bytes to the target
+    async fn spawn_proxy(protocol: Protocol) -> (String, tokio::sync::oneshot::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (report, target) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let destination = match protocol {
+                Protocol::HttpConnect => {
+                    let mut head = Vec::new();
+                    while !head.ends_with(b"\r\n\r\n") {
+                        head.push(read_u8(&mut client).await.unwrap());
+                    }
+                    let head = String::from_utf8(head).unwrap();
+                    assert!(head.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"), "{}", head);
+                    client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.unwrap();
+                    head.split_whitespace().nth(1).unwrap().to_string()
+                }
+                Protocol::Socks5 => {
+                    let mut greeting = [0u8; 3];
+                    client.read_exact(&mut greeting).await.unwrap();
+                    assert_eq!(greeting, [5, 1, 0]);
+                    client.write_all(&[5, 0]).await.unwrap();
+                    let mut request = [0u8; 5];
+                    client.read_exact(&mut request).await.unwrap();
+                    assert_eq!(&request[..4], &[5, 1, 0, 3]);
+                    let mut name = vec![0u8; request[4] as usize + 2];
+                    client.read_exact(&mut name).await.unwrap();
+                    let port = u16::from_be_bytes([name[name.len() - 2], name[name.len() - 1]]);
+                    client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+                    format!("{}:{}", String::from_utf8_lossy(&name[..name.len() - 2]), port)
+                }
+            };
+            let _ = report.send(destination.clone());
+            let mut upstream = TcpStream::connect(destination).await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+        });
+        let url = match protocol {
+            Protocol::HttpConnect => format!("http://user:secret@{}", address),
+            Protocol::Socks5 => format!("socks5://{}", address),
+        };
+        (url, target)
+    }
+
+    #[test]
+    fn test_proxy_url_parsing() {
+        let proxy = Proxy::parse("http://alice:pw@proxy.corp:3128/").unwrap();
+        assert_eq!(proxy.protocol, Protocol::HttpConnect);
+        assert_eq!((proxy.host.as_str(), proxy.port), ("proxy.corp", 3128));
+        let (user, password) = proxy.credentials.unwrap();
+        assert_eq!((user.as_str(), password.expose().as_str()), ("alice", "pw"));
+
+        let proxy = Proxy::parse("socks5://[::1]").unwrap();
+        assert_eq!((proxy.protocol, proxy.host.as_str(), proxy.port), (Protocol::Socks5, "::1", 1080));
+        assert!(Proxy::parse("ftp://proxy").is_err());
+        assert!(Proxy::parse("http://proxy:notaport").is_err());
+        let error = Proxy::parse("http://alice:pw@proxy:notaport").unwrap_err();
+        assert!(!error.to_string().contains("pw"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_through_proxies() {
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        server.connect().await.unwrap();
+        let url = format!("ws://localhost:{}", port);
+
+        for protocol in [Protocol::HttpConnect, Protocol::Socks5] {
+            let (proxy_url, target) = spawn_proxy(protocol).await;
+            let config = TransportConfig {
+                proxy_url: Some(proxy_url.into()),
+                ..Default::default()
+            };
+            let client = WebSocketTransport::new_client_with_config(&url, &config).await.unwrap();
+            assert_eq!(target.await.unwrap(), format!("localhost:{}", port));
+
+            let mut incoming = server.subscribe();
+            Transport::connect(&client).await.unwrap();
+            let envelope = Envelope::builder()
+                .from("client")
+                .to("server")
+                .operation(OperationType::Data)
+                .build()
+                .unwrap();
+            client.send_to_server(envelope.clone()).await.unwrap();
+            let (received, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+            assert_eq!(received.message_id(), envelope.message_id());
+            client.shutdown().await.unwrap();
+        }
+        server.shutdown().await.unwrap();
+    }
+}
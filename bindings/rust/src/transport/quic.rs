@@ -0,0 +1,497 @@
+/*!
+# UMICP QUIC Transport
+
+quinn based server and client (requires the `quic` feature), for lossy mobile/IoT links where
+TCP head-of-line blocking hurts latency.
+
+Envelopes travel over unidirectional QUIC streams as length-prefixed JSON frames (a big-endian
+`u32` length, then the payload). [`QuicStreamMode`] picks how streams are used when sending:
+
+- [`PerEnvelope`](QuicStreamMode::PerEnvelope) opens a fresh stream for each envelope, so a lost
+  packet only stalls the envelope it belongs to, at the cost of ordering between envelopes.
+- [`Multiplexed`](QuicStreamMode::Multiplexed) writes every envelope to one long-lived stream
+  per connection, keeping them ordered like a TCP transport would.
+
+Receivers accept both, so peers can choose independently. QUIC always runs over TLS 1.3: servers
+need `tls_cert_path`/`tls_key_path`, and clients verify certificates as described for the
+WebSocket transport's `wss://` support.
+*/
+
+use super::{ConnectionHandler, EventStream, MessageHandler, Subscribers, Subscription, Transport, TransportEvent};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::{TransportConfig, TransportStats};
+use async_trait::async_trait;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Connection, Endpoint, RecvStream, SendStream, VarInt};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use tokio::sync::{mpsc, watch};
+
+/// ALPN protocol identifier negotiated by UMICP QUIC peers
+pub const QUIC_ALPN: &[u8] = b"umicp";
+
+/// How a [`QuicTransport`] maps outgoing envelopes onto QUIC streams
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuicStreamMode {
+    /// One unidirectional stream per envelope (no head-of-line blocking between envelopes)
+    #[default]
+    PerEnvelope,
+    /// One long-lived unidirectional stream per connection (envelopes stay ordered)
+    Multiplexed,
+}
+
+struct Peer {
+    connection: Connection,
+    /// Frame queue feeding the long-lived stream in multiplexed mode
+    stream: Option<mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+enum Role {
+    Server,
+    Client { peer_id: String, connection: Mutex<Option<Connection>> },
+}
+
+struct Shared {
+    role: Role,
+    endpoint: Endpoint,
+    mode: QuicStreamMode,
+    max_payload_size: usize,
+    message_handler: RwLock<Option<MessageHandler>>,
+    connection_handler: RwLock<Option<ConnectionHandler>>,
+    subscribers: Subscribers,
+    peers: RwLock<HashMap<String, Peer>>,
+    stats: Mutex<TransportStats>,
+    started: Instant,
+    shutdown: watch::Sender<bool>,
+}
+
+/// QUIC transport for UMICP envelopes
+#[derive(Clone)]
+pub struct QuicTransport {
+    shared: Arc<Shared>,
+}
+
+impl QuicTransport {
+    /// Bind a QUIC server to `addr` using the certificate and key from `config`
+    pub async fn new_server(addr: &str, config: &TransportConfig, mode: QuicStreamMode) -> Result<Self> {
+        let mut tls = super::tls::server_config(config)?;
+        tls.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+        let crypto = QuicServerConfig::try_from(tls)
+            .map_err(|e| UmicpError::configuration(format!("TLS configuration unusable for QUIC: {}", e)))?;
+
+        let bind_addr = resolve(addr).await?;
+        let endpoint = Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(crypto)), bind_addr)
+            .map_err(|e| UmicpError::connection(format!("Failed to bind {}: {}", addr, e)))?;
+
+        Ok(Self::with_role(Role::Server, endpoint, config, mode))
+    }
+
+    /// Connect a QUIC client to `url` (`quic://host:port`)
+    pub async fn new_client(url: &str, config: &TransportConfig, mode: QuicStreamMode) -> Result<Self> {
+        let authority = url
+            .strip_prefix("quic://")
+            .ok_or_else(|| UmicpError::configuration(format!("QUIC URL must start with quic://: {}", url)))?
+            .trim_end_matches('/');
+        let host = match authority.rsplit_once(':') {
+            Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => return Err(UmicpError::configuration(format!("QUIC URL needs an explicit port: {}", url))),
+        };
+        let remote = resolve(authority).await?;
+
+        let mut tls = super::tls::client_config(config)?;
+        tls.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+        let crypto = QuicClientConfig::try_from(tls)
+            .map_err(|e| UmicpError::configuration(format!("TLS configuration unusable for QUIC: {}", e)))?;
+
+        let local: SocketAddr = if remote.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }.parse().unwrap();
+        let mut endpoint = Endpoint::client(local)?;
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+
+        let connection = endpoint
+            .connect(remote, host)
+            .map_err(|e| UmicpError::connection(format!("Failed to connect to {}: {}", url, e)))?
+            .await
+            .map_err(|e| UmicpError::connection(format!("Failed to connect to {}: {}", url, e)))?;
+
+        let role = Role::Client {
+            peer_id: url.to_string(),
+            connection: Mutex::new(Some(connection.clone())),
+        };
+        let transport = Self::with_role(role, endpoint, config, mode);
+        transport.register_peer(url.to_string(), connection);
+        Ok(transport)
+    }
+
+    fn with_role(role: Role, endpoint: Endpoint, config: &TransportConfig, mode: QuicStreamMode) -> Self {
+        let (shutdown, _) = watch::channel(false);
+        QuicTransport {
+            shared: Arc::new(Shared {
+                role,
+                endpoint,
+                mode,
+                max_payload_size: config.max_payload_size,
+                message_handler: RwLock::new(None),
+                connection_handler: RwLock::new(None),
+                subscribers: Subscribers::default(),
+                peers: RwLock::new(HashMap::new()),
+                stats: Mutex::new(TransportStats::default()),
+                started: Instant::now(),
+                shutdown,
+            }),
+        }
+    }
+
+    /// Set message handler for incoming messages
+    ///
+    /// Envelopes on different streams are delivered concurrently, so the handler may run
+    /// several times at once in per-envelope mode.
+    pub fn set_message_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(Envelope, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        *self.shared.message_handler.write().unwrap() = Some(super::message_handler(handler));
+    }
+
+    /// Set connection handler for connection events (`true` on connect, `false` on disconnect)
+    pub fn set_connection_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(bool, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        *self.shared.connection_handler.write().unwrap() = Some(super::connection_handler(handler));
+    }
+
+    /// Local address of the underlying UDP socket
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.shared.endpoint.local_addr()?)
+    }
+
+    /// Drive the transport until [`shutdown`](Transport::shutdown) is called
+    ///
+    /// A server accepts connections; a client reads from the server until either side closes.
+    pub async fn run(&self) -> Result<()> {
+        match &self.shared.role {
+            Role::Server => {
+                let mut shutdown = self.shared.shutdown.subscribe();
+                loop {
+                    let incoming = tokio::select! {
+                        incoming = self.shared.endpoint.accept() => match incoming {
+                            Some(incoming) => incoming,
+                            None => return Ok(()),
+                        },
+                        _ = shutdown.wait_for(|stopped| *stopped) => return Ok(()),
+                    };
+                    let transport = self.clone();
+                    tokio::spawn(async move {
+                        // A failed handshake only affects that peer
+                        if let Ok(connection) = incoming.await {
+                            let conn_id = uuid::Uuid::new_v4().to_string();
+                            transport.register_peer(conn_id.clone(), connection.clone());
+                            transport.serve_connection(connection, conn_id).await;
+                        }
+                    });
+                }
+            }
+            Role::Client { peer_id, connection } => {
+                let connection = connection
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .ok_or_else(|| UmicpError::transport("Client is already running"))?;
+                self.serve_connection(connection, peer_id.clone()).await;
+                Ok(())
+            }
+        }
+    }
+
+    /// Send message to a specific connection
+    pub async fn send_to(&self, envelope: Envelope, connection_id: &str) -> Result<()> {
+        let json = envelope.serialize()?;
+        if json.len() > self.shared.max_payload_size {
+            return Err(UmicpError::validation(format!(
+                "Envelope of {} bytes exceeds max payload size {}",
+                json.len(),
+                self.shared.max_payload_size
+            )));
+        }
+        let frame = frame(json.as_bytes());
+
+        let (connection, stream) = {
+            let peers = self.shared.peers.read().unwrap();
+            let peer = peers
+                .get(connection_id)
+                .ok_or_else(|| UmicpError::connection(format!("Unknown connection: {}", connection_id)))?;
+            (peer.connection.clone(), peer.stream.clone())
+        };
+
+        match stream {
+            Some(stream) => stream
+                .send(frame)
+                .map_err(|_| UmicpError::connection(format!("Connection closed: {}", connection_id)))?,
+            None => {
+                let closed = |e: &dyn std::fmt::Display| UmicpError::connection(format!("Send to {} failed: {}", connection_id, e));
+                let mut stream = connection.open_uni().await.map_err(|e| closed(&e))?;
+                stream.write_all(&frame).await.map_err(|e| closed(&e))?;
+                stream.finish().map_err(|e| closed(&e))?;
+            }
+        }
+
+        let mut stats = self.shared.stats.lock().unwrap();
+        stats.messages_sent += 1;
+        stats.bytes_sent += json.len() as u64;
+        Ok(())
+    }
+
+    /// Send message to server (client mode)
+    pub async fn send_to_server(&self, envelope: Envelope) -> Result<()> {
+        match &self.shared.role {
+            Role::Client { peer_id, .. } => self.send_to(envelope, peer_id).await,
+            Role::Server => Err(UmicpError::transport("send_to_server is only available in client mode")),
+        }
+    }
+
+    fn register_peer(&self, conn_id: String, connection: Connection) {
+        let stream = match self.shared.mode {
+            QuicStreamMode::PerEnvelope => None,
+            QuicStreamMode::Multiplexed => {
+                let (sender, mut frames) = mpsc::unbounded_channel::<Vec<u8>>();
+                let connection = connection.clone();
+                tokio::spawn(async move {
+                    let mut stream: SendStream = match connection.open_uni().await {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    while let Some(frame) = frames.recv().await {
+                        if stream.write_all(&frame).await.is_err() {
+                            return;
+                        }
+                    }
+                    let _ = stream.finish();
+                });
+                Some(sender)
+            }
+        };
+
+        self.shared.peers.write().unwrap().insert(conn_id, Peer { connection, stream });
+        let mut stats = self.shared.stats.lock().unwrap();
+        stats.active_connections += 1;
+        stats.total_connections += 1;
+    }
+
+    async fn serve_connection(&self, connection: Connection, conn_id: String) {
+        self.notify_connection(true, conn_id.clone()).await;
+
+        let mut shutdown = self.shared.shutdown.subscribe();
+        loop {
+            let stream = tokio::select! {
+                stream = connection.accept_uni() => match stream {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                },
+                _ = shutdown.wait_for(|stopped| *stopped) => break,
+            };
+            let transport = self.clone();
+            let conn_id = conn_id.clone();
+            tokio::spawn(async move { transport.read_stream(stream, conn_id).await });
+        }
+
+        if self.shared.peers.write().unwrap().remove(&conn_id).is_some() {
+            let mut stats = self.shared.stats.lock().unwrap();
+            stats.active_connections = stats.active_connections.saturating_sub(1);
+        }
+        self.notify_connection(false, conn_id).await;
+    }
+
+    async fn read_stream(&self, mut stream: RecvStream, conn_id: String) {
+        loop {
+            let mut length = [0u8; 4];
+            // A clean end of stream between frames surfaces as an error here too
+            if stream.read_exact(&mut length).await.is_err() {
+                return;
+            }
+            let length = u32::from_be_bytes(length) as usize;
+            if length > self.shared.max_payload_size {
+                let _ = stream.stop(VarInt::from_u32(1));
+                return;
+            }
+            let mut payload = vec![0u8; length];
+            if stream.read_exact(&mut payload).await.is_err() {
+                return;
+            }
+
+            {
+                let mut stats = self.shared.stats.lock().unwrap();
+                stats.messages_received += 1;
+                stats.bytes_received += length as u64;
+            }
+
+            // Frames that are not valid envelopes are dropped rather than closing the stream
+            let parsed = std::str::from_utf8(&payload)
+                .map_err(|_| UmicpError::serialization("Frame is not valid UTF-8"))
+                .and_then(Envelope::deserialize);
+            let envelope = match parsed {
+                Ok(envelope) => envelope,
+                Err(error) => {
+                    self.shared.subscribers.notify(TransportEvent::error(Some(&conn_id), error));
+                    continue;
+                }
+            };
+
+            self.shared.subscribers.publish(&envelope, &conn_id);
+            let handler = self.shared.message_handler.read().unwrap().clone();
+            if let Some(handler) = handler {
+                if let Err(error) = handler(envelope, conn_id.clone()).await {
+                    self.shared.subscribers.notify(TransportEvent::error(Some(&conn_id), error));
+                }
+            }
+        }
+    }
+
+    async fn notify_connection(&self, connected: bool, conn_id: String) {
+        self.shared.subscribers.notify(match connected {
+            true => TransportEvent::Connected(conn_id.clone()),
+            false => TransportEvent::Disconnected(conn_id.clone()),
+        });
+        let handler = self.shared.connection_handler.read().unwrap().clone();
+        if let Some(handler) = handler {
+            handler(connected, conn_id).await;
+        }
+    }
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+async fn resolve(addr: &str) -> Result<SocketAddr> {
+    tokio::net::lookup_host(addr)
+        .await
+        .map_err(|e| UmicpError::connection(format!("Failed to resolve {}: {}", addr, e)))?
+        .next()
+        .ok_or_else(|| UmicpError::connection(format!("No address found for {}", addr)))
+}
+
+#[async_trait]
+impl Transport for QuicTransport {
+    /// Spawn [`run`](QuicTransport::run) in the background
+    async fn connect(&self) -> Result<()> {
+        let transport = self.clone();
+        tokio::spawn(async move { transport.run().await });
+        Ok(())
+    }
+
+    async fn send(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<()> {
+        match connection_id {
+            Some(connection_id) => self.send_to(envelope, connection_id).await,
+            None => self.send_to_server(envelope).await,
+        }
+    }
+
+    fn subscribe(&self) -> Subscription {
+        self.shared.subscribers.subscribe()
+    }
+
+    fn events(&self) -> EventStream {
+        self.shared.subscribers.events()
+    }
+
+    async fn stats(&self) -> TransportStats {
+        let mut stats = self.shared.stats.lock().unwrap().clone();
+        stats.uptime_seconds = self.shared.started.elapsed().as_secs();
+        stats
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.shared.shutdown.send_replace(true);
+        self.shared.peers.write().unwrap().clear();
+        self.shared.endpoint.close(VarInt::from_u32(0), b"shutdown");
+        self.shared.stats.lock().unwrap().active_connections = 0;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for QuicTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QuicTransport")
+            .field("local_addr", &self.shared.endpoint.local_addr().ok())
+            .field("mode", &self.shared.mode)
+            .field("connections", &self.shared.peers.read().unwrap().len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::OperationType;
+    use std::time::Duration;
+
+    fn make_envelope(from: &str, operation: OperationType) -> Envelope {
+        Envelope::builder()
+            .from(from)
+            .to("peer")
+            .operation(operation)
+            .message_id(&uuid::Uuid::new_v4().to_string())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_quic_both_stream_modes() {
+        let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("umicp-quic-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem").to_string_lossy().into_owned();
+        let key_path = dir.join("key.pem").to_string_lossy().into_owned();
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+
+        for mode in [QuicStreamMode::PerEnvelope, QuicStreamMode::Multiplexed] {
+            let server_config = TransportConfig {
+                tls_cert_path: Some(cert_path.clone()),
+                tls_key_path: Some(key_path.clone()),
+                ..Default::default()
+            };
+            let server = QuicTransport::new_server("127.0.0.1:0", &server_config, mode).await.unwrap();
+            let mut incoming = server.subscribe();
+            server.connect().await.unwrap();
+
+            let client_config = TransportConfig {
+                tls_ca_path: Some(cert_path.clone()),
+                ..Default::default()
+            };
+            let url = format!("quic://127.0.0.1:{}", server.local_addr().unwrap().port());
+            let client = QuicTransport::new_client(&url, &client_config, mode).await.unwrap();
+            let mut replies = client.subscribe();
+            client.connect().await.unwrap();
+
+            for _ in 0..3 {
+                client.send(make_envelope("client", OperationType::Data), None).await.unwrap();
+            }
+            let mut conn_id = String::new();
+            for _ in 0..3 {
+                let (envelope, id) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+                assert_eq!(envelope.from(), "client");
+                conn_id = id;
+            }
+
+            server.send(make_envelope("server", OperationType::Ack), Some(&conn_id)).await.unwrap();
+            let (reply, _) = tokio::time::timeout(Duration::from_secs(5), replies.recv()).await.unwrap().unwrap();
+            assert_eq!(reply.operation(), OperationType::Ack);
+            assert_eq!(server.stats().await.messages_received, 3);
+
+            client.shutdown().await.unwrap();
+            server.shutdown().await.unwrap();
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
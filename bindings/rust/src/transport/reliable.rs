@@ -0,0 +1,497 @@
+/*!
+# UMICP Reliable Delivery
+
+[`Transport`] wrapper adding acknowledged, retransmitted delivery on top of any transport.
+
+An envelope sent at least once is marked with the [`DELIVERY_CAPABILITY`] and kept until an
+[`Ack`](OperationType::Ack) whose correlation ID is its message ID comes back; when
+`ack_timeout_ms` passes first, the same envelope (same message ID) is sent again, up to
+`max_retries` more times, each retransmission reported as a [`TransportEvent::Retry`]. On the
+receiving side, a `ReliableTransport` acks every marked envelope before handing it to
+subscribers, so both ends of a link should be wrapped.
+
+Exactly-once delivery is at-least-once plus a [`DedupStore`] on the receiver: a retransmitted
+envelope is acked again, since the first ack may be the one that got lost, but only dispatched the
+first time its sender and message ID are seen.
+
+Acks that settle a pending send are consumed; every other envelope is passed through. An ack only
+settles a send to a given connection when it arrives on that connection.
+
+With the `crypto` feature, a receiver given a [`Signer`](crate::keystore::Signer) through
+[`set_receipt_signer`](ReliableTransport::set_receipt_signer) signs the acks of envelopes asking
+for a receipt (see `receipt`), and [`send_with_receipt`](ReliableTransport::send_with_receipt)
+sends an envelope asking for one and checks the signed ack against the receiver's public key.
+*/
+
+use super::{DedupStore, EventStream, MemoryDedupStore, Subscribers, Subscription, Transport, TransportEvent};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::{DeliveryConfig, DeliveryMode, OperationType, TransportStats};
+use async_trait::async_trait;
+use std::collections::HashMap;
+#[cfg(feature = "crypto")]
+use std::sync::RwLock;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Capability marking an envelope that expects an ack; the value names the delivery mode
+pub const DELIVERY_CAPABILITY: &str = "delivery";
+
+const AT_LEAST_ONCE: &str = "at-least-once";
+const EXACTLY_ONCE: &str = "exactly-once";
+
+struct Shared {
+    inner: Arc<dyn Transport>,
+    config: DeliveryConfig,
+    /// Sends waiting for their ack, keyed by message ID
+    pending: Mutex<HashMap<String, Waiting>>,
+    dedup: Arc<dyn DedupStore>,
+    /// Events of the wrapped transport; taken by `connect`
+    incoming: Mutex<Option<EventStream>>,
+    subscribers: Subscribers,
+    /// Key signing the acks of envelopes that ask for a receipt
+    #[cfg(feature = "crypto")]
+    receipt_signer: RwLock<Option<Arc<dyn crate::keystore::Signer>>>,
+}
+
+/// A send waiting for its ack
+struct Waiting {
+    /// Connection the envelope was sent to; acks arriving on any other are not for it
+    connection_id: Option<String>,
+    ack: oneshot::Sender<Envelope>,
+}
+
+/// Transport wrapper providing at-least-once delivery
+#[derive(Clone)]
+pub struct ReliableTransport {
+    shared: Arc<Shared>,
+}
+
+/// Forgets a pending send however its future ends, including cancellation
+struct PendingGuard<'a> {
+    shared: &'a Shared,
+    message_id: String,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.shared.pending.lock().unwrap().remove(&self.message_id);
+    }
+}
+
+impl ReliableTransport {
+    /// Wrap `inner`; envelopes arriving from now on are seen by the wrapper
+    ///
+    /// Duplicates are tracked in memory, over the last `dedup_window` envelopes.
+    pub fn new(inner: Arc<dyn Transport>, config: DeliveryConfig) -> Self {
+        let dedup = Arc::new(MemoryDedupStore::new(config.dedup_window));
+        Self::with_dedup_store(inner, config, dedup)
+    }
+
+    /// Wrap `inner`, tracking duplicates in `dedup`, e.g. a [`FileDedupStore`](super::FileDedupStore)
+    pub fn with_dedup_store(inner: Arc<dyn Transport>, config: DeliveryConfig, dedup: Arc<dyn DedupStore>) -> Self {
+        let incoming = inner.events();
+        ReliableTransport {
+            shared: Arc::new(Shared {
+                inner,
+                config,
+                pending: Mutex::new(HashMap::new()),
+                dedup,
+                incoming: Mutex::new(Some(incoming)),
+                subscribers: Subscribers::default(),
+                #[cfg(feature = "crypto")]
+                receipt_signer: RwLock::new(None),
+            }),
+        }
+    }
+
+    /// Send with the given delivery mode, ignoring the configured one
+    pub async fn send_with_mode(
+        &self,
+        envelope: Envelope,
+        connection_id: Option<&str>,
+        mode: DeliveryMode,
+    ) -> Result<()> {
+        match mode {
+            DeliveryMode::AtMostOnce => self.shared.inner.send(envelope, connection_id).await,
+            DeliveryMode::AtLeastOnce | DeliveryMode::ExactlyOnce => {
+                self.send_acked_with_mode(envelope, connection_id, mode).await.map(|_| ())
+            }
+        }
+    }
+
+    /// Send at least once, resolving with the peer's ack
+    ///
+    /// Fails with a timeout error once the retry budget is spent without an ack.
+    pub async fn send_acked(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<Envelope> {
+        self.send_acked_with_mode(envelope, connection_id, DeliveryMode::AtLeastOnce).await
+    }
+
+    /// Send exactly once, resolving with the peer's ack
+    ///
+    /// The envelope may still be retransmitted; the receiving `ReliableTransport` discards the
+    /// copies it has already dispatched.
+    pub async fn send_exactly_once(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<Envelope> {
+        self.send_acked_with_mode(envelope, connection_id, DeliveryMode::ExactlyOnce).await
+    }
+
+    async fn send_acked_with_mode(
+        &self,
+        mut envelope: Envelope,
+        connection_id: Option<&str>,
+        mode: DeliveryMode,
+    ) -> Result<Envelope> {
+        let marker = match mode {
+            DeliveryMode::ExactlyOnce => EXACTLY_ONCE,
+            _ => AT_LEAST_ONCE,
+        };
+        envelope.add_capability(DELIVERY_CAPABILITY, marker);
+        let message_id = envelope.message_id().to_string();
+
+        let (sender, mut ack) = oneshot::channel();
+        let waiting = Waiting {
+            connection_id: connection_id.map(str::to_string),
+            ack: sender,
+        };
+        self.shared.pending.lock().unwrap().insert(message_id.clone(), waiting);
+        let _guard = PendingGuard {
+            shared: &self.shared,
+            message_id: message_id.clone(),
+        };
+
+        let timeout = Duration::from_millis(self.shared.config.ack_timeout_ms);
+        let mut last_error = None;
+        let mut failed = false;
+        for attempt in 0..=self.shared.config.max_retries {
+            if attempt > 0 {
+                self.shared.subscribers.notify(TransportEvent::Retry {
+                    connection_id: connection_id.map(str::to_string),
+                    message_id: message_id.clone(),
+                    attempt,
+                    error: last_error.as_ref().filter(|_| failed).map(ToString::to_string),
+                });
+            }
+            // A failed send is one spent attempt: the link may be back by the next one
+            failed = match self.shared.inner.send(envelope.clone(), connection_id).await {
+                Ok(()) => false,
+                Err(error) => {
+                    last_error = Some(error);
+                    true
+                }
+            };
+            match tokio::time::timeout(timeout, &mut ack).await {
+                Ok(Ok(ack)) => return Ok(ack),
+                Ok(Err(_)) => return Err(UmicpError::transport("Reliable transport stopped before the ack arrived")),
+                Err(_) => continue,
+            }
+        }
+
+        let attempts = self.shared.config.max_retries + 1;
+        Err(match last_error {
+            Some(error) => UmicpError::timeout(format!(
+                "No ack for {} after {} attempts (last send failed: {})",
+                message_id, attempts, error
+            )),
+            None => UmicpError::timeout(format!("No ack for {} after {} attempts", message_id, attempts)),
+        })
+    }
+
+    /// Sign the acks of envelopes asking for a receipt with `signer`, e.g. a `KeyStore`
+    #[cfg(feature = "crypto")]
+    pub fn set_receipt_signer(&self, signer: Arc<dyn crate::keystore::Signer>) {
+        *self.shared.receipt_signer.write().unwrap() = Some(signer);
+    }
+
+    /// Send at least once, asking for a signed receipt; resolves with the receipt once its
+    /// signature checks out against `verifier`, for the sender to keep as proof of delivery
+    ///
+    /// Fails with an authentication error if the peer acks without a valid receipt.
+    #[cfg(feature = "crypto")]
+    pub async fn send_with_receipt(
+        &self,
+        mut envelope: Envelope,
+        connection_id: Option<&str>,
+        verifier: &crate::keystore::KeyStore,
+    ) -> Result<Envelope> {
+        crate::receipt::request_receipt(&mut envelope);
+        let receipt = self.send_acked(envelope.clone(), connection_id).await?;
+        crate::receipt::verify_receipt(verifier, &envelope, &receipt)?;
+        Ok(receipt)
+    }
+
+    /// Number of sends still waiting for an ack
+    pub fn pending(&self) -> usize {
+        self.shared.pending.lock().unwrap().len()
+    }
+
+    async fn receive_loop(&self, mut incoming: EventStream) {
+        while let Some(event) = incoming.recv().await {
+            let (envelope, conn_id) = match event {
+                TransportEvent::Message(envelope, conn_id) => (*envelope, conn_id),
+                other => {
+                    self.shared.subscribers.notify(other);
+                    continue;
+                }
+            };
+            if envelope.operation() == OperationType::Ack {
+                let waiting = envelope.correlation_id().and_then(|id| {
+                    let mut pending = self.shared.pending.lock().unwrap();
+                    let target = pending.get(id)?.connection_id.as_deref();
+                    // Message IDs come from the peers, so only the connection sent to may settle one
+                    match target.is_none_or(|target| target == conn_id) {
+                        true => pending.remove(id),
+                        false => None,
+                    }
+                });
+                if let Some(waiting) = waiting {
+                    let _ = waiting.ack.send(envelope);
+                    continue;
+                }
+            }
+
+            let delivery = envelope
+                .capabilities()
+                .and_then(|capabilities| capabilities.get(DELIVERY_CAPABILITY))
+                .cloned();
+            let first_copy = match delivery.as_deref() {
+                // Keyed by sender too, since message IDs are only unique per sender
+                Some(EXACTLY_ONCE) => {
+                    let key = format!("{}\u{1f}{}", envelope.from(), envelope.message_id());
+                    match self.shared.dedup.insert(&key) {
+                        Ok(first) => first,
+                        // Without a record the envelope cannot be acked safely; the sender retries
+                        Err(_) => continue,
+                    }
+                }
+                _ => true,
+            };
+            if delivery.is_some() {
+                // A lost ack only costs a retransmission, so failures are not fatal here
+                let _ = self.shared.inner.send(self.ack(&envelope), Some(&conn_id)).await;
+            }
+            if first_copy {
+                self.shared.subscribers.publish(&envelope, &conn_id);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for ReliableTransport {
+    /// Connect the wrapped transport and start processing acks
+    async fn connect(&self) -> Result<()> {
+        let incoming = self
+            .shared
+            .incoming
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| UmicpError::transport("Reliable transport is already connected"))?;
+        self.shared.inner.connect().await?;
+
+        let transport = self.clone();
+        tokio::spawn(async move { transport.receive_loop(incoming).await });
+        Ok(())
+    }
+
+    /// Send with the configured delivery mode
+    async fn send(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<()> {
+        self.send_with_mode(envelope, connection_id, self.shared.config.mode).await
+    }
+
+    fn subscribe(&self) -> Subscription {
+        self.shared.subscribers.subscribe()
+    }
+
+    fn events(&self) -> EventStream {
+        self.shared.subscribers.events()
+    }
+
+    async fn stats(&self) -> TransportStats {
+        self.shared.inner.stats().await
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.shared.inner.shutdown().await
+    }
+}
+
+impl ReliableTransport {
+    /// Ack for `envelope`: a signed receipt when it asks for one and there is a key to sign with
+    fn ack(&self, envelope: &Envelope) -> Envelope {
+        #[cfg(feature = "crypto")]
+        if crate::receipt::wants_receipt(envelope) {
+            let signer = self.shared.receipt_signer.read().unwrap().clone();
+            if let Some(Ok(receipt)) = signer.map(|signer| crate::receipt::sign_receipt(signer.as_ref(), envelope)) {
+                return receipt;
+            }
+        }
+        envelope.reply(OperationType::Ack)
+    }
+}
+
+impl std::fmt::Debug for ReliableTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReliableTransport")
+            .field("config", &self.shared.config)
+            .field("pending", &self.pending())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    fn make_envelope(operation: OperationType) -> Envelope {
+        Envelope::builder()
+            .from("peer")
+            .to("test")
+            .operation(operation)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reliable_retransmit_until_ack() {
+        let mock = MockTransport::new();
+        let reliable = ReliableTransport::new(
+            Arc::new(mock.clone()),
+            DeliveryConfig {
+                ack_timeout_ms: 100,
+                max_retries: 2,
+                ..Default::default()
+            },
+        );
+        let mut delivered = reliable.subscribe();
+        reliable.connect().await.unwrap();
+
+        // First attempt is lost; the retransmission is acked
+        let sender = reliable.clone();
+        let send = tokio::spawn(async move { sender.send_acked(make_envelope(OperationType::Data), None).await });
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let sent = mock.take_sent();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].envelope.message_id(), sent[1].envelope.message_id());
+
+        mock.inject(sent[1].envelope.reply(OperationType::Ack)).await.unwrap();
+        let ack = send.await.unwrap().unwrap();
+        assert_eq!(ack.correlation_id(), Some(sent[0].envelope.message_id()));
+        assert_eq!(reliable.pending(), 0);
+        // The settling ack is consumed, not delivered
+        assert!(delivered.try_recv().is_err());
+
+        // An ack from a connection the envelope was not sent to settles nothing
+        let sender = reliable.clone();
+        let envelope = make_envelope(OperationType::Data);
+        let send = tokio::spawn(async move { sender.send_acked(envelope, Some("other")).await });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let sent = mock.take_sent();
+        mock.inject(sent[0].envelope.reply(OperationType::Ack)).await.unwrap();
+        assert_eq!(reliable.pending(), 1);
+        assert_eq!(delivered.recv().await.unwrap().0.operation(), OperationType::Ack);
+        send.abort();
+        let _ = send.await;
+        mock.take_sent();
+
+        // No ack at all: one send plus two retries, then a timeout
+        let error = reliable.send(make_envelope(OperationType::Data), None).await.unwrap_err();
+        assert!(matches!(error, UmicpError::Timeout { .. }));
+        assert_eq!(mock.take_sent().len(), 3);
+
+        // Incoming marked envelopes are acked and delivered
+        let mut incoming = make_envelope(OperationType::Data);
+        incoming.add_capability(DELIVERY_CAPABILITY, AT_LEAST_ONCE);
+        mock.inject(incoming.clone()).await.unwrap();
+        let (received, _) = delivered.recv().await.unwrap();
+        assert_eq!(received.message_id(), incoming.message_id());
+        let acks = mock.take_sent();
+        assert_eq!(acks[0].envelope.operation(), OperationType::Ack);
+        assert_eq!(acks[0].envelope.correlation_id(), Some(incoming.message_id()));
+        assert_eq!(acks[0].connection_id.as_deref(), Some("mock-peer"));
+    }
+
+    #[cfg(feature = "crypto")]
+    #[tokio::test]
+    async fn test_reliable_signed_receipts() {
+        use crate::keystore::{KeyPurpose, KeyStore};
+
+        let (sender_link, receiver_link) = (MockTransport::new(), MockTransport::new());
+        let sender = ReliableTransport::new(Arc::new(sender_link.clone()), DeliveryConfig::default());
+        let receiver = ReliableTransport::new(Arc::new(receiver_link.clone()), DeliveryConfig::default());
+        let receiver_keys = Arc::new(KeyStore::new());
+        let key_id = receiver_keys.generate(KeyPurpose::Signing).unwrap();
+        receiver.set_receipt_signer(receiver_keys.clone());
+        let trusted = KeyStore::new();
+        trusted.import_public_key(&key_id, &receiver_keys.public_key(&key_id).unwrap()).unwrap();
+        sender.connect().await.unwrap();
+        receiver.connect().await.unwrap();
+
+        // Carry one envelope each way between the two mocks
+        let relay = |from: &MockTransport, to: &MockTransport| {
+            let (from, to) = (from.clone(), to.clone());
+            async move {
+                let sent = loop {
+                    match from.take_sent().pop() {
+                        Some(sent) => break sent,
+                        None => tokio::time::sleep(Duration::from_millis(5)).await,
+                    }
+                };
+                to.inject(sent.envelope).await.unwrap();
+            }
+        };
+
+        let sending = {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                sender.send_with_receipt(make_envelope(OperationType::Data), None, &trusted).await
+            })
+        };
+        relay(&sender_link, &receiver_link).await;
+        relay(&receiver_link, &sender_link).await;
+        let receipt = sending.await.unwrap().unwrap();
+        assert_eq!(receipt.capabilities().unwrap()[crate::keystore::SIGNATURE_KEY_CAPABILITY], key_id);
+
+        // A receiver that cannot sign acks plainly, which does not count as a receipt
+        let sending = {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                sender.send_with_receipt(make_envelope(OperationType::Data), None, &KeyStore::new()).await
+            })
+        };
+        relay(&sender_link, &receiver_link).await;
+        relay(&receiver_link, &sender_link).await;
+        let error = sending.await.unwrap().unwrap_err();
+        assert!(matches!(error, UmicpError::Authentication { .. }), "{}", error);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reliable_exactly_once_drops_redelivery() {
+        let mock = MockTransport::new();
+        let reliable = ReliableTransport::new(Arc::new(mock.clone()), DeliveryConfig::default());
+        let mut delivered = reliable.subscribe();
+        reliable.connect().await.unwrap();
+
+        // The sender never saw our first ack, so the envelope arrives twice
+        let mut envelope = make_envelope(OperationType::Request);
+        envelope.add_capability(DELIVERY_CAPABILITY, EXACTLY_ONCE);
+        mock.inject(envelope.clone()).await.unwrap();
+        mock.inject(envelope.clone()).await.unwrap();
+        let mut followup = make_envelope(OperationType::Data);
+        followup.add_capability(DELIVERY_CAPABILITY, EXACTLY_ONCE);
+        mock.inject(followup.clone()).await.unwrap();
+
+        assert_eq!(delivered.recv().await.unwrap().0.message_id(), envelope.message_id());
+        assert_eq!(delivered.recv().await.unwrap().0.message_id(), followup.message_id());
+        assert!(delivered.try_recv().is_err());
+
+        // Both copies were acked
+        let acks = mock.take_sent();
+        assert_eq!(acks.len(), 3);
+        assert!(acks[..2]
+            .iter()
+            .all(|ack| ack.envelope.correlation_id() == Some(envelope.message_id())));
+    }
+}
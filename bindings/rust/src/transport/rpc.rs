@@ -0,0 +1,230 @@
+/*!
+# UMICP Request/Response
+
+Request/response on top of any [`Transport`], using envelope correlation IDs.
+
+[`Transport::request`] sends an envelope and resolves with the first
+[`Response`](OperationType::Response) or [`Ack`](OperationType::Ack) whose correlation ID is the
+request's message ID; an [`Error`](OperationType::Error) reply becomes
+[`UmicpError::Remote`], carrying the reply's [`ERROR_CAPABILITY`].
+
+On the other side, [`serve_requests`] runs a handler for every incoming
+[`Request`](OperationType::Request) and sends back the envelope it returns. Handlers usually
+build it with [`Envelope::reply`]; a missing correlation ID is filled in, and a handler error is
+sent as an `Error` reply.
+*/
+
+use super::{CancellationToken, Subscription, Transport};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::OperationType;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Capability carrying the reason of an `Error` reply
+pub const ERROR_CAPABILITY: &str = "error";
+
+/// Capability carrying a machine-readable code on `Error` envelopes sent by the transport itself:
+/// `"forbidden"` for envelopes an [`Authorizer`](crate::auth::Authorizer) rejected, `"overloaded"`
+/// for envelopes or connections refused by a load limit (the equivalent of HTTP 429)
+pub const ERROR_CODE_CAPABILITY: &str = "error_code";
+
+/// `Error` reply to `envelope` carrying `error` as its reason and, if given, a code
+pub(crate) fn error_reply(envelope: &Envelope, error: &UmicpError, code: Option<&str>) -> Envelope {
+    let mut reply = envelope.reply(OperationType::Error);
+    reply.add_capability(ERROR_CAPABILITY, &error.to_string());
+    if let Some(code) = code {
+        reply.add_capability(ERROR_CODE_CAPABILITY, code);
+    }
+    reply
+}
+
+/// Send `envelope` and wait for the correlated reply
+pub(crate) async fn request<T: Transport + ?Sized>(
+    transport: &T,
+    envelope: Envelope,
+    connection_id: Option<&str>,
+    timeout: Duration,
+) -> Result<Envelope> {
+    let message_id = envelope.message_id().to_string();
+    // Subscribed before sending, so a fast reply cannot be missed
+    let incoming = transport.subscribe();
+    let exchange = async {
+        transport.send(envelope, connection_id).await?;
+        wait_for_reply(incoming, &message_id).await
+    };
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| UmicpError::timeout(format!("No response to {} within {:?}", message_id, timeout)))?
+}
+
+/// Wait on `incoming` for the reply correlated with `message_id`
+pub(crate) async fn wait_for_reply(mut incoming: Subscription, message_id: &str) -> Result<Envelope> {
+    while let Some((reply, _)) = incoming.recv().await {
+        if reply.correlation_id() != Some(message_id) {
+            continue;
+        }
+        match reply.operation() {
+            OperationType::Response | OperationType::Ack => return Ok(reply),
+            OperationType::Error => {
+                let reason = reply
+                    .capabilities()
+                    .and_then(|capabilities| capabilities.get(ERROR_CAPABILITY))
+                    .map_or("no reason given", String::as_str);
+                return Err(UmicpError::remote(format!("Request {} failed: {}", message_id, reason)));
+            }
+            _ => {}
+        }
+    }
+    Err(UmicpError::connection(format!("Transport stopped before {} was answered", message_id)))
+}
+
+/// Answer every incoming request with the envelope `handler` returns
+///
+/// Each request is handled on its own task, so a slow handler does not hold up the others. The
+/// returned task ends when the transport stops delivering envelopes; use
+/// [`serve_requests_until`] to stop serving earlier.
+pub fn serve_requests<F, Fut>(transport: Arc<dyn Transport>, handler: F) -> JoinHandle<()>
+where
+    F: Fn(Envelope, String) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Envelope>> + Send + 'static,
+{
+    serve_requests_until(transport, CancellationToken::new(), handler)
+}
+
+/// [`serve_requests`] until `cancel` is cancelled
+///
+/// Cancelling stops taking requests and drops the handlers still running along with their
+/// tasks; their requesters get no reply.
+pub fn serve_requests_until<F, Fut>(
+    transport: Arc<dyn Transport>,
+    cancel: CancellationToken,
+    handler: F,
+) -> JoinHandle<()>
+where
+    F: Fn(Envelope, String) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Envelope>> + Send + 'static,
+{
+    let handler = Arc::new(handler);
+    let mut incoming = transport.subscribe();
+    tokio::spawn(async move {
+        while let Some(Some((envelope, conn_id))) = cancel.run_until_cancelled(incoming.recv()).await {
+            if envelope.operation() != OperationType::Request {
+                continue;
+            }
+            let transport = transport.clone();
+            let handler = handler.clone();
+            let cancel = cancel.clone();
+            tokio::spawn(async move {
+                let handling = cancel.run_until_cancelled(handler(envelope.clone(), conn_id.clone()));
+                let Some(handled) = handling.await else {
+                    return;
+                };
+                let reply = match handled {
+                    Ok(mut response) => {
+                        if response.correlation_id().is_none() {
+                            response.set_correlation_id(envelope.message_id());
+                        }
+                        response
+                    }
+                    Err(error) => error_reply(&envelope, &error, None),
+                };
+                // The requester times out if the reply is lost
+                let _ = transport.send(reply, Some(&conn_id)).await;
+            });
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::LoopbackTransport;
+
+    fn make_request(payload: &str) -> Envelope {
+        Envelope::builder()
+            .from("client")
+            .to("server")
+            .operation(OperationType::Request)
+            .capability("query", payload)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_request_response_round_trip() {
+        let (server, client) = LoopbackTransport::pair();
+        let _serving = serve_requests(Arc::new(server.clone()), |request, _conn_id| async move {
+            let query = request.capabilities().and_then(|c| c.get("query")).cloned().unwrap_or_default();
+            match query.as_str() {
+                "fail" => Err(UmicpError::validation("bad query")),
+                "ignore" => std::future::pending().await,
+                _ => {
+                    let mut response = request.reply(OperationType::Response);
+                    response.add_capability("answer", &query.to_uppercase());
+                    Ok(response)
+                }
+            }
+        });
+        server.connect().await.unwrap();
+        client.connect().await.unwrap();
+
+        let request = make_request("ping");
+        let response = client.request(request.clone(), Duration::from_secs(5)).await.unwrap();
+        assert_eq!(response.correlation_id(), Some(request.message_id()));
+        assert_eq!(response.capabilities().unwrap().get("answer").map(String::as_str), Some("PING"));
+
+        let error = client.request(make_request("fail"), Duration::from_secs(5)).await.unwrap_err();
+        assert!(matches!(error, UmicpError::Remote { ref message } if message.contains("bad query")));
+
+        let error = client.request(make_request("ignore"), Duration::from_millis(50)).await.unwrap_err();
+        assert!(matches!(error, UmicpError::Timeout { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_requests_and_serving() {
+        // Reports when the handler holding it is dropped
+        struct Guard(Option<tokio::sync::oneshot::Sender<()>>);
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                let _ = self.0.take().unwrap().send(());
+            }
+        }
+
+        let (server, client) = LoopbackTransport::pair();
+        let (dropped, handler_dropped) = tokio::sync::oneshot::channel();
+        let dropped = std::sync::Mutex::new(Some(dropped));
+        let serving_token = CancellationToken::new();
+        let serving = serve_requests_until(Arc::new(server.clone()), serving_token.clone(), move |_, _| {
+            let guard = Guard(dropped.lock().unwrap().take());
+            async move {
+                let _guard = guard;
+                std::future::pending().await
+            }
+        });
+        server.connect().await.unwrap();
+        client.connect().await.unwrap();
+
+        let cancel = CancellationToken::new();
+        let request = {
+            let client = client.clone();
+            let cancel = cancel.clone();
+            tokio::spawn(async move {
+                client.request_cancellable(make_request("slow"), Duration::from_secs(30), &cancel).await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        cancel.cancel();
+        let error = tokio::time::timeout(Duration::from_secs(5), request).await.unwrap().unwrap().unwrap_err();
+        assert!(matches!(error, UmicpError::Cancelled { .. }), "{}", error);
+        let error = client.send_cancellable(make_request("late"), None, &cancel).await.unwrap_err();
+        assert!(matches!(error, UmicpError::Cancelled { .. }));
+
+        // The handler still waiting on the request is dropped along with the serving task
+        serving_token.cancel();
+        tokio::time::timeout(Duration::from_secs(5), handler_dropped).await.unwrap().unwrap();
+        tokio::time::timeout(Duration::from_secs(5), serving).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+/*!
+# UMICP Sequence Tracking
+
+Gap detection and reordering for envelopes sent with a [`FrameOptions::sequence`], kept per
+logical stream of a connection.
+
+The first sequence number seen on a stream sets where it starts. From then on, an envelope
+numbered past the next expected one reveals a gap, reported once as a [`Gap`] so the receiver
+can ask for the missing envelopes again. With a reorder window the envelopes after the gap are
+held back and released in order as soon as the gap fills; they are released anyway, skipping the
+gap, once more than `window` are held or the oldest has waited `timeout`. Without a window they
+are delivered as they arrive. Envelopes numbered below the next expected one (retransmissions
+of skipped envelopes, or duplicates) are always passed straight through.
+
+[`FrameOptions::sequence`]: crate::types::FrameOptions::sequence
+*/
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// Sequence numbers that did not arrive in order on a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Gap {
+    pub(crate) stream_id: u32,
+    /// First missing sequence number
+    pub(crate) expected: u64,
+    /// Sequence number that arrived instead; `expected..received` are missing
+    pub(crate) received: u64,
+}
+
+struct Stream<T> {
+    /// Next sequence number to deliver
+    next: u64,
+    /// Highest sequence number seen
+    highest: u64,
+    /// Items waiting for the gap before them to fill
+    held: BTreeMap<u64, T>,
+    /// When the current gap started holding items up
+    waiting_since: Option<Instant>,
+}
+
+impl<T> Stream<T> {
+    /// Move the held items that are next in line to `ready`
+    fn drain(&mut self, ready: &mut Vec<T>, now: Instant) {
+        while let Some(item) = self.held.remove(&self.next) {
+            ready.push(item);
+            self.next += 1;
+        }
+        self.waiting_since = match self.held.is_empty() {
+            true => None,
+            false => Some(now),
+        };
+    }
+
+    /// Give up on the current gap and deliver from the first held item on
+    fn skip(&mut self, ready: &mut Vec<T>, now: Instant) {
+        if let Some(&first) = self.held.keys().next() {
+            self.next = first;
+            self.drain(ready, now);
+        }
+    }
+}
+
+/// Per-stream sequence tracking for one connection
+pub(crate) struct Sequencer<T> {
+    window: usize,
+    timeout: Duration,
+    streams: HashMap<u32, Stream<T>>,
+}
+
+impl<T> Sequencer<T> {
+    /// Hold up to `window` out-of-order items per stream for at most `timeout` (zero: no limit)
+    pub(crate) fn new(window: usize, timeout: Duration) -> Self {
+        Sequencer {
+            window,
+            timeout,
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Accept `item`, numbered `sequence` on `stream_id`
+    ///
+    /// Returns the items now deliverable, in order, and the gap `item` revealed, if any.
+    pub(crate) fn push(&mut self, stream_id: u32, sequence: u64, item: T, now: Instant) -> (Vec<T>, Option<Gap>) {
+        let stream = self.streams.entry(stream_id).or_insert_with(|| Stream {
+            next: sequence,
+            highest: sequence,
+            held: BTreeMap::new(),
+            waiting_since: None,
+        });
+        if sequence < stream.next {
+            return (vec![item], None);
+        }
+
+        // Everything up to `highest` is delivered, held or already reported missing
+        let gap = (sequence > stream.highest.saturating_add(1)).then(|| Gap {
+            stream_id,
+            expected: stream.highest + 1,
+            received: sequence,
+        });
+        stream.highest = stream.highest.max(sequence);
+
+        let mut ready = Vec::new();
+        if sequence == stream.next {
+            ready.push(item);
+            stream.next += 1;
+            stream.drain(&mut ready, now);
+        } else if self.window == 0 {
+            ready.push(item);
+            stream.next = sequence + 1;
+        } else {
+            stream.held.insert(sequence, item);
+            stream.waiting_since.get_or_insert(now);
+            if stream.held.len() > self.window {
+                stream.skip(&mut ready, now);
+            }
+        }
+        (ready, gap)
+    }
+
+    /// Release what has waited longer than the timeout, skipping the gaps holding it up
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<T> {
+        let mut ready = Vec::new();
+        if self.timeout.is_zero() {
+            return ready;
+        }
+        for stream in self.streams.values_mut() {
+            if stream.waiting_since.is_some_and(|since| now.duration_since(since) >= self.timeout) {
+                stream.skip(&mut ready, now);
+            }
+        }
+        ready
+    }
+
+    /// When [`expire`](Self::expire) will next have something to release
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        if self.timeout.is_zero() {
+            return None;
+        }
+        self.streams
+            .values()
+            .filter_map(|stream| stream.waiting_since)
+            .min()
+            .map(|since| since + self.timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequencer_reorders_and_reports_gaps() {
+        let now = Instant::now();
+        let mut sequencer = Sequencer::new(4, Duration::from_millis(100));
+
+        assert_eq!(sequencer.push(1, 10, 'a', now), (vec!['a'], None));
+        // 11 and 12 missing: held and reported once
+        let gap = Gap {
+            stream_id: 1,
+            expected: 11,
+            received: 13,
+        };
+        assert_eq!(sequencer.push(1, 13, 'd', now), (vec![], Some(gap)));
+        assert_eq!(sequencer.push(1, 12, 'c', now), (vec![], None));
+        assert_eq!(sequencer.deadline(), Some(now + Duration::from_millis(100)));
+        assert_eq!(sequencer.push(1, 11, 'b', now), (vec!['b', 'c', 'd'], None));
+        assert_eq!(sequencer.deadline(), None);
+
+        // Streams are independent, and late envelopes pass straight through
+        assert_eq!(sequencer.push(2, 0, 'x', now), (vec!['x'], None));
+        assert_eq!(sequencer.push(1, 5, 'z', now), (vec!['z'], None));
+
+        // A gap that never fills is skipped after the timeout
+        let (ready, gap) = sequencer.push(1, 16, 'g', now);
+        assert!(ready.is_empty());
+        assert_eq!(gap.map(|gap| (gap.expected, gap.received)), Some((14, 16)));
+        assert!(sequencer.expire(now + Duration::from_millis(50)).is_empty());
+        assert_eq!(sequencer.expire(now + Duration::from_millis(100)), vec!['g']);
+        assert_eq!(sequencer.push(1, 17, 'h', now), (vec!['h'], None));
+    }
+
+    #[test]
+    fn test_sequencer_window_limits() {
+        let now = Instant::now();
+
+        // Past the window, the oldest gap is given up on
+        let mut sequencer = Sequencer::new(2, Duration::ZERO);
+        assert_eq!(sequencer.push(0, 0, 0, now).0, vec![0]);
+        assert!(sequencer.push(0, 2, 2, now).0.is_empty());
+        assert!(sequencer.push(0, 3, 3, now).0.is_empty());
+        assert_eq!(sequencer.push(0, 5, 5, now).0, vec![2, 3]);
+        assert_eq!(sequencer.push(0, 4, 4, now).0, vec![4, 5]);
+        assert_eq!(sequencer.deadline(), None);
+
+        // Without a window nothing is held, but gaps are still reported
+        let mut sequencer = Sequencer::new(0, Duration::ZERO);
+        assert_eq!(sequencer.push(0, 0, 0, now), (vec![0], None));
+        let (ready, gap) = sequencer.push(0, 3, 3, now);
+        assert_eq!(ready, vec![3]);
+        assert_eq!(gap.map(|gap| (gap.expected, gap.received)), Some((1, 3)));
+        assert_eq!(sequencer.push(0, 4, 4, now), (vec![4], None));
+    }
+}
@@ -0,0 +1,730 @@
+/*!
+# UMICP Server-Sent Events Transport
+
+HTTP-only server and client (requires the `sse` feature), for browsers and networks where
+WebSocket upgrades are blocked but plain HTTP gets through.
+
+Downstream, a client opens `GET /events` and keeps the response open as a `text/event-stream`.
+The server first sends a `session` event whose data is the session ID, which is also the
+connection ID on the server side, then one `envelope` event per envelope, with the envelope's
+JSON as its data. Comment lines go out every `heartbeat_interval` seconds so idle proxies keep
+the stream open.
+
+Upstream, each envelope is a `POST /messages` with the JSON envelope as its body and the session
+in the [`SSE_SESSION_HEADER`] header (or a `session` query parameter, for code that cannot set
+headers). The server answers `202 Accepted` once the envelope has been handled, `404` for a
+session that is not open and `413` for a body over `max_payload_size`. Clients keep one HTTP
+connection alive for their posts.
+
+Both endpoints send CORS headers and answer preflight requests, so browser code on any origin
+can use `EventSource` and `fetch` against them. A session ends when its event stream closes; a
+client that reconnects gets a new one. Only plain `http://` is spoken: put TLS in front of the
+server with a reverse proxy.
+*/
+
+use super::http::{self, Head, CORS_HEADERS};
+use super::{
+    accept_backoff, ConnectionHandler, EventStream, MessageHandler, Subscribers, Subscription, Transport,
+    TransportEvent,
+};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::{TransportConfig, TransportStats};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+
+/// Header carrying the session ID of a posted envelope
+pub const SSE_SESSION_HEADER: &str = http::SESSION_HEADER;
+
+const EVENTS_PATH: &str = "/events";
+const MESSAGES_PATH: &str = "/messages";
+
+/// Upper bound on the body of a response to a post, which only carries an error message
+const MAX_RESPONSE_BODY: usize = 64 * 1024;
+
+enum Role {
+    Server {
+        listener: Mutex<Option<TcpListener>>,
+        local_addr: SocketAddr,
+    },
+    /// Boxed: a client holds two connections' worth of state
+    Client(Box<Client>),
+}
+
+struct Client {
+    url: String,
+    base_path: String,
+    session: String,
+    events: Mutex<Option<BufReader<OwnedReadHalf>>>,
+    /// Write half of the event stream's connection; dropping it tells the server the client left
+    events_writer: Mutex<Option<OwnedWriteHalf>>,
+    /// Connection for posts
+    upstream: http::ClientConnection,
+}
+
+struct Shared {
+    role: Role,
+    max_payload_size: usize,
+    /// How often idle event streams get a comment line; zero disables them
+    keepalive_interval: Duration,
+    message_handler: RwLock<Option<MessageHandler>>,
+    connection_handler: RwLock<Option<ConnectionHandler>>,
+    subscribers: Subscribers,
+    /// Event queue of every open session (server mode)
+    sessions: RwLock<HashMap<String, mpsc::UnboundedSender<String>>>,
+    stats: Mutex<TransportStats>,
+    started: Instant,
+    shutdown: watch::Sender<bool>,
+}
+
+/// Server-Sent Events transport for UMICP envelopes
+#[derive(Clone)]
+pub struct SseTransport {
+    shared: Arc<Shared>,
+}
+
+impl SseTransport {
+    /// Bind an SSE server to `addr`; call [`run`](Self::run) to start accepting
+    pub async fn new_server(addr: &str, config: &TransportConfig) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| UmicpError::connection(format!("Failed to bind {}: {}", addr, e)))?;
+        let local_addr = listener.local_addr()?;
+        let role = Role::Server {
+            listener: Mutex::new(Some(listener)),
+            local_addr,
+        };
+        Ok(Self::with_role(role, config))
+    }
+
+    /// Open the event stream of the server at `url` (`http://host:port[/base]`)
+    pub async fn new_client(url: &str, config: &TransportConfig) -> Result<Self> {
+        let (authority, base_path) = http::parse_url(url)?;
+        let timeout = Duration::from_secs(config.connection_timeout);
+        let stream = http::connect(&authority, timeout).await?;
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        let start_line = format!("GET {}{} HTTP/1.1", base_path, EVENTS_PATH);
+        let headers = [
+            ("Host", authority.as_str()),
+            ("Accept", "text/event-stream"),
+            ("Cache-Control", "no-cache"),
+        ];
+        http::write_head(&mut writer, &start_line, &headers).await?;
+        let opened = async {
+            let head = http::read_head(&mut reader)
+                .await?
+                .ok_or_else(|| UmicpError::connection(format!("{} closed the connection", url)))?;
+            if head.status() != Some(200) {
+                return Err(UmicpError::connection(format!(
+                    "{} refused the event stream: {}",
+                    url, head.start_line
+                )));
+            }
+            match read_event(&mut reader, config.max_payload_size).await? {
+                Some((name, session)) if name == "session" => Ok(session),
+                _ => Err(UmicpError::connection(format!("{} opened no session", url))),
+            }
+        };
+        let session = tokio::time::timeout(timeout, opened)
+            .await
+            .map_err(|_| UmicpError::timeout(format!("Opening the event stream of {} timed out", url)))??;
+
+        let role = Role::Client(Box::new(Client {
+            url: url.to_string(),
+            base_path,
+            session,
+            events: Mutex::new(Some(reader)),
+            events_writer: Mutex::new(Some(writer)),
+            upstream: http::ClientConnection::new(&authority, timeout),
+        }));
+        let transport = Self::with_role(role, config);
+        {
+            let mut stats = transport.shared.stats.lock().unwrap();
+            stats.active_connections = 1;
+            stats.total_connections = 1;
+        }
+        Ok(transport)
+    }
+
+    fn with_role(role: Role, config: &TransportConfig) -> Self {
+        let (shutdown, _) = watch::channel(false);
+        SseTransport {
+            shared: Arc::new(Shared {
+                role,
+                max_payload_size: config.max_payload_size,
+                keepalive_interval: Duration::from_secs(config.heartbeat_interval),
+                message_handler: RwLock::new(None),
+                connection_handler: RwLock::new(None),
+                subscribers: Subscribers::default(),
+                sessions: RwLock::new(HashMap::new()),
+                stats: Mutex::new(TransportStats::default()),
+                started: Instant::now(),
+                shutdown,
+            }),
+        }
+    }
+
+    /// Set message handler for incoming messages
+    ///
+    /// On a server the handler runs before the post that carried the envelope is answered, so
+    /// each client's envelopes are handled in the order it sent them.
+    pub fn set_message_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(Envelope, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        *self.shared.message_handler.write().unwrap() = Some(super::message_handler(handler));
+    }
+
+    /// Set connection handler for session events (`true` when opened, `false` when closed)
+    pub fn set_connection_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(bool, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        *self.shared.connection_handler.write().unwrap() = Some(super::connection_handler(handler));
+    }
+
+    /// Address a server is bound to
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.shared.role {
+            Role::Server { local_addr, .. } => Some(*local_addr),
+            Role::Client(_) => None,
+        }
+    }
+
+    /// Session ID the server assigned to this client
+    pub fn session_id(&self) -> Option<&str> {
+        match &self.shared.role {
+            Role::Client(client) => Some(&client.session),
+            Role::Server { .. } => None,
+        }
+    }
+
+    /// Drive the transport until [`shutdown`](Transport::shutdown) is called
+    ///
+    /// A server accepts connections; a client reads its event stream until either side closes it.
+    pub async fn run(&self) -> Result<()> {
+        let mut shutdown = self.shared.shutdown.subscribe();
+        match &self.shared.role {
+            Role::Server { listener, .. } => {
+                let listener = listener
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .ok_or_else(|| UmicpError::transport("Server is already running"))?;
+                loop {
+                    let accepted = tokio::select! {
+                        accepted = listener.accept() => accepted,
+                        _ = shutdown.wait_for(|stopped| *stopped) => return Ok(()),
+                    };
+                    match accepted {
+                        Ok((stream, _)) => {
+                            let transport = self.clone();
+                            tokio::spawn(async move { transport.serve_connection(stream).await });
+                        }
+                        Err(error) => {
+                            // The listener is still open; failing here would stop the whole server
+                            let backoff = accept_backoff(&error);
+                            self.shared.subscribers.notify(TransportEvent::error(None, error));
+                            tokio::select! {
+                                _ = tokio::time::sleep(backoff) => {}
+                                _ = shutdown.wait_for(|stopped| *stopped) => return Ok(()),
+                            }
+                        }
+                    }
+                }
+            }
+            Role::Client(client) => {
+                let url = &client.url;
+                let mut reader = client
+                    .events
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .ok_or_else(|| UmicpError::transport("Client is already running"))?;
+                self.notify_connection(true, url.clone()).await;
+                loop {
+                    let event = tokio::select! {
+                        event = read_event(&mut reader, self.shared.max_payload_size) => event,
+                        _ = shutdown.wait_for(|stopped| *stopped) => break,
+                    };
+                    match event {
+                        Ok(Some((name, data))) if name == "envelope" => {
+                            self.dispatch(data.as_bytes(), url).await;
+                        }
+                        Ok(Some(_)) => {}
+                        Ok(None) => break,
+                        Err(error) => {
+                            self.shared.subscribers.notify(TransportEvent::error(Some(url), error));
+                            break;
+                        }
+                    }
+                }
+                self.shared.stats.lock().unwrap().active_connections = 0;
+                self.notify_connection(false, url.clone()).await;
+                Ok(())
+            }
+        }
+    }
+
+    /// Send an envelope down a session's event stream (server mode)
+    pub async fn send_to(&self, envelope: Envelope, connection_id: &str) -> Result<()> {
+        let event = format_event("envelope", &envelope.serialize()?);
+        let session = self
+            .shared
+            .sessions
+            .read()
+            .unwrap()
+            .get(connection_id)
+            .cloned()
+            .ok_or_else(|| UmicpError::connection(format!("Unknown connection: {}", connection_id)))?;
+        let bytes = event.len() as u64;
+        session
+            .send(event)
+            .map_err(|_| UmicpError::connection(format!("Connection closed: {}", connection_id)))?;
+
+        let mut stats = self.shared.stats.lock().unwrap();
+        stats.messages_sent += 1;
+        stats.bytes_sent += bytes;
+        Ok(())
+    }
+
+    /// Post an envelope to the server (client mode)
+    pub async fn send_to_server(&self, envelope: Envelope) -> Result<()> {
+        let Role::Client(client) = &self.shared.role else {
+            return Err(UmicpError::transport("send_to_server is only available in client mode"));
+        };
+        let Client {
+            url,
+            base_path,
+            session,
+            upstream,
+            ..
+        } = client.as_ref();
+        let body = envelope.serialize()?;
+        if body.len() > self.shared.max_payload_size {
+            return Err(UmicpError::validation(format!(
+                "Envelope of {} bytes exceeds max payload size {}",
+                body.len(),
+                self.shared.max_payload_size
+            )));
+        }
+
+        let start_line = format!("POST {}{} HTTP/1.1", base_path, MESSAGES_PATH);
+        let headers = [("Content-Type", "application/json"), (SSE_SESSION_HEADER, session.as_str())];
+        let (head, response) = upstream.request(&start_line, &headers, body.as_bytes(), MAX_RESPONSE_BODY).await?;
+        let reason = String::from_utf8_lossy(&response);
+        match head.status() {
+            Some(200..=299) => {
+                let mut stats = self.shared.stats.lock().unwrap();
+                stats.messages_sent += 1;
+                stats.bytes_sent += body.len() as u64;
+                Ok(())
+            }
+            Some(404) => Err(UmicpError::connection(format!("Session {} is not open on {}", session, url))),
+            Some(413) => Err(UmicpError::validation(reason.into_owned())),
+            _ => Err(UmicpError::transport(format!("{} answered {}: {}", url, head.start_line, reason))),
+        }
+    }
+
+    /// Serve the requests of one HTTP connection
+    async fn serve_connection(&self, stream: TcpStream) {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut shutdown = self.shared.shutdown.subscribe();
+        loop {
+            let head = tokio::select! {
+                head = http::read_head(&mut reader) => match head {
+                    Ok(Some(head)) => head,
+                    _ => return,
+                },
+                _ = shutdown.wait_for(|stopped| *stopped) => return,
+            };
+            let (method, _) = head.request();
+            // Whether the request's body, if any, was read, so the next request can follow
+            let mut consumed = head.header("content-length").unwrap_or("0") == "0";
+            let (status, message) = match (method, head.path()) {
+                ("OPTIONS", _) => (204, String::new()),
+                ("GET", EVENTS_PATH) => {
+                    self.stream_events(reader, writer).await;
+                    return;
+                }
+                ("POST", MESSAGES_PATH) => {
+                    match http::read_body(&mut reader, &head, self.shared.max_payload_size).await {
+                        Ok(body) => {
+                            consumed = true;
+                            self.receive_post(&head, &body).await
+                        }
+                        Err(error) => (413, error.to_string()),
+                    }
+                }
+                (_, EVENTS_PATH | MESSAGES_PATH) => (405, "Method not allowed".to_string()),
+                _ => (404, "Not found".to_string()),
+            };
+
+            let keep_alive = head.keep_alive() && consumed;
+            let mut headers = CORS_HEADERS.to_vec();
+            headers.push(("Content-Type", "text/plain; charset=utf-8"));
+            if !keep_alive {
+                headers.push(("Connection", "close"));
+            }
+            let status_line = http::status_line(status);
+            let written = http::write_message(&mut writer, &status_line, &headers, message.as_bytes()).await;
+            if written.is_err() || !keep_alive {
+                return;
+            }
+        }
+    }
+
+    /// Handle a posted envelope, answering with a status and a message
+    async fn receive_post(&self, head: &Head, body: &[u8]) -> (u16, String) {
+        let session = head.header(SSE_SESSION_HEADER).or_else(|| head.query("session")).unwrap_or_default();
+        if !self.shared.sessions.read().unwrap().contains_key(session) {
+            return (404, format!("Unknown session: {}", session));
+        }
+        let session = session.to_string();
+        {
+            let mut stats = self.shared.stats.lock().unwrap();
+            stats.messages_received += 1;
+            stats.bytes_received += body.len() as u64;
+        }
+        match self.dispatch(body, &session).await {
+            true => (202, String::new()),
+            false => (400, "Body is not a valid envelope".to_string()),
+        }
+    }
+
+    /// Decode an envelope and hand it to subscribers and the handler; `false` if it is invalid
+    async fn dispatch(&self, bytes: &[u8], conn_id: &str) -> bool {
+        let parsed = std::str::from_utf8(bytes)
+            .map_err(|_| UmicpError::serialization("Envelope is not valid UTF-8"))
+            .and_then(Envelope::deserialize);
+        let envelope = match parsed {
+            Ok(envelope) => envelope,
+            Err(error) => {
+                self.shared.subscribers.notify(TransportEvent::error(Some(conn_id), error));
+                return false;
+            }
+        };
+        if let Role::Client(_) = self.shared.role {
+            let mut stats = self.shared.stats.lock().unwrap();
+            stats.messages_received += 1;
+            stats.bytes_received += bytes.len() as u64;
+        }
+
+        self.shared.subscribers.publish(&envelope, conn_id);
+        let handler = self.shared.message_handler.read().unwrap().clone();
+        if let Some(handler) = handler {
+            if let Err(error) = handler(envelope, conn_id.to_string()).await {
+                self.shared.subscribers.notify(TransportEvent::error(Some(conn_id), error));
+            }
+        }
+        true
+    }
+
+    /// Turn a connection into a session's event stream until either side closes it
+    async fn stream_events(&self, mut reader: BufReader<OwnedReadHalf>, mut writer: OwnedWriteHalf) {
+        let session = uuid::Uuid::new_v4().to_string();
+        let mut headers = CORS_HEADERS.to_vec();
+        headers.extend([
+            ("Content-Type", "text/event-stream"),
+            ("Cache-Control", "no-cache"),
+            // Keeps nginx and similar proxies from buffering the stream
+            ("X-Accel-Buffering", "no"),
+        ]);
+        let opened = async {
+            http::write_head(&mut writer, &http::status_line(200), &headers).await?;
+            writer.write_all(format_event("session", &session).as_bytes()).await?;
+            writer.flush().await?;
+            Ok::<_, UmicpError>(())
+        };
+        if opened.await.is_err() {
+            return;
+        }
+
+        let (sender, mut queue) = mpsc::unbounded_channel::<String>();
+        self.shared.sessions.write().unwrap().insert(session.clone(), sender);
+        {
+            let mut stats = self.shared.stats.lock().unwrap();
+            stats.active_connections += 1;
+            stats.total_connections += 1;
+        }
+        self.notify_connection(true, session.clone()).await;
+
+        let mut shutdown = self.shared.shutdown.subscribe();
+        let mut keepalive = (!self.shared.keepalive_interval.is_zero()).then(|| {
+            let interval = self.shared.keepalive_interval;
+            tokio::time::interval_at(tokio::time::Instant::now() + interval, interval)
+        });
+        let mut discard = [0u8; 512];
+        loop {
+            let event = tokio::select! {
+                event = queue.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = async {
+                    match keepalive.as_mut() {
+                        Some(keepalive) => keepalive.tick().await,
+                        None => std::future::pending().await,
+                    }
+                } => ": keepalive\n\n".to_string(),
+                // The client sends nothing more; end of input means it left
+                read = reader.read(&mut discard) => match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => continue,
+                },
+                _ = shutdown.wait_for(|stopped| *stopped) => break,
+            };
+            if writer.write_all(event.as_bytes()).await.is_err() || writer.flush().await.is_err() {
+                break;
+            }
+        }
+
+        if self.shared.sessions.write().unwrap().remove(&session).is_some() {
+            let mut stats = self.shared.stats.lock().unwrap();
+            stats.active_connections = stats.active_connections.saturating_sub(1);
+        }
+        self.notify_connection(false, session).await;
+    }
+
+    async fn notify_connection(&self, connected: bool, conn_id: String) {
+        self.shared.subscribers.notify(match connected {
+            true => TransportEvent::Connected(conn_id.clone()),
+            false => TransportEvent::Disconnected(conn_id.clone()),
+        });
+        let handler = self.shared.connection_handler.read().unwrap().clone();
+        if let Some(handler) = handler {
+            handler(connected, conn_id).await;
+        }
+    }
+}
+
+/// One event in `text/event-stream` form; multi-line data becomes several `data` fields
+fn format_event(name: &str, data: &str) -> String {
+    let mut event = format!("event: {}\n", name);
+    for line in data.split('\n') {
+        event.push_str("data: ");
+        event.push_str(line);
+        event.push('\n');
+    }
+    event.push('\n');
+    event
+}
+
+/// Read the next event's name and data, skipping comments; `Ok(None)` once the stream ends
+///
+/// Lines longer than `max_size` are refused rather than buffered.
+async fn read_event<R>(reader: &mut R, max_size: usize) -> Result<Option<(String, String)>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut name = String::new();
+    let mut data: Option<String> = None;
+    loop {
+        let mut line = String::new();
+        // Room for the field name on top of the data
+        let limit = max_size as u64 + 64;
+        if (&mut *reader).take(limit).read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        if !line.ends_with('\n') {
+            return Err(UmicpError::validation("Event stream line exceeds max payload size"));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            match data.take() {
+                Some(data) => {
+                    let name = if name.is_empty() { "message".to_string() } else { name };
+                    return Ok(Some((name, data)));
+                }
+                None => {
+                    name.clear();
+                    continue;
+                }
+            }
+        }
+        if line.starts_with(':') {
+            continue;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "event" => name = value.to_string(),
+            "data" => match &mut data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => data = Some(value.to_string()),
+            },
+            // `id` and `retry` only matter to browsers reconnecting on their own
+            _ => {}
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for SseTransport {
+    /// Spawn [`run`](SseTransport::run) in the background
+    async fn connect(&self) -> Result<()> {
+        let transport = self.clone();
+        tokio::spawn(async move { transport.run().await });
+        Ok(())
+    }
+
+    async fn send(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<()> {
+        match (&self.shared.role, connection_id) {
+            (Role::Server { .. }, Some(connection_id)) => self.send_to(envelope, connection_id).await,
+            (Role::Server { .. }, None) => Err(UmicpError::transport("A connection ID is required in server mode")),
+            (Role::Client(_), _) => self.send_to_server(envelope).await,
+        }
+    }
+
+    fn subscribe(&self) -> Subscription {
+        self.shared.subscribers.subscribe()
+    }
+
+    fn events(&self) -> EventStream {
+        self.shared.subscribers.events()
+    }
+
+    async fn stats(&self) -> TransportStats {
+        let mut stats = self.shared.stats.lock().unwrap().clone();
+        stats.uptime_seconds = self.shared.started.elapsed().as_secs();
+        stats
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        self.shared.shutdown.send_replace(true);
+        // Dropping the queues ends every event stream
+        self.shared.sessions.write().unwrap().clear();
+        if let Role::Client(client) = &self.shared.role {
+            client.events_writer.lock().unwrap().take();
+            client.upstream.close().await;
+        }
+        self.shared.stats.lock().unwrap().active_connections = 0;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for SseTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SseTransport")
+            .field("local_addr", &self.local_addr())
+            .field("session", &self.session_id())
+            .field("sessions", &self.shared.sessions.read().unwrap().len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::OperationType;
+
+    fn make_envelope(from: &str, operation: OperationType) -> Envelope {
+        Envelope::builder()
+            .from(from)
+            .to("peer")
+            .operation(operation)
+            .message_id(&uuid::Uuid::new_v4().to_string())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sse_round_trip() {
+        let config = TransportConfig {
+            max_payload_size: 4096,
+            ..Default::default()
+        };
+        let server = SseTransport::new_server("127.0.0.1:0", &config).await.unwrap();
+        let mut incoming = server.subscribe();
+        let mut events = server.events();
+        server.connect().await.unwrap();
+        let url = format!("http://{}", server.local_addr().unwrap());
+
+        let client = SseTransport::new_client(&url, &TransportConfig::default()).await.unwrap();
+        let session = client.session_id().unwrap().to_string();
+        let mut replies = client.subscribe();
+        client.connect().await.unwrap();
+
+        for _ in 0..3 {
+            client.send(make_envelope("client", OperationType::Data), None).await.unwrap();
+        }
+        for _ in 0..3 {
+            let received = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap();
+            let (envelope, conn_id) = received.unwrap();
+            assert_eq!(envelope.from(), "client");
+            assert_eq!(conn_id, session);
+        }
+
+        // Replies come down the event stream, multi-line data included
+        let mut reply = make_envelope("server", OperationType::Ack);
+        reply.add_capability("note", "line one\nline two");
+        server.send(reply, Some(&session)).await.unwrap();
+        let (reply, _) = tokio::time::timeout(Duration::from_secs(5), replies.recv()).await.unwrap().unwrap();
+        assert_eq!(reply.operation(), OperationType::Ack);
+        assert_eq!(reply.capabilities().unwrap()["note"], "line one\nline two");
+        assert_eq!(server.stats().await.messages_received, 3);
+        assert_eq!(client.stats().await.messages_received, 1);
+
+        // Bodies over the server's limit are refused
+        let mut oversized = make_envelope("client", OperationType::Data);
+        oversized.set_payload(vec![0; 8192]);
+        assert!(matches!(client.send_to_server(oversized).await, Err(UmicpError::Validation { .. })));
+        client.send_to_server(make_envelope("client", OperationType::Data)).await.unwrap();
+
+        client.shutdown().await.unwrap();
+        let disconnected = async {
+            while let Some(event) = events.recv().await {
+                if let TransportEvent::Disconnected(id) = event {
+                    return id;
+                }
+            }
+            unreachable!()
+        };
+        assert_eq!(tokio::time::timeout(Duration::from_secs(5), disconnected).await.unwrap(), session);
+        assert!(server.send(make_envelope("server", OperationType::Ack), Some(&session)).await.is_err());
+        server.shutdown().await.unwrap();
+    }
+
+    /// One request and its response on a kept-alive connection
+    async fn exchange(connection: &mut BufReader<TcpStream>, start_line: &str, body: &[u8]) -> Head {
+        http::write_message(connection.get_mut(), start_line, &[], body).await.unwrap();
+        let head = http::read_head(connection).await.unwrap().unwrap();
+        http::read_body(connection, &head, 1024).await.unwrap();
+        head
+    }
+
+    #[tokio::test]
+    async fn test_sse_http_endpoints() {
+        let server = SseTransport::new_server("127.0.0.1:0", &TransportConfig::default()).await.unwrap();
+        server.connect().await.unwrap();
+        let mut connection = BufReader::new(TcpStream::connect(server.local_addr().unwrap()).await.unwrap());
+
+        // Preflight, unknown paths and sessions, all on one kept-alive connection
+        let head = exchange(&mut connection, "OPTIONS /messages HTTP/1.1", b"").await;
+        assert_eq!(head.status(), Some(204));
+        assert_eq!(head.header("access-control-allow-origin"), Some("*"));
+        assert_eq!(exchange(&mut connection, "GET /elsewhere HTTP/1.1", b"").await.status(), Some(404));
+        assert_eq!(exchange(&mut connection, "GET /messages HTTP/1.1", b"").await.status(), Some(405));
+        let head = exchange(&mut connection, "POST /messages?session=nope HTTP/1.1", b"{}").await;
+        assert_eq!(head.status(), Some(404));
+        server.shutdown().await.unwrap();
+    }
+}
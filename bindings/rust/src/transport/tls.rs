@@ -0,0 +1,414 @@
+/*!
+# UMICP TLS Configuration
+
+rustls setup shared by the WebSocket (`tls` feature) and QUIC (`quic` feature) transports,
+driven by the TLS fields of [`TransportConfig`].
+
+A WebSocket server keeps its certificate in a [`ReloadableAcceptor`], so a renewed certificate
+and key can be loaded from the same files while the server runs. Only handshakes after the
+reload use them; connections already open are not affected.
+
+A server can also serve several names from one listener: each of `tls_virtual_hosts` gives the
+certificate presented to clients that ask for its name through SNI, with `tls_cert_path` and
+`tls_key_path` as the fallback for clients asking for another name or none. Without a fallback
+such clients fail the handshake. The name a connection was accepted under is kept in its
+[`ConnectionInfo`](crate::types::ConnectionInfo), so the server can route it accordingly.
+
+With `tls_pins` set, the peer's certificate must also match one of them, by the SHA-256 of the
+whole certificate or of its public key. A client checks the server's certificate after the usual
+chain verification, or instead of it when `tls_accept_invalid_certs` is set; a server asks
+clients for a certificate and takes only pinned ones. Either way a mismatch fails the handshake
+with [`ApplicationVerificationFailure`](CertificateError::ApplicationVerificationFailure), which
+the peer sees as an `access_denied` alert, so both ends can tell it from other TLS failures.
+*/
+
+use crate::error::{Result, UmicpError};
+use crate::types::{TlsPin, TlsVirtualHost, TransportConfig};
+use crate::utils::constant_time_eq;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, DistinguishedName, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+#[cfg(feature = "tls")]
+use std::sync::{Mutex, RwLock};
+#[cfg(feature = "tls")]
+use std::time::SystemTime;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<std::result::Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(UmicpError::configuration(format!("No certificates found in {}", path)));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| UmicpError::configuration(format!("No private key found in {}", path)))
+}
+
+/// Certificate and key files a TLS server presents
+#[derive(Debug, Clone)]
+struct ServerCertificates {
+    cert_path: Option<String>,
+    key_path: Option<String>,
+    virtual_hosts: Vec<TlsVirtualHost>,
+}
+
+impl ServerCertificates {
+    fn new(config: &TransportConfig) -> Self {
+        ServerCertificates {
+            cert_path: config.tls_cert_path.clone(),
+            key_path: config.tls_key_path.clone(),
+            virtual_hosts: config.tls_virtual_hosts.clone(),
+        }
+    }
+
+    /// Every file, for watching them for changes
+    #[cfg(feature = "tls")]
+    fn paths(&self) -> Vec<&str> {
+        let hosts = self.virtual_hosts.iter();
+        let hosts = hosts.flat_map(|host| [host.cert_path.as_str(), host.key_path.as_str()]);
+        let default = [self.cert_path.as_deref(), self.key_path.as_deref()].into_iter().flatten();
+        default.chain(hosts).collect()
+    }
+
+    /// Certificates by server name, and the fallback, which is optional when there are virtual hosts
+    fn load(&self) -> Result<VirtualHosts> {
+        let default = match (&self.cert_path, &self.key_path) {
+            (Some(cert_path), Some(key_path)) => Some(Arc::new(certified_key(cert_path, key_path)?)),
+            (None, None) if !self.virtual_hosts.is_empty() => None,
+            (None, _) => return Err(UmicpError::configuration("TLS server requires tls_cert_path")),
+            (Some(_), None) => return Err(UmicpError::configuration("TLS server requires tls_key_path")),
+        };
+        let mut hosts = HashMap::new();
+        for host in &self.virtual_hosts {
+            let key = certified_key(&host.cert_path, &host.key_path)?;
+            hosts.insert(host.server_name.to_ascii_lowercase(), Arc::new(key));
+        }
+        Ok(VirtualHosts { hosts, default })
+    }
+}
+
+fn certified_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey> {
+    CertifiedKey::from_der(load_certs(cert_path)?, load_key(key_path)?, &provider())
+        .map_err(|e| UmicpError::configuration(format!("Invalid TLS certificate or key in {}: {}", cert_path, e)))
+}
+
+/// Picks the certificate for the server name a client asked for
+#[derive(Debug)]
+struct VirtualHosts {
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for VirtualHosts {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let host = client_hello.server_name().map(str::to_ascii_lowercase);
+        host.and_then(|host| self.hosts.get(&host)).or(self.default.as_ref()).cloned()
+    }
+}
+
+/// Build a server configuration from `tls_cert_path`, `tls_key_path` and `tls_virtual_hosts`
+pub(crate) fn server_config(config: &TransportConfig) -> Result<ServerConfig> {
+    load_server_config(&ServerCertificates::new(config), &config.tls_pins)
+}
+
+fn load_server_config(certificates: &ServerCertificates, pins: &[TlsPin]) -> Result<ServerConfig> {
+    let resolver = certificates.load()?;
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| UmicpError::configuration(format!("Invalid TLS configuration: {}", e)))?;
+    let builder = match pins.is_empty() {
+        true => builder.with_no_client_auth(),
+        false => builder.with_client_cert_verifier(Arc::new(PinnedClientCertificate {
+            pins: pins.to_vec(),
+            provider: provider(),
+        })),
+    };
+    Ok(builder.with_cert_resolver(Arc::new(resolver)))
+}
+
+/// Server TLS acceptor whose certificate and key can be loaded again from their files
+#[cfg(feature = "tls")]
+pub(crate) struct ReloadableAcceptor {
+    certificates: ServerCertificates,
+    pins: Vec<TlsPin>,
+    acceptor: RwLock<tokio_rustls::TlsAcceptor>,
+    /// Modification times of the certificate and key files at the last load attempt
+    modified: Mutex<Option<Vec<SystemTime>>>,
+}
+
+#[cfg(feature = "tls")]
+impl ReloadableAcceptor {
+    /// Load the certificates and keys named by `config`
+    pub(crate) fn new(config: &TransportConfig) -> Result<Self> {
+        let certificates = ServerCertificates::new(config);
+        let modified = Self::modified(&certificates);
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config(config)?));
+        Ok(ReloadableAcceptor {
+            certificates,
+            pins: config.tls_pins.clone(),
+            acceptor: RwLock::new(acceptor),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Acceptor for the next handshake
+    pub(crate) fn acceptor(&self) -> tokio_rustls::TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    /// Load the certificates and keys again; on error the current ones stay in use
+    pub(crate) fn reload(&self) -> Result<()> {
+        *self.modified.lock().unwrap() = Self::modified(&self.certificates);
+        let config = load_server_config(&self.certificates, &self.pins)?;
+        *self.acceptor.write().unwrap() = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        Ok(())
+    }
+
+    /// Reload if any file changed since the last attempt; `Ok(true)` when reloaded
+    ///
+    /// A failed attempt is not repeated until the files change again, so a certificate written
+    /// before its key is picked up once the key follows.
+    pub(crate) fn reload_if_changed(&self) -> Result<bool> {
+        let modified = Self::modified(&self.certificates);
+        if modified.is_none() || modified == *self.modified.lock().unwrap() {
+            return Ok(false);
+        }
+        self.reload().map(|_| true)
+    }
+
+    /// Modification times of every file, or `None` if one is missing
+    fn modified(certificates: &ServerCertificates) -> Option<Vec<SystemTime>> {
+        let modified = |path: &str| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        certificates.paths().into_iter().map(modified).collect()
+    }
+}
+
+/// Build a client configuration
+///
+/// Server certificates are checked against `tls_ca_path` when set, otherwise against the
+/// bundled webpki roots. `tls_accept_invalid_certs` skips verification entirely, apart from
+/// `tls_pins`. `tls_cert_path` and `tls_key_path`, when set, are presented as a client certificate.
+pub(crate) fn client_config(config: &TransportConfig) -> Result<ClientConfig> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| UmicpError::configuration(format!("Invalid TLS configuration: {}", e)))?;
+
+    let verifier: Arc<dyn ServerCertVerifier> = if config.tls_accept_invalid_certs {
+        Arc::new(AcceptAnyCertificate(provider()))
+    } else {
+        let mut roots = RootCertStore::empty();
+        match &config.tls_ca_path {
+            Some(path) => {
+                for cert in load_certs(path)? {
+                    roots
+                        .add(cert)
+                        .map_err(|e| UmicpError::configuration(format!("Invalid CA certificate in {}: {}", path, e)))?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider())
+            .build()
+            .map_err(|e| UmicpError::configuration(format!("Invalid TLS configuration: {}", e)))?
+    };
+    let verifier: Arc<dyn ServerCertVerifier> = match config.tls_pins.is_empty() {
+        true => verifier,
+        false => Arc::new(PinnedServerCertificate {
+            pins: config.tls_pins.clone(),
+            inner: verifier,
+        }),
+    };
+    let builder = builder.dangerous().with_custom_certificate_verifier(verifier);
+
+    match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => builder
+            .with_client_auth_cert(load_certs(cert_path)?, load_key(key_path)?)
+            .map_err(|e| UmicpError::configuration(format!("Invalid TLS client certificate or key: {}", e))),
+        _ => Ok(builder.with_no_client_auth()),
+    }
+}
+
+/// Server name for SNI and certificate verification
+#[cfg(feature = "tls")]
+pub(crate) fn server_name(host: &str) -> Result<ServerName<'static>> {
+    ServerName::try_from(host.to_string())
+        .map_err(|e| UmicpError::configuration(format!("Invalid TLS server name {}: {}", host, e)))
+}
+
+/// Whether a handshake failed on a pin: one of ours, or the peer's as reported by its alert
+#[cfg(feature = "tls")]
+pub(crate) fn is_pin_mismatch(error: &std::io::Error) -> bool {
+    matches!(
+        error.get_ref().and_then(|error| error.downcast_ref::<rustls::Error>()),
+        Some(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+            | Some(rustls::Error::AlertReceived(rustls::AlertDescription::AccessDenied))
+    )
+}
+
+/// Check `cert` against `pins`, by its own fingerprint or that of its public key
+fn check_pins(pins: &[TlsPin], cert: &CertificateDer<'_>) -> std::result::Result<(), rustls::Error> {
+    let fingerprint = Sha256::digest(cert.as_ref());
+    // A certificate that does not parse can still match by fingerprint
+    let public_key = webpki::EndEntityCert::try_from(cert)
+        .ok()
+        .map(|cert| Sha256::digest(cert.subject_public_key_info().as_ref()));
+    let matched = pins.iter().any(|pin| match pin {
+        TlsPin::Certificate(digest) => constant_time_eq(&fingerprint, digest),
+        TlsPin::PublicKey(digest) => public_key.is_some_and(|public_key| constant_time_eq(&public_key, digest)),
+    });
+    match matched {
+        true => Ok(()),
+        false => Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)),
+    }
+}
+
+/// Server verifier that also requires the certificate to match a pin
+#[derive(Debug)]
+struct PinnedServerCertificate {
+    pins: Vec<TlsPin>,
+    inner: Arc<dyn ServerCertVerifier>,
+}
+
+impl ServerCertVerifier for PinnedServerCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let verified = self
+            .inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        check_pins(&self.pins, end_entity)?;
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Client verifier that requires a certificate and accepts only pinned ones
+#[derive(Debug)]
+struct PinnedClientCertificate {
+    pins: Vec<TlsPin>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ClientCertVerifier for PinnedClientCertificate {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> std::result::Result<ClientCertVerified, rustls::Error> {
+        check_pins(&self.pins, end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Verifier that accepts any server certificate; for development against self-signed peers
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
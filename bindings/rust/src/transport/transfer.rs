@@ -0,0 +1,302 @@
+/*!
+# UMICP Stream Transfers
+
+Moving payloads too large to hold in memory, such as multi-GB model checkpoints, as a sequence
+of chunks.
+
+A transfer is a run of envelopes sharing a [`TRANSFER_CAPABILITY`] (the message ID of the
+header envelope) and marked by [`TRANSFER_PART_CAPABILITY`]: the caller's header envelope as
+`start`, then `chunk` envelopes whose raw payloads are the data, then `end` carrying the total
+size in [`TRANSFER_SIZE_CAPABILITY`], or `abort` with a reason if the source failed. Parts travel
+as binary frames on a mux stream of their own, so other traffic keeps flowing between chunks.
+
+The sender reads its source only as fast as the connection drains, keeping at most a few MB
+queued; the receiver hands each chunk to an [`IncomingStream`] and stops reading the
+connection while that stream's small buffer is full.
+*/
+
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::types::OperationType;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, Notify};
+
+/// Capability naming the transfer an envelope belongs to
+pub const TRANSFER_CAPABILITY: &str = "transfer";
+
+/// Capability telling which part of a transfer an envelope is: `start`, `chunk`, `end` or `abort`
+pub const TRANSFER_PART_CAPABILITY: &str = "transfer_part";
+
+/// Capability of the `end` part holding the number of bytes sent
+pub const TRANSFER_SIZE_CAPABILITY: &str = "transfer_size";
+
+/// Data bytes per `chunk` envelope
+pub(crate) const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Most bytes a sender keeps queued on a connection before waiting for it to drain
+pub(crate) const WINDOW: usize = 4 * 1024 * 1024;
+
+/// Transfers use mux streams from here up, away from application stream IDs
+pub(crate) const FIRST_STREAM_ID: u32 = 0x8000_0000;
+
+/// Chunks buffered per incoming stream before the connection stops being read
+const BUFFERED_CHUNKS: usize = 8;
+
+/// One envelope of a transfer
+pub(crate) enum Part {
+    Start,
+    Chunk,
+    End(u64),
+    Abort(String),
+}
+
+impl Part {
+    fn name(&self) -> &'static str {
+        match self {
+            Part::Start => "start",
+            Part::Chunk => "chunk",
+            Part::End(_) => "end",
+            Part::Abort(_) => "abort",
+        }
+    }
+}
+
+/// Transfer ID and part of an envelope, if it belongs to a transfer
+pub(crate) fn part_of(envelope: &Envelope) -> Option<(String, Part)> {
+    let capabilities = envelope.capabilities()?;
+    let id = capabilities.get(TRANSFER_CAPABILITY)?.clone();
+    let part = match capabilities.get(TRANSFER_PART_CAPABILITY)?.as_str() {
+        "start" => Part::Start,
+        "chunk" => Part::Chunk,
+        "end" => Part::End(capabilities.get(TRANSFER_SIZE_CAPABILITY)?.parse().ok()?),
+        "abort" => Part::Abort(capabilities.get(crate::transport::ERROR_CAPABILITY).cloned().unwrap_or_default()),
+        _ => return None,
+    };
+    Some((id, part))
+}
+
+/// The `start` envelope of a transfer: the caller's header, tagged
+pub(crate) fn start(mut header: Envelope) -> Envelope {
+    let id = header.message_id().to_string();
+    header.add_capability(TRANSFER_CAPABILITY, &id);
+    header.add_capability(TRANSFER_PART_CAPABILITY, Part::Start.name());
+    header
+}
+
+/// A later envelope of the transfer opened by `header`
+pub(crate) fn part(header: &Envelope, part: Part, payload: Option<Vec<u8>>) -> Envelope {
+    let mut envelope = Envelope::new();
+    envelope.set_from(header.from());
+    envelope.set_to(header.to());
+    envelope.set_operation(OperationType::Control);
+    envelope.add_capability(TRANSFER_CAPABILITY, header.message_id());
+    envelope.add_capability(TRANSFER_PART_CAPABILITY, part.name());
+    match &part {
+        Part::End(size) => envelope.add_capability(TRANSFER_SIZE_CAPABILITY, &size.to_string()),
+        Part::Abort(reason) => envelope.add_capability(crate::transport::ERROR_CAPABILITY, reason),
+        Part::Start | Part::Chunk => {}
+    }
+    if let Some(payload) = payload {
+        envelope.set_payload(payload);
+    }
+    envelope
+}
+
+/// Stream bytes queued on a connection and not yet written
+#[derive(Debug, Default)]
+pub(crate) struct Backlog {
+    bytes: AtomicUsize,
+    drained: Notify,
+}
+
+impl Backlog {
+    pub(crate) fn add(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes, Ordering::AcqRel);
+    }
+
+    /// Bytes queued and not yet written
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn release(&self, bytes: usize) {
+        self.bytes.fetch_sub(bytes, Ordering::AcqRel);
+        self.drained.notify_waiters();
+    }
+
+    /// Wake every waiter, e.g. because the connection is gone
+    pub(crate) fn wake(&self) {
+        self.drained.notify_waiters();
+    }
+
+    /// Wait until at most [`WINDOW`] bytes are queued, or `closed` says the writer is gone
+    pub(crate) async fn wait_for_room(&self, closed: impl Fn() -> bool) -> Result<()> {
+        loop {
+            // Registered before the check, so a release in between is not missed
+            let drained = self.drained.notified();
+            if self.bytes.load(Ordering::Acquire) <= WINDOW {
+                return Ok(());
+            }
+            if closed() {
+                return Err(UmicpError::connection("Connection closed during transfer"));
+            }
+            drained.await;
+        }
+    }
+}
+
+/// What an incoming stream's channel carries
+pub(crate) enum Chunk {
+    Data(Vec<u8>),
+    End,
+    Failed(UmicpError),
+}
+
+/// A transfer arriving on a connection, yielding its data chunk by chunk
+///
+/// Poll it as a [`futures_core::Stream`] of chunks or use [`next_chunk`](Self::next_chunk); it ends
+/// after the last chunk, or with an error if the sender aborted or the connection dropped first.
+/// Dropping it discards the rest of the transfer.
+#[derive(Debug)]
+pub struct IncomingStream {
+    /// The envelope the sender opened the transfer with
+    pub header: Envelope,
+    /// Connection the transfer arrives on
+    pub connection_id: String,
+    chunks: mpsc::Receiver<Chunk>,
+    done: bool,
+}
+
+impl IncomingStream {
+    /// Wait for the next chunk; `None` once the transfer is complete
+    pub async fn next_chunk(&mut self) -> Option<Result<Vec<u8>>> {
+        std::future::poll_fn(|cx| self.poll_chunk(cx)).await
+    }
+
+    /// Write every remaining chunk to `writer`, returning how many bytes were written
+    pub async fn copy_to<W>(&mut self, writer: &mut W) -> Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut written = 0;
+        while let Some(chunk) = self.next_chunk().await {
+            let chunk = chunk?;
+            writer.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        writer.flush().await?;
+        Ok(written)
+    }
+
+    fn poll_chunk(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Vec<u8>>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        let chunk = match self.chunks.poll_recv(cx) {
+            Poll::Ready(chunk) => chunk,
+            Poll::Pending => return Poll::Pending,
+        };
+        Poll::Ready(match chunk {
+            Some(Chunk::Data(data)) => Some(Ok(data)),
+            Some(Chunk::End) => {
+                self.done = true;
+                None
+            }
+            Some(Chunk::Failed(error)) => {
+                self.done = true;
+                Some(Err(error))
+            }
+            None => {
+                self.done = true;
+                Some(Err(UmicpError::connection("Connection closed before the transfer completed")))
+            }
+        })
+    }
+}
+
+impl futures_core::Stream for IncomingStream {
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_chunk(cx)
+    }
+}
+
+/// Transfers open on one connection
+#[derive(Default)]
+pub(crate) struct Transfers {
+    open: HashMap<String, Open>,
+}
+
+struct Open {
+    chunks: mpsc::Sender<Chunk>,
+    received: u64,
+}
+
+impl Transfers {
+    /// Route one part; `listener` receives the streams of newly started transfers
+    ///
+    /// Waits while the stream's buffer is full, which holds back the rest of the connection.
+    pub(crate) async fn receive(
+        &mut self,
+        id: String,
+        part: Part,
+        mut envelope: Envelope,
+        connection_id: &str,
+        listener: Option<&mpsc::UnboundedSender<IncomingStream>>,
+    ) -> Result<()> {
+        match part {
+            Part::Start => {
+                let (chunks, receiver) = mpsc::channel(BUFFERED_CHUNKS);
+                let stream = IncomingStream {
+                    header: envelope,
+                    connection_id: connection_id.to_string(),
+                    chunks: receiver,
+                    done: false,
+                };
+                match listener.is_some_and(|listener| listener.send(stream).is_ok()) {
+                    true => {
+                        self.open.insert(id, Open { chunks, received: 0 });
+                        Ok(())
+                    }
+                    false => Err(UmicpError::transport(format!("No receiver for stream transfer {}", id))),
+                }
+            }
+            Part::Chunk => {
+                let Some(open) = self.open.get_mut(&id) else {
+                    return Ok(());
+                };
+                let data = envelope.take_payload().unwrap_or_default();
+                open.received += data.len() as u64;
+                // A dropped stream just discards the rest
+                if open.chunks.send(Chunk::Data(data)).await.is_err() {
+                    self.open.remove(&id);
+                }
+                Ok(())
+            }
+            Part::End(size) => {
+                if let Some(open) = self.open.remove(&id) {
+                    let last = match open.received == size {
+                        true => Chunk::End,
+                        false => Chunk::Failed(UmicpError::transport(format!(
+                            "Transfer {} ended after {} of {} bytes",
+                            id, open.received, size
+                        ))),
+                    };
+                    let _ = open.chunks.send(last).await;
+                }
+                Ok(())
+            }
+            Part::Abort(reason) => {
+                if let Some(open) = self.open.remove(&id) {
+                    let _ = open.chunks.send(Chunk::Failed(UmicpError::remote(reason))).await;
+                }
+                Ok(())
+            }
+        }
+    }
+}
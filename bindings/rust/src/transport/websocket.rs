@@ -0,0 +1,3985 @@
+/*!
+# UMICP WebSocket Transport
+
+tokio-tungstenite based server and client (requires the `websocket` feature).
+
+Envelopes travel as JSON text frames, or as CBOR binary frames with the `cbor` feature. The codec
+is negotiated as a WebSocket subprotocol: clients offer `umicp.v1.json` and/or `umicp.v1.cbor`
+from [`TransportConfig::codecs`] and the server picks the first it accepts. Clients that offer no
+subprotocol are taken to speak JSON; an offer with no supported variant is refused with HTTP 400
+naming the subprotocols the server does speak. A transport is a cheap handle over shared state, so it
+can be cloned into handlers and background tasks: a clone can call `send`/`send_to_server`
+while another drives `run()`.
+
+TLS, proxies, dual-stack listening, reconnection, compression, coalescing and connection limits
+are set through [`TransportConfig`]. The wire details live in sibling modules: `transport::handshake`
+for capability exchange, `transport::mux` and `transport::transfer` for streams, and
+`transport::priority`, `transport::batch`, `transport::compression` and `transport::sequence` for
+what happens to each outgoing and incoming envelope.
+*/
+
+use super::{
+    accept_backoff, CancellationToken, ConnectionHandler, EventStream, MessageHandler, StateHandler, Subscribers,
+    Subscription, Transport, TransportEvent,
+};
+use crate::auth::{Authorizer, Principal, TokenValidator, AUTH_CAPABILITY};
+use crate::envelope::Envelope;
+use crate::error::{Result, UmicpError};
+use crate::quota::QuotaTracker;
+use super::batch::{self, Coalescer};
+use super::compression::{self, COMPRESSION_HEADER};
+use super::endpoint::{self, EndpointRegistry};
+use super::filter::{self, SubscriptionFilter};
+use super::frame;
+use super::handshake::{self, FEATURE_BATCHES, FEATURE_BINARY_FRAMES, FEATURE_TRANSFERS};
+use super::transfer::{self, IncomingStream};
+use super::priority::PriorityQueue;
+use super::sequence::Sequencer;
+use super::{latency, mux, rpc};
+use crate::types::{
+    Compression, ConnectionInfo, ConnectionState, ConnectionStats, EnvelopeCodec, FrameOptions, LatencyStats,
+    MessagePriority, OperationType, PeerCapabilities, ShardStats, TransportConfig, TransportStats,
+};
+use async_trait::async_trait;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::error::ProtocolError;
+use tokio_tungstenite::tungstenite::http::header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+
+/// Capability of the `Control` notice a server sends before closing an idle connection; holds the
+/// idle timeout in seconds
+pub const IDLE_TIMEOUT_CAPABILITY: &str = "idle_timeout";
+
+/// Read half of a client socket, boxed so plain and TLS streams share one type
+type ClientStream = Pin<Box<dyn Stream<Item = std::result::Result<Message, WsError>> + Send>>;
+
+/// Write half of a client socket
+type ClientSink = Pin<Box<dyn Sink<Message, Error = WsError> + Send>>;
+
+/// What both ends of a connection agreed on during the upgrade
+#[derive(Debug, Clone, Copy, Default)]
+struct Agreement {
+    compression: Compression,
+    codec: EnvelopeCodec,
+}
+
+/// Outbound side of a connection
+#[derive(Clone)]
+struct Peer {
+    /// Queue drained into the socket's write half
+    sender: mpsc::UnboundedSender<Outgoing>,
+    agreed: Agreement,
+    /// Whether payloads may go out as binary frames, per the peer's handshake
+    binary_frames: bool,
+    /// Largest message the peer accepts, per its handshake
+    max_payload_size: usize,
+    /// Whether the peer accepts coalesced batches, per its handshake
+    batches: bool,
+    /// Messages queued in `sender`, or held by the writer, and not yet written
+    queued: Arc<AtomicUsize>,
+    /// Stream data queued in `sender` or the writer's scheduler
+    backlog: Arc<transfer::Backlog>,
+    /// Broadcasts the peer asked to receive; `None` for all of them
+    filter: Option<Arc<SubscriptionFilter>>,
+    activity: Arc<Activity>,
+}
+
+/// When a connection last carried an envelope each way, in milliseconds since the transport
+/// started; pings and pongs are not counted
+struct Activity {
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl Activity {
+    fn new(now: u64) -> Self {
+        Activity {
+            received: AtomicU64::new(now),
+            sent: AtomicU64::new(now),
+        }
+    }
+
+    fn last(&self) -> u64 {
+        self.received.load(Ordering::Relaxed).max(self.sent.load(Ordering::Relaxed))
+    }
+}
+
+impl Peer {
+    /// Encode an envelope for this peer: as a binary frame if it has a payload and the peer takes
+    /// them, otherwise as a document in the agreed codec; `true` when framed
+    fn serialize(&self, envelope: &Envelope, options: &FrameOptions) -> Result<(Vec<u8>, bool)> {
+        match (envelope.payload().is_some() || options.sequence.is_some()) && self.binary_frames {
+            true => Ok((frame::encode(envelope, options, self.agreed.codec)?, true)),
+            false => Ok((envelope.encode(self.agreed.codec)?, false)),
+        }
+    }
+
+    /// Apply what the peer advertised in its handshake; `codecs` are ours, most preferred first
+    fn adopt(&mut self, capabilities: &PeerCapabilities, codecs: &[EnvelopeCodec]) {
+        self.binary_frames = capabilities.supports(FEATURE_BINARY_FRAMES);
+        self.batches = capabilities.supports(FEATURE_BATCHES);
+        self.max_payload_size = capabilities.max_payload_size;
+        if let Some(codec) = codecs.iter().find(|codec| capabilities.codecs.contains(codec)) {
+            self.agreed.codec = *codec;
+        }
+    }
+
+    /// Queue item for a message carrying an envelope of `priority`; urgent envelopes, and any to
+    /// peers that do not take batches, are never held back to be coalesced
+    fn envelope(&self, message: Message, priority: MessagePriority) -> Outgoing {
+        match self.batches && priority != MessagePriority::High {
+            true => Outgoing::Envelope(message, priority),
+            false => Outgoing::Message(message, priority),
+        }
+    }
+
+    /// Queue for the writer; `false` once the connection is closed
+    fn send(&self, outgoing: Outgoing) -> bool {
+        match &outgoing {
+            Outgoing::Stream(_, data) => self.backlog.add(data.len()),
+            _ => {
+                self.queued.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sender.send(outgoing).is_ok()
+    }
+}
+
+/// Worker runtime of a sharded server
+struct Shard {
+    runtime: tokio::runtime::Handle,
+    /// Cloned into each connection; the shard's thread exits once every clone is gone
+    alive: mpsc::Sender<()>,
+}
+
+/// Who a connection authenticated as, and what the authorizer decided for its envelopes so far
+struct Access {
+    principal: Option<Principal>,
+    /// TLS server name the connection was accepted under, choosing its message handler
+    server_name: Option<String>,
+    /// The authorizer the decisions came from; replacing it starts the cache afresh
+    authorizer: Option<Arc<dyn Authorizer>>,
+    /// By decision key: `None` when allowed, otherwise why not
+    decisions: HashMap<String, Option<String>>,
+}
+
+impl Access {
+    fn new(principal: Option<Principal>, server_name: Option<String>) -> Self {
+        Access {
+            principal,
+            server_name,
+            authorizer: None,
+            decisions: HashMap::new(),
+        }
+    }
+
+    /// Ask `authorizer` about `envelope`, or reuse its decision for an envelope with the same key;
+    /// cached denials come back as `Forbidden` errors
+    fn authorize(&mut self, authorizer: Option<Arc<dyn Authorizer>>, envelope: &Envelope) -> Result<()> {
+        let Some(authorizer) = authorizer else {
+            return Ok(());
+        };
+        if !self.authorizer.as_ref().is_some_and(|cached| Arc::ptr_eq(cached, &authorizer)) {
+            self.decisions.clear();
+            self.authorizer = Some(authorizer.clone());
+        }
+        let Some(key) = authorizer.decision_key(envelope) else {
+            return authorizer.authorize(self.principal.as_ref(), envelope);
+        };
+        if let Some(decision) = self.decisions.get(&key) {
+            return decision.as_ref().map_or(Ok(()), |message| Err(UmicpError::forbidden(message.as_str())));
+        }
+        let decision = authorizer.authorize(self.principal.as_ref(), envelope);
+        let cached = match &decision {
+            Ok(()) => None,
+            Err(UmicpError::Forbidden { message }) => Some(message.clone()),
+            // Other failures, such as a malformed envelope, say nothing about the next one
+            Err(_) => return decision,
+        };
+        self.decisions.insert(key, cached);
+        decision
+    }
+}
+
+/// Item queued for a connection's writer task
+enum Outgoing {
+    /// Written as-is, ahead of any pending stream chunks and after queued messages of a higher
+    /// priority
+    Message(Message, MessagePriority),
+    /// Like `Message`, but may be held back briefly to go out in one batch with others
+    Envelope(Message, MessagePriority),
+    /// Serialized envelope for a logical stream, chunked and interleaved with other streams
+    Stream(u32, Vec<u8>),
+}
+
+enum Role {
+    Server {
+        /// Taken by `run`
+        listeners: Mutex<Option<Vec<TcpListener>>>,
+        local_addrs: Vec<SocketAddr>,
+        #[cfg(feature = "tls")]
+        tls: Option<super::tls::ReloadableAcceptor>,
+        /// How often the TLS certificate and key files are checked for changes; zero for never
+        #[cfg(feature = "tls")]
+        tls_reload_interval: Duration,
+    },
+    Client {
+        url: String,
+        /// Kept to reconnect with the same TLS and backoff settings
+        config: Box<TransportConfig>,
+        stream: Mutex<Option<ClientStream>>,
+        state: Mutex<ConnectionState>,
+        state_handler: RwLock<Option<StateHandler>>,
+    },
+}
+
+struct Shared {
+    role: Role,
+    max_payload_size: usize,
+    /// Whether a server accepts compression offers from clients
+    compression_enabled: bool,
+    compression_threshold: usize,
+    /// Codecs a server accepts, most preferred first
+    codecs: Vec<EnvelopeCodec>,
+    /// What this end advertises in its handshake
+    capabilities: PeerCapabilities,
+    message_handler: RwLock<Option<MessageHandler>>,
+    connection_handler: RwLock<Option<ConnectionHandler>>,
+    /// Handlers for logical streams; envelopes on other streams go to `message_handler`
+    stream_handlers: RwLock<HashMap<u32, MessageHandler>>,
+    /// Handlers by TLS server name, taking the place of `message_handler` for connections
+    /// accepted under that name
+    host_handlers: RwLock<HashMap<String, MessageHandler>>,
+    subscribers: Subscribers,
+    peers: RwLock<HashMap<String, Peer>>,
+    /// Addresses, activity and principal of every open connection
+    connections: Mutex<HashMap<String, ConnectionInfo>>,
+    /// Set on servers that require clients to authenticate
+    token_validator: RwLock<Option<Arc<dyn TokenValidator>>>,
+    /// Vets each incoming envelope before dispatch
+    authorizer: RwLock<Option<Arc<dyn Authorizer>>>,
+    /// Usage limits per principal, kept across reconnections
+    quotas: RwLock<Option<Arc<QuotaTracker>>>,
+    /// Filter a client asks its server to apply to broadcasts, again on every reconnection
+    subscription_filter: RwLock<Option<SubscriptionFilter>>,
+    /// Names a client registers with its server, again on every reconnection
+    endpoint_names: RwLock<Vec<String>>,
+    /// Names a server's clients registered
+    endpoints: Mutex<EndpointRegistry>,
+    /// Receives transfers started by peers
+    stream_listener: Mutex<Option<mpsc::UnboundedSender<IncomingStream>>>,
+    /// Mux stream of the next outgoing transfer, offset from `transfer::FIRST_STREAM_ID`
+    next_transfer: AtomicU32,
+    /// Connections being served, handshakes included
+    serving: AtomicUsize,
+    /// Limit on `serving`; 0 for none
+    max_connections: usize,
+    /// One permit per envelope being dispatched, when `max_inflight_handlers` is set
+    handler_slots: Option<Arc<Semaphore>>,
+    /// How long a client that sent no `Authorization` header has to send its auth envelope
+    auth_timeout: Duration,
+    /// Higher-priority messages a connection writes in a row while a lower-priority one waits
+    max_priority_burst: usize,
+    /// How long a server keeps a connection that carries no envelopes; zero for ever
+    idle_timeout: Duration,
+    /// How long small envelopes may be held to be coalesced, and how many bytes of them
+    coalesce_delay: Duration,
+    coalesce_max_bytes: usize,
+    /// Sequenced envelopes held per stream to restore their order, and for how long
+    reorder_window: usize,
+    reorder_timeout: Duration,
+    stats: Mutex<TransportStats>,
+    /// How often each connection is pinged; zero disables pings
+    ping_interval: Duration,
+    latency: Mutex<Latency>,
+    started: Instant,
+    shutdown: watch::Sender<bool>,
+    /// Cancelled by `shutdown`, dropping handlers and transfers still running
+    cancel: CancellationToken,
+    /// Accepts left to fail before the listeners are used
+    #[cfg(test)]
+    accept_faults: AtomicUsize,
+}
+
+impl Shared {
+    /// Count `error` by category, overall and for its connection, then report it as an `Error`
+    /// event
+    fn report(&self, conn_id: Option<&str>, error: impl Into<UmicpError>) {
+        let error = error.into();
+        self.count_error(conn_id, &error);
+        self.subscribers.notify(TransportEvent::error(conn_id, error));
+    }
+
+    /// Count the refusal of a peer's credentials as an error and report it as an
+    /// `AuthenticationFailed` event
+    fn report_auth_failure(&self, conn_id: &str, error: &UmicpError) {
+        self.count_error(None, error);
+        self.subscribers.notify(TransportEvent::AuthenticationFailed {
+            connection_id: conn_id.to_string(),
+            message: error.to_string(),
+        });
+    }
+
+    fn count_error(&self, conn_id: Option<&str>, error: &UmicpError) {
+        let category = error.category();
+        let mut stats = self.stats.lock().unwrap();
+        *stats.errors.entry(category.to_string()).or_default() += 1;
+        if let Some(connection) = conn_id.and_then(|conn_id| stats.connections.get_mut(conn_id)) {
+            *connection.errors.entry(category.to_string()).or_default() += 1;
+        }
+    }
+}
+
+/// Round-trip times from ping/pong, overall and per open connection
+#[derive(Default)]
+struct Latency {
+    overall: latency::Histogram,
+    connections: HashMap<String, latency::Histogram>,
+}
+
+/// WebSocket transport for UMICP envelopes
+///
+/// Each connection queues its outgoing messages by [`Envelope::priority`]: acks, errors and
+/// control messages overtake queued bulk data. Both ends open with a handshake envelope
+/// advertising their codecs, compression, largest accepted message and protocol version.
+///
+/// A server sheds load past `max_connections` and `max_inflight_handlers`: a connection beyond
+/// the limit is sent an `"overloaded"` `Error` envelope and closed with code 1013 (try again
+/// later), and an envelope arriving while every handler slot is busy is answered with an
+/// `"overloaded"` `Error` reply instead of being dispatched. Connections idle past `idle_timeout`
+/// are sent a `Control` notice with the [`IDLE_TIMEOUT_CAPABILITY`] and closed;
+/// [`ConnectionInfo::idle_ms`] reports how long each has been quiet. With `accept_shards` above 1,
+/// one task accepts and every connection's I/O and handlers run on the shard it was given.
+#[derive(Clone)]
+pub struct WebSocketTransport {
+    shared: Arc<Shared>,
+}
+
+impl WebSocketTransport {
+    /// Bind a WebSocket server to `addr`; call [`run`](Self::run) to start accepting
+    pub async fn new_server(addr: &str) -> Result<Self> {
+        Self::new_server_with_config(addr, &TransportConfig::default()).await
+    }
+
+    /// Bind a WebSocket server to `addr`, terminating TLS when `config.tls_enabled` is set
+    pub async fn new_server_with_config(addr: &str, config: &TransportConfig) -> Result<Self> {
+        #[cfg(feature = "tls")]
+        let tls = match config.tls_enabled {
+            true => Some(super::tls::ReloadableAcceptor::new(config)?),
+            false => None,
+        };
+        #[cfg(not(feature = "tls"))]
+        if config.tls_enabled {
+            return Err(UmicpError::configuration("TLS requires the `tls` feature"));
+        }
+
+        let listeners = super::net::bind(addr, config.dual_stack)
+            .await
+            .map_err(|e| UmicpError::connection(format!("Failed to bind {}: {}", addr, e)))?;
+        let local_addrs = listeners.iter().map(TcpListener::local_addr).collect::<std::io::Result<_>>()?;
+
+        Ok(Self::with_role(
+            Role::Server {
+                listeners: Mutex::new(Some(listeners)),
+                local_addrs,
+                #[cfg(feature = "tls")]
+                tls,
+                #[cfg(feature = "tls")]
+                tls_reload_interval: Duration::from_secs(config.tls_reload_interval),
+            },
+            config,
+        ))
+    }
+
+    /// Connect a WebSocket client to `url`; call [`run`](Self::run) to receive messages
+    pub async fn new_client(url: &str) -> Result<Self> {
+        Self::new_client_with_config(url, &TransportConfig::default()).await
+    }
+
+    /// Connect a WebSocket client to `url`, using TLS for `wss://` URLs or when
+    /// `config.tls_enabled` is set
+    ///
+    /// The connection goes through `config.proxy_url` when set (see `transport::proxy`);
+    /// otherwise the host's addresses are tried Happy Eyeballs style (see `transport::net`).
+    pub async fn new_client_with_config(url: &str, config: &TransportConfig) -> Result<Self> {
+        let (sink, stream, agreed) = Self::open_socket(url, config).await?;
+        let transport = Self::with_role(
+            Role::Client {
+                url: url.to_string(),
+                config: Box::new(config.clone()),
+                stream: Mutex::new(Some(stream)),
+                state: Mutex::new(ConnectionState::Connected),
+                state_handler: RwLock::new(None),
+            },
+            config,
+        );
+        transport.register_peer(client_info(url), sink, agreed);
+        Ok(transport)
+    }
+
+    async fn open_socket(url: &str, config: &TransportConfig) -> Result<(ClientSink, ClientStream, Agreement)> {
+        let mut request = url
+            .into_client_request()
+            .map_err(|e| UmicpError::configuration(format!("Invalid WebSocket URL {}: {}", url, e)))?;
+        if config.compression_enabled {
+            let offer = HeaderValue::from_str(&compression::offer()).expect("algorithm names are valid header text");
+            request.headers_mut().insert(COMPRESSION_HEADER, offer);
+        }
+        if let Some(codec) = config.codecs.iter().find(|codec| !EnvelopeCodec::supported().contains(codec)) {
+            return Err(UmicpError::configuration(format!("{} is not supported by this build", codec.subprotocol())));
+        }
+        let offered = config.codecs.iter().map(|codec| codec.subprotocol()).collect::<Vec<_>>().join(", ");
+        if offered.is_empty() {
+            return Err(UmicpError::configuration("No envelope codecs configured"));
+        }
+        let offer = HeaderValue::from_str(&offered).expect("subprotocol names are valid header text");
+        request.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, offer);
+        if let Some(token) = &config.auth_token {
+            let bearer = HeaderValue::from_str(&format!("Bearer {}", token.expose()))
+                .map_err(|_| UmicpError::configuration("Auth token is not valid header text"))?;
+            request.headers_mut().insert(AUTHORIZATION, bearer);
+        }
+        let secure = config.tls_enabled || request.uri().scheme_str() == Some("wss");
+        let connect_error = |e: WsError| match e {
+            WsError::Http(response) if response.status() == StatusCode::UNAUTHORIZED => {
+                let reason = response.body().as_deref().map(String::from_utf8_lossy).unwrap_or_default();
+                UmicpError::authentication(format!("{} rejected the credentials: {}", url, reason))
+            }
+            WsError::Http(response) if response.status() == StatusCode::BAD_REQUEST => {
+                let reason = response.body().as_deref().map(String::from_utf8_lossy).unwrap_or_default();
+                UmicpError::transport(format!("{} refused the connection: {}", url, reason))
+            }
+            WsError::Protocol(ProtocolError::SecWebSocketSubProtocolError(_)) => UmicpError::transport(format!(
+                "{} does not speak any of the offered subprotocols ({})",
+                url, offered
+            )),
+            // With TLS 1.3 a server rejects the client certificate after the client's handshake;
+            // its alert is seen here unless the connection is reset first
+            #[cfg(feature = "tls")]
+            WsError::Io(e) if super::tls::is_pin_mismatch(&e) => {
+                UmicpError::pin_mismatch(format!("{} accepts no certificate this client presents", url))
+            }
+            e => UmicpError::connection(format!("Failed to connect to {}: {}", url, e)),
+        };
+        let proxy = config.proxy_url.as_ref().map(|url| super::proxy::Proxy::parse(url.expose())).transpose()?;
+
+        let host = request
+            .uri()
+            .host()
+            .ok_or_else(|| UmicpError::configuration(format!("WebSocket URL has no host: {}", url)))?
+            // IPv6 literals come bracketed
+            .trim_matches(|c| c == '[' || c == ']')
+            .to_string();
+        let port = request.uri().port_u16().unwrap_or(if secure { 443 } else { 80 });
+        let tcp = match &proxy {
+            Some(proxy) => proxy.connect(&host, port).await?,
+            None => super::net::connect(&host, port, Duration::from_millis(config.happy_eyeballs_delay_ms))
+                .await
+                .map_err(|e| UmicpError::connection(format!("Failed to connect to {}: {}", url, e)))?,
+        };
+
+        if !secure {
+            let (socket, response) = tokio_tungstenite::client_async(request, tcp).await.map_err(connect_error)?;
+            return Ok(split_socket(socket, &response));
+        }
+
+        #[cfg(feature = "tls")]
+        {
+            let stream = tokio_rustls::TlsConnector::from(Arc::new(super::tls::client_config(config)?))
+                .connect(super::tls::server_name(&host)?, tcp)
+                .await
+                .map_err(|e| match super::tls::is_pin_mismatch(&e) {
+                    true => UmicpError::pin_mismatch(format!("{} presented a certificate matching no pin: {}", url, e)),
+                    false => UmicpError::connection(format!("TLS handshake with {} failed: {}", url, e)),
+                })?;
+            let (socket, response) = tokio_tungstenite::client_async(request, stream).await.map_err(connect_error)?;
+            Ok(split_socket(socket, &response))
+        }
+        #[cfg(not(feature = "tls"))]
+        Err(UmicpError::configuration("wss:// URLs require the `tls` feature"))
+    }
+
+    fn with_role(role: Role, config: &TransportConfig) -> Self {
+        let (shutdown, _) = watch::channel(false);
+        let shards = match role {
+            Role::Server { .. } if config.accept_shards > 1 => config.accept_shards,
+            _ => 0,
+        };
+        WebSocketTransport {
+            shared: Arc::new(Shared {
+                role,
+                max_payload_size: config.max_payload_size,
+                compression_enabled: config.compression_enabled,
+                compression_threshold: config.compression_threshold,
+                codecs: config.codecs.clone(),
+                capabilities: PeerCapabilities {
+                    protocol_version: crate::UMICP_VERSION.to_string(),
+                    codecs: config
+                        .codecs
+                        .iter()
+                        .copied()
+                        .filter(|codec| EnvelopeCodec::supported().contains(codec))
+                        .collect(),
+                    compression: match config.compression_enabled {
+                        true => compression::supported().to_vec(),
+                        false => Vec::new(),
+                    },
+                    max_payload_size: config.max_payload_size,
+                    features: vec![
+                        FEATURE_BINARY_FRAMES.to_string(),
+                        FEATURE_TRANSFERS.to_string(),
+                        FEATURE_BATCHES.to_string(),
+                    ],
+                },
+                message_handler: RwLock::new(None),
+                connection_handler: RwLock::new(None),
+                stream_handlers: RwLock::new(HashMap::new()),
+                host_handlers: RwLock::new(HashMap::new()),
+                subscribers: Subscribers::default(),
+                peers: RwLock::new(HashMap::new()),
+                connections: Mutex::new(HashMap::new()),
+                token_validator: RwLock::new(None),
+                authorizer: RwLock::new(None),
+                quotas: RwLock::new(None),
+                subscription_filter: RwLock::new(None),
+                endpoint_names: RwLock::new(Vec::new()),
+                endpoints: Mutex::new(EndpointRegistry::default()),
+                stream_listener: Mutex::new(None),
+                next_transfer: AtomicU32::new(0),
+                serving: AtomicUsize::new(0),
+                max_connections: config.max_connections,
+                handler_slots: (config.max_inflight_handlers > 0)
+                    .then(|| Arc::new(Semaphore::new(config.max_inflight_handlers))),
+                auth_timeout: Duration::from_secs(config.connection_timeout),
+                max_priority_burst: config.max_priority_burst,
+                idle_timeout: Duration::from_secs(config.idle_timeout),
+                coalesce_delay: Duration::from_millis(config.coalesce_delay_ms),
+                coalesce_max_bytes: config.coalesce_max_bytes,
+                reorder_window: config.reorder_window,
+                reorder_timeout: Duration::from_millis(config.reorder_timeout_ms),
+                stats: Mutex::new(TransportStats {
+                    shards: vec![ShardStats::default(); shards],
+                    ..Default::default()
+                }),
+                ping_interval: Duration::from_secs(config.heartbeat_interval),
+                latency: Mutex::new(Latency::default()),
+                started: Instant::now(),
+                shutdown,
+                cancel: CancellationToken::new(),
+                #[cfg(test)]
+                accept_faults: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Set message handler for incoming messages
+    ///
+    /// The handler receives each envelope with the ID of the connection it arrived on and is
+    /// awaited before the next frame from that connection is read.
+    pub fn set_message_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(Envelope, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        *self.shared.message_handler.write().unwrap() = Some(super::message_handler(handler));
+    }
+
+    /// Set handler for envelopes arriving on logical stream `stream_id`
+    ///
+    /// Each connection gets its own worker per stream, so the handler sees a stream's envelopes
+    /// in order without holding up other streams or the plain message handler.
+    pub fn set_stream_handler<F, Fut>(&self, stream_id: u32, handler: F)
+    where
+        F: Fn(Envelope, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.shared
+            .stream_handlers
+            .write()
+            .unwrap()
+            .insert(stream_id, super::message_handler(handler));
+    }
+
+    /// Set handler for envelopes from connections accepted under TLS server name `server_name`
+    /// (TLS server mode)
+    ///
+    /// It replaces the plain message handler for those connections, so tenants sharing a listener
+    /// through `tls_virtual_hosts` are handled apart; connections under other names, or none,
+    /// still go to the plain message handler. Stream handlers are shared by all names.
+    #[cfg(feature = "tls")]
+    pub fn set_host_message_handler<F, Fut>(&self, server_name: &str, handler: F)
+    where
+        F: Fn(Envelope, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.shared
+            .host_handlers
+            .write()
+            .unwrap()
+            .insert(server_name.to_ascii_lowercase(), super::message_handler(handler));
+    }
+
+    /// Set connection handler for connection events (`true` on connect, `false` on disconnect)
+    pub fn set_connection_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(bool, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        *self.shared.connection_handler.write().unwrap() = Some(super::connection_handler(handler));
+    }
+
+    /// Set handler for client connection state transitions (client mode)
+    ///
+    /// Reconnections made while [`run`](Self::run) drives the client are reported too.
+    pub fn set_state_handler<F, Fut>(&self, handler: F)
+    where
+        F: Fn(ConnectionState) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        if let Role::Client { state_handler, .. } = &self.shared.role {
+            *state_handler.write().unwrap() = Some(super::state_handler(handler));
+        }
+    }
+
+    /// Require clients to authenticate with a token `validator` accepts (server mode)
+    ///
+    /// Applies to connections accepted from then on. Clients send `auth_token` from their config
+    /// as an `Authorization: Bearer` header, checked during the upgrade; a client that sends no
+    /// header must make its first envelope after the handshake a `Control` envelope carrying the
+    /// token in its [`AUTH_CAPABILITY`](crate::auth::AUTH_CAPABILITY). Rejected upgrades fail with
+    /// HTTP 401 and rejected envelopes close the connection with a policy-violation code.
+    ///
+    /// The principal is recorded in the connection's [`ConnectionInfo`], and each outcome is
+    /// reported as a [`TransportEvent::Authenticated`] before the connection's `Connected` or as a
+    /// [`TransportEvent::AuthenticationFailed`].
+    pub fn set_token_validator<V: TokenValidator + 'static>(&self, validator: V) {
+        *self.shared.token_validator.write().unwrap() = Some(Arc::new(validator));
+    }
+
+    /// Check every incoming envelope with `authorizer` before dispatching it
+    ///
+    /// Rejected envelopes are answered with an `Error` reply whose
+    /// [`ERROR_CODE_CAPABILITY`](super::ERROR_CODE_CAPABILITY) is `"forbidden"`. Decisions the
+    /// authorizer gives a [`decision_key`](Authorizer::decision_key) for, such as a
+    /// [`Policy`](crate::policy::Policy)'s, are cached per connection.
+    pub fn set_authorizer<A: Authorizer + 'static>(&self, authorizer: A) {
+        *self.shared.authorizer.write().unwrap() = Some(Arc::new(authorizer));
+    }
+
+    /// Hold every peer to the quotas of `tracker` (server mode)
+    ///
+    /// Usage is counted by principal subject, or by IP address for peers that did not
+    /// authenticate, so it carries over when a peer reconnects. Envelopes over quota are
+    /// answered with a `"quota_exceeded"` `Error` reply and counted in
+    /// [`TransportStats::quota_rejections`].
+    pub fn set_quotas(&self, tracker: QuotaTracker) {
+        *self.shared.quotas.write().unwrap() = Some(Arc::new(tracker));
+    }
+
+    /// The quota tracker set with [`set_quotas`](Self::set_quotas), to inspect usage or adjust quotas
+    pub fn quotas(&self) -> Option<Arc<QuotaTracker>> {
+        self.shared.quotas.read().unwrap().clone()
+    }
+
+    /// Details of an open connection, including the principal it authenticated as
+    pub fn connection_info(&self, connection_id: &str) -> Option<ConnectionInfo> {
+        let info = self.shared.connections.lock().unwrap().get(connection_id).cloned();
+        info.map(|info| self.snapshot(info))
+    }
+
+    /// Details of every open connection
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let connections: Vec<_> = self.shared.connections.lock().unwrap().values().cloned().collect();
+        connections.into_iter().map(|info| self.snapshot(info)).collect()
+    }
+
+    /// Fill in how long `info`'s connection has been idle and the names it registered
+    fn snapshot(&self, mut info: ConnectionInfo) -> ConnectionInfo {
+        info.endpoints = self.shared.endpoints.lock().unwrap().names(&info.id);
+        if let Some(peer) = self.shared.peers.read().unwrap().get(&info.id) {
+            let now = self.shared.started.elapsed().as_millis() as u64;
+            info.idle_ms = now.saturating_sub(peer.activity.last());
+        }
+        info
+    }
+
+    /// What a connection's peer advertised in its handshake; `None` until it has arrived
+    ///
+    /// From then on sends to that peer use the best codec both sides list, carry payloads in
+    /// binary frames if the peer supports them and refuse envelopes larger than it accepts.
+    pub fn peer_capabilities(&self, connection_id: &str) -> Option<PeerCapabilities> {
+        self.shared.connections.lock().unwrap().get(connection_id)?.capabilities.clone()
+    }
+
+    /// Filter a connection registered for the broadcasts it receives, if any
+    pub fn subscription_filter(&self, connection_id: &str) -> Option<SubscriptionFilter> {
+        let peers = self.shared.peers.read().unwrap();
+        peers.get(connection_id)?.filter.as_deref().cloned()
+    }
+
+    /// Ask the server to only broadcast envelopes matching `filter` to this client (client mode)
+    ///
+    /// `None` removes the filter. Resolves once the server has accepted it; the filter is
+    /// registered again after every reconnection.
+    pub async fn set_subscription_filter(&self, filter: Option<SubscriptionFilter>) -> Result<()> {
+        let Role::Client { url, config, .. } = &self.shared.role else {
+            return Err(UmicpError::transport("set_subscription_filter is only available in client mode"));
+        };
+        let request = filter::request(filter.as_ref())?;
+        *self.shared.subscription_filter.write().unwrap() = filter;
+        let timeout = Duration::from_secs(config.connection_timeout);
+        Transport::request_to(self, request, url, timeout).await.map(|_| ())
+    }
+
+    /// Register `name` with the server as an endpoint of this client (client mode)
+    ///
+    /// The server can then address this client by name with [`send_to`](Self::send_to). Resolves
+    /// once the server has accepted it; names are registered again after every reconnection.
+    pub async fn register_endpoint(&self, name: &str) -> Result<()> {
+        let Role::Client { url, config, .. } = &self.shared.role else {
+            return Err(UmicpError::transport("register_endpoint is only available in client mode"));
+        };
+        let name = endpoint::validate(name)?;
+        let request = {
+            let mut names = self.shared.endpoint_names.write().unwrap();
+            if !names.contains(&name) {
+                names.push(name);
+            }
+            endpoint::request(&names)
+        };
+        let timeout = Duration::from_secs(config.connection_timeout);
+        Transport::request_to(self, request, url, timeout).await.map(|_| ())
+    }
+
+    /// Connection a client registered as endpoint `name`, if it is open (server mode)
+    pub fn endpoint_connection(&self, name: &str) -> Option<String> {
+        self.shared.endpoints.lock().unwrap().resolve(name).map(str::to_string)
+    }
+
+    /// Load the TLS certificate and key again from `tls_cert_path` and `tls_key_path` (TLS server
+    /// mode)
+    ///
+    /// Handshakes from now on use them; open connections keep the certificate they were accepted
+    /// with. On error the current certificate stays in use.
+    #[cfg(feature = "tls")]
+    pub fn reload_tls(&self) -> Result<()> {
+        match &self.shared.role {
+            Role::Server { tls: Some(tls), .. } => tls.reload(),
+            _ => Err(UmicpError::transport("reload_tls is only available on TLS servers")),
+        }
+    }
+
+    /// Principal an open connection authenticated as, if any
+    pub fn principal(&self, connection_id: &str) -> Option<Principal> {
+        self.shared.connections.lock().unwrap().get(connection_id)?.principal.clone()
+    }
+
+    /// Current connection state (client mode)
+    pub fn connection_state(&self) -> Option<ConnectionState> {
+        match &self.shared.role {
+            Role::Client { state, .. } => Some(*state.lock().unwrap()),
+            Role::Server { .. } => None,
+        }
+    }
+
+    /// Address the server is bound to, the first of them with `dual_stack` (server mode)
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs().into_iter().next()
+    }
+
+    /// Every address the server is bound to (server mode)
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        match &self.shared.role {
+            Role::Server { local_addrs, .. } => local_addrs.clone(),
+            Role::Client { .. } => Vec::new(),
+        }
+    }
+
+    /// Drive the transport until [`shutdown`](Self::shutdown) is called
+    ///
+    /// A server accepts connections; a client reads from the server, reconnecting when the
+    /// connection drops, and fails once `max_reconnect_attempts` attempts in a row have failed.
+    /// Handlers and subscriptions carry over to the new connection.
+    pub async fn run(&self) -> Result<()> {
+        match &self.shared.role {
+            Role::Server { listeners, .. } => {
+                let listeners = listeners
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .ok_or_else(|| UmicpError::transport("Server is already running"))?;
+                self.accept_loop(listeners).await
+            }
+            Role::Client { url, config, stream, .. } => {
+                let mut stream = stream
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .ok_or_else(|| UmicpError::transport("Client is already running"))?;
+                loop {
+                    self.notify_connection(true, url.clone()).await;
+                    self.read_loop(stream, url.clone()).await;
+                    self.drop_peer(url).await;
+
+                    if *self.shared.shutdown.borrow() || config.max_reconnect_attempts == 0 {
+                        self.set_state(ConnectionState::Disconnected).await;
+                        return Ok(());
+                    }
+                    stream = match self.reconnect(url, config).await {
+                        Some(stream) => stream,
+                        None if *self.shared.shutdown.borrow() => {
+                            self.set_state(ConnectionState::Disconnected).await;
+                            return Ok(());
+                        }
+                        None => {
+                            self.set_state(ConnectionState::Failed).await;
+                            return Err(UmicpError::connection(format!(
+                                "Gave up reconnecting to {} after {} attempts",
+                                url, config.max_reconnect_attempts
+                            )));
+                        }
+                    };
+                }
+            }
+        }
+    }
+
+    /// Retry the connection with backoff; `None` when attempts run out or shutdown is requested
+    async fn reconnect(&self, url: &str, config: &TransportConfig) -> Option<ClientStream> {
+        self.set_state(ConnectionState::Reconnecting).await;
+        let mut shutdown = self.shared.shutdown.subscribe();
+        let timeout = Duration::from_secs(config.connection_timeout);
+
+        for attempt in 1..=config.max_reconnect_attempts {
+            tokio::select! {
+                _ = tokio::time::sleep(config.reconnect_delay(attempt)) => {}
+                _ = shutdown.wait_for(|stopped| *stopped) => return None,
+            }
+            self.shared.stats.lock().unwrap().reconnect_attempts += 1;
+            if let Ok(Ok((sink, stream, agreed))) = tokio::time::timeout(timeout, Self::open_socket(url, config)).await {
+                // A shutdown during the attempt wins over the fresh connection
+                if *self.shared.shutdown.borrow() {
+                    return None;
+                }
+                self.register_peer(client_info(url), sink, agreed);
+                self.shared.stats.lock().unwrap().reconnects += 1;
+                self.set_state(ConnectionState::Connected).await;
+                return Some(stream);
+            }
+        }
+        None
+    }
+
+    async fn set_state(&self, next: ConnectionState) {
+        let Role::Client { state, state_handler, .. } = &self.shared.role else {
+            return;
+        };
+        if std::mem::replace(&mut *state.lock().unwrap(), next) == next {
+            return;
+        }
+        let handler = state_handler.read().unwrap().clone();
+        if let Some(handler) = handler {
+            handler(next).await;
+        }
+    }
+
+    /// Send message to a specific connection
+    ///
+    /// With a `coalesce_delay_ms`, a small envelope that is not urgent may be held and sent in a
+    /// batch; one that is not held sends the pending batch ahead of it, so envelopes keep their
+    /// order.
+    pub async fn send(&self, envelope: Envelope, connection_id: &str) -> Result<()> {
+        self.send_with_options(envelope, connection_id, &FrameOptions::default()).await
+    }
+
+    /// Send message to a specific connection on the logical stream in `options.stream_id`
+    ///
+    /// Without a stream ID (or with stream 0) the envelope goes out as an ordinary frame, ahead
+    /// of any chunks still queued for other streams. A `sequence` goes out in a binary frame for
+    /// the receiver to check the order by; the remaining frame options are not used by this
+    /// transport.
+    ///
+    /// Envelopes carrying a raw [`payload`](Envelope::payload) go out as a
+    /// [`BinaryFrame`](super::BinaryFrame), so tensors are not inflated by base64. Large envelopes
+    /// are chunked and interleaved with other streams, so a control message is not stuck behind a
+    /// multi-MB transfer. The receiver reports a gap in a stream's sequence numbers as
+    /// [`TransportEvent::SequenceGap`] and, with a `reorder_window`, holds later envelopes back
+    /// to deliver them in order.
+    pub async fn send_with_options(&self, envelope: Envelope, connection_id: &str, options: &FrameOptions) -> Result<()> {
+        let peer = self
+            .shared
+            .peers
+            .read()
+            .unwrap()
+            .get(connection_id)
+            .cloned()
+            .ok_or_else(|| UmicpError::connection(format!("Unknown connection: {}", connection_id)))?;
+
+        let (encoded, framed) = peer.serialize(&envelope, options)?;
+        let original = encoded.len();
+        if original > peer.max_payload_size {
+            return Err(UmicpError::validation(format!(
+                "Envelope of {} bytes exceeds the {} bytes {} accepts",
+                original, peer.max_payload_size, connection_id
+            )));
+        }
+        let (payload, compressed) = self.encode(encoded, peer.agreed.compression)?;
+        let bytes = payload.len();
+        let outgoing = match options.stream_id {
+            None | Some(0) => {
+                let message = envelope_message(payload, compressed || framed, peer.agreed.codec);
+                peer.envelope(message, envelope.priority())
+            }
+            Some(stream_id) => Outgoing::Stream(stream_id, payload),
+        };
+
+        if !peer.send(outgoing) {
+            return Err(UmicpError::connection(format!("Connection closed: {}", connection_id)));
+        }
+        self.record_sent(connection_id, bytes, compressed.then_some(original));
+        Ok(())
+    }
+
+    /// Send everything `reader` yields to a connection as a chunked transfer opened by `header`
+    ///
+    /// The source is read only as fast as the connection drains, so it can be far larger than
+    /// memory. The transfer gets a mux stream of its own and other envelopes keep flowing between
+    /// chunks. Returns the number of bytes sent; if
+    /// reading fails the transfer is aborted and the receiver sees the error. A transfer still
+    /// running when the transport shuts down is aborted too.
+    pub async fn send_stream<R>(&self, header: Envelope, reader: R, connection_id: &str) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.send_stream_cancellable(header, reader, connection_id, &self.shared.cancel).await
+    }
+
+    /// [`send_stream`](Self::send_stream), aborting the transfer once `cancel` is cancelled
+    ///
+    /// Chunks are only ever queued whole; on cancellation the receiver is sent an abort and the
+    /// call fails with [`UmicpError::Cancelled`].
+    pub async fn send_stream_cancellable<R>(
+        &self,
+        header: Envelope,
+        mut reader: R,
+        connection_id: &str,
+        cancel: &CancellationToken,
+    ) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let peer = self
+            .shared
+            .peers
+            .read()
+            .unwrap()
+            .get(connection_id)
+            .cloned()
+            .ok_or_else(|| UmicpError::connection(format!("Unknown connection: {}", connection_id)))?;
+        let offset = self.shared.next_transfer.fetch_add(1, Ordering::Relaxed) % transfer::FIRST_STREAM_ID;
+        let stream_id = transfer::FIRST_STREAM_ID + offset;
+
+        // Leaves room for the chunk envelope within what the peer accepts
+        let chunk_size = transfer::CHUNK_SIZE.min(peer.max_payload_size.saturating_sub(4096).max(4096));
+
+        if cancel.is_cancelled() {
+            return Err(UmicpError::cancelled(format!("Transfer to {} cancelled", connection_id)));
+        }
+        let header = transfer::start(header);
+        let sending = async {
+            self.queue_transfer_part(&peer, connection_id, stream_id, &header).await?;
+            let mut sent = 0u64;
+            loop {
+                let mut chunk = vec![0; chunk_size];
+                let mut filled = 0;
+                let failure = loop {
+                    match reader.read(&mut chunk[filled..]).await {
+                        Ok(0) => break None,
+                        Ok(read) => {
+                            filled += read;
+                            if filled == chunk.len() {
+                                break None;
+                            }
+                        }
+                        Err(error) => break Some(error),
+                    }
+                };
+                chunk.truncate(filled);
+                let last = failure.is_some() || filled < chunk_size;
+                if filled > 0 {
+                    sent += filled as u64;
+                    let part = transfer::part(&header, transfer::Part::Chunk, Some(chunk));
+                    self.queue_transfer_part(&peer, connection_id, stream_id, &part).await?;
+                }
+                if let Some(error) = failure {
+                    let abort = transfer::part(&header, transfer::Part::Abort(error.to_string()), None);
+                    let _ = self.queue_transfer_part(&peer, connection_id, stream_id, &abort).await;
+                    return Err(error.into());
+                }
+                if last {
+                    break;
+                }
+            }
+            let end = transfer::part(&header, transfer::Part::End(sent), None);
+            self.queue_transfer_part(&peer, connection_id, stream_id, &end).await?;
+            Ok(sent)
+        };
+        match cancel.run_until_cancelled(sending).await {
+            Some(result) => result,
+            None => {
+                // Nothing is left half queued; the receiver learns the transfer is over
+                let abort = transfer::part(&header, transfer::Part::Abort("Transfer cancelled".to_string()), None);
+                let _ = self.push_transfer_part(&peer, connection_id, stream_id, &abort);
+                Err(UmicpError::cancelled(format!("Transfer to {} cancelled", connection_id)))
+            }
+        }
+    }
+
+    /// Queue one envelope of a transfer, then wait until the connection has room for more
+    async fn queue_transfer_part(
+        &self,
+        peer: &Peer,
+        conn_id: &str,
+        stream_id: u32,
+        envelope: &Envelope,
+    ) -> Result<()> {
+        self.push_transfer_part(peer, conn_id, stream_id, envelope)?;
+        peer.backlog.wait_for_room(|| peer.sender.is_closed()).await
+    }
+
+    /// Queue one envelope of a transfer
+    fn push_transfer_part(&self, peer: &Peer, conn_id: &str, stream_id: u32, envelope: &Envelope) -> Result<()> {
+        let encoded = frame::encode(envelope, &FrameOptions::default(), peer.agreed.codec)?;
+        let original = encoded.len();
+        let (payload, compressed) = self.encode(encoded, peer.agreed.compression)?;
+        let bytes = payload.len();
+        if !peer.send(Outgoing::Stream(stream_id, payload)) {
+            return Err(UmicpError::connection("Connection closed during transfer"));
+        }
+        self.record_sent(conn_id, bytes, compressed.then_some(original));
+        Ok(())
+    }
+
+    /// Receive the transfers peers start with [`send_stream`](Self::send_stream)
+    ///
+    /// Each call replaces the previous receiver. Transfers that arrive while there is none are
+    /// dropped and reported as an `Error` event. A transfer whose chunks are not consumed holds
+    /// back the rest of its connection once a few chunks are buffered.
+    pub fn incoming_streams(&self) -> mpsc::UnboundedReceiver<IncomingStream> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        *self.shared.stream_listener.lock().unwrap() = Some(sender);
+        receiver
+    }
+
+    /// Send an envelope to every connection
+    pub async fn broadcast(&self, envelope: Envelope) -> Result<usize> {
+        self.broadcast_filtered(envelope, |_| true).await
+    }
+
+    /// Send an envelope to every connection whose ID passes `filter`
+    ///
+    /// The envelope is encoded once per serialization and compressed at most once per algorithm;
+    /// each peer gets a copy of the finished frame. Returns how many connections it was queued
+    /// for, skipping any that closed meanwhile, accept no envelopes that large, or registered a
+    /// [`SubscriptionFilter`] it does not match.
+    pub async fn broadcast_filtered<F>(&self, envelope: Envelope, filter: F) -> Result<usize>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let (peers, unsubscribed): (Vec<_>, Vec<_>) = self
+            .shared
+            .peers
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(conn_id, _)| filter(conn_id))
+            .map(|(conn_id, peer)| (conn_id.clone(), peer.clone()))
+            .partition(|(_, peer)| peer.filter.as_ref().is_none_or(|filter| filter.matches(&envelope)));
+        if !unsubscribed.is_empty() {
+            self.shared.stats.lock().unwrap().filtered_messages += unsubscribed.len() as u64;
+        }
+
+        // One encoding per serialization and one frame per serialization and algorithm, usually
+        // just a few
+        type Serialization = (EnvelopeCodec, bool);
+        let mut encodings: Vec<(Serialization, Vec<u8>, bool)> = Vec::new();
+        let mut frames: Vec<(Serialization, Compression, Message, usize, bool)> = Vec::new();
+        let mut delivered = 0;
+        for (peer_id, peer) in peers {
+            let serialization = (peer.agreed.codec, peer.binary_frames);
+            let compression = peer.agreed.compression;
+            let index = match frames.iter().position(|(s, c, ..)| (*s, *c) == (serialization, compression)) {
+                Some(index) => index,
+                None => {
+                    let (encoded, framed) = match encodings.iter().find(|(s, ..)| *s == serialization) {
+                        Some((_, encoded, framed)) => (encoded.clone(), *framed),
+                        None => {
+                            let (encoded, framed) = peer.serialize(&envelope, &FrameOptions::default())?;
+                            encodings.push((serialization, encoded.clone(), framed));
+                            (encoded, framed)
+                        }
+                    };
+                    let original = encoded.len();
+                    let (payload, compressed) = self.encode(encoded, compression)?;
+                    let message = envelope_message(payload, compressed || framed, peer.agreed.codec);
+                    frames.push((serialization, compression, message, original, compressed));
+                    frames.len() - 1
+                }
+            };
+            let (_, _, message, original, compressed) = &frames[index];
+            if *original > peer.max_payload_size {
+                continue;
+            }
+            if peer.send(peer.envelope(message.clone(), envelope.priority())) {
+                self.record_sent(&peer_id, message.len(), compressed.then_some(*original));
+                delivered += 1;
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// Payload for an encoded envelope on a connection using `compression`, and whether it was
+    /// compressed
+    fn encode(&self, encoded: Vec<u8>, compression: Compression) -> Result<(Vec<u8>, bool)> {
+        if compression != Compression::None && encoded.len() >= self.shared.compression_threshold {
+            Ok((compression::compress(compression, &encoded)?, true))
+        } else {
+            Ok((encoded, false))
+        }
+    }
+
+    /// Count a message sent on `conn_id`; `original` is its uncompressed size when compression
+    /// was used
+    fn record_sent(&self, conn_id: &str, bytes: usize, original: Option<usize>) {
+        let mut stats = self.shared.stats.lock().unwrap();
+        stats.messages_sent += 1;
+        stats.bytes_sent += bytes as u64;
+        if let Some(original) = original {
+            stats.compressed_bytes_sent += bytes as u64;
+            stats.uncompressed_bytes_sent += original as u64;
+        }
+        if let Some(connection) = stats.connections.get_mut(conn_id) {
+            connection.messages_sent += 1;
+            connection.bytes_sent += bytes as u64;
+            if let Some(original) = original {
+                connection.compressed_bytes_sent += bytes as u64;
+                connection.uncompressed_bytes_sent += original as u64;
+            }
+        }
+    }
+
+    /// Send message to the connection registered as endpoint `name` (server mode)
+    ///
+    /// A server sending through the [`Transport`] trait without a connection ID delivers to the
+    /// endpoint named by the envelope's `to` the same way.
+    pub async fn send_to(&self, name: &str, envelope: Envelope) -> Result<()> {
+        let connection_id = self
+            .endpoint_connection(name)
+            .ok_or_else(|| UmicpError::connection(format!("Unknown endpoint: {}", name)))?;
+        self.send(envelope, &connection_id).await
+    }
+
+    /// Send message to server (client mode)
+    pub async fn send_to_server(&self, envelope: Envelope) -> Result<()> {
+        match &self.shared.role {
+            Role::Client { url, .. } => self.send(envelope, url).await,
+            Role::Server { .. } => Err(UmicpError::transport("send_to_server is only available in client mode")),
+        }
+    }
+
+    /// Get transport statistics
+    ///
+    /// [`TransportStats::connections`] breaks traffic, compression and errors down per open
+    /// connection, with how many messages and stream bytes are queued for it. Errors reported as
+    /// `Error` events are also counted by [`UmicpError::category`], and clients count their
+    /// reconnections.
+    pub async fn get_stats(&self) -> TransportStats {
+        let mut stats = self.shared.stats.lock().unwrap().clone();
+        stats.uptime_seconds = self.shared.started.elapsed().as_secs();
+        if let Some(latency) = self.shared.latency.lock().unwrap().overall.stats() {
+            stats.avg_latency_ms = Some(latency.avg_ms);
+            stats.latency_p50_ms = Some(latency.p50_ms);
+            stats.latency_p95_ms = Some(latency.p95_ms);
+            stats.latency_p99_ms = Some(latency.p99_ms);
+        }
+        for (conn_id, peer) in self.shared.peers.read().unwrap().iter() {
+            if let Some(connection) = stats.connections.get_mut(conn_id) {
+                connection.queued_messages = peer.queued.load(Ordering::Relaxed) as u64;
+                connection.queued_stream_bytes = peer.backlog.bytes() as u64;
+            }
+        }
+        stats
+    }
+
+    /// Statistics of an open connection; the same figures [`get_stats`](Self::get_stats) lists
+    /// for every connection
+    pub async fn connection_stats(&self, connection_id: &str) -> Option<ConnectionStats> {
+        self.get_stats().await.connections.remove(connection_id)
+    }
+
+    /// Zero the counters in [`get_stats`](Self::get_stats), overall and per connection
+    ///
+    /// Figures describing the present, such as open connections and queue depths, are kept, as
+    /// are latency samples.
+    pub fn reset_stats(&self) {
+        self.shared.stats.lock().unwrap().reset();
+    }
+
+    /// Round-trip times sampled on an open connection, once its first pong has arrived
+    pub fn connection_latency(&self, connection_id: &str) -> Option<LatencyStats> {
+        self.shared.latency.lock().unwrap().connections.get(connection_id)?.stats()
+    }
+
+    /// Ping a connection now instead of waiting for the next heartbeat
+    ///
+    /// The round-trip time is recorded when the pong comes back.
+    pub fn ping(&self, connection_id: &str) -> Result<()> {
+        let peer = self
+            .shared
+            .peers
+            .read()
+            .unwrap()
+            .get(connection_id)
+            .cloned()
+            .ok_or_else(|| UmicpError::connection(format!("Unknown connection: {}", connection_id)))?;
+        match peer.send(ping_message(self.shared.started)) {
+            true => Ok(()),
+            false => Err(UmicpError::connection(format!("Connection closed: {}", connection_id))),
+        }
+    }
+
+    /// Record the RTT carried by a pong echoing one of our pings
+    fn record_pong(&self, conn_id: &str, payload: &[u8]) {
+        let Ok(sent) = <[u8; 8]>::try_from(payload) else {
+            return;
+        };
+        let sent = Duration::from_micros(u64::from_be_bytes(sent));
+        let Some(rtt) = self.shared.started.elapsed().checked_sub(sent) else {
+            return;
+        };
+        let mut latency = self.shared.latency.lock().unwrap();
+        latency.overall.record(rtt);
+        // Late pongs from a dropped connection must not resurrect its entry
+        if self.shared.peers.read().unwrap().contains_key(conn_id) {
+            latency.connections.entry(conn_id.to_string()).or_default().record(rtt);
+        }
+    }
+
+    /// Token cancelled when the transport shuts down
+    ///
+    /// Handlers and transfers still running at shutdown are dropped; tasks they spawned can watch
+    /// this token to stop along with them.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shared.cancel.child_token()
+    }
+
+    /// Shutdown the transport, closing every connection and stopping `run()`
+    ///
+    /// Cancels the [`shutdown_token`](Self::shutdown_token): handlers still running are dropped
+    /// and transfers in progress are aborted.
+    pub async fn shutdown(&self) -> Result<()> {
+        self.shared.shutdown.send_replace(true);
+        self.shared.cancel.cancel();
+
+        let peers: Vec<_> = self.shared.peers.write().unwrap().drain().collect();
+        for (_, peer) in peers {
+            peer.send(Outgoing::Message(Message::Close(None), MessagePriority::Low));
+        }
+        {
+            let mut stats = self.shared.stats.lock().unwrap();
+            stats.connections.clear();
+            stats.active_connections = 0;
+            for shard in &mut stats.shards {
+                shard.active_connections = 0;
+            }
+        }
+        self.shared.latency.lock().unwrap().connections.clear();
+        self.shared.connections.lock().unwrap().clear();
+        Ok(())
+    }
+
+    async fn accept_loop(&self, listeners: Vec<TcpListener>) -> Result<()> {
+        let shards = self.start_shards()?;
+        let mut shutdown = self.shared.shutdown.subscribe();
+        let mut accepted_count = 0;
+        let mut tls_check = self.tls_reload_ticker();
+        loop {
+            let accepted = tokio::select! {
+                accepted = self.accept(&listeners) => accepted,
+                _ = tick(tls_check.as_mut()) => {
+                    self.reload_tls_if_changed();
+                    continue;
+                }
+                _ = shutdown.wait_for(|stopped| *stopped) => return Ok(()),
+            };
+            let stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(error) => {
+                    // The listener is still open; failing here would stop the whole server
+                    let backoff = accept_backoff(&error);
+                    self.shared.report(None, error);
+                    match self.shared.cancel.run_until_cancelled(tokio::time::sleep(backoff)).await {
+                        Some(()) => continue,
+                        None => return Ok(()),
+                    }
+                }
+            };
+            // Counted here rather than in the task so the limit holds for bursts of accepts
+            let serving = self.shared.serving.fetch_add(1, Ordering::SeqCst);
+            let admitted = self.shared.max_connections == 0 || serving < self.shared.max_connections;
+            let transport = self.clone();
+            if shards.is_empty() {
+                tokio::spawn(async move {
+                    transport.serve_connection(stream, admitted, None).await;
+                    transport.shared.serving.fetch_sub(1, Ordering::SeqCst);
+                });
+                continue;
+            }
+
+            let index = accepted_count % shards.len();
+            accepted_count += 1;
+            // Moved off this reactor so the connection's I/O runs on the shard's thread
+            let stream = match stream.into_std() {
+                Ok(stream) => stream,
+                Err(_) => {
+                    self.shared.serving.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+            };
+            let alive = shards[index].alive.clone();
+            shards[index].runtime.spawn(async move {
+                if let Ok(stream) = TcpStream::from_std(stream) {
+                    transport.serve_connection(stream, admitted, Some(index)).await;
+                }
+                transport.shared.serving.fetch_sub(1, Ordering::SeqCst);
+                drop(alive);
+            });
+        }
+    }
+
+    /// Next connection on any of `listeners`
+    async fn accept(&self, listeners: &[TcpListener]) -> std::io::Result<(TcpStream, SocketAddr)> {
+        #[cfg(test)]
+        {
+            let faults = &self.shared.accept_faults;
+            if faults.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| left.checked_sub(1)).is_ok() {
+                // EMFILE, as when the process runs out of file descriptors
+                return Err(std::io::Error::from_raw_os_error(24));
+            }
+        }
+        accept_any(listeners).await
+    }
+
+    /// Ticker for checking the TLS files for changes, on TLS servers with a reload interval
+    fn tls_reload_ticker(&self) -> Option<tokio::time::Interval> {
+        #[cfg(feature = "tls")]
+        if let Role::Server { tls: Some(_), tls_reload_interval, .. } = &self.shared.role {
+            if !tls_reload_interval.is_zero() {
+                let start = tokio::time::Instant::now() + *tls_reload_interval;
+                return Some(tokio::time::interval_at(start, *tls_reload_interval));
+            }
+        }
+        None
+    }
+
+    /// Reload the TLS certificate if its files changed, reporting failures as error events
+    fn reload_tls_if_changed(&self) {
+        #[cfg(feature = "tls")]
+        if let Role::Server { tls: Some(tls), .. } = &self.shared.role {
+            if let Err(error) = tls.reload_if_changed() {
+                self.shared.report(None, error);
+            }
+        }
+    }
+
+    /// Start the worker runtimes of a sharded server, each driven by a thread of its own until
+    /// the accept loop and every connection it handed the shard are done
+    fn start_shards(&self) -> Result<Vec<Shard>> {
+        let count = self.shared.stats.lock().unwrap().shards.len();
+        (0..count)
+            .map(|index| {
+                let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+                let (alive, mut finished) = mpsc::channel::<()>(1);
+                let shard = Shard {
+                    runtime: runtime.handle().clone(),
+                    alive,
+                };
+                std::thread::Builder::new()
+                    .name(format!("umicp-shard-{}", index))
+                    .spawn(move || runtime.block_on(finished.recv()))?;
+                Ok(shard)
+            })
+            .collect()
+    }
+
+    async fn serve_connection(&self, stream: TcpStream, admitted: bool, shard: Option<usize>) {
+        let now = chrono::Utc::now();
+        let address = |addr: std::io::Result<SocketAddr>| addr.map(|addr| addr.to_string()).unwrap_or_default();
+        let info = ConnectionInfo {
+            id: uuid::Uuid::new_v4().to_string(),
+            remote_addr: address(stream.peer_addr()),
+            local_addr: address(stream.local_addr()),
+            connected_at: now,
+            last_activity: now,
+            principal: None,
+            capabilities: None,
+            shard,
+            idle_ms: 0,
+            endpoints: Vec::new(),
+            server_name: None,
+        };
+        #[cfg(feature = "tls")]
+        if let Role::Server { tls: Some(tls), .. } = &self.shared.role {
+            match tls.acceptor().accept(stream).await {
+                Ok(stream) => {
+                    let server_name = stream.get_ref().1.server_name().map(str::to_ascii_lowercase);
+                    let info = ConnectionInfo { server_name, ..info };
+                    self.serve_socket(stream, info, admitted).await
+                }
+                Err(e) if super::tls::is_pin_mismatch(&e) => self.shared.report(
+                    None,
+                    UmicpError::pin_mismatch(format!("TLS handshake with {} failed on a pin", info.remote_addr)),
+                ),
+                Err(_) => {}
+            }
+            return;
+        }
+        self.serve_socket(stream, info, admitted).await;
+    }
+
+    async fn serve_socket<S>(&self, stream: S, mut info: ConnectionInfo, admitted: bool)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        if !admitted {
+            self.shared.stats.lock().unwrap().rejected_connections += 1;
+            // Upgraded anyway so the client gets a reason it can act on
+            let mut codec = EnvelopeCodec::default();
+            #[allow(clippy::result_large_err)]
+            let negotiate = |request: &Request, mut response: Response| {
+                codec = self.negotiate_codec(request, &mut response)?;
+                Ok(response)
+            };
+            if let Ok(mut socket) = tokio_tungstenite::accept_hdr_async(stream, negotiate).await {
+                let limit = self.shared.max_connections;
+                let error = UmicpError::transport(format!("Server is at its limit of {} connections", limit));
+                if let Ok(encoded) = self.overloaded_notice(&info.id, &error).encode(codec) {
+                    let _ = socket.send(envelope_message(encoded, false, codec)).await;
+                }
+                let close = CloseFrame {
+                    code: CloseCode::Again,
+                    reason: "Too many connections".into(),
+                };
+                let _ = socket.send(Message::Close(Some(close))).await;
+            }
+            return;
+        }
+        let validator = self.shared.token_validator.read().unwrap().clone();
+        let mut agreed = Agreement::default();
+        // tungstenite fixes the callback's error type
+        #[allow(clippy::result_large_err)]
+        let negotiate = |request: &Request, mut response: Response| {
+            if let (Some(validator), Some(header)) = (&validator, request.headers().get(AUTHORIZATION)) {
+                let token = header.to_str().ok().and_then(|header| header.strip_prefix("Bearer "));
+                match token.map(|token| validator.validate(token.trim())) {
+                    Some(Ok(principal)) => info.principal = Some(principal),
+                    rejected => {
+                        let error = match rejected {
+                            Some(Err(error)) => error,
+                            _ => UmicpError::authentication("Authorization header is not a bearer token"),
+                        };
+                        self.shared.report_auth_failure(&info.id, &error);
+                        let mut rejection = ErrorResponse::new(Some(error.to_string()));
+                        *rejection.status_mut() = StatusCode::UNAUTHORIZED;
+                        return Err(rejection);
+                    }
+                }
+            }
+            agreed.codec = self.negotiate_codec(request, &mut response)?;
+            let offer = request.headers().get(COMPRESSION_HEADER).and_then(|offer| offer.to_str().ok());
+            if let (true, Some(offer)) = (self.shared.compression_enabled, offer) {
+                agreed.compression = compression::negotiate(offer);
+                if agreed.compression != Compression::None {
+                    let answer = HeaderValue::from_static(compression::answer(agreed.compression));
+                    response.headers_mut().insert(COMPRESSION_HEADER, answer);
+                }
+            }
+            Ok(response)
+        };
+
+        // A failed handshake only affects that peer
+        let socket = match tokio_tungstenite::accept_hdr_async(stream, negotiate).await {
+            Ok(socket) => socket,
+            Err(_) => return,
+        };
+        let (mut sink, mut stream) = socket.split();
+
+        if let (Some(validator), None) = (&validator, &info.principal) {
+            match self.authenticate_first_envelope(&mut stream, validator.as_ref(), agreed.codec).await {
+                Ok((principal, capabilities)) => {
+                    info.principal = Some(principal);
+                    info.capabilities = capabilities;
+                }
+                Err(error) => {
+                    let close = CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: error.to_string().into(),
+                    };
+                    self.shared.report_auth_failure(&info.id, &error);
+                    let _ = sink.send(Message::Close(Some(close))).await;
+                    return;
+                }
+            }
+        }
+
+        let conn_id = info.id.clone();
+        if let Some(principal) = &info.principal {
+            self.shared.subscribers.notify(TransportEvent::Authenticated {
+                connection_id: conn_id.clone(),
+                subject: principal.subject.clone(),
+            });
+        }
+        self.register_peer(info, sink, agreed);
+        self.notify_connection(true, conn_id.clone()).await;
+        self.read_loop(stream, conn_id.clone()).await;
+        self.drop_peer(&conn_id).await;
+    }
+
+    /// Pick the codec for a connection from the subprotocols its client offers, answering with
+    /// the choice; clients that offer none speak JSON
+    #[allow(clippy::result_large_err)]
+    fn negotiate_codec(
+        &self,
+        request: &Request,
+        response: &mut Response,
+    ) -> std::result::Result<EnvelopeCodec, ErrorResponse> {
+        let offer = match request.headers().get(SEC_WEBSOCKET_PROTOCOL) {
+            Some(offer) => offer.to_str().unwrap_or_default(),
+            None => return Ok(EnvelopeCodec::Json),
+        };
+        let chosen = offer
+            .split(',')
+            .filter_map(|name| EnvelopeCodec::from_subprotocol(name.trim()))
+            .find(|codec| self.shared.codecs.contains(codec) && EnvelopeCodec::supported().contains(codec));
+        match chosen {
+            Some(codec) => {
+                response
+                    .headers_mut()
+                    .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(codec.subprotocol()));
+                Ok(codec)
+            }
+            None => {
+                let spoken = self.shared.codecs.iter().map(|codec| codec.subprotocol()).collect::<Vec<_>>();
+                let error = UmicpError::transport(format!(
+                    "None of the offered subprotocols ({}) is supported; this server speaks {}",
+                    offer,
+                    spoken.join(", ")
+                ));
+                let message = error.to_string();
+                self.shared.report(None, error);
+                let mut rejection = ErrorResponse::new(Some(message));
+                *rejection.status_mut() = StatusCode::BAD_REQUEST;
+                Err(rejection)
+            }
+        }
+    }
+
+    /// Unsolicited `Error` envelope telling a connection it was refused for load
+    fn overloaded_notice(&self, conn_id: &str, error: &UmicpError) -> Envelope {
+        let from = self.local_addr().map(|addr| addr.to_string()).unwrap_or_default();
+        let mut notice = Envelope::new();
+        notice.set_from(&from);
+        notice.set_to(conn_id);
+        notice.set_operation(OperationType::Error);
+        notice.add_capability(super::ERROR_CAPABILITY, &error.to_string());
+        notice.add_capability(super::ERROR_CODE_CAPABILITY, "overloaded");
+        notice
+    }
+
+    /// Wait for the `Control` envelope carrying the token of a client that sent no header,
+    /// along with the client's handshake if that came first
+    async fn authenticate_first_envelope<S>(
+        &self,
+        stream: &mut S,
+        validator: &dyn TokenValidator,
+        codec: EnvelopeCodec,
+    ) -> Result<(Principal, Option<PeerCapabilities>)>
+    where
+        S: Stream<Item = std::result::Result<Message, WsError>> + Unpin,
+    {
+        let deadline = tokio::time::Instant::now() + self.shared.auth_timeout;
+        let mut capabilities = None;
+        loop {
+            let frame = tokio::time::timeout_at(deadline, stream.next())
+                .await
+                .map_err(|_| UmicpError::authentication("No credentials presented in time"))?;
+            let bytes = match frame {
+                Some(Ok(Message::Text(text))) => text.into_bytes(),
+                Some(Ok(Message::Binary(bytes))) => bytes,
+                _ => return Err(UmicpError::authentication("Expected an authentication envelope")),
+            };
+            let bytes = compression::decompress(&bytes, self.shared.max_payload_size)?.unwrap_or(bytes);
+            let envelope = frame::decode_envelope(&bytes, codec)?;
+            if capabilities.is_none() {
+                if let Some(advertised) = handshake::parse(&envelope) {
+                    capabilities = Some(advertised);
+                    continue;
+                }
+            }
+            let token = envelope
+                .capabilities()
+                .filter(|_| envelope.operation() == OperationType::Control)
+                .and_then(|capabilities| capabilities.get(AUTH_CAPABILITY))
+                .ok_or_else(|| UmicpError::authentication("Expected an authentication envelope"))?;
+            return Ok((validator.validate(token)?, capabilities));
+        }
+    }
+
+    fn register_peer<S>(&self, info: ConnectionInfo, mut sink: S, agreed: Agreement)
+    where
+        S: Sink<Message> + Unpin + Send + 'static,
+    {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Outgoing>();
+        let backlog = Arc::new(transfer::Backlog::default());
+        let written = backlog.clone();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let dequeued = queued.clone();
+        let max_burst = self.shared.max_priority_burst;
+        let coalesce_delay = self.shared.coalesce_delay;
+        let coalesce_max_bytes = self.shared.coalesce_max_bytes;
+        // Only for counting batches; the writer must not keep the transport alive
+        let shared = Arc::downgrade(&self.shared);
+        let started = self.shared.started;
+        let activity = Arc::new(Activity::new(started.elapsed().as_millis() as u64));
+        let sent = activity.clone();
+        tokio::spawn(async move {
+            // Each message with whether it may be coalesced
+            let mut messages = PriorityQueue::new(max_burst);
+            let mut scheduler = mux::Scheduler::default();
+            let mut coalescer = Coalescer::new(coalesce_delay, coalesce_max_bytes);
+            // Held back until every message queued before it is written
+            let mut closing = None;
+            loop {
+                // Take everything already queued before writing, so the most urgent message
+                // goes next and plain messages overtake pending stream data; only block when idle
+                if closing.is_none() {
+                    let mut outgoing = match messages.is_empty() && scheduler.is_empty() {
+                        true => tokio::select! {
+                            outgoing = receiver.recv() => match outgoing {
+                                Some(outgoing) => Some(outgoing),
+                                None => break,
+                            },
+                            _ = sleep_until(coalescer.deadline()) => None,
+                        },
+                        false => receiver.try_recv().ok(),
+                    };
+                    while let Some(next) = outgoing {
+                        match next {
+                            Outgoing::Message(message @ Message::Close(_), _) => {
+                                closing = Some(message);
+                                break;
+                            }
+                            Outgoing::Message(message, priority) => messages.push(priority, (message, false)),
+                            Outgoing::Envelope(message, priority) => {
+                                let coalesce = !coalesce_delay.is_zero() && coalescer.accepts(&message);
+                                messages.push(priority, (message, coalesce));
+                            }
+                            Outgoing::Stream(stream_id, data) => scheduler.push(stream_id, data),
+                        }
+                        outgoing = receiver.try_recv().ok();
+                    }
+                }
+
+                // A batch goes out once full or due, and before any message that is not
+                // coalesced, so envelopes keep their order; pings do not wait for it
+                let popped = messages.pop();
+                let coalesced = matches!(popped, Some((_, true)));
+                let (next, flush) = match popped {
+                    Some((message, true)) => (None, coalescer.push(message, Instant::now())),
+                    Some((message, false)) => {
+                        let flush = !matches!(message, Message::Ping(_));
+                        (Some(message), flush)
+                    }
+                    None => (None, closing.is_some()),
+                };
+                let due = coalescer.deadline().is_some_and(|deadline| Instant::now() >= deadline);
+                if flush || due {
+                    let count = coalescer.len() as u64;
+                    if let Some(batch) = coalescer.take() {
+                        if sink.send(batch).await.is_err() {
+                            break;
+                        }
+                        dequeued.fetch_sub(count as usize, Ordering::Relaxed);
+                        sent.sent.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+                        if let Some(shared) = shared.upgrade().filter(|_| count > 1) {
+                            let mut stats = shared.stats.lock().unwrap();
+                            stats.batches_sent += 1;
+                            stats.coalesced_messages += count;
+                        }
+                    }
+                }
+                if coalesced {
+                    continue;
+                }
+
+                if let Some(message) = next {
+                    let traffic = !matches!(message, Message::Ping(_));
+                    if sink.send(message).await.is_err() {
+                        break;
+                    }
+                    dequeued.fetch_sub(1, Ordering::Relaxed);
+                    if traffic {
+                        sent.sent.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+                    }
+                } else if let Some(close) = closing.take() {
+                    let _ = sink.send(close).await;
+                    break;
+                } else if let Some(frame) = scheduler.next_frame() {
+                    let chunk = frame.len() - mux::HEADER_LEN;
+                    if sink.send(Message::Binary(frame)).await.is_err() {
+                        break;
+                    }
+                    written.release(chunk);
+                    sent.sent.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+                }
+            }
+            let _ = sink.close().await;
+            // Closed first, so woken transfer senders see the connection is gone
+            drop(receiver);
+            written.wake();
+        });
+
+        let mut peer = Peer {
+            sender,
+            agreed,
+            queued,
+            backlog,
+            binary_frames: false,
+            max_payload_size: usize::MAX,
+            batches: false,
+            filter: None,
+            activity,
+        };
+
+        if !self.shared.ping_interval.is_zero() {
+            let pings = peer.clone();
+            let interval = self.shared.ping_interval;
+            let started = self.shared.started;
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+                // Stops once the writer task is gone
+                loop {
+                    ticker.tick().await;
+                    if !pings.send(ping_message(started)) {
+                        break;
+                    }
+                }
+            });
+        }
+
+        // Our handshake goes out first; it is not counted as a message
+        let hello = handshake::hello(&self.shared.capabilities);
+        if let Ok(encoded) = hello.encode(agreed.codec) {
+            let message = envelope_message(encoded, false, agreed.codec);
+            peer.send(Outgoing::Message(message, MessagePriority::High));
+        }
+        // Followed by the client's filter, so the server applies it before broadcasting to it
+        let filter = self.shared.subscription_filter.read().unwrap().clone();
+        if let Some(filter) = filter {
+            if let Ok(encoded) = filter::request(Some(&filter)).and_then(|request| request.encode(agreed.codec)) {
+                let message = envelope_message(encoded, false, agreed.codec);
+                peer.send(Outgoing::Message(message, MessagePriority::High));
+            }
+        }
+        // And by its endpoint names, so the server can address it by them again
+        let names = self.shared.endpoint_names.read().unwrap().clone();
+        if !names.is_empty() {
+            if let Ok(encoded) = endpoint::request(&names).encode(agreed.codec) {
+                let message = envelope_message(encoded, false, agreed.codec);
+                peer.send(Outgoing::Message(message, MessagePriority::High));
+            }
+        }
+        if let Some(capabilities) = &info.capabilities {
+            peer.adopt(capabilities, &self.shared.capabilities.codecs);
+        }
+
+        let conn_id = info.id.clone();
+        let shard = info.shard;
+        self.shared.connections.lock().unwrap().insert(conn_id.clone(), info);
+        self.shared.peers.write().unwrap().insert(conn_id.clone(), peer);
+        let mut stats = self.shared.stats.lock().unwrap();
+        stats.connections.insert(conn_id, ConnectionStats::default());
+        stats.active_connections += 1;
+        stats.total_connections += 1;
+        if let Some(shard) = shard {
+            stats.shards[shard].active_connections += 1;
+            stats.shards[shard].total_connections += 1;
+        }
+    }
+
+    /// Record a peer's handshake and serialize what follows to suit it
+    fn adopt_capabilities(&self, conn_id: &str, capabilities: PeerCapabilities) {
+        if let Some(peer) = self.shared.peers.write().unwrap().get_mut(conn_id) {
+            peer.adopt(&capabilities, &self.shared.capabilities.codecs);
+        }
+        if let Some(info) = self.shared.connections.lock().unwrap().get_mut(conn_id) {
+            info.capabilities = Some(capabilities);
+        }
+    }
+
+    async fn drop_peer(&self, conn_id: &str) {
+        let shard = self.connection_info(conn_id).and_then(|info| info.shard);
+        // Already gone if shutdown() drained it
+        if self.shared.peers.write().unwrap().remove(conn_id).is_some() {
+            let mut stats = self.shared.stats.lock().unwrap();
+            stats.connections.remove(conn_id);
+            stats.active_connections = stats.active_connections.saturating_sub(1);
+            if let Some(shard) = shard {
+                let shard = &mut stats.shards[shard];
+                shard.active_connections = shard.active_connections.saturating_sub(1);
+            }
+        }
+        self.shared.latency.lock().unwrap().connections.remove(conn_id);
+        self.shared.endpoints.lock().unwrap().remove(conn_id);
+        self.shared.connections.lock().unwrap().remove(conn_id);
+        self.notify_connection(false, conn_id.to_string()).await;
+    }
+
+    async fn notify_connection(&self, connected: bool, conn_id: String) {
+        self.shared.subscribers.notify(match connected {
+            true => TransportEvent::Connected(conn_id.clone()),
+            false => TransportEvent::Disconnected(conn_id.clone()),
+        });
+        let handler = self.shared.connection_handler.read().unwrap().clone();
+        if let Some(handler) = handler {
+            handler(connected, conn_id).await;
+        }
+    }
+
+    async fn read_loop<S>(&self, mut stream: S, conn_id: String)
+    where
+        S: Stream<Item = std::result::Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        let mut shutdown = self.shared.shutdown.subscribe();
+        let mut reassembler = mux::Reassembler::new(self.shared.max_payload_size);
+        // Per-stream workers for this connection; dropping them at return ends the workers
+        let mut workers: HashMap<u32, mpsc::UnboundedSender<(Envelope, Option<OwnedSemaphorePermit>)>> = HashMap::new();
+        let mut transfers = transfer::Transfers::default();
+        let mut sequencer = Sequencer::new(self.shared.reorder_window, self.shared.reorder_timeout);
+        // Envelopes released by the sequencer, waiting to be dispatched
+        let mut ready = VecDeque::new();
+        // Messages unpacked from a batch, waiting to be decoded
+        let mut unbatched = VecDeque::new();
+        // Fixed for the connection's lifetime, so looked up once
+        let info = self.connection_info(&conn_id);
+        let server_name = info.as_ref().and_then(|info| info.server_name.clone());
+        let mut access = Access::new(self.principal(&conn_id), server_name);
+        let shard = info.as_ref().and_then(|info| info.shard);
+        // Quotas follow the principal across reconnections; anonymous peers are counted by address
+        let quota_key = match (&access.principal, info) {
+            (Some(principal), _) => principal.subject.clone(),
+            (None, Some(info)) => match info.remote_addr.parse::<SocketAddr>() {
+                Ok(addr) => addr.ip().to_string(),
+                Err(_) => info.remote_addr,
+            },
+            (None, None) => conn_id.clone(),
+        };
+        let (codec, activity) = match self.shared.peers.read().unwrap().get(&conn_id) {
+            Some(peer) => (peer.agreed.codec, Some(peer.activity.clone())),
+            None => (EnvelopeCodec::default(), None),
+        };
+        // Only servers drop idle connections; a client would just reconnect
+        let idle_timeout = match self.shared.role {
+            Role::Server { .. } if !self.shared.idle_timeout.is_zero() => Some(self.shared.idle_timeout),
+            _ => None,
+        };
+        let idle_deadline = || {
+            let (timeout, activity) = idle_timeout.zip(activity.as_ref())?;
+            Some(self.shared.started + Duration::from_millis(activity.last()) + timeout)
+        };
+        loop {
+            if let Some((stream_id, envelope)) = ready.pop_front() {
+                self.dispatch(envelope, stream_id, &conn_id, &mut access, &mut transfers, &mut workers).await;
+                continue;
+            }
+
+            let (stream_id, bytes) = match unbatched.pop_front() {
+                Some(bytes) => (0, bytes),
+                None => {
+                    let deadline = sequencer.deadline();
+                    let idle_at = idle_deadline();
+                    let frame = tokio::select! {
+                        frame = stream.next() => Some(frame),
+                        _ = sleep_until(deadline) => {
+                            ready.extend(sequencer.expire(Instant::now()));
+                            continue;
+                        }
+                        _ = sleep_until(idle_at) => None,
+                        _ = shutdown.wait_for(|stopped| *stopped) => return,
+                    };
+                    let Some(frame) = frame else {
+                        // Sends may have moved the deadline on while we slept
+                        if idle_deadline().is_some_and(|idle_at| Instant::now() >= idle_at) {
+                            self.close_idle(&conn_id).await;
+                            return;
+                        }
+                        continue;
+                    };
+
+                    match frame {
+                        Some(Ok(Message::Text(text))) => (0, text.into_bytes()),
+                        Some(Ok(Message::Binary(bytes))) => match mux::decode(&bytes) {
+                            Some((stream_id, fin, chunk)) => match reassembler.push(stream_id, fin, chunk) {
+                                Some(message) => (stream_id, message),
+                                None => continue,
+                            },
+                            None => (0, bytes),
+                        },
+                        Some(Err(error)) => {
+                            self.shared.report(Some(&conn_id), error);
+                            return;
+                        }
+                        Some(Ok(Message::Pong(payload))) => {
+                            self.record_pong(&conn_id, &payload);
+                            continue;
+                        }
+                        Some(Ok(Message::Close(_))) | None => return,
+                        Some(Ok(_)) => continue,
+                    }
+                }
+            };
+            if batch::is_batch(&bytes) {
+                match batch::decode(&bytes) {
+                    Ok(messages) => unbatched.extend(messages),
+                    Err(error) => self.shared.report(Some(&conn_id), error),
+                }
+                continue;
+            }
+            let wire_bytes = bytes.len() as u64;
+            let (bytes, compressed) = match compression::decompress(&bytes, self.shared.max_payload_size) {
+                Ok(Some(decompressed)) => (decompressed, true),
+                Ok(None) => (bytes, false),
+                Err(error) => {
+                    self.shared.report(Some(&conn_id), error);
+                    continue;
+                }
+            };
+
+            // Frames that are not valid envelopes are dropped rather than closing the connection
+            let (envelope, options) = match frame::decode_message(&bytes, codec) {
+                Ok(decoded) => decoded,
+                Err(error) => {
+                    self.shared.report(Some(&conn_id), error);
+                    continue;
+                }
+            };
+
+            if let Some(capabilities) = handshake::parse(&envelope) {
+                self.adopt_capabilities(&conn_id, capabilities);
+                continue;
+            }
+
+            {
+                let mut stats = self.shared.stats.lock().unwrap();
+                stats.messages_received += 1;
+                stats.bytes_received += wire_bytes;
+                if compressed {
+                    stats.compressed_bytes_received += wire_bytes;
+                    stats.uncompressed_bytes_received += bytes.len() as u64;
+                }
+                if let Some(shard) = shard {
+                    stats.shards[shard].messages_received += 1;
+                    stats.shards[shard].bytes_received += wire_bytes;
+                }
+                if let Some(connection) = stats.connections.get_mut(&conn_id) {
+                    connection.messages_received += 1;
+                    connection.bytes_received += wire_bytes;
+                    if compressed {
+                        connection.compressed_bytes_received += wire_bytes;
+                        connection.uncompressed_bytes_received += bytes.len() as u64;
+                    }
+                }
+            }
+            if let Some(info) = self.shared.connections.lock().unwrap().get_mut(&conn_id) {
+                info.last_activity = chrono::Utc::now();
+            }
+            if let Some(activity) = &activity {
+                activity.received.store(self.shared.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+            }
+
+            let quotas = self.shared.quotas.read().unwrap().clone();
+            if let Some(exceeded) = quotas.and_then(|quotas| quotas.charge(&quota_key, wire_bytes).err()) {
+                self.shared.stats.lock().unwrap().quota_rejections += 1;
+                let _ = self.send(exceeded.error_reply(&envelope), &conn_id).await;
+                self.shared.report(Some(&conn_id), exceeded.error());
+                continue;
+            }
+
+            if let Some(request) = filter::parse_request(&envelope) {
+                let reply = match request {
+                    Ok(filter) => {
+                        if let Some(peer) = self.shared.peers.write().unwrap().get_mut(&conn_id) {
+                            peer.filter = filter.map(Arc::new);
+                        }
+                        envelope.reply(OperationType::Ack)
+                    }
+                    Err(error) => {
+                        let reply = rpc::error_reply(&envelope, &error, Some("invalid_filter"));
+                        self.shared.report(Some(&conn_id), error);
+                        reply
+                    }
+                };
+                let _ = self.send(reply, &conn_id).await;
+                continue;
+            }
+
+            if let Some(request) = endpoint::parse_request(&envelope) {
+                let reply = match request {
+                    Ok(names) => {
+                        self.shared.endpoints.lock().unwrap().register(&conn_id, names);
+                        envelope.reply(OperationType::Ack)
+                    }
+                    Err(error) => {
+                        let reply = rpc::error_reply(&envelope, &error, Some("invalid_endpoint"));
+                        self.shared.report(Some(&conn_id), error);
+                        reply
+                    }
+                };
+                let _ = self.send(reply, &conn_id).await;
+                continue;
+            }
+
+            match options.sequence {
+                Some(sequence) => {
+                    // Numbered per logical stream: the frame's own, or the mux stream it came on
+                    let sequenced_stream = options.stream_id.unwrap_or(stream_id);
+                    let (released, gap) =
+                        sequencer.push(sequenced_stream, sequence, (stream_id, envelope), Instant::now());
+                    if let Some(gap) = gap {
+                        self.shared.stats.lock().unwrap().sequence_gaps += 1;
+                        self.shared.subscribers.notify(TransportEvent::SequenceGap {
+                            connection_id: conn_id.clone(),
+                            stream_id: gap.stream_id,
+                            expected: gap.expected,
+                            received: gap.received,
+                        });
+                    }
+                    ready.extend(released);
+                }
+                None => ready.push_back((stream_id, envelope)),
+            }
+        }
+    }
+
+    /// Tell an idle connection why it is being closed, then close it
+    async fn close_idle(&self, conn_id: &str) {
+        let mut notice = Envelope::new();
+        notice.set_from(&self.local_addr().map(|addr| addr.to_string()).unwrap_or_default());
+        notice.set_to(conn_id);
+        notice.set_operation(OperationType::Control);
+        notice.add_capability(IDLE_TIMEOUT_CAPABILITY, &self.shared.idle_timeout.as_secs().to_string());
+        let _ = self.send(notice, conn_id).await;
+
+        let peer = self.shared.peers.read().unwrap().get(conn_id).cloned();
+        if let Some(peer) = peer {
+            let close = CloseFrame {
+                code: CloseCode::Away,
+                reason: "Idle timeout".into(),
+            };
+            peer.send(Outgoing::Message(Message::Close(Some(close)), MessagePriority::Low));
+        }
+        self.shared.stats.lock().unwrap().idle_disconnects += 1;
+    }
+
+    /// Authorize an incoming envelope and hand it to its subscribers and handler
+    async fn dispatch(
+        &self,
+        envelope: Envelope,
+        stream_id: u32,
+        conn_id: &str,
+        access: &mut Access,
+        transfers: &mut transfer::Transfers,
+        workers: &mut HashMap<u32, mpsc::UnboundedSender<(Envelope, Option<OwnedSemaphorePermit>)>>,
+    ) {
+        let authorizer = self.shared.authorizer.read().unwrap().clone();
+        if let Err(error) = access.authorize(authorizer, &envelope) {
+            let reply = rpc::error_reply(&envelope, &error, Some("forbidden"));
+            self.shared.report(Some(conn_id), error);
+            let _ = self.send(reply, conn_id).await;
+            return;
+        }
+
+        if let Some((id, part)) = transfer::part_of(&envelope) {
+            let listener = self.shared.stream_listener.lock().unwrap().clone();
+            if let Err(error) = transfers.receive(id, part, envelope, conn_id, listener.as_ref()).await {
+                self.shared.report(Some(conn_id), error);
+            }
+            return;
+        }
+
+        // Held until the envelope has been handled
+        let permit = match &self.shared.handler_slots {
+            Some(slots) => match slots.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    self.shared.stats.lock().unwrap().shed_messages += 1;
+                    let error = UmicpError::transport("Server is overloaded; retry later");
+                    let _ = self.send(rpc::error_reply(&envelope, &error, Some("overloaded")), conn_id).await;
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        self.shared.subscribers.publish(&envelope, conn_id);
+
+        if stream_id != 0 {
+            if let Some(worker) = workers.get(&stream_id) {
+                let _ = worker.send((envelope, permit));
+                return;
+            }
+            let handler = self.shared.stream_handlers.read().unwrap().get(&stream_id).cloned();
+            if let Some(handler) = handler {
+                let worker = spawn_stream_worker(self.shared.clone(), handler, conn_id.to_string(), (envelope, permit));
+                workers.insert(stream_id, worker);
+                return;
+            }
+        }
+
+        let host_handler = access.server_name.as_ref().and_then(|name| {
+            self.shared.host_handlers.read().unwrap().get(name).cloned()
+        });
+        let handler = host_handler.or_else(|| self.shared.message_handler.read().unwrap().clone());
+        if let Some(handler) = handler {
+            let handled = self.shared.cancel.run_until_cancelled(handler(envelope, conn_id.to_string()));
+            if let Some(Err(error)) = handled.await {
+                self.shared.report(Some(conn_id), error);
+            }
+        }
+        drop(permit);
+    }
+}
+
+/// Next connection to arrive on any of `listeners`
+async fn accept_any(listeners: &[TcpListener]) -> std::io::Result<(TcpStream, SocketAddr)> {
+    let accepts = listeners.iter().map(|listener| Box::pin(listener.accept()));
+    futures_util::future::select_all(accepts).await.0
+}
+
+/// Wait for the next tick of `ticker`, or for ever without one
+async fn tick(ticker: Option<&mut tokio::time::Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Sleep until `deadline`, or for ever without one
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Ping carrying the time since `started`, in microseconds, for the pong to echo back
+fn ping_message(started: Instant) -> Outgoing {
+    let micros = started.elapsed().as_micros() as u64;
+    Outgoing::Message(Message::Ping(micros.to_be_bytes().to_vec()), MessagePriority::High)
+}
+
+/// Feed one stream's envelopes to its handler in arrival order, starting with `first`
+fn spawn_stream_worker(
+    shared: Arc<Shared>,
+    handler: MessageHandler,
+    conn_id: String,
+    first: (Envelope, Option<OwnedSemaphorePermit>),
+) -> mpsc::UnboundedSender<(Envelope, Option<OwnedSemaphorePermit>)> {
+    let (sender, mut envelopes) = mpsc::unbounded_channel();
+    let _ = sender.send(first);
+    tokio::spawn(async move {
+        // The handler slot, if any, is released once the envelope is handled
+        while let Some((envelope, _permit)) = envelopes.recv().await {
+            match shared.cancel.run_until_cancelled(handler(envelope, conn_id.clone())).await {
+                Some(Ok(())) => {}
+                Some(Err(error)) => shared.report(Some(&conn_id), error),
+                // The transport shut down
+                None => break,
+            }
+        }
+    });
+    sender
+}
+
+/// Connection record for a client's link to the server at `url`
+fn client_info(url: &str) -> ConnectionInfo {
+    let now = chrono::Utc::now();
+    ConnectionInfo {
+        id: url.to_string(),
+        remote_addr: url.to_string(),
+        local_addr: String::new(),
+        connected_at: now,
+        last_activity: now,
+        principal: None,
+        capabilities: None,
+        shard: None,
+        idle_ms: 0,
+        endpoints: Vec::new(),
+        server_name: None,
+    }
+}
+
+/// Message carrying an encoded envelope; `binary` when it was compressed or framed
+///
+/// Only plain JSON goes out as text; everything else is binary.
+fn envelope_message(payload: Vec<u8>, binary: bool, codec: EnvelopeCodec) -> Message {
+    match (codec, binary) {
+        // Serialized JSON is always valid UTF-8
+        (EnvelopeCodec::Json, false) => Message::Text(String::from_utf8(payload).unwrap()),
+        _ => Message::Binary(payload),
+    }
+}
+
+/// Box both halves of a client socket and read the server's compression and codec answers
+fn split_socket<S, B>(
+    socket: WebSocketStream<S>,
+    response: &tokio_tungstenite::tungstenite::http::Response<B>,
+) -> (ClientSink, ClientStream, Agreement)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let answer = |name| response.headers().get(name).and_then(|answer| answer.to_str().ok());
+    let agreed = Agreement {
+        compression: answer(COMPRESSION_HEADER).map_or(Compression::None, compression::negotiate),
+        // tungstenite already checked the answer is one we offered
+        codec: answer(SEC_WEBSOCKET_PROTOCOL.as_str())
+            .and_then(EnvelopeCodec::from_subprotocol)
+            .unwrap_or_default(),
+    };
+    let (sink, stream) = socket.split();
+    (Box::pin(sink), Box::pin(stream), agreed)
+}
+
+#[async_trait]
+impl Transport for WebSocketTransport {
+    /// Spawn [`run`](WebSocketTransport::run) in the background
+    async fn connect(&self) -> Result<()> {
+        let transport = self.clone();
+        tokio::spawn(async move { transport.run().await });
+        Ok(())
+    }
+
+    /// Without a connection ID, clients send to their server and servers to the endpoint named
+    /// by the envelope's `to`
+    async fn send(&self, envelope: Envelope, connection_id: Option<&str>) -> Result<()> {
+        match (connection_id, &self.shared.role) {
+            (Some(connection_id), _) => WebSocketTransport::send(self, envelope, connection_id).await,
+            (None, Role::Client { .. }) => self.send_to_server(envelope).await,
+            (None, Role::Server { .. }) => {
+                let name = envelope.to().to_string();
+                self.send_to(&name, envelope).await
+            }
+        }
+    }
+
+    fn subscribe(&self) -> Subscription {
+        self.shared.subscribers.subscribe()
+    }
+
+    fn events(&self) -> EventStream {
+        self.shared.subscribers.events()
+    }
+
+    async fn stats(&self) -> TransportStats {
+        self.get_stats().await
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        WebSocketTransport::shutdown(self).await
+    }
+}
+
+impl std::fmt::Debug for WebSocketTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut debug = f.debug_struct("WebSocketTransport");
+        match &self.shared.role {
+            Role::Server { local_addrs, .. } => debug.field("server", local_addrs),
+            Role::Client { url, .. } => debug.field("client", url),
+        };
+        debug
+            .field("connections", &self.shared.peers.read().unwrap().len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::OperationType;
+    use std::time::Duration;
+
+    fn make_envelope(from: &str, to: &str, operation: OperationType) -> Envelope {
+        Envelope::builder()
+            .from(from)
+            .to(to)
+            .operation(operation)
+            .message_id(&uuid::Uuid::new_v4().to_string())
+            .build()
+            .unwrap()
+    }
+
+    /// Capabilities `connection_id` advertised, once its handshake has arrived
+    async fn handshake_of(transport: &WebSocketTransport, connection_id: &str) -> PeerCapabilities {
+        let wait = async {
+            loop {
+                if let Some(capabilities) = transport.peer_capabilities(connection_id) {
+                    return capabilities;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_websocket_round_trip() {
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+
+        // Echo every data message back as an ack
+        let responder = server.clone();
+        server.set_message_handler(move |envelope, conn_id| {
+            let responder = responder.clone();
+            async move {
+                let ack = make_envelope("server", envelope.from(), OperationType::Ack);
+                responder.send(ack, &conn_id).await
+            }
+        });
+        let runner = server.clone();
+        tokio::spawn(async move { runner.run().await });
+
+        let client = WebSocketTransport::new_client(&url).await.unwrap();
+        let (acks, mut acked) = mpsc::unbounded_channel();
+        client.set_message_handler(move |envelope, _| {
+            let acks = acks.clone();
+            async move {
+                let _ = acks.send(envelope.operation());
+                Ok(())
+            }
+        });
+        let runner = client.clone();
+        let client_task = tokio::spawn(async move { runner.run().await });
+
+        client
+            .send_to_server(make_envelope("client", "server", OperationType::Data))
+            .await
+            .unwrap();
+        let operation = tokio::time::timeout(Duration::from_secs(5), acked.recv()).await.unwrap();
+        assert_eq!(operation, Some(OperationType::Ack));
+
+        let stats = client.get_stats().await;
+        assert_eq!((stats.messages_sent, stats.messages_received), (1, 1));
+        assert_eq!(server.get_stats().await.total_connections, 1);
+        assert!(server.send_to_server(make_envelope("s", "c", OperationType::Data)).await.is_err());
+
+        client.shutdown().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), client_task).await.unwrap().unwrap().unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    async fn next_state(transitions: &mut mpsc::UnboundedReceiver<ConnectionState>) -> Option<ConnectionState> {
+        tokio::time::timeout(Duration::from_secs(5), transitions.recv()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_websocket_reconnect() {
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        Transport::connect(&server).await.unwrap();
+
+        let config = TransportConfig {
+            max_reconnect_attempts: 20,
+            reconnect_initial_delay_ms: 20,
+            reconnect_max_delay_ms: 100,
+            ..Default::default()
+        };
+        let client = WebSocketTransport::new_client_with_config(&format!("ws://{}", addr), &config).await.unwrap();
+        let (states, mut transitions) = mpsc::unbounded_channel();
+        client.set_state_handler(move |state| {
+            let states = states.clone();
+            async move {
+                let _ = states.send(state);
+            }
+        });
+        let mut replies = client.subscribe();
+        let runner = client.clone();
+        let client_task = tokio::spawn(async move { runner.run().await });
+
+        // Server goes away and comes back on the same port
+        server.shutdown().await.unwrap();
+        assert_eq!(next_state(&mut transitions).await, Some(ConnectionState::Reconnecting));
+        let server = loop {
+            match WebSocketTransport::new_server(&addr.to_string()).await {
+                Ok(server) => break server,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let mut incoming = server.subscribe();
+        Transport::connect(&server).await.unwrap();
+        assert_eq!(next_state(&mut transitions).await, Some(ConnectionState::Connected));
+
+        // Same handle, same subscription after the reconnect
+        client.send_to_server(make_envelope("client", "server", OperationType::Data)).await.unwrap();
+        let (_, conn_id) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+        server.send(make_envelope("server", "client", OperationType::Ack), &conn_id).await.unwrap();
+        let (ack, _) = tokio::time::timeout(Duration::from_secs(5), replies.recv()).await.unwrap().unwrap();
+        assert_eq!(ack.operation(), OperationType::Ack);
+        let stats = client.get_stats().await;
+        assert_eq!(stats.total_connections, 2);
+        assert_eq!(stats.reconnects, 1);
+        assert!(stats.reconnect_attempts >= 1);
+
+        // Server gone for good: the client gives up
+        server.shutdown().await.unwrap();
+        assert_eq!(next_state(&mut transitions).await, Some(ConnectionState::Reconnecting));
+        assert_eq!(next_state(&mut transitions).await, Some(ConnectionState::Failed));
+        assert!(client_task.await.unwrap().is_err());
+        assert_eq!(client.connection_state(), Some(ConnectionState::Failed));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_stream_multiplexing() {
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let (ordered, mut on_stream) = mpsc::unbounded_channel();
+        server.set_stream_handler(5, move |envelope, _| {
+            let ordered = ordered.clone();
+            async move {
+                let _ = ordered.send(envelope.message_id().to_string());
+                Ok(())
+            }
+        });
+        let mut incoming = server.subscribe();
+        Transport::connect(&server).await.unwrap();
+
+        let client = WebSocketTransport::new_client(&url).await.unwrap();
+        let bulk = Envelope::builder()
+            .from("client")
+            .to("server")
+            .operation(OperationType::Data)
+            .message_id(&uuid::Uuid::new_v4().to_string())
+            .capability("blob", &"x".repeat(mux::CHUNK_SIZE * 16))
+            .build()
+            .unwrap();
+        let on = |stream_id| FrameOptions {
+            stream_id: Some(stream_id),
+            ..Default::default()
+        };
+        client.send_with_options(bulk, &url, &on(9)).await.unwrap();
+        client.send_to_server(make_envelope("client", "server", OperationType::Control)).await.unwrap();
+        let mut sent = Vec::new();
+        for _ in 0..3 {
+            let envelope = make_envelope("client", "server", OperationType::Data);
+            sent.push(envelope.message_id().to_string());
+            client.send_with_options(envelope, &url, &on(5)).await.unwrap();
+        }
+
+        // The control message overtakes the bulk transfer queued before it
+        let mut arrivals = Vec::new();
+        for _ in 0..5 {
+            let (envelope, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+            arrivals.push(envelope.operation());
+        }
+        assert_eq!(arrivals[0], OperationType::Control);
+
+        let mut handled = Vec::new();
+        for _ in 0..3 {
+            handled.push(tokio::time::timeout(Duration::from_secs(5), on_stream.recv()).await.unwrap().unwrap());
+        }
+        assert_eq!(handled, sent);
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    /// Source that yields `good` bytes and then fails
+    struct FailingReader {
+        good: usize,
+    }
+
+    impl AsyncRead for FailingReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if self.good == 0 {
+                return std::task::Poll::Ready(Err(std::io::Error::other("disk on fire")));
+            }
+            let count = self.good.min(buf.remaining());
+            buf.put_slice(&vec![1; count]);
+            self.good -= count;
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_stream_transfer() {
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        let mut streams = server.incoming_streams();
+        let mut incoming = server.subscribe();
+        Transport::connect(&server).await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let client = WebSocketTransport::new_client(&url).await.unwrap();
+
+        // Far more than the send window, so the sender has to wait for the receiver
+        let size = 24 * 1024 * 1024u64;
+        let header = make_envelope("client", "server", OperationType::Data);
+        let sender = client.clone();
+        let url_ = url.clone();
+        let sending = tokio::spawn(async move {
+            sender.send_stream(header, tokio::io::repeat(7).take(size), &url_).await
+        });
+
+        let mut stream = tokio::time::timeout(Duration::from_secs(5), streams.recv()).await.unwrap().unwrap();
+        assert_eq!(stream.header.from(), "client");
+        let receiving = tokio::spawn(async move {
+            let mut received = 0u64;
+            while let Some(chunk) = stream.next_chunk().await {
+                let chunk = chunk.unwrap();
+                assert!(chunk.iter().all(|byte| *byte == 7));
+                received += chunk.len() as u64;
+            }
+            received
+        });
+        // Other traffic shares the connection with the transfer
+        client.send_to_server(make_envelope("client", "server", OperationType::Control)).await.unwrap();
+        let (control, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+        assert_eq!(control.operation(), OperationType::Control);
+
+        assert_eq!(tokio::time::timeout(Duration::from_secs(10), receiving).await.unwrap().unwrap(), size);
+        assert_eq!(sending.await.unwrap().unwrap(), size);
+
+        // A failing source aborts the transfer on both ends
+        let header = make_envelope("client", "server", OperationType::Data);
+        let error = client.send_stream(header, FailingReader { good: 1000 }, &url).await.unwrap_err();
+        assert!(error.to_string().contains("disk on fire"));
+        let mut stream = tokio::time::timeout(Duration::from_secs(5), streams.recv()).await.unwrap().unwrap();
+        let mut sink = Vec::new();
+        let error = stream.copy_to(&mut sink).await.unwrap_err();
+        assert!(matches!(error, UmicpError::Remote { .. }), "{}", error);
+        assert_eq!(sink.len(), 1000);
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_cancellation() {
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        let mut streams = server.incoming_streams();
+        // Reports when the handler holding it is dropped
+        struct Guard(mpsc::UnboundedSender<&'static str>);
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                let _ = self.0.send("dropped");
+            }
+        }
+        let (events, mut handling) = mpsc::unbounded_channel();
+        server.set_message_handler(move |_, _| {
+            let guard = Guard(events.clone());
+            async move {
+                let _ = guard.0.send("started");
+                // Never finishes on its own
+                std::future::pending::<()>().await;
+                drop(guard);
+                Ok(())
+            }
+        });
+        Transport::connect(&server).await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let client = WebSocketTransport::new_client(&url).await.unwrap();
+
+        // An endless transfer the receiver does not drain, stopped by its token
+        let cancel = CancellationToken::new();
+        let sending = {
+            let (client, url, cancel) = (client.clone(), url.clone(), cancel.clone());
+            let header = make_envelope("client", "server", OperationType::Data);
+            let endless = tokio::io::repeat(3);
+            tokio::spawn(async move { client.send_stream_cancellable(header, endless, &url, &cancel).await })
+        };
+        let mut stream = tokio::time::timeout(Duration::from_secs(5), streams.recv()).await.unwrap().unwrap();
+        cancel.cancel();
+        let error = tokio::time::timeout(Duration::from_secs(5), sending).await.unwrap().unwrap().unwrap_err();
+        assert!(matches!(error, UmicpError::Cancelled { .. }), "{}", error);
+        let error = stream.copy_to(&mut tokio::io::sink()).await.unwrap_err();
+        assert!(error.to_string().contains("Transfer cancelled"), "{}", error);
+
+        // Shutting down drops the handler that would otherwise run for ever
+        let token = server.shutdown_token();
+        client.send_to_server(make_envelope("client", "server", OperationType::Data)).await.unwrap();
+        assert_eq!(tokio::time::timeout(Duration::from_secs(5), handling.recv()).await.unwrap(), Some("started"));
+        server.shutdown().await.unwrap();
+        assert!(token.is_cancelled());
+        assert_eq!(tokio::time::timeout(Duration::from_secs(5), handling.recv()).await.unwrap(), Some("dropped"));
+
+        client.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_binary_payload_frames() {
+        // Uncompressed, so the wire size reflects the framing alone
+        let config = TransportConfig {
+            compression_enabled: false,
+            ..Default::default()
+        };
+        let server = WebSocketTransport::new_server_with_config("127.0.0.1:0", &config).await.unwrap();
+        let mut incoming = server.subscribe();
+        Transport::connect(&server).await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let client = WebSocketTransport::new_client_with_config(&url, &config).await.unwrap();
+        Transport::connect(&client).await.unwrap();
+        // Payloads go out as binary frames once the server has said it reads them
+        assert!(handshake_of(&client, &url).await.supports(FEATURE_BINARY_FRAMES));
+
+        let weights: Vec<u8> = (0..65_536u32).flat_map(|i| (i as f32).to_le_bytes()).collect();
+        let mut envelope = make_envelope("client", "server", OperationType::Data);
+        envelope.set_payload(weights.clone());
+        client.send_to_server(envelope.clone()).await.unwrap();
+        let on_stream = FrameOptions {
+            stream_id: Some(3),
+            ..Default::default()
+        };
+        client.send_with_options(envelope, &url, &on_stream).await.unwrap();
+
+        for _ in 0..2 {
+            let (received, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+            assert_eq!(received.payload(), Some(weights.as_slice()));
+        }
+        let stats = client.get_stats().await;
+        assert!(stats.bytes_sent < 2 * (weights.len() as u64 + 1024), "{} bytes sent", stats.bytes_sent);
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_priority_queue() {
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        let mut incoming = server.subscribe();
+        Transport::connect(&server).await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let client = WebSocketTransport::new_client(&url).await.unwrap();
+        Transport::connect(&client).await.unwrap();
+
+        // Queued while the writer cannot run (single-threaded runtime), so it sees them all at once
+        let mut bulk = Vec::new();
+        for _ in 0..40 {
+            let mut envelope = make_envelope("client", "server", OperationType::Data);
+            envelope.set_payload(vec![0; 4096]);
+            bulk.push(envelope.message_id().to_string());
+            client.send_to_server(envelope).await.unwrap();
+        }
+        let mut urgent = make_envelope("client", "server", OperationType::Data);
+        urgent.set_priority(MessagePriority::High);
+        client.send_to_server(urgent.clone()).await.unwrap();
+        client.send_to_server(make_envelope("client", "server", OperationType::Ack)).await.unwrap();
+
+        let mut order = Vec::new();
+        for _ in 0..42 {
+            let (received, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+            order.push(received);
+        }
+        assert_eq!(order[0].message_id(), urgent.message_id());
+        assert_eq!(order[1].operation(), OperationType::Ack);
+        // Bulk data keeps its own order
+        let data: Vec<_> = order[2..].iter().map(|envelope| envelope.message_id().to_string()).collect();
+        assert_eq!(data, bulk);
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_message_coalescing() {
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        let mut incoming = server.subscribe();
+        Transport::connect(&server).await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let config = TransportConfig {
+            coalesce_delay_ms: 50,
+            // So the large envelope stays too large to hold
+            compression_enabled: false,
+            ..Default::default()
+        };
+        let client = WebSocketTransport::new_client_with_config(&url, &config).await.unwrap();
+        Transport::connect(&client).await.unwrap();
+        assert!(handshake_of(&client, &url).await.supports(FEATURE_BATCHES));
+
+        // Small envelopes are held; one too large to hold sends them first, keeping their order
+        let mut sent = Vec::new();
+        for index in 0..26 {
+            let mut envelope = make_envelope("client", "server", OperationType::Data);
+            if index == 20 {
+                envelope.set_payload(vec![0; 32 * 1024]);
+            }
+            sent.push(envelope.message_id().to_string());
+            client.send_to_server(envelope).await.unwrap();
+        }
+
+        let mut received = Vec::new();
+        for _ in 0..26 {
+            let (envelope, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+            received.push(envelope.message_id().to_string());
+        }
+        assert_eq!(received, sent);
+        // The last five went out together once the delay was up
+        let stats = client.get_stats().await;
+        assert_eq!((stats.batches_sent, stats.coalesced_messages), (2, 25));
+        assert_eq!(server.get_stats().await.messages_received, 26);
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_capability_handshake() {
+        let server_config = TransportConfig {
+            max_payload_size: 64 * 1024,
+            ..Default::default()
+        };
+        let server = WebSocketTransport::new_server_with_config("127.0.0.1:0", &server_config).await.unwrap();
+        let mut incoming = server.subscribe();
+        Transport::connect(&server).await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+
+        // Prefers CBOR but upgrades speaking JSON, the first codec the server knows
+        let client_config = TransportConfig {
+            codecs: EnvelopeCodec::supported().iter().rev().copied().collect(),
+            ..Default::default()
+        };
+        let client = WebSocketTransport::new_client_with_config(&url, &client_config).await.unwrap();
+        Transport::connect(&client).await.unwrap();
+
+        let advertised = handshake_of(&client, &url).await;
+        assert_eq!(advertised.protocol_version, crate::UMICP_VERSION);
+        assert_eq!(advertised.codecs, EnvelopeCodec::supported());
+        assert_eq!(advertised.max_payload_size, 64 * 1024);
+        assert!(advertised.supports(FEATURE_BINARY_FRAMES) && advertised.supports(FEATURE_TRANSFERS));
+        let conn_id = server.connections()[0].id.clone();
+        let advertised = handshake_of(&server, &conn_id).await;
+        assert_eq!(advertised.codecs, client_config.codecs);
+        assert_eq!(advertised.max_payload_size, client_config.max_payload_size);
+
+        // The client now serializes in its preferred codec; handshakes are never delivered
+        #[cfg(feature = "cbor")]
+        assert_eq!(client.shared.peers.read().unwrap()[&url].agreed.codec, EnvelopeCodec::Cbor);
+        client.send_to_server(make_envelope("client", "server", OperationType::Data)).await.unwrap();
+        let (received, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+        assert_eq!(received.operation(), OperationType::Data);
+        assert_eq!(server.get_stats().await.messages_received, 1);
+
+        // Nothing larger than the server accepts is sent
+        let mut oversized = make_envelope("client", "server", OperationType::Data);
+        oversized.set_payload(vec![0; 128 * 1024]);
+        assert!(client.send_to_server(oversized).await.is_err());
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_sharded_server() {
+        let config = TransportConfig {
+            accept_shards: 3,
+            ..Default::default()
+        };
+        let server = WebSocketTransport::new_server_with_config("127.0.0.1:0", &config).await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        // Answers with the name of the thread the handler ran on
+        let responder = server.clone();
+        server.set_message_handler(move |envelope, conn_id| {
+            let responder = responder.clone();
+            async move {
+                let thread = std::thread::current().name().unwrap_or_default().to_string();
+                let mut reply = make_envelope("server", envelope.from(), OperationType::Ack);
+                reply.add_capability("thread", &thread);
+                responder.send(reply, &conn_id).await
+            }
+        });
+        Transport::connect(&server).await.unwrap();
+
+        let (replies, mut replied) = mpsc::unbounded_channel();
+        let mut clients = Vec::new();
+        for i in 0..6 {
+            let client = WebSocketTransport::new_client(&url).await.unwrap();
+            let replies = replies.clone();
+            client.set_message_handler(move |envelope, _| {
+                let replies = replies.clone();
+                async move {
+                    let _ = replies.send(envelope.capabilities().unwrap()["thread"].clone());
+                    Ok(())
+                }
+            });
+            Transport::connect(&client).await.unwrap();
+            let envelope = make_envelope(&format!("client-{}", i), "server", OperationType::Data);
+            client.send_to_server(envelope).await.unwrap();
+            clients.push(client);
+        }
+
+        let mut threads = std::collections::HashSet::new();
+        for _ in 0..6 {
+            let thread = tokio::time::timeout(Duration::from_secs(5), replied.recv()).await.unwrap().unwrap();
+            assert!(thread.starts_with("umicp-shard-"), "handled on {}", thread);
+            threads.insert(thread);
+        }
+        assert_eq!(threads.len(), 3);
+
+        // Round-robin: two connections and two messages per shard
+        let stats = server.get_stats().await;
+        assert_eq!(stats.shards.len(), 3);
+        for shard in &stats.shards {
+            assert_eq!((shard.active_connections, shard.total_connections, shard.messages_received), (2, 2, 2));
+        }
+        assert_eq!(stats.messages_received, 6);
+        assert!(server.connections().iter().all(|info| info.shard.is_some()));
+
+        clients[0].shutdown().await.unwrap();
+        let drained = async {
+            while server.get_stats().await.shards.iter().map(|shard| shard.active_connections).sum::<u32>() != 5 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), drained).await.unwrap();
+        for client in &clients[1..] {
+            client.shutdown().await.unwrap();
+        }
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_compression_negotiation() {
+        let large = || {
+            Envelope::builder()
+                .from("client")
+                .to("server")
+                .operation(OperationType::Data)
+                .message_id(&uuid::Uuid::new_v4().to_string())
+                .capability("blob", &"embedding ".repeat(2000))
+                .build()
+                .unwrap()
+        };
+
+        for server_compresses in [true, false] {
+            let config = TransportConfig {
+                compression_enabled: server_compresses,
+                ..Default::default()
+            };
+            let server = WebSocketTransport::new_server_with_config("127.0.0.1:0", &config).await.unwrap();
+            let mut incoming = server.subscribe();
+            Transport::connect(&server).await.unwrap();
+
+            let client = WebSocketTransport::new_client(&format!("ws://{}", server.local_addr().unwrap())).await.unwrap();
+            client.send_to_server(make_envelope("client", "server", OperationType::Control)).await.unwrap();
+            client.send_to_server(large()).await.unwrap();
+            for _ in 0..2 {
+                tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+            }
+
+            let sent = client.get_stats().await;
+            let received = server.get_stats().await;
+            assert_eq!(sent.bytes_sent, received.bytes_received);
+            if server_compresses {
+                // Only the message above the threshold is compressed
+                assert!(sent.compressed_bytes_sent * 10 < sent.uncompressed_bytes_sent);
+                assert!(sent.uncompressed_bytes_sent > 20_000 && sent.bytes_sent < sent.uncompressed_bytes_sent);
+                assert_eq!(received.uncompressed_bytes_received, sent.uncompressed_bytes_sent);
+            } else {
+                assert_eq!((sent.compressed_bytes_sent, received.compressed_bytes_received), (0, 0));
+            }
+
+            client.shutdown().await.unwrap();
+            server.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_subprotocol_negotiation() {
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        let mut incoming = server.subscribe();
+        Transport::connect(&server).await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+
+        // Clients that predate negotiation send no subprotocol and are served JSON
+        let (mut legacy, response) = tokio_tungstenite::connect_async(url.as_str()).await.unwrap();
+        assert!(response.headers().get(SEC_WEBSOCKET_PROTOCOL).is_none());
+        let json = make_envelope("legacy", "server", OperationType::Data).serialize().unwrap();
+        legacy.send(Message::Text(json)).await.unwrap();
+        let (envelope, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+        assert_eq!(envelope.from(), "legacy");
+
+        // An offer without a supported variant is refused with a reason
+        let mut request = url.as_str().into_client_request().unwrap();
+        request.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("umicp.v2.json"));
+        match tokio_tungstenite::connect_async(request).await {
+            Err(WsError::Http(response)) => {
+                assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+                let reason = String::from_utf8(response.body().clone().unwrap()).unwrap();
+                assert!(reason.contains("umicp.v2.json") && reason.contains("umicp.v1.json"), "{}", reason);
+            }
+            other => panic!("unsupported subprotocol was accepted: {:?}", other.map(|_| ())),
+        }
+
+        for &codec in EnvelopeCodec::supported() {
+            let config = TransportConfig {
+                codecs: vec![codec],
+                ..Default::default()
+            };
+            let client = WebSocketTransport::new_client_with_config(&url, &config).await.unwrap();
+            let peer = client.shared.peers.read().unwrap().values().next().unwrap().agreed;
+            assert_eq!(peer.codec, codec);
+            client.send_to_server(make_envelope("client", "server", OperationType::Data)).await.unwrap();
+            let received = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap();
+            let (envelope, conn_id) = received.unwrap();
+            assert_eq!(envelope.from(), "client");
+
+            // Replies go out in the connection's codec
+            let mut replies = client.subscribe();
+            Transport::connect(&client).await.unwrap();
+            server.send(make_envelope("server", "client", OperationType::Ack), &conn_id).await.unwrap();
+            let (reply, _) = tokio::time::timeout(Duration::from_secs(5), replies.recv()).await.unwrap().unwrap();
+            assert_eq!(reply.operation(), OperationType::Ack);
+            client.shutdown().await.unwrap();
+        }
+        server.shutdown().await.unwrap();
+
+        // A client whose codecs the server refuses gets a clear error
+        #[cfg(feature = "cbor")]
+        {
+            let json_only = TransportConfig {
+                codecs: vec![EnvelopeCodec::Json],
+                ..Default::default()
+            };
+            let server = WebSocketTransport::new_server_with_config("127.0.0.1:0", &json_only).await.unwrap();
+            Transport::connect(&server).await.unwrap();
+            let cbor_only = TransportConfig {
+                codecs: vec![EnvelopeCodec::Cbor],
+                ..Default::default()
+            };
+            let url = format!("ws://{}", server.local_addr().unwrap());
+            let error = WebSocketTransport::new_client_with_config(&url, &cbor_only).await.unwrap_err();
+            assert!(matches!(error, UmicpError::Transport { .. }), "{}", error);
+            assert!(error.to_string().contains("umicp.v1.cbor"), "{}", error);
+            server.shutdown().await.unwrap();
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn test_websocket_tls() {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("umicp-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+
+        let server_config = TransportConfig {
+            tls_enabled: true,
+            tls_cert_path: Some(cert_path.to_string_lossy().into_owned()),
+            tls_key_path: Some(key_path.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let server = WebSocketTransport::new_server_with_config("127.0.0.1:0", &server_config).await.unwrap();
+        let mut incoming = server.subscribe();
+        Transport::connect(&server).await.unwrap();
+        let url = format!("wss://localhost:{}", server.local_addr().unwrap().port());
+
+        // Untrusted self-signed certificate is rejected by the default roots
+        assert!(WebSocketTransport::new_client(&url).await.is_err());
+
+        let trusted = TransportConfig {
+            tls_ca_path: Some(cert_path.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let insecure = TransportConfig {
+            tls_accept_invalid_certs: true,
+            ..Default::default()
+        };
+        for config in [trusted, insecure] {
+            let client = WebSocketTransport::new_client_with_config(&url, &config).await.unwrap();
+            client.send_to_server(make_envelope("client", "server", OperationType::Data)).await.unwrap();
+            let (envelope, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+            assert_eq!(envelope.from(), "client");
+            client.shutdown().await.unwrap();
+        }
+
+        server.shutdown().await.unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn test_websocket_tls_pinning() {
+        use crate::types::TlsPin;
+        use crate::utils::base64_encode;
+        use sha2::{Digest, Sha256};
+
+        let dir = std::env::temp_dir().join(format!("umicp-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // A fresh certificate and key in `dir`, with its certificate and public key pins
+        let issue = |name: &str| {
+            let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+            let cert_path = dir.join(format!("{}.pem", name)).to_string_lossy().into_owned();
+            let key_path = dir.join(format!("{}.key", name)).to_string_lossy().into_owned();
+            std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+            std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+            let fingerprint = hex::encode(Sha256::digest(certified.cert.der()));
+            let spki = format!("sha256/{}", base64_encode(&Sha256::digest(&certified.key_pair.public_key_der())));
+            (cert_path, key_path, fingerprint.parse::<TlsPin>().unwrap(), spki.parse::<TlsPin>().unwrap())
+        };
+        let (server_cert, server_key, server_fingerprint, server_spki) = issue("server");
+        let (client_cert, client_key, _, client_spki) = issue("client");
+        let (other_cert, other_key, other_fingerprint, _) = issue("other");
+
+        let server_config = TransportConfig {
+            tls_enabled: true,
+            tls_cert_path: Some(server_cert),
+            tls_key_path: Some(server_key),
+            tls_pins: vec![client_spki],
+            ..Default::default()
+        };
+        let server = WebSocketTransport::new_server_with_config("127.0.0.1:0", &server_config).await.unwrap();
+        let mut incoming = server.subscribe();
+        let mut events = server.events();
+        Transport::connect(&server).await.unwrap();
+        let url = format!("wss://localhost:{}", server.local_addr().unwrap().port());
+        let client = |pins: Vec<TlsPin>, cert: &str, key: &str| TransportConfig {
+            tls_accept_invalid_certs: true,
+            tls_pins: pins,
+            tls_cert_path: Some(cert.to_string()),
+            tls_key_path: Some(key.to_string()),
+            max_reconnect_attempts: 0,
+            ..Default::default()
+        };
+
+        // A pinned server certificate or key, with a pinned client key, gets through
+        for pin in [server_fingerprint, server_spki] {
+            let config = client(vec![other_fingerprint, pin], &client_cert, &client_key);
+            let client = WebSocketTransport::new_client_with_config(&url, &config).await.unwrap();
+            client.send_to_server(make_envelope("client", "server", OperationType::Data)).await.unwrap();
+            let (envelope, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+            assert_eq!(envelope.from(), "client");
+            client.shutdown().await.unwrap();
+        }
+
+        // A server matching no pin fails the client's handshake
+        let config = client(vec![other_fingerprint], &client_cert, &client_key);
+        let error = WebSocketTransport::new_client_with_config(&url, &config).await.unwrap_err();
+        assert!(matches!(error, UmicpError::PinMismatch { .. }), "{}", error);
+
+        // A client certificate matching none of the server's pins is refused, and reported
+        let config = client(vec![server_spki], &other_cert, &other_key);
+        assert!(WebSocketTransport::new_client_with_config(&url, &config).await.is_err());
+        // Both failures are reported by the server, the client's through its alert
+        let mut mismatches = 0;
+        while mismatches < 2 {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+            if matches!(event, TransportEvent::Error { message, .. } if message.contains("Pin mismatch")) {
+                mismatches += 1;
+            }
+        }
+        assert_eq!(server.get_stats().await.errors.get("pin_mismatch"), Some(&2));
+
+        server.shutdown().await.unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn test_websocket_tls_reload() {
+        let dir = std::env::temp_dir().join(format!("umicp-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        // Issue a fresh certificate into the server's files; returns a client config trusting it
+        let issue = |name: &str| {
+            let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+            let ca_path = dir.join(format!("{}.pem", name));
+            std::fs::write(&ca_path, certified.cert.pem()).unwrap();
+            std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+            std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+            TransportConfig {
+                tls_ca_path: Some(ca_path.to_string_lossy().into_owned()),
+                max_reconnect_attempts: 0,
+                ..Default::default()
+            }
+        };
+
+        let trusts_first = issue("first");
+        let server_config = TransportConfig {
+            tls_enabled: true,
+            tls_cert_path: Some(cert_path.to_string_lossy().into_owned()),
+            tls_key_path: Some(key_path.to_string_lossy().into_owned()),
+            tls_reload_interval: 1,
+            ..Default::default()
+        };
+        let server = WebSocketTransport::new_server_with_config("127.0.0.1:0", &server_config).await.unwrap();
+        let mut incoming = server.subscribe();
+        Transport::connect(&server).await.unwrap();
+        let url = format!("wss://localhost:{}", server.local_addr().unwrap().port());
+        let client = WebSocketTransport::new_client_with_config(&url, &trusts_first).await.unwrap();
+        Transport::connect(&client).await.unwrap();
+
+        // A reload on request serves the new certificate to new connections only
+        let trusts_second = issue("second");
+        server.reload_tls().unwrap();
+        assert!(WebSocketTransport::new_client_with_config(&url, &trusts_first).await.is_err());
+        let renewed = WebSocketTransport::new_client_with_config(&url, &trusts_second).await.unwrap();
+        client.send_to_server(make_envelope("client", "server", OperationType::Data)).await.unwrap();
+        let (envelope, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+        assert_eq!(envelope.from(), "client");
+
+        // Changed files are picked up on their own
+        let trusts_third = issue("third");
+        let picked_up = async {
+            loop {
+                if let Ok(client) = WebSocketTransport::new_client_with_config(&url, &trusts_third).await {
+                    return client;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        let third = tokio::time::timeout(Duration::from_secs(5), picked_up).await.unwrap();
+
+        // A broken key is reported and the current certificate stays in use
+        std::fs::write(&key_path, "not a key").unwrap();
+        assert!(server.reload_tls().is_err());
+        let fourth = WebSocketTransport::new_client_with_config(&url, &trusts_third).await.unwrap();
+
+        for client in [client, renewed, third, fourth] {
+            client.shutdown().await.unwrap();
+        }
+        server.shutdown().await.unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn test_websocket_tls_virtual_hosts() {
+        use crate::types::TlsVirtualHost;
+
+        let dir = std::env::temp_dir().join(format!("umicp-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let issue = |name: &str| {
+            let certified = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+            let cert_path = dir.join(format!("{}.pem", name)).to_string_lossy().into_owned();
+            let key_path = dir.join(format!("{}.key", name)).to_string_lossy().into_owned();
+            std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+            std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+            TlsVirtualHost::new(name, cert_path, key_path)
+        };
+        let tenants = [issue("a.tenants.test"), issue("b.tenants.test")];
+
+        let server_config = TransportConfig {
+            tls_enabled: true,
+            tls_virtual_hosts: tenants.to_vec(),
+            ..Default::default()
+        };
+        let server = WebSocketTransport::new_server_with_config("127.0.0.1:0", &server_config).await.unwrap();
+        let (handled, mut handled_rx) = mpsc::unbounded_channel();
+        for tenant in &tenants {
+            let (handled, name) = (handled.clone(), tenant.server_name.clone());
+            server.set_host_message_handler(&tenant.server_name.to_uppercase(), move |envelope, conn_id| {
+                let _ = handled.send((name.clone(), envelope.from().to_string(), conn_id));
+                async { Ok(()) }
+            });
+        }
+        Transport::connect(&server).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        // Handshake asking for `name`, trusting only `tenant`'s certificate
+        let handshake = |tenant: &TlsVirtualHost, name: &str| {
+            let trusting = TransportConfig {
+                tls_ca_path: Some(tenant.cert_path.clone()),
+                ..Default::default()
+            };
+            let client_config = super::super::tls::client_config(&trusting).unwrap();
+            let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+            let name = super::super::tls::server_name(name).unwrap();
+            async move { connector.connect(name, TcpStream::connect(addr).await.unwrap()).await }
+        };
+
+        // Each name is served its own certificate and handled by its own handler
+        for tenant in &tenants {
+            let tls = handshake(tenant, &tenant.server_name).await.unwrap();
+            let url = format!("wss://{}/", tenant.server_name);
+            let (mut socket, _) = tokio_tungstenite::client_async(url, tls).await.unwrap();
+            let json = make_envelope(&tenant.server_name, "server", OperationType::Data).serialize().unwrap();
+            socket.send(Message::Text(json)).await.unwrap();
+            let handled = tokio::time::timeout(Duration::from_secs(5), handled_rx.recv()).await.unwrap();
+            let (host, from, conn_id) = handled.unwrap();
+            assert_eq!((host.as_str(), from.as_str()), (tenant.server_name.as_str(), tenant.server_name.as_str()));
+            let info = server.connection_info(&conn_id).unwrap();
+            assert_eq!(info.server_name.as_deref(), Some(tenant.server_name.as_str()));
+        }
+
+        // Another tenant's certificate is not trusted, and other names have no fallback
+        assert!(handshake(&tenants[0], &tenants[1].server_name).await.is_err());
+        assert!(handshake(&tenants[0], "c.tenants.test").await.is_err());
+
+        server.shutdown().await.unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_dual_stack() {
+        let config = TransportConfig {
+            dual_stack: true,
+            ..Default::default()
+        };
+        let server = WebSocketTransport::new_server_with_config("0.0.0.0:0", &config).await.unwrap();
+        let mut incoming = server.subscribe();
+        Transport::connect(&server).await.unwrap();
+        let addrs = server.local_addrs();
+        assert_eq!(addrs.len(), 2);
+        assert!(addrs[0].is_ipv4() && addrs[1].is_ipv6());
+        let port = addrs[0].port();
+
+        for host in ["127.0.0.1", "[::1]", "localhost"] {
+            let url = format!("ws://{}:{}", host, port);
+            let client = WebSocketTransport::new_client(&url).await.unwrap();
+            client.send_to_server(make_envelope("client", "server", OperationType::Data)).await.unwrap();
+            let (_, conn_id) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+            let remote: SocketAddr = server.connection_info(&conn_id).unwrap().remote_addr.parse().unwrap();
+            assert!(remote.ip().is_loopback());
+            client.shutdown().await.unwrap();
+        }
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_accept_errors() {
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        server.shared.accept_faults.store(2, Ordering::SeqCst);
+        let mut incoming = server.subscribe();
+        let mut events = server.events();
+        Transport::connect(&server).await.unwrap();
+
+        // Failed accepts are reported and counted, and the server goes on accepting
+        for _ in 0..2 {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+            assert!(matches!(event, TransportEvent::Error { connection_id: None, .. }), "{:?}", event);
+        }
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let client = WebSocketTransport::new_client(&url).await.unwrap();
+        client.send_to_server(make_envelope("client", "server", OperationType::Data)).await.unwrap();
+        let (envelope, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+        assert_eq!(envelope.from(), "client");
+        assert_eq!(server.get_stats().await.errors.values().sum::<u64>(), 2);
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_as_transport() {
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let server: Arc<dyn Transport> = Arc::new(server);
+        let mut incoming = server.subscribe();
+        server.connect().await.unwrap();
+
+        let client: Arc<dyn Transport> = Arc::new(WebSocketTransport::new_client(&url).await.unwrap());
+        let mut replies = client.subscribe();
+        client.connect().await.unwrap();
+        client.send(make_envelope("client", "server", OperationType::Request), None).await.unwrap();
+
+        let (request, conn_id) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+        assert_eq!(request.operation(), OperationType::Request);
+        assert!(server.send(make_envelope("server", "client", OperationType::Response), None).await.is_err());
+        server.send(make_envelope("server", "client", OperationType::Response), Some(&conn_id)).await.unwrap();
+
+        let (reply, _) = tokio::time::timeout(Duration::from_secs(5), replies.recv()).await.unwrap().unwrap();
+        assert_eq!(reply.operation(), OperationType::Response);
+        assert_eq!(client.stats().await.messages_received, 1);
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_broadcast() {
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let (events, mut connected) = mpsc::unbounded_channel();
+        server.set_connection_handler(move |up, conn_id| {
+            let events = events.clone();
+            async move {
+                if up {
+                    let _ = events.send(conn_id);
+                }
+            }
+        });
+        server.connect().await.unwrap();
+
+        let mut clients = Vec::new();
+        let mut conn_ids = Vec::new();
+        for _ in 0..3 {
+            let client = WebSocketTransport::new_client(&url).await.unwrap();
+            let incoming = client.subscribe();
+            Transport::connect(&client).await.unwrap();
+            conn_ids.push(tokio::time::timeout(Duration::from_secs(5), connected.recv()).await.unwrap().unwrap());
+            clients.push((client, incoming));
+        }
+
+        let announcement = make_envelope("server", "*", OperationType::Control);
+        assert_eq!(server.broadcast(announcement.clone()).await.unwrap(), 3);
+        let skipped = conn_ids[0].clone();
+        let update = make_envelope("server", "*", OperationType::Data);
+        assert_eq!(server.broadcast_filtered(update.clone(), |conn_id| conn_id != skipped).await.unwrap(), 2);
+        assert_eq!(server.get_stats().await.messages_sent, 5);
+
+        for (index, (_, incoming)) in clients.iter_mut().enumerate() {
+            let (received, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+            assert_eq!(received.message_id(), announcement.message_id());
+            // The filtered-out client gets nothing after the announcement
+            if index > 0 {
+                let (received, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+                assert_eq!(received.message_id(), update.message_id());
+            }
+        }
+        for (client, _) in &clients {
+            client.shutdown().await.unwrap();
+        }
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_subscription_filter() {
+        use crate::transport::{CapabilityMatcher, FILTER_CAPABILITY};
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        server.connect().await.unwrap();
+
+        let mobile = WebSocketTransport::new_client(&url).await.unwrap();
+        Transport::connect(&mobile).await.unwrap();
+        let filter = SubscriptionFilter::new()
+            .operation(OperationType::Data)
+            .capability(CapabilityMatcher::Equals("model".into(), "resnet-50".into()));
+        mobile.set_subscription_filter(Some(filter.clone())).await.unwrap();
+        // After the server's ack
+        let mut mobile_incoming = mobile.subscribe();
+        let desktop = WebSocketTransport::new_client(&url).await.unwrap();
+        let mut desktop_incoming = desktop.subscribe();
+        Transport::connect(&desktop).await.unwrap();
+        handshake_of(&desktop, &url).await;
+        let filters: Vec<_> = server.connections().iter().map(|info| server.subscription_filter(&info.id)).collect();
+        assert_eq!(filters.len(), 2);
+        assert!(filters.contains(&Some(filter)) && filters.contains(&None));
+
+        let mut other_model = make_envelope("server", "*", OperationType::Data);
+        other_model.add_capability("model", "bert");
+        assert_eq!(server.broadcast(other_model.clone()).await.unwrap(), 1);
+        let mut followed = make_envelope("server", "*", OperationType::Data);
+        followed.add_capability("model", "resnet-50");
+        assert_eq!(server.broadcast(followed.clone()).await.unwrap(), 2);
+        assert_eq!(server.get_stats().await.filtered_messages, 1);
+
+        let (received, _) = tokio::time::timeout(Duration::from_secs(5), mobile_incoming.recv()).await.unwrap().unwrap();
+        assert_eq!(received.message_id(), followed.message_id());
+        for expected in [&other_model, &followed] {
+            let (received, _) = tokio::time::timeout(Duration::from_secs(5), desktop_incoming.recv()).await.unwrap().unwrap();
+            assert_eq!(received.message_id(), expected.message_id());
+        }
+
+        // Filters that do not parse are refused; removing the filter restores every broadcast
+        let mut invalid = make_envelope("mobile", "server", OperationType::Control);
+        invalid.add_capability(FILTER_CAPABILITY, r#"{"expression": "model =="}"#);
+        let error = Transport::request_to(&mobile, invalid, &url, Duration::from_secs(5)).await.unwrap_err();
+        assert!(matches!(error, UmicpError::Remote { .. }), "{}", error);
+        mobile.set_subscription_filter(None).await.unwrap();
+        assert_eq!(server.broadcast(other_model).await.unwrap(), 2);
+
+        mobile.shutdown().await.unwrap();
+        desktop.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_named_endpoints() {
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        server.connect().await.unwrap();
+
+        let mut workers = Vec::new();
+        for name in ["worker-1", "worker-2"] {
+            let worker = WebSocketTransport::new_client(&url).await.unwrap();
+            Transport::connect(&worker).await.unwrap();
+            worker.register_endpoint(name).await.unwrap();
+            // After the server's ack
+            let incoming = worker.subscribe();
+            workers.push((worker, incoming));
+        }
+        assert!(workers[0].0.register_endpoint("worker 1").await.is_err());
+        let conn_id = server.endpoint_connection("worker-2").unwrap();
+        assert_eq!(server.connection_info(&conn_id).unwrap().endpoints, vec!["worker-2"]);
+
+        // Addressed by name, or by the envelope's `to` through the `Transport` trait
+        let direct = make_envelope("server", "workers", OperationType::Data);
+        server.send_to("worker-2", direct.clone()).await.unwrap();
+        let routed = make_envelope("server", "worker-1", OperationType::Data);
+        Transport::send(&server, routed.clone(), None).await.unwrap();
+        for ((_, incoming), expected) in workers.iter_mut().zip([&routed, &direct]) {
+            let (received, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+            assert_eq!(received.message_id(), expected.message_id());
+        }
+        assert!(server.send_to("worker-3", direct.clone()).await.is_err());
+
+        // Names are released with their connection
+        let (worker, _) = workers.pop().unwrap();
+        worker.shutdown().await.unwrap();
+        let released = async {
+            while server.endpoint_connection("worker-2").is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), released).await.unwrap();
+        assert!(server.endpoint_connection("worker-1").is_some());
+
+        workers[0].0.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_connection_stats() {
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        let (handled, mut envelopes) = mpsc::unbounded_channel();
+        server.set_message_handler(move |envelope, conn_id| {
+            let handled = handled.clone();
+            async move {
+                let _ = handled.send(conn_id);
+                match envelope.operation() {
+                    OperationType::Request => Err(UmicpError::validation("Requests are not served")),
+                    _ => Ok(()),
+                }
+            }
+        });
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        server.connect().await.unwrap();
+
+        let noisy = WebSocketTransport::new_client(&url).await.unwrap();
+        let quiet = WebSocketTransport::new_client(&url).await.unwrap();
+        let large = Envelope::builder()
+            .from("noisy")
+            .to("server")
+            .operation(OperationType::Data)
+            .message_id(&uuid::Uuid::new_v4().to_string())
+            .capability("blob", &"gradient ".repeat(2000))
+            .build()
+            .unwrap();
+        noisy.send_to_server(large).await.unwrap();
+        noisy.send_to_server(make_envelope("noisy", "server", OperationType::Request)).await.unwrap();
+        noisy.send_to_server(make_envelope("noisy", "server", OperationType::Request)).await.unwrap();
+        quiet.send_to_server(make_envelope("quiet", "server", OperationType::Data)).await.unwrap();
+        let mut senders = HashMap::<String, u64>::new();
+        for _ in 0..4 {
+            let conn_id = tokio::time::timeout(Duration::from_secs(5), envelopes.recv()).await.unwrap().unwrap();
+            *senders.entry(conn_id).or_default() += 1;
+        }
+        let noisy_id = senders.iter().find(|(_, count)| **count == 3).unwrap().0.clone();
+        let quiet_id = senders.iter().find(|(_, count)| **count == 1).unwrap().0.clone();
+
+        // The handler's errors are counted once it has returned
+        let errors = async {
+            while server.get_stats().await.errors.get("validation") != Some(&2) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), errors).await.unwrap();
+        let stats = server.get_stats().await;
+        assert_eq!(stats.connections.len(), 2);
+        let noisy_stats = &stats.connections[&noisy_id];
+        assert_eq!(noisy_stats.messages_received, 3);
+        assert_eq!(noisy_stats.errors.get("validation"), Some(&2));
+        assert!(noisy_stats.compression_ratio_received().unwrap() > 10.0);
+        assert_eq!(noisy_stats.queued_messages, 0);
+        let quiet_stats = server.connection_stats(&quiet_id).await.unwrap();
+        assert_eq!(quiet_stats.messages_received, 1);
+        assert!(quiet_stats.errors.is_empty() && quiet_stats.compression_ratio_received().is_none());
+        assert_eq!(stats.messages_received, 4);
+        assert_eq!(noisy.get_stats().await.compression_ratio_sent(), noisy_stats.compression_ratio_received());
+
+        // Counters start over; open connections are still listed
+        server.reset_stats();
+        let stats = server.get_stats().await;
+        assert_eq!((stats.messages_received, stats.bytes_received), (0, 0));
+        assert!(stats.errors.is_empty());
+        assert_eq!(stats.active_connections, 2);
+        assert_eq!(stats.connections[&noisy_id].messages_received, 0);
+        assert!(stats.connections[&noisy_id].errors.is_empty());
+
+        // And a closed connection is no longer
+        quiet.shutdown().await.unwrap();
+        let closed = async {
+            while server.connection_stats(&quiet_id).await.is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), closed).await.unwrap();
+
+        noisy.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_idle_timeout() {
+        let config = TransportConfig {
+            idle_timeout: 1,
+            // Pings alone must not keep the connection open
+            heartbeat_interval: 1,
+            ..Default::default()
+        };
+        let server = WebSocketTransport::new_server_with_config("127.0.0.1:0", &config).await.unwrap();
+        let mut incoming = server.subscribe();
+        Transport::connect(&server).await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let client_config = TransportConfig {
+            max_reconnect_attempts: 0,
+            ..Default::default()
+        };
+        let client = WebSocketTransport::new_client_with_config(&url, &client_config).await.unwrap();
+        let mut notices = client.subscribe();
+        Transport::connect(&client).await.unwrap();
+
+        client.send_to_server(make_envelope("client", "server", OperationType::Data)).await.unwrap();
+        let (_, conn_id) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let idle_ms = server.connection_info(&conn_id).unwrap().idle_ms;
+        assert!((200..1000).contains(&idle_ms), "{} ms idle", idle_ms);
+
+        // Traffic from the server keeps the connection open too
+        server.send(make_envelope("server", "client", OperationType::Data), &conn_id).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        assert!(server.connection_info(&conn_id).is_some());
+
+        let (notice, _) = loop {
+            let received = tokio::time::timeout(Duration::from_secs(5), notices.recv()).await.unwrap().unwrap();
+            if received.0.operation() == OperationType::Control {
+                break received;
+            }
+        };
+        assert_eq!(notice.capabilities().unwrap().get(IDLE_TIMEOUT_CAPABILITY).map(String::as_str), Some("1"));
+        let closed = async {
+            while client.connection_state() != Some(ConnectionState::Disconnected) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), closed).await.unwrap();
+        assert!(server.connection_info(&conn_id).is_none());
+        assert_eq!(server.get_stats().await.idle_disconnects, 1);
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_ping_latency() {
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        server.connect().await.unwrap();
+        let client = WebSocketTransport::new_client(&url).await.unwrap();
+        Transport::connect(&client).await.unwrap();
+        assert!(client.connection_latency(&url).is_none());
+        assert!(client.get_stats().await.avg_latency_ms.is_none());
+
+        for _ in 0..3 {
+            client.ping(&url).unwrap();
+        }
+        let latency = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match client.connection_latency(&url) {
+                    Some(latency) if latency.samples == 3 => return latency,
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(latency.min_ms <= latency.p50_ms && latency.p50_ms <= latency.p99_ms);
+        assert!(latency.p99_ms <= latency.max_ms);
+
+        let stats = client.get_stats().await;
+        assert_eq!(stats.avg_latency_ms, Some(latency.avg_ms));
+        assert_eq!(stats.latency_p95_ms, Some(latency.p95_ms));
+        assert!(client.ping("nobody").is_err());
+
+        client.shutdown().await.unwrap();
+        assert!(client.connection_latency(&url).is_none());
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_jwt_auth() {
+        use crate::auth::{sign_hs256, JwtValidator};
+        use crate::secret::Secret;
+        let secret = b"handshake secret";
+        let token = |sub: &str| {
+            let exp = chrono::Utc::now().timestamp() + 3600;
+            sign_hs256(&serde_json::json!({ "sub": sub, "exp": exp }), secret).unwrap()
+        };
+
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        server.set_token_validator(JwtValidator::hs256(secret));
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let (seen, mut principals) = mpsc::unbounded_channel();
+        let inspector = server.clone();
+        server.set_message_handler(move |_, conn_id| {
+            let principal = inspector.principal(&conn_id).map(|principal| principal.subject);
+            let _ = seen.send(principal);
+            async { Ok(()) }
+        });
+        let mut server_events = server.events();
+        server.connect().await.unwrap();
+        let config = |auth_token: Option<String>| TransportConfig {
+            auth_token: auth_token.map(Secret::new),
+            max_reconnect_attempts: 0,
+            ..Default::default()
+        };
+
+        // Bearer header, checked during the upgrade
+        let alice = WebSocketTransport::new_client_with_config(&url, &config(Some(token("alice")))).await.unwrap();
+        Transport::connect(&alice).await.unwrap();
+        alice.send_to_server(make_envelope("alice", "server", OperationType::Data)).await.unwrap();
+        let principal = tokio::time::timeout(Duration::from_secs(5), principals.recv()).await.unwrap().unwrap();
+        assert_eq!(principal.as_deref(), Some("alice"));
+        let info = server.connections().pop().unwrap();
+        assert_eq!(info.principal.unwrap().subject, "alice");
+        assert!(info.remote_addr.starts_with("127.0.0.1:"));
+
+        let forged = token("mallory") + "x";
+        let error = WebSocketTransport::new_client_with_config(&url, &config(Some(forged))).await.unwrap_err();
+        assert!(matches!(error, UmicpError::Authentication { .. }), "{}", error);
+
+        // No header: the first envelope carries the token
+        let bob = WebSocketTransport::new_client(&url).await.unwrap();
+        Transport::connect(&bob).await.unwrap();
+        let mut hello = make_envelope("bob", "server", OperationType::Control);
+        hello.add_capability(AUTH_CAPABILITY, &token("bob"));
+        bob.send_to_server(hello).await.unwrap();
+        bob.send_to_server(make_envelope("bob", "server", OperationType::Data)).await.unwrap();
+        let principal = tokio::time::timeout(Duration::from_secs(5), principals.recv()).await.unwrap().unwrap();
+        assert_eq!(principal.as_deref(), Some("bob"));
+
+        // Anything else first gets the connection closed
+        let eve = WebSocketTransport::new_client_with_config(&url, &config(None)).await.unwrap();
+        let mut events = eve.events();
+        Transport::connect(&eve).await.unwrap();
+        eve.send_to_server(make_envelope("eve", "server", OperationType::Data)).await.unwrap();
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+            if matches!(event, TransportEvent::Disconnected(_)) {
+                break;
+            }
+        }
+        assert_eq!(server.connections().len(), 2);
+
+        // Every outcome was reported as an event
+        let mut outcomes = Vec::new();
+        while outcomes.len() < 4 {
+            match tokio::time::timeout(Duration::from_secs(5), server_events.recv()).await.unwrap().unwrap() {
+                TransportEvent::Authenticated { subject, .. } => outcomes.push(subject),
+                TransportEvent::AuthenticationFailed { .. } => outcomes.push("refused".to_string()),
+                _ => {}
+            }
+        }
+        assert_eq!(outcomes, ["alice", "refused", "bob", "refused"]);
+
+        for transport in [alice, bob, eve, server] {
+            transport.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_authorizer() {
+        use crate::auth::{sign_hs256, JwtValidator};
+        use crate::transport::{ERROR_CAPABILITY, ERROR_CODE_CAPABILITY};
+        let secret = b"authorizer secret";
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        server.set_token_validator(JwtValidator::hs256(secret));
+        server.set_authorizer(|principal: Option<&Principal>, envelope: &Envelope| {
+            let scope = principal.and_then(|principal| principal.claim("scope"));
+            let read_only = scope == Some(&serde_json::json!("read"));
+            match (read_only, envelope.operation()) {
+                (true, OperationType::Data) => Err(UmicpError::forbidden("read-only clients cannot send data")),
+                _ => Ok(()),
+            }
+        });
+        let (seen, mut dispatched) = mpsc::unbounded_channel();
+        server.set_message_handler(move |envelope, _| {
+            let _ = seen.send(envelope.operation());
+            async { Ok(()) }
+        });
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        server.connect().await.unwrap();
+
+        let exp = chrono::Utc::now().timestamp() + 3600;
+        let token = sign_hs256(&serde_json::json!({ "sub": "viewer", "scope": "read", "exp": exp }), secret).unwrap();
+        let config = TransportConfig {
+            auth_token: Some(token.into()),
+            ..Default::default()
+        };
+        let client = WebSocketTransport::new_client_with_config(&url, &config).await.unwrap();
+        let mut server_events = server.events();
+        Transport::connect(&client).await.unwrap();
+
+        let mut incoming = client.subscribe();
+        let data = make_envelope("viewer", "server", OperationType::Data);
+        client.send_to_server(data.clone()).await.unwrap();
+        let (reply, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+        assert_eq!(reply.operation(), OperationType::Error);
+        assert_eq!(reply.correlation_id(), Some(data.message_id()));
+        let capabilities = reply.capabilities().unwrap();
+        assert_eq!(capabilities.get(ERROR_CODE_CAPABILITY).map(String::as_str), Some("forbidden"));
+        assert!(capabilities.get(ERROR_CAPABILITY).unwrap().contains("read-only"));
+
+        // Allowed operations still reach the handler; the rejected one never did
+        client.send_to_server(make_envelope("viewer", "server", OperationType::Control)).await.unwrap();
+        let operation = tokio::time::timeout(Duration::from_secs(5), dispatched.recv()).await.unwrap();
+        assert_eq!(operation, Some(OperationType::Control));
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), server_events.recv()).await.unwrap().unwrap();
+            if let TransportEvent::Error { message, .. } = event {
+                assert!(message.contains("Permission denied"));
+                break;
+            }
+        }
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_quotas_survive_reconnects() {
+        use crate::quota::{Quota, QUOTA_CAPABILITY, QUOTA_EXCEEDED_CODE, RETRY_AFTER_CAPABILITY};
+        use crate::transport::ERROR_CODE_CAPABILITY;
+        let server = WebSocketTransport::new_server("127.0.0.1:0").await.unwrap();
+        server.set_quotas(QuotaTracker::new(Quota::new().messages_per_day(2)));
+        let (seen, mut dispatched) = mpsc::unbounded_channel();
+        server.set_message_handler(move |envelope, _| {
+            let _ = seen.send(envelope.message_id().to_string());
+            async { Ok(()) }
+        });
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        server.connect().await.unwrap();
+
+        let client = WebSocketTransport::new_client(&url).await.unwrap();
+        Transport::connect(&client).await.unwrap();
+        for _ in 0..2 {
+            client.send_to_server(make_envelope("worker", "server", OperationType::Data)).await.unwrap();
+            tokio::time::timeout(Duration::from_secs(5), dispatched.recv()).await.unwrap().unwrap();
+        }
+        client.shutdown().await.unwrap();
+
+        // A new connection from the same peer picks up where the last one left off
+        let client = WebSocketTransport::new_client(&url).await.unwrap();
+        Transport::connect(&client).await.unwrap();
+        let mut incoming = client.subscribe();
+        let data = make_envelope("worker", "server", OperationType::Data);
+        client.send_to_server(data.clone()).await.unwrap();
+        let (reply, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+        assert_eq!(reply.operation(), OperationType::Error);
+        assert_eq!(reply.correlation_id(), Some(data.message_id()));
+        let capabilities = reply.capabilities().unwrap();
+        assert_eq!(capabilities.get(ERROR_CODE_CAPABILITY).map(String::as_str), Some(QUOTA_EXCEEDED_CODE));
+        assert_eq!(capabilities.get(QUOTA_CAPABILITY).map(String::as_str), Some("messages_per_day"));
+        assert!(capabilities.contains_key(RETRY_AFTER_CAPABILITY));
+        assert!(dispatched.try_recv().is_err());
+        assert_eq!(server.get_stats().await.quota_rejections, 1);
+        assert_eq!(server.quotas().unwrap().usage("127.0.0.1").messages_today, 2);
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn test_websocket_cached_authorization() {
+        use crate::policy::{Policy, Rule};
+
+        struct Counting(Policy, Arc<AtomicUsize>);
+        impl Authorizer for Counting {
+            fn authorize(&self, principal: Option<&Principal>, envelope: &Envelope) -> Result<()> {
+                self.1.fetch_add(1, Ordering::Relaxed);
+                self.0.authorize(principal, envelope)
+            }
+
+            fn decision_key(&self, envelope: &Envelope) -> Option<String> {
+                self.0.decision_key(envelope)
+            }
+        }
+
+        let policy = Policy::new().rule(Rule::allow().subject("worker-*").operation(OperationType::Data));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let authorizer: Arc<dyn Authorizer> = Arc::new(Counting(policy, calls.clone()));
+        let mut access = Access::new(Some(Principal::new("worker-1")), None);
+        for _ in 0..3 {
+            access.authorize(Some(authorizer.clone()), &make_envelope("w", "s", OperationType::Data)).unwrap();
+            let denied = access.authorize(Some(authorizer.clone()), &make_envelope("w", "s", OperationType::Control));
+            assert!(matches!(denied, Err(UmicpError::Forbidden { .. })));
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        // A new authorizer is asked again
+        let replacement: Arc<dyn Authorizer> = Arc::new(Counting(Policy::new(), calls.clone()));
+        assert!(access.authorize(Some(replacement), &make_envelope("w", "s", OperationType::Data)).is_err());
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+        assert!(access.authorize(None, &make_envelope("w", "s", OperationType::Data)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_websocket_load_shedding() {
+        use crate::transport::ERROR_CODE_CAPABILITY;
+        let config = TransportConfig {
+            max_connections: 1,
+            max_inflight_handlers: 1,
+            ..Default::default()
+        };
+        let server = WebSocketTransport::new_server_with_config("127.0.0.1:0", &config).await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let release = Arc::new(tokio::sync::Notify::new());
+        let (started, mut busy) = mpsc::unbounded_channel();
+        let gate = release.clone();
+        server.set_stream_handler(1, move |_, _| {
+            let _ = started.send(());
+            let gate = gate.clone();
+            async move {
+                gate.notified().await;
+                Ok(())
+            }
+        });
+        let (seen, mut handled) = mpsc::unbounded_channel();
+        server.set_message_handler(move |envelope, _| {
+            let _ = seen.send(envelope.message_id().to_string());
+            async { Ok(()) }
+        });
+        server.connect().await.unwrap();
+        let code = |envelope: &Envelope| envelope.capabilities().and_then(|c| c.get(ERROR_CODE_CAPABILITY)).cloned();
+
+        let client = WebSocketTransport::new_client(&url).await.unwrap();
+        let mut incoming = client.subscribe();
+        Transport::connect(&client).await.unwrap();
+
+        // The second connection is told why and closed
+        let extra = WebSocketTransport::new_client(&url).await.unwrap();
+        let mut notices = extra.subscribe();
+        Transport::connect(&extra).await.unwrap();
+        let (notice, _) = tokio::time::timeout(Duration::from_secs(5), notices.recv()).await.unwrap().unwrap();
+        assert_eq!(notice.operation(), OperationType::Error);
+        assert_eq!(code(&notice).as_deref(), Some("overloaded"));
+
+        // Occupy the only handler slot, then overflow it
+        let slow = make_envelope("client", "server", OperationType::Data);
+        let stream = FrameOptions {
+            stream_id: Some(1),
+            ..Default::default()
+        };
+        client.send_with_options(slow, &url, &stream).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), busy.recv()).await.unwrap();
+        let shed = make_envelope("client", "server", OperationType::Data);
+        client.send_to_server(shed.clone()).await.unwrap();
+        let (reply, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+        assert_eq!(reply.correlation_id(), Some(shed.message_id()));
+        assert_eq!(code(&reply).as_deref(), Some("overloaded"));
+
+        release.notify_one();
+        let accepted = make_envelope("client", "server", OperationType::Data);
+        // The slot frees once the slow handler returns
+        let retry = async {
+            loop {
+                client.send_to_server(accepted.clone()).await.unwrap();
+                let outcome = tokio::select! {
+                    id = handled.recv() => id,
+                    _ = tokio::time::sleep(Duration::from_millis(50)) => None,
+                };
+                if outcome.as_deref() == Some(accepted.message_id()) {
+                    break;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), retry).await.unwrap();
+
+        let stats = server.get_stats().await;
+        assert_eq!(stats.rejected_connections, 1);
+        assert!(stats.shed_messages >= 1);
+        for transport in [client, extra, server] {
+            transport.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_sequence_reordering() {
+        let config = TransportConfig {
+            reorder_window: 4,
+            reorder_timeout_ms: 100,
+            ..Default::default()
+        };
+        let server = WebSocketTransport::new_server_with_config("127.0.0.1:0", &config).await.unwrap();
+        let mut incoming = server.subscribe();
+        let mut events = server.events();
+        Transport::connect(&server).await.unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let client = WebSocketTransport::new_client(&url).await.unwrap();
+        Transport::connect(&client).await.unwrap();
+        // Sequence numbers only travel in binary frames
+        handshake_of(&client, &url).await;
+
+        // 1 arrives late and 4 never does
+        let envelopes: Vec<_> = (0..6).map(|_| make_envelope("client", "server", OperationType::Data)).collect();
+        for sequence in [0, 2, 3, 1, 5] {
+            let options = FrameOptions {
+                sequence: Some(sequence),
+                ..Default::default()
+            };
+            client.send_with_options(envelopes[sequence as usize].clone(), &url, &options).await.unwrap();
+        }
+
+        let mut order = Vec::new();
+        for _ in 0..5 {
+            let (received, _) = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+            order.push(envelopes.iter().position(|sent| sent.message_id() == received.message_id()).unwrap());
+        }
+        // 5 waits out the timeout, then is delivered past the gap
+        assert_eq!(order, vec![0, 1, 2, 3, 5]);
+
+        let mut gaps = Vec::new();
+        while gaps.len() < 2 {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+            if let TransportEvent::SequenceGap { stream_id, expected, received, .. } = event {
+                gaps.push((stream_id, expected, received));
+            }
+        }
+        assert_eq!(gaps, vec![(0, 1, 2), (0, 4, 5)]);
+        assert_eq!(server.get_stats().await.sequence_gaps, 2);
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+}
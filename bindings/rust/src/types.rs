@@ -4,14 +4,16 @@
 Core type definitions for the UMICP protocol.
 */
 
+use crate::secret::Secret;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 /// Operation types for UMICP messages
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum OperationType {
     /// Control message for protocol management
+    #[default]
     Control = 0,
     /// Regular data message
     Data = 1,
@@ -25,12 +27,6 @@ pub enum OperationType {
     Response = 5,
 }
 
-impl Default for OperationType {
-    fn default() -> Self {
-        OperationType::Control
-    }
-}
-
 impl std::fmt::Display for OperationType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let op_str = match self {
@@ -45,13 +41,76 @@ impl std::fmt::Display for OperationType {
     }
 }
 
+impl std::str::FromStr for OperationType {
+    type Err = crate::error::UmicpError;
+
+    /// Parse the lowercase name used on the wire, e.g. `data`
+    fn from_str(name: &str) -> crate::error::Result<Self> {
+        match name {
+            "control" => Ok(OperationType::Control),
+            "data" => Ok(OperationType::Data),
+            "ack" => Ok(OperationType::Ack),
+            "error" => Ok(OperationType::Error),
+            "request" => Ok(OperationType::Request),
+            "response" => Ok(OperationType::Response),
+            _ => Err(crate::error::UmicpError::validation(format!("Unknown operation type: {}", name))),
+        }
+    }
+}
+
+/// How urgently an envelope goes out, relative to others queued for the same connection
+///
+/// Set with the [`PRIORITY_CAPABILITY`](crate::envelope::PRIORITY_CAPABILITY); envelopes without
+/// it get their operation's default, so acks, errors and control messages overtake bulk data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessagePriority {
+    /// Bulk traffic: `Data` envelopes by default
+    Low,
+    /// `Request` and `Response` envelopes by default
+    #[default]
+    Normal,
+    /// `Ack`, `Error` and `Control` envelopes by default
+    High,
+}
+
+impl MessagePriority {
+    /// Name used in the priority capability
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessagePriority::Low => "low",
+            MessagePriority::Normal => "normal",
+            MessagePriority::High => "high",
+        }
+    }
+
+    /// Priority named `name`, ignoring case
+    pub fn from_name(name: &str) -> Option<Self> {
+        [MessagePriority::Low, MessagePriority::Normal, MessagePriority::High]
+            .into_iter()
+            .find(|priority| priority.as_str().eq_ignore_ascii_case(name))
+    }
+}
+
+impl OperationType {
+    /// Priority of envelopes with this operation that do not set one
+    pub fn default_priority(&self) -> MessagePriority {
+        match self {
+            OperationType::Control | OperationType::Ack | OperationType::Error => MessagePriority::High,
+            OperationType::Request | OperationType::Response => MessagePriority::Normal,
+            OperationType::Data => MessagePriority::Low,
+        }
+    }
+}
+
 /// Payload types for message content
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum PayloadType {
     /// Vector data (numeric arrays)
     Vector = 0,
     /// Text data
+    #[default]
     Text = 1,
     /// Metadata information
     Metadata = 2,
@@ -59,12 +118,6 @@ pub enum PayloadType {
     Binary = 3,
 }
 
-impl Default for PayloadType {
-    fn default() -> Self {
-        PayloadType::Text
-    }
-}
-
 impl std::fmt::Display for PayloadType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let type_str = match self {
@@ -78,10 +131,11 @@ impl std::fmt::Display for PayloadType {
 }
 
 /// Encoding types for numeric data
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum EncodingType {
     /// 32-bit floating point
+    #[default]
     Float32 = 0,
     /// 64-bit floating point
     Float64 = 1,
@@ -99,12 +153,6 @@ pub enum EncodingType {
     Uint64 = 7,
 }
 
-impl Default for EncodingType {
-    fn default() -> Self {
-        EncodingType::Float32
-    }
-}
-
 impl std::fmt::Display for EncodingType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let enc_str = match self {
@@ -146,24 +194,206 @@ impl Default for PayloadHint {
 }
 
 /// Transport statistics
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TransportStats {
     /// Total messages sent
     pub messages_sent: u64,
     /// Total messages received
     pub messages_received: u64,
-    /// Total bytes sent
+    /// Total bytes sent, as written to the wire
     pub bytes_sent: u64,
-    /// Total bytes received
+    /// Total bytes received, as read from the wire
     pub bytes_received: u64,
+    /// Wire bytes of sent messages that were compressed
+    #[serde(default)]
+    pub compressed_bytes_sent: u64,
+    /// Size of those sent messages before compression
+    #[serde(default)]
+    pub uncompressed_bytes_sent: u64,
+    /// Wire bytes of received messages that were compressed
+    #[serde(default)]
+    pub compressed_bytes_received: u64,
+    /// Size of those received messages after decompression
+    #[serde(default)]
+    pub uncompressed_bytes_received: u64,
     /// Current connection count
     pub active_connections: u32,
     /// Total connection count
     pub total_connections: u64,
+    /// Connections turned away because `max_connections` were already open
+    #[serde(default)]
+    pub rejected_connections: u64,
+    /// Envelopes refused because `max_inflight_handlers` were already being handled
+    #[serde(default)]
+    pub shed_messages: u64,
+    /// Connections closed for carrying no envelopes for `idle_timeout`
+    #[serde(default)]
+    pub idle_disconnects: u64,
+    /// Envelopes refused because their sender was over quota
+    #[serde(default)]
+    pub quota_rejections: u64,
+    /// Batches sent, each carrying several coalesced messages
+    #[serde(default)]
+    pub batches_sent: u64,
+    /// Messages sent inside those batches
+    #[serde(default)]
+    pub coalesced_messages: u64,
+    /// Broadcast copies not sent because the connection's subscription filter did not match
+    #[serde(default)]
+    pub filtered_messages: u64,
+    /// Gaps found in sequenced streams (see [`FrameOptions::sequence`])
+    #[serde(default)]
+    pub sequence_gaps: u64,
     /// Uptime in seconds
     pub uptime_seconds: u64,
-    /// Average latency in milliseconds
+    /// Average round-trip time in milliseconds, over every connection
     pub avg_latency_ms: Option<f64>,
+    /// Median round-trip time in milliseconds
+    #[serde(default)]
+    pub latency_p50_ms: Option<f64>,
+    /// 95th percentile round-trip time in milliseconds
+    #[serde(default)]
+    pub latency_p95_ms: Option<f64>,
+    /// 99th percentile round-trip time in milliseconds
+    #[serde(default)]
+    pub latency_p99_ms: Option<f64>,
+    /// Per-shard figures of a server spreading connections over `accept_shards` runtimes
+    #[serde(default)]
+    pub shards: Vec<ShardStats>,
+    /// Errors reported as `Error` events, by [`UmicpError::category`](crate::UmicpError::category)
+    #[serde(default)]
+    pub errors: BTreeMap<String, u64>,
+    /// Reconnection attempts made after a client lost its connection
+    #[serde(default)]
+    pub reconnect_attempts: u64,
+    /// Reconnection attempts that succeeded
+    #[serde(default)]
+    pub reconnects: u64,
+    /// Figures of each open connection, by connection ID
+    #[serde(default)]
+    pub connections: BTreeMap<String, ConnectionStats>,
+}
+
+impl TransportStats {
+    /// Uncompressed size of compressed sent messages over their wire size; `None` before any
+    pub fn compression_ratio_sent(&self) -> Option<f64> {
+        compression_ratio(self.uncompressed_bytes_sent, self.compressed_bytes_sent)
+    }
+
+    /// Uncompressed size of compressed received messages over their wire size; `None` before any
+    pub fn compression_ratio_received(&self) -> Option<f64> {
+        compression_ratio(self.uncompressed_bytes_received, self.compressed_bytes_received)
+    }
+
+    /// Zero every counter, keeping what describes the present: open connections, queue depths
+    /// and uptime
+    pub fn reset(&mut self) {
+        let active_connections = self.active_connections;
+        let uptime_seconds = self.uptime_seconds;
+        let shards = self
+            .shards
+            .iter()
+            .map(|shard| ShardStats {
+                active_connections: shard.active_connections,
+                ..Default::default()
+            })
+            .collect();
+        let connections = std::mem::take(&mut self.connections)
+            .into_iter()
+            .map(|(id, connection)| {
+                let reset = ConnectionStats {
+                    queued_messages: connection.queued_messages,
+                    queued_stream_bytes: connection.queued_stream_bytes,
+                    ..Default::default()
+                };
+                (id, reset)
+            })
+            .collect();
+        *self = TransportStats {
+            active_connections,
+            uptime_seconds,
+            shards,
+            connections,
+            ..Default::default()
+        };
+    }
+}
+
+/// Statistics of one connection
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    /// Messages sent on the connection
+    pub messages_sent: u64,
+    /// Messages received on the connection
+    pub messages_received: u64,
+    /// Bytes sent, as written to the wire
+    pub bytes_sent: u64,
+    /// Bytes received, as read from the wire
+    pub bytes_received: u64,
+    /// Wire bytes of sent messages that were compressed
+    pub compressed_bytes_sent: u64,
+    /// Size of those sent messages before compression
+    pub uncompressed_bytes_sent: u64,
+    /// Wire bytes of received messages that were compressed
+    pub compressed_bytes_received: u64,
+    /// Size of those received messages after decompression
+    pub uncompressed_bytes_received: u64,
+    /// Errors reported for the connection, by category
+    pub errors: BTreeMap<String, u64>,
+    /// Messages queued for the connection but not yet written, when the figures were taken
+    pub queued_messages: u64,
+    /// Bytes of stream data queued for the connection but not yet written
+    pub queued_stream_bytes: u64,
+}
+
+impl ConnectionStats {
+    /// Uncompressed size of compressed sent messages over their wire size; `None` before any
+    pub fn compression_ratio_sent(&self) -> Option<f64> {
+        compression_ratio(self.uncompressed_bytes_sent, self.compressed_bytes_sent)
+    }
+
+    /// Uncompressed size of compressed received messages over their wire size; `None` before any
+    pub fn compression_ratio_received(&self) -> Option<f64> {
+        compression_ratio(self.uncompressed_bytes_received, self.compressed_bytes_received)
+    }
+}
+
+fn compression_ratio(uncompressed: u64, compressed: u64) -> Option<f64> {
+    (compressed > 0).then(|| uncompressed as f64 / compressed as f64)
+}
+
+/// Statistics of one worker runtime of a sharded server
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ShardStats {
+    /// Current connection count
+    pub active_connections: u32,
+    /// Total connection count
+    pub total_connections: u64,
+    /// Total messages received
+    pub messages_received: u64,
+    /// Total bytes received, as read from the wire
+    pub bytes_received: u64,
+}
+
+/// Round-trip time summary, from ping/pong samples
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LatencyStats {
+    /// Number of RTT samples
+    pub samples: u64,
+    /// Most recent RTT in milliseconds
+    pub last_ms: f64,
+    /// Mean RTT in milliseconds
+    pub avg_ms: f64,
+    /// Fastest RTT in milliseconds
+    pub min_ms: f64,
+    /// Slowest RTT in milliseconds
+    pub max_ms: f64,
+    /// Median RTT in milliseconds
+    pub p50_ms: f64,
+    /// 95th percentile RTT in milliseconds
+    pub p95_ms: f64,
+    /// 99th percentile RTT in milliseconds
+    pub p99_ms: f64,
 }
 
 /// Connection information
@@ -179,6 +409,61 @@ pub struct ConnectionInfo {
     pub connected_at: chrono::DateTime<chrono::Utc>,
     /// Last activity timestamp
     pub last_activity: chrono::DateTime<chrono::Utc>,
+    /// Identity the peer authenticated as, when the server requires authentication
+    #[serde(default)]
+    pub principal: Option<crate::auth::Principal>,
+    /// What the peer advertised in its handshake, once it has arrived
+    #[serde(default)]
+    pub capabilities: Option<PeerCapabilities>,
+    /// Worker runtime serving the connection, on a sharded server
+    #[serde(default)]
+    pub shard: Option<usize>,
+    /// Milliseconds since an envelope last went either way, when this snapshot was taken
+    #[serde(default)]
+    pub idle_ms: u64,
+    /// Endpoint names the peer registered to be addressed by
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+    /// Server name the peer asked for through TLS SNI, lowercased
+    #[serde(default)]
+    pub server_name: Option<String>,
+}
+
+/// What a peer advertised about itself when the connection opened
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerCapabilities {
+    /// UMICP protocol version the peer speaks
+    pub protocol_version: String,
+    /// Envelope codecs the peer can decode, most preferred first
+    pub codecs: Vec<EnvelopeCodec>,
+    /// Compression algorithms the peer accepts
+    pub compression: Vec<Compression>,
+    /// Largest message the peer accepts, in bytes
+    pub max_payload_size: usize,
+    /// Optional protocol features, such as `binary_frames` and `transfers`
+    pub features: Vec<String>,
+}
+
+impl PeerCapabilities {
+    /// Whether the peer advertised `feature`
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|supported| supported == feature)
+    }
+}
+
+/// Lifecycle state of a client connection, reported on every transition
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    /// Not connected and not trying to be
+    #[default]
+    Disconnected,
+    /// Connected to the remote end
+    Connected,
+    /// Connection lost; waiting for or performing a reconnection attempt
+    Reconnecting,
+    /// Every reconnection attempt failed; the transport has given up
+    Failed,
 }
 
 /// Matrix operation result
@@ -196,8 +481,316 @@ pub struct MatrixResult {
     pub data: Option<Vec<f32>>,
 }
 
+/// Owned dense f32 tensor stored in row-major order
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tensor {
+    /// Dimension sizes, outermost first
+    pub shape: Vec<usize>,
+    /// Elements in row-major order
+    pub data: Vec<f32>,
+}
+
+impl Tensor {
+    /// Create a tensor, checking that the shape matches the element count
+    pub fn new(shape: Vec<usize>, data: Vec<f32>) -> crate::error::Result<Self> {
+        let expected: usize = shape.iter().product();
+        if expected != data.len() {
+            return Err(crate::error::UmicpError::validation(format!(
+                "Tensor shape {:?} expects {} elements, got {}",
+                shape, expected, data.len()
+            )));
+        }
+        Ok(Tensor { shape, data })
+    }
+
+    /// Number of elements
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Whether the tensor holds no elements
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// Metric used when scoring pairs of vectors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SimilarityMetric {
+    /// Cosine similarity (higher is more similar)
+    #[default]
+    Cosine,
+    /// Euclidean (L2) distance (lower is more similar)
+    Euclidean,
+}
+
+/// Elementwise activation functions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Activation {
+    /// Rectified linear unit: max(0, x)
+    Relu,
+    /// Gaussian error linear unit (tanh approximation)
+    Gelu,
+    /// Logistic sigmoid: 1 / (1 + e^-x)
+    Sigmoid,
+    /// Hyperbolic tangent
+    Tanh,
+}
+
+impl std::fmt::Display for Activation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Activation::Relu => "relu",
+            Activation::Gelu => "gelu",
+            Activation::Sigmoid => "sigmoid",
+            Activation::Tanh => "tanh",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Elementwise math functions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ElementwiseFn {
+    /// Exponential: e^x
+    Exp,
+    /// Natural logarithm
+    Log,
+    /// Square root
+    Sqrt,
+    /// Absolute value
+    Abs,
+}
+
+/// Normalization method
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NormKind {
+    /// Divide by the L2 norm
+    #[default]
+    L2,
+    /// Rescale into `[0, 1]` using the minimum and maximum
+    MinMax,
+    /// Standardize to zero mean and unit variance
+    ZScore,
+}
+
+/// Span over which normalization statistics are computed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NormScope {
+    /// Each row is normalized independently
+    #[default]
+    Row,
+    /// The whole tensor is normalized as one span
+    Tensor,
+}
+
+/// Matrix axis to reduce along
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Axis {
+    /// Reduce along rows, producing one value per column
+    /// (e.g. mean-pooling token embeddings into a sentence embedding)
+    Row,
+    /// Reduce along columns, producing one value per row
+    Column,
+}
+
+/// Reduction applied along an axis
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Reduction {
+    /// Sum of the values
+    Sum,
+    /// Arithmetic mean of the values
+    Mean,
+    /// Maximum value
+    Max,
+    /// Minimum value
+    Min,
+    /// Population variance of the values
+    Variance,
+    /// Population standard deviation of the values
+    Std,
+    /// Median of the values
+    Median,
+    /// Percentile in `[0, 100]`, linearly interpolated between ranks
+    Percentile(f32),
+}
+
+/// Summation strategy used by dot products and reductions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SummationMode {
+    /// Plain f32 accumulation (fastest)
+    #[default]
+    Fast,
+    /// Kahan-Babuska (Neumaier) compensated summation with f64 accumulation
+    Kahan,
+    /// Pairwise (cascade) summation with f64 accumulation
+    Pairwise,
+}
+
+/// How matrix operations treat NaN and infinite values in their outputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NonFinitePolicy {
+    /// Leave non-finite values untouched
+    #[default]
+    Ignore,
+    /// Fail the operation with a matrix error
+    Error,
+    /// Replace non-finite values with zero
+    Sanitize,
+}
+
+/// Message compression algorithm negotiated between transport peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Messages are sent as-is
+    #[default]
+    None,
+    /// DEFLATE (RFC 1951)
+    Deflate,
+    /// Zstandard; offered only with the `zstd` feature
+    Zstd,
+}
+
+/// Encoding of envelopes on the wire, negotiated as a WebSocket subprotocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvelopeCodec {
+    /// JSON documents, sent as text frames unless compressed
+    #[default]
+    Json,
+    /// CBOR (RFC 8949) maps, sent as binary frames; usable only with the `cbor` feature
+    Cbor,
+}
+
+impl EnvelopeCodec {
+    /// Codecs this build can use, in the default preference order
+    pub fn supported() -> &'static [EnvelopeCodec] {
+        if cfg!(feature = "cbor") {
+            &[EnvelopeCodec::Json, EnvelopeCodec::Cbor]
+        } else {
+            &[EnvelopeCodec::Json]
+        }
+    }
+
+    /// WebSocket subprotocol naming this codec
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            EnvelopeCodec::Json => "umicp.v1.json",
+            EnvelopeCodec::Cbor => "umicp.v1.cbor",
+        }
+    }
+
+    /// Codec named by a subprotocol, if it is one of ours
+    pub fn from_subprotocol(name: &str) -> Option<Self> {
+        [EnvelopeCodec::Json, EnvelopeCodec::Cbor]
+            .into_iter()
+            .find(|codec| codec.subprotocol().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Memory ordering of an operand of [`Matrix::multiply_with_layout`](crate::Matrix::multiply_with_layout)
+///
+/// Other matrix operations take row-major buffers only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MultiplyLayout {
+    /// C order: consecutive elements walk along a row
+    #[default]
+    RowMajor,
+    /// Fortran/BLAS order: consecutive elements walk down a column
+    ColumnMajor,
+}
+
+impl MultiplyLayout {
+    /// Offset of element (row, col) in a rows x cols buffer with this layout
+    pub fn index(&self, row: usize, col: usize, rows: usize, cols: usize) -> usize {
+        match self {
+            MultiplyLayout::RowMajor => row * cols + col,
+            MultiplyLayout::ColumnMajor => col * rows + row,
+        }
+    }
+}
+
+/// Shape parameters for a 2D convolution over a single CHW image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conv2dParams {
+    /// Number of input channels
+    pub in_channels: usize,
+    /// Number of output channels (filters)
+    pub out_channels: usize,
+    /// Input height
+    pub height: usize,
+    /// Input width
+    pub width: usize,
+    /// Kernel height
+    pub kernel_height: usize,
+    /// Kernel width
+    pub kernel_width: usize,
+    /// Step between kernel applications (both dimensions)
+    pub stride: usize,
+    /// Zero padding added to each border (both dimensions)
+    pub padding: usize,
+}
+
+impl Conv2dParams {
+    /// Create parameters for a square kernel with stride 1 and no padding
+    pub fn new(in_channels: usize, out_channels: usize, height: usize, width: usize, kernel_size: usize) -> Self {
+        Conv2dParams {
+            in_channels,
+            out_channels,
+            height,
+            width,
+            kernel_height: kernel_size,
+            kernel_width: kernel_size,
+            stride: 1,
+            padding: 0,
+        }
+    }
+
+    /// Set the stride
+    pub fn with_stride(mut self, stride: usize) -> Self {
+        self.stride = stride;
+        self
+    }
+
+    /// Set the zero padding
+    pub fn with_padding(mut self, padding: usize) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Output height, or 0 if the kernel does not fit
+    pub fn output_height(&self) -> usize {
+        Self::output_dim(self.height, self.kernel_height, self.stride, self.padding)
+    }
+
+    /// Output width, or 0 if the kernel does not fit
+    pub fn output_width(&self) -> usize {
+        Self::output_dim(self.width, self.kernel_width, self.stride, self.padding)
+    }
+
+    fn output_dim(input: usize, kernel: usize, stride: usize, padding: usize) -> usize {
+        let padded = input + 2 * padding;
+        if stride == 0 || kernel == 0 || kernel > padded {
+            0
+        } else {
+            (padded - kernel) / stride + 1
+        }
+    }
+}
+
 /// Frame options for advanced messaging
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct FrameOptions {
     /// Frame type identifier
     pub frame_type: Option<u32>,
@@ -213,15 +806,51 @@ pub struct FrameOptions {
     pub encrypted: bool,
 }
 
-impl Default for FrameOptions {
-    fn default() -> Self {
-        FrameOptions {
-            frame_type: None,
-            stream_id: None,
-            sequence: None,
-            flags: None,
-            compressed: false,
-            encrypted: false,
+/// Identity a TLS peer's certificate must have, checked during the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TlsPin {
+    /// SHA-256 fingerprint of the DER certificate; changes whenever the certificate is reissued
+    Certificate([u8; 32]),
+    /// SHA-256 of the certificate's DER SubjectPublicKeyInfo; survives reissuing for the same key
+    PublicKey([u8; 32]),
+}
+
+impl std::str::FromStr for TlsPin {
+    type Err = crate::error::UmicpError;
+
+    /// Parse `sha256/<base64>` as a public key pin, the format of HPKP and curl's
+    /// `--pinnedpubkey`, or 64 hex digits (colons allowed) as a certificate fingerprint, as
+    /// `openssl x509 -fingerprint -sha256` prints it
+    fn from_str(pin: &str) -> crate::error::Result<Self> {
+        let invalid = || crate::error::UmicpError::configuration(format!("Invalid TLS pin: {}", pin));
+        let digest = |bytes: Vec<u8>| <[u8; 32]>::try_from(bytes).map_err(|_| invalid());
+        match pin.strip_prefix("sha256/") {
+            Some(encoded) => Ok(TlsPin::PublicKey(digest(
+                crate::utils::base64_decode(encoded).map_err(|_| invalid())?,
+            )?)),
+            None => Ok(TlsPin::Certificate(digest(hex::decode(pin.replace(':', "")).map_err(|_| invalid())?)?)),
+        }
+    }
+}
+
+/// Certificate a TLS server presents to clients asking for one server name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsVirtualHost {
+    /// Name clients send through SNI, matched case-insensitively
+    pub server_name: String,
+    /// Certificate chain path
+    pub cert_path: String,
+    /// Private key path
+    pub key_path: String,
+}
+
+impl TlsVirtualHost {
+    /// Serve `server_name` with the certificate and key in these files
+    pub fn new(server_name: impl Into<String>, cert_path: impl Into<String>, key_path: impl Into<String>) -> Self {
+        TlsVirtualHost {
+            server_name: server_name.into(),
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
         }
     }
 }
@@ -231,20 +860,85 @@ impl Default for FrameOptions {
 pub struct TransportConfig {
     /// Maximum payload size in bytes
     pub max_payload_size: usize,
-    /// Heartbeat interval in seconds
+    /// Heartbeat interval in seconds; each connection is pinged this often to sample its
+    /// round-trip time (0 disables pings)
     pub heartbeat_interval: u64,
-    /// Maximum reconnection attempts
+    /// Maximum reconnection attempts after a client loses its connection (0 disables reconnection)
     pub max_reconnect_attempts: u32,
+    /// Delay before the first reconnection attempt in milliseconds; doubles on each further attempt
+    pub reconnect_initial_delay_ms: u64,
+    /// Upper bound on the reconnection delay in milliseconds
+    pub reconnect_max_delay_ms: u64,
     /// Connection timeout in seconds
     pub connection_timeout: u64,
-    /// Enable compression
+    /// Offer message compression when connecting; used only if the peer agrees
     pub compression_enabled: bool,
+    /// Messages smaller than this many bytes are sent uncompressed
+    pub compression_threshold: usize,
     /// Enable TLS/SSL
     pub tls_enabled: bool,
     /// TLS certificate path (optional)
     pub tls_cert_path: Option<String>,
     /// TLS private key path (optional)
     pub tls_key_path: Option<String>,
+    /// PEM bundle of CA certificates trusted by clients (defaults to the webpki roots)
+    pub tls_ca_path: Option<String>,
+    /// Accept any server certificate (dangerous; development only)
+    pub tls_accept_invalid_certs: bool,
+    /// How often a TLS server checks its certificate and key files for changes, reloading them
+    /// for new connections when they change, in seconds (0 only reloads on request)
+    pub tls_reload_interval: u64,
+    /// Certificates or public keys the TLS peer must present one of, failing the handshake with
+    /// [`UmicpError::PinMismatch`](crate::error::UmicpError::PinMismatch) otherwise
+    ///
+    /// A client checks the server's certificate, after the usual verification against
+    /// `tls_ca_path` or instead of it with `tls_accept_invalid_certs`. A server requires clients
+    /// to present a certificate, their `tls_cert_path` and `tls_key_path`, and accepts only
+    /// pinned ones. Empty disables pinning.
+    pub tls_pins: Vec<TlsPin>,
+    /// Certificates a TLS server presents by the server name clients ask for, `tls_cert_path` and
+    /// `tls_key_path` serving any other name and becoming optional
+    pub tls_virtual_hosts: Vec<TlsVirtualHost>,
+    /// Bearer token (e.g. a JWT) presented by clients when connecting
+    pub auth_token: Option<Secret<String>>,
+    /// Have a server listen on both IPv4 and IPv6: on every address its bind address resolves to,
+    /// and on `0.0.0.0` and `[::]` alike for an unspecified one
+    pub dual_stack: bool,
+    /// Milliseconds a client waits on a connection attempt before also trying the server's next
+    /// address, alternating IP families (Happy Eyeballs, RFC 8305)
+    pub happy_eyeballs_delay_ms: u64,
+    /// Most connections a server serves at once, handshakes included (0 for no limit)
+    pub max_connections: usize,
+    /// Most envelopes a server dispatches to handlers at once, across connections (0 for no limit)
+    pub max_inflight_handlers: usize,
+    /// Proxy clients connect through: `http://[user:password@]host[:port]` for HTTP CONNECT or
+    /// `socks5://[user:password@]host[:port]`
+    pub proxy_url: Option<Secret<String>>,
+    /// Envelope codecs, most preferred first: clients offer them as WebSocket subprotocols and
+    /// servers accept only these
+    pub codecs: Vec<EnvelopeCodec>,
+    /// Worker runtimes, each on its own thread, a server spreads its connections across
+    /// (0 or 1 serves them all on the runtime calling `run`)
+    pub accept_shards: usize,
+    /// Envelopes per stream held back after a gap in their [`FrameOptions::sequence`] numbers, so
+    /// they can be delivered in order once it fills (0 delivers them at once, only reporting gaps)
+    pub reorder_window: usize,
+    /// How long held envelopes wait for a gap to fill before being delivered anyway, in
+    /// milliseconds (0 waits until the window overflows)
+    pub reorder_timeout_ms: u64,
+    /// Higher-priority messages a connection sends in a row while a lower-priority one waits;
+    /// the next send then takes the waiting one, so bulk data is delayed but never starved
+    /// (0 for strict priority)
+    pub max_priority_burst: usize,
+    /// Seconds without an envelope either way after which a server closes a connection, having
+    /// sent it a `Control` notice first (0 disables); pings do not count as traffic
+    pub idle_timeout: u64,
+    /// Milliseconds a connection may hold small envelopes back to send several in one frame, for
+    /// peers that accept batches (0 sends each at once); urgent envelopes are never held
+    pub coalesce_delay_ms: u64,
+    /// Held bytes at which a batch goes out without waiting for the delay; larger envelopes are
+    /// never held
+    pub coalesce_max_bytes: usize,
 }
 
 impl Default for TransportConfig {
@@ -253,11 +947,120 @@ impl Default for TransportConfig {
             max_payload_size: 1024 * 1024, // 1MB
             heartbeat_interval: 30,
             max_reconnect_attempts: 3,
+            reconnect_initial_delay_ms: 500,
+            reconnect_max_delay_ms: 30_000,
             connection_timeout: 10,
             compression_enabled: true,
+            compression_threshold: 1024,
             tls_enabled: false,
             tls_cert_path: None,
             tls_key_path: None,
+            tls_ca_path: None,
+            tls_accept_invalid_certs: false,
+            tls_reload_interval: 0,
+            tls_pins: Vec::new(),
+            tls_virtual_hosts: Vec::new(),
+            auth_token: None,
+            dual_stack: false,
+            happy_eyeballs_delay_ms: 250,
+            max_connections: 0,
+            max_inflight_handlers: 0,
+            proxy_url: None,
+            codecs: EnvelopeCodec::supported().to_vec(),
+            accept_shards: 0,
+            reorder_window: 0,
+            reorder_timeout_ms: 1000,
+            max_priority_burst: 16,
+            idle_timeout: 0,
+            coalesce_delay_ms: 0,
+            coalesce_max_bytes: 16 * 1024,
+        }
+    }
+}
+
+impl TransportConfig {
+    /// Delay before reconnection attempt `attempt` (1-based)
+    ///
+    /// Exponential backoff from `reconnect_initial_delay_ms`, capped at `reconnect_max_delay_ms`,
+    /// with jitter: the result is uniformly distributed over the upper half of the backoff so
+    /// clients dropped together do not reconnect in lockstep.
+    pub fn reconnect_delay(&self, attempt: u32) -> std::time::Duration {
+        let exponent = attempt.saturating_sub(1).min(32);
+        let backoff = self
+            .reconnect_initial_delay_ms
+            .saturating_mul(1u64 << exponent)
+            .min(self.reconnect_max_delay_ms);
+        let half = backoff / 2;
+        let jitter = rand::Rng::gen_range(&mut rand::thread_rng(), 0, backoff - half + 1);
+        std::time::Duration::from_millis(half + jitter)
+    }
+}
+
+/// Delivery guarantee for envelopes sent through a [`ReliableTransport`](crate::ReliableTransport)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryMode {
+    /// Send once; no acknowledgement expected
+    #[default]
+    AtMostOnce,
+    /// Retransmit until the peer acknowledges; the peer may see duplicates
+    AtLeastOnce,
+    /// Retransmit until the peer acknowledges; the peer drops duplicates it has already seen
+    ExactlyOnce,
+}
+
+/// Acknowledgement and retry settings for reliable delivery
+#[derive(Debug, Clone)]
+pub struct DeliveryConfig {
+    /// Mode used by `Transport::send`
+    pub mode: DeliveryMode,
+    /// How long to wait for an ack before retransmitting, in milliseconds
+    pub ack_timeout_ms: u64,
+    /// Retransmissions after the first attempt before giving up
+    pub max_retries: u32,
+    /// Message IDs remembered by the default in-memory dedup store
+    pub dedup_window: usize,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        DeliveryConfig {
+            mode: DeliveryMode::AtLeastOnce,
+            ack_timeout_ms: 5000,
+            max_retries: 3,
+            dedup_window: 10_000,
+        }
+    }
+}
+
+/// How a [`LoadBalancedTransport`](crate::LoadBalancedTransport) picks the backend for a send
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    /// Take healthy backends in turn
+    #[default]
+    RoundRobin,
+    /// Take the healthy backend with the fewest sends and requests in flight
+    LeastOutstanding,
+}
+
+/// Backend selection and health tracking settings for client-side load balancing
+#[derive(Debug, Clone)]
+pub struct BalancerConfig {
+    /// Selection strategy
+    pub strategy: BalanceStrategy,
+    /// Consecutive failed sends after which a backend is taken out of rotation
+    pub failure_threshold: u32,
+    /// How long a failing backend stays out of rotation before it is tried again, in milliseconds
+    pub cooldown_ms: u64,
+}
+
+impl Default for BalancerConfig {
+    fn default() -> Self {
+        BalancerConfig {
+            strategy: BalanceStrategy::RoundRobin,
+            failure_threshold: 3,
+            cooldown_ms: 5000,
         }
     }
 }
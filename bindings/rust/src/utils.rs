@@ -4,6 +4,7 @@
 Utility functions for UMICP operations.
 */
 
+use crate::envelope::Envelope;
 use crate::error::{Result, UmicpError};
 use chrono::{DateTime, Utc};
 use sha2::{Digest, Sha256};
@@ -34,6 +35,36 @@ pub fn generate_hash(data: &[u8]) -> String {
     hex::encode(result)
 }
 
+/// Compare two byte strings in time that depends only on their lengths
+///
+/// Use this rather than `==` for hashes, MACs, tokens and anything else an attacker could
+/// otherwise guess byte by byte from how quickly a comparison fails.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a
+        .iter()
+        .zip(b)
+        .fold(0u8, |difference, (x, y)| difference | std::hint::black_box(x ^ y));
+    difference == 0
+}
+
+/// Check that `envelope` hashes to `expected`, a hex digest as returned by [`Envelope::hash`],
+/// comparing in constant time
+pub fn verify_hash(envelope: &Envelope, expected: &str) -> Result<()> {
+    let expected =
+        hex::decode(expected.trim()).map_err(|_| UmicpError::validation("Expected envelope hash is not hex"))?;
+    let actual = hex::decode(envelope.hash()?).expect("envelope hashes are hex");
+    if !constant_time_eq(&actual, &expected) {
+        return Err(UmicpError::validation(format!(
+            "Envelope {} does not match the expected hash",
+            envelope.message_id()
+        )));
+    }
+    Ok(())
+}
+
 /// Validate UUID format
 pub fn validate_uuid(uuid_str: &str) -> bool {
     Uuid::parse_str(uuid_str).is_ok()
@@ -128,7 +159,7 @@ pub fn sanitize_string(input: &str) -> String {
 
 /// Check if string contains only ASCII characters
 pub fn is_ascii_only(input: &str) -> bool {
-    input.chars().all(|c| c.is_ascii())
+    input.is_ascii()
 }
 
 /// Truncate string to maximum length
@@ -0,0 +1,280 @@
+/*!
+# UMICP Matrix Views
+
+Non-owning, strided windows over row-major `f32` buffers.
+
+A view records its shape and the row stride of the buffer it borrows, so rows, columns and
+sub-blocks of a large shared buffer can be sliced out and handed to matrix operations
+without copying.
+*/
+
+use crate::error::{Result, UmicpError};
+
+/// Read-only window over a row-major buffer
+#[derive(Debug, Clone, Copy)]
+pub struct MatrixView<'a> {
+    data: &'a [f32],
+    rows: usize,
+    cols: usize,
+    stride: usize,
+}
+
+/// Mutable window over a row-major buffer
+#[derive(Debug)]
+pub struct MatrixViewMut<'a> {
+    data: &'a mut [f32],
+    rows: usize,
+    cols: usize,
+    stride: usize,
+}
+
+/// Number of buffer elements a rows x cols window with the given stride spans
+fn span(rows: usize, cols: usize, stride: usize) -> usize {
+    if rows == 0 || cols == 0 {
+        0
+    } else {
+        (rows - 1) * stride + cols
+    }
+}
+
+fn validate_layout(len: usize, rows: usize, cols: usize, stride: usize) -> Result<()> {
+    if stride < cols {
+        return Err(UmicpError::matrix(format!(
+            "View stride {} is smaller than its column count {}",
+            stride, cols
+        )));
+    }
+    if len < span(rows, cols, stride) {
+        return Err(UmicpError::matrix(format!(
+            "Buffer of {} elements is too small for a {}x{} view with stride {}",
+            len, rows, cols, stride
+        )));
+    }
+    Ok(())
+}
+
+fn validate_block(rows: usize, cols: usize, row: usize, col: usize, block_rows: usize, block_cols: usize) -> Result<()> {
+    if row + block_rows > rows || col + block_cols > cols {
+        return Err(UmicpError::matrix(format!(
+            "Block {}x{} at ({}, {}) exceeds view bounds {}x{}",
+            block_rows, block_cols, row, col, rows, cols
+        )));
+    }
+    Ok(())
+}
+
+impl<'a> MatrixView<'a> {
+    /// View a densely packed rows x cols buffer
+    pub fn new(data: &'a [f32], rows: usize, cols: usize) -> Result<Self> {
+        Self::with_stride(data, rows, cols, cols)
+    }
+
+    /// View a rows x cols window whose rows start `stride` elements apart
+    pub fn with_stride(data: &'a [f32], rows: usize, cols: usize, stride: usize) -> Result<Self> {
+        validate_layout(data.len(), rows, cols, stride)?;
+        let data = &data[..span(rows, cols, stride)];
+        Ok(Self { data, rows, cols, stride })
+    }
+
+    /// Number of rows
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Number of columns
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    /// Distance in elements between the starts of consecutive rows
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Whether the view covers one unbroken run of the buffer
+    pub fn is_contiguous(&self) -> bool {
+        self.stride == self.cols || self.rows <= 1
+    }
+
+    /// Underlying elements when the view is contiguous
+    pub fn as_slice(&self) -> Option<&'a [f32]> {
+        self.is_contiguous().then_some(self.data)
+    }
+
+    /// Element at (row, col)
+    pub fn get(&self, row: usize, col: usize) -> Option<f32> {
+        (row < self.rows && col < self.cols).then(|| self.data[row * self.stride + col])
+    }
+
+    /// A single row as a plain slice
+    pub fn row(&self, row: usize) -> Result<&'a [f32]> {
+        validate_block(self.rows, self.cols, row, 0, 1, self.cols)?;
+        let start = row * self.stride;
+        Ok(&self.data[start..start + self.cols])
+    }
+
+    /// A single column as a rows x 1 view
+    pub fn column(&self, col: usize) -> Result<MatrixView<'a>> {
+        self.block(0, col, self.rows, 1)
+    }
+
+    /// A contiguous range of rows
+    pub fn row_range(&self, start: usize, end: usize) -> Result<MatrixView<'a>> {
+        if start > end {
+            return Err(UmicpError::matrix(format!("Invalid row range {}..{}", start, end)));
+        }
+        self.block(start, 0, end - start, self.cols)
+    }
+
+    /// A block_rows x block_cols sub-block whose top-left corner is (row, col)
+    pub fn block(&self, row: usize, col: usize, block_rows: usize, block_cols: usize) -> Result<MatrixView<'a>> {
+        validate_block(self.rows, self.cols, row, col, block_rows, block_cols)?;
+        let start = if block_rows == 0 || block_cols == 0 { 0 } else { row * self.stride + col };
+        let data = &self.data[start..start + span(block_rows, block_cols, self.stride)];
+        Ok(MatrixView { data, rows: block_rows, cols: block_cols, stride: self.stride })
+    }
+
+    /// Iterate over the rows as plain slices
+    pub fn iter_rows(&self) -> impl Iterator<Item = &'a [f32]> + '_ {
+        (0..self.rows).map(move |r| &self.data[r * self.stride..r * self.stride + self.cols])
+    }
+
+    /// Copy the window into a densely packed row-major vector
+    pub fn to_vec(&self) -> Vec<f32> {
+        let mut out = Vec::with_capacity(self.rows * self.cols);
+        for row in self.iter_rows() {
+            out.extend_from_slice(row);
+        }
+        out
+    }
+}
+
+impl<'a> MatrixViewMut<'a> {
+    /// Mutably view a densely packed rows x cols buffer
+    pub fn new(data: &'a mut [f32], rows: usize, cols: usize) -> Result<Self> {
+        Self::with_stride(data, rows, cols, cols)
+    }
+
+    /// Mutably view a rows x cols window whose rows start `stride` elements apart
+    pub fn with_stride(data: &'a mut [f32], rows: usize, cols: usize, stride: usize) -> Result<Self> {
+        validate_layout(data.len(), rows, cols, stride)?;
+        let data = &mut data[..span(rows, cols, stride)];
+        Ok(Self { data, rows, cols, stride })
+    }
+
+    /// Number of rows
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Number of columns
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    /// Distance in elements between the starts of consecutive rows
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Whether the view covers one unbroken run of the buffer
+    pub fn is_contiguous(&self) -> bool {
+        self.stride == self.cols || self.rows <= 1
+    }
+
+    /// Read-only reborrow of this view
+    pub fn as_view(&self) -> MatrixView<'_> {
+        MatrixView { data: self.data, rows: self.rows, cols: self.cols, stride: self.stride }
+    }
+
+    /// Underlying elements when the view is contiguous
+    pub fn as_mut_slice(&mut self) -> Option<&mut [f32]> {
+        if self.is_contiguous() {
+            Some(self.data)
+        } else {
+            None
+        }
+    }
+
+    /// Element at (row, col)
+    pub fn get(&self, row: usize, col: usize) -> Option<f32> {
+        self.as_view().get(row, col)
+    }
+
+    /// Mutable element at (row, col)
+    pub fn get_mut(&mut self, row: usize, col: usize) -> Option<&mut f32> {
+        if row < self.rows && col < self.cols {
+            Some(&mut self.data[row * self.stride + col])
+        } else {
+            None
+        }
+    }
+
+    /// A single row as a plain mutable slice
+    pub fn row_mut(&mut self, row: usize) -> Result<&mut [f32]> {
+        validate_block(self.rows, self.cols, row, 0, 1, self.cols)?;
+        let start = row * self.stride;
+        Ok(&mut self.data[start..start + self.cols])
+    }
+
+    /// A mutable block_rows x block_cols sub-block whose top-left corner is (row, col)
+    pub fn block_mut(&mut self, row: usize, col: usize, block_rows: usize, block_cols: usize) -> Result<MatrixViewMut<'_>> {
+        validate_block(self.rows, self.cols, row, col, block_rows, block_cols)?;
+        let start = if block_rows == 0 || block_cols == 0 { 0 } else { row * self.stride + col };
+        let end = start + span(block_rows, block_cols, self.stride);
+        Ok(MatrixViewMut { data: &mut self.data[start..end], rows: block_rows, cols: block_cols, stride: self.stride })
+    }
+
+    /// Set every element of the window to `value`
+    pub fn fill(&mut self, value: f32) {
+        for r in 0..self.rows {
+            let start = r * self.stride;
+            self.data[start..start + self.cols].fill(value);
+        }
+    }
+
+    /// Copy another view of the same shape into this window
+    pub fn copy_from(&mut self, source: &MatrixView<'_>) -> Result<()> {
+        if source.rows() != self.rows || source.cols() != self.cols {
+            return Err(UmicpError::matrix(format!(
+                "View shape mismatch: source {}x{} != destination {}x{}",
+                source.rows(), source.cols(), self.rows, self.cols
+            )));
+        }
+        for (r, row) in source.iter_rows().enumerate() {
+            let start = r * self.stride;
+            self.data[start..start + self.cols].copy_from_slice(row);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_view_slicing() {
+        // 4x5 buffer holding 0..20
+        let mut buffer: Vec<f32> = (0..20).map(|i| i as f32).collect();
+
+        let view = MatrixView::new(&buffer, 4, 5).unwrap();
+        assert_eq!(view.row(2).unwrap(), &[10.0, 11.0, 12.0, 13.0, 14.0]);
+        assert_eq!(view.column(3).unwrap().to_vec(), vec![3.0, 8.0, 13.0, 18.0]);
+
+        let block = view.block(1, 1, 2, 3).unwrap();
+        assert!(!block.is_contiguous());
+        assert_eq!(block.to_vec(), vec![6.0, 7.0, 8.0, 11.0, 12.0, 13.0]);
+        assert_eq!(block.get(1, 2), Some(13.0));
+        assert_eq!(view.row_range(1, 3).unwrap().as_slice().unwrap().len(), 10);
+        assert!(view.block(3, 3, 2, 2).is_err());
+
+        let mut view_mut = MatrixViewMut::new(&mut buffer, 4, 5).unwrap();
+        view_mut.block_mut(0, 3, 4, 2).unwrap().fill(-1.0);
+        *view_mut.get_mut(0, 0).unwrap() = 42.0;
+        assert_eq!(&buffer[..5], &[42.0, 1.0, 2.0, -1.0, -1.0]);
+        assert_eq!(&buffer[15..], &[15.0, 16.0, 17.0, -1.0, -1.0]);
+
+        assert!(MatrixView::with_stride(&buffer, 4, 6, 5).is_err());
+    }
+}
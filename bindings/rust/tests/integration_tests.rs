@@ -223,4 +223,18 @@ mod tests {
         assert_eq!(capabilities.get("key1").unwrap(), "value1");
         assert_eq!(capabilities.get("key2").unwrap(), "value2");
     }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let config = TransportConfig {
+            reconnect_initial_delay_ms: 100,
+            reconnect_max_delay_ms: 1000,
+            ..Default::default()
+        };
+
+        for (attempt, backoff) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (40, 1000)] {
+            let delay = config.reconnect_delay(attempt).as_millis() as u64;
+            assert!(delay >= backoff / 2 && delay <= backoff, "attempt {}: {}ms", attempt, delay);
+        }
+    }
 }